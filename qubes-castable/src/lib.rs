@@ -14,6 +14,203 @@ pub use core::{
     primitive::{u8, usize},
 };
 
+#[cfg(feature = "arbitrary")]
+#[doc(hidden)]
+pub extern crate arbitrary;
+
+/// Implement `arbitrary::Arbitrary` for a [`castable!`] struct, one field at
+/// a time.  Expands to nothing unless the `arbitrary` feature is enabled.
+#[cfg(feature = "arbitrary")]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __impl_arbitrary {
+    ($s: ident { $($name: ident: $ty: ty),* }) => {
+        impl<'a> $crate::arbitrary::Arbitrary<'a> for $s {
+            #[allow(unused_variables)]
+            fn arbitrary(
+                u: &mut $crate::arbitrary::Unstructured<'a>,
+            ) -> $crate::arbitrary::Result<Self> {
+                $crate::core::result::Result::Ok(Self {
+                    $($name: $crate::arbitrary::Arbitrary::arbitrary(u)?),*
+                })
+            }
+
+            #[allow(unused_variables)]
+            fn size_hint(depth: $crate::usize) -> ($crate::usize, $crate::core::option::Option<$crate::usize>) {
+                $crate::arbitrary::size_hint::and_all(&[
+                    $(<$ty as $crate::arbitrary::Arbitrary<'a>>::size_hint(depth)),*
+                ])
+            }
+        }
+    };
+}
+
+#[cfg(not(feature = "arbitrary"))]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __impl_arbitrary {
+    ($($t: tt)*) => {};
+}
+
+#[cfg(feature = "serde")]
+#[doc(hidden)]
+pub extern crate serde;
+
+#[cfg(feature = "serde")]
+#[doc(hidden)]
+pub mod serde_support;
+
+/// Implement `serde::Serialize` and `serde::Deserialize` for a [`castable!`]
+/// struct, as a struct with the same fields.  Expands to nothing unless the
+/// `serde` feature is enabled.
+#[cfg(feature = "serde")]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __impl_serde {
+    ($s: ident { $($name: ident: $ty: ty),* }) => {
+        impl $crate::serde::Serialize for $s {
+            fn serialize<S: $crate::serde::Serializer>(
+                &self,
+                serializer: S,
+            ) -> $crate::core::result::Result<S::Ok, S::Error> {
+                use $crate::serde::ser::SerializeStruct as _;
+                let fields: &[&str] = &[$($crate::core::stringify!($name)),*];
+                #[allow(unused_mut)]
+                let mut state = serializer.serialize_struct(
+                    $crate::core::stringify!($s),
+                    fields.len(),
+                )?;
+                $(
+                    state.serialize_field(
+                        $crate::core::stringify!($name),
+                        &$crate::serde_support::Ser(&self.$name),
+                    )?;
+                )*
+                state.end()
+            }
+        }
+
+        impl<'de> $crate::serde::Deserialize<'de> for $s {
+            fn deserialize<D: $crate::serde::Deserializer<'de>>(
+                deserializer: D,
+            ) -> $crate::core::result::Result<Self, D::Error> {
+                use $crate::serde::de::Error as _;
+                const FIELDS: &[&str] = &[$($crate::core::stringify!($name)),*];
+                struct Visitor;
+                impl<'de> $crate::serde::de::Visitor<'de> for Visitor {
+                    type Value = $s;
+
+                    fn expecting(
+                        &self,
+                        f: &mut $crate::core::fmt::Formatter<'_>,
+                    ) -> $crate::core::fmt::Result {
+                        f.write_str($crate::core::concat!("struct ", $crate::core::stringify!($s)))
+                    }
+
+                    #[allow(unused_mut, unused_variables, unused_assignments)]
+                    fn visit_seq<A: $crate::serde::de::SeqAccess<'de>>(
+                        self,
+                        mut seq: A,
+                    ) -> $crate::core::result::Result<$s, A::Error> {
+                        let mut index = 0;
+                        $(
+                            let $name = match seq
+                                .next_element::<$crate::serde_support::De<$ty>>()?
+                            {
+                                $crate::core::option::Option::Some(value) => value.0,
+                                $crate::core::option::Option::None => {
+                                    return $crate::core::result::Result::Err(
+                                        A::Error::invalid_length(index, &self),
+                                    )
+                                }
+                            };
+                            index += 1;
+                        )*
+                        $crate::core::result::Result::Ok($s { $($name),* })
+                    }
+
+                    #[allow(unused_mut, unused_variables)]
+                    fn visit_map<A: $crate::serde::de::MapAccess<'de>>(
+                        self,
+                        mut map: A,
+                    ) -> $crate::core::result::Result<$s, A::Error> {
+                        $(
+                            let mut $name: $crate::core::option::Option<$ty> =
+                                $crate::core::option::Option::None;
+                        )*
+                        while let $crate::core::option::Option::Some(key) =
+                            map.next_key_seed($crate::serde_support::FieldName(FIELDS))?
+                        {
+                            $(
+                                if key == $crate::core::stringify!($name) {
+                                    if $name.is_some() {
+                                        return $crate::core::result::Result::Err(
+                                            A::Error::duplicate_field(
+                                                $crate::core::stringify!($name),
+                                            ),
+                                        );
+                                    }
+                                    $name = $crate::core::option::Option::Some(
+                                        map.next_value::<$crate::serde_support::De<$ty>>()?.0,
+                                    );
+                                }
+                            )*
+                        }
+                        $crate::core::result::Result::Ok($s {
+                            $(
+                                $name: $name.ok_or_else(|| {
+                                    A::Error::missing_field($crate::core::stringify!($name))
+                                })?,
+                            )*
+                        })
+                    }
+                }
+                deserializer.deserialize_struct($crate::core::stringify!($s), FIELDS, Visitor)
+            }
+        }
+
+        impl $crate::serde_support::SerdeField for $s {
+            fn serialize_field<S: $crate::serde::Serializer>(
+                &self,
+                serializer: S,
+            ) -> $crate::core::result::Result<S::Ok, S::Error> {
+                $crate::serde::Serialize::serialize(self, serializer)
+            }
+
+            fn deserialize_field<'de, D: $crate::serde::Deserializer<'de>>(
+                deserializer: D,
+            ) -> $crate::core::result::Result<Self, D::Error> {
+                $crate::serde::Deserialize::deserialize(deserializer)
+            }
+        }
+    };
+}
+
+#[cfg(not(feature = "serde"))]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __impl_serde {
+    ($($t: tt)*) => {};
+}
+
+/// Implement `Debug` for a [`castable!`] struct, as `derive(Debug)` would.
+/// Expands to nothing for structs marked `#[castable(no_debug)]`, which
+/// implement `Debug` themselves.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __impl_debug {
+    ($s: ident { $($name: ident),* } no_debug) => {};
+    ($s: ident { $($name: ident),* }) => {
+        impl $crate::core::fmt::Debug for $s {
+            fn fmt(&self, f: &mut $crate::core::fmt::Formatter<'_>) -> $crate::core::fmt::Result {
+                f.debug_struct($crate::core::stringify!($s))
+                    $(.field($crate::core::stringify!($name), &self.$name))*
+                    .finish()
+            }
+        }
+    };
+}
+
 /// If the provided expression is false, fail the build with a type error.
 #[macro_export]
 macro_rules! static_assert {
@@ -25,6 +222,41 @@ macro_rules! static_assert {
     };
 }
 
+/// One field of a struct made with [`castable!`]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct Field {
+    /// Name of the field
+    pub name: &'static str,
+    /// Type of the field, as written in the struct definition
+    pub ty: &'static str,
+    /// Size of the field in bytes
+    pub size: usize,
+    /// Fields of the field's type, or empty if the type was not made with
+    /// [`castable!`]
+    pub fields: &'static [Field],
+    /// What the field holds, if it has no fields of its own
+    pub scalar: Scalar,
+}
+
+/// What a [`Castable`] type holds, for code that walks [`Castable::FIELDS`]
+/// without knowing the types, such as language bindings
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum Scalar {
+    /// An unsigned integer
+    Unsigned,
+    /// A signed integer
+    Signed,
+    /// An `Option` of a nonzero unsigned integer, where `None` is 0
+    OptionalUnsigned,
+    /// An `Option` of a nonzero signed integer, where `None` is 0
+    OptionalSigned,
+    /// An array of bytes
+    Bytes,
+    /// Anything else, such as a struct made with [`castable!`]
+    Other,
+}
+
 /// A trait for types that can be casted to and from a raw byte slice.
 ///
 /// All [`Castable`] types are `Copy`, and thus do *not* implement `Drop`.
@@ -62,6 +294,14 @@ pub unsafe trait Castable:
     + Sized
     + 'static
 {
+    /// The fields of this type if it was made with [`castable!`], in order.
+    /// There is never any padding before, between or after them, so each
+    /// field starts where the previous one ends.
+    const FIELDS: &'static [Field] = &[];
+
+    /// What this type holds.  The size is that of the type.
+    const SCALAR: Scalar = Scalar::Other;
+
     /// Casts a [`Castable`] type to a `&[u8]`, without any copies.
     ///
     /// This is safe because [`Castable`] is unsafe to implement.
@@ -202,6 +442,40 @@ pub unsafe trait Castable:
         // it, so this cannot create a value with an invalid bit pattern.
         unsafe { core::mem::zeroed() }
     }
+
+    /// Reverses the byte order of every integer in `self`, in place.  Types
+    /// made with [`castable!`] swap each field.  This does nothing for
+    /// single-byte types.
+    #[inline]
+    fn swap_endian(&mut self) {}
+
+    /// Converts between native byte order and the little-endian order used
+    /// on the wire, like [`u32::to_le`].  This does nothing on little-endian
+    /// hosts.  Converting twice gives back the original value.
+    ///
+    /// ```rust
+    /// # use qubes_castable::Castable;
+    /// assert_eq!(Castable::as_bytes(&0x0102u16.to_le_order()), &[2, 1]);
+    /// ```
+    #[inline]
+    fn to_le_order(mut self) -> Self {
+        if cfg!(target_endian = "big") {
+            self.swap_endian()
+        }
+        self
+    }
+
+    /// Like [`Castable::from_bytes`], but `buf` is in little-endian order,
+    /// as on the wire.  Use this for bytes that may come from a host with a
+    /// different byte order, such as a capture file.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the length of `buf` is not equal to `size_of::<Self>`.
+    #[inline]
+    fn from_le_bytes(buf: &[u8]) -> Self {
+        Self::from_bytes(buf).to_le_order()
+    }
 }
 
 // SAFETY: () is a ZST
@@ -209,7 +483,7 @@ unsafe impl Castable for () {}
 
 // Unsafely implement Castable for Option<NonZero*>, but check layouts first
 macro_rules! unsafe_castable_nonzero {
-    ($(($i: ident, $j: ident),)*) => {
+    ($(($i: ident, $j: ident, $scalar: ident, $optional: ident),)*) => {
         const _: () = {
             $(
                 static_assert!(
@@ -227,28 +501,54 @@ macro_rules! unsafe_castable_nonzero {
         };
         $(
             // SAFETY: the safe usage of this is part of its API contract.
-            unsafe impl Castable for $j {}
+            unsafe impl Castable for $j {
+                const SCALAR: Scalar = Scalar::$scalar;
+
+                #[inline]
+                fn swap_endian(&mut self) {
+                    *self = self.swap_bytes()
+                }
+            }
             // SAFETY: Option<NonZero*> satisfies the Castable requirements due to the null pointer
             // optimization.
-            unsafe impl Castable for Option<core::num::$i> {}
+            unsafe impl Castable for Option<core::num::$i> {
+                const SCALAR: Scalar = Scalar::$optional;
+
+                #[inline]
+                fn swap_endian(&mut self) {
+                    *self = self.and_then(|v| core::num::$i::new(v.get().swap_bytes()))
+                }
+            }
         )*
     }
 }
 
 unsafe_castable_nonzero! {
-    (NonZeroU8, u8),
-    (NonZeroU16, u16),
-    (NonZeroU32, u32),
-    (NonZeroU64, u64),
-    (NonZeroI8, i8),
-    (NonZeroI16, i16),
-    (NonZeroI32, i32),
-    (NonZeroI64, i64),
+    (NonZeroU8, u8, Unsigned, OptionalUnsigned),
+    (NonZeroU16, u16, Unsigned, OptionalUnsigned),
+    (NonZeroU32, u32, Unsigned, OptionalUnsigned),
+    (NonZeroU64, u64, Unsigned, OptionalUnsigned),
+    (NonZeroI8, i8, Signed, OptionalSigned),
+    (NonZeroI16, i16, Signed, OptionalSigned),
+    (NonZeroI32, i32, Signed, OptionalSigned),
+    (NonZeroI64, i64, Signed, OptionalSigned),
 }
 
 // Arrays of castable types are castable
 // SAFETY: an array is layed out contiguously in memory.
-unsafe impl<T: Castable, const COUNT: usize> Castable for [T; COUNT] {}
+unsafe impl<T: Castable, const COUNT: usize> Castable for [T; COUNT] {
+    const SCALAR: Scalar = match T::SCALAR {
+        Scalar::Unsigned if core::mem::size_of::<T>() == 1 => Scalar::Bytes,
+        _ => Scalar::Other,
+    };
+
+    #[inline]
+    fn swap_endian(&mut self) {
+        for element in self {
+            element.swap_endian()
+        }
+    }
+}
 
 /// Create a struct that is marked as castable, meaning that it can be converted
 /// to and from a byte slice without any run-time overhead.  This macro:
@@ -368,6 +668,27 @@ unsafe impl<T: Castable, const COUNT: usize> Castable for [T; COUNT] {}
 /// };
 /// ```
 ///
+/// A struct whose contents must not be logged can be marked
+/// `#[castable(no_debug)]`, and must then implement `Debug` itself:
+///
+/// ```rust
+/// # use qubes_castable::castable;
+/// castable! {
+///     #[castable(no_debug)]
+///     /// A secret
+///     struct Secret {
+///         /// The secret bytes
+///         pub data: [u8; 16],
+///     }
+/// }
+///
+/// impl core::fmt::Debug for Secret {
+///     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+///         f.write_str("Secret")
+///     }
+/// }
+/// ```
+///
 /// The `NonZero*` types from `core::num` are not castable
 ///
 /// ```rust,compile_fail
@@ -397,7 +718,8 @@ unsafe impl<T: Castable, const COUNT: usize> Castable for [T; COUNT] {}
 /// ```
 #[macro_export]
 macro_rules! castable {
-    ($($(#[doc = $m: expr])*
+    ($($(#[castable($opt: ident)])?
+    $(#[doc = $m: expr])*
     $p: vis struct $s: ident {
         $(
             $(#[doc = $n: expr])*
@@ -405,7 +727,7 @@ macro_rules! castable {
         ),*$(,)?
     })+) => {
         $(
-        #[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Debug, Hash)]
+        #[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
         $(#[doc = $m])*
         #[repr(C)]
         $p struct $s {
@@ -422,7 +744,22 @@ macro_rules! castable {
         // Castable.  Since the struct is comprised entirely of its individual
         // fields, and since the individual fields are Castable, the result
         // struct meets the Castable contract.
-        unsafe impl $crate::Castable for $s {}
+        unsafe impl $crate::Castable for $s {
+            const FIELDS: &'static [$crate::Field] = &[$(
+                $crate::Field {
+                    name: $crate::core::stringify!($name),
+                    ty: $crate::core::stringify!($ty),
+                    size: $crate::size_of::<$ty>(),
+                    fields: <$ty as $crate::Castable>::FIELDS,
+                    scalar: <$ty as $crate::Castable>::SCALAR,
+                }
+            ),*];
+
+            #[inline]
+            fn swap_endian(&mut self) {
+                $($crate::Castable::swap_endian(&mut self.$name);)*
+            }
+        }
         $crate::static_assert!({
             const fn _size_of_castable<T: $crate::Castable>() -> $crate::usize {
                 $crate::size_of::<T>()
@@ -448,6 +785,9 @@ macro_rules! castable {
                 $crate::cast!(s)
             }
         }
+        $crate::__impl_arbitrary!($s { $($name: $ty),* });
+        $crate::__impl_serde!($s { $($name: $ty),* });
+        $crate::__impl_debug!($s { $($name),* } $($opt)?);
         )+
     }
 }
@@ -593,6 +933,101 @@ mod test {
         );
     }
 
+    #[test]
+    fn swap_endian() {
+        use core::num::NonZeroU16;
+        let mut value = [0x0102u16, 0x0304];
+        value.swap_endian();
+        assert_eq!(value, [0x0201, 0x0403]);
+        let mut value = NonZeroU16::new(0x0102);
+        value.swap_endian();
+        assert_eq!(value, NonZeroU16::new(0x0201));
+        let mut value = [1u8, 2];
+        value.swap_endian();
+        assert_eq!(value, [1, 2]);
+        assert_eq!(<i32 as Castable>::from_le_bytes(&[8, 7, 6, 5]), 0x05060708);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde() {
+        extern crate std;
+        use std::string::ToString as _;
+        castable! {
+            struct Inner {
+                pub a: Option<core::num::NonZeroU32>,
+                pub b: [u8; 40],
+            }
+            struct Outer {
+                pub inner: Inner,
+                pub c: i32,
+            }
+        }
+        let mut outer = Outer::default();
+        outer.inner.b[39] = 7;
+        outer.c = -2;
+        let json = serde_json::to_string(&outer).unwrap();
+        assert!(
+            json.starts_with(r#"{"inner":{"a":null,"b":[0,"#),
+            "{}",
+            json
+        );
+        assert_eq!(serde_json::from_str::<Outer>(&json).unwrap(), outer);
+        let err = serde_json::from_str::<Inner>(r#"{"a":1,"b":[1,2]}"#).unwrap_err();
+        assert!(err.to_string().contains("40 bytes"), "{}", err);
+        let err = serde_json::from_str::<Outer>(r#"{"c":1}"#).unwrap_err();
+        assert!(err.to_string().contains("missing field `inner`"), "{}", err);
+    }
+
+    #[cfg(feature = "arbitrary")]
+    #[test]
+    fn arbitrary() {
+        use arbitrary::{Arbitrary, Unstructured};
+        castable! {
+            struct Fields {
+                pub a: u8,
+                pub b: [u8; 2],
+                pub c: Option<core::num::NonZeroU8>,
+            }
+        }
+        assert_eq!(Fields::size_hint(0), (4, Some(5)));
+        let mut u = Unstructured::new(&[1, 2, 3, 0]);
+        let fields = Fields::arbitrary(&mut u).unwrap();
+        assert_eq!(fields.a, 1);
+        assert_eq!(fields.b, [2, 3]);
+        assert_eq!(fields.c, None);
+    }
+
+    #[test]
+    fn fields() {
+        castable! {
+            struct Inner {
+                pub a: u16,
+                pub b: [u8; 2],
+            }
+            struct Outer {
+                pub inner: Inner,
+                pub c: u32,
+            }
+        }
+        assert_eq!(Outer::FIELDS.len(), 2);
+        assert_eq!(Outer::FIELDS[0].name, "inner");
+        assert_eq!(Outer::FIELDS[0].ty, "Inner");
+        assert_eq!(Outer::FIELDS[0].fields, Inner::FIELDS);
+        assert_eq!(Inner::FIELDS[1].ty, "[u8; 2]");
+        assert_eq!(Outer::FIELDS[1].size, 4);
+        assert!(u32::FIELDS.is_empty());
+        assert_eq!(Inner::FIELDS[0].scalar, Scalar::Unsigned);
+        assert_eq!(Inner::FIELDS[1].scalar, Scalar::Bytes);
+        assert_eq!(Outer::FIELDS[0].scalar, Scalar::Other);
+        assert_eq!(<[i8; 2]>::SCALAR, Scalar::Other);
+        assert_eq!(i32::SCALAR, Scalar::Signed);
+        assert_eq!(
+            <Option<core::num::NonZeroU32>>::SCALAR,
+            Scalar::OptionalUnsigned
+        );
+    }
+
     #[test]
     #[should_panic = "Size mismatch: got 0 bytes but expected 1"]
     fn mismatch() {
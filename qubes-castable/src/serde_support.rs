@@ -0,0 +1,143 @@
+//! Support code for the serde implementations generated by [`castable!`].
+//!
+//! [`castable!`]: crate::castable
+
+use core::convert::TryInto as _;
+use core::fmt;
+use core::num::{
+    NonZeroI16, NonZeroI32, NonZeroI64, NonZeroI8, NonZeroU16, NonZeroU32, NonZeroU64, NonZeroU8,
+};
+use serde::de::{self, DeserializeSeed, Deserializer, SeqAccess, Visitor};
+use serde::{Deserialize, Serialize, Serializer};
+
+/// How a field of a [`castable!`](crate::castable) struct is serialized.
+/// Byte arrays are serialized as bytes, since serde only supports arrays of
+/// up to 32 elements.  Everything else uses its own serde implementation.
+pub trait SerdeField: Sized {
+    /// Serialize the field
+    fn serialize_field<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error>;
+    /// Deserialize the field
+    fn deserialize_field<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error>;
+}
+
+macro_rules! serde_field_via_serde {
+    ($($t: ty),*$(,)?) => {
+        $(
+            impl SerdeField for $t {
+                fn serialize_field<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                    Serialize::serialize(self, serializer)
+                }
+
+                fn deserialize_field<'de, D: Deserializer<'de>>(
+                    deserializer: D,
+                ) -> Result<Self, D::Error> {
+                    Deserialize::deserialize(deserializer)
+                }
+            }
+        )*
+    }
+}
+
+serde_field_via_serde! {
+    (),
+    u8,
+    u16,
+    u32,
+    u64,
+    i8,
+    i16,
+    i32,
+    i64,
+    Option<NonZeroU8>,
+    Option<NonZeroU16>,
+    Option<NonZeroU32>,
+    Option<NonZeroU64>,
+    Option<NonZeroI8>,
+    Option<NonZeroI16>,
+    Option<NonZeroI32>,
+    Option<NonZeroI64>,
+}
+
+impl<const N: usize> SerdeField for [u8; N] {
+    fn serialize_field<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(self)
+    }
+
+    fn deserialize_field<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_bytes(ByteArray::<N>)
+    }
+}
+
+/// Visitor for a byte array of exactly `N` bytes
+struct ByteArray<const N: usize>;
+
+impl<'de, const N: usize> Visitor<'de> for ByteArray<N> {
+    type Value = [u8; N];
+
+    fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} bytes", N)
+    }
+
+    fn visit_bytes<E: de::Error>(self, v: &[u8]) -> Result<[u8; N], E> {
+        v.try_into().map_err(|_| E::invalid_length(v.len(), &self))
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<[u8; N], A::Error> {
+        let mut out = [0; N];
+        for (i, byte) in out.iter_mut().enumerate() {
+            *byte = seq
+                .next_element()?
+                .ok_or_else(|| de::Error::invalid_length(i, &self))?;
+        }
+        match seq.next_element::<de::IgnoredAny>()? {
+            None => Ok(out),
+            Some(_) => Err(de::Error::invalid_length(N + 1, &self)),
+        }
+    }
+}
+
+/// Serializes a field with [`SerdeField`]
+pub struct Ser<'a, T>(pub &'a T);
+
+impl<T: SerdeField> Serialize for Ser<'_, T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.0.serialize_field(serializer)
+    }
+}
+
+/// Deserializes a field with [`SerdeField`]
+pub struct De<T>(pub T);
+
+impl<'de, T: SerdeField> Deserialize<'de> for De<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        T::deserialize_field(deserializer).map(De)
+    }
+}
+
+/// Deserializes the name of a field, which must be one of `self.0`
+#[derive(Copy, Clone)]
+pub struct FieldName(pub &'static [&'static str]);
+
+impl<'de> DeserializeSeed<'de> for FieldName {
+    type Value = &'static str;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<&'static str, D::Error> {
+        deserializer.deserialize_identifier(self)
+    }
+}
+
+impl<'de> Visitor<'de> for FieldName {
+    type Value = &'static str;
+
+    fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("a field name")
+    }
+
+    fn visit_str<E: de::Error>(self, v: &str) -> Result<&'static str, E> {
+        self.0
+            .iter()
+            .copied()
+            .find(|name| *name == v)
+            .ok_or_else(|| E::unknown_field(v, self.0))
+    }
+}
@@ -0,0 +1,283 @@
+/*
+ * The Qubes OS Project, https://www.qubes-os.org
+ *
+ * Copyright (C) 2021  Demi Marie Obenour  <demi@invisiblethingslab.com>
+ *
+ * This program is free software; you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License
+ * as published by the Free Software Foundation; either version 2
+ * of the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301, USA.
+ *
+ */
+
+//! Keyboard state tracking.
+//!
+//! The daemon sends X11 keycodes, which are evdev keycodes plus 8 (the same
+//! convention xkbcommon uses).  [`KeyboardState`] tracks which keys are
+//! pressed and the X11 modifier mask.  With the `xkb` feature, it can also
+//! resolve keycodes to keysyms and text.  With the `proposed` feature, it
+//! also keeps the layout from the proposed `MSG_KEYBOARD_LAYOUT`, and with
+//! both features it can compile that layout into an xkb keymap.
+
+use crate::TrustedKeypress;
+use core::convert::TryFrom as _;
+use qubes_gui::{KeyEvent, KeymapNotify};
+
+#[cfg(feature = "proposed")]
+use qubes_gui::proposed::ValidKeyboardLayout;
+
+#[cfg(feature = "xkb")]
+pub use xkbcommon;
+
+/// Agent-side keyboard state
+pub struct KeyboardState {
+    keys: KeymapNotify,
+    modifiers: u32,
+    #[cfg(feature = "proposed")]
+    layout: Option<ValidKeyboardLayout>,
+    #[cfg(feature = "xkb")]
+    xkb: Option<xkbcommon::xkb::State>,
+}
+
+impl core::fmt::Debug for KeyboardState {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("KeyboardState")
+            .field("pressed", &self.keys.pressed().count())
+            .field("modifiers", &self.modifiers)
+            .finish()
+    }
+}
+
+impl Default for KeyboardState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl KeyboardState {
+    /// No keys pressed and no modifiers active
+    pub fn new() -> Self {
+        Self {
+            keys: KeymapNotify::default(),
+            modifiers: 0,
+            #[cfg(feature = "proposed")]
+            layout: None,
+            #[cfg(feature = "xkb")]
+            xkb: None,
+        }
+    }
+
+    /// Like [`KeyboardState::new`], but also track xkb state for `keymap`, so
+    /// that [`KeyboardState::keysym`] and [`KeyboardState::utf8`] work.
+    #[cfg(feature = "xkb")]
+    pub fn with_xkb(keymap: &xkbcommon::xkb::Keymap) -> Self {
+        Self {
+            xkb: Some(xkbcommon::xkb::State::new(keymap)),
+            ..Self::new()
+        }
+    }
+
+    /// Track xkb state for `keymap` from now on, replacing any previous
+    /// keymap.  Keys that are pressed stay pressed in the new state.
+    #[cfg(feature = "xkb")]
+    pub fn set_keymap(&mut self, keymap: &xkbcommon::xkb::Keymap) {
+        use xkbcommon::xkb::{KeyDirection, Keycode, State};
+        let mut state = State::new(keymap);
+        for keycode in self.keys.pressed() {
+            state.update_key(Keycode::new(keycode.into()), KeyDirection::Down);
+        }
+        self.xkb = Some(state)
+    }
+
+    /// Handle a proposed `MSG_KEYBOARD_LAYOUT` from the daemon, remembering
+    /// the layout.  This does not change the xkb keymap; see
+    /// [`KeyboardState::set_xkb_layout`] for that.
+    #[cfg(feature = "proposed")]
+    pub fn set_layout(&mut self, layout: &ValidKeyboardLayout) {
+        self.layout = Some(*layout)
+    }
+
+    /// Like [`KeyboardState::set_layout`], but also compile the layout in
+    /// `context` and track xkb state for the result.  Returns `false`, and
+    /// changes nothing, if xkb cannot compile the layout.
+    #[cfg(all(feature = "proposed", feature = "xkb"))]
+    pub fn set_xkb_layout(
+        &mut self,
+        context: &xkbcommon::xkb::Context,
+        layout: &ValidKeyboardLayout,
+    ) -> bool {
+        use xkbcommon::xkb::{Keymap, KEYMAP_COMPILE_NO_FLAGS};
+        let options = match layout.options() {
+            "" => None,
+            options => Some(options.into()),
+        };
+        let keymap = Keymap::new_from_names(
+            context,
+            layout.rules(),
+            layout.model(),
+            layout.layout(),
+            layout.variant(),
+            options,
+            KEYMAP_COMPILE_NO_FLAGS,
+        );
+        match keymap {
+            Some(keymap) => {
+                self.set_keymap(&keymap);
+                self.set_layout(layout);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// The layout from the most recent `MSG_KEYBOARD_LAYOUT`, if any
+    #[cfg(feature = "proposed")]
+    pub fn layout(&self) -> Option<&ValidKeyboardLayout> {
+        self.layout.as_ref()
+    }
+
+    fn update(&mut self, keycode: u32, event: KeyEvent) {
+        let keycode = match u8::try_from(keycode) {
+            Ok(keycode) => keycode,
+            Err(_) => return,
+        };
+        let pressed = event == KeyEvent::Press;
+        if self.keys.is_pressed(keycode) == pressed {
+            return;
+        }
+        if pressed {
+            self.keys.set(keycode)
+        } else {
+            self.keys.clear(keycode)
+        }
+        #[cfg(feature = "xkb")]
+        if let Some(state) = self.xkb.as_mut() {
+            use xkbcommon::xkb::{KeyDirection, Keycode};
+            let direction = if pressed {
+                KeyDirection::Down
+            } else {
+                KeyDirection::Up
+            };
+            state.update_key(Keycode::new(keycode.into()), direction);
+        }
+    }
+
+    /// Handle a `MSG_KEYPRESS` from the daemon.  The modifier mask is taken
+    /// from the event, which reports the state *before* the key changed.
+    pub fn keypress(&mut self, keypress: &TrustedKeypress) {
+        self.modifiers = keypress.state();
+        self.update(keypress.keycode(), keypress.event())
+    }
+
+    /// Handle a `MSG_KEYMAP_NOTIFY` from the daemon, which the daemon sends
+    /// when a window gains focus.  `f` is called with a synthetic event for
+    /// every key whose state changed, in increasing order of keycode, after
+    /// that change has been applied.
+    pub fn sync(&mut self, keymap: &KeymapNotify, mut f: impl FnMut(KeyEvent, u32)) {
+        let old = self.keys;
+        for keypress in old.diff(keymap) {
+            let event = if keypress.ty == qubes_gui::EV_KEY_PRESS {
+                KeyEvent::Press
+            } else {
+                KeyEvent::Release
+            };
+            self.update(keypress.keycode, event);
+            f(event, keypress.keycode)
+        }
+    }
+
+    /// Is the key with X11 keycode `keycode` pressed?
+    pub fn is_pressed(&self, keycode: u8) -> bool {
+        self.keys.is_pressed(keycode)
+    }
+
+    /// Iterate over all pressed keys
+    pub fn pressed(&self) -> impl Iterator<Item = u8> + '_ {
+        self.keys.pressed()
+    }
+
+    /// The X11 modifier mask reported by the most recent key event
+    pub fn modifiers(&self) -> u32 {
+        self.modifiers
+    }
+
+    /// The keysym that `keycode` currently produces, or `None` if xkb state
+    /// is not being tracked
+    #[cfg(feature = "xkb")]
+    pub fn keysym(&self, keycode: u32) -> Option<xkbcommon::xkb::Keysym> {
+        let state = self.xkb.as_ref()?;
+        Some(state.key_get_one_sym(xkbcommon::xkb::Keycode::new(keycode)))
+    }
+
+    /// The text that `keycode` currently produces, or `None` if xkb state is
+    /// not being tracked
+    #[cfg(feature = "xkb")]
+    pub fn utf8(&self, keycode: u32) -> Option<std::string::String> {
+        let state = self.xkb.as_ref()?;
+        Some(state.key_get_utf8(xkbcommon::xkb::Keycode::new(keycode)))
+    }
+
+    /// The effective xkb modifier mask, or `None` if xkb state is not being
+    /// tracked
+    #[cfg(feature = "xkb")]
+    pub fn xkb_modifiers(&self) -> Option<u32> {
+        let state = self.xkb.as_ref()?;
+        Some(state.serialize_mods(xkbcommon::xkb::STATE_MODS_EFFECTIVE))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(ty: u32, keycode: u32) -> TrustedKeypress {
+        TrustedKeypress::validate(&qubes_gui::Keypress {
+            ty,
+            keycode,
+            state: qubes_gui::x11::SHIFT_MASK,
+            ..Default::default()
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn tracks_keys() {
+        let mut state = KeyboardState::new();
+        state.keypress(&key(qubes_gui::EV_KEY_PRESS, 38));
+        assert!(state.is_pressed(38));
+        assert_eq!(state.modifiers(), qubes_gui::x11::SHIFT_MASK);
+        let mut keymap = KeymapNotify::default();
+        keymap.set(50);
+        let mut events = [None; 2];
+        let mut i = 0;
+        state.sync(&keymap, |event, keycode| {
+            events[i] = Some((event, keycode));
+            i += 1
+        });
+        assert_eq!(
+            events,
+            [Some((KeyEvent::Release, 38)), Some((KeyEvent::Press, 50))]
+        );
+        assert!(state.is_pressed(50) && !state.is_pressed(38));
+    }
+
+    #[cfg(feature = "proposed")]
+    #[test]
+    fn layout() {
+        let mut state = KeyboardState::new();
+        assert!(state.layout().is_none());
+        let mut layout = qubes_gui::proposed::KeyboardLayout::default();
+        layout.layout[..2].copy_from_slice(b"fr");
+        state.set_layout(&layout.validate().unwrap());
+        assert_eq!(state.layout().map(|l| l.layout()), Some("fr"));
+    }
+}
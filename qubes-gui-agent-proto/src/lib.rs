@@ -29,6 +29,25 @@
 use core::convert::TryInto as _;
 use qubes_castable::Castable;
 
+#[cfg(feature = "xkb")]
+extern crate std;
+
+mod keyboard;
+mod modifiers;
+mod pointer;
+#[cfg(feature = "proposed")]
+mod touch;
+mod trusted;
+
+pub use keyboard::KeyboardState;
+pub use modifiers::Modifiers;
+pub use pointer::{PointerEvent, PointerState};
+#[cfg(feature = "proposed")]
+pub use touch::{TouchPoint, TouchState};
+pub use trusted::{
+    TrustedButton, TrustedCrossing, TrustedFocus, TrustedKeypress, ENTER_NOTIFY, LEAVE_NOTIFY,
+};
+
 /// Errors when parsing an agent-side Qubes OS GUI Protocol message.
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub enum Error {
@@ -49,21 +68,39 @@ pub enum Error {
         /// The type provided by the GUI daemon
         ty: u32,
     },
+    /// Invalid crossing event type
+    BadCrossing {
+        /// The type provided by the GUI daemon
+        ty: u32,
+    },
+    /// Invalid X11 event mode
+    BadMode {
+        /// The mode provided by the GUI daemon
+        mode: u32,
+    },
+    /// Invalid X11 event detail
+    BadDetail {
+        /// The detail provided by the GUI daemon
+        detail: u32,
+    },
+    /// A boolean was neither 0 nor 1
+    BadBoolean(u32),
 }
 
 /// A GUI protocol event
 #[non_exhaustive]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Event<'a> {
     /// Daemon ⇒ agent: A key has been pressed or released
-    Keypress(qubes_gui::Keypress),
+    Keypress(TrustedKeypress),
     /// Daemon ⇒ agent: A button has been pressed or released
-    Button(qubes_gui::Button),
+    Button(TrustedButton),
     /// Daemon ⇒ agent: The pointer has moved
     Motion(qubes_gui::Motion),
     /// Daemon ⇒ agent: The pointer has entered or left a window.
-    Crossing(qubes_gui::Crossing),
+    Crossing(TrustedCrossing),
     /// Daemon ⇒ agent: A window has just acquired focus.
-    Focus(qubes_gui::Focus),
+    Focus(TrustedFocus),
     /// Daemon ⇒ agent, obsolete.
     Resize(qubes_gui::Rectangle),
     /// Agent ⇒ daemon: Create a window
@@ -141,24 +178,14 @@ impl<'a> Event<'a> {
             .expect("validated by Header::validate_length()");
         let res = match ty {
             Msg::Motion => Event::Motion(Castable::from_bytes(body)),
-            Msg::Crossing => Event::Crossing(Castable::from_bytes(body)),
+            Msg::Crossing => {
+                Event::Crossing(TrustedCrossing::validate(&Castable::from_bytes(body))?)
+            }
             Msg::Close => Event::Close,
             Msg::Keypress => {
-                let keypress: qubes_gui::Keypress = Castable::from_bytes(body);
-                match keypress.ty {
-                    qubes_gui::EV_KEY_PRESS | qubes_gui::EV_KEY_RELEASE => {}
-                    ty => return Err(Error::BadKeypress { ty }),
-                }
-                Event::Keypress(keypress)
-            }
-            Msg::Button => {
-                let button: qubes_gui::Button = Castable::from_bytes(body);
-                match button.ty {
-                    qubes_gui::EV_BUTTON_PRESS | qubes_gui::EV_BUTTON_RELEASE => {}
-                    ty => return Err(Error::BadButton { ty }),
-                }
-                Event::Button(button)
+                Event::Keypress(TrustedKeypress::validate(&Castable::from_bytes(body))?)
             }
+            Msg::Button => Event::Button(TrustedButton::validate(&Castable::from_bytes(body))?),
             Msg::ClipboardReq => Event::ClipboardReq,
             Msg::ClipboardData => {
                 let untrusted_data = core::str::from_utf8(body).map_err(Error::BadUTF8)?;
@@ -167,14 +194,7 @@ impl<'a> Event<'a> {
             Msg::KeymapNotify => Event::Keymap(Castable::from_bytes(body)),
             Msg::Map => Event::Redraw(Castable::from_bytes(body)),
             Msg::Unmap => Event::Configure(Castable::from_bytes(body)),
-            Msg::Focus => {
-                let focus: qubes_gui::Focus = Castable::from_bytes(body);
-                match focus.ty {
-                    qubes_gui::EV_FOCUS_IN | qubes_gui::EV_FOCUS_OUT => {}
-                    ty => return Err(Error::BadFocus { ty }),
-                }
-                Event::Focus(focus)
-            }
+            Msg::Focus => Event::Focus(TrustedFocus::validate(&Castable::from_bytes(body))?),
             Msg::WindowFlags => Event::WindowFlags(Castable::from_bytes(body)),
             Msg::Destroy => Event::Destroy,
             // Agent ⇒ daemon messages
@@ -0,0 +1,200 @@
+/*
+ * The Qubes OS Project, https://www.qubes-os.org
+ *
+ * Copyright (C) 2021  Demi Marie Obenour  <demi@invisiblethingslab.com>
+ *
+ * This program is free software; you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License
+ * as published by the Free Software Foundation; either version 2
+ * of the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301, USA.
+ *
+ */
+
+//! Modifier state tracking.
+//!
+//! Every input event carries the X11 modifier mask, but only as of that
+//! event.  Keys released while another window had focus produce no events
+//! at all, so the mask goes stale until the next `MSG_KEYMAP_NOTIFY`.
+//! [`Modifiers`] reconciles the mask with the held keys when that arrives.
+
+use crate::{Event, TrustedButton, TrustedCrossing, TrustedKeypress};
+use qubes_gui::x11::{CONTROL_MASK, MOD1_MASK, MOD4_MASK, SHIFT_MASK};
+use qubes_gui::{KeyEvent, KeymapNotify};
+
+/// Modifiers that are reconciled against held keys
+const HELD_MASK: u32 = SHIFT_MASK | CONTROL_MASK | MOD1_MASK | MOD4_MASK;
+
+/// X11 keycodes of the modifier keys in the standard evdev keymap, and the
+/// mask bit each one sets
+const MODIFIER_KEYS: [(u8, u32); 8] = [
+    (50, SHIFT_MASK),    // Shift_L
+    (62, SHIFT_MASK),    // Shift_R
+    (37, CONTROL_MASK),  // Control_L
+    (105, CONTROL_MASK), // Control_R
+    (64, MOD1_MASK),     // Alt_L
+    (108, MOD1_MASK),    // Alt_R
+    (133, MOD4_MASK),    // Super_L
+    (134, MOD4_MASK),    // Super_R
+];
+
+/// Tracks the X11 modifier mask across the event stream
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct Modifiers {
+    mask: u32,
+    /// Bit `i` is set if `MODIFIER_KEYS[i]` is held
+    held: u8,
+}
+
+impl Modifiers {
+    /// No modifiers active
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The modifier bits of `mask` that `held` keys account for
+    fn held_mask(held: u8) -> u32 {
+        MODIFIER_KEYS
+            .iter()
+            .enumerate()
+            .filter(|&(i, _)| held & 1 << i != 0)
+            .fold(0, |mask, (_, &(_, bit))| mask | bit)
+    }
+
+    /// Handle a `MSG_KEYPRESS`.  The event reports the mask *before* the key
+    /// changed, so the effect of a modifier key on its own bit is applied
+    /// here.
+    pub fn keypress(&mut self, keypress: &TrustedKeypress) {
+        self.mask = keypress.state() & qubes_gui::x11::MODIFIER_MASK;
+        let index = MODIFIER_KEYS
+            .iter()
+            .position(|&(keycode, _)| u32::from(keycode) == keypress.keycode());
+        if let Some(i) = index {
+            let bit = MODIFIER_KEYS[i].1;
+            if keypress.event() == KeyEvent::Press {
+                self.held |= 1 << i;
+                self.mask |= bit;
+            } else {
+                self.held &= !(1 << i);
+                self.mask = self.mask & !bit | Self::held_mask(self.held) & bit;
+            }
+        }
+    }
+
+    /// Handle a `MSG_BUTTON`
+    pub fn button(&mut self, button: &TrustedButton) {
+        self.mask = button.state() & qubes_gui::x11::MODIFIER_MASK
+    }
+
+    /// Handle a `MSG_MOTION`
+    pub fn motion(&mut self, motion: &qubes_gui::Motion) {
+        self.mask = motion.state & qubes_gui::x11::MODIFIER_MASK
+    }
+
+    /// Handle a `MSG_CROSSING`
+    pub fn crossing(&mut self, crossing: &TrustedCrossing) {
+        self.mask = crossing.state() & qubes_gui::x11::MODIFIER_MASK
+    }
+
+    /// Handle a `MSG_KEYMAP_NOTIFY`.  Shift, Control, Alt, and Super are
+    /// recomputed from the modifier keys held in `keymap`, assuming the
+    /// standard evdev keymap.  Locks (such as Caps Lock) are not held keys,
+    /// so they are left alone.
+    pub fn keymap(&mut self, keymap: &KeymapNotify) {
+        self.held = MODIFIER_KEYS
+            .iter()
+            .enumerate()
+            .filter(|&(_, &(keycode, _))| keymap.is_pressed(keycode))
+            .fold(0, |held, (i, _)| held | 1 << i);
+        self.mask = self.mask & !HELD_MASK | Self::held_mask(self.held);
+    }
+
+    /// Handle any event.  Events without modifier state are ignored.
+    pub fn event(&mut self, event: &Event<'_>) {
+        match event {
+            Event::Keypress(keypress) => self.keypress(keypress),
+            Event::Button(button) => self.button(button),
+            Event::Motion(motion) => self.motion(motion),
+            Event::Crossing(crossing) => self.crossing(crossing),
+            Event::Keymap(keymap) => self.keymap(keymap),
+            _ => {}
+        }
+    }
+
+    /// The X11 modifier mask
+    pub fn mask(&self) -> u32 {
+        self.mask
+    }
+
+    /// Is Shift down?
+    pub fn shift(&self) -> bool {
+        self.mask & SHIFT_MASK != 0
+    }
+
+    /// Is Control down?
+    pub fn control(&self) -> bool {
+        self.mask & CONTROL_MASK != 0
+    }
+
+    /// Is Alt (`Mod1`) down?
+    pub fn alt(&self) -> bool {
+        self.mask & MOD1_MASK != 0
+    }
+
+    /// Is Super (`Mod4`) down?
+    pub fn super_key(&self) -> bool {
+        self.mask & MOD4_MASK != 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(ty: u32, keycode: u32, state: u32) -> TrustedKeypress {
+        TrustedKeypress::validate(&qubes_gui::Keypress {
+            ty,
+            keycode,
+            state,
+            ..Default::default()
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn modifier_keys() {
+        let mut mods = Modifiers::new();
+        mods.keypress(&key(qubes_gui::EV_KEY_PRESS, 50, 0));
+        assert!(mods.shift() && !mods.control());
+        mods.keypress(&key(qubes_gui::EV_KEY_PRESS, 62, SHIFT_MASK));
+        mods.keypress(&key(qubes_gui::EV_KEY_RELEASE, 50, SHIFT_MASK));
+        assert!(mods.shift(), "Shift_R is still held");
+        mods.keypress(&key(qubes_gui::EV_KEY_RELEASE, 62, SHIFT_MASK));
+        assert!(!mods.shift());
+        mods.keypress(&key(qubes_gui::EV_KEY_PRESS, 38, CONTROL_MASK));
+        assert!(mods.control());
+    }
+
+    #[test]
+    fn keymap_notify() {
+        let mut mods = Modifiers::new();
+        let lock = qubes_gui::x11::LOCK_MASK;
+        mods.keypress(&key(qubes_gui::EV_KEY_PRESS, 64, lock | CONTROL_MASK));
+        assert!(mods.alt() && mods.control());
+        // Focus moved away, Alt and Control were released, and Super was
+        // pressed.
+        let mut keymap = KeymapNotify::default();
+        keymap.set(133);
+        mods.event(&Event::Keymap(keymap));
+        assert_eq!(mods.mask(), lock | MOD4_MASK);
+        assert!(mods.super_key() && !mods.alt() && !mods.control());
+    }
+}
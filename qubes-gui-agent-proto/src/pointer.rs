@@ -0,0 +1,328 @@
+/*
+ * The Qubes OS Project, https://www.qubes-os.org
+ *
+ * Copyright (C) 2021  Demi Marie Obenour  <demi@invisiblethingslab.com>
+ *
+ * This program is free software; you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License
+ * as published by the Free Software Foundation; either version 2
+ * of the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301, USA.
+ *
+ */
+
+//! Pointer state tracking and gesture recognition.
+//!
+//! GUI messages carry no timestamps, so callers pass the time at which each
+//! button event was received, in milliseconds from any fixed origin.
+
+use crate::{TrustedButton, TrustedCrossing};
+use qubes_gui::x11::{BUTTON_MASK, MODIFIER_MASK};
+use qubes_gui::{ButtonEvent, Coordinates, WindowID};
+
+/// A higher-level pointer event
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum PointerEvent {
+    /// The pointer entered a window
+    Enter {
+        /// The window entered
+        window: WindowID,
+        /// Where the pointer entered
+        coordinates: Coordinates,
+    },
+    /// The pointer left a window
+    Leave {
+        /// The window left
+        window: WindowID,
+    },
+    /// A button was pressed and released without moving far
+    Click {
+        /// The window clicked in
+        window: WindowID,
+        /// X11 button number
+        button: u32,
+        /// Where the button was pressed
+        coordinates: Coordinates,
+        /// 1 for a single click, 2 for a double click, and so on
+        count: u32,
+    },
+    /// The pointer moved far enough with a button held to start a drag
+    DragStart {
+        /// The window the drag started in
+        window: WindowID,
+        /// X11 button number
+        button: u32,
+        /// Where the button was pressed
+        coordinates: Coordinates,
+    },
+    /// The button that started a drag was released
+    DragEnd {
+        /// The window the drag started in
+        window: WindowID,
+        /// X11 button number
+        button: u32,
+        /// Where the button was released
+        coordinates: Coordinates,
+    },
+}
+
+#[derive(Debug, Copy, Clone)]
+struct Press {
+    window: WindowID,
+    button: u32,
+    coordinates: Coordinates,
+    dragging: bool,
+}
+
+#[derive(Debug, Copy, Clone)]
+struct LastClick {
+    window: WindowID,
+    button: u32,
+    coordinates: Coordinates,
+    time_ms: u64,
+    count: u32,
+}
+
+/// Agent-side pointer state
+#[derive(Debug)]
+pub struct PointerState {
+    window: Option<WindowID>,
+    coordinates: Coordinates,
+    state: u32,
+    press: Option<Press>,
+    last_click: Option<LastClick>,
+    double_click_ms: u64,
+    drag_threshold: u32,
+}
+
+impl Default for PointerState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn distance(a: Coordinates, b: Coordinates) -> u32 {
+    let dx = (i64::from(a.x) - i64::from(b.x)).unsigned_abs();
+    let dy = (i64::from(a.y) - i64::from(b.y)).unsigned_abs();
+    dx.max(dy).min(u32::MAX.into()) as u32
+}
+
+impl PointerState {
+    /// The pointer is not in any window and no buttons are pressed.  Clicks
+    /// within 400ms count as a double click, and moving more than 4 pixels
+    /// with a button pressed starts a drag.
+    pub fn new() -> Self {
+        Self {
+            window: None,
+            coordinates: Coordinates::default(),
+            state: 0,
+            press: None,
+            last_click: None,
+            double_click_ms: 400,
+            drag_threshold: 4,
+        }
+    }
+
+    /// Set the maximum time between clicks of a double click
+    pub fn double_click_ms(mut self, double_click_ms: u64) -> Self {
+        self.double_click_ms = double_click_ms;
+        self
+    }
+
+    /// Set the distance (in pixels, along either axis) the pointer must move
+    /// with a button pressed to start a drag
+    pub fn drag_threshold(mut self, drag_threshold: u32) -> Self {
+        self.drag_threshold = drag_threshold;
+        self
+    }
+
+    /// Handle a `MSG_MOTION`
+    pub fn motion(&mut self, window: WindowID, motion: &qubes_gui::Motion) -> Option<PointerEvent> {
+        self.window = Some(window);
+        self.coordinates = motion.coordinates;
+        self.state = motion.state;
+        let press = self.press.as_mut()?;
+        if press.dragging || distance(press.coordinates, motion.coordinates) <= self.drag_threshold
+        {
+            return None;
+        }
+        press.dragging = true;
+        self.last_click = None;
+        Some(PointerEvent::DragStart {
+            window: press.window,
+            button: press.button,
+            coordinates: press.coordinates,
+        })
+    }
+
+    /// Handle a `MSG_BUTTON` received at `time_ms`
+    pub fn button(
+        &mut self,
+        window: WindowID,
+        button: &TrustedButton,
+        time_ms: u64,
+    ) -> Option<PointerEvent> {
+        self.window = Some(window);
+        self.coordinates = button.coordinates();
+        self.state = button.state();
+        if button.event() == ButtonEvent::Press {
+            if self.press.is_none() {
+                self.press = Some(Press {
+                    window,
+                    button: button.button(),
+                    coordinates: button.coordinates(),
+                    dragging: false,
+                })
+            }
+            return None;
+        }
+        let press = match self.press {
+            Some(press) if press.button == button.button() => press,
+            _ => return None,
+        };
+        self.press = None;
+        if press.dragging {
+            return Some(PointerEvent::DragEnd {
+                window: press.window,
+                button: press.button,
+                coordinates: button.coordinates(),
+            });
+        }
+        let count = match self.last_click {
+            Some(last)
+                if last.window == press.window
+                    && last.button == press.button
+                    && time_ms.saturating_sub(last.time_ms) <= self.double_click_ms
+                    && distance(last.coordinates, press.coordinates) <= self.drag_threshold =>
+            {
+                last.count + 1
+            }
+            _ => 1,
+        };
+        self.last_click = Some(LastClick {
+            window: press.window,
+            button: press.button,
+            coordinates: press.coordinates,
+            time_ms,
+            count,
+        });
+        Some(PointerEvent::Click {
+            window: press.window,
+            button: press.button,
+            coordinates: press.coordinates,
+            count,
+        })
+    }
+
+    /// Handle a `MSG_CROSSING`
+    pub fn crossing(
+        &mut self,
+        window: WindowID,
+        crossing: &TrustedCrossing,
+    ) -> Option<PointerEvent> {
+        self.coordinates = crossing.coordinates();
+        self.state = crossing.state();
+        if crossing.entered() {
+            self.window = Some(window);
+            Some(PointerEvent::Enter {
+                window,
+                coordinates: crossing.coordinates(),
+            })
+        } else {
+            if self.window == Some(window) {
+                self.window = None
+            }
+            Some(PointerEvent::Leave { window })
+        }
+    }
+
+    /// The window the pointer is in, if known
+    pub fn window(&self) -> Option<WindowID> {
+        self.window
+    }
+
+    /// The most recently reported pointer position, relative to
+    /// [`PointerState::window`]
+    pub fn coordinates(&self) -> Coordinates {
+        self.coordinates
+    }
+
+    /// The X11 button mask (`Button1Mask` through `Button5Mask`) from the
+    /// most recent event
+    pub fn buttons(&self) -> u32 {
+        self.state & BUTTON_MASK
+    }
+
+    /// The X11 modifier mask from the most recent event
+    pub fn modifiers(&self) -> u32 {
+        self.state & MODIFIER_MASK
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn button(ty: u32, x: i32) -> TrustedButton {
+        TrustedButton::validate(&qubes_gui::Button {
+            ty,
+            coordinates: Coordinates { x, y: 0 },
+            state: 0,
+            button: 1,
+        })
+        .unwrap()
+    }
+
+    fn click(pointer: &mut PointerState, time_ms: u64) -> Option<PointerEvent> {
+        let window = WindowID::from(1);
+        assert_eq!(
+            pointer.button(window, &button(qubes_gui::EV_BUTTON_PRESS, 0), time_ms),
+            None
+        );
+        pointer.button(window, &button(qubes_gui::EV_BUTTON_RELEASE, 0), time_ms)
+    }
+
+    fn count(event: Option<PointerEvent>) -> u32 {
+        match event {
+            Some(PointerEvent::Click { count, .. }) => count,
+            e => panic!("Expected a click, got {:?}", e),
+        }
+    }
+
+    #[test]
+    fn double_click() {
+        let mut pointer = PointerState::new();
+        assert_eq!(count(click(&mut pointer, 0)), 1);
+        assert_eq!(count(click(&mut pointer, 300)), 2);
+        assert_eq!(count(click(&mut pointer, 1000)), 1, "too slow");
+    }
+
+    #[test]
+    fn drag() {
+        let mut pointer = PointerState::new();
+        let window = WindowID::from(1);
+        pointer.button(window, &button(qubes_gui::EV_BUTTON_PRESS, 0), 0);
+        let mut motion = qubes_gui::Motion::default();
+        motion.coordinates.x = 3;
+        assert_eq!(pointer.motion(window, &motion), None, "below threshold");
+        motion.coordinates.x = 10;
+        assert!(matches!(
+            pointer.motion(window, &motion),
+            Some(PointerEvent::DragStart { .. })
+        ));
+        assert_eq!(pointer.motion(window, &motion), None, "only once");
+        assert!(matches!(
+            pointer.button(window, &button(qubes_gui::EV_BUTTON_RELEASE, 10), 0),
+            Some(PointerEvent::DragEnd { .. })
+        ));
+    }
+}
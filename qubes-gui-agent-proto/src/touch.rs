@@ -0,0 +1,151 @@
+/*
+ * The Qubes OS Project, https://www.qubes-os.org
+ *
+ * Copyright (C) 2021  Demi Marie Obenour  <demi@invisiblethingslab.com>
+ *
+ * This program is free software; you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License
+ * as published by the Free Software Foundation; either version 2
+ * of the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301, USA.
+ *
+ */
+
+//! Touch state tracking, for the proposed `MSG_TOUCH`.
+//!
+//! See [`qubes_gui::proposed`] for the rules the daemon follows.  Events that
+//! break them, such as a touch point going down in a slot that is in use,
+//! are ignored.
+
+use qubes_gui::proposed::{TouchEvent, ValidTouch, MAX_TOUCH_SLOTS};
+use qubes_gui::{Coordinates, WindowID};
+
+/// A touch point that is down
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct TouchPoint {
+    /// The slot of the touch point
+    pub slot: u32,
+    /// The window the touch point went down in
+    pub window: WindowID,
+    /// Where the touch point is, relative to [`TouchPoint::window`]
+    pub coordinates: Coordinates,
+}
+
+/// Agent-side touch state: which touch points are down, and where
+#[derive(Debug, Default)]
+pub struct TouchState {
+    slots: [Option<TouchPoint>; MAX_TOUCH_SLOTS as usize],
+}
+
+impl TouchState {
+    /// No touch points are down
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Handle a `MSG_TOUCH` sent to `window`.  Returns the touch point, as
+    /// of the event, or `None` if the event was ignored.  For
+    /// [`TouchEvent::Up`], the touch point is no longer down.
+    pub fn touch(&mut self, window: WindowID, touch: &ValidTouch) -> Option<TouchPoint> {
+        let slot = &mut self.slots[touch.slot() as usize];
+        match (touch.event(), slot.as_mut()) {
+            (TouchEvent::Down, None) => {
+                let point = TouchPoint {
+                    slot: touch.slot(),
+                    window,
+                    coordinates: touch.coordinates(),
+                };
+                *slot = Some(point);
+                Some(point)
+            }
+            (TouchEvent::Motion, Some(point)) if point.window == window => {
+                point.coordinates = touch.coordinates();
+                Some(*point)
+            }
+            (TouchEvent::Up, Some(point)) if point.window == window => {
+                point.coordinates = touch.coordinates();
+                slot.take()
+            }
+            _ => None,
+        }
+    }
+
+    /// The touch point in `slot`, if it is down
+    pub fn point(&self, slot: u32) -> Option<TouchPoint> {
+        *self.slots.get(slot as usize)?
+    }
+
+    /// The touch points that are down, in order of slot
+    pub fn points(&self) -> impl Iterator<Item = TouchPoint> + '_ {
+        self.slots.iter().flatten().copied()
+    }
+
+    /// Forget every touch point in `window`, which has been destroyed
+    pub fn remove_window(&mut self, window: WindowID) {
+        for slot in self.slots.iter_mut() {
+            if matches!(slot, Some(point) if point.window == window) {
+                *slot = None
+            }
+        }
+    }
+
+    /// Forget every touch point, as after a reconnection.  The new daemon
+    /// does not say which touch points are down.
+    pub fn reset(&mut self) {
+        self.slots = Default::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use qubes_gui::proposed::Touch;
+
+    fn touch(event: TouchEvent, slot: u32, x: i32) -> ValidTouch {
+        Touch {
+            ty: event as u32,
+            slot,
+            coordinates: Coordinates { x, y: 0 },
+        }
+        .validate()
+        .unwrap()
+    }
+
+    #[test]
+    fn slots() {
+        let mut state = TouchState::new();
+        let (one, two) = (WindowID::from(1), WindowID::from(2));
+        assert!(state.touch(one, &touch(TouchEvent::Down, 0, 5)).is_some());
+        assert!(state.touch(two, &touch(TouchEvent::Down, 3, 1)).is_some());
+        assert_eq!(
+            state.touch(one, &touch(TouchEvent::Down, 0, 9)),
+            None,
+            "slot in use"
+        );
+        let moved = state.touch(one, &touch(TouchEvent::Motion, 0, 7)).unwrap();
+        assert_eq!((moved.window, moved.coordinates.x), (one, 7));
+        assert_eq!(
+            state.touch(one, &touch(TouchEvent::Motion, 3, 7)),
+            None,
+            "another window's touch point"
+        );
+        assert!(state.points().map(|p| p.slot).eq([0, 3].iter().copied()));
+        let up = state.touch(one, &touch(TouchEvent::Up, 0, 8)).unwrap();
+        assert_eq!(up.coordinates.x, 8);
+        assert_eq!(state.point(0), None);
+        assert_eq!(state.touch(one, &touch(TouchEvent::Up, 0, 8)), None);
+        state.remove_window(two);
+        assert_eq!(state.points().count(), 0);
+        state.touch(one, &touch(TouchEvent::Down, 1, 0));
+        state.reset();
+        assert_eq!(state.point(1), None);
+    }
+}
@@ -0,0 +1,512 @@
+/*
+ * The Qubes OS Project, https://www.qubes-os.org
+ *
+ * Copyright (C) 2021  Demi Marie Obenour  <demi@invisiblethingslab.com>
+ *
+ * This program is free software; you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License
+ * as published by the Free Software Foundation; either version 2
+ * of the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301, USA.
+ *
+ */
+
+//! Validated input events.
+//!
+//! The types here can only be obtained by validating the corresponding wire
+//! struct, so code that receives one can rely on the invariants documented
+//! in `qubes-gui`.
+
+use crate::Error;
+use core::convert::TryInto as _;
+use qubes_gui::{
+    ButtonEvent, Coordinates, CrossingDetail, CrossingMode, FocusDetail, FocusEvent, KeyEvent,
+};
+
+/// X11 `EnterNotify`
+pub const ENTER_NOTIFY: u32 = 7;
+/// X11 `LeaveNotify`
+pub const LEAVE_NOTIFY: u32 = 8;
+
+/// A validated [`qubes_gui::Keypress`]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct TrustedKeypress {
+    event: KeyEvent,
+    coordinates: Coordinates,
+    state: u32,
+    keycode: u32,
+}
+
+impl TrustedKeypress {
+    /// Validate a keypress from the daemon
+    ///
+    /// # Errors
+    ///
+    /// Fails if the event type is neither [`qubes_gui::EV_KEY_PRESS`] nor
+    /// [`qubes_gui::EV_KEY_RELEASE`].
+    pub fn validate(untrusted: &qubes_gui::Keypress) -> Result<Self, Error> {
+        let event = untrusted
+            .ty
+            .try_into()
+            .map_err(|ty| Error::BadKeypress { ty })?;
+        Ok(Self {
+            event,
+            coordinates: untrusted.coordinates,
+            state: untrusted.state,
+            keycode: untrusted.keycode,
+        })
+    }
+
+    /// Was the key pressed or released?
+    pub fn event(&self) -> KeyEvent {
+        self.event
+    }
+
+    /// Coordinates of the key press
+    pub fn coordinates(&self) -> Coordinates {
+        self.coordinates
+    }
+
+    /// X11 modifier state
+    pub fn state(&self) -> u32 {
+        self.state
+    }
+
+    /// X11 key code
+    pub fn keycode(&self) -> u32 {
+        self.keycode
+    }
+}
+
+/// A validated [`qubes_gui::Button`]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct TrustedButton {
+    event: ButtonEvent,
+    coordinates: Coordinates,
+    state: u32,
+    button: u32,
+}
+
+impl TrustedButton {
+    /// Validate a button event from the daemon
+    ///
+    /// # Errors
+    ///
+    /// Fails if the event type is neither [`qubes_gui::EV_BUTTON_PRESS`] nor
+    /// [`qubes_gui::EV_BUTTON_RELEASE`].
+    pub fn validate(untrusted: &qubes_gui::Button) -> Result<Self, Error> {
+        let event = untrusted
+            .ty
+            .try_into()
+            .map_err(|ty| Error::BadButton { ty })?;
+        Ok(Self {
+            event,
+            coordinates: untrusted.coordinates,
+            state: untrusted.state,
+            button: untrusted.button,
+        })
+    }
+
+    /// Was the button pressed or released?
+    pub fn event(&self) -> ButtonEvent {
+        self.event
+    }
+
+    /// Coordinates of the button press
+    pub fn coordinates(&self) -> Coordinates {
+        self.coordinates
+    }
+
+    /// Bitmask of modifier keys
+    pub fn state(&self) -> u32 {
+        self.state
+    }
+
+    /// X11 button number
+    pub fn button(&self) -> u32 {
+        self.button
+    }
+
+    /// The button, decoded.  See [`qubes_gui::x11::MouseButton`].
+    pub fn mouse_button(&self) -> qubes_gui::x11::MouseButton {
+        self.button.into()
+    }
+}
+
+/// A validated [`qubes_gui::Focus`]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct TrustedFocus {
+    event: FocusEvent,
+    detail: FocusDetail,
+}
+
+impl TrustedFocus {
+    /// Validate a focus event from the daemon
+    ///
+    /// # Errors
+    ///
+    /// Fails if the event type is neither [`qubes_gui::EV_FOCUS_IN`] nor
+    /// [`qubes_gui::EV_FOCUS_OUT`], if the mode is not 0, or if the detail
+    /// is greater than 7.
+    pub fn validate(untrusted: &qubes_gui::Focus) -> Result<Self, Error> {
+        let event = untrusted
+            .ty
+            .try_into()
+            .map_err(|ty| Error::BadFocus { ty })?;
+        if untrusted.mode != 0 {
+            return Err(Error::BadMode {
+                mode: untrusted.mode,
+            });
+        }
+        let detail = untrusted
+            .detail
+            .try_into()
+            .map_err(|detail| Error::BadDetail { detail })?;
+        Ok(Self { event, detail })
+    }
+
+    /// Was focus gained or lost?
+    pub fn event(&self) -> FocusEvent {
+        self.event
+    }
+
+    /// The X11 event detail
+    pub fn detail(&self) -> FocusDetail {
+        self.detail
+    }
+}
+
+/// A validated [`qubes_gui::Crossing`]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct TrustedCrossing {
+    entered: bool,
+    coordinates: Coordinates,
+    state: u32,
+    mode: CrossingMode,
+    detail: CrossingDetail,
+    focus: bool,
+}
+
+impl TrustedCrossing {
+    /// Validate a crossing event from the daemon
+    ///
+    /// # Errors
+    ///
+    /// Fails if the event type is neither [`ENTER_NOTIFY`] nor
+    /// [`LEAVE_NOTIFY`], if the mode or detail is not a valid X11 value, or
+    /// if the focus is neither 0 nor 1.
+    pub fn validate(untrusted: &qubes_gui::Crossing) -> Result<Self, Error> {
+        let entered = match untrusted.ty {
+            ENTER_NOTIFY => true,
+            LEAVE_NOTIFY => false,
+            ty => return Err(Error::BadCrossing { ty }),
+        };
+        let mode = untrusted
+            .mode
+            .try_into()
+            .map_err(|mode| Error::BadMode { mode })?;
+        let detail = untrusted
+            .detail
+            .try_into()
+            .map_err(|detail| Error::BadDetail { detail })?;
+        let focus = match untrusted.focus {
+            0 => false,
+            1 => true,
+            other => return Err(Error::BadBoolean(other)),
+        };
+        Ok(Self {
+            entered,
+            coordinates: untrusted.coordinates,
+            state: untrusted.state,
+            mode,
+            detail,
+            focus,
+        })
+    }
+
+    /// Did the pointer enter (as opposed to leave) the window?
+    pub fn entered(&self) -> bool {
+        self.entered
+    }
+
+    /// Coordinates of the crossing
+    pub fn coordinates(&self) -> Coordinates {
+        self.coordinates
+    }
+
+    /// X11 state of the crossing
+    pub fn state(&self) -> u32 {
+        self.state
+    }
+
+    /// X11 mode of the crossing
+    pub fn mode(&self) -> CrossingMode {
+        self.mode
+    }
+
+    /// X11 detail of the crossing
+    pub fn detail(&self) -> CrossingDetail {
+        self.detail
+    }
+
+    /// Does the window have focus?
+    pub fn focus(&self) -> bool {
+        self.focus
+    }
+}
+
+impl From<TrustedKeypress> for qubes_gui::Keypress {
+    fn from(trusted: TrustedKeypress) -> Self {
+        Self {
+            ty: trusted.event as u32,
+            coordinates: trusted.coordinates,
+            state: trusted.state,
+            keycode: trusted.keycode,
+        }
+    }
+}
+
+impl From<TrustedButton> for qubes_gui::Button {
+    fn from(trusted: TrustedButton) -> Self {
+        Self {
+            ty: trusted.event as u32,
+            coordinates: trusted.coordinates,
+            state: trusted.state,
+            button: trusted.button,
+        }
+    }
+}
+
+impl From<TrustedFocus> for qubes_gui::Focus {
+    fn from(trusted: TrustedFocus) -> Self {
+        Self {
+            ty: trusted.event as u32,
+            mode: 0,
+            detail: trusted.detail as u32,
+        }
+    }
+}
+
+impl From<TrustedCrossing> for qubes_gui::Crossing {
+    fn from(trusted: TrustedCrossing) -> Self {
+        Self {
+            ty: if trusted.entered {
+                ENTER_NOTIFY
+            } else {
+                LEAVE_NOTIFY
+            },
+            coordinates: trusted.coordinates,
+            state: trusted.state,
+            mode: trusted.mode as u32,
+            detail: trusted.detail as u32,
+            focus: trusted.focus as u32,
+        }
+    }
+}
+
+// Validated types are serialized as the corresponding wire struct, and
+// validated again when deserialized.
+#[cfg(feature = "serde")]
+macro_rules! serde_via_wire {
+    ($(($trusted: ident, $wire: ident),)+) => {
+        $(
+            impl serde::Serialize for $trusted {
+                fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                    serde::Serialize::serialize(&qubes_gui::$wire::from(*self), serializer)
+                }
+            }
+
+            impl<'de> serde::Deserialize<'de> for $trusted {
+                fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+                    let untrusted: qubes_gui::$wire = serde::Deserialize::deserialize(deserializer)?;
+                    Self::validate(&untrusted)
+                        .map_err(|e| serde::de::Error::custom(format_args!("{:?}", e)))
+                }
+            }
+        )+
+    }
+}
+
+#[cfg(feature = "serde")]
+serde_via_wire! {
+    (TrustedKeypress, Keypress),
+    (TrustedButton, Button),
+    (TrustedFocus, Focus),
+    (TrustedCrossing, Crossing),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn to_wire() {
+        let crossing = qubes_gui::Crossing {
+            ty: LEAVE_NOTIFY,
+            mode: 2,
+            detail: 4,
+            ..Default::default()
+        };
+        let trusted = TrustedCrossing::validate(&crossing).unwrap();
+        assert_eq!(qubes_gui::Crossing::from(trusted), crossing);
+        let keypress = qubes_gui::Keypress {
+            ty: qubes_gui::EV_KEY_RELEASE,
+            keycode: 38,
+            ..Default::default()
+        };
+        let trusted = TrustedKeypress::validate(&keypress).unwrap();
+        assert_eq!(qubes_gui::Keypress::from(trusted), keypress);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde() {
+        let focus = qubes_gui::Focus {
+            ty: qubes_gui::EV_FOCUS_OUT,
+            mode: 0,
+            detail: 3,
+        };
+        let json = serde_json::to_string(&TrustedFocus::validate(&focus).unwrap()).unwrap();
+        assert_eq!(json, r#"{"ty":10,"mode":0,"detail":3}"#);
+        let trusted: TrustedFocus = serde_json::from_str(&json).unwrap();
+        assert_eq!(qubes_gui::Focus::from(trusted), focus);
+        assert!(serde_json::from_str::<TrustedFocus>(r#"{"ty":10,"mode":1,"detail":3}"#).is_err());
+    }
+
+    #[test]
+    fn focus() {
+        let mut focus = qubes_gui::Focus {
+            ty: qubes_gui::EV_FOCUS_IN,
+            mode: 0,
+            detail: 7,
+        };
+        let trusted = TrustedFocus::validate(&focus).unwrap();
+        assert_eq!(trusted.event(), FocusEvent::In);
+        assert_eq!(trusted.detail(), FocusDetail::DetailNone);
+        focus.mode = 1;
+        assert_eq!(
+            TrustedFocus::validate(&focus),
+            Err(Error::BadMode { mode: 1 })
+        );
+        focus.mode = 0;
+        focus.detail = 8;
+        assert_eq!(
+            TrustedFocus::validate(&focus),
+            Err(Error::BadDetail { detail: 8 })
+        );
+    }
+
+    #[test]
+    fn crossing() {
+        let mut crossing = qubes_gui::Crossing {
+            ty: ENTER_NOTIFY,
+            focus: 1,
+            ..Default::default()
+        };
+        let trusted = TrustedCrossing::validate(&crossing).unwrap();
+        assert!(trusted.entered() && trusted.focus());
+        assert_eq!(trusted.mode(), CrossingMode::Normal);
+        assert_eq!(trusted.detail(), CrossingDetail::Ancestor);
+        crossing.mode = 3;
+        assert_eq!(
+            TrustedCrossing::validate(&crossing),
+            Err(Error::BadMode { mode: 3 })
+        );
+        crossing.mode = qubes_gui::NOTIFY_UNGRAB;
+        crossing.detail = qubes_gui::NOTIFY_POINTER;
+        assert_eq!(
+            TrustedCrossing::validate(&crossing),
+            Err(Error::BadDetail {
+                detail: qubes_gui::NOTIFY_POINTER
+            })
+        );
+        crossing.detail = qubes_gui::NOTIFY_NONLINEAR;
+        let trusted = TrustedCrossing::validate(&crossing).unwrap();
+        assert_eq!(trusted.mode(), CrossingMode::Ungrab);
+        assert_eq!(FocusDetail::from(trusted.detail()), FocusDetail::Nonlinear);
+        crossing.focus = 2;
+        assert_eq!(
+            TrustedCrossing::validate(&crossing),
+            Err(Error::BadBoolean(2))
+        );
+        crossing.ty = qubes_gui::EV_FOCUS_IN;
+        assert_eq!(
+            TrustedCrossing::validate(&crossing),
+            Err(Error::BadCrossing {
+                ty: qubes_gui::EV_FOCUS_IN
+            })
+        );
+    }
+
+    /// Check that the specification names every field of `valid` that
+    /// `validate` checks
+    fn check_rules<T: qubes_castable::Castable>(
+        msg: qubes_gui::Msg,
+        valid: T,
+        validate: impl Fn(&T) -> bool,
+    ) {
+        assert!(validate(&valid), "{}", msg);
+        qubes_gui::spec::corrupt_each_field(&valid, &mut |path, corrupted| {
+            assert!(
+                validate(corrupted) || qubes_gui::spec::mentions(msg, path),
+                "{} rules do not mention {:?}",
+                msg,
+                path
+            )
+        });
+    }
+
+    #[test]
+    fn rules_name_checked_fields() {
+        let coordinates = Coordinates { x: 1, y: 2 };
+        check_rules(
+            qubes_gui::Msg::Keypress,
+            qubes_gui::Keypress {
+                ty: qubes_gui::EV_KEY_PRESS,
+                coordinates,
+                state: 0,
+                keycode: 38,
+            },
+            |keypress| TrustedKeypress::validate(keypress).is_ok(),
+        );
+        check_rules(
+            qubes_gui::Msg::Button,
+            qubes_gui::Button {
+                ty: qubes_gui::EV_BUTTON_PRESS,
+                coordinates,
+                state: 0,
+                button: 1,
+            },
+            |button| TrustedButton::validate(button).is_ok(),
+        );
+        check_rules(
+            qubes_gui::Msg::Focus,
+            qubes_gui::Focus {
+                ty: qubes_gui::EV_FOCUS_IN,
+                mode: 0,
+                detail: 1,
+            },
+            |focus| TrustedFocus::validate(focus).is_ok(),
+        );
+        check_rules(
+            qubes_gui::Msg::Crossing,
+            qubes_gui::Crossing {
+                ty: ENTER_NOTIFY,
+                coordinates,
+                state: 0,
+                mode: 1,
+                detail: 1,
+                focus: 1,
+            },
+            |crossing| TrustedCrossing::validate(crossing).is_ok(),
+        );
+    }
+}
@@ -0,0 +1,214 @@
+/*
+ * The Qubes OS Project, https://www.qubes-os.org
+ *
+ * Copyright (C) 2021  Demi Marie Obenour  <demi@invisiblethingslab.com>
+ *
+ * This program is free software; you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License
+ * as published by the Free Software Foundation; either version 2
+ * of the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301, USA.
+ *
+ */
+
+//! Benchmarks for the hot paths: validating headers, parsing bodies,
+//! queueing writes, and coalescing damage.
+
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, Criterion};
+use qubes_castable::Castable;
+use qubes_gui::{Coordinates, Rectangle, UntrustedHeader, WindowSize};
+use qubes_gui_connection::{Connection, Transport};
+use std::cell::Cell;
+use std::os::raw::c_int;
+use std::rc::Rc;
+use std::task::Poll;
+use vchan::{Error, Status};
+
+fn header(ty: u32, untrusted_len: usize) -> UntrustedHeader {
+    UntrustedHeader {
+        ty,
+        window: 1.into(),
+        untrusted_len: untrusted_len as u32,
+    }
+}
+
+fn validation(c: &mut Criterion) {
+    let headers = [
+        header(qubes_gui::MSG_MOTION, 20),
+        header(qubes_gui::MSG_SHMIMAGE, 16),
+        header(qubes_gui::MSG_CLIPBOARD_DATA, 1000),
+        header(qubes_gui::MSG_WINDOW_DUMP, 16 + 4 * 1024),
+        header(0x7fff_0000, 0),
+    ];
+    c.bench_function("validate_length", |b| {
+        b.iter(|| {
+            for header in &headers {
+                let _ = black_box(black_box(header).validate_length());
+            }
+        })
+    });
+}
+
+struct Accept;
+
+impl qubes_gui_daemon_proto::MessageVisitor for Accept {}
+
+fn parsing(c: &mut Criterion) {
+    let motion = qubes_gui::Motion::default();
+    let motion_header = header(qubes_gui::MSG_MOTION, motion.as_bytes().len())
+        .validate_length()
+        .unwrap()
+        .unwrap();
+    c.bench_function("parse motion (agent)", |b| {
+        b.iter(|| qubes_gui_agent_proto::Event::parse(motion_header, black_box(motion.as_bytes())))
+    });
+
+    let create = qubes_gui::Create {
+        rectangle: Rectangle {
+            top_left: Coordinates { x: 10, y: 10 },
+            size: WindowSize {
+                width: 640,
+                height: 480,
+            },
+        },
+        parent: None,
+        override_redirect: 0,
+    };
+    let create_header = header(qubes_gui::MSG_CREATE, create.as_bytes().len())
+        .validate_length()
+        .unwrap()
+        .unwrap();
+    c.bench_function("visit create (daemon)", |b| {
+        b.iter(|| {
+            qubes_gui_daemon_proto::visit(&mut Accept, create_header, black_box(create.as_bytes()))
+        })
+    });
+
+    let title = qubes_gui::WMName::new("A typical window title - Some Application").unwrap();
+    let title_header = header(qubes_gui::MSG_SET_TITLE, title.as_bytes().len())
+        .validate_length()
+        .unwrap()
+        .unwrap();
+    c.bench_function("visit set_title (daemon)", |b| {
+        b.iter(|| {
+            qubes_gui_daemon_proto::visit(&mut Accept, title_header, black_box(title.as_bytes()))
+        })
+    });
+}
+
+/// A transport that answers the version handshake, then accepts as many
+/// bytes as it has been given budget for and throws them away
+#[derive(Debug)]
+struct Sink {
+    handshake: Vec<u8>,
+    budget: Rc<Cell<usize>>,
+}
+
+impl Transport for Sink {
+    fn status(&self) -> Status {
+        Status::Connected
+    }
+    fn data_ready(&self) -> usize {
+        self.handshake.len()
+    }
+    fn buffer_space(&self) -> usize {
+        self.budget.get()
+    }
+    fn send(&mut self, buffer: &[u8]) -> Result<(), Error> {
+        self.budget.set(self.budget.get() - buffer.len());
+        Ok(())
+    }
+    fn recv(&mut self, buffer: &mut [u8]) -> Result<(), Error> {
+        let rest = self.handshake.split_off(buffer.len());
+        buffer.copy_from_slice(&self.handshake);
+        self.handshake = rest;
+        Ok(())
+    }
+    fn fd(&self) -> c_int {
+        -1
+    }
+}
+
+/// An agent connection that has finished the handshake, with `backlog`
+/// bytes already queued
+fn connected_agent(backlog: usize) -> (Connection, Rc<Cell<usize>>) {
+    let budget = Rc::new(Cell::new(usize::MAX));
+    let handshake = qubes_gui::XConfVersion {
+        version: qubes_gui::PROTOCOL_VERSION,
+        xconf: Default::default(),
+    };
+    let mut connection = Connection::agent_over(Sink {
+        handshake: handshake.as_bytes().to_vec(),
+        budget: budget.clone(),
+    });
+    while let Poll::Ready(event) = connection.read_event() {
+        event.unwrap();
+    }
+    budget.set(0);
+    connection.send_raw_bytes(&vec![0; backlog]).unwrap();
+    (connection, budget)
+}
+
+fn writes(c: &mut Criterion) {
+    const MESSAGE_SIZE: usize = 1024;
+    const MESSAGES: usize = 16;
+    let body = [b'x'; MESSAGE_SIZE - 12];
+    for &backlog in &[0, 1 << 16] {
+        c.bench_function(&format!("send 16 KiB, {} byte backlog", backlog), |b| {
+            b.iter_batched_ref(
+                || connected_agent(backlog),
+                |(connection, budget)| {
+                    budget.set(MESSAGE_SIZE * MESSAGES);
+                    for _ in 0..MESSAGES {
+                        connection
+                            .send_raw(&body, 1.into(), qubes_gui::MSG_CLIPBOARD_DATA)
+                            .unwrap()
+                    }
+                },
+                BatchSize::SmallInput,
+            )
+        });
+    }
+}
+
+/// Merge damage rectangles into their bounding box, dropping parts outside
+/// the window, as an agent does before sending `MSG_SHMIMAGE`
+fn coalesce(damage: &[Rectangle], window: WindowSize) -> Option<Rectangle> {
+    damage
+        .iter()
+        .filter_map(|r| r.clamp_to(window))
+        .try_fold(Rectangle::default(), Rectangle::union)
+}
+
+fn damage(c: &mut Criterion) {
+    let window = WindowSize {
+        width: 1920,
+        height: 1080,
+    };
+    let damage: Vec<Rectangle> = (0..256)
+        .map(|i| Rectangle {
+            top_left: Coordinates {
+                x: (i * 37) % 2000 - 40,
+                y: (i * 53) % 1100 - 10,
+            },
+            size: WindowSize {
+                width: 16 + (i as u32 * 7) % 64,
+                height: 16 + (i as u32 * 11) % 64,
+            },
+        })
+        .collect();
+    c.bench_function("coalesce 256 damage rectangles", |b| {
+        b.iter(|| coalesce(black_box(&damage), window))
+    });
+}
+
+criterion_group!(benches, validation, parsing, writes, damage);
+criterion_main!(benches);
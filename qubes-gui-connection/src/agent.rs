@@ -0,0 +1,731 @@
+/*
+ * The Qubes OS Project, https://www.qubes-os.org
+ *
+ * Copyright (C) 2021  Demi Marie Obenour  <demi@invisiblethingslab.com>
+ *
+ * This program is free software; you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License
+ * as published by the Free Software Foundation; either version 2
+ * of the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301, USA.
+ *
+ */
+
+//! A GUI agent that keeps track of its own windows.
+
+use crate::dispatch::{dispatch_event, MessageHandler};
+use crate::{replay::SessionState, Connection, Event, OutgoingMessage};
+use qubes_castable::Castable as _;
+use qubes_gui::{Coordinates, Header, Rectangle, WindowID, WindowSize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::convert::TryFrom;
+use std::io;
+use std::num::NonZeroU32;
+use std::task::Poll;
+
+pub use qubes_gui::{PendingOps, WindowInfo};
+
+/// What the agent knows about one of its windows
+#[derive(Debug)]
+struct WindowState {
+    /// Current position and size, as last sent or received
+    rectangle: Rectangle,
+    /// Parent window
+    parent: Option<NonZeroU32>,
+    /// Is this an override-redirect window?
+    override_redirect: bool,
+    /// Has the agent mapped this window?
+    mapped: bool,
+    /// Currently set [`qubes_gui::WindowFlag`]s
+    flags: u32,
+    /// Window title
+    title: String,
+    /// Was this window created with [`Agent::create_popup`]?
+    popup: bool,
+    /// Set if this window was created with [`Agent::create_dialog`]
+    dialog: Option<Dialog>,
+    /// Operations that are waiting for the daemon or the agent
+    pending: PendingOps,
+}
+
+/// A hook run when the root window changes
+type ScreenHook = Box<dyn FnMut(&qubes_gui::XConf)>;
+
+/// Hooks registered with [`Agent::on_screen_changed`]
+#[derive(Default)]
+struct ScreenHooks(Vec<ScreenHook>);
+
+impl std::fmt::Debug for ScreenHooks {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("ScreenHooks").field(&self.0.len()).finish()
+    }
+}
+
+/// What the agent knows about a dialog
+#[derive(Debug, Copy, Clone)]
+struct Dialog {
+    /// The window the dialog belongs to
+    owner: NonZeroU32,
+    /// Should the owner be left alone while the dialog is shown?
+    modal: bool,
+    /// Whether the dialog should demand attention, if that has changed and
+    /// not yet been sent
+    attention: Option<bool>,
+}
+
+/// A GUI agent.  This wraps a [`Connection`] and records the state of every
+/// window the agent creates, so that it can be inspected later and recreated
+/// automatically if the daemon restarts.
+///
+/// Events for windows that the agent has destroyed are dropped until the
+/// daemon acknowledges the destruction, as the protocol requires.  The
+/// acknowledgement itself is reported as a `MSG_DESTROY` message.
+///
+/// Popups, such as menus and tooltips, are best created with
+/// [`Agent::create_popup`], which takes care of the details the protocol
+/// requires of them.  Dialogs are created with [`Agent::create_dialog`].
+#[derive(Debug)]
+pub struct Agent {
+    connection: Connection,
+    windows: BTreeMap<NonZeroU32, WindowState>,
+    destroyed: BTreeSet<NonZeroU32>,
+    session: SessionState,
+    screen_hooks: ScreenHooks,
+}
+
+fn no_such_window(window: NonZeroU32) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("Window {} does not exist", window),
+    )
+}
+
+impl Agent {
+    /// Wrap `connection`, which must be an agent connection
+    pub fn new(connection: Connection) -> Self {
+        Self {
+            connection,
+            windows: BTreeMap::new(),
+            destroyed: BTreeSet::new(),
+            session: SessionState::new(),
+            screen_hooks: ScreenHooks::default(),
+        }
+    }
+
+    /// Send `message` to `window` and record it for replay
+    fn send<T: qubes_gui::AgentMessage>(
+        &mut self,
+        window: NonZeroU32,
+        message: &T,
+    ) -> io::Result<()> {
+        self.connection.send_agent(message, window.into())?;
+        self.session.record(window.into(), message);
+        Ok(())
+    }
+
+    /// The underlying connection.  Messages sent directly on the connection
+    /// are not tracked.
+    pub fn connection(&mut self) -> &mut Connection {
+        &mut self.connection
+    }
+
+    fn info<'a>(&'a self, id: NonZeroU32, state: &'a WindowState) -> WindowInfo<'a> {
+        WindowInfo {
+            id,
+            rectangle: state.rectangle,
+            parent: state.parent,
+            override_redirect: state.override_redirect,
+            mapped: state.mapped,
+            flags: state.flags,
+            title: &state.title,
+            hints: None,
+            popup: state.popup,
+            dialog_owner: state.dialog.map(|dialog| dialog.owner),
+            pending: PendingOps {
+                dump_ack: self.connection.outstanding_dumps(Some(id)) != 0,
+                ..state.pending
+            },
+        }
+    }
+
+    /// Iterate over all live windows, in order of window ID
+    pub fn windows(&self) -> impl Iterator<Item = WindowInfo<'_>> {
+        self.windows
+            .iter()
+            .map(move |(&id, state)| self.info(id, state))
+    }
+
+    /// Get a single window, or `None` if it does not exist
+    pub fn window(&self, window: NonZeroU32) -> Option<WindowInfo<'_>> {
+        self.windows
+            .get(&window)
+            .map(|state| self.info(window, state))
+    }
+
+    /// Has `window` been destroyed, without the daemon acknowledging it yet?
+    /// Such windows cannot be recreated.
+    pub fn destroy_pending(&self, window: NonZeroU32) -> bool {
+        self.destroyed.contains(&window)
+    }
+
+    fn state(&mut self, window: NonZeroU32) -> io::Result<&mut WindowState> {
+        self.windows
+            .get_mut(&window)
+            .ok_or_else(|| no_such_window(window))
+    }
+
+    /// Create a window
+    ///
+    /// # Errors
+    ///
+    /// Fails if the window already exists, if its destruction has not yet
+    /// been acknowledged, or if sending fails.
+    pub fn create_window(
+        &mut self,
+        window: NonZeroU32,
+        create: &qubes_gui::Create,
+    ) -> io::Result<()> {
+        if self.windows.contains_key(&window) {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("Window {} already exists", window),
+            ));
+        }
+        if self.destroyed.contains(&window) {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("Destruction of window {} not yet acknowledged", window),
+            ));
+        }
+        self.send(window, create)?;
+        self.windows.insert(
+            window,
+            WindowState {
+                rectangle: create.rectangle,
+                parent: create.parent,
+                override_redirect: create.override_redirect != 0,
+                mapped: false,
+                flags: 0,
+                title: String::new(),
+                popup: false,
+                dialog: None,
+                pending: PendingOps::default(),
+            },
+        );
+        Ok(())
+    }
+
+    /// Create a popup window, such as a menu or tooltip.  This is an
+    /// override-redirect child of `parent` that occupies `rectangle`, which
+    /// is relative to the top-left corner of `parent`.  The popup is not
+    /// shown until [`Agent::map_popup`] is called, so its contents can be
+    /// drawn first.
+    ///
+    /// The popup is destroyed when `parent` is, and when the daemon asks for
+    /// it to be closed.  In the latter case, the `MSG_CLOSE` is still
+    /// reported, and the popup is destroyed by the next call to
+    /// [`Agent::read_event`].
+    ///
+    /// # Errors
+    ///
+    /// Fails if `parent` does not exist or is being closed, if the popup
+    /// would be outside the coordinate space, if `window` cannot be created
+    /// (see [`Agent::create_window`]), or if sending fails.
+    pub fn create_popup(
+        &mut self,
+        window: NonZeroU32,
+        parent: NonZeroU32,
+        rectangle: Rectangle,
+    ) -> io::Result<()> {
+        let create = qubes_gui::Create {
+            rectangle: self.popup_rectangle(parent, rectangle)?,
+            parent: Some(parent),
+            override_redirect: 1,
+        };
+        self.create_window(window, &create)?;
+        self.state(window)?.popup = true;
+        Ok(())
+    }
+
+    /// Move and/or resize a popup.  `rectangle` is relative to the top-left
+    /// corner of its parent, as for [`Agent::create_popup`].
+    pub fn configure_popup(&mut self, window: NonZeroU32, rectangle: Rectangle) -> io::Result<()> {
+        let parent = self.popup_parent(window)?;
+        let configure = qubes_gui::Configure {
+            rectangle: self.popup_rectangle(parent, rectangle)?,
+            override_redirect: 1,
+        };
+        self.configure(window, &configure)
+    }
+
+    /// Show a popup above its parent
+    ///
+    /// # Errors
+    ///
+    /// Fails if `window` is not a popup, if its parent is not mapped, or if
+    /// sending fails.
+    pub fn map_popup(&mut self, window: NonZeroU32) -> io::Result<()> {
+        let parent = self.popup_parent(window)?;
+        self.map_transient(window, parent, 1)
+    }
+
+    /// Map `window`, which is transient for `owner`
+    fn map_transient(
+        &mut self,
+        window: NonZeroU32,
+        owner: NonZeroU32,
+        override_redirect: u32,
+    ) -> io::Result<()> {
+        if !matches!(self.windows.get(&owner), Some(state) if state.mapped) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Parent window {} is not mapped", owner),
+            ));
+        }
+        let info = qubes_gui::MapInfo {
+            transient_for: owner.get(),
+            override_redirect,
+        };
+        self.map(window, &info)
+    }
+
+    /// The parent of popup `window`
+    fn popup_parent(&self, window: NonZeroU32) -> io::Result<NonZeroU32> {
+        let state = self
+            .windows
+            .get(&window)
+            .ok_or_else(|| no_such_window(window))?;
+        match state.parent {
+            Some(parent) if state.popup => Ok(parent),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Window {} is not a popup", window),
+            )),
+        }
+    }
+
+    /// Translate `rectangle` from the coordinates of `parent` to those of
+    /// the screen
+    fn popup_rectangle(&self, parent: NonZeroU32, rectangle: Rectangle) -> io::Result<Rectangle> {
+        let state = self
+            .windows
+            .get(&parent)
+            .ok_or_else(|| no_such_window(parent))?;
+        if state.pending.close_requested {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Window {} is being closed", parent),
+            ));
+        }
+        let origin = state.rectangle.top_left;
+        match (
+            origin.x.checked_add(rectangle.top_left.x),
+            origin.y.checked_add(rectangle.top_left.y),
+        ) {
+            (Some(x), Some(y)) => Ok(Rectangle {
+                top_left: Coordinates { x, y },
+                size: rectangle.size,
+            }),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Popup position out of range",
+            )),
+        }
+    }
+
+    /// Create a dialog of size `size` that belongs to `owner`, centered over
+    /// it.  Unlike a popup, a dialog is managed by the window manager.  It is
+    /// not shown until [`Agent::map_dialog`] is called.
+    ///
+    /// `MSG_CLOSE` for the dialog is reported as for any other window, so
+    /// that the application can decide what closing it means.  The dialog is
+    /// destroyed when `owner` is.
+    ///
+    /// While a `modal` dialog is shown, [`Agent::modal_dialog`] returns it,
+    /// and applications should ignore input to `owner`.  If `owner` gets the
+    /// focus anyway, the dialog demands attention (with
+    /// [`qubes_gui::WindowFlag::DemandsAttention`]) until it gets the focus
+    /// itself.
+    ///
+    /// # Errors
+    ///
+    /// Fails if `owner` does not exist, if `window` cannot be created (see
+    /// [`Agent::create_window`]), or if sending fails.
+    pub fn create_dialog(
+        &mut self,
+        window: NonZeroU32,
+        owner: NonZeroU32,
+        size: WindowSize,
+        modal: bool,
+    ) -> io::Result<()> {
+        let outer = self
+            .windows
+            .get(&owner)
+            .ok_or_else(|| no_such_window(owner))?
+            .rectangle;
+        let center = |start: i32, outer: u32, inner: u32| {
+            let start = i64::from(start) + (i64::from(outer) - i64::from(inner)) / 2;
+            start.clamp(i32::MIN.into(), i32::MAX.into()) as i32
+        };
+        let create = qubes_gui::Create {
+            rectangle: Rectangle {
+                top_left: Coordinates {
+                    x: center(outer.top_left.x, outer.size.width, size.width),
+                    y: center(outer.top_left.y, outer.size.height, size.height),
+                },
+                size,
+            },
+            parent: None,
+            override_redirect: 0,
+        };
+        self.create_window(window, &create)?;
+        self.state(window)?.dialog = Some(Dialog {
+            owner,
+            modal,
+            attention: None,
+        });
+        Ok(())
+    }
+
+    /// Show a dialog
+    ///
+    /// # Errors
+    ///
+    /// Fails if `window` is not a dialog, if its owner is not mapped, or if
+    /// sending fails.
+    pub fn map_dialog(&mut self, window: NonZeroU32) -> io::Result<()> {
+        let state = self
+            .windows
+            .get(&window)
+            .ok_or_else(|| no_such_window(window))?;
+        match state.dialog {
+            Some(dialog) => self.map_transient(window, dialog.owner, 0),
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Window {} is not a dialog", window),
+            )),
+        }
+    }
+
+    /// The modal dialog shown for `owner`, if any.  Applications should
+    /// ignore input to `owner` while there is one.
+    pub fn modal_dialog(&self, owner: NonZeroU32) -> Option<NonZeroU32> {
+        self.windows
+            .iter()
+            .find_map(|(&id, state)| match state.dialog {
+                Some(dialog) if dialog.owner == owner && dialog.modal && state.mapped => Some(id),
+                _ => None,
+            })
+    }
+
+    /// Move and/or resize a window
+    pub fn configure(
+        &mut self,
+        window: NonZeroU32,
+        configure: &qubes_gui::Configure,
+    ) -> io::Result<()> {
+        self.state(window)?;
+        self.send(window, configure)?;
+        let state = self.state(window)?;
+        state.rectangle = configure.rectangle;
+        state.pending.configure = true;
+        Ok(())
+    }
+
+    /// Map a window
+    pub fn map(&mut self, window: NonZeroU32, info: &qubes_gui::MapInfo) -> io::Result<()> {
+        self.state(window)?;
+        self.send(window, info)?;
+        self.state(window)?.mapped = true;
+        Ok(())
+    }
+
+    /// Unmap a window
+    pub fn unmap(&mut self, window: NonZeroU32) -> io::Result<()> {
+        self.state(window)?;
+        self.send(window, &qubes_gui::Unmap {})?;
+        self.state(window)?.mapped = false;
+        Ok(())
+    }
+
+    /// Set the title of a window.  Titles longer than 127 bytes are
+    /// truncated, at a character boundary.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the title contains a NUL byte, or if sending fails.
+    pub fn set_title(&mut self, window: NonZeroU32, title: &str) -> io::Result<()> {
+        self.state(window)?;
+        let msg = qubes_gui::WMName::new(title)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))?;
+        self.send(window, &msg)?;
+        let state = self.state(window)?;
+        state.title.clear();
+        state.title.push_str(&msg.as_str_lossy());
+        Ok(())
+    }
+
+    /// Set the class of a window
+    pub fn set_class(&mut self, window: NonZeroU32, class: &qubes_gui::WMClass) -> io::Result<()> {
+        self.state(window)?;
+        self.send(window, class)
+    }
+
+    /// Set the cursor of a window
+    pub fn set_cursor(&mut self, window: NonZeroU32, cursor: &qubes_gui::Cursor) -> io::Result<()> {
+        self.state(window)?;
+        self.send(window, cursor)
+    }
+
+    /// Dock a window into the system tray.  This must be done before the
+    /// window is mapped.  See [`crate::TrayIcon`] for the rest of what tray
+    /// icons need.
+    pub fn dock(&mut self, window: NonZeroU32) -> io::Result<()> {
+        self.state(window)?;
+        self.send(window, &qubes_gui::Dock {})
+    }
+
+    /// Set and/or clear window flags
+    pub fn set_flags(
+        &mut self,
+        window: NonZeroU32,
+        flags: &qubes_gui::WindowFlags,
+    ) -> io::Result<()> {
+        self.state(window)?;
+        self.send(window, flags)?;
+        let state = self.state(window)?;
+        state.flags = (state.flags | flags.set) & !flags.unset;
+        Ok(())
+    }
+
+    /// Set the buffer of a window the legacy way, for daemons that predate
+    /// `MSG_WINDOW_DUMP`.  The buffer holds an image of size `size`,
+    /// starting `offset` bytes into its first page, and `mfns` are the
+    /// machine frame numbers of its pages, in order.
+    ///
+    /// Finding the MFNs needs a kernel interface, such as privcmd or the
+    /// u2mfn driver of PV guests.  That is out of scope for this crate,
+    /// which only sends the message: the caller must find them.
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`io::ErrorKind::InvalidInput`] if the resulting
+    /// [`qubes_gui::ShmCmd`] is not valid, such as if there are too few or
+    /// too many MFNs, and otherwise if the window does not exist or sending
+    /// fails.
+    pub fn legacy_dump(
+        &mut self,
+        window: NonZeroU32,
+        size: WindowSize,
+        offset: u32,
+        mfns: &[u32],
+    ) -> io::Result<()> {
+        self.state(window)?;
+        let cmd = qubes_gui::ShmCmd {
+            shmid: 0,
+            width: size.width,
+            height: size.height,
+            bpp: 24,
+            off: offset,
+            num_mfn: u32::try_from(mfns.len()).unwrap_or(u32::MAX),
+            domid: 0,
+        };
+        cmd.validate()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let message = OutgoingMessage::MfnDump {
+            cmd,
+            mfns: mfns.to_vec(),
+        };
+        self.connection.send_message(&message, window.into())
+    }
+
+    /// Destroy a window, after any popups and dialogs it has.  Its ID cannot
+    /// be reused until the daemon acknowledges the destruction.
+    pub fn destroy(&mut self, window: NonZeroU32) -> io::Result<()> {
+        self.state(window)?;
+        let dependents: Vec<_> = self
+            .windows
+            .iter()
+            .filter(|(_, state)| {
+                state.popup && state.parent == Some(window)
+                    || matches!(state.dialog, Some(dialog) if dialog.owner == window)
+            })
+            .map(|(&id, _)| id)
+            .collect();
+        for dependent in dependents {
+            self.destroy(dependent)?
+        }
+        self.send(window, &qubes_gui::Destroy {})?;
+        self.windows.remove(&window);
+        self.destroyed.insert(window);
+        Ok(())
+    }
+
+    /// Should a message with header `header` be reported?  Messages for
+    /// destroyed windows are not, except for the daemon’s acknowledgement.
+    fn keep_message(destroyed: &mut BTreeSet<NonZeroU32>, header: Header) -> bool {
+        match header.untrusted_window().window {
+            Some(window) if destroyed.contains(&window) => {
+                header.ty() == qubes_gui::MSG_DESTROY && destroyed.remove(&window)
+            }
+            _ => true,
+        }
+    }
+
+    /// Send what had to wait for the previous event to be released: destroy
+    /// popups the daemon has closed, and change whether dialogs demand
+    /// attention.
+    fn catch_up(&mut self) -> io::Result<()> {
+        let closed: Vec<_> = self
+            .windows
+            .iter()
+            .filter(|(_, state)| state.popup && state.pending.close_requested)
+            .map(|(&id, _)| id)
+            .collect();
+        for popup in closed {
+            // Popups of popups may already be gone
+            if self.windows.contains_key(&popup) {
+                self.destroy(popup)?
+            }
+        }
+        let attention: Vec<_> = self
+            .windows
+            .iter_mut()
+            .filter_map(|(&id, state)| Some((id, state.dialog.as_mut()?.attention.take()?)))
+            .collect();
+        for (dialog, demand) in attention {
+            let flag = qubes_gui::WindowFlag::DemandsAttention as u32;
+            let flags = if demand {
+                qubes_gui::WindowFlags {
+                    set: flag,
+                    unset: 0,
+                }
+            } else {
+                qubes_gui::WindowFlags {
+                    set: 0,
+                    unset: flag,
+                }
+            };
+            self.set_flags(dialog, &flags)?
+        }
+        Ok(())
+    }
+
+    /// `window` has gained the focus.  A dialog that demands attention stops
+    /// doing so, and the modal dialogs of an owner start.
+    fn observe_focus(windows: &mut BTreeMap<NonZeroU32, WindowState>, window: NonZeroU32) {
+        let flag = qubes_gui::WindowFlag::DemandsAttention as u32;
+        for (&id, state) in windows.iter_mut() {
+            let demanding = state.flags & flag != 0;
+            let (mapped, dialog) = match state.dialog.as_mut() {
+                Some(dialog) => (state.mapped, dialog),
+                None => continue,
+            };
+            if id == window && demanding {
+                dialog.attention = Some(false)
+            } else if dialog.owner == window && dialog.modal && mapped && !demanding {
+                dialog.attention = Some(true)
+            }
+        }
+    }
+
+    /// Update window state from a message sent by the daemon
+    fn observe_windows(
+        windows: &mut BTreeMap<NonZeroU32, WindowState>,
+        header: Header,
+        body: &[u8],
+    ) {
+        if let (qubes_gui::MSG_FOCUS, Some(window)) =
+            (header.ty(), header.untrusted_window().window)
+        {
+            if qubes_gui::Focus::from_bytes(body).ty == qubes_gui::EV_FOCUS_IN {
+                Self::observe_focus(windows, window)
+            }
+            return;
+        }
+        let state = match header.untrusted_window() {
+            WindowID {
+                window: Some(window),
+            } => match windows.get_mut(&window) {
+                Some(state) => state,
+                None => return,
+            },
+            WindowID { window: None } => return,
+        };
+        match header.ty() {
+            qubes_gui::MSG_CONFIGURE => {
+                let configure = qubes_gui::Configure::from_bytes(body);
+                state.rectangle = configure.rectangle;
+                state.pending.configure = false;
+            }
+            qubes_gui::MSG_WINDOW_FLAGS => {
+                let flags = qubes_gui::WindowFlags::from_bytes(body);
+                state.flags = (state.flags | flags.set) & !flags.unset;
+            }
+            qubes_gui::MSG_CLOSE => state.pending.close_requested = true,
+            _ => {}
+        }
+    }
+
+    /// Register `hook` to be run when the root window changes, so that
+    /// windows can be laid out again.  The hook runs before
+    /// [`Event::ScreenChanged`] is reported.
+    pub fn on_screen_changed(&mut self, hook: impl FnMut(&qubes_gui::XConf) + 'static) {
+        self.screen_hooks.0.push(Box::new(hook))
+    }
+
+    /// Like [`Connection::read_event`], but also updates the state of the
+    /// agent’s windows.  After a reconnection, all windows are recreated
+    /// before [`Event::Reconnected`] is returned, as the new daemon does not
+    /// know about them.
+    pub fn read_event(&mut self) -> Poll<io::Result<Event<'_>>> {
+        if let Err(e) = self.catch_up() {
+            return Poll::Ready(Err(e));
+        }
+        let (windows, session) = (&mut self.windows, &self.session);
+        let destroyed = &mut self.destroyed;
+        let replay = |connection: &mut Connection| session.replay(connection);
+        let keep = |header| Self::keep_message(destroyed, header);
+        match self.connection.read_event_with(replay, keep) {
+            Poll::Ready(Ok(Event::Message(buffer))) => {
+                let (header, body) = (buffer.hdr(), buffer.body());
+                Self::observe_windows(windows, header, body);
+                Poll::Ready(Ok(Event::Message(buffer)))
+            }
+            Poll::Ready(Ok(Event::Reconnected(xconf))) => {
+                self.destroyed.clear();
+                for state in windows.values_mut() {
+                    state.pending = PendingOps::default()
+                }
+                Poll::Ready(Ok(Event::Reconnected(xconf)))
+            }
+            Poll::Ready(Ok(Event::ScreenChanged(xconf))) => {
+                for hook in self.screen_hooks.0.iter_mut() {
+                    hook(&xconf)
+                }
+                Poll::Ready(Ok(Event::ScreenChanged(xconf)))
+            }
+            other => other,
+        }
+    }
+
+    /// Like [`crate::dispatch`], but also updates the state of the agent’s
+    /// windows.
+    pub fn dispatch<H: MessageHandler + ?Sized>(&mut self, handler: &mut H) -> io::Result<()> {
+        loop {
+            match self.read_event() {
+                Poll::Pending => break Ok(()),
+                Poll::Ready(Err(e)) => break Err(e),
+                Poll::Ready(Ok(event)) => dispatch_event(handler, event),
+            }
+        }
+    }
+}
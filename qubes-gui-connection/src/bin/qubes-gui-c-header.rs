@@ -0,0 +1,48 @@
+/*
+ * The Qubes OS Project, https://www.qubes-os.org
+ *
+ * Copyright (C) 2021  Demi Marie Obenour  <demi@invisiblethingslab.com>
+ *
+ * This program is free software; you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License
+ * as published by the Free Software Foundation; either version 2
+ * of the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301, USA.
+ *
+ */
+
+//! Print a C header that checks `qubes-gui-protocol.h` against the Rust
+//! message definitions.
+
+const USAGE: &str = "\
+Usage: qubes-gui-c-header
+
+Print a C header of static assertions on the size of every message struct and
+the offset of every member, generated from the Rust definitions.  Include it
+after qubes-gui-protocol.h; it compiles only if both agree on the wire layout.
+";
+
+fn main() {
+    match std::env::args().nth(1).as_deref() {
+        None => {}
+        Some("--help") => return print!("{}", USAGE),
+        Some(arg) => {
+            eprintln!(
+                "qubes-gui-c-header: unexpected argument {}\n\n{}",
+                arg, USAGE
+            );
+            std::process::exit(2)
+        }
+    }
+    let mut header = String::new();
+    qubes_gui::c_header::write(&mut header).expect("formatting to a String cannot fail");
+    print!("{}", header)
+}
@@ -0,0 +1,165 @@
+/*
+ * The Qubes OS Project, https://www.qubes-os.org
+ *
+ * Copyright (C) 2021  Demi Marie Obenour  <demi@invisiblethingslab.com>
+ *
+ * This program is free software; you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License
+ * as published by the Free Software Foundation; either version 2
+ * of the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301, USA.
+ *
+ */
+
+//! Run the conformance scenarios against a GUI protocol implementation and
+//! print a pass/fail report.
+
+use qubes_gui_connection::conformance::{AgentHarness, DaemonHarness, Report};
+use qubes_gui_connection::{SocketTransport, Transport, VchanTransport};
+use std::io;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::time::Duration;
+
+const USAGE: &str = "\
+Usage: qubes-gui-conformance agent (--domain ID | --socket PATH) [--timeout SECS]
+       qubes-gui-conformance daemon (--domain ID | --socket PATH) [--timeout SECS]
+
+Test a GUI agent by acting as a scripted daemon, or a GUI daemon by acting as
+a hostile agent.  Each scenario uses a new connection, so the implementation
+under test must reconnect (or be restarted) between scenarios.
+
+Options:
+  --domain ID     Talk to the implementation in domain ID over a vchan
+  --socket PATH   For agents, listen on the Unix socket PATH and accept the
+                  agent there; for daemons, connect to a daemon listening there
+  --timeout SECS  How long each scenario may take (default: 5)
+  --help          Print this message
+
+Exits with status 0 if no scenario failed, and 1 otherwise.
+";
+
+#[derive(Debug, PartialEq)]
+enum Role {
+    Agent,
+    Daemon,
+}
+
+#[derive(Debug)]
+enum Address {
+    Domain(u16),
+    Socket(String),
+}
+
+#[derive(Debug)]
+struct Options {
+    role: Role,
+    address: Address,
+    timeout: Duration,
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Options, String> {
+    let role = match args.next().as_deref() {
+        Some("agent") => Role::Agent,
+        Some("daemon") => Role::Daemon,
+        _ => return Err("expected `agent` or `daemon`".to_owned()),
+    };
+    let mut address = None;
+    let mut timeout = Duration::from_secs(5);
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or(format!("{} needs a value", arg));
+        match &*arg {
+            "--domain" => {
+                let domain = value()?.parse().map_err(|_| "bad domain ID")?;
+                address = Some(Address::Domain(domain))
+            }
+            "--socket" => address = Some(Address::Socket(value()?)),
+            "--timeout" => {
+                let secs = value()?.parse().map_err(|_| "bad timeout")?;
+                timeout = Duration::from_secs(secs)
+            }
+            _ => return Err(format!("unexpected argument {}", arg)),
+        }
+    }
+    Ok(Options {
+        role,
+        address: address.ok_or("--domain or --socket is required")?,
+        timeout,
+    })
+}
+
+fn test_agent(options: &Options) -> io::Result<Report> {
+    Ok(match &options.address {
+        Address::Domain(domain) => AgentHarness::new(|| {
+            Ok(Box::new(VchanTransport::daemon(*domain)?) as Box<dyn Transport>)
+        })
+        .timeout(options.timeout)
+        .run(),
+        Address::Socket(path) => {
+            let listener = UnixListener::bind(path)?;
+            AgentHarness::new(|| {
+                eprintln!("Waiting for the agent to connect to {}", path);
+                let (stream, _) = listener.accept()?;
+                Ok(Box::new(SocketTransport::new(stream)?) as Box<dyn Transport>)
+            })
+            .timeout(options.timeout)
+            .run()
+        }
+    })
+}
+
+fn test_daemon(options: &Options) -> Report {
+    match &options.address {
+        Address::Domain(domain) => DaemonHarness::new(|| {
+            eprintln!("Waiting for the daemon in domain {} to connect", domain);
+            Ok(Box::new(VchanTransport::agent(*domain)?) as Box<dyn Transport>)
+        })
+        .timeout(options.timeout)
+        .run(),
+        Address::Socket(path) => DaemonHarness::new(|| {
+            let stream = UnixStream::connect(path)?;
+            Ok(Box::new(SocketTransport::new(stream)?) as Box<dyn Transport>)
+        })
+        .timeout(options.timeout)
+        .run(),
+    }
+}
+
+fn run(options: &Options) -> io::Result<bool> {
+    let report = match options.role {
+        Role::Agent => test_agent(options)?,
+        Role::Daemon => test_daemon(options),
+    };
+    println!("{}", report);
+    Ok(report.passed())
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.iter().any(|arg| arg == "--help") {
+        print!("{}", USAGE);
+        return;
+    }
+    let options = match parse_args(args.into_iter()) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("qubes-gui-conformance: {}\n\n{}", e, USAGE);
+            std::process::exit(2)
+        }
+    };
+    match run(&options) {
+        Ok(true) => {}
+        Ok(false) => std::process::exit(1),
+        Err(e) => {
+            eprintln!("qubes-gui-conformance: {}", e);
+            std::process::exit(2)
+        }
+    }
+}
@@ -0,0 +1,196 @@
+/*
+ * The Qubes OS Project, https://www.qubes-os.org
+ *
+ * Copyright (C) 2021  Demi Marie Obenour  <demi@invisiblethingslab.com>
+ *
+ * This program is free software; you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License
+ * as published by the Free Software Foundation; either version 2
+ * of the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301, USA.
+ *
+ */
+
+//! Pretty-print the messages in a capture file.
+//!
+//! Each direction of the capture is reassembled into messages, which are
+//! printed one per line with their decoded fields.  Clipboard contents are
+//! redacted unless `--show-clipboard` is passed.
+
+use qubes_gui_connection::capture_file::{CaptureReader, Direction};
+use qubes_gui_connection::decode::{Peer, Reassembler};
+use std::io::{self, Read, Write};
+use std::time::Duration;
+
+const USAGE: &str = "\
+Usage: qubes-gui-inspect [OPTIONS] [FILE]
+
+Pretty-print the messages in a GUI protocol capture.  Reads standard input if
+FILE is omitted or is `-`.
+
+Options:
+  --side agent|daemon  Which side made the capture (default: agent)
+  --show-clipboard     Print clipboard contents instead of redacting them
+  --help               Print this message
+";
+
+/// Which side of the connection made the capture
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Side {
+    Agent,
+    Daemon,
+}
+
+#[derive(Debug)]
+struct Options {
+    side: Side,
+    show_clipboard: bool,
+    path: Option<String>,
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Options, String> {
+    let mut options = Options {
+        side: Side::Agent,
+        show_clipboard: false,
+        path: None,
+    };
+    while let Some(arg) = args.next() {
+        match &*arg {
+            "--side" => {
+                options.side = match args.next().as_deref() {
+                    Some("agent") => Side::Agent,
+                    Some("daemon") => Side::Daemon,
+                    _ => return Err("--side must be `agent` or `daemon`".to_owned()),
+                }
+            }
+            "--show-clipboard" => options.show_clipboard = true,
+            "-" => options.path = None,
+            _ if arg.starts_with('-') => return Err(format!("unknown option {}", arg)),
+            _ if options.path.is_some() => return Err("too many arguments".to_owned()),
+            _ => options.path = Some(arg),
+        }
+    }
+    Ok(options)
+}
+
+fn inspect(input: impl Read, options: &Options, out: &mut impl Write) -> io::Result<()> {
+    let mut agent = Reassembler::new(Peer::Agent);
+    let mut daemon = Reassembler::new(Peer::Daemon);
+    for record in CaptureReader::new(input)? {
+        let record = record?;
+        let stream = match (options.side, record.direction) {
+            (Side::Agent, Direction::Sent) | (Side::Daemon, Direction::Received) => &mut agent,
+            (Side::Agent, Direction::Received) | (Side::Daemon, Direction::Sent) => &mut daemon,
+        };
+        let prefix = timestamp(record.timestamp, stream.peer());
+        let mut res = Ok(());
+        stream.feed(&record.data, |item| {
+            if res.is_ok() {
+                res = writeln!(out, "{} {}", prefix, item.describe(options.show_clipboard))
+            }
+        });
+        res?
+    }
+    for stream in [&agent, &daemon] {
+        if stream.pending() != 0 {
+            writeln!(
+                out,
+                "{} bytes from {} left over at end of capture",
+                stream.pending(),
+                stream.peer().name()
+            )?
+        }
+    }
+    Ok(())
+}
+
+fn timestamp(timestamp: Duration, peer: Peer) -> String {
+    format!(
+        "{:6}.{:06} {:6}",
+        timestamp.as_secs(),
+        timestamp.subsec_micros(),
+        peer.name()
+    )
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.iter().any(|arg| arg == "--help") {
+        print!("{}", USAGE);
+        return;
+    }
+    let options = match parse_args(args.into_iter()) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("qubes-gui-inspect: {}\n\n{}", e, USAGE);
+            std::process::exit(2)
+        }
+    };
+    let stdout = io::stdout();
+    let mut out = io::BufWriter::new(stdout.lock());
+    let res = match &options.path {
+        Some(path) => std::fs::File::open(path)
+            .and_then(|file| inspect(io::BufReader::new(file), &options, &mut out)),
+        None => inspect(io::stdin().lock(), &options, &mut out),
+    };
+    if let Err(e) = res.and_then(|()| out.flush()) {
+        eprintln!("qubes-gui-inspect: {}", e);
+        std::process::exit(1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use qubes_castable::Castable;
+    use qubes_gui::UntrustedHeader;
+    use qubes_gui_connection::capture_file::CaptureWriter;
+
+    fn message(ty: u32, window: u32, body: &[u8]) -> Vec<u8> {
+        let header = UntrustedHeader {
+            ty,
+            window: window.into(),
+            untrusted_len: body.len() as u32,
+        };
+        [header.as_bytes(), body].concat()
+    }
+
+    #[test]
+    fn reassembles_and_redacts() {
+        let title = qubes_gui::WMName::new("hi").unwrap();
+        let mut agent = qubes_gui::PROTOCOL_VERSION.as_bytes().to_vec();
+        agent.extend(message(qubes_gui::MSG_SET_TITLE, 3, title.as_bytes()));
+        agent.extend(message(qubes_gui::MSG_CLIPBOARD_DATA, 0, b"secret"));
+        let mut writer = CaptureWriter::new(vec![]).unwrap();
+        let (first, second) = agent.split_at(10);
+        for part in [first, second] {
+            writer
+                .write_record(Direction::Sent, Duration::from_millis(1), part)
+                .unwrap();
+        }
+        let capture = writer.into_inner();
+
+        let options = parse_args(std::iter::empty()).unwrap();
+        let mut out = vec![];
+        inspect(&capture[..], &options, &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let lines: Vec<_> = out.lines().collect();
+        assert_eq!(lines.len(), 3, "{}", out);
+        assert!(lines[0].ends_with("agent  VERSION 1.7"), "{}", out);
+        assert!(
+            lines[1].ends_with("agent  WMNAME window 3 \"hi\""),
+            "{}",
+            out
+        );
+        assert!(lines[2].contains("<6 bytes redacted>"), "{}", out);
+        assert!(!out.contains("secret"));
+    }
+}
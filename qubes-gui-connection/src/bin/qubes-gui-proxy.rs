@@ -0,0 +1,184 @@
+/*
+ * The Qubes OS Project, https://www.qubes-os.org
+ *
+ * Copyright (C) 2021  Demi Marie Obenour  <demi@invisiblethingslab.com>
+ *
+ * This program is free software; you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License
+ * as published by the Free Software Foundation; either version 2
+ * of the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301, USA.
+ *
+ */
+
+//! A live protocol analyzer.
+//!
+//! Connects to an agent as if it were the daemon, and to a daemon (over a
+//! vchan or a Unix socket) as if it were the agent, then forwards everything
+//! between them, printing each message and any protocol violations.
+
+use qubes_gui_connection::{
+    poll_fds, CaptureTransport, Proxy, SocketTransport, Transport, VchanTransport,
+};
+use std::io::{self, Write};
+use std::os::unix::net::UnixStream;
+use std::time::Instant;
+use vchan::Status;
+
+const USAGE: &str = "\
+Usage: qubes-gui-proxy --agent-domain ID (--daemon-domain ID | --daemon-socket PATH) [OPTIONS]
+
+Forward GUI protocol traffic between an agent and a daemon, printing every
+message in both directions.
+
+Options:
+  --agent-domain ID     Domain of the agent to connect to
+  --daemon-domain ID    Domain of the daemon, which must connect to us
+  --daemon-socket PATH  Unix socket on which a daemon is listening
+  --capture FILE        Also write a capture (made from the daemon's side)
+  --show-clipboard      Print clipboard contents instead of redacting them
+  --help                Print this message
+";
+
+#[derive(Debug)]
+enum DaemonAddress {
+    Domain(u16),
+    Socket(String),
+}
+
+#[derive(Debug)]
+struct Options {
+    agent_domain: u16,
+    daemon: DaemonAddress,
+    capture: Option<String>,
+    show_clipboard: bool,
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Options, String> {
+    let mut agent_domain = None;
+    let mut daemon = None;
+    let mut capture = None;
+    let mut show_clipboard = false;
+    let domain = |arg: Option<String>| {
+        arg.and_then(|s| s.parse::<u16>().ok())
+            .ok_or_else(|| "domain IDs must be integers".to_owned())
+    };
+    while let Some(arg) = args.next() {
+        match &*arg {
+            "--agent-domain" => agent_domain = Some(domain(args.next())?),
+            "--daemon-domain" => daemon = Some(DaemonAddress::Domain(domain(args.next())?)),
+            "--daemon-socket" => {
+                let path = args.next().ok_or("--daemon-socket needs a path")?;
+                daemon = Some(DaemonAddress::Socket(path))
+            }
+            "--capture" => capture = Some(args.next().ok_or("--capture needs a path")?),
+            "--show-clipboard" => show_clipboard = true,
+            _ => return Err(format!("unexpected argument {}", arg)),
+        }
+    }
+    Ok(Options {
+        agent_domain: agent_domain.ok_or("--agent-domain is required")?,
+        daemon: daemon.ok_or("--daemon-domain or --daemon-socket is required")?,
+        capture,
+        show_clipboard,
+    })
+}
+
+fn run(options: &Options) -> io::Result<()> {
+    let daemon: Box<dyn Transport> = match &options.daemon {
+        DaemonAddress::Socket(path) => Box::new(SocketTransport::new(UnixStream::connect(path)?)?),
+        DaemonAddress::Domain(domain) => {
+            let mut daemon = VchanTransport::agent(*domain)?;
+            eprintln!("Waiting for the daemon in domain {} to connect", domain);
+            while daemon.status() == Status::Waiting {
+                poll_fds(&[(daemon.fd(), false)], None)?;
+                daemon.wait()
+            }
+            Box::new(daemon)
+        }
+    };
+    let agent = VchanTransport::daemon(options.agent_domain)?;
+    let agent: Box<dyn Transport> = match &options.capture {
+        Some(path) => Box::new(CaptureTransport::new(
+            agent,
+            io::BufWriter::new(std::fs::File::create(path)?),
+        )?),
+        None => Box::new(agent),
+    };
+    let mut proxy = Proxy::new(agent, daemon);
+    let start = Instant::now();
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    loop {
+        let mut res = Ok(());
+        proxy.pump(|peer, item| {
+            if res.is_ok() {
+                let t = start.elapsed();
+                res = writeln!(
+                    out,
+                    "{:6}.{:06} {:6} {}",
+                    t.as_secs(),
+                    t.subsec_micros(),
+                    peer.name(),
+                    item.describe(options.show_clipboard)
+                )
+            }
+        })?;
+        res.and_then(|()| out.flush())?;
+        if let Some(peer) = proxy.disconnected() {
+            eprintln!("The {} disconnected", peer.name());
+            return Ok(());
+        }
+        // A transport with data buffered for a peer that is not reading
+        // needs to be told when it can write more, or the proxy stalls.
+        let [agent, daemon] = proxy.fds();
+        let [agent_out, daemon_out] = proxy.wants_writable();
+        poll_fds(&[(agent, agent_out), (daemon, daemon_out)], None)?;
+        proxy.wait()
+    }
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.iter().any(|arg| arg == "--help") {
+        print!("{}", USAGE);
+        return;
+    }
+    let options = match parse_args(args.into_iter()) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("qubes-gui-proxy: {}\n\n{}", e, USAGE);
+            std::process::exit(2)
+        }
+    };
+    if let Err(e) = run(&options) {
+        eprintln!("qubes-gui-proxy: {}", e);
+        std::process::exit(1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(s: &str) -> impl Iterator<Item = String> + '_ {
+        s.split_whitespace().map(str::to_owned)
+    }
+
+    #[test]
+    fn arguments() {
+        let options = parse_args(args("--agent-domain 5 --daemon-socket /tmp/s")).unwrap();
+        assert_eq!(options.agent_domain, 5);
+        assert!(matches!(options.daemon, DaemonAddress::Socket(ref s) if s == "/tmp/s"));
+        assert!(parse_args(args("--agent-domain 5")).is_err());
+        assert!(parse_args(args("--agent-domain x --daemon-domain 0")).is_err());
+    }
+}
@@ -0,0 +1,45 @@
+/*
+ * The Qubes OS Project, https://www.qubes-os.org
+ *
+ * Copyright (C) 2021  Demi Marie Obenour  <demi@invisiblethingslab.com>
+ *
+ * This program is free software; you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License
+ * as published by the Free Software Foundation; either version 2
+ * of the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301, USA.
+ *
+ */
+
+//! Print a Markdown specification of the protocol, generated from the Rust
+//! message definitions.
+
+const USAGE: &str = "\
+Usage: qubes-gui-spec
+
+Print a Markdown specification of the Qubes OS GUI protocol: message numbers,
+direction, body layouts, length rules, and protocol versions.  It is generated
+from the Rust definitions, which are the authoritative ones.
+";
+
+fn main() {
+    match std::env::args().nth(1).as_deref() {
+        None => {}
+        Some("--help") => return print!("{}", USAGE),
+        Some(arg) => {
+            eprintln!("qubes-gui-spec: unexpected argument {}\n\n{}", arg, USAGE);
+            std::process::exit(2)
+        }
+    }
+    let mut spec = String::new();
+    qubes_gui::spec::write(&mut spec).expect("formatting to a String cannot fail");
+    print!("{}", spec)
+}
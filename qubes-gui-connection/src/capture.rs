@@ -0,0 +1,338 @@
+/*
+ * The Qubes OS Project, https://www.qubes-os.org
+ *
+ * Copyright (C) 2021  Demi Marie Obenour  <demi@invisiblethingslab.com>
+ *
+ * This program is free software; you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License
+ * as published by the Free Software Foundation; either version 2
+ * of the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301, USA.
+ *
+ */
+
+//! Recording and replaying the raw bytes of a connection.
+//!
+//! [`CaptureTransport`] wraps another [`Transport`] and writes every byte
+//! sent or received to a capture.  [`ReplayTransport`] feeds the received
+//! half of a capture back into a [`Connection`](crate::Connection), which
+//! makes it possible to reproduce protocol problems offline.  Both use the
+//! format in [`crate::capture_file`].
+
+use crate::capture_file::{CaptureReader, CaptureWriter, Direction};
+use crate::Transport;
+use std::io::{self, Read, Write};
+use std::os::raw::c_int;
+use std::time::Instant;
+use vchan::{Error, Status};
+
+/// A transport that records all traffic of another transport
+pub struct CaptureTransport<T: Transport, W: Write + std::fmt::Debug> {
+    inner: T,
+    writer: CaptureWriter<W>,
+    start: Instant,
+    error: Option<io::Error>,
+}
+
+impl<T: Transport, W: Write + std::fmt::Debug> std::fmt::Debug for CaptureTransport<T, W> {
+    /// The writer is not shown, as it may hold the captured data
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CaptureTransport")
+            .field("inner", &self.inner)
+            .field("start", &self.start)
+            .field("error", &self.error)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<T: Transport, W: Write + std::fmt::Debug> CaptureTransport<T, W> {
+    /// Record the traffic of `inner` to `writer`.  Fails if the capture
+    /// header cannot be written.
+    pub fn new(inner: T, writer: W) -> io::Result<Self> {
+        Ok(Self {
+            inner,
+            writer: CaptureWriter::new(writer)?,
+            start: Instant::now(),
+            error: None,
+        })
+    }
+
+    /// The error that stopped recording, if any.  Failing to record does not
+    /// affect the connection itself.
+    pub fn error(&self) -> Option<&io::Error> {
+        self.error.as_ref()
+    }
+
+    /// Stop recording and return the wrapped transport and the writer
+    pub fn into_inner(self) -> (T, W) {
+        (self.inner, self.writer.into_inner())
+    }
+
+    fn record(&mut self, direction: Direction, data: &[u8]) {
+        if self.error.is_some() || data.is_empty() {
+            return;
+        }
+        let res = self
+            .writer
+            .write_record(direction, self.start.elapsed(), data);
+        if let Err(e) = res {
+            self.error = Some(e)
+        }
+    }
+}
+
+impl<T: Transport, W: Write + std::fmt::Debug> Transport for CaptureTransport<T, W> {
+    fn status(&self) -> Status {
+        self.inner.status()
+    }
+    fn data_ready(&self) -> usize {
+        self.inner.data_ready()
+    }
+    fn buffer_space(&self) -> usize {
+        self.inner.buffer_space()
+    }
+    fn send(&mut self, buffer: &[u8]) -> Result<(), Error> {
+        self.inner.send(buffer)?;
+        self.record(Direction::Sent, buffer);
+        Ok(())
+    }
+    fn recv(&mut self, buffer: &mut [u8]) -> Result<(), Error> {
+        self.inner.recv(buffer)?;
+        self.record(Direction::Received, buffer);
+        Ok(())
+    }
+    fn wait(&mut self) {
+        self.inner.wait()
+    }
+    fn flush(&mut self) -> Result<bool, Error> {
+        self.inner.flush()
+    }
+    fn wants_writable(&self) -> bool {
+        self.inner.wants_writable()
+    }
+    fn fd(&self) -> c_int {
+        self.inner.fd()
+    }
+    fn reconnect(&mut self) -> Result<(), Error> {
+        self.inner.reconnect()
+    }
+}
+
+/// A transport that replays the received half of a capture.
+///
+/// Everything sent is kept and can be inspected with
+/// [`ReplayTransport::sent`].  Once all captured data has been read, the
+/// transport reports that the peer has disconnected.
+#[derive(Default)]
+pub struct ReplayTransport {
+    incoming: Vec<u8>,
+    cursor: usize,
+    sent: Vec<u8>,
+}
+
+impl std::fmt::Debug for ReplayTransport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ReplayTransport")
+            .field("incoming", &self.incoming.len())
+            .field("cursor", &self.cursor)
+            .field("sent", &self.sent.len())
+            .finish()
+    }
+}
+
+impl ReplayTransport {
+    /// Load a capture from `reader`
+    pub fn new(reader: impl Read) -> io::Result<Self> {
+        let mut incoming = vec![];
+        for record in CaptureReader::new(reader)? {
+            let record = record?;
+            if record.direction == Direction::Received {
+                incoming.extend_from_slice(&record.data)
+            }
+        }
+        Ok(Self {
+            incoming,
+            ..Self::default()
+        })
+    }
+
+    /// Everything that has been sent so far
+    pub fn sent(&self) -> &[u8] {
+        &self.sent
+    }
+}
+
+impl Transport for ReplayTransport {
+    fn status(&self) -> Status {
+        if self.data_ready() > 0 {
+            Status::Connected
+        } else {
+            Status::Disconnected
+        }
+    }
+    fn data_ready(&self) -> usize {
+        self.incoming.len() - self.cursor
+    }
+    fn buffer_space(&self) -> usize {
+        usize::MAX >> 1
+    }
+    fn send(&mut self, buffer: &[u8]) -> Result<(), Error> {
+        self.sent.extend_from_slice(buffer);
+        Ok(())
+    }
+    fn recv(&mut self, buffer: &mut [u8]) -> Result<(), Error> {
+        let end = self.cursor + buffer.len();
+        buffer.copy_from_slice(self.incoming.get(self.cursor..end).ok_or(Error::Read)?);
+        self.cursor = end;
+        Ok(())
+    }
+    fn fd(&self) -> c_int {
+        -1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Connection, Event, LoopbackTransport};
+    use qubes_castable::Castable as _;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use std::task::Poll;
+    use std::time::Duration;
+
+    /// A capture that can still be read after its transport is gone
+    #[derive(Debug, Clone, Default)]
+    struct Shared(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// The type, window, and body of the next `count` messages read from
+    /// `connection`
+    fn messages(connection: &mut Connection, count: usize) -> Vec<(u32, u32, Vec<u8>)> {
+        let mut messages = vec![];
+        while messages.len() < count {
+            match connection.read_event() {
+                Poll::Ready(Ok(Event::Message(buffer))) => {
+                    let window = buffer.hdr().untrusted_window().window;
+                    messages.push((
+                        buffer.hdr().ty(),
+                        window.map_or(0, |w| w.get()),
+                        buffer.body().to_owned(),
+                    ))
+                }
+                Poll::Ready(Ok(Event::Reconnected(_))) => {}
+                Poll::Ready(other) => panic!("unexpected {:?}", other),
+                Poll::Pending => std::thread::sleep(Duration::from_millis(1)),
+            }
+        }
+        messages
+    }
+
+    #[test]
+    fn capture_then_replay() {
+        let mut source = CaptureWriter::new(vec![]).unwrap();
+        let t = Duration::from_micros;
+        source
+            .write_record(Direction::Received, t(0), b"hello, ")
+            .unwrap();
+        source
+            .write_record(Direction::Sent, t(5), b"ignored")
+            .unwrap();
+        source
+            .write_record(Direction::Received, t(9), b"world")
+            .unwrap();
+        let source = source.into_inner();
+        let replay = ReplayTransport::new(&source[..]).unwrap();
+        assert_eq!(replay.data_ready(), 12);
+
+        let mut capture = CaptureTransport::new(replay, vec![]).unwrap();
+        let mut buf = [0u8; 7];
+        capture.recv(&mut buf).unwrap();
+        assert_eq!(&buf, b"hello, ");
+        capture.send(b"reply").unwrap();
+        capture.discard(5).unwrap();
+        assert_eq!(capture.status(), Status::Disconnected);
+        assert!(capture.recv(&mut buf[..1]).is_err());
+        assert!(capture.error().is_none());
+        let (replay, recorded) = capture.into_inner();
+        assert_eq!(replay.sent(), b"reply");
+
+        let directions: Vec<_> = CaptureReader::new(&recorded[..])
+            .unwrap()
+            .map(|r| r.unwrap().direction)
+            .collect();
+        assert_eq!(
+            directions,
+            [Direction::Received, Direction::Sent, Direction::Received]
+        );
+        let again = ReplayTransport::new(&recorded[..]).unwrap();
+        assert_eq!(again.incoming, b"hello, world");
+    }
+
+    #[test]
+    fn record_and_replay_session() {
+        let (ours, theirs) = LoopbackTransport::pair();
+        let (agent_capture, daemon_capture) = (Shared::default(), Shared::default());
+        let mut agent =
+            Connection::agent_over(CaptureTransport::new(ours, agent_capture.clone()).unwrap());
+        let mut daemon = Connection::daemon_over(
+            CaptureTransport::new(theirs, daemon_capture.clone()).unwrap(),
+            Default::default(),
+        );
+        loop {
+            let _ = daemon.read_message();
+            match agent.read_event() {
+                Poll::Ready(Ok(Event::Reconnected(_))) => break,
+                Poll::Ready(other) => panic!("unexpected {:?}", other),
+                Poll::Pending => std::thread::sleep(Duration::from_millis(1)),
+            }
+        }
+        let map_info = qubes_gui::MapInfo::default();
+        let keypress = qubes_gui::Keypress {
+            ty: qubes_gui::EV_KEY_PRESS,
+            keycode: 38,
+            ..Default::default()
+        };
+        agent
+            .send_raw(map_info.as_bytes(), 7.into(), qubes_gui::MSG_MAP)
+            .unwrap();
+        daemon
+            .send_raw(keypress.as_bytes(), 7.into(), qubes_gui::MSG_KEYPRESS)
+            .unwrap();
+        daemon
+            .send_raw(&[], 7.into(), qubes_gui::MSG_CLIPBOARD_REQ)
+            .unwrap();
+        let from_daemon = messages(&mut agent, 2);
+        let from_agent = messages(&mut daemon, 1);
+        drop((agent, daemon));
+
+        // Each side of the capture replays what that side received
+        let replay = ReplayTransport::new(&agent_capture.0.borrow()[..]).unwrap();
+        let mut agent = Connection::agent_over(replay);
+        assert_eq!(messages(&mut agent, 2), from_daemon);
+        assert!(agent.needs_reconnect(), "the whole capture was replayed");
+        let replay = ReplayTransport::new(&daemon_capture.0.borrow()[..]).unwrap();
+        let mut daemon = Connection::daemon_over(replay, Default::default());
+        assert_eq!(messages(&mut daemon, 1), from_agent);
+        assert!(daemon.needs_reconnect(), "the whole capture was replayed");
+        assert_eq!(from_agent[0].0, qubes_gui::MSG_MAP);
+        assert_eq!(from_daemon[0].2, keypress.as_bytes());
+    }
+}
@@ -0,0 +1,233 @@
+/*
+ * The Qubes OS Project, https://www.qubes-os.org
+ *
+ * Copyright (C) 2021  Demi Marie Obenour  <demi@invisiblethingslab.com>
+ *
+ * This program is free software; you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License
+ * as published by the Free Software Foundation; either version 2
+ * of the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301, USA.
+ *
+ */
+
+//! The capture file format.
+//!
+//! A capture starts with the 8-byte [`MAGIC`] followed by the format
+//! [`VERSION`] as a little-endian `u32`.  The rest of the file is a sequence
+//! of records, each of which is:
+//!
+//! - a direction byte: 0 for bytes sent, 1 for bytes received
+//! - the time since the capture started, in microseconds, as a
+//!   little-endian `u64`
+//! - the length of the data, as a little-endian `u32`
+//! - the data itself
+//!
+//! Records hold raw stream bytes, so a message may be split across several
+//! records, and one record may hold several messages.
+
+use std::convert::{TryFrom, TryInto};
+use std::io::{self, Read, Write};
+use std::time::Duration;
+
+/// The bytes every capture starts with
+pub const MAGIC: [u8; 8] = *b"QGUICAP\0";
+
+/// The version of the format written by [`CaptureWriter`]
+pub const VERSION: u32 = 1;
+
+/// The direction of a captured record, from the point of view of the side
+/// doing the capturing
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Direction {
+    /// Bytes written to the peer
+    Sent = 0,
+    /// Bytes read from the peer
+    Received = 1,
+}
+
+/// One record of a capture
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    /// Which way the bytes went
+    pub direction: Direction,
+    /// When the bytes went, relative to the start of the capture
+    pub timestamp: Duration,
+    /// The bytes themselves
+    pub data: Vec<u8>,
+}
+
+fn invalid(msg: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Writes a capture
+#[derive(Debug)]
+pub struct CaptureWriter<W: Write> {
+    writer: W,
+}
+
+impl<W: Write> CaptureWriter<W> {
+    /// Start a capture by writing the file header to `writer`
+    pub fn new(mut writer: W) -> io::Result<Self> {
+        writer.write_all(&MAGIC)?;
+        writer.write_all(&VERSION.to_le_bytes())?;
+        Ok(Self { writer })
+    }
+
+    /// Append a record.  Records longer than `u32::MAX` bytes are rejected.
+    pub fn write_record(
+        &mut self,
+        direction: Direction,
+        timestamp: Duration,
+        data: &[u8],
+    ) -> io::Result<()> {
+        let len = u32::try_from(data.len())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "record too long"))?;
+        let micros = u64::try_from(timestamp.as_micros()).unwrap_or(u64::MAX);
+        let mut header = [0u8; 13];
+        header[0] = direction as u8;
+        header[1..9].copy_from_slice(&micros.to_le_bytes());
+        header[9..].copy_from_slice(&len.to_le_bytes());
+        self.writer.write_all(&header)?;
+        self.writer.write_all(data)
+    }
+
+    /// Flush the underlying writer
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+
+    /// The underlying writer
+    pub fn get_ref(&self) -> &W {
+        &self.writer
+    }
+
+    /// Stop writing and return the underlying writer
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+/// Reads a capture.  Also an iterator over the records in it.
+#[derive(Debug)]
+pub struct CaptureReader<R: Read> {
+    reader: R,
+    version: u32,
+}
+
+impl<R: Read> CaptureReader<R> {
+    /// Read and check the file header from `reader`
+    pub fn new(mut reader: R) -> io::Result<Self> {
+        let mut header = [0u8; 12];
+        reader.read_exact(&mut header)?;
+        if header[..8] != MAGIC {
+            return Err(invalid("not a capture file"));
+        }
+        let version = u32::from_le_bytes(header[8..].try_into().unwrap());
+        if version != VERSION {
+            return Err(invalid("unsupported capture version"));
+        }
+        Ok(Self { reader, version })
+    }
+
+    /// The format version of the capture
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    /// Read the next record, or [`None`] at the end of the capture
+    pub fn next_record(&mut self) -> io::Result<Option<Record>> {
+        let mut header = [0u8; 13];
+        if self.reader.read(&mut header[..1])? == 0 {
+            return Ok(None);
+        }
+        self.reader.read_exact(&mut header[1..])?;
+        let direction = match header[0] {
+            0 => Direction::Sent,
+            1 => Direction::Received,
+            _ => return Err(invalid("bad direction in capture")),
+        };
+        let micros = u64::from_le_bytes(header[1..9].try_into().unwrap());
+        let len = u32::from_le_bytes(header[9..].try_into().unwrap()).into();
+        // Do not trust the length for the allocation size: the file may be
+        // truncated or corrupt.
+        let mut data = vec![];
+        (&mut self.reader).take(len).read_to_end(&mut data)?;
+        if (data.len() as u64) < len {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        Ok(Some(Record {
+            direction,
+            timestamp: Duration::from_micros(micros),
+            data,
+        }))
+    }
+
+    /// Stop reading and return the underlying reader
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+}
+
+impl<R: Read> Iterator for CaptureReader<R> {
+    type Item = io::Result<Record>;
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_record().transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let mut writer = CaptureWriter::new(vec![]).unwrap();
+        writer
+            .write_record(Direction::Received, Duration::from_micros(3), b"abc")
+            .unwrap();
+        writer
+            .write_record(Direction::Sent, Duration::from_secs(1), b"")
+            .unwrap();
+        let file = writer.into_inner();
+        assert_eq!(&file[..8], b"QGUICAP\0");
+
+        let records = CaptureReader::new(&file[..])
+            .unwrap()
+            .collect::<io::Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(
+            records,
+            [
+                Record {
+                    direction: Direction::Received,
+                    timestamp: Duration::from_micros(3),
+                    data: b"abc".to_vec(),
+                },
+                Record {
+                    direction: Direction::Sent,
+                    timestamp: Duration::from_secs(1),
+                    data: vec![],
+                },
+            ]
+        );
+
+        let mut truncated = CaptureReader::new(&file[..file.len() - 14]).unwrap();
+        assert!(truncated.next_record().is_err());
+    }
+
+    #[test]
+    fn bad_header() {
+        assert!(CaptureReader::new(&b"QGUICAP\0\x02\0\0\0"[..]).is_err());
+        assert!(CaptureReader::new(&b"PCAP"[..]).is_err());
+    }
+}
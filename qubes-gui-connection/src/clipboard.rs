@@ -0,0 +1,196 @@
+/*
+ * The Qubes OS Project, https://www.qubes-os.org
+ *
+ * Copyright (C) 2021  Demi Marie Obenour  <demi@invisiblethingslab.com>
+ *
+ * This program is free software; you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License
+ * as published by the Free Software Foundation; either version 2
+ * of the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301, USA.
+ *
+ */
+
+//! Matching clipboard requests with their replies.
+//!
+//! `MSG_CLIPBOARD_REQ` and `MSG_CLIPBOARD_DATA` carry no request ID, so a
+//! reply can only be matched to a request by counting: replies arrive in the
+//! order the requests were sent.  [`ClipboardTracker`] does that counting.
+//! It works on either side: a daemon tracks the requests it sent to an
+//! agent, and an agent tracks the requests it has yet to answer.
+
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// Requests that have been abandoned but not yet answered are remembered
+/// so that their late replies are not mistaken for replies to newer
+/// requests.  A peer this far behind is not going to catch up.
+const MAX_OUTSTANDING: usize = 64;
+
+/// Identifies one clipboard request
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ClipboardRequestId(u64);
+
+/// What happened to a clipboard request.  Every request gets exactly one of
+/// [`ClipboardEvent::Completed`], [`ClipboardEvent::TimedOut`], and
+/// [`ClipboardEvent::Superseded`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ClipboardEvent {
+    /// The data answers this request.  Use it.
+    Completed(ClipboardRequestId),
+    /// No data arrived in time.  The request has been abandoned.
+    TimedOut(ClipboardRequestId),
+    /// A newer request was made before data arrived.  The request has been
+    /// abandoned.
+    Superseded(ClipboardRequestId),
+    /// The data answers a request that was already abandoned.  Discard it.
+    Stale(ClipboardRequestId),
+    /// The data does not answer any request.  Discard it.
+    Unsolicited,
+}
+
+#[derive(Debug)]
+struct Request {
+    id: ClipboardRequestId,
+    deadline: Instant,
+    abandoned: bool,
+}
+
+/// Tracks outstanding clipboard requests.  At most one request is live at a
+/// time: making a new one abandons the previous one.
+#[derive(Debug)]
+pub struct ClipboardTracker {
+    timeout: Duration,
+    next_id: u64,
+    /// Requests that have not been answered, oldest first.  Only the last
+    /// one can be live.
+    outstanding: VecDeque<Request>,
+    /// Events not yet returned by [`ClipboardTracker::poll`]
+    events: VecDeque<ClipboardEvent>,
+}
+
+impl ClipboardTracker {
+    /// Requests time out after `timeout`
+    pub fn new(timeout: Duration) -> Self {
+        Self {
+            timeout,
+            next_id: 0,
+            outstanding: VecDeque::new(),
+            events: VecDeque::new(),
+        }
+    }
+
+    fn live(&mut self) -> Option<&mut Request> {
+        self.outstanding.back_mut().filter(|r| !r.abandoned)
+    }
+
+    /// Record a `MSG_CLIPBOARD_REQ` sent (or received) at time `now`.  Any
+    /// live request is superseded.
+    pub fn request(&mut self, now: Instant) -> ClipboardRequestId {
+        if let Some(old) = self.live() {
+            old.abandoned = true;
+            let id = old.id;
+            self.events.push_back(ClipboardEvent::Superseded(id));
+        }
+        if self.outstanding.len() == MAX_OUTSTANDING {
+            self.outstanding.pop_front();
+        }
+        let id = ClipboardRequestId(self.next_id);
+        self.next_id += 1;
+        self.outstanding.push_back(Request {
+            id,
+            deadline: now + self.timeout,
+            abandoned: false,
+        });
+        id
+    }
+
+    /// Record a `MSG_CLIPBOARD_DATA` received (or about to be sent), and
+    /// find out which request it answers
+    pub fn data(&mut self) -> ClipboardEvent {
+        match self.outstanding.pop_front() {
+            None => ClipboardEvent::Unsolicited,
+            Some(r) if r.abandoned => ClipboardEvent::Stale(r.id),
+            Some(r) => ClipboardEvent::Completed(r.id),
+        }
+    }
+
+    /// Report requests that were superseded, or that have timed out by
+    /// `now`.  Call this until it returns `None`.
+    pub fn poll(&mut self, now: Instant) -> Option<ClipboardEvent> {
+        if let Some(event) = self.events.pop_front() {
+            return Some(event);
+        }
+        let live = self.live().filter(|r| now >= r.deadline)?;
+        live.abandoned = true;
+        Some(ClipboardEvent::TimedOut(live.id))
+    }
+
+    /// When the live request will time out, if there is one
+    pub fn deadline(&self) -> Option<Instant> {
+        self.outstanding
+            .back()
+            .filter(|r| !r.abandoned)
+            .map(|r| r.deadline)
+    }
+
+    /// The live request, if any
+    pub fn pending(&self) -> Option<ClipboardRequestId> {
+        self.outstanding
+            .back()
+            .filter(|r| !r.abandoned)
+            .map(|r| r.id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn completes_once() {
+        let start = Instant::now();
+        let second = Duration::from_secs(1);
+        let mut tracker = ClipboardTracker::new(5 * second);
+        assert_eq!(tracker.data(), ClipboardEvent::Unsolicited);
+        let id = tracker.request(start);
+        assert_eq!(tracker.deadline(), Some(start + 5 * second));
+        assert_eq!(tracker.poll(start + 4 * second), None);
+        assert_eq!(tracker.data(), ClipboardEvent::Completed(id));
+        assert_eq!(tracker.pending(), None);
+        assert_eq!(tracker.poll(start + 10 * second), None);
+    }
+
+    #[test]
+    fn stale_replies() {
+        let start = Instant::now();
+        let second = Duration::from_secs(1);
+        let mut tracker = ClipboardTracker::new(second);
+        let first = tracker.request(start);
+        assert_eq!(
+            tracker.poll(start + second),
+            Some(ClipboardEvent::TimedOut(first))
+        );
+        assert_eq!(tracker.poll(start + second), None);
+        let second_id = tracker.request(start + 2 * second);
+        let third = tracker.request(start + 2 * second);
+        assert_eq!(
+            tracker.poll(start + 2 * second),
+            Some(ClipboardEvent::Superseded(second_id))
+        );
+        assert_eq!(tracker.pending(), Some(third));
+        // The late replies to the first two requests must not be taken as
+        // the reply to the third.
+        assert_eq!(tracker.data(), ClipboardEvent::Stale(first));
+        assert_eq!(tracker.data(), ClipboardEvent::Stale(second_id));
+        assert_eq!(tracker.data(), ClipboardEvent::Completed(third));
+    }
+}
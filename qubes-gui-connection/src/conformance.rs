@@ -0,0 +1,943 @@
+/*
+ * The Qubes OS Project, https://www.qubes-os.org
+ *
+ * Copyright (C) 2021  Demi Marie Obenour  <demi@invisiblethingslab.com>
+ *
+ * This program is free software; you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License
+ * as published by the Free Software Foundation; either version 2
+ * of the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301, USA.
+ *
+ */
+
+//! Conformance testing of GUI agents and daemons.
+//!
+//! [`AgentHarness`] plays the part of a scripted daemon and runs a series of
+//! scenarios against an agent, each over a fresh connection.  Every message
+//! the agent sends is checked: it must have a valid length, be one that
+//! agents may send, and refer only to windows the agent has created.
+//!
+//! [`DaemonHarness`] plays the part of a hostile agent.  It sends bad
+//! lengths, unknown messages, reused window IDs, out-of-range geometry and
+//! floods of clipboard data, and checks that the daemon either disconnects
+//! or keeps working.  Messages that cannot be framed must be rejected.
+//!
+//! The results are collected in a [`Report`].  The harnesses poll instead of
+//! waiting on file descriptors, so they work with any [`Transport`],
+//! including a [`LoopbackTransport`](crate::LoopbackTransport) connected to
+//! an implementation running in another thread.
+
+use crate::decode::{Item, Peer, Reassembler};
+use crate::Transport;
+use qubes_castable::Castable;
+use qubes_gui::{Header, UntrustedHeader};
+use std::collections::{BTreeSet, VecDeque};
+use std::fmt;
+use std::io;
+use std::num::NonZeroU32;
+use std::time::{Duration, Instant};
+use vchan::Status;
+
+/// The result of one scenario
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Verdict {
+    /// The implementation behaved correctly
+    Pass,
+    /// The implementation misbehaved, for the given reason
+    Fail(String),
+    /// The scenario could not be run, for the given reason
+    Skip(String),
+}
+
+/// The result of one scenario, with its name
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Outcome {
+    /// The name of the scenario
+    pub scenario: &'static str,
+    /// What happened
+    pub verdict: Verdict,
+}
+
+/// The results of a conformance run
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Report {
+    /// The result of each scenario, in the order they were run
+    pub outcomes: Vec<Outcome>,
+}
+
+impl Report {
+    /// True if no scenario failed
+    pub fn passed(&self) -> bool {
+        !self
+            .outcomes
+            .iter()
+            .any(|o| matches!(o.verdict, Verdict::Fail(_)))
+    }
+
+    fn count(&self, f: impl Fn(&Verdict) -> bool) -> usize {
+        self.outcomes.iter().filter(|o| f(&o.verdict)).count()
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for outcome in &self.outcomes {
+            match &outcome.verdict {
+                Verdict::Pass => writeln!(f, "PASS {}", outcome.scenario)?,
+                Verdict::Fail(why) => writeln!(f, "FAIL {}: {}", outcome.scenario, why)?,
+                Verdict::Skip(why) => writeln!(f, "SKIP {}: {}", outcome.scenario, why)?,
+            }
+        }
+        write!(
+            f,
+            "{} passed, {} failed, {} skipped",
+            self.count(|v| *v == Verdict::Pass),
+            self.count(|v| matches!(v, Verdict::Fail(_))),
+            self.count(|v| matches!(v, Verdict::Skip(_))),
+        )
+    }
+}
+
+fn fail<T>(why: impl Into<String>) -> Result<T, Verdict> {
+    Err(Verdict::Fail(why.into()))
+}
+
+/// A message received from the peer under test
+#[derive(Debug)]
+struct Received {
+    header: Header,
+    body: Vec<u8>,
+}
+
+impl Received {
+    fn window(&self) -> u32 {
+        self.header
+            .untrusted_window()
+            .window
+            .map_or(0, NonZeroU32::get)
+    }
+}
+
+/// What a [`Reassembler`] found, copied out of its buffer
+enum Found {
+    Handshake(u32),
+    Message(Received),
+    Problem(String),
+}
+
+/// One connection to the implementation under test
+struct Script {
+    transport: Box<dyn Transport>,
+    reassembler: Reassembler,
+    received: VecDeque<Received>,
+    peer_version: Option<u32>,
+    windows: BTreeSet<NonZeroU32>,
+    /// The last window ID used when testing a daemon
+    last_window: u32,
+    xconf: qubes_gui::XConf,
+    deadline: Instant,
+    settle: Duration,
+    buffer: Vec<u8>,
+}
+
+impl Script {
+    /// Read whatever the agent has sent and check it
+    fn poll(&mut self) -> Result<(), Verdict> {
+        self.transport.wait();
+        let len = self.transport.data_ready();
+        if len == 0 {
+            std::thread::sleep(Duration::from_millis(1));
+            return Ok(());
+        }
+        self.buffer.resize(len, 0);
+        if self.transport.recv(&mut self.buffer).is_err() {
+            return fail(format!("reading from the {} failed", self.peer_name()));
+        }
+        let mut found = vec![];
+        self.reassembler.feed(&self.buffer, |item| {
+            found.push(match item {
+                Item::Handshake { version, .. } => Found::Handshake(version),
+                Item::Message { header, body } => Found::Message(Received {
+                    header,
+                    body: body.to_vec(),
+                }),
+                Item::Unknown(_) | Item::Violation(_) => Found::Problem(item.describe(false)),
+            })
+        });
+        for found in found {
+            match found {
+                Found::Handshake(version) => self.peer_version = Some(version),
+                Found::Message(message) => {
+                    if self.reassembler.peer() == Peer::Agent {
+                        self.track_windows(&message)?
+                    }
+                    self.received.push_back(message)
+                }
+                Found::Problem(problem) => {
+                    return fail(format!("{} sent {}", self.peer_name(), problem))
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Check that `message` only refers to windows that exist
+    fn track_windows(&mut self, message: &Received) -> Result<(), Verdict> {
+        let name = crate::decode::message_name(message.header.ty()).unwrap();
+        let window = message.header.untrusted_window().window;
+        match (message.header.ty(), window) {
+            (qubes_gui::MSG_CLIPBOARD_DATA, _) | (_, None) => {}
+            (qubes_gui::MSG_CREATE, Some(window)) if self.windows.contains(&window) => {
+                return fail(format!("agent created window {} twice", window))
+            }
+            (qubes_gui::MSG_CREATE, Some(window)) => {
+                self.windows.insert(window);
+            }
+            (_, Some(window)) if !self.windows.contains(&window) => {
+                return fail(format!("agent sent {} to unknown window {}", name, window))
+            }
+            (qubes_gui::MSG_DESTROY, Some(window)) => {
+                self.windows.remove(&window);
+                // Acknowledge the destruction, so the agent can reuse the ID
+                self.send(qubes_gui::MSG_DESTROY, window.get(), &[])?
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn peer_name(&self) -> &'static str {
+        self.reassembler.peer().name()
+    }
+
+    fn disconnected(&self) -> bool {
+        self.transport.status() == Status::Disconnected
+    }
+
+    fn check_deadline(&self, what: &str) -> Result<(), Verdict> {
+        if self.disconnected() {
+            fail(format!("{} disconnected while {}", self.peer_name(), what))
+        } else if Instant::now() > self.deadline {
+            fail(format!("timed out while {}", what))
+        } else {
+            Ok(())
+        }
+    }
+
+    fn send_bytes(&mut self, bytes: &[u8]) -> Result<(), Verdict> {
+        match self.transport.send(bytes) {
+            Ok(()) => Ok(()),
+            Err(e) => fail(format!("sending to the {} failed: {}", self.peer_name(), e)),
+        }
+    }
+
+    fn send(&mut self, ty: u32, window: u32, body: &[u8]) -> Result<(), Verdict> {
+        self.send_bytes(&message(ty, window, body))
+    }
+
+    /// Wait for the agent's version
+    fn handshake(&mut self) -> Result<u32, Verdict> {
+        loop {
+            if let Some(version) = self.peer_version {
+                if version >> 16 != qubes_gui::PROTOCOL_VERSION_MAJOR {
+                    return fail(format!("agent sent major version {}", version >> 16));
+                }
+                return Ok(version);
+            }
+            self.check_deadline("waiting for the agent's version")?;
+            self.poll()?
+        }
+    }
+
+    /// Tell the agent that `version` is the negotiated version
+    fn reply_version(&mut self, version: u32) -> Result<(), Verdict> {
+        let xconf = qubes_gui::XConfVersion {
+            version,
+            xconf: self.xconf,
+        };
+        self.send_bytes(xconf.as_bytes())
+    }
+
+    /// Negotiate the best version both sides support
+    fn negotiate(&mut self) -> Result<(), Verdict> {
+        let version = self.handshake()?;
+        self.reply_version(version.min(qubes_gui::PROTOCOL_VERSION))
+    }
+
+    /// Wait for a message matching `f`.  Returns [`None`] on timeout.
+    fn wait_for(
+        &mut self,
+        mut f: impl FnMut(&Received) -> bool,
+    ) -> Result<Option<Received>, Verdict> {
+        loop {
+            if let Some(pos) = self.received.iter().position(&mut f) {
+                return Ok(self.received.remove(pos));
+            }
+            if Instant::now() > self.deadline {
+                return Ok(None);
+            }
+            if self.disconnected() {
+                return fail(format!("{} disconnected", self.peer_name()));
+            }
+            self.poll()?
+        }
+    }
+
+    /// Keep reading for a while, failing if the peer misbehaves or
+    /// disconnects
+    fn settle(&mut self) -> Result<(), Verdict> {
+        let end = Instant::now() + self.settle;
+        while Instant::now() < end {
+            if self.disconnected() {
+                return fail(format!("{} disconnected", self.peer_name()));
+            }
+            self.poll()?
+        }
+        Ok(())
+    }
+
+    /// A window ID not yet used when testing a daemon
+    fn fresh_window(&mut self) -> u32 {
+        self.last_window += 1;
+        self.last_window
+    }
+
+    /// Send our version as an agent and wait for the daemon's reply
+    fn hello(&mut self) -> Result<(), Verdict> {
+        while self.transport.status() == Status::Waiting {
+            self.check_deadline("waiting for the daemon to connect")?;
+            self.poll()?
+        }
+        self.send_bytes(qubes_gui::PROTOCOL_VERSION.as_bytes())?;
+        loop {
+            if let Some(version) = self.peer_version {
+                let (major, minor) = (version >> 16, version & 0xFFFF);
+                if major != qubes_gui::PROTOCOL_VERSION_MAJOR
+                    || !(4..=qubes_gui::PROTOCOL_VERSION_MINOR).contains(&minor)
+                {
+                    return fail(format!(
+                        "daemon negotiated unsupported version {}.{}",
+                        major, minor
+                    ));
+                }
+                return Ok(());
+            }
+            self.check_deadline("waiting for the daemon's version")?;
+            self.poll()?
+        }
+    }
+
+    /// Send `bytes` as fast as the peer reads them.  Returns `false` if the
+    /// peer disconnected first, and fails if it stops reading.
+    fn send_all(&mut self, mut bytes: &[u8]) -> Result<bool, Verdict> {
+        while !bytes.is_empty() {
+            if self.disconnected() {
+                return Ok(false);
+            }
+            let len = self.transport.buffer_space().min(bytes.len());
+            if len == 0 {
+                if Instant::now() > self.deadline {
+                    return fail(format!("{} stopped reading", self.peer_name()));
+                }
+                self.poll()?;
+                continue;
+            }
+            if self.transport.send(&bytes[..len]).is_err() {
+                return Ok(false);
+            }
+            bytes = &bytes[len..]
+        }
+        Ok(true)
+    }
+
+    /// Wait for the peer to disconnect because of something unacceptable
+    fn expect_reject(&mut self, what: &str) -> Result<(), Verdict> {
+        while !self.disconnected() {
+            if Instant::now() > self.deadline {
+                return fail(format!("{} accepted {}", self.peer_name(), what));
+            }
+            self.poll()?
+        }
+        Ok(())
+    }
+
+    /// Check that the daemon has either disconnected or still handles
+    /// messages, by creating a window, setting its title many times, and
+    /// destroying it
+    fn survive_or_reject(&mut self) -> Result<(), Verdict> {
+        const PROBE_TITLES: usize = 1000;
+        let window = self.fresh_window();
+        let mut probe = message(qubes_gui::MSG_CREATE, window, valid_create().as_bytes());
+        let title = qubes_gui::WMName::new("conformance probe").unwrap();
+        for _ in 0..PROBE_TITLES {
+            probe.extend(message(qubes_gui::MSG_SET_TITLE, window, title.as_bytes()))
+        }
+        probe.extend(message(qubes_gui::MSG_DESTROY, window, &[]));
+        self.send_all(&probe)?;
+        Ok(())
+    }
+}
+
+fn message(ty: u32, window: u32, body: &[u8]) -> Vec<u8> {
+    let header = UntrustedHeader {
+        ty,
+        window: window.into(),
+        untrusted_len: body.len() as u32,
+    };
+    [header.as_bytes(), body].concat()
+}
+
+fn valid_create() -> qubes_gui::Create {
+    qubes_gui::Create {
+        rectangle: qubes_gui::Rectangle {
+            top_left: qubes_gui::Coordinates { x: 0, y: 0 },
+            size: qubes_gui::WindowSize {
+                width: 100,
+                height: 100,
+            },
+        },
+        parent: None,
+        override_redirect: 0,
+    }
+}
+
+/// A scenario: run against a fresh connection
+type Scenario = fn(&mut Script) -> Result<(), Verdict>;
+
+const AGENT_SCENARIOS: &[(&str, Scenario)] = &[
+    ("negotiation at the current version", negotiate_current),
+    ("negotiation at version 1.4", negotiate_oldest),
+    (
+        "negotiation at an incompatible version",
+        negotiate_incompatible,
+    ),
+    ("window lifecycle", window_lifecycle),
+    ("clipboard round trip", clipboard_round_trip),
+    ("malformed message tolerance", malformed_tolerance),
+];
+
+fn negotiate_current(d: &mut Script) -> Result<(), Verdict> {
+    d.negotiate()?;
+    d.settle()
+}
+
+fn negotiate_oldest(d: &mut Script) -> Result<(), Verdict> {
+    d.handshake()?;
+    d.reply_version(qubes_gui::PROTOCOL_VERSION_MAJOR << 16 | 4)?;
+    d.settle()
+}
+
+fn negotiate_incompatible(d: &mut Script) -> Result<(), Verdict> {
+    d.handshake()?;
+    d.reply_version((qubes_gui::PROTOCOL_VERSION_MAJOR + 1) << 16)?;
+    let end = Instant::now() + d.settle;
+    while Instant::now() < end && !d.disconnected() {
+        d.poll()?;
+        if let Some(message) = d.received.front() {
+            return fail(format!(
+                "agent sent {} after an incompatible version",
+                crate::decode::message_name(message.header.ty()).unwrap()
+            ));
+        }
+    }
+    Ok(())
+}
+
+fn window_lifecycle(d: &mut Script) -> Result<(), Verdict> {
+    d.negotiate()?;
+    let create = match d.wait_for(|m| m.header.ty() == qubes_gui::MSG_CREATE)? {
+        Some(create) => create,
+        None => return Err(Verdict::Skip("agent created no windows".to_owned())),
+    };
+    let window = create.window();
+    let create = qubes_gui::Create::from_bytes(&create.body);
+    let configure = qubes_gui::Configure {
+        rectangle: create.rectangle,
+        override_redirect: create.override_redirect,
+    };
+    d.send(qubes_gui::MSG_CONFIGURE, window, configure.as_bytes())?;
+    let focus = qubes_gui::Focus {
+        ty: qubes_gui::EV_FOCUS_IN,
+        mode: 0,
+        detail: 0,
+    };
+    d.send(qubes_gui::MSG_FOCUS, window, focus.as_bytes())?;
+    let motion = qubes_gui::Motion::default();
+    d.send(qubes_gui::MSG_MOTION, window, motion.as_bytes())?;
+    d.send(qubes_gui::MSG_CLOSE, window, &[])?;
+    d.settle()
+}
+
+fn clipboard_round_trip(d: &mut Script) -> Result<(), Verdict> {
+    const DATA: &[u8] = "qubes-gui conformance \u{2713}".as_bytes();
+    d.negotiate()?;
+    d.send(qubes_gui::MSG_CLIPBOARD_DATA, 0, DATA)?;
+    d.send(qubes_gui::MSG_CLIPBOARD_REQ, 0, &[])?;
+    match d.wait_for(|m| m.header.ty() == qubes_gui::MSG_CLIPBOARD_DATA)? {
+        None => fail("agent did not answer the clipboard request"),
+        Some(m) if m.body != DATA => fail("agent returned different clipboard contents"),
+        Some(_) => Ok(()),
+    }
+}
+
+fn malformed_tolerance(d: &mut Script) -> Result<(), Verdict> {
+    const UNKNOWN_WINDOW: u32 = 0x7fff_fff0;
+    d.negotiate()?;
+    d.send(0x7fff_0000, 0, &[0xAA; 100])?;
+    let keymap = qubes_gui::KeymapNotify::default();
+    d.send(
+        qubes_gui::MSG_KEYMAP_NOTIFY,
+        UNKNOWN_WINDOW,
+        keymap.as_bytes(),
+    )?;
+    let motion = qubes_gui::Motion::default();
+    d.send(qubes_gui::MSG_MOTION, UNKNOWN_WINDOW, motion.as_bytes())?;
+    d.send(qubes_gui::MSG_CLOSE, UNKNOWN_WINDOW, &[])?;
+    d.settle()
+}
+
+const DAEMON_SCENARIOS: &[(&str, Scenario)] = &[
+    ("negotiation", daemon_negotiation),
+    ("bad length", bad_length),
+    ("oversized clipboard", oversized_clipboard),
+    ("unknown message types", unknown_messages),
+    ("messages in the wrong direction", wrong_direction),
+    ("window ID reuse", window_id_reuse),
+    ("messages to unknown windows", unknown_windows),
+    ("out-of-range geometry", bad_geometry),
+    ("clipboard flood", clipboard_flood),
+];
+
+fn daemon_negotiation(a: &mut Script) -> Result<(), Verdict> {
+    a.hello()?;
+    a.settle()
+}
+
+fn bad_length(a: &mut Script) -> Result<(), Verdict> {
+    a.hello()?;
+    let window = a.fresh_window();
+    let mut create = message(qubes_gui::MSG_CREATE, window, valid_create().as_bytes());
+    create.extend_from_slice(&[0; 4]);
+    let len = create.len() as u32 - 12;
+    create[8..12].copy_from_slice(&len.to_ne_bytes());
+    a.send_bytes(&create)?;
+    a.expect_reject("a CREATE with a bad length")
+}
+
+fn oversized_clipboard(a: &mut Script) -> Result<(), Verdict> {
+    a.hello()?;
+    let header = UntrustedHeader {
+        ty: qubes_gui::MSG_CLIPBOARD_DATA,
+        window: 0.into(),
+        untrusted_len: qubes_gui::MAX_CLIPBOARD_SIZE + 1,
+    };
+    a.send_bytes(header.as_bytes())?;
+    a.expect_reject("clipboard data longer than the maximum")
+}
+
+fn unknown_messages(a: &mut Script) -> Result<(), Verdict> {
+    a.hello()?;
+    a.send(0x7fff_0000, 0, &[0xAA; 100])?;
+    a.send(0, 0, &[])?;
+    a.survive_or_reject()
+}
+
+fn wrong_direction(a: &mut Script) -> Result<(), Verdict> {
+    a.hello()?;
+    let window = a.fresh_window();
+    a.send(qubes_gui::MSG_CREATE, window, valid_create().as_bytes())?;
+    let keypress = qubes_gui::Keypress::default();
+    a.send(qubes_gui::MSG_KEYPRESS, window, keypress.as_bytes())?;
+    a.send(qubes_gui::MSG_CLOSE, window, &[])?;
+    a.send(qubes_gui::MSG_WINDOW_DUMP_ACK, window, &[])?;
+    a.survive_or_reject()
+}
+
+fn window_id_reuse(a: &mut Script) -> Result<(), Verdict> {
+    a.hello()?;
+    let window = a.fresh_window();
+    let create = valid_create();
+    a.send(qubes_gui::MSG_CREATE, window, create.as_bytes())?;
+    a.send(qubes_gui::MSG_CREATE, window, create.as_bytes())?;
+    let child = qubes_gui::Create {
+        parent: NonZeroU32::new(window),
+        ..create
+    };
+    a.send(qubes_gui::MSG_CREATE, window, child.as_bytes())?;
+    a.survive_or_reject()
+}
+
+fn unknown_windows(a: &mut Script) -> Result<(), Verdict> {
+    a.hello()?;
+    let window = a.fresh_window() + 0x1000;
+    let title = qubes_gui::WMName::new("nobody").unwrap();
+    a.send(qubes_gui::MSG_SET_TITLE, window, title.as_bytes())?;
+    let map = qubes_gui::MapInfo::default();
+    a.send(qubes_gui::MSG_MAP, window, map.as_bytes())?;
+    a.send(qubes_gui::MSG_DESTROY, window, &[])?;
+    let orphan = qubes_gui::Create {
+        parent: NonZeroU32::new(window),
+        ..valid_create()
+    };
+    let orphan_id = a.fresh_window();
+    a.send(qubes_gui::MSG_CREATE, orphan_id, orphan.as_bytes())?;
+    a.survive_or_reject()
+}
+
+fn bad_geometry(a: &mut Script) -> Result<(), Verdict> {
+    a.hello()?;
+    let sizes = [
+        (0, 0),
+        (0, 100),
+        (qubes_gui::MAX_WINDOW_WIDTH + 1, 100),
+        (100, qubes_gui::MAX_WINDOW_HEIGHT + 1),
+        (u32::MAX, u32::MAX),
+    ];
+    for &(width, height) in &sizes {
+        let mut create = valid_create();
+        create.rectangle.size = qubes_gui::WindowSize { width, height };
+        let window = a.fresh_window();
+        a.send(qubes_gui::MSG_CREATE, window, create.as_bytes())?;
+    }
+    let window = a.fresh_window();
+    a.send(qubes_gui::MSG_CREATE, window, valid_create().as_bytes())?;
+    let configure = qubes_gui::Configure {
+        rectangle: qubes_gui::Rectangle {
+            top_left: qubes_gui::Coordinates {
+                x: i32::MIN,
+                y: i32::MAX,
+            },
+            size: qubes_gui::WindowSize {
+                width: u32::MAX,
+                height: 1,
+            },
+        },
+        override_redirect: 2,
+    };
+    a.send(qubes_gui::MSG_CONFIGURE, window, configure.as_bytes())?;
+    let image = qubes_gui::ShmImage {
+        rectangle: configure.rectangle,
+    };
+    a.send(qubes_gui::MSG_SHMIMAGE, window, image.as_bytes())?;
+    a.survive_or_reject()
+}
+
+fn clipboard_flood(a: &mut Script) -> Result<(), Verdict> {
+    const MESSAGES: usize = 64;
+    a.hello()?;
+    let data = vec![b'A'; qubes_gui::MAX_CLIPBOARD_SIZE as usize];
+    let one = message(qubes_gui::MSG_CLIPBOARD_DATA, 0, &data);
+    for _ in 0..MESSAGES {
+        if !a.send_all(&one)? {
+            return Ok(());
+        }
+    }
+    a.survive_or_reject()
+}
+
+fn run_scenarios(
+    connect: &mut dyn FnMut() -> io::Result<Box<dyn Transport>>,
+    peer: Peer,
+    scenarios: &[(&'static str, Scenario)],
+    xconf: qubes_gui::XConf,
+    timeout: Duration,
+    settle: Duration,
+) -> Report {
+    let mut report = Report::default();
+    for &(scenario, run) in scenarios {
+        let verdict = match connect() {
+            Err(e) => Verdict::Fail(format!("cannot connect to the {}: {}", peer.name(), e)),
+            Ok(transport) => {
+                let mut script = Script {
+                    transport,
+                    reassembler: Reassembler::new(peer),
+                    received: VecDeque::new(),
+                    peer_version: None,
+                    windows: BTreeSet::new(),
+                    last_window: 0,
+                    xconf,
+                    deadline: Instant::now() + timeout,
+                    settle,
+                    buffer: vec![],
+                };
+                run(&mut script).err().unwrap_or(Verdict::Pass)
+            }
+        };
+        report.outcomes.push(Outcome { scenario, verdict })
+    }
+    report
+}
+
+/// Runs the agent conformance scenarios
+#[derive(Debug)]
+pub struct AgentHarness<F> {
+    connect: F,
+    xconf: qubes_gui::XConf,
+    timeout: Duration,
+    settle: Duration,
+}
+
+impl<F: FnMut() -> io::Result<Box<dyn Transport>>> AgentHarness<F> {
+    /// Create a harness that calls `connect` to get a new connection to the
+    /// agent under test for each scenario
+    pub fn new(connect: F) -> Self {
+        Self {
+            connect,
+            xconf: qubes_gui::XConf {
+                size: qubes_gui::WindowSize {
+                    width: 1920,
+                    height: 1080,
+                },
+                depth: 24,
+                mem: 1920 * 1080 * 4 / 1024 + 1,
+            },
+            timeout: Duration::from_secs(5),
+            settle: Duration::from_millis(200),
+        }
+    }
+
+    /// Set the root window configuration sent to the agent
+    pub fn xconf(mut self, xconf: qubes_gui::XConf) -> Self {
+        self.xconf = xconf;
+        self
+    }
+
+    /// Set how long each scenario may take
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Set how long to watch the agent for misbehavior after each stimulus
+    pub fn settle(mut self, settle: Duration) -> Self {
+        self.settle = settle;
+        self
+    }
+
+    /// Run all scenarios
+    pub fn run(&mut self) -> Report {
+        run_scenarios(
+            &mut self.connect,
+            Peer::Agent,
+            AGENT_SCENARIOS,
+            self.xconf,
+            self.timeout,
+            self.settle,
+        )
+    }
+}
+
+/// Runs the daemon conformance scenarios
+#[derive(Debug)]
+pub struct DaemonHarness<F> {
+    connect: F,
+    timeout: Duration,
+    settle: Duration,
+}
+
+impl<F: FnMut() -> io::Result<Box<dyn Transport>>> DaemonHarness<F> {
+    /// Create a harness that calls `connect` to get a new connection to the
+    /// daemon under test for each scenario
+    pub fn new(connect: F) -> Self {
+        Self {
+            connect,
+            timeout: Duration::from_secs(5),
+            settle: Duration::from_millis(200),
+        }
+    }
+
+    /// Set how long each scenario may take
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Set how long to watch the daemon for misbehavior after connecting
+    pub fn settle(mut self, settle: Duration) -> Self {
+        self.settle = settle;
+        self
+    }
+
+    /// Run all scenarios
+    pub fn run(&mut self) -> Report {
+        run_scenarios(
+            &mut self.connect,
+            Peer::Daemon,
+            DAEMON_SCENARIOS,
+            Default::default(),
+            self.timeout,
+            self.settle,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Agent, Connection, Event, LoopbackTransport};
+    use std::task::Poll;
+
+    /// A minimal well-behaved agent
+    fn reference_agent(transport: LoopbackTransport, create: bool) {
+        let mut agent = Agent::new(Connection::agent_over(transport));
+        let mut clipboard = vec![];
+        let window = NonZeroU32::new(1).unwrap();
+        let create_msg = valid_create();
+        while !agent.connection().needs_reconnect() {
+            let (ty, body) = match agent.read_event() {
+                Poll::Pending => {
+                    std::thread::sleep(Duration::from_millis(1));
+                    continue;
+                }
+                Poll::Ready(Err(_)) => return,
+                Poll::Ready(Ok(Event::Message(m))) => (m.hdr().ty(), m.body().to_vec()),
+                Poll::Ready(Ok(Event::Reconnected(_))) if create => {
+                    agent.create_window(window, &create_msg).unwrap();
+                    continue;
+                }
+                Poll::Ready(Ok(_)) => continue,
+            };
+            let res = match ty {
+                qubes_gui::MSG_CLIPBOARD_DATA => {
+                    clipboard = body;
+                    Ok(())
+                }
+                qubes_gui::MSG_CLIPBOARD_REQ => {
+                    agent
+                        .connection()
+                        .send_raw(&clipboard, 0.into(), qubes_gui::MSG_CLIPBOARD_DATA)
+                }
+                qubes_gui::MSG_CLOSE if agent.window(window).is_some() => agent.destroy(window),
+                _ => Ok(()),
+            };
+            if res.is_err() {
+                return;
+            }
+        }
+    }
+
+    fn harness(create: bool) -> Report {
+        AgentHarness::new(|| {
+            let (ours, theirs) = LoopbackTransport::pair();
+            std::thread::spawn(move || reference_agent(theirs, create));
+            Ok(Box::new(ours) as Box<dyn Transport>)
+        })
+        .timeout(Duration::from_secs(1))
+        .settle(Duration::from_millis(50))
+        .run()
+    }
+
+    #[test]
+    fn reference_agent_passes() {
+        let report = harness(true);
+        assert!(report.passed(), "{}", report);
+        assert!(report.outcomes.iter().all(|o| o.verdict == Verdict::Pass));
+        assert_eq!(report.outcomes.len(), AGENT_SCENARIOS.len());
+    }
+
+    #[test]
+    fn windowless_agent_is_skipped() {
+        let report = harness(false);
+        assert!(report.passed(), "{}", report);
+        let skipped: Vec<_> = report
+            .outcomes
+            .iter()
+            .filter(|o| matches!(o.verdict, Verdict::Skip(_)))
+            .map(|o| o.scenario)
+            .collect();
+        assert_eq!(skipped, ["window lifecycle"]);
+    }
+
+    #[test]
+    fn misbehaving_agent_fails() {
+        let report = AgentHarness::new(|| {
+            let (ours, mut theirs) = LoopbackTransport::pair();
+            theirs.send(qubes_gui::PROTOCOL_VERSION.as_bytes()).unwrap();
+            let header = UntrustedHeader {
+                ty: qubes_gui::MSG_UNMAP,
+                window: 5.into(),
+                untrusted_len: 0,
+            };
+            theirs.send(header.as_bytes()).unwrap();
+            // Keep the agent's end open until the harness is done with it
+            std::mem::forget(theirs);
+            Ok(Box::new(ours) as Box<dyn Transport>)
+        })
+        .timeout(Duration::from_millis(200))
+        .settle(Duration::from_millis(20))
+        .run();
+        assert!(!report.passed());
+        assert_eq!(
+            report.outcomes[0].verdict,
+            Verdict::Fail("agent sent UNMAP to unknown window 5".to_owned())
+        );
+    }
+
+    fn reference_daemon(transport: LoopbackTransport) {
+        let mut connection = Connection::daemon_over(transport, Default::default());
+        loop {
+            match connection.read_event() {
+                Poll::Pending => {}
+                Poll::Ready(Err(_)) => return,
+                Poll::Ready(Ok(_)) => continue,
+            }
+            if connection.needs_reconnect() {
+                return;
+            }
+            std::thread::sleep(Duration::from_millis(1))
+        }
+    }
+
+    #[test]
+    fn reference_daemon_passes() {
+        let report = DaemonHarness::new(|| {
+            let (ours, theirs) = LoopbackTransport::pair();
+            std::thread::spawn(move || reference_daemon(theirs));
+            Ok(Box::new(ours) as Box<dyn Transport>)
+        })
+        .timeout(Duration::from_secs(10))
+        .settle(Duration::from_millis(50))
+        .run();
+        assert!(report.passed(), "{}", report);
+        assert_eq!(report.outcomes.len(), DAEMON_SCENARIOS.len());
+    }
+
+    #[test]
+    fn stuck_daemon_fails() {
+        let report = DaemonHarness::new(|| {
+            let (ours, mut theirs) = LoopbackTransport::pair();
+            let xconf = qubes_gui::XConfVersion {
+                version: qubes_gui::PROTOCOL_VERSION,
+                xconf: Default::default(),
+            };
+            theirs.send(xconf.as_bytes()).unwrap();
+            std::mem::forget(theirs);
+            Ok(Box::new(ours) as Box<dyn Transport>)
+        })
+        .timeout(Duration::from_millis(200))
+        .settle(Duration::from_millis(20))
+        .run();
+        let verdict = |name| {
+            let outcome = report.outcomes.iter().find(|o| o.scenario == name);
+            outcome.unwrap().verdict.clone()
+        };
+        assert_eq!(verdict("negotiation"), Verdict::Pass);
+        assert_eq!(
+            verdict("bad length"),
+            Verdict::Fail("daemon accepted a CREATE with a bad length".to_owned())
+        );
+        assert_eq!(
+            verdict("clipboard flood"),
+            Verdict::Fail("daemon stopped reading".to_owned())
+        );
+    }
+}
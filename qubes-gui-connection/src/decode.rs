@@ -0,0 +1,355 @@
+/*
+ * The Qubes OS Project, https://www.qubes-os.org
+ *
+ * Copyright (C) 2021  Demi Marie Obenour  <demi@invisiblethingslab.com>
+ *
+ * This program is free software; you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License
+ * as published by the Free Software Foundation; either version 2
+ * of the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301, USA.
+ *
+ */
+
+//! Decoding raw protocol streams for debugging tools.
+//!
+//! Unlike a [`Connection`](crate::Connection), a [`Reassembler`] never takes
+//! part in the protocol: it only watches the bytes one peer sends and splits
+//! them into [`Item`]s, checking each message on the way.  It is used by the
+//! capture inspector and the debugging proxy.  Only protocol 1.4 and later
+//! are supported, as earlier versions use a different handshake.
+
+use qubes_castable::Castable;
+use qubes_gui::{Header, UntrustedHeader, Violation, ViolationKind};
+use std::convert::TryFrom;
+use std::fmt::Debug;
+use std::mem::size_of;
+
+/// One side of a connection
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Peer {
+    /// The GUI agent, running in the qube whose windows are shown
+    Agent,
+    /// The GUI daemon, running in the GUI qube
+    Daemon,
+}
+
+impl Peer {
+    /// The lowercase name of the peer
+    pub fn name(self) -> &'static str {
+        match self {
+            Peer::Agent => "agent",
+            Peer::Daemon => "daemon",
+        }
+    }
+}
+
+/// The name of a message type, as used in the C headers, or [`None`] if the
+/// type is unknown
+pub fn message_name(ty: u32) -> Option<&'static str> {
+    qubes_gui::Msg::try_from(ty).ok().map(qubes_gui::Msg::name)
+}
+
+/// Whether `peer` may send messages of type `ty`.  Unknown types are allowed
+/// in both directions.  `MSG_DESTROY` is allowed from the daemon as an
+/// acknowledgement.
+pub fn may_send(peer: Peer, ty: u32) -> bool {
+    match peer {
+        Peer::Agent => qubes_gui::agent_may_send(ty),
+        Peer::Daemon => qubes_gui::daemon_may_send(ty),
+    }
+}
+
+fn fields<T: Castable + Debug>(body: &[u8]) -> String {
+    format!("{:?}", T::from_le_bytes(body))
+}
+
+/// Decode the body of a message for display.  Clipboard contents are
+/// replaced by their length unless `show_clipboard` is set.
+pub fn describe(header: Header, body: &[u8], show_clipboard: bool) -> String {
+    use qubes_gui::*;
+    match header.ty() {
+        MSG_CLIPBOARD_DATA if show_clipboard => format!("{:?}", String::from_utf8_lossy(body)),
+        MSG_CLIPBOARD_DATA => format!("{:?}", qubes_gui::Redacted(body)),
+        MSG_KEYPRESS => fields::<Keypress>(body),
+        MSG_BUTTON => fields::<Button>(body),
+        MSG_MOTION => fields::<Motion>(body),
+        MSG_CROSSING => fields::<Crossing>(body),
+        MSG_FOCUS => fields::<Focus>(body),
+        MSG_CREATE => fields::<Create>(body),
+        MSG_MAP => fields::<MapInfo>(body),
+        MSG_CONFIGURE => fields::<Configure>(body),
+        MSG_SHMIMAGE => fields::<ShmImage>(body),
+        MSG_WINDOW_HINTS => fields::<WindowHints>(body),
+        MSG_WINDOW_FLAGS => fields::<WindowFlags>(body),
+        MSG_CURSOR => fields::<Cursor>(body),
+        MSG_SET_TITLE => format!("{:?}", WMName::from_le_bytes(body).as_str_lossy()),
+        MSG_WINDOW_CLASS => {
+            let class = WMClass::from_le_bytes(body);
+            format!(
+                "class {:?} instance {:?}",
+                class.class_as_str_lossy(),
+                class.instance_as_str_lossy()
+            )
+        }
+        MSG_KEYMAP_NOTIFY => {
+            let pressed: Vec<u8> = KeymapNotify::from_le_bytes(body).pressed().collect();
+            format!("pressed {:?}", pressed)
+        }
+        MSG_WINDOW_DUMP => {
+            let (dump, refs) = body.split_at(size_of::<WindowDumpHeader>());
+            format!(
+                "{:?} with {} grant refs",
+                WindowDumpHeader::from_le_bytes(dump),
+                refs.len() / size_of::<u32>()
+            )
+        }
+        MSG_MFNDUMP => format!("{} mfns", body.len() / size_of::<u32>()),
+        _ => String::new(),
+    }
+}
+
+/// Something found in a stream
+#[derive(Clone, Copy)]
+pub enum Item<'a> {
+    /// The version handshake.  Only the daemon sends `xconf`.
+    Handshake {
+        /// The protocol version
+        version: u32,
+        /// The root window configuration
+        xconf: Option<qubes_gui::XConf>,
+    },
+    /// A complete message of a known type
+    Message {
+        /// The validated header
+        header: Header,
+        /// The body
+        body: &'a [u8],
+    },
+    /// A message of an unknown type, whose body is skipped
+    Unknown(UntrustedHeader),
+    /// A protocol violation.  If the message had a valid length, it is also
+    /// reported as an [`Item::Message`] afterwards.  Otherwise, the stream
+    /// cannot be parsed any further.
+    Violation(Violation),
+}
+
+/// Message bodies are redacted
+impl Debug for Item<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Item::Handshake { version, xconf } => f
+                .debug_struct("Handshake")
+                .field("version", version)
+                .field("xconf", xconf)
+                .finish(),
+            Item::Message { header, body } => f
+                .debug_struct("Message")
+                .field("header", header)
+                .field("body", &qubes_gui::Redacted(body))
+                .finish(),
+            Item::Unknown(header) => f.debug_tuple("Unknown").field(header).finish(),
+            Item::Violation(violation) => f.debug_tuple("Violation").field(violation).finish(),
+        }
+    }
+}
+
+impl Item<'_> {
+    /// A one-line description of the item, with clipboard contents
+    /// redacted unless `show_clipboard` is set
+    pub fn describe(&self, show_clipboard: bool) -> String {
+        match *self {
+            Item::Handshake { version, xconf } => {
+                let xconf = xconf.map_or(String::new(), |x| format!(" {:?}", x));
+                format!("VERSION {}.{}{}", version >> 16, version & 0xFFFF, xconf)
+            }
+            Item::Message { header, body } => format!(
+                "{} window {} {}",
+                message_name(header.ty()).unwrap(),
+                header.untrusted_window().window.map_or(0, |w| w.get()),
+                describe(header, body, show_clipboard)
+            ),
+            Item::Unknown(header) => format!(
+                "unknown type {} window {} ({} bytes)",
+                header.ty,
+                header.window.window.map_or(0, |w| w.get()),
+                header.untrusted_len
+            ),
+            Item::Violation(violation) => format!("PROTOCOL VIOLATION: {}", violation),
+        }
+    }
+}
+
+/// Splits the bytes sent by one peer into [`Item`]s
+#[derive(Debug)]
+pub struct Reassembler {
+    peer: Peer,
+    /// Bytes not yet part of a complete item
+    buffer: Vec<u8>,
+    /// Length of the handshake that has not been seen yet
+    handshake: usize,
+    /// Bytes of an unknown message that still need to be skipped
+    skip: usize,
+    /// Set once the stream can no longer be parsed
+    broken: bool,
+}
+
+impl Reassembler {
+    /// Watch the bytes sent by `peer`, starting with the handshake
+    pub fn new(peer: Peer) -> Self {
+        Self {
+            peer,
+            buffer: vec![],
+            handshake: match peer {
+                Peer::Agent => size_of::<u32>(),
+                Peer::Daemon => size_of::<qubes_gui::XConfVersion>(),
+            },
+            skip: 0,
+            broken: false,
+        }
+    }
+
+    /// The peer whose bytes are being watched
+    pub fn peer(&self) -> Peer {
+        self.peer
+    }
+
+    /// Whether a bad length has made the rest of the stream unparsable
+    pub fn is_broken(&self) -> bool {
+        self.broken
+    }
+
+    /// The number of bytes buffered that are not yet part of a complete item
+    pub fn pending(&self) -> usize {
+        self.buffer.len()
+    }
+
+    /// Add `data` to the stream and call `f` on every item it completes
+    pub fn feed(&mut self, mut data: &[u8], mut f: impl FnMut(Item<'_>)) {
+        if self.broken {
+            return;
+        }
+        let skipped = self.skip.min(data.len());
+        self.skip -= skipped;
+        data = &data[skipped..];
+        self.buffer.extend_from_slice(data);
+        let mut used = 0;
+        loop {
+            let rest = &self.buffer[used..];
+            if self.handshake > 0 {
+                if rest.len() < self.handshake {
+                    break;
+                }
+                let version = <u32 as Castable>::from_le_bytes(&rest[..size_of::<u32>()]);
+                let xconf = if self.peer == Peer::Daemon {
+                    Some(qubes_gui::XConfVersion::from_le_bytes(&rest[..self.handshake]).xconf)
+                } else {
+                    None
+                };
+                f(Item::Handshake { version, xconf });
+                used += self.handshake;
+                self.handshake = 0;
+                continue;
+            }
+            if rest.len() < size_of::<UntrustedHeader>() {
+                break;
+            }
+            let untrusted_header = UntrustedHeader::from_le_bytes(&rest[..size_of::<Header>()]);
+            let header = match untrusted_header.validate_length() {
+                Err(_) => {
+                    f(Item::Violation(Violation::new(
+                        ViolationKind::BadLength,
+                        &untrusted_header,
+                    )));
+                    self.broken = true;
+                    self.buffer = vec![];
+                    return;
+                }
+                Ok(None) => {
+                    f(Item::Unknown(untrusted_header));
+                    used += size_of::<Header>();
+                    let len = untrusted_header.untrusted_len as usize;
+                    let skipped = len.min(self.buffer.len() - used);
+                    used += skipped;
+                    self.skip = len - skipped;
+                    continue;
+                }
+                Ok(Some(header)) => header,
+            };
+            if rest.len() < size_of::<Header>() + header.len() {
+                break;
+            }
+            if !may_send(self.peer, header.ty()) {
+                f(Item::Violation(Violation::from_header(
+                    ViolationKind::WrongDirection,
+                    &header,
+                )))
+            }
+            let body = &rest[size_of::<Header>()..][..header.len()];
+            f(Item::Message { header, body });
+            used += size_of::<Header>() + header.len();
+        }
+        self.buffer.drain(..used);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(ty: u32, len: u32) -> Vec<u8> {
+        let header = UntrustedHeader {
+            ty,
+            window: 7.into(),
+            untrusted_len: len,
+        };
+        [header.as_bytes(), &vec![0; len as usize][..]].concat()
+    }
+
+    fn items(r: &mut Reassembler, data: &[u8]) -> Vec<String> {
+        let mut v = vec![];
+        r.feed(data, |item| v.push(item.describe(false)));
+        v
+    }
+
+    #[test]
+    fn split_messages() {
+        let mut r = Reassembler::new(Peer::Agent);
+        let mut stream = qubes_gui::PROTOCOL_VERSION.as_bytes().to_vec();
+        stream.extend(message(qubes_gui::MSG_DESTROY, 0));
+        stream.extend(message(1000, 3));
+        stream.extend(message(qubes_gui::MSG_CLIPBOARD_DATA, 5));
+        let (a, b) = stream.split_at(19);
+        assert_eq!(items(&mut r, a), ["VERSION 1.7", "DESTROY window 7 "]);
+        assert_eq!(r.pending(), 3);
+        assert_eq!(
+            items(&mut r, b),
+            [
+                "unknown type 1000 window 7 (3 bytes)",
+                "CLIPBOARD_DATA window 7 <5 bytes redacted>"
+            ]
+        );
+        assert_eq!(r.pending(), 0);
+    }
+
+    #[test]
+    fn violations() {
+        let mut r = Reassembler::new(Peer::Agent);
+        r.feed(qubes_gui::PROTOCOL_VERSION.as_bytes(), |_| {});
+        let out = items(&mut r, &message(qubes_gui::MSG_CLOSE, 0));
+        assert_eq!(out.len(), 2);
+        assert!(out[0].contains("WrongDirection"));
+        assert!(!r.is_broken());
+        let out = items(&mut r, &message(qubes_gui::MSG_CLOSE, 1));
+        assert!(out[0].contains("BadLength"));
+        assert!(r.is_broken());
+    }
+}
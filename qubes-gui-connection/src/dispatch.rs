@@ -0,0 +1,227 @@
+/*
+ * The Qubes OS Project, https://www.qubes-os.org
+ *
+ * Copyright (C) 2021  Demi Marie Obenour  <demi@invisiblethingslab.com>
+ *
+ * This program is free software; you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License
+ * as published by the Free Software Foundation; either version 2
+ * of the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301, USA.
+ *
+ */
+
+//! Callback-based dispatch of daemon ⇒ agent messages.
+//!
+//! Implement [`MessageHandler`] and call [`dispatch`] whenever the connection
+//! is readable.  Every method has a default implementation that does
+//! nothing, so handlers only need to implement the messages they care
+//! about.
+
+use crate::{Connection, Event, Extension};
+use qubes_castable::Castable;
+use qubes_gui::{Header, Msg, WindowID, XConf, XConfVersion};
+use std::convert::TryInto;
+use std::io;
+use std::task::Poll;
+use std::time::Duration;
+
+/// Callbacks for messages sent by the GUI daemon.  All window IDs are
+/// untrusted: the daemon may send messages for windows that do not exist.
+#[allow(unused_variables)]
+pub trait MessageHandler {
+    /// A key has been pressed or released
+    fn on_keypress(&mut self, window: WindowID, keypress: &qubes_gui::Keypress) {}
+    /// A button has been pressed or released
+    fn on_button(&mut self, window: WindowID, button: &qubes_gui::Button) {}
+    /// The pointer has moved
+    fn on_motion(&mut self, window: WindowID, motion: &qubes_gui::Motion) {}
+    /// The pointer has entered or left a window
+    fn on_crossing(&mut self, window: WindowID, crossing: &qubes_gui::Crossing) {}
+    /// A window has gained or lost focus
+    fn on_focus(&mut self, window: WindowID, focus: &qubes_gui::Focus) {}
+    /// The daemon wants a window mapped
+    fn on_map(&mut self, window: WindowID, info: &qubes_gui::MapInfo) {}
+    /// A window has been moved and/or resized
+    fn on_configure(&mut self, window: WindowID, configure: &qubes_gui::Configure) {}
+    /// The user wishes to close a window
+    fn on_close(&mut self, window: WindowID) {}
+    /// The daemon has destroyed a window
+    fn on_destroy(&mut self, window: WindowID) {}
+    /// The daemon requests the clipboard contents.  The agent is expected to
+    /// reply with `MSG_CLIPBOARD_DATA`.
+    fn on_clipboard_req(&mut self, window: WindowID) {}
+    /// The daemon has sent clipboard data.  The data is UNTRUSTED and not
+    /// necessarily valid UTF-8.
+    fn on_clipboard_data(&mut self, window: WindowID, untrusted_data: &[u8]) {}
+    /// Part of the clipboard data, if streaming was enabled with
+    /// [`Connection::set_stream_clipboard`].  The data is UNTRUSTED.
+    fn on_clipboard_chunk(&mut self, window: WindowID, untrusted_data: &[u8]) {}
+    /// All of the streamed clipboard data has been received
+    fn on_clipboard_end(&mut self, window: WindowID, len: usize) {}
+    /// The keymap has changed
+    fn on_keymap(&mut self, window: WindowID, keymap: &qubes_gui::KeymapNotify) {}
+    /// The daemon has changed window flags
+    fn on_window_flags(&mut self, window: WindowID, flags: &qubes_gui::WindowFlags) {}
+    /// The daemon has stopped using the buffer of a window dump
+    fn on_dump_ack(&mut self, window: WindowID) {}
+    /// (Re)connected to a daemon.  Also called for the initial connection.
+    fn on_reconnected(&mut self, xconf: &XConfVersion) {}
+    /// An extension is no longer available
+    fn on_downgraded(&mut self, ext: Extension) {}
+    /// The root window has changed
+    fn on_screen_changed(&mut self, xconf: &XConf) {}
+    /// Nothing has been received from the daemon for `silent_for`.  Only
+    /// called if enabled with [`Connection::set_liveness_timeout`].
+    fn on_peer_unresponsive(&mut self, silent_for: Duration) {}
+    /// A message of unknown type, whose body has been discarded.  Only called
+    /// if enabled with [`Connection::set_report_unknown_messages`].  `len` is
+    /// UNTRUSTED.
+    fn on_unknown_message(&mut self, ty: u32, window: WindowID, len: u32) {}
+    /// A message from [`qubes_gui::proposed`], whose length has been checked.
+    /// Only called once the proposed version has been negotiated.
+    /// `untrusted_body` is UNTRUSTED.
+    #[cfg(feature = "proposed")]
+    fn on_proposed(&mut self, ty: u32, window: WindowID, untrusted_body: &[u8]) {}
+    /// A message the daemon should not send to an agent, or one this
+    /// library does not know how to handle.  The body has the length
+    /// required by [`Header::validate_length`].
+    fn on_other(&mut self, header: Header, body: &[u8]) {}
+}
+
+/// Call the method of `handler` that corresponds to a message
+pub fn dispatch_message<H: MessageHandler + ?Sized>(handler: &mut H, header: Header, body: &[u8]) {
+    let window = header.untrusted_window();
+    let ty: Msg = match header.ty().try_into() {
+        Ok(ty) => ty,
+        Err(_) => return handler.on_other(header, body),
+    };
+    match ty {
+        Msg::Keypress => handler.on_keypress(window, &Castable::from_bytes(body)),
+        Msg::Button => handler.on_button(window, &Castable::from_bytes(body)),
+        Msg::Motion => handler.on_motion(window, &Castable::from_bytes(body)),
+        Msg::Crossing => handler.on_crossing(window, &Castable::from_bytes(body)),
+        Msg::Focus => handler.on_focus(window, &Castable::from_bytes(body)),
+        Msg::Map => handler.on_map(window, &Castable::from_bytes(body)),
+        Msg::Configure => handler.on_configure(window, &Castable::from_bytes(body)),
+        Msg::Close => handler.on_close(window),
+        Msg::Destroy => handler.on_destroy(window),
+        Msg::ClipboardReq => handler.on_clipboard_req(window),
+        Msg::ClipboardData => handler.on_clipboard_data(window, body),
+        Msg::KeymapNotify => handler.on_keymap(window, &Castable::from_bytes(body)),
+        Msg::WindowFlags => handler.on_window_flags(window, &Castable::from_bytes(body)),
+        Msg::DumpAck => handler.on_dump_ack(window),
+        _ => handler.on_other(header, body),
+    }
+}
+
+/// Read every available event from `connection` and pass it to `handler`.
+/// Returns once no more events are available.
+///
+/// # Errors
+///
+/// Fails if reading from the connection fails.
+pub fn dispatch<H: MessageHandler + ?Sized>(
+    connection: &mut Connection,
+    handler: &mut H,
+) -> io::Result<()> {
+    loop {
+        match connection.read_event() {
+            Poll::Pending => break Ok(()),
+            Poll::Ready(Err(e)) => break Err(e),
+            Poll::Ready(Ok(event)) => dispatch_event(handler, event),
+        }
+    }
+}
+
+/// Call the method of `handler` that corresponds to an event
+pub(crate) fn dispatch_event<H: MessageHandler + ?Sized>(handler: &mut H, event: Event<'_>) {
+    match event {
+        Event::Message(buffer) => dispatch_message(handler, buffer.hdr(), buffer.body()),
+        Event::Reconnected(xconf) => handler.on_reconnected(&xconf),
+        Event::Downgraded(ext) => handler.on_downgraded(ext),
+        Event::ScreenChanged(xconf) => handler.on_screen_changed(&xconf),
+        Event::ClipboardChunk {
+            window,
+            untrusted_data,
+        } => handler.on_clipboard_chunk(window, untrusted_data),
+        Event::ClipboardEnd { window, len } => handler.on_clipboard_end(window, len),
+        Event::PeerUnresponsive { silent_for } => handler.on_peer_unresponsive(silent_for),
+        Event::UnknownMessage { ty, window, len } => handler.on_unknown_message(ty, window, len),
+        #[cfg(feature = "proposed")]
+        Event::Proposed {
+            ty,
+            window,
+            untrusted_body,
+        } => handler.on_proposed(ty, window, untrusted_body),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Default)]
+    struct Recorder(Vec<&'static str>);
+
+    impl MessageHandler for Recorder {
+        fn on_motion(&mut self, _: WindowID, _: &qubes_gui::Motion) {
+            self.0.push("motion")
+        }
+        fn on_close(&mut self, _: WindowID) {
+            self.0.push("close")
+        }
+        fn on_clipboard_data(&mut self, _: WindowID, data: &[u8]) {
+            assert_eq!(data, b"abc");
+            self.0.push("clipboard")
+        }
+        fn on_other(&mut self, _: Header, _: &[u8]) {
+            self.0.push("other")
+        }
+    }
+
+    fn header(ty: u32, len: usize) -> Header {
+        qubes_gui::UntrustedHeader {
+            ty,
+            window: std::num::NonZeroU32::new(1).unwrap().into(),
+            untrusted_len: len as u32,
+        }
+        .validate_length()
+        .unwrap()
+        .unwrap()
+    }
+
+    #[test]
+    fn dispatches_by_type() {
+        let mut recorder = Recorder::default();
+        let motion = qubes_gui::Motion::default();
+        let body = motion.as_bytes();
+        dispatch_message(
+            &mut recorder,
+            header(qubes_gui::MSG_MOTION, body.len()),
+            body,
+        );
+        dispatch_message(&mut recorder, header(qubes_gui::MSG_CLOSE, 0), &[]);
+        dispatch_message(
+            &mut recorder,
+            header(qubes_gui::MSG_CLIPBOARD_DATA, 3),
+            b"abc",
+        );
+        let create = qubes_gui::Create::default();
+        let body = create.as_bytes();
+        dispatch_message(
+            &mut recorder,
+            header(qubes_gui::MSG_CREATE, body.len()),
+            body,
+        );
+        assert_eq!(recorder.0, ["motion", "close", "clipboard", "other"]);
+    }
+}
@@ -0,0 +1,163 @@
+/*
+ * The Qubes OS Project, https://www.qubes-os.org
+ *
+ * Copyright (C) 2021  Demi Marie Obenour  <demi@invisiblethingslab.com>
+ *
+ * This program is free software; you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License
+ * as published by the Free Software Foundation; either version 2
+ * of the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301, USA.
+ *
+ */
+
+//! Bookkeeping of window dumps the daemon has not acknowledged yet.
+//!
+//! With [`Extension::DumpAck`](crate::Extension::DumpAck), the daemon sends
+//! `MSG_WINDOW_DUMP_ACK` once it has stopped using the buffer a
+//! `MSG_WINDOW_DUMP` replaced, and only then may the grant references of that
+//! buffer be freed or reused.  The daemon acknowledges the dumps of each
+//! window in the order they were sent.
+
+use std::collections::{HashMap, VecDeque};
+use std::num::NonZeroU32;
+
+/// Run once a dump has been acknowledged
+pub(crate) type DumpCallback = Box<dyn FnOnce()>;
+
+/// Outstanding dumps, oldest first, for each window
+#[derive(Default)]
+pub(crate) struct DumpTracker {
+    windows: HashMap<NonZeroU32, VecDeque<Vec<DumpCallback>>>,
+    /// Total number of outstanding dumps
+    outstanding: usize,
+    /// Maximum value of `outstanding`
+    limit: Option<usize>,
+}
+
+impl std::fmt::Debug for DumpTracker {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DumpTracker")
+            .field("outstanding", &self.outstanding)
+            .field("limit", &self.limit)
+            .finish_non_exhaustive()
+    }
+}
+
+impl DumpTracker {
+    pub(crate) fn set_limit(&mut self, limit: Option<usize>) {
+        self.limit = limit
+    }
+
+    /// Can another dump be sent without exceeding the limit?
+    pub(crate) fn has_room(&self) -> bool {
+        !matches!(self.limit, Some(limit) if self.outstanding >= limit)
+    }
+
+    /// The number of outstanding dumps, for one window or in total
+    pub(crate) fn outstanding(&self, window: Option<NonZeroU32>) -> usize {
+        match window {
+            None => self.outstanding,
+            Some(window) => self.windows.get(&window).map_or(0, VecDeque::len),
+        }
+    }
+
+    /// A dump has been sent for `window`
+    pub(crate) fn sent(&mut self, window: NonZeroU32) {
+        self.windows.entry(window).or_default().push_back(vec![]);
+        self.outstanding += 1
+    }
+
+    /// Run `callback` once the newest dump for `window` is acknowledged.  If
+    /// there is none, `callback` is returned, so that the caller can run it
+    /// immediately.
+    pub(crate) fn on_acked(
+        &mut self,
+        window: NonZeroU32,
+        callback: DumpCallback,
+    ) -> Option<DumpCallback> {
+        match self.windows.get_mut(&window).and_then(VecDeque::back_mut) {
+            Some(callbacks) => {
+                callbacks.push(callback);
+                None
+            }
+            None => Some(callback),
+        }
+    }
+
+    /// The daemon has acknowledged the oldest dump for `window`.  Spurious
+    /// acknowledgements are ignored.
+    pub(crate) fn acked(&mut self, window: NonZeroU32) {
+        let queue = match self.windows.get_mut(&window) {
+            Some(queue) => queue,
+            None => return,
+        };
+        let callbacks = queue.pop_front().unwrap_or_default();
+        if queue.is_empty() {
+            self.windows.remove(&window);
+        }
+        self.outstanding -= 1;
+        callbacks.into_iter().for_each(|callback| callback())
+    }
+
+    /// No more acknowledgements will arrive, because the daemon has gone away
+    /// or no longer supports them.  The daemon is no longer using any old
+    /// buffers, so every callback runs.
+    pub(crate) fn complete_all(&mut self) {
+        self.outstanding = 0;
+        for (_, queue) in self.windows.drain() {
+            queue.into_iter().flatten().for_each(|callback| callback())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[test]
+    fn acks_in_order() {
+        let log = Rc::new(RefCell::new(vec![]));
+        let record = |n: u32| -> DumpCallback {
+            let log = log.clone();
+            Box::new(move || log.borrow_mut().push(n))
+        };
+        let (a, b) = (NonZeroU32::new(1).unwrap(), NonZeroU32::new(2).unwrap());
+        let mut tracker = DumpTracker::default();
+        tracker.set_limit(Some(2));
+        assert!(
+            tracker.on_acked(a, record(0)).is_some(),
+            "nothing to wait for"
+        );
+        tracker.sent(a);
+        assert!(tracker.on_acked(a, record(1)).is_none());
+        tracker.sent(a);
+        assert!(tracker.on_acked(a, record(2)).is_none());
+        assert!(!tracker.has_room());
+        assert_eq!(tracker.outstanding(Some(a)), 2);
+        assert_eq!(tracker.outstanding(Some(b)), 0);
+
+        tracker.acked(b);
+        tracker.acked(a);
+        assert_eq!(*log.borrow(), [1]);
+        assert!(tracker.has_room());
+        tracker.sent(b);
+        assert!(tracker.on_acked(b, record(3)).is_none());
+        assert_eq!(tracker.outstanding(None), 2);
+
+        tracker.complete_all();
+        log.borrow_mut().sort_unstable();
+        assert_eq!(*log.borrow(), [1, 2, 3]);
+        assert_eq!(tracker.outstanding(None), 0);
+    }
+}
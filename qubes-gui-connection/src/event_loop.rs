@@ -0,0 +1,388 @@
+/*
+ * The Qubes OS Project, https://www.qubes-os.org
+ *
+ * Copyright (C) 2021  Demi Marie Obenour  <demi@invisiblethingslab.com>
+ *
+ * This program is free software; you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License
+ * as published by the Free Software Foundation; either version 2
+ * of the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301, USA.
+ *
+ */
+
+//! A main loop for simple agents.
+
+use crate::dispatch::{dispatch, MessageHandler};
+use crate::Connection;
+use std::collections::{BTreeMap, BTreeSet};
+use std::io;
+use std::os::raw::c_int;
+use std::os::unix::io::AsRawFd as _;
+use std::time::{Duration, Instant};
+
+/// Identifies a file descriptor or timer registered with an [`EventLoop`]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Token(u64);
+
+/// Something registered with an [`EventLoop`] that needs attention
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Ready {
+    /// A file descriptor is readable, or has hung up or failed
+    Fd(Token),
+    /// A timer has expired.  It has been removed from the loop.
+    Timer(Token),
+}
+
+/// Wait until at least one of `fds` is ready or `timeout` (forever if
+/// [`None`]) has passed, and return which are ready.  Each file descriptor
+/// is ready when it is readable or has hung up or failed, and also, if its
+/// flag is set, when it is writable.  Being interrupted by a signal is
+/// treated like a timeout.
+pub fn poll_fds(fds: &[(c_int, bool)], timeout: Option<Duration>) -> io::Result<Vec<bool>> {
+    let timeout_ms = match timeout {
+        None => -1,
+        Some(t) => t.as_nanos().div_ceil(1_000_000).min(c_int::MAX as u128) as c_int,
+    };
+    let mut pollfds: Vec<libc::pollfd> = fds
+        .iter()
+        .map(|&(fd, writable)| libc::pollfd {
+            fd,
+            events: if writable {
+                libc::POLLIN | libc::POLLOUT
+            } else {
+                libc::POLLIN
+            },
+            revents: 0,
+        })
+        .collect();
+    // SAFETY: `pollfds` is a valid array of `pollfds.len()` `struct pollfd`s.
+    if unsafe {
+        libc::poll(
+            pollfds.as_mut_ptr(),
+            pollfds.len() as libc::nfds_t,
+            timeout_ms,
+        )
+    } < 0
+    {
+        let e = io::Error::last_os_error();
+        if e.kind() != io::ErrorKind::Interrupted {
+            return Err(e);
+        }
+        pollfds.iter_mut().for_each(|fd| fd.revents = 0);
+    }
+    Ok(pollfds.iter().map(|fd| fd.revents != 0).collect())
+}
+
+/// Fail if the peer is gone and the connection will not be reestablished,
+/// as its file descriptor would otherwise keep reporting the hangup.
+pub(crate) fn check_connected(connection: &Connection) -> io::Result<()> {
+    if connection.needs_reconnect() && connection.reconnect_deadline().is_none() {
+        Err(io::Error::new(
+            io::ErrorKind::NotConnected,
+            "Peer disconnected",
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+/// Drives a [`Connection`]: waits on its file descriptor and on any file
+/// descriptors and timers registered by the caller, reads and dispatches
+/// messages, flushes queued writes, and honors the reconnection and liveness
+/// deadlines of the connection.
+///
+/// ```no_run
+/// # use qubes_gui_connection::{Connection, EventLoop, MessageHandler, Ready};
+/// struct Handler;
+/// impl MessageHandler for Handler {}
+///
+/// let mut event_loop = EventLoop::new(Connection::agent(0)?);
+/// let redraw = event_loop.add_timer(std::time::Instant::now());
+/// loop {
+///     for ready in event_loop.turn(&mut Handler, None)? {
+///         if ready == Ready::Timer(redraw) {
+///             // draw, using event_loop.connection()
+///         }
+///     }
+/// }
+/// # Ok::<(), std::io::Error>(())
+/// ```
+#[derive(Debug)]
+pub struct EventLoop {
+    connection: Connection,
+    fds: BTreeMap<Token, c_int>,
+    timers: BTreeSet<(Instant, Token)>,
+    next_token: u64,
+}
+
+impl EventLoop {
+    /// Drive `connection`
+    pub fn new(connection: Connection) -> Self {
+        Self {
+            connection,
+            fds: BTreeMap::new(),
+            timers: BTreeSet::new(),
+            next_token: 0,
+        }
+    }
+
+    /// The connection being driven, for sending messages
+    pub fn connection(&mut self) -> &mut Connection {
+        &mut self.connection
+    }
+
+    /// Stop driving the connection and return it
+    pub fn into_inner(self) -> Connection {
+        self.connection
+    }
+
+    fn token(&mut self) -> Token {
+        self.next_token += 1;
+        Token(self.next_token)
+    }
+
+    /// Report [`Ready::Fd`] whenever `fd` is readable.  The caller remains
+    /// responsible for `fd`, and must remove it before closing it.
+    pub fn add_fd(&mut self, fd: c_int) -> Token {
+        let token = self.token();
+        self.fds.insert(token, fd);
+        token
+    }
+
+    /// Stop watching a file descriptor.  Returns `false` if `token` was not
+    /// registered with [`EventLoop::add_fd`].
+    pub fn remove_fd(&mut self, token: Token) -> bool {
+        self.fds.remove(&token).is_some()
+    }
+
+    /// Report [`Ready::Timer`] once, at or after `deadline`
+    pub fn add_timer(&mut self, deadline: Instant) -> Token {
+        let token = self.token();
+        self.timers.insert((deadline, token));
+        token
+    }
+
+    /// Cancel a timer.  Returns `false` if it has already expired or was
+    /// never added.
+    pub fn cancel_timer(&mut self, token: Token) -> bool {
+        let key = self.timers.iter().find(|&&(_, t)| t == token).copied();
+        key.is_some_and(|key| self.timers.remove(&key))
+    }
+
+    /// The earliest time at which something must happen, if any
+    fn deadline(&self) -> Option<Instant> {
+        let timer = self.timers.iter().next().map(|&(deadline, _)| deadline);
+        [
+            timer,
+            self.connection.reconnect_deadline(),
+            self.connection.liveness_deadline(),
+        ]
+        .iter()
+        .flatten()
+        .min()
+        .copied()
+    }
+
+    /// Wait until something happens, or at most `timeout` if that is not
+    /// `None`, and handle it.  Messages and other connection events are
+    /// passed to `handler`; everything else that needs attention is
+    /// returned, file descriptors first.  Interruption by a signal is not an
+    /// error: it just returns early.
+    ///
+    /// # Errors
+    ///
+    /// Fails if waiting fails, if reading from the connection fails, or if
+    /// the peer has disconnected and no [`crate::ReconnectPolicy`] will
+    /// reconnect.
+    pub fn turn<H: MessageHandler + ?Sized>(
+        &mut self,
+        handler: &mut H,
+        timeout: Option<Duration>,
+    ) -> io::Result<Vec<Ready>> {
+        // Handle anything that arrived before the last wakeup was
+        // acknowledged, so that it is not left waiting for the next one.
+        dispatch(&mut self.connection, handler)?;
+        let now = Instant::now();
+        let deadline = match (self.deadline(), timeout.map(|t| now + t)) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        };
+        // Queued writes are flushed by `dispatch`, so the connection also
+        // needs attention once its transport can write out what it buffered.
+        let connection = (
+            self.connection.as_raw_fd(),
+            self.connection.wants_writable(),
+        );
+        let fds: Vec<(c_int, bool)> = std::iter::once(connection)
+            .chain(self.fds.values().map(|&fd| (fd, false)))
+            .collect();
+        let timeout = deadline.map(|deadline| deadline.saturating_duration_since(now));
+        let ready = poll_fds(&fds, timeout)?;
+        if ready[0] {
+            self.connection.wait()
+        }
+        dispatch(&mut self.connection, handler)?;
+        check_connected(&self.connection)?;
+        let mut ready: Vec<Ready> = self
+            .fds
+            .keys()
+            .zip(&ready[1..])
+            .filter(|(_, &ready)| ready)
+            .map(|(&token, _)| Ready::Fd(token))
+            .collect();
+        let now = Instant::now();
+        while let Some(&(deadline, token)) = self.timers.iter().next() {
+            if deadline > now {
+                break;
+            }
+            self.timers.remove(&(deadline, token));
+            ready.push(Ready::Timer(token))
+        }
+        Ok(ready)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{LoopbackTransport, SocketTransport};
+    use std::io::Write as _;
+    use std::os::unix::net::UnixStream;
+
+    #[derive(Default)]
+    struct Recorder {
+        reconnected: usize,
+        clipboard_requests: usize,
+    }
+
+    impl MessageHandler for Recorder {
+        fn on_reconnected(&mut self, _: &qubes_gui::XConfVersion) {
+            self.reconnected += 1
+        }
+        fn on_clipboard_req(&mut self, _: qubes_gui::WindowID) {
+            self.clipboard_requests += 1
+        }
+    }
+
+    #[test]
+    fn timers() {
+        let (ours, _theirs) = LoopbackTransport::pair();
+        let mut event_loop = EventLoop::new(Connection::agent_over(ours));
+        let now = Instant::now();
+        let late = event_loop.add_timer(now + Duration::from_secs(3600));
+        let cancelled = event_loop.add_timer(now);
+        let soon = event_loop.add_timer(now + Duration::from_millis(20));
+        let first = event_loop.add_timer(now);
+        assert!(event_loop.cancel_timer(cancelled));
+        assert!(!event_loop.cancel_timer(cancelled));
+        let mut handler = Recorder::default();
+        let ready = event_loop.turn(&mut handler, None).unwrap();
+        assert_eq!(ready, [Ready::Timer(first)]);
+        let ready = event_loop.turn(&mut handler, None).unwrap();
+        assert_eq!(ready, [Ready::Timer(soon)]);
+        assert!(now.elapsed() >= Duration::from_millis(20));
+        let ready = event_loop
+            .turn(&mut handler, Some(Duration::from_millis(1)))
+            .unwrap();
+        assert!(ready.is_empty());
+        assert!(event_loop.cancel_timer(late));
+    }
+
+    #[test]
+    fn connection_and_user_fds() {
+        let (a, b) = UnixStream::pair().unwrap();
+        let mut daemon =
+            Connection::daemon_over(SocketTransport::new(b).unwrap(), Default::default());
+        let agent = Connection::agent_over(SocketTransport::new(a).unwrap());
+        let mut event_loop = EventLoop::new(agent);
+        let (mut user, theirs) = UnixStream::pair().unwrap();
+        let token = event_loop.add_fd(theirs.as_raw_fd());
+        let mut handler = Recorder::default();
+        while handler.reconnected == 0 {
+            daemon.wait();
+            let _ = daemon.read_message();
+            let ready = event_loop
+                .turn(&mut handler, Some(Duration::from_millis(10)))
+                .unwrap();
+            assert!(ready.is_empty());
+        }
+        daemon
+            .send_raw(&[], 1.into(), qubes_gui::MSG_CLIPBOARD_REQ)
+            .unwrap();
+        while handler.clipboard_requests == 0 {
+            assert!(event_loop.turn(&mut handler, None).unwrap().is_empty());
+        }
+        user.write_all(b"x").unwrap();
+        assert_eq!(
+            event_loop.turn(&mut handler, None).unwrap(),
+            [Ready::Fd(token)]
+        );
+        assert!(event_loop.remove_fd(token));
+        assert!(event_loop
+            .turn(&mut handler, Some(Duration::from_millis(1)))
+            .unwrap()
+            .is_empty());
+        drop(daemon);
+        let error = loop {
+            match event_loop.turn(&mut handler, None) {
+                Ok(ready) => assert!(ready.is_empty()),
+                Err(e) => break e,
+            }
+        };
+        assert_eq!(error.kind(), io::ErrorKind::NotConnected);
+    }
+
+    #[test]
+    fn waits_until_writable() {
+        const COUNT: usize = 64;
+        let (a, b) = UnixStream::pair().unwrap();
+        // The daemon never sends anything after the handshake, so only the
+        // socket becoming writable can wake the agent up.
+        let daemon = std::thread::spawn(move || {
+            let mut daemon =
+                Connection::daemon_over(SocketTransport::new(b).unwrap(), Default::default());
+            let mut received = 0;
+            while received < COUNT {
+                daemon.wait();
+                match daemon.read_message() {
+                    std::task::Poll::Ready(Ok(_)) => received += 1,
+                    std::task::Poll::Ready(Err(e)) => panic!("{}", e),
+                    std::task::Poll::Pending => std::thread::sleep(Duration::from_millis(1)),
+                }
+            }
+        });
+        let agent = Connection::agent_over(SocketTransport::new(a).unwrap());
+        let mut event_loop = EventLoop::new(agent);
+        let mut handler = Recorder::default();
+        while handler.reconnected == 0 {
+            event_loop.turn(&mut handler, None).unwrap();
+        }
+        let data = vec![b'x'; 60000];
+        for _ in 0..COUNT {
+            event_loop
+                .connection()
+                .send_raw(&data, 0.into(), qubes_gui::MSG_CLIPBOARD_DATA)
+                .unwrap();
+        }
+        assert!(event_loop.connection().wants_writable());
+        let start = Instant::now();
+        while !event_loop.connection.raw.queue.is_empty()
+            || event_loop.connection().wants_writable()
+        {
+            event_loop
+                .turn(&mut handler, Some(Duration::from_secs(10)))
+                .unwrap();
+            assert!(start.elapsed() < Duration::from_secs(10));
+        }
+        daemon.join().unwrap();
+    }
+}
@@ -0,0 +1,234 @@
+/*
+ * The Qubes OS Project, https://www.qubes-os.org
+ *
+ * Copyright (C) 2021  Demi Marie Obenour  <demi@invisiblethingslab.com>
+ *
+ * This program is free software; you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License
+ * as published by the Free Software Foundation; either version 2
+ * of the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301, USA.
+ *
+ */
+
+//! Protocol extensions, and falling back when they go away.
+//!
+//! Which extensions are available depends on the protocol version negotiated
+//! with the peer.  That can change at any reconnection, and an extension can
+//! also be rejected by the peer mid-session.  In either case, the connection
+//! runs the fallback hooks registered with
+//! [`Connection::on_downgrade`](crate::Connection::on_downgrade), and then
+//! reports [`Event::Downgraded`](crate::Event::Downgraded) from
+//! [`Connection::read_event`](crate::Connection::read_event), so that
+//! higher-level code can switch to a fallback path.
+
+use std::collections::VecDeque;
+
+/// A protocol feature that is not available with every peer
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[non_exhaustive]
+pub enum Extension {
+    /// The daemon acknowledges each `MSG_WINDOW_DUMP` with
+    /// `MSG_WINDOW_DUMP_ACK`.  Without this, agents cannot know when the
+    /// daemon has stopped using the old buffer.
+    DumpAck,
+    /// The daemon sends
+    /// [`MSG_SCREEN_CONFIGURED`](qubes_gui::proposed::MSG_SCREEN_CONFIGURED)
+    /// when the root window changes.  Without this, agents only learn of the
+    /// change when they reconnect.  This is a proposal, only available if
+    /// both sides opt in to
+    /// [`PROPOSED_VERSION`](qubes_gui::proposed::PROPOSED_VERSION) with
+    /// [`Connection::set_propose`](crate::Connection::set_propose).
+    #[cfg(feature = "proposed")]
+    ScreenConfig,
+}
+
+impl Extension {
+    /// All known extensions
+    #[cfg(not(feature = "proposed"))]
+    pub const ALL: &'static [Extension] = &[Extension::DumpAck];
+
+    /// All known extensions
+    #[cfg(feature = "proposed")]
+    pub const ALL: &'static [Extension] = &[Extension::DumpAck, Extension::ScreenConfig];
+
+    /// The minimum protocol version (as used on the wire) that supports this
+    /// extension
+    pub fn min_version(self) -> u32 {
+        match self {
+            Extension::DumpAck => {
+                qubes_gui::PROTOCOL_VERSION_MAJOR << 16 | qubes_gui::Msg::DumpAck.since().unwrap()
+            }
+            #[cfg(feature = "proposed")]
+            Extension::ScreenConfig => qubes_gui::proposed::PROPOSED_VERSION,
+        }
+    }
+
+    fn bit(self) -> u32 {
+        1 << self as u32
+    }
+}
+
+/// A set of [`Extension`]s
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct Extensions(u32);
+
+impl Extensions {
+    /// The extensions supported by protocol version `version`
+    pub fn for_version(version: u32) -> Self {
+        Extension::ALL
+            .iter()
+            .filter(|ext| version >> 16 == ext.min_version() >> 16 && version >= ext.min_version())
+            .fold(Self::default(), |set, &ext| set.with(ext))
+    }
+
+    /// Is `ext` in the set?
+    pub fn contains(self, ext: Extension) -> bool {
+        self.0 & ext.bit() != 0
+    }
+
+    /// The set with `ext` added
+    pub fn with(self, ext: Extension) -> Self {
+        Self(self.0 | ext.bit())
+    }
+
+    /// The set with `ext` removed
+    pub fn without(self, ext: Extension) -> Self {
+        Self(self.0 & !ext.bit())
+    }
+
+    /// Iterate over the members of the set
+    pub fn iter(self) -> impl Iterator<Item = Extension> {
+        Extension::ALL
+            .iter()
+            .copied()
+            .filter(move |&ext| self.contains(ext))
+    }
+}
+
+/// A fallback hook, run when an extension becomes unavailable
+pub type FallbackHook = Box<dyn FnMut(Extension)>;
+
+/// Tracks which extensions are available, and runs fallback hooks when one
+/// goes away
+#[derive(Default)]
+pub(crate) struct DowngradeManager {
+    /// Extensions that can currently be used
+    active: Extensions,
+    /// Extensions the current peer has rejected
+    rejected: Extensions,
+    /// Fallback hooks
+    hooks: Vec<(Extension, FallbackHook)>,
+    /// Downgrades not yet reported by `read_event`
+    pending: VecDeque<Extension>,
+}
+
+impl std::fmt::Debug for DowngradeManager {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DowngradeManager")
+            .field("active", &self.active)
+            .field("rejected", &self.rejected)
+            .field("hooks", &self.hooks.len())
+            .field("pending", &self.pending)
+            .finish()
+    }
+}
+
+impl DowngradeManager {
+    pub(crate) fn active(&self) -> Extensions {
+        self.active
+    }
+
+    pub(crate) fn add_hook(&mut self, ext: Extension, hook: FallbackHook) {
+        self.hooks.push((ext, hook))
+    }
+
+    fn downgrade(&mut self, ext: Extension) {
+        self.active = self.active.without(ext);
+        for (_, hook) in self.hooks.iter_mut().filter(|(e, _)| *e == ext) {
+            hook(ext)
+        }
+        self.pending.push_back(ext)
+    }
+
+    /// Version negotiation with a (possibly new) peer has completed
+    pub(crate) fn negotiated(&mut self, version: u32) {
+        let new = Extensions::for_version(version);
+        for ext in self.active.iter().filter(|&ext| !new.contains(ext)) {
+            self.downgrade(ext)
+        }
+        self.active = new;
+        self.rejected = Extensions::default();
+    }
+
+    /// The peer has rejected `ext`.  Returns `false` if it was not active.
+    pub(crate) fn reject(&mut self, ext: Extension) -> bool {
+        if !self.active.contains(ext) {
+            return false;
+        }
+        self.rejected = self.rejected.with(ext);
+        self.downgrade(ext);
+        true
+    }
+
+    /// The next downgrade to report, if any
+    pub(crate) fn next_event(&mut self) -> Option<Extension> {
+        self.pending.pop_front()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{cell::Cell, rc::Rc};
+
+    #[test]
+    fn version_gating() {
+        assert!(Extensions::for_version(0x10007).contains(Extension::DumpAck));
+        assert!(!Extensions::for_version(0x10006).contains(Extension::DumpAck));
+        assert!(!Extensions::for_version(0x20007).contains(Extension::DumpAck));
+        #[cfg(feature = "proposed")]
+        {
+            assert!(!Extensions::for_version(0x10007).contains(Extension::ScreenConfig));
+            assert!(Extensions::for_version(0x10008).contains(Extension::ScreenConfig));
+        }
+    }
+
+    #[test]
+    fn downgrade_on_reconnect() {
+        let fired = Rc::new(Cell::new(0));
+        let f = fired.clone();
+        let mut manager = DowngradeManager::default();
+        manager.add_hook(Extension::DumpAck, Box::new(move |_| f.set(f.get() + 1)));
+        manager.negotiated(0x10007);
+        assert_eq!(
+            manager.next_event(),
+            None,
+            "no downgrade at first connection"
+        );
+        manager.negotiated(0x10006);
+        assert_eq!(fired.get(), 1);
+        assert_eq!(manager.next_event(), Some(Extension::DumpAck));
+        assert_eq!(manager.next_event(), None);
+        assert!(!manager.reject(Extension::DumpAck), "already gone");
+    }
+
+    #[test]
+    fn rejection() {
+        let mut manager = DowngradeManager::default();
+        manager.negotiated(0x10007);
+        assert!(manager.reject(Extension::DumpAck));
+        assert!(!manager.active().contains(Extension::DumpAck));
+        assert_eq!(manager.next_event(), Some(Extension::DumpAck));
+        manager.negotiated(0x10007);
+        assert!(manager.active().contains(Extension::DumpAck), "new peer");
+    }
+}
@@ -0,0 +1,334 @@
+/*
+ * The Qubes OS Project, https://www.qubes-os.org
+ *
+ * Copyright (C) 2021  Demi Marie Obenour  <demi@invisiblethingslab.com>
+ *
+ * This program is free software; you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License
+ * as published by the Free Software Foundation; either version 2
+ * of the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301, USA.
+ *
+ */
+
+//! Which of the agent's windows has the focus, and how they are stacked.
+//!
+//! The daemon never says how windows are stacked, so [`FocusTracker`] works
+//! it out from what it does say.  A window that gains the focus is assumed
+//! to have been raised, as window managers do.  When the pointer enters a
+//! window, that window must be above every other window under the pointer.
+//! This is a best guess: other stacking changes go unnoticed.
+
+use crate::MessageHandler;
+use qubes_gui::{FocusEvent, Rectangle, WindowID, XConfVersion};
+use std::collections::{BTreeMap, VecDeque};
+use std::convert::TryFrom;
+use std::num::NonZeroU32;
+
+/// X11 `EnterNotify`, the type of a [`qubes_gui::Crossing`] into a window
+const ENTER_NOTIFY: u32 = 7;
+/// X11 `LeaveNotify`, the type of a [`qubes_gui::Crossing`] out of a window
+const LEAVE_NOTIFY: u32 = 8;
+
+/// A change reported by [`FocusTracker::poll`]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum FocusChange {
+    /// The window has gained the keyboard focus
+    Focused(NonZeroU32),
+    /// The window has lost the keyboard focus
+    Unfocused(NonZeroU32),
+    /// The pointer has entered the window
+    PointerEntered(NonZeroU32),
+    /// The pointer has left the window
+    PointerLeft(NonZeroU32),
+    /// The stacking order has changed
+    Restacked,
+}
+
+/// Tracks focus, pointer, and stacking order from the messages the daemon
+/// sends.  Feed it messages by passing it to [`crate::dispatch`], or by
+/// calling its [`MessageHandler`] methods from another handler.  Only windows
+/// added with [`FocusTracker::add_window`] are tracked.
+#[derive(Debug, Default)]
+pub struct FocusTracker {
+    rectangles: BTreeMap<NonZeroU32, Rectangle>,
+    /// Bottom to top
+    stacking: Vec<NonZeroU32>,
+    focused: Option<NonZeroU32>,
+    pointer: Option<NonZeroU32>,
+    /// Changes not yet returned by [`FocusTracker::poll`]
+    changes: VecDeque<FocusChange>,
+}
+
+impl FocusTracker {
+    /// Create a tracker with no windows
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Track `window`, which occupies `rectangle`.  New windows are assumed
+    /// to be on top.
+    pub fn add_window(&mut self, window: NonZeroU32, rectangle: Rectangle) {
+        if self.rectangles.insert(window, rectangle).is_none() {
+            self.stacking.push(window)
+        }
+    }
+
+    /// Stop tracking `window`, which has been destroyed
+    pub fn remove_window(&mut self, window: NonZeroU32) {
+        if self.rectangles.remove(&window).is_none() {
+            return;
+        }
+        self.stacking.retain(|&w| w != window);
+        if self.focused == Some(window) {
+            self.focused = None
+        }
+        if self.pointer == Some(window) {
+            self.pointer = None
+        }
+    }
+
+    /// The window with the keyboard focus, if it is one of the agent's
+    pub fn focused(&self) -> Option<NonZeroU32> {
+        self.focused
+    }
+
+    /// The window the pointer is in, if it is one of the agent's
+    pub fn pointer(&self) -> Option<NonZeroU32> {
+        self.pointer
+    }
+
+    /// The tracked windows, from the bottom of the stack to the top
+    pub fn stacking(&self) -> &[NonZeroU32] {
+        &self.stacking
+    }
+
+    /// Is `window` known to be above `other`?
+    pub fn is_above(&self, window: NonZeroU32, other: NonZeroU32) -> bool {
+        let position = |w| self.stacking.iter().position(|&s| s == w);
+        matches!((position(window), position(other)), (Some(a), Some(b)) if a > b)
+    }
+
+    /// The next change, if any.  Call this until it returns `None`.
+    pub fn poll(&mut self) -> Option<FocusChange> {
+        self.changes.pop_front()
+    }
+
+    /// `window`, if it is tracked
+    fn tracked(&self, window: WindowID) -> Option<NonZeroU32> {
+        window.window.filter(|w| self.rectangles.contains_key(w))
+    }
+
+    /// Move the window at `index` to just above the window at `above`
+    fn restack(&mut self, index: usize, above: usize) {
+        if index >= above {
+            return;
+        }
+        let window = self.stacking.remove(index);
+        self.stacking.insert(above, window);
+        self.changes.push_back(FocusChange::Restacked)
+    }
+}
+
+impl MessageHandler for FocusTracker {
+    fn on_focus(&mut self, window: WindowID, focus: &qubes_gui::Focus) {
+        let window = match self.tracked(window) {
+            Some(window) => window,
+            None => return,
+        };
+        match FocusEvent::try_from(focus.ty) {
+            Ok(FocusEvent::In) => {
+                if let Some(old) = self.focused.filter(|&old| old != window) {
+                    self.changes.push_back(FocusChange::Unfocused(old))
+                }
+                if self.focused != Some(window) {
+                    self.focused = Some(window);
+                    self.changes.push_back(FocusChange::Focused(window))
+                }
+                let index = self.stacking.iter().position(|&w| w == window);
+                if let Some(index) = index {
+                    self.restack(index, self.stacking.len() - 1)
+                }
+            }
+            Ok(FocusEvent::Out) if self.focused == Some(window) => {
+                self.focused = None;
+                self.changes.push_back(FocusChange::Unfocused(window))
+            }
+            _ => {}
+        }
+    }
+
+    fn on_crossing(&mut self, window: WindowID, crossing: &qubes_gui::Crossing) {
+        let window = match self.tracked(window) {
+            Some(window) => window,
+            None => return,
+        };
+        match crossing.ty {
+            ENTER_NOTIFY => {
+                if self.pointer != Some(window) {
+                    self.pointer = Some(window);
+                    self.changes.push_back(FocusChange::PointerEntered(window))
+                }
+                let origin = self.rectangles[&window].top_left;
+                let (x, y) = (
+                    i64::from(origin.x) + i64::from(crossing.coordinates.x),
+                    i64::from(origin.y) + i64::from(crossing.coordinates.y),
+                );
+                let contains = |r: &Rectangle| {
+                    let (left, top) = (i64::from(r.top_left.x), i64::from(r.top_left.y));
+                    (left..left + i64::from(r.size.width)).contains(&x)
+                        && (top..top + i64::from(r.size.height)).contains(&y)
+                };
+                let index = self.stacking.iter().position(|&w| w == window);
+                let covered = self
+                    .stacking
+                    .iter()
+                    .rposition(|w| *w != window && contains(&self.rectangles[w]));
+                if let (Some(index), Some(covered)) = (index, covered) {
+                    self.restack(index, covered)
+                }
+            }
+            LEAVE_NOTIFY if self.pointer == Some(window) => {
+                self.pointer = None;
+                self.changes.push_back(FocusChange::PointerLeft(window))
+            }
+            _ => {}
+        }
+    }
+
+    fn on_configure(&mut self, window: WindowID, configure: &qubes_gui::Configure) {
+        if let Some(window) = self.tracked(window) {
+            self.rectangles.insert(window, configure.rectangle);
+        }
+    }
+
+    fn on_destroy(&mut self, window: WindowID) {
+        if let Some(window) = window.window {
+            self.remove_window(window)
+        }
+    }
+
+    fn on_reconnected(&mut self, _xconf: &XConfVersion) {
+        // The new daemon will say where the focus and pointer are
+        if let Some(window) = self.focused.take() {
+            self.changes.push_back(FocusChange::Unfocused(window))
+        }
+        if let Some(window) = self.pointer.take() {
+            self.changes.push_back(FocusChange::PointerLeft(window))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use qubes_gui::{Coordinates, WindowSize};
+
+    fn id(n: u32) -> NonZeroU32 {
+        NonZeroU32::new(n).unwrap()
+    }
+
+    fn rectangle(x: i32, y: i32) -> Rectangle {
+        Rectangle {
+            top_left: Coordinates { x, y },
+            size: WindowSize {
+                width: 100,
+                height: 100,
+            },
+        }
+    }
+
+    fn focus(ty: u32) -> qubes_gui::Focus {
+        qubes_gui::Focus {
+            ty,
+            mode: 0,
+            detail: 0,
+        }
+    }
+
+    fn changes(tracker: &mut FocusTracker) -> Vec<FocusChange> {
+        std::iter::from_fn(|| tracker.poll()).collect()
+    }
+
+    #[test]
+    fn focus_raises() {
+        let mut tracker = FocusTracker::new();
+        tracker.add_window(id(1), rectangle(0, 0));
+        tracker.add_window(id(2), rectangle(50, 50));
+        tracker.on_focus(id(1).into(), &focus(qubes_gui::EV_FOCUS_IN));
+        assert_eq!(
+            changes(&mut tracker),
+            [FocusChange::Focused(id(1)), FocusChange::Restacked]
+        );
+        assert_eq!(tracker.stacking(), [id(2), id(1)]);
+        tracker.on_focus(id(2).into(), &focus(qubes_gui::EV_FOCUS_IN));
+        tracker.on_focus(id(1).into(), &focus(qubes_gui::EV_FOCUS_OUT));
+        assert_eq!(
+            changes(&mut tracker),
+            [
+                FocusChange::Unfocused(id(1)),
+                FocusChange::Focused(id(2)),
+                FocusChange::Restacked
+            ]
+        );
+        assert_eq!(tracker.focused(), Some(id(2)));
+        tracker.on_focus(id(3).into(), &focus(qubes_gui::EV_FOCUS_IN));
+        assert_eq!(tracker.focused(), Some(id(2)), "untracked window");
+        tracker.on_destroy(id(2).into());
+        assert_eq!(
+            (tracker.focused(), tracker.stacking()),
+            (None, &[id(1)][..])
+        );
+    }
+
+    #[test]
+    fn crossing_reveals_stacking() {
+        let mut tracker = FocusTracker::new();
+        tracker.add_window(id(1), rectangle(0, 0));
+        tracker.add_window(id(2), rectangle(50, 50));
+        tracker.add_window(id(3), rectangle(500, 500));
+        let crossing = |ty, x, y| qubes_gui::Crossing {
+            ty,
+            coordinates: Coordinates { x, y },
+            state: 0,
+            mode: 0,
+            detail: 0,
+            focus: 0,
+        };
+        // At (60, 60), which both 1 and 2 cover
+        tracker.on_crossing(id(1).into(), &crossing(ENTER_NOTIFY, 60, 60));
+        assert_eq!(
+            changes(&mut tracker),
+            [FocusChange::PointerEntered(id(1)), FocusChange::Restacked]
+        );
+        assert!(tracker.is_above(id(1), id(2)));
+        assert!(tracker.is_above(id(3), id(1)), "3 does not overlap");
+        // At (10, 10), which only 1 covers
+        tracker.on_crossing(id(1).into(), &crossing(LEAVE_NOTIFY, 10, 10));
+        tracker.on_crossing(id(1).into(), &crossing(ENTER_NOTIFY, 10, 10));
+        assert_eq!(
+            changes(&mut tracker),
+            [
+                FocusChange::PointerLeft(id(1)),
+                FocusChange::PointerEntered(id(1))
+            ]
+        );
+        let configure = qubes_gui::Configure {
+            rectangle: rectangle(0, 0),
+            override_redirect: 0,
+        };
+        tracker.on_configure(id(3).into(), &configure);
+        tracker.on_crossing(id(3).into(), &crossing(ENTER_NOTIFY, 20, 20));
+        assert_eq!(tracker.stacking(), [id(2), id(1), id(3)]);
+        tracker.on_reconnected(&Default::default());
+        assert_eq!(tracker.pointer(), None);
+    }
+}
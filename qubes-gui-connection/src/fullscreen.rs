@@ -0,0 +1,239 @@
+/*
+ * The Qubes OS Project, https://www.qubes-os.org
+ *
+ * Copyright (C) 2021  Demi Marie Obenour  <demi@invisiblethingslab.com>
+ *
+ * This program is free software; you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License
+ * as published by the Free Software Foundation; either version 2
+ * of the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301, USA.
+ *
+ */
+
+//! Asking the daemon to make a window fullscreen.
+//!
+//! An agent asks by setting [`WindowFlag::Fullscreen`] with
+//! `MSG_WINDOW_FLAGS`, and stops asking by unsetting it.  The request may or
+//! may not be honored.  If it is, the daemon sends `MSG_WINDOW_FLAGS` back
+//! with the new state; if it is not, the daemon usually says nothing at all.
+//! The daemon also sends `MSG_WINDOW_FLAGS` when the user changes the state
+//! of the window without being asked to.  [`FullscreenController`] sorts this
+//! out, so that the agent knows what state the window is really in.
+
+use qubes_gui::{WindowFlag, WindowFlags};
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// A change in the fullscreen state of a window
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum FullscreenEvent {
+    /// The window is now fullscreen, whether or not the agent asked for it
+    Entered,
+    /// The window is no longer fullscreen, whether or not the agent asked
+    /// for it
+    Left,
+    /// The daemon did not honor a request in time.  The window stays as it
+    /// was.
+    Refused {
+        /// Did the request ask for fullscreen?
+        fullscreen: bool,
+    },
+}
+
+#[derive(Debug, Copy, Clone)]
+struct Request {
+    fullscreen: bool,
+    deadline: Instant,
+}
+
+/// Tracks the fullscreen state of one window
+#[derive(Debug)]
+pub struct FullscreenController {
+    timeout: Duration,
+    /// The state last reported by the daemon
+    fullscreen: bool,
+    /// The request awaiting an answer, if any
+    request: Option<Request>,
+    /// Events not yet returned by [`FullscreenController::poll`]
+    events: VecDeque<FullscreenEvent>,
+}
+
+impl FullscreenController {
+    /// Requests are considered refused if not honored within `timeout`.  The
+    /// window starts out not fullscreen.
+    pub fn new(timeout: Duration) -> Self {
+        Self {
+            timeout,
+            fullscreen: false,
+            request: None,
+            events: VecDeque::new(),
+        }
+    }
+
+    /// Ask for the window to be made fullscreen, or to stop being so, at
+    /// time `now`.  Returns the message to send to the window, or `None` if
+    /// the window is already in that state and no other request is waiting.
+    /// A new request replaces any previous one.
+    pub fn request(&mut self, fullscreen: bool, now: Instant) -> Option<WindowFlags> {
+        if fullscreen == self.fullscreen && self.request.is_none() {
+            return None;
+        }
+        self.request = Some(Request {
+            fullscreen,
+            deadline: now + self.timeout,
+        });
+        let flag = WindowFlag::Fullscreen as u32;
+        Some(if fullscreen {
+            WindowFlags {
+                set: flag,
+                unset: 0,
+            }
+        } else {
+            WindowFlags {
+                set: 0,
+                unset: flag,
+            }
+        })
+    }
+
+    /// Record a `MSG_WINDOW_FLAGS` sent by the daemon to the window.  Messages
+    /// that say nothing about fullscreen are ignored.
+    pub fn flags(&mut self, flags: &WindowFlags) {
+        let fullscreen = if flags.sets(WindowFlag::Fullscreen) {
+            true
+        } else if flags.unsets(WindowFlag::Fullscreen) {
+            false
+        } else {
+            return;
+        };
+        // Any answer settles the request: the daemon has made up its mind
+        if let Some(request) = self.request.take() {
+            if request.fullscreen != fullscreen {
+                self.events.push_back(FullscreenEvent::Refused {
+                    fullscreen: request.fullscreen,
+                })
+            }
+        }
+        if fullscreen != self.fullscreen {
+            self.fullscreen = fullscreen;
+            self.events.push_back(if fullscreen {
+                FullscreenEvent::Entered
+            } else {
+                FullscreenEvent::Left
+            })
+        }
+    }
+
+    /// Report changes of state, and requests that have gone unanswered
+    /// until `now`.  Call this until it returns `None`.
+    pub fn poll(&mut self, now: Instant) -> Option<FullscreenEvent> {
+        if let Some(event) = self.events.pop_front() {
+            return Some(event);
+        }
+        let request = self.request.filter(|r| now >= r.deadline)?;
+        self.request = None;
+        Some(FullscreenEvent::Refused {
+            fullscreen: request.fullscreen,
+        })
+    }
+
+    /// When the request awaiting an answer will be considered refused, if
+    /// there is one
+    pub fn deadline(&self) -> Option<Instant> {
+        self.request.map(|r| r.deadline)
+    }
+
+    /// Is the window fullscreen, as far as the daemon has said?
+    pub fn is_fullscreen(&self) -> bool {
+        self.fullscreen
+    }
+
+    /// The state requested but not yet settled, if any
+    pub fn pending(&self) -> Option<bool> {
+        self.request.map(|r| r.fullscreen)
+    }
+
+    /// Start over after a reconnection.  A new daemon shows the window as
+    /// not fullscreen until told otherwise, and will not answer requests
+    /// sent to the old one.  Neither change is reported.
+    pub fn reset(&mut self) {
+        self.fullscreen = false;
+        self.request = None;
+        self.events.clear()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn flags(set: bool) -> WindowFlags {
+        let flag = WindowFlag::Fullscreen as u32;
+        WindowFlags {
+            set: if set { flag } else { 0 },
+            unset: if set { 0 } else { flag },
+        }
+    }
+
+    #[test]
+    fn honored() {
+        let now = Instant::now();
+        let mut controller = FullscreenController::new(Duration::from_secs(1));
+        assert_eq!(controller.request(false, now), None, "already so");
+        assert_eq!(controller.request(true, now), Some(flags(true)));
+        assert_eq!(controller.pending(), Some(true));
+        controller.flags(&flags(true));
+        assert_eq!(controller.poll(now), Some(FullscreenEvent::Entered));
+        assert_eq!(controller.poll(now + Duration::from_secs(2)), None);
+        assert!(controller.is_fullscreen());
+        assert_eq!(controller.deadline(), None);
+    }
+
+    #[test]
+    fn refused() {
+        let now = Instant::now();
+        let second = Duration::from_secs(1);
+        let mut controller = FullscreenController::new(second);
+        controller.request(true, now);
+        assert_eq!(controller.deadline(), Some(now + second));
+        assert_eq!(controller.poll(now), None);
+        let refused = FullscreenEvent::Refused { fullscreen: true };
+        assert_eq!(controller.poll(now + second), Some(refused));
+        assert!(!controller.is_fullscreen());
+
+        controller.request(true, now);
+        controller.flags(&flags(false));
+        assert_eq!(controller.poll(now), Some(refused), "answered no");
+        assert_eq!(controller.poll(now), None);
+    }
+
+    #[test]
+    fn unsolicited() {
+        let now = Instant::now();
+        let mut controller = FullscreenController::new(Duration::from_secs(1));
+        controller.flags(&flags(true));
+        controller.flags(&WindowFlags {
+            set: WindowFlag::DemandsAttention as u32,
+            unset: 0,
+        });
+        assert_eq!(controller.poll(now), Some(FullscreenEvent::Entered));
+        assert_eq!(controller.request(false, now), Some(flags(false)));
+        controller.flags(&flags(false));
+        assert_eq!(controller.poll(now), Some(FullscreenEvent::Left));
+        assert_eq!(controller.poll(now), None);
+
+        controller.flags(&flags(true));
+        controller.reset();
+        assert!(!controller.is_fullscreen());
+        assert_eq!(controller.poll(now), None);
+    }
+}
@@ -0,0 +1,414 @@
+/*
+ * The Qubes OS Project, https://www.qubes-os.org
+ *
+ * Copyright (C) 2021  Demi Marie Obenour  <demi@invisiblethingslab.com>
+ *
+ * This program is free software; you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License
+ * as published by the Free Software Foundation; either version 2
+ * of the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301, USA.
+ *
+ */
+
+//! A GLib `GSource` that drives a [`Connection`], so that GTK-based agents
+//! and tools can handle messages on their main loop.
+//!
+//! Requires the `glib` feature, which links against `libglib-2.0`.  Only the
+//! small part of the GLib C API that is needed is declared here, so that no
+//! GLib bindings are required.
+
+use crate::dispatch::{dispatch, MessageHandler};
+use crate::event_loop::check_connected;
+use crate::Connection;
+use std::cell::RefCell;
+use std::io;
+use std::os::raw::{c_char, c_int, c_uint, c_void};
+use std::os::unix::io::AsRawFd as _;
+use std::ptr::NonNull;
+use std::time::Instant;
+
+/// The parts of the GLib C API used by [`GuiSource`]
+#[allow(non_camel_case_types)]
+pub mod ffi {
+    use std::os::raw::{c_char, c_int, c_uint, c_void};
+
+    /// `gboolean`
+    pub type gboolean = c_int;
+    /// `GSourceFunc`
+    pub type GSourceFunc = Option<unsafe extern "C" fn(*mut c_void) -> gboolean>;
+
+    /// `GMainContext`.  Only used through pointers.
+    #[repr(C)]
+    pub struct GMainContext {
+        _private: [u8; 0],
+    }
+
+    /// `GSource`, whose layout is public in `gmain.h`
+    #[repr(C)]
+    pub struct GSource {
+        callback_data: *mut c_void,
+        callback_funcs: *mut c_void,
+        source_funcs: *const GSourceFuncs,
+        ref_count: c_uint,
+        context: *mut GMainContext,
+        priority: c_int,
+        flags: c_uint,
+        source_id: c_uint,
+        poll_fds: *mut c_void,
+        prev: *mut GSource,
+        next: *mut GSource,
+        name: *mut c_char,
+        private: *mut c_void,
+    }
+
+    /// `GSourceFuncs`
+    #[repr(C)]
+    pub struct GSourceFuncs {
+        pub(super) prepare: Option<unsafe extern "C" fn(*mut GSource, *mut c_int) -> gboolean>,
+        pub(super) check: Option<unsafe extern "C" fn(*mut GSource) -> gboolean>,
+        pub(super) dispatch:
+            Option<unsafe extern "C" fn(*mut GSource, GSourceFunc, *mut c_void) -> gboolean>,
+        pub(super) finalize: Option<unsafe extern "C" fn(*mut GSource)>,
+        pub(super) closure_callback: GSourceFunc,
+        pub(super) closure_marshal: Option<unsafe extern "C" fn()>,
+    }
+
+    /// `G_IO_IN`
+    pub const G_IO_IN: c_uint = 1;
+    /// `G_IO_ERR`
+    pub const G_IO_ERR: c_uint = 8;
+    /// `G_IO_HUP`
+    pub const G_IO_HUP: c_uint = 16;
+
+    #[link(name = "glib-2.0")]
+    extern "C" {
+        pub(super) fn g_source_new(funcs: *mut GSourceFuncs, struct_size: c_uint) -> *mut GSource;
+        pub(super) fn g_source_add_unix_fd(
+            source: *mut GSource,
+            fd: c_int,
+            events: c_uint,
+        ) -> *mut c_void;
+        pub(super) fn g_source_remove_unix_fd(source: *mut GSource, tag: *mut c_void);
+        pub(super) fn g_source_query_unix_fd(source: *mut GSource, tag: *mut c_void) -> c_uint;
+        pub(super) fn g_source_attach(source: *mut GSource, context: *mut GMainContext) -> c_uint;
+        pub(super) fn g_source_destroy(source: *mut GSource);
+        pub(super) fn g_source_is_destroyed(source: *mut GSource) -> gboolean;
+        pub(super) fn g_source_set_name(source: *mut GSource, name: *const c_char);
+        pub(super) fn g_source_unref(source: *mut GSource);
+    }
+}
+
+/// What a [`GuiSource`] drives
+struct State<H> {
+    connection: Connection,
+    handler: H,
+    /// The file descriptor being watched, which changes on reconnection
+    fd: c_int,
+    /// Returned by `g_source_add_unix_fd`, or null if `fd` is -1
+    tag: *mut c_void,
+    /// Has anything been dispatched yet?
+    started: bool,
+    /// The error that removed the source
+    error: Option<io::Error>,
+}
+
+/// The memory allocated by `g_source_new`
+#[repr(C)]
+struct RawSource<H> {
+    base: ffi::GSource,
+    state: *const RefCell<State<H>>,
+}
+
+/// Provides a `'static` [`ffi::GSourceFuncs`] for every handler type
+trait Funcs {
+    const FUNCS: ffi::GSourceFuncs;
+}
+
+impl<H: MessageHandler> Funcs for H {
+    const FUNCS: ffi::GSourceFuncs = ffi::GSourceFuncs {
+        prepare: Some(prepare::<H>),
+        check: Some(check::<H>),
+        dispatch: Some(dispatch_source::<H>),
+        finalize: Some(finalize::<H>),
+        closure_callback: None,
+        closure_marshal: None,
+    };
+}
+
+/// # Safety
+///
+/// `source` must have been created by [`GuiSource::new`] with handler type
+/// `H`, and not yet finalized.
+unsafe fn state<'a, H>(source: *mut ffi::GSource) -> &'a RefCell<State<H>> {
+    &*(*source.cast::<RawSource<H>>()).state
+}
+
+/// The earliest time at which the connection needs attention even if its
+/// file descriptor is not readable
+fn deadline(connection: &Connection) -> Option<Instant> {
+    match (
+        connection.reconnect_deadline(),
+        connection.liveness_deadline(),
+    ) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        (a, b) => a.or(b),
+    }
+}
+
+unsafe extern "C" fn prepare<H>(source: *mut ffi::GSource, timeout: *mut c_int) -> ffi::gboolean {
+    let state = state::<H>(source).borrow();
+    *timeout = match deadline(&state.connection) {
+        None => -1,
+        Some(deadline) => {
+            let ns = deadline
+                .saturating_duration_since(Instant::now())
+                .as_nanos();
+            ns.div_ceil(1_000_000).min(c_int::MAX as u128) as c_int
+        }
+    };
+    // Data that arrived before the source was attached has already been
+    // signalled, so dispatch once without waiting for the file descriptor.
+    (!state.started || *timeout == 0).into()
+}
+
+unsafe extern "C" fn check<H>(source: *mut ffi::GSource) -> ffi::gboolean {
+    let state = state::<H>(source).borrow();
+    let ready = !state.tag.is_null() && ffi::g_source_query_unix_fd(source, state.tag) != 0;
+    let expired = deadline(&state.connection).is_some_and(|d| d <= Instant::now());
+    (ready || expired).into()
+}
+
+unsafe extern "C" fn dispatch_source<H: MessageHandler>(
+    source: *mut ffi::GSource,
+    _callback: ffi::GSourceFunc,
+    _user_data: *mut c_void,
+) -> ffi::gboolean {
+    let mut state = state::<H>(source).borrow_mut();
+    let state = &mut *state;
+    state.started = true;
+    if !state.tag.is_null() && ffi::g_source_query_unix_fd(source, state.tag) != 0 {
+        state.connection.wait()
+    }
+    let res = dispatch(&mut state.connection, &mut state.handler)
+        .and_then(|()| check_connected(&state.connection));
+    if let Err(e) = res {
+        state.error = Some(e);
+        return 0;
+    }
+    let fd = state.connection.as_raw_fd();
+    if fd != state.fd {
+        if !state.tag.is_null() {
+            ffi::g_source_remove_unix_fd(source, state.tag)
+        }
+        watch(source, state, fd)
+    }
+    1
+}
+
+/// Watch `fd`, which may be -1 if there is nothing to watch
+///
+/// # Safety
+///
+/// `source` must be valid, and not be watching any file descriptor.
+unsafe fn watch<H>(source: *mut ffi::GSource, state: &mut State<H>, fd: c_int) {
+    state.fd = fd;
+    state.tag = if fd < 0 {
+        std::ptr::null_mut()
+    } else {
+        ffi::g_source_add_unix_fd(source, fd, ffi::G_IO_IN | ffi::G_IO_ERR | ffi::G_IO_HUP)
+    }
+}
+
+unsafe extern "C" fn finalize<H>(source: *mut ffi::GSource) {
+    drop(Box::from_raw(
+        (*source.cast::<RawSource<H>>()).state as *mut RefCell<State<H>>,
+    ))
+}
+
+/// A GLib `GSource` that drives a [`Connection`].  Whenever the main loop it
+/// is attached to runs, messages and other connection events are passed to
+/// the handler, queued writes are flushed, and the reconnection and liveness
+/// deadlines of the connection are honored.
+///
+/// If reading from the connection fails, the source is removed from its
+/// main loop; the error can then be retrieved with
+/// [`GuiSource::take_error`].  Dropping the [`GuiSource`] also removes it.
+pub struct GuiSource<H> {
+    raw: NonNull<ffi::GSource>,
+    state: *const RefCell<State<H>>,
+}
+
+impl<H> std::fmt::Debug for GuiSource<H> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("GuiSource")
+            .field("raw", &self.raw)
+            .field("destroyed", &self.is_destroyed())
+            .finish()
+    }
+}
+
+impl<H: MessageHandler + 'static> GuiSource<H> {
+    /// Create a source that drives `connection`, passing events to
+    /// `handler`.  It does nothing until attached with
+    /// [`GuiSource::attach`].
+    pub fn new(connection: Connection, handler: H) -> Self {
+        let fd = connection.as_raw_fd();
+        let state = Box::into_raw(Box::new(RefCell::new(State {
+            connection,
+            handler,
+            fd: -1,
+            tag: std::ptr::null_mut(),
+            started: false,
+            error: None,
+        })));
+        let size = std::mem::size_of::<RawSource<H>>() as c_uint;
+        // GLib never writes through the pointer to the functions.
+        let funcs = &<H as Funcs>::FUNCS as *const ffi::GSourceFuncs as *mut _;
+        // SAFETY: `funcs` is valid for `'static`, and `size` is at least the
+        // size of a `GSource`.  The allocation is suitably aligned for a
+        // pointer and zero-initialized, so `state` can be written.
+        unsafe {
+            let raw = ffi::g_source_new(funcs, size);
+            let raw = NonNull::new(raw).expect("g_source_new never returns NULL");
+            (*raw.as_ptr().cast::<RawSource<H>>()).state = state;
+            ffi::g_source_set_name(
+                raw.as_ptr(),
+                b"Qubes GUI connection\0".as_ptr().cast::<c_char>(),
+            );
+            watch(raw.as_ptr(), &mut (*state).borrow_mut(), fd);
+            Self { raw, state }
+        }
+    }
+
+    /// Attach the source to `context`, or to the global default context if
+    /// `context` is null, and return its source ID.
+    ///
+    /// # Safety
+    ///
+    /// `context` must be null or a valid `GMainContext`.  The context must
+    /// only ever be iterated by the current thread, as neither the
+    /// connection nor the handler are accessed with any synchronization.
+    pub unsafe fn attach(&self, context: *mut ffi::GMainContext) -> u32 {
+        ffi::g_source_attach(self.raw.as_ptr(), context)
+    }
+}
+
+impl<H> GuiSource<H> {
+    /// Run `f` with the connection and the handler, for example to send
+    /// messages.
+    ///
+    /// # Panics
+    ///
+    /// Panics if called from a method of the handler, as they are already in
+    /// use.
+    pub fn with<R>(&self, f: impl FnOnce(&mut Connection, &mut H) -> R) -> R {
+        // SAFETY: `state` is only freed when the source is finalized, which
+        // cannot happen while `self` holds a reference.
+        let mut state = unsafe { &*self.state }.borrow_mut();
+        let state = &mut *state;
+        f(&mut state.connection, &mut state.handler)
+    }
+
+    /// Has the source been removed from its main loop?
+    pub fn is_destroyed(&self) -> bool {
+        // SAFETY: `self.raw` is a valid source.
+        unsafe { ffi::g_source_is_destroyed(self.raw.as_ptr()) != 0 }
+    }
+
+    /// If an error removed the source, return it.  Later calls return
+    /// `None`.
+    pub fn take_error(&self) -> Option<io::Error> {
+        // SAFETY: as in `GuiSource::with`
+        unsafe { &*self.state }.borrow_mut().error.take()
+    }
+
+    /// The underlying `GSource`, which remains owned by `self`
+    pub fn as_ptr(&self) -> *mut ffi::GSource {
+        self.raw.as_ptr()
+    }
+}
+
+impl<H> Drop for GuiSource<H> {
+    fn drop(&mut self) {
+        // SAFETY: `self.raw` is a valid source, and `self` holds a reference
+        // to it.
+        unsafe {
+            ffi::g_source_destroy(self.raw.as_ptr());
+            ffi::g_source_unref(self.raw.as_ptr());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::SocketTransport;
+    use std::os::unix::net::UnixStream;
+    use std::time::Duration;
+
+    extern "C" {
+        fn g_main_context_new() -> *mut ffi::GMainContext;
+        fn g_main_context_iteration(context: *mut ffi::GMainContext, may_block: c_int) -> c_int;
+        fn g_main_context_unref(context: *mut ffi::GMainContext);
+    }
+
+    #[derive(Default)]
+    struct Recorder {
+        reconnected: usize,
+        clipboard_requests: usize,
+    }
+
+    impl MessageHandler for Recorder {
+        fn on_reconnected(&mut self, _: &qubes_gui::XConfVersion) {
+            self.reconnected += 1
+        }
+        fn on_clipboard_req(&mut self, _: qubes_gui::WindowID) {
+            self.clipboard_requests += 1
+        }
+    }
+
+    #[test]
+    fn main_loop() {
+        let (a, b) = UnixStream::pair().unwrap();
+        let mut daemon =
+            Connection::daemon_over(SocketTransport::new(b).unwrap(), Default::default());
+        let agent = Connection::agent_over(SocketTransport::new(a).unwrap());
+        let source = GuiSource::new(agent, Recorder::default());
+        let start = Instant::now();
+        // SAFETY: the context is new, and only used by this thread.
+        unsafe {
+            let context = g_main_context_new();
+            assert_ne!(source.attach(context), 0);
+            while source.with(|_, h| h.reconnected) == 0 {
+                daemon.wait();
+                let _ = daemon.read_message();
+                g_main_context_iteration(context, 0);
+                assert!(start.elapsed() < Duration::from_secs(10));
+            }
+            daemon
+                .send_raw(&[], 1.into(), qubes_gui::MSG_CLIPBOARD_REQ)
+                .unwrap();
+            while source.with(|_, h| h.clipboard_requests) == 0 {
+                g_main_context_iteration(context, 1);
+            }
+            assert!(!source.is_destroyed());
+            drop(daemon);
+            while !source.is_destroyed() {
+                g_main_context_iteration(context, 1);
+            }
+            assert!(source.take_error().is_some());
+            assert!(source.take_error().is_none());
+            drop(source);
+            g_main_context_unref(context);
+        }
+    }
+}
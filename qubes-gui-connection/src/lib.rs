@@ -25,7 +25,7 @@
 #![forbid(clippy::all)]
 
 pub use qubes_gui;
-use std::convert::TryInto;
+use std::convert::TryFrom;
 use std::task::Poll;
 
 use qubes_castable::{static_assert, Castable};
@@ -33,10 +33,67 @@ use qubes_gui::{Header, UntrustedHeader};
 use std::collections::VecDeque;
 use std::io::{self, Error, ErrorKind};
 use std::mem::size_of;
-use vchan::{Status, Vchan};
+use std::time::{Duration, Instant};
+use vchan::Status;
 
+pub mod agent;
+pub mod capture;
+pub mod capture_file;
+mod clipboard;
+pub mod conformance;
+pub mod decode;
+pub mod dispatch;
+mod dumps;
+#[cfg(unix)]
+mod event_loop;
+pub mod extensions;
+mod focus;
+mod fullscreen;
+#[cfg(all(feature = "glib", unix))]
+pub mod glib;
+mod liveness;
+pub mod metrics;
+mod outgoing;
+mod pool;
+pub mod proxy;
+mod reconnect;
+pub mod replay;
+pub mod scale;
+pub mod surface;
 #[cfg(test)]
 mod tests;
+pub mod transport;
+pub mod tray;
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+pub mod uring;
+
+pub use agent::Agent;
+pub use capture::{CaptureTransport, ReplayTransport};
+pub use capture_file::{CaptureReader, CaptureWriter};
+pub use clipboard::{ClipboardEvent, ClipboardRequestId, ClipboardTracker};
+pub use dispatch::{dispatch, MessageHandler};
+#[cfg(unix)]
+pub use event_loop::{poll_fds, EventLoop, Ready, Token};
+pub use extensions::{Extension, Extensions};
+pub use focus::{FocusChange, FocusTracker};
+pub use fullscreen::{FullscreenController, FullscreenEvent};
+#[cfg(all(feature = "glib", unix))]
+pub use glib::GuiSource;
+pub use metrics::Metrics;
+pub use outgoing::OutgoingMessage;
+pub use proxy::Proxy;
+pub use reconnect::{ReconnectCallback, ReconnectPolicy};
+pub use replay::SessionState;
+pub use scale::{Scale, Scaled};
+#[cfg(feature = "raw-window-handle")]
+pub use surface::QubesWindowHandle;
+pub use surface::{DoubleBuffer, GrantAllocator, ResizableSurface, Surface};
+#[cfg(unix)]
+pub use transport::SocketTransport;
+pub use transport::{LoopbackTransport, Transport, VchanTransport};
+pub use tray::{TrayClick, TrayIcon};
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+pub use uring::UringTransport;
 
 /// Protocol state
 #[derive(Debug)]
@@ -46,62 +103,69 @@ enum ReadState {
     Connecting,
     /// Negotiating protocol version
     Negotiating,
+    /// Reading the rest of an [`XConfScale`](qubes_gui::proposed::XConfScale)
+    /// from a daemon that accepted the proposed version
+    #[cfg(feature = "proposed")]
+    NegotiatingScale,
     /// Reading a message header
     ReadingHeader,
     /// Reading a message body
     ReadingBody { header: Header },
+    /// Reading a message body into a caller-provided buffer, of which the
+    /// first `done` bytes have been filled.  With `done == 0`, the body may
+    /// still be read into the internal buffer instead.
+    ReadingBodyInto { header: Header, done: usize },
     /// Discarding data from an unknown message
     Discard(usize),
+    /// Streaming the body of a `MSG_CLIPBOARD_DATA`
+    StreamingClipboard { header: Header, remaining: usize },
+    /// The body of a streamed `MSG_CLIPBOARD_DATA` has been fully read
+    ClipboardEnd { header: Header },
+    /// Reading the body of a proposed message into the internal buffer
+    #[cfg(feature = "proposed")]
+    ReadingProposed { header: UntrustedHeader },
     /// Something went wrong.  Terminal state.
     Error,
 }
 
-// Trait for a vchan, for unit-testing
-trait VchanMock
-where
-    Self: Sized,
-{
-    fn buffer_space(&self) -> usize;
-    fn recv_into(&self, buf: &mut Vec<u8>, bytes: usize) -> Result<(), vchan::Error>;
-    fn recv_struct<T: Castable + Default>(&self) -> Result<T, vchan::Error>;
-    fn send(&self, buf: &[u8]) -> Result<(), vchan::Error>;
-    fn wait(&self);
-    fn data_ready(&self) -> usize;
-    fn status(&self) -> Status;
-    fn discard(&self, bytes: usize) -> Result<(), vchan::Error>;
+/// What [`RawMessageStream::read_message_internal`] has read
+#[derive(Debug, Clone, Copy)]
+enum Incoming {
+    /// A complete message, whose body is in the buffer
+    Message(Header),
+    /// Part of the body of a `MSG_CLIPBOARD_DATA`, which is in the buffer
+    ClipboardChunk(Header),
+    /// The end of a streamed `MSG_CLIPBOARD_DATA`
+    ClipboardEnd(Header),
+    /// The body of this message does not fit in the caller-provided buffer.
+    /// None of it has been read.
+    BodyTooLarge(Header),
+    /// The header of the next message, whose body has not been read
+    Peeked(Header),
+    /// A message of unknown type, whose body is being discarded
+    Unknown(UntrustedHeader),
+    /// A [`MSG_SCREEN_CONFIGURED`](qubes_gui::proposed::MSG_SCREEN_CONFIGURED)
+    /// with this body
+    #[cfg(feature = "proposed")]
+    ScreenConfigured(qubes_gui::XConf),
+    /// A proposed message, whose body is in the buffer
+    #[cfg(feature = "proposed")]
+    Proposed(UntrustedHeader),
 }
 
-impl VchanMock for Option<Vchan> {
-    fn discard(&self, bytes: usize) -> Result<(), vchan::Error> {
-        Vchan::discard(self.as_ref().unwrap(), bytes)
-    }
-    fn buffer_space(&self) -> usize {
-        Vchan::buffer_space(self.as_ref().unwrap())
-    }
-    fn recv_into(&self, buf: &mut Vec<u8>, bytes: usize) -> Result<(), vchan::Error> {
-        Vchan::recv_into(self.as_ref().unwrap(), buf, bytes)
-    }
-    fn recv_struct<T: Castable>(&self) -> Result<T, vchan::Error> {
-        Vchan::recv_struct(self.as_ref().unwrap())
-    }
-    fn send(&self, buf: &[u8]) -> Result<(), vchan::Error> {
-        Vchan::send(self.as_ref().unwrap(), buf)
-    }
-    fn wait(&self) {
-        Vchan::wait(self.as_ref().unwrap())
-    }
-    fn data_ready(&self) -> usize {
-        Vchan::data_ready(self.as_ref().unwrap())
-    }
-    fn status(&self) -> Status {
-        self.as_ref()
-            .map(Vchan::status)
-            .unwrap_or(Status::Disconnected)
-    }
+/// Where [`RawMessageStream::read_message_internal`] puts message bodies
+#[derive(Debug)]
+enum Body<'a> {
+    /// The internal buffer
+    Internal,
+    /// A caller-provided buffer
+    Caller(&'a mut [u8]),
+    /// Nowhere: stop as soon as the next header has been read
+    Peek,
 }
 
 /// The kind of a state machine
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Kind {
     /// An agent instance
     Agent,
@@ -109,9 +173,8 @@ pub enum Kind {
     Daemon,
 }
 
-#[derive(Debug)]
-struct RawMessageStream<T: VchanMock> {
-    /// Vchan
+struct RawMessageStream<T: Transport> {
+    /// The underlying transport, usually a vchan
     vchan: T,
     /// Write buffer
     queue: VecDeque<u8>,
@@ -119,23 +182,94 @@ struct RawMessageStream<T: VchanMock> {
     state: ReadState,
     /// Read buffer
     buffer: Vec<u8>,
+    /// Idle read buffers
+    pool: pool::BufferPool,
     /// Was reconnect successful?
     did_reconnect: bool,
+    /// The number of bytes read from the transport so far
+    consumed: u64,
     /// Configuration from the daemon
     xconf: qubes_gui::XConfVersion,
-    /// Peer domain ID
-    domid: u16,
     /// Agent or daemon?
     kind: Kind,
+    /// Report `MSG_CLIPBOARD_DATA` bodies as they arrive?
+    stream_clipboard: bool,
+    /// Report the headers of messages of unknown type?
+    report_unknown: bool,
+    /// Accept [`MSG_SCREEN_CONFIGURED`](qubes_gui::proposed::MSG_SCREEN_CONFIGURED)?
+    #[cfg(feature = "proposed")]
+    screen_messages: bool,
+    /// What to do with deprecated messages
+    deprecated: qubes_gui::DeprecatedPolicy,
+    /// Offer the proposed protocol version?
+    #[cfg(feature = "proposed")]
+    propose: bool,
+    /// Daemons: the default scale and features offered to agents
+    #[cfg(feature = "proposed")]
+    scale_offer: qubes_gui::proposed::XConfScale,
+    /// The configuration negotiated with the peer, if the proposed version
+    /// was
+    #[cfg(feature = "proposed")]
+    scale: Option<qubes_gui::proposed::XConfScale>,
+    /// Where to report protocol violations
+    violations: Violations,
+    /// Traffic counters
+    metrics: Metrics,
 }
 
-/// A buffer
-#[derive(Debug)]
+impl<T: Transport> std::fmt::Debug for RawMessageStream<T> {
+    /// Only the lengths of the buffers are shown, as they may hold
+    /// clipboard data
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RawMessageStream")
+            .field("vchan", &self.vchan)
+            .field("queue", &self.queue.len())
+            .field("state", &self.state)
+            .field("buffer", &self.buffer.len())
+            .field("did_reconnect", &self.did_reconnect)
+            .field("xconf", &self.xconf)
+            .field("kind", &self.kind)
+            .field("violations", &self.violations)
+            .field("metrics", &self.metrics)
+            .finish_non_exhaustive()
+    }
+}
+
+/// An optional [`qubes_gui::ViolationSink`]
+#[derive(Default)]
+struct Violations(Option<Box<dyn qubes_gui::ViolationSink>>);
+
+impl std::fmt::Debug for Violations {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("Violations")
+            .field(&self.0.is_some())
+            .finish()
+    }
+}
+
+impl Violations {
+    fn report(&mut self, kind: qubes_gui::ViolationKind, header: &UntrustedHeader) {
+        if let Some(sink) = self.0.as_mut() {
+            sink.report(&qubes_gui::Violation::new(kind, header))
+        }
+    }
+}
+
+/// A buffer.  The `Debug` output does not include the body.
 pub struct Buffer<'a> {
     inner: &'a mut Vec<u8>,
     hdr: Header,
 }
 
+impl std::fmt::Debug for Buffer<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Buffer")
+            .field("hdr", &self.hdr)
+            .field("body", &qubes_gui::Redacted(self.inner))
+            .finish()
+    }
+}
+
 impl<'a> Buffer<'a> {
     /// Gets the header
     pub fn hdr(&self) -> Header {
@@ -145,13 +279,65 @@ impl<'a> Buffer<'a> {
     pub fn body(&self) -> &[u8] {
         &self.inner[..]
     }
-    /// Takes ownership of the body
+    /// Takes ownership of the body.  Give it back with
+    /// [`Connection::recycle`] once done with it, so that its allocation can
+    /// be reused.
     pub fn take(mut self) -> Vec<u8> {
         std::mem::replace(&mut self.inner, vec![])
     }
 }
 
-impl<T: VchanMock + 'static> RawMessageStream<T> {
+impl<T: Transport> RawMessageStream<T> {
+    fn new(vchan: T, kind: Kind, xconf: qubes_gui::XConfVersion) -> Self {
+        Self {
+            vchan,
+            queue: Default::default(),
+            state: ReadState::Connecting,
+            buffer: vec![],
+            pool: Default::default(),
+            did_reconnect: false,
+            consumed: 0,
+            kind,
+            xconf,
+            stream_clipboard: false,
+            report_unknown: false,
+            #[cfg(feature = "proposed")]
+            screen_messages: false,
+            deprecated: Default::default(),
+            #[cfg(feature = "proposed")]
+            propose: false,
+            #[cfg(feature = "proposed")]
+            scale_offer: qubes_gui::proposed::XConfScale {
+                default_scale: qubes_gui::proposed::SCALE_DENOMINATOR,
+                ..Default::default()
+            },
+            #[cfg(feature = "proposed")]
+            scale: None,
+            violations: Default::default(),
+            metrics: Default::default(),
+        }
+    }
+
+    /// The version to send to the daemon
+    fn agent_version(&self) -> u32 {
+        #[cfg(feature = "proposed")]
+        if self.propose {
+            return qubes_gui::proposed::PROPOSED_VERSION;
+        }
+        qubes_gui::PROTOCOL_VERSION
+    }
+
+    /// Receive any [`Castable`] struct, adding its size to `consumed`
+    fn recv_struct<U: Castable + Default>(
+        vchan: &mut T,
+        consumed: &mut u64,
+    ) -> Result<U, vchan::Error> {
+        let mut datum = U::default();
+        vchan.recv(datum.as_mut_bytes())?;
+        *consumed += size_of::<U>() as u64;
+        Ok(datum)
+    }
+
     /// Attempts to write as much of `slice` as possible to the `vchan`.  Never
     /// blocks.  Returns the number of bytes written.
     ///
@@ -171,27 +357,27 @@ impl<T: VchanMock + 'static> RawMessageStream<T> {
 
     /// Write as much of the buffered data as possible without blocking.
     /// Returns the number of bytes successfully written.
+    ///
+    /// The queue is made contiguous first, so each send takes as much as the
+    /// transport has room for.  Transports that write out immediately (such
+    /// as [`SocketTransport`]) may make room again, so this keeps sending
+    /// until the queue is empty or the transport is full; the caller can then
+    /// rely on the transport to say when there is room again.
     fn flush_pending_writes(&mut self) -> Result<usize, vchan::Error> {
-        let mut written = 0;
-        loop {
-            let (front, back) = self.queue.as_slices();
-            let to_write = if front.is_empty() {
-                if back.is_empty() {
-                    break Ok(written);
-                }
-                back
-            } else {
-                front
-            };
-            let written_this_time = Self::write_slice(&mut self.vchan, to_write)?;
-            if written_this_time == 0 {
-                break Ok(written);
-            }
-            written += written_this_time;
-            for _ in 0..written_this_time {
-                let _ = self.queue.pop_front();
+        if self.queue.is_empty() {
+            return Ok(0);
+        }
+        let mut total = 0;
+        while !self.queue.is_empty() {
+            let written = Self::write_slice(&mut self.vchan, self.queue.make_contiguous())?;
+            if written == 0 {
+                break;
             }
+            self.queue.drain(..written);
+            total += written;
         }
+        self.metrics.record_queue_depth(self.queue.len());
+        Ok(total)
     }
 
     /// Write as much of the buffered data to the vchan as possible.  Queue the
@@ -209,16 +395,35 @@ impl<T: VchanMock + 'static> RawMessageStream<T> {
         self.flush_pending_writes()?;
         if !self.queue.is_empty() {
             self.queue.extend(buf);
-            return Ok(());
-        }
-        let written = Self::write_slice(&mut self.vchan, buf)?;
-        if written != buf.len() {
-            assert!(written < buf.len());
-            self.queue.extend(&buf[written..]);
+        } else {
+            let written = Self::write_slice(&mut self.vchan, buf)?;
+            if written != buf.len() {
+                assert!(written < buf.len());
+                self.queue.extend(&buf[written..]);
+                self.flush_pending_writes()?;
+            }
         }
+        self.metrics.record_queue_depth(self.queue.len());
         Ok(())
     }
 
+    /// Like [`RawMessageStream::write`], but writes several buffers, which
+    /// are handed to the transport together if they fit.
+    pub fn write_vectored(&mut self, bufs: &[&[u8]]) -> Result<(), vchan::Error> {
+        #[cfg(not(test))]
+        match self.state {
+            ReadState::Error | ReadState::Connecting | ReadState::Negotiating => return Ok(()),
+            _ => {}
+        }
+        self.flush_pending_writes()?;
+        let len: usize = bufs.iter().map(|buf| buf.len()).sum();
+        if self.queue.is_empty() && len <= self.vchan.buffer_space() {
+            self.vchan.send_vectored(bufs)
+        } else {
+            bufs.iter().try_for_each(|buf| self.write(buf))
+        }
+    }
+
     /// Acknowledge an event on the vchan.
     pub fn wait(&mut self) {
         self.vchan.wait()
@@ -229,7 +434,15 @@ impl<T: VchanMock + 'static> RawMessageStream<T> {
         std::mem::replace(&mut self.did_reconnect, false)
     }
 
-    fn read_message_internal(&mut self) -> io::Result<Option<Header>> {
+    /// Read a message.  If `yield_on_reconnect` is true, return `Ok(None)` as
+    /// soon as version negotiation completes, even if more data is ready, so
+    /// that the caller can report the reconnection before any message from the
+    /// new peer.  `body` says where message bodies go.
+    fn read_message_internal(
+        &mut self,
+        yield_on_reconnect: bool,
+        mut body: Body<'_>,
+    ) -> io::Result<Option<Incoming>> {
         const SIZE_OF_XCONF: usize = size_of::<qubes_gui::XConfVersion>();
         self.flush_pending_writes()?;
         static_assert!(
@@ -245,7 +458,7 @@ impl<T: VchanMock + 'static> RawMessageStream<T> {
                         Kind::Daemon => self.state = ReadState::Negotiating,
                         Kind::Agent => {
                             assert!(self.vchan.buffer_space() >= 4, "vchans have larger buffers");
-                            match self.vchan.send(qubes_gui::PROTOCOL_VERSION.as_bytes()) {
+                            match self.vchan.send(self.agent_version().as_bytes()) {
                                 Ok(()) => self.state = ReadState::Negotiating,
                                 Err(e) => break Err(e.into()),
                             }
@@ -260,89 +473,282 @@ impl<T: VchanMock + 'static> RawMessageStream<T> {
                 }
                 ReadState::Negotiating => match self.kind {
                     Kind::Agent if ready >= SIZE_OF_XCONF => {
-                        let new_xconf: qubes_gui::XConfVersion = self.vchan.recv_struct()?;
-                        let (daemon_major, daemon_minor) =
-                            (new_xconf.version >> 16, new_xconf.version & 0xFFFF);
-                        if qubes_gui::PROTOCOL_VERSION_MAJOR == daemon_major
-                            && qubes_gui::PROTOCOL_VERSION_MINOR >= daemon_minor
-                            && daemon_minor >= 4
+                        let new_xconf: qubes_gui::XConfVersion =
+                            Self::recv_struct(&mut self.vchan, &mut self.consumed)?;
+                        #[cfg(feature = "proposed")]
+                        if self.propose
+                            && new_xconf.version == qubes_gui::proposed::PROPOSED_VERSION
                         {
                             self.xconf = new_xconf;
-                            self.state = ReadState::ReadingHeader;
-                            self.did_reconnect = true;
-                        } else {
-                            break Err(Error::new(ErrorKind::InvalidData,
-                                            format!(
-                                                "Version negotiation failed: their version is {}.{} but ours is {}.{}",
-                                                daemon_major, daemon_minor,
-                                                qubes_gui::PROTOCOL_VERSION_MAJOR,
-                                                qubes_gui::PROTOCOL_VERSION_MINOR,
-                                                )));
+                            self.state = ReadState::NegotiatingScale;
+                            continue;
+                        }
+                        if let Err(e) = qubes_gui::check_daemon_version(new_xconf.version) {
+                            break Err(Error::new(ErrorKind::InvalidData, e));
+                        }
+                        self.xconf = new_xconf;
+                        self.state = ReadState::ReadingHeader;
+                        self.did_reconnect = true;
+                        if yield_on_reconnect {
+                            break Ok(None);
                         }
                     }
                     Kind::Daemon if ready >= 4 => {
-                        let version: u32 = self.vchan.recv_struct()?;
-                        let (major, minor) = (version >> 16, version & 0xFFFF);
-                        if major == qubes_gui::PROTOCOL_VERSION_MAJOR {
-                            let version = version.min(qubes_gui::PROTOCOL_VERSION_MINOR);
-                            self.xconf.version = version;
-                            self.vchan.send(if version >= 4 {
-                                self.xconf.as_bytes()
-                            } else {
-                                self.xconf.xconf.as_bytes()
-                            })?;
-                            self.state = ReadState::ReadingHeader
+                        let version: u32 = Self::recv_struct(&mut self.vchan, &mut self.consumed)?;
+                        #[cfg(feature = "proposed")]
+                        if self.propose
+                            && version >> 16 == qubes_gui::PROTOCOL_VERSION_MAJOR
+                            && version >= qubes_gui::proposed::PROPOSED_VERSION
+                        {
+                            self.xconf.version = qubes_gui::proposed::PROPOSED_VERSION;
+                            let scale = qubes_gui::proposed::XConfScale {
+                                xconf: self.xconf,
+                                ..self.scale_offer
+                            };
+                            self.vchan.send(scale.as_bytes())?;
+                            self.scale = Some(scale);
+                            self.state = ReadState::ReadingHeader;
+                            self.did_reconnect = true;
+                            if yield_on_reconnect {
+                                break Ok(None);
+                            }
+                            continue;
+                        }
+                        let minor = match qubes_gui::negotiate_agent_version(version) {
+                            Ok(minor) => minor,
+                            Err(e) => break Err(Error::new(ErrorKind::InvalidData, e)),
+                        };
+                        self.xconf.version = qubes_gui::PROTOCOL_VERSION_MAJOR << 16 | minor;
+                        self.vchan.send(if minor >= 4 {
+                            self.xconf.as_bytes()
                         } else {
-                            break Err(Error::new(
-                                    ErrorKind::InvalidData,
-                                    format!(
-                                        "Unsupported version from agent: daemon supports {}.{} but agent sent {}.{}",
-                                        qubes_gui::PROTOCOL_VERSION_MAJOR,
-                                        qubes_gui::PROTOCOL_VERSION_MINOR,
-                                        major,
-                                        minor,
-                                    )));
+                            self.xconf.xconf.as_bytes()
+                        })?;
+                        self.state = ReadState::ReadingHeader;
+                        self.did_reconnect = true;
+                        if yield_on_reconnect {
+                            break Ok(None);
                         }
                     }
                     Kind::Agent | Kind::Daemon => break Ok(None),
                 },
+                #[cfg(feature = "proposed")]
+                ReadState::NegotiatingScale
+                    if ready < size_of::<qubes_gui::proposed::XConfScale>() - SIZE_OF_XCONF =>
+                {
+                    break Ok(None)
+                }
+                #[cfg(feature = "proposed")]
+                ReadState::NegotiatingScale => {
+                    let mut scale = qubes_gui::proposed::XConfScale {
+                        xconf: self.xconf,
+                        ..Default::default()
+                    };
+                    let tail = &mut scale.as_mut_bytes()[SIZE_OF_XCONF..];
+                    self.vchan.recv(tail)?;
+                    self.consumed += tail.len() as u64;
+                    if scale.default_scale().is_none() {
+                        break Err(Error::new(
+                            ErrorKind::InvalidData,
+                            "Default scale factor out of range",
+                        ));
+                    }
+                    self.scale = Some(scale);
+                    self.state = ReadState::ReadingHeader;
+                    self.did_reconnect = true;
+                    if yield_on_reconnect {
+                        break Ok(None);
+                    }
+                }
                 ReadState::ReadingHeader if ready < size_of::<Header>() => break Ok(None),
                 ReadState::ReadingHeader => {
                     // Reset buffer to 0 bytes
                     self.buffer.clear();
-                    let header: UntrustedHeader = self.vchan.recv_struct()?;
-                    match header.validate_length() {
+                    let header: UntrustedHeader =
+                        Self::recv_struct(&mut self.vchan, &mut self.consumed)?;
+                    match header.validate_length_with(self.deprecated) {
                         Err(e) => {
-                            break Err(Error::new(ErrorKind::InvalidData, format!("{}", e)));
+                            let kind = e
+                                .violation_kind()
+                                .unwrap_or(qubes_gui::ViolationKind::BadLength);
+                            self.violations.report(kind, &header);
+                            break Err(Error::new(ErrorKind::InvalidData, e));
+                        }
+                        Ok(Some(header))
+                            if self.stream_clipboard
+                                && header.ty() == qubes_gui::MSG_CLIPBOARD_DATA =>
+                        {
+                            self.state = match header.len() {
+                                0 => ReadState::ClipboardEnd { header },
+                                remaining => ReadState::StreamingClipboard { header, remaining },
+                            }
                         }
-                        Ok(Some(header)) if header.len() == 0 => {
+                        Ok(Some(header)) if header.len() == 0 && !matches!(body, Body::Peek) => {
                             self.state = ReadState::ReadingHeader;
-                            break Ok(Some(header));
+                            break Ok(Some(Incoming::Message(header)));
+                        }
+                        Ok(Some(header)) if matches!(body, Body::Caller(_)) => {
+                            self.state = ReadState::ReadingBodyInto { header, done: 0 }
                         }
-                        Ok(Some(header)) => self.state = ReadState::ReadingBody { header },
-                        Ok(None) if header.untrusted_len == 0 => {
-                            self.state = ReadState::ReadingHeader
+                        Ok(Some(header)) => {
+                            // Allocate the whole body up front, rather than
+                            // growing the buffer as pieces of it arrive.
+                            self.pool.prepare(&mut self.buffer, header.len());
+                            self.state = ReadState::ReadingBody { header }
+                        }
+                        Ok(None) => {
+                            #[cfg(feature = "proposed")]
+                            match qubes_gui::proposed::check_header(&header, self.xconf.version) {
+                                Err(e) => {
+                                    self.violations
+                                        .report(qubes_gui::ViolationKind::BadLength, &header);
+                                    break Err(Error::new(ErrorKind::InvalidData, e));
+                                }
+                                Ok(true) => {
+                                    let len = header.untrusted_len as usize;
+                                    self.pool.prepare(&mut self.buffer, len);
+                                    self.state = ReadState::ReadingProposed { header };
+                                    continue;
+                                }
+                                Ok(false) => {}
+                            }
+                            // Daemons must treat unknown messages as errors,
+                            // but agents must ignore them.  Deprecated
+                            // messages end up here if the policy skips them.
+                            // If the policy was chosen explicitly, they are
+                            // reported on both sides; `AcceptLegacy` skips
+                            // `MSG_RESIZE` just like before it existed.
+                            let deprecated = qubes_gui::Msg::try_from(header.ty)
+                                .is_ok_and(qubes_gui::Msg::is_deprecated);
+                            if deprecated
+                                && self.deprecated != qubes_gui::DeprecatedPolicy::AcceptLegacy
+                            {
+                                self.violations
+                                    .report(qubes_gui::ViolationKind::Deprecated, &header)
+                            } else if let Kind::Daemon = self.kind {
+                                self.violations
+                                    .report(qubes_gui::ViolationKind::UnknownMessage, &header)
+                            }
+                            self.metrics.record_discarded();
+                            self.state = match header.untrusted_len {
+                                0 => ReadState::ReadingHeader,
+                                len => ReadState::Discard(len as _),
+                            };
+                            if self.report_unknown {
+                                break Ok(Some(Incoming::Unknown(header)));
+                            }
                         }
-                        Ok(None) => self.state = ReadState::Discard(header.untrusted_len as _),
                     }
                 }
+                ReadState::ReadingBody { header }
+                | ReadState::ReadingBodyInto { header, .. }
+                | ReadState::StreamingClipboard { header, .. }
+                | ReadState::ClipboardEnd { header }
+                    if matches!(body, Body::Peek) =>
+                {
+                    break Ok(Some(Incoming::Peeked(*header)))
+                }
                 ReadState::Discard(untrusted_len) => {
-                    match self.vchan.discard(ready.min(*untrusted_len)) {
+                    let to_discard = ready.min(*untrusted_len);
+                    self.consumed += to_discard as u64;
+                    match self.vchan.discard(to_discard) {
                         Err(e) => break Err(e.into()),
                         Ok(()) if ready >= *untrusted_len => self.state = ReadState::ReadingHeader,
                         Ok(()) => *untrusted_len -= ready,
                     }
                 }
+                #[cfg(feature = "proposed")]
+                &mut ReadState::ReadingProposed { header } => {
+                    let to_read = header.untrusted_len as usize - self.buffer.len();
+                    self.vchan.recv_into(&mut self.buffer, to_read.min(ready))?;
+                    self.consumed += to_read.min(ready) as u64;
+                    break if ready < to_read {
+                        Ok(None)
+                    } else if self.screen_messages
+                        && header.ty == qubes_gui::proposed::MSG_SCREEN_CONFIGURED
+                    {
+                        let xconf = qubes_gui::XConf::from_bytes(&self.buffer);
+                        self.xconf.xconf = xconf;
+                        self.state = ReadState::ReadingHeader;
+                        Ok(Some(Incoming::ScreenConfigured(xconf)))
+                    } else {
+                        self.state = ReadState::ReadingHeader;
+                        Ok(Some(Incoming::Proposed(header)))
+                    };
+                }
+                &mut ReadState::ReadingBody { header } if matches!(body, Body::Caller(_)) => {
+                    // Move whatever has already been read into the caller's
+                    // buffer, and carry on from there.
+                    let done = self.buffer.len();
+                    match &mut body {
+                        Body::Caller(dst) if dst.len() >= header.len() => {
+                            dst[..done].copy_from_slice(&self.buffer);
+                            self.buffer.clear();
+                            self.state = ReadState::ReadingBodyInto { header, done }
+                        }
+                        _ => break Ok(Some(Incoming::BodyTooLarge(header))),
+                    }
+                }
                 &mut ReadState::ReadingBody { header } => {
                     let to_read = header.len() - self.buffer.len();
                     self.vchan.recv_into(&mut self.buffer, to_read.min(ready))?;
+                    self.consumed += to_read.min(ready) as u64;
                     break if ready >= to_read {
                         self.state = ReadState::ReadingHeader;
-                        Ok(Some(header))
+                        Ok(Some(Incoming::Message(header)))
                     } else {
                         Ok(None)
                     };
                 }
+                &mut ReadState::ReadingBodyInto { header, done } => {
+                    let dst = match &mut body {
+                        Body::Caller(dst) if dst.len() < header.len() => {
+                            break Ok(Some(Incoming::BodyTooLarge(header)))
+                        }
+                        Body::Caller(dst) => dst,
+                        Body::Internal | Body::Peek if done == 0 => {
+                            self.pool.prepare(&mut self.buffer, header.len());
+                            self.state = ReadState::ReadingBody { header };
+                            continue;
+                        }
+                        Body::Internal | Body::Peek => {
+                            break Err(Error::new(
+                                ErrorKind::InvalidInput,
+                                "Message body was partially read into a caller-provided buffer",
+                            ))
+                        }
+                    };
+                    let to_read = (header.len() - done).min(ready);
+                    self.vchan.recv(&mut dst[done..done + to_read])?;
+                    self.consumed += to_read as u64;
+                    break if done + to_read == header.len() {
+                        self.state = ReadState::ReadingHeader;
+                        Ok(Some(Incoming::Message(header)))
+                    } else {
+                        self.state = ReadState::ReadingBodyInto {
+                            header,
+                            done: done + to_read,
+                        };
+                        Ok(None)
+                    };
+                }
+                ReadState::StreamingClipboard { .. } if ready == 0 => break Ok(None),
+                &mut ReadState::StreamingClipboard { header, remaining } => {
+                    let to_read = remaining.min(ready);
+                    self.buffer.clear();
+                    self.vchan.recv_into(&mut self.buffer, to_read)?;
+                    self.consumed += to_read as u64;
+                    self.state = match remaining - to_read {
+                        0 => ReadState::ClipboardEnd { header },
+                        remaining => ReadState::StreamingClipboard { header, remaining },
+                    };
+                    break Ok(Some(Incoming::ClipboardChunk(header)));
+                }
+                &mut ReadState::ClipboardEnd { header } => {
+                    self.buffer.clear();
+                    self.state = ReadState::ReadingHeader;
+                    break Ok(Some(Incoming::ClipboardEnd(header)));
+                }
             }
         }
     }
@@ -351,122 +757,441 @@ impl<T: VchanMock + 'static> RawMessageStream<T> {
     /// more data needs to arrive, returns `Ok(None)`.  If an error occurs,
     /// `Err` is returned, and the stream is placed in an error state.  If the
     /// stream is in an error state, all further functions will fail.
+    ///
+    /// Streamed clipboard data is skipped.
     pub fn read_message<'a>(&'a mut self) -> io::Result<Option<Buffer<'a>>> {
-        match self.read_message_internal() {
-            Ok(Some(header)) => Ok(Some(Buffer {
-                hdr: header,
-                inner: &mut self.buffer,
-            })),
-            Ok(None) => Ok(None),
-            Err(e) => {
-                self.state = ReadState::Error;
-                Err(e)
+        loop {
+            match self.read_header(false)? {
+                None => return Ok(None),
+                Some(Incoming::Message(header)) => return Ok(Some(self.buffer(header))),
+                Some(Incoming::ClipboardChunk(_))
+                | Some(Incoming::ClipboardEnd(_))
+                | Some(Incoming::Unknown(_)) => {}
+                #[cfg(feature = "proposed")]
+                Some(Incoming::ScreenConfigured(_)) | Some(Incoming::Proposed(_)) => {}
+                Some(Incoming::BodyTooLarge(_)) | Some(Incoming::Peeked(_)) => {
+                    unreachable!("not peeking, and no caller-provided buffer")
+                }
+            }
+        }
+    }
+
+    /// Like [`RawMessageStream::read_message`], but reads the body into
+    /// `buf` instead of the internal buffer.
+    pub fn read_message_into(&mut self, buf: &mut [u8]) -> io::Result<Option<Header>> {
+        loop {
+            match self.read_header_into(false, Body::Caller(&mut *buf))? {
+                None => return Ok(None),
+                Some(Incoming::Message(header)) => return Ok(Some(header)),
+                Some(Incoming::ClipboardChunk(_))
+                | Some(Incoming::ClipboardEnd(_))
+                | Some(Incoming::Unknown(_)) => {}
+                #[cfg(feature = "proposed")]
+                Some(Incoming::ScreenConfigured(_)) | Some(Incoming::Proposed(_)) => {}
+                Some(Incoming::Peeked(_)) => unreachable!("not peeking"),
+                Some(Incoming::BodyTooLarge(header)) => {
+                    return Err(Error::new(
+                        ErrorKind::InvalidInput,
+                        format!(
+                            "Message body is {} bytes, but the buffer is only {} bytes",
+                            header.len(),
+                            buf.len()
+                        ),
+                    ))
+                }
             }
         }
     }
 
+    /// Returns the header of the next message without reading its body.
+    /// The message is still returned by the next read.  If the body of a
+    /// streamed `MSG_CLIPBOARD_DATA` is being read, returns its header.
+    /// Messages of unknown type are skipped.
+    pub fn peek_header(&mut self) -> io::Result<Option<Header>> {
+        loop {
+            match self.read_header_into(false, Body::Peek)? {
+                None => return Ok(None),
+                Some(Incoming::Peeked(header)) => return Ok(Some(header)),
+                Some(Incoming::Unknown(_)) => {}
+                #[cfg(feature = "proposed")]
+                Some(Incoming::ScreenConfigured(_)) | Some(Incoming::Proposed(_)) => {}
+                Some(incoming) => unreachable!("peeking returned {:?}", incoming),
+            }
+        }
+    }
+
+    /// Like [`RawMessageStream::read_message`], but only returns the header.
+    /// The body (if any) can then be obtained with
+    /// [`RawMessageStream::buffer`].  See
+    /// [`RawMessageStream::read_message_internal`] for `yield_on_reconnect`.
+    fn read_header(&mut self, yield_on_reconnect: bool) -> io::Result<Option<Incoming>> {
+        self.read_header_into(yield_on_reconnect, Body::Internal)
+    }
+
+    /// Like [`RawMessageStream::read_header`], but puts bodies in `body`.
+    fn read_header_into(
+        &mut self,
+        yield_on_reconnect: bool,
+        body: Body<'_>,
+    ) -> io::Result<Option<Incoming>> {
+        let res = self.read_message_internal(yield_on_reconnect, body);
+        match res {
+            Err(_) => self.state = ReadState::Error,
+            Ok(Some(Incoming::Message(header))) | Ok(Some(Incoming::ClipboardEnd(header))) => self
+                .metrics
+                .record_received(header.ty(), size_of::<Header>() + header.len()),
+            #[cfg(feature = "proposed")]
+            Ok(Some(Incoming::ScreenConfigured(_))) => self.metrics.record_received(
+                qubes_gui::proposed::MSG_SCREEN_CONFIGURED,
+                size_of::<Header>() + size_of::<qubes_gui::XConf>(),
+            ),
+            #[cfg(feature = "proposed")]
+            Ok(Some(Incoming::Proposed(header))) => self
+                .metrics
+                .record_received(header.ty, size_of::<Header>() + self.buffer.len()),
+            Ok(_) => {}
+        }
+        res
+    }
+
+    /// The buffer holding the body of the message with header `hdr`, which
+    /// must have just been returned by [`RawMessageStream::read_header`].
+    fn buffer(&mut self, hdr: Header) -> Buffer<'_> {
+        Buffer {
+            hdr,
+            inner: &mut self.buffer,
+        }
+    }
+
     pub fn needs_reconnect(&self) -> bool {
         self.vchan.status() == Status::Disconnected
     }
-}
 
-impl RawMessageStream<Option<Vchan>> {
-    pub fn agent(domain: u16) -> io::Result<Self> {
-        let vchan = Vchan::server(domain, qubes_gui::LISTENING_PORT.into(), 4096, 4096)?;
-        Ok(Self {
-            vchan: Some(vchan),
-            queue: Default::default(),
-            state: ReadState::Connecting,
-            buffer: vec![],
-            did_reconnect: false,
-            domid: domain,
-            kind: Kind::Agent,
-            xconf: Default::default(),
-        })
+    /// Write out everything queued, waiting at most until `deadline`.
+    fn drain(&mut self, deadline: Instant) -> io::Result<()> {
+        loop {
+            self.flush_pending_writes()?;
+            if self.queue.is_empty() && self.vchan.flush()? {
+                return Ok(());
+            }
+            if self.needs_reconnect() {
+                return Err(Error::new(
+                    ErrorKind::NotConnected,
+                    "Peer disconnected before all messages were sent",
+                ));
+            }
+            if Instant::now() >= deadline {
+                return Err(Error::new(
+                    ErrorKind::TimedOut,
+                    format!("Timed out with {} bytes still queued", self.queue.len()),
+                ));
+            }
+            std::thread::sleep(Duration::from_millis(1))
+        }
     }
+}
 
-    pub fn daemon(domain: u16, xconf: qubes_gui::XConf) -> io::Result<Self> {
-        Ok(Self {
-            vchan: Some(Vchan::client(domain, qubes_gui::LISTENING_PORT.into())?),
-            queue: Default::default(),
-            state: ReadState::ReadingHeader,
-            buffer: vec![],
-            did_reconnect: false,
-            domid: domain,
-            kind: Kind::Daemon,
-            xconf: qubes_gui::XConfVersion {
-                version: qubes_gui::PROTOCOL_VERSION,
-                xconf,
-            },
-        })
+impl<T: Transport> Drop for RawMessageStream<T> {
+    fn drop(&mut self) {
+        // Write out as much as possible without blocking, so that messages
+        // sent just before dropping the connection are not silently lost.
+        if self.flush_pending_writes().is_ok() {
+            let _ = self.vchan.flush();
+        }
     }
+}
 
+impl<T: Transport> RawMessageStream<T> {
     pub fn reconnect(&mut self) -> Result<(), vchan::Error> {
-        self.vchan = None;
-        self.vchan = Some(Vchan::server(
-            self.domid,
-            qubes_gui::LISTENING_PORT.into(),
-            4096,
-            4096,
-        )?);
+        self.vchan.reconnect()?;
         self.queue.clear();
         self.buffer.clear();
         self.state = ReadState::Connecting;
+        #[cfg(feature = "proposed")]
+        {
+            self.scale = None;
+        }
+        self.metrics.record_queue_depth(0);
+        self.metrics.record_reconnect();
         Ok(())
     }
 
     pub fn as_raw_fd(&self) -> std::os::raw::c_int {
-        self.vchan.as_ref().unwrap().fd()
+        self.vchan.fd()
+    }
+}
+
+/// An event reported by [`Connection::read_event`].  The `Debug` output does
+/// not include message bodies or clipboard data.
+#[non_exhaustive]
+pub enum Event<'a> {
+    /// A complete message has been received
+    Message(Buffer<'a>),
+    /// Version negotiation with a (possibly new) peer has completed.  This is
+    /// also reported for the initial connection.  Agents must recreate all of
+    /// their windows when they receive this.
+    Reconnected(qubes_gui::XConfVersion),
+    /// An extension is no longer available, either because the peer rejected
+    /// it or because a new peer negotiated an older protocol version.  Any
+    /// hooks registered with [`Connection::on_downgrade`] have already run.
+    Downgraded(Extension),
+    /// Part of the body of a `MSG_CLIPBOARD_DATA`.  Only reported if enabled
+    /// with [`Connection::set_stream_clipboard`].  Chunks are reported in
+    /// order, and may split UTF-8 sequences.
+    ClipboardChunk {
+        /// The window the message was sent to
+        window: qubes_gui::WindowID,
+        /// UNTRUSTED clipboard data
+        untrusted_data: &'a [u8],
+    },
+    /// All of the body of a streamed `MSG_CLIPBOARD_DATA` has been reported
+    ClipboardEnd {
+        /// The window the message was sent to
+        window: qubes_gui::WindowID,
+        /// The total length of the body
+        len: usize,
+    },
+    /// Nothing has been received from the peer for longer than the timeout
+    /// set with [`Connection::set_liveness_timeout`].  Reported once per
+    /// silence.  Daemons will usually want to tear the connection down, and
+    /// agents to reconnect.
+    PeerUnresponsive {
+        /// How long the peer has been silent
+        silent_for: Duration,
+    },
+    /// The root window has changed.  Reported after [`Event::Reconnected`]
+    /// if the new daemon's root window differs from the old one's, and when
+    /// the daemon sends `MSG_SCREEN_CONFIGURED` (see
+    /// [`Extension::ScreenConfig`]).  Agents will usually want to lay their
+    /// windows out again.
+    ScreenChanged(qubes_gui::XConf),
+    /// A message of unknown type has been received.  Its body has been
+    /// discarded.  Only reported if enabled with
+    /// [`Connection::set_report_unknown_messages`].
+    UnknownMessage {
+        /// The message type
+        ty: u32,
+        /// The window the message was sent to
+        window: qubes_gui::WindowID,
+        /// UNTRUSTED length of the body
+        len: u32,
+    },
+    /// A message from [`qubes_gui::proposed`].  Only reported once
+    /// [`PROPOSED_VERSION`](qubes_gui::proposed::PROPOSED_VERSION) has been
+    /// negotiated (see [`Connection::set_propose`]).  The length of the body
+    /// has been checked with [`qubes_gui::proposed::check_header`], but
+    /// nothing else has.
+    #[cfg(feature = "proposed")]
+    Proposed {
+        /// The message type
+        ty: u32,
+        /// The window the message was sent to
+        window: qubes_gui::WindowID,
+        /// UNTRUSTED body
+        untrusted_body: &'a [u8],
+    },
+}
+
+impl std::fmt::Debug for Event<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Event::Message(buffer) => f.debug_tuple("Message").field(buffer).finish(),
+            Event::Reconnected(xconf) => f.debug_tuple("Reconnected").field(xconf).finish(),
+            Event::Downgraded(extension) => f.debug_tuple("Downgraded").field(extension).finish(),
+            Event::ScreenChanged(xconf) => f.debug_tuple("ScreenChanged").field(xconf).finish(),
+            Event::ClipboardChunk {
+                window,
+                untrusted_data,
+            } => f
+                .debug_struct("ClipboardChunk")
+                .field("window", window)
+                .field("untrusted_data", &qubes_gui::Redacted(untrusted_data))
+                .finish(),
+            Event::ClipboardEnd { window, len } => f
+                .debug_struct("ClipboardEnd")
+                .field("window", window)
+                .field("len", len)
+                .finish(),
+            Event::PeerUnresponsive { silent_for } => f
+                .debug_struct("PeerUnresponsive")
+                .field("silent_for", silent_for)
+                .finish(),
+            Event::UnknownMessage { ty, window, len } => f
+                .debug_struct("UnknownMessage")
+                .field("ty", ty)
+                .field("window", window)
+                .field("len", len)
+                .finish(),
+            #[cfg(feature = "proposed")]
+            Event::Proposed {
+                ty,
+                window,
+                untrusted_body,
+            } => f
+                .debug_struct("Proposed")
+                .field("ty", ty)
+                .field("window", window)
+                .field("untrusted_body", &qubes_gui::Redacted(untrusted_body))
+                .finish(),
+        }
     }
 }
+
 /// The entry-point to the library.
 #[derive(Debug)]
 pub struct Connection {
-    raw: RawMessageStream<Option<vchan::Vchan>>,
+    raw: RawMessageStream<Box<dyn Transport>>,
+    reconnect: Option<reconnect::ReconnectManager>,
+    extensions: extensions::DowngradeManager,
+    liveness: Option<liveness::LivenessMonitor>,
+    dumps: dumps::DumpTracker,
+    /// The root window of the current daemon, once negotiated
+    screen: Option<qubes_gui::XConf>,
+    /// A change of root window not yet reported by `read_event`
+    screen_changed: Option<qubes_gui::XConf>,
+    /// An error from the `on_reconnect` callback of `read_event_with`, which
+    /// is reported after the reconnection itself
+    reconnect_error: Option<io::Error>,
 }
 
 impl Connection {
     /// Send a GUI message.  This never blocks; outgoing messages are queued
     /// until there is space in the vchan.
+    #[deprecated(note = "use send_agent or send_daemon, which check the direction at compile time")]
     pub fn send<T: qubes_gui::Message>(
         &mut self,
         message: &T,
         window: qubes_gui::WindowID,
     ) -> io::Result<()> {
-        self.send_raw(message.as_bytes(), window, T::KIND as _)
+        self.send_with_header(Header::for_message::<T>(window), message.as_bytes())
     }
 
-    /// Raw version of [`Connection::send`].  Using [`Connection::send`] is preferred
-    /// where possible, as it automatically selects the correct message type.
+    /// Send a message from an agent.  This never blocks; outgoing messages
+    /// are queued until there is space in the vchan.  Only messages that
+    /// agents may send are accepted:
+    ///
+    /// ```compile_fail
+    /// fn press(connection: &mut qubes_gui_connection::Connection) {
+    ///     let keypress = qubes_gui::Keypress::default();
+    ///     connection.send_agent(&keypress, Default::default()).unwrap();
+    /// }
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`io::ErrorKind::InvalidInput`] if this is a daemon
+    /// connection.
+    pub fn send_agent<T: qubes_gui::AgentMessage>(
+        &mut self,
+        message: &T,
+        window: qubes_gui::WindowID,
+    ) -> io::Result<()> {
+        self.send_as(Kind::Agent, message, window)
+    }
+
+    /// Send a message from a daemon.  Like [`Connection::send_agent`], but
+    /// only messages that daemons may send are accepted.
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`io::ErrorKind::InvalidInput`] if this is an agent
+    /// connection.
+    pub fn send_daemon<T: qubes_gui::DaemonMessage>(
+        &mut self,
+        message: &T,
+        window: qubes_gui::WindowID,
+    ) -> io::Result<()> {
+        self.send_as(Kind::Daemon, message, window)
+    }
+
+    fn send_as<T: qubes_gui::Message>(
+        &mut self,
+        kind: Kind,
+        message: &T,
+        window: qubes_gui::WindowID,
+    ) -> io::Result<()> {
+        if self.raw.kind != kind {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                match kind {
+                    Kind::Agent => "agent message sent on a daemon connection",
+                    Kind::Daemon => "daemon message sent on an agent connection",
+                },
+            ));
+        }
+        self.send_with_header(Header::for_message::<T>(window), message.as_bytes())
+    }
+
+    /// Raw version of [`Connection::send_agent`] and
+    /// [`Connection::send_daemon`].  Using those is preferred where possible,
+    /// as they automatically select the correct message type.
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`io::ErrorKind::InvalidInput`] if `ty` is not a known
+    /// message type, or if `message` is not a valid length for it.
     pub fn send_raw(
         &mut self,
         message: &[u8],
         window: qubes_gui::WindowID,
         ty: u32,
     ) -> io::Result<()> {
-        let untrusted_len = message
-            .len()
-            .try_into()
-            .expect("Message length must fit in a u32");
-        let header = qubes_gui::UntrustedHeader {
-            ty,
-            window,
-            untrusted_len,
+        let header = qubes_gui::Msg::try_from(ty)
+            .map_err(|ty| qubes_gui::ProtocolError::UnknownType { ty })
+            .and_then(|kind| Header::with_len(kind, window, message.len()))
+            .map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+        self.send_with_header(header, message)
+    }
+
+    /// Send a message of any type.  Like [`Connection::send_agent`], this
+    /// never blocks.
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`io::ErrorKind::InvalidInput`] if the message is too long.
+    pub fn send_message(
+        &mut self,
+        message: &OutgoingMessage,
+        window: qubes_gui::WindowID,
+    ) -> io::Result<()> {
+        let header = message
+            .header(window)
+            .map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+        self.send_with_header(header, &message.body())
+    }
+
+    fn send_with_header(&mut self, header: Header, message: &[u8]) -> io::Result<()> {
+        let dump = match header.untrusted_window().window {
+            Some(window)
+                if header.ty() == qubes_gui::MSG_WINDOW_DUMP
+                    && self.extensions().contains(Extension::DumpAck) =>
+            {
+                if !self.dumps.has_room() {
+                    return Err(Error::new(
+                        ErrorKind::WouldBlock,
+                        "Too many window dumps awaiting acknowledgement",
+                    ));
+                }
+                Some(window)
+            }
+            _ => None,
         };
-        header
-            .validate_length()
-            .unwrap()
-            .expect("Sending unknown message!");
-        // FIXME this is slow
-        self.raw.write(header.as_bytes())?;
-        self.raw.write(message)?;
+        self.raw
+            .write_vectored(&[header.inner().as_bytes(), message])?;
+        if let Some(window) = dump {
+            self.dumps.sent(window)
+        }
+        self.raw
+            .metrics
+            .record_sent(header.ty(), size_of::<UntrustedHeader>() + message.len());
         Ok(())
     }
 
-    /// Even rawer version of [`Connection::send`].  Using [`Connection::send`] is
-    /// preferred where possible, as it automatically selects the correct
-    /// message type.  Otherwise, prefer [`Connection::send_raw`], which at least
-    /// ensures correct framing.
+    /// Even rawer version of [`Connection::send_agent`] and
+    /// [`Connection::send_daemon`].  Using those is preferred where possible,
+    /// as they automatically select the correct message type.  Otherwise,
+    /// prefer [`Connection::send_raw`], which at least ensures correct
+    /// framing.
     pub fn send_raw_bytes(&mut self, msg: &[u8]) -> io::Result<()> {
         self.raw.write(msg).map_err(From::from)
     }
@@ -477,6 +1202,12 @@ impl Connection {
         self.raw.wait()
     }
 
+    /// Give back a body obtained with [`Buffer::take`], so that its
+    /// allocation can be reused for a later message.
+    pub fn recycle(&mut self, body: Vec<u8>) {
+        self.raw.pool.recycle(body)
+    }
+
     /// If a complete message has been buffered, returns `Ok(Some(msg))`.  If
     /// more data needs to arrive, returns `Ok(None)`.  If an error occurs,
     /// `Err` is returned, and the stream is placed in an error state.  If the
@@ -489,18 +1220,452 @@ impl Connection {
         }
     }
 
+    /// Like [`Connection::read_message`], but reads the body directly into
+    /// `buf` instead of an internal buffer, and returns only the header.  The
+    /// body is in `buf[..header.len()]`.
+    ///
+    /// Once this has returned [`Poll::Pending`], part of the body may already
+    /// have been written to `buf`, so the next call must pass the same buffer
+    /// (with its contents intact).  If the body does not fit in `buf`, this
+    /// fails with [`ErrorKind::InvalidInput`] without consuming the body, and
+    /// the connection remains usable: call this again with a larger buffer,
+    /// or use [`Connection::read_message`].
+    pub fn read_message_into(&mut self, buf: &mut [u8]) -> Poll<io::Result<Header>> {
+        match self.raw.read_message_into(buf) {
+            Ok(None) => Poll::Pending,
+            Ok(Some(v)) => Poll::Ready(Ok(v)),
+            Err(e) => Poll::Ready(Err(e)),
+        }
+    }
+
+    /// Returns the header of the next message without reading its body, so
+    /// that the message can be routed or rejected before the body is read.
+    /// The message is still returned by the next call to
+    /// [`Connection::read_message`], [`Connection::read_message_into`], or
+    /// [`Connection::read_event`].  The header has already been validated.
+    pub fn peek_header(&mut self) -> Poll<io::Result<Header>> {
+        match self.raw.peek_header() {
+            Ok(None) => Poll::Pending,
+            Ok(Some(v)) => Poll::Ready(Ok(v)),
+            Err(e) => Poll::Ready(Err(e)),
+        }
+    }
+
+    /// Like [`Connection::read_message`], but also reports connection-level
+    /// events.  If a [`ReconnectPolicy`] has been set with
+    /// [`Connection::set_reconnect_policy`], this also reconnects
+    /// automatically when the peer disconnects, and only returns an error once
+    /// the policy gives up.
+    pub fn read_event(&mut self) -> Poll<io::Result<Event<'_>>> {
+        self.read_event_with(|_| Ok(()), |_| true)
+    }
+
+    /// Like [`Connection::read_event`], but runs `on_reconnect` after a
+    /// reconnection and before [`Event::Reconnected`] is returned.  If it
+    /// fails, [`Event::Reconnected`] is still returned, and the error is
+    /// returned by the next call.  Messages for which `keep` returns `false`
+    /// are silently dropped.
+    pub(crate) fn read_event_with(
+        &mut self,
+        on_reconnect: impl FnOnce(&mut Self) -> io::Result<()>,
+        mut keep: impl FnMut(Header) -> bool,
+    ) -> Poll<io::Result<Event<'_>>> {
+        if let Some(e) = self.reconnect_error.take() {
+            return Poll::Ready(Err(e));
+        }
+        if let Some(manager) = self.reconnect.as_mut() {
+            if self.raw.needs_reconnect() {
+                let now = Instant::now();
+                match manager.poll(now) {
+                    reconnect::Action::Wait(_) => return Poll::Pending,
+                    reconnect::Action::GiveUp(failures) => {
+                        return Poll::Ready(Err(Error::new(
+                            ErrorKind::NotConnected,
+                            format!("Giving up after {} failed reconnection attempts", failures),
+                        )))
+                    }
+                    reconnect::Action::Attempt => {
+                        let res = self.raw.reconnect();
+                        manager.attempted(now, res.is_ok());
+                        if res.is_err() {
+                            return Poll::Pending;
+                        }
+                    }
+                }
+            }
+        }
+        if let Some(ext) = self.extensions.next_event() {
+            return Poll::Ready(Ok(Event::Downgraded(ext)));
+        }
+        if let Some(xconf) = self.screen_changed.take() {
+            return Poll::Ready(Ok(Event::ScreenChanged(xconf)));
+        }
+        if self.raw.did_reconnect {
+            return Poll::Ready(Ok(self.take_reconnected(on_reconnect)));
+        }
+        loop {
+            break match self.raw.read_header(true) {
+                Ok(None) if self.raw.did_reconnect => {
+                    Poll::Ready(Ok(self.take_reconnected(on_reconnect)))
+                }
+                Ok(None) => match self.check_liveness() {
+                    Some(silent_for) => Poll::Ready(Ok(Event::PeerUnresponsive { silent_for })),
+                    None => Poll::Pending,
+                },
+                Ok(Some(Incoming::Message(header))) => {
+                    if header.ty() == qubes_gui::MSG_WINDOW_DUMP_ACK {
+                        if let Some(window) = header.untrusted_window().window {
+                            self.dumps.acked(window)
+                        }
+                    }
+                    if !keep(header) {
+                        continue;
+                    }
+                    Poll::Ready(Ok(Event::Message(self.raw.buffer(header))))
+                }
+                Ok(Some(Incoming::ClipboardChunk(header))) => {
+                    Poll::Ready(Ok(Event::ClipboardChunk {
+                        window: header.untrusted_window(),
+                        untrusted_data: &self.raw.buffer,
+                    }))
+                }
+                Ok(Some(Incoming::ClipboardEnd(header))) => Poll::Ready(Ok(Event::ClipboardEnd {
+                    window: header.untrusted_window(),
+                    len: header.len(),
+                })),
+                #[cfg(feature = "proposed")]
+                Ok(Some(Incoming::ScreenConfigured(xconf))) => {
+                    if self.screen.replace(xconf) == Some(xconf) {
+                        continue;
+                    }
+                    Poll::Ready(Ok(Event::ScreenChanged(xconf)))
+                }
+                Ok(Some(Incoming::Unknown(header))) => Poll::Ready(Ok(Event::UnknownMessage {
+                    ty: header.ty,
+                    window: header.window,
+                    len: header.untrusted_len,
+                })),
+                #[cfg(feature = "proposed")]
+                Ok(Some(Incoming::Proposed(header))) => Poll::Ready(Ok(Event::Proposed {
+                    ty: header.ty,
+                    window: header.window,
+                    untrusted_body: &self.raw.buffer,
+                })),
+                Ok(Some(Incoming::BodyTooLarge(_))) | Ok(Some(Incoming::Peeked(_))) => {
+                    unreachable!("not peeking, and no caller-provided buffer")
+                }
+                Err(e) => Poll::Ready(Err(e)),
+            };
+        }
+    }
+
+    /// If a liveness timeout is set, note any progress since the last call,
+    /// and return how long the peer has been silent if it has just become
+    /// unresponsive.  Only called once nothing more can be read, so that data
+    /// that has arrived is read before the peer is declared silent.
+    fn check_liveness(&mut self) -> Option<Duration> {
+        let monitor = self.liveness.as_mut()?;
+        let now = Instant::now();
+        monitor.observe(now, self.raw.consumed, self.raw.vchan.status());
+        monitor.check(now)
+    }
+
+    fn take_reconnected(
+        &mut self,
+        on_reconnect: impl FnOnce(&mut Self) -> io::Result<()>,
+    ) -> Event<'static> {
+        let xconf = self.raw.xconf;
+        self.raw.did_reconnect = false;
+        self.dumps.complete_all();
+        self.extensions.negotiated(xconf.version);
+        if let Kind::Agent = self.raw.kind {
+            #[cfg(feature = "proposed")]
+            {
+                self.raw.screen_messages = self.extensions().contains(Extension::ScreenConfig);
+            }
+            if matches!(self.screen.replace(xconf.xconf), Some(old) if old != xconf.xconf) {
+                self.screen_changed = Some(xconf.xconf)
+            }
+        }
+        if let Some(manager) = self.reconnect.as_mut() {
+            manager.negotiated(&xconf)
+        }
+        self.reconnect_error = on_reconnect(self).err();
+        Event::Reconnected(xconf)
+    }
+
+    /// The extensions that can currently be used.  This is only updated by
+    /// [`Connection::read_event`].
+    pub fn extensions(&self) -> Extensions {
+        self.extensions.active()
+    }
+
+    /// Traffic counters for this connection
+    pub fn metrics(&self) -> &Metrics {
+        &self.raw.metrics
+    }
+
+    /// Register `hook` to be run when `ext` stops being available.  The hook
+    /// runs before [`Event::Downgraded`] is reported, so that higher-level
+    /// code has already switched to its fallback by the time the application
+    /// hears about it.
+    pub fn on_downgrade(&mut self, ext: Extension, hook: impl FnMut(Extension) + 'static) {
+        self.extensions.add_hook(ext, Box::new(hook))
+    }
+
+    /// Report that the peer has rejected `ext`.  It will not be used again
+    /// until the next reconnection.  Returns `false` if `ext` was not in use.
+    pub fn reject_extension(&mut self, ext: Extension) -> bool {
+        let rejected = self.extensions.reject(ext);
+        if rejected && ext == Extension::DumpAck {
+            self.dumps.complete_all()
+        }
+        #[cfg(feature = "proposed")]
+        if rejected && ext == Extension::ScreenConfig {
+            self.raw.screen_messages = false
+        }
+        rejected
+    }
+
+    /// Allow at most `limit` `MSG_WINDOW_DUMP`s, across all windows, to await
+    /// `MSG_WINDOW_DUMP_ACK` at once.  Sending another one fails with
+    /// [`ErrorKind::WouldBlock`] until an acknowledgement arrives.  Pass
+    /// `None`, the default, for no limit.  Dumps are only tracked while
+    /// [`Extension::DumpAck`] is available.
+    pub fn set_dump_limit(&mut self, limit: Option<usize>) {
+        self.dumps.set_limit(limit)
+    }
+
+    /// The number of window dumps sent to `window`, or to any window if
+    /// `window` is `None`, that the daemon has not acknowledged yet.
+    /// Acknowledgements are only noticed by [`Connection::read_event`].
+    pub fn outstanding_dumps(&self, window: Option<std::num::NonZeroU32>) -> usize {
+        self.dumps.outstanding(window)
+    }
+
+    /// Run `callback` once the daemon has acknowledged every window dump sent
+    /// to `window` so far.  From then on, the buffers those dumps replaced
+    /// are no longer in use, and their grant references can be freed or
+    /// reused.  If nothing is outstanding, `callback` runs immediately.
+    ///
+    /// Callbacks also run if acknowledgements stop being possible: on
+    /// reconnection, and if [`Extension::DumpAck`] is rejected.
+    pub fn on_dumps_acked(
+        &mut self,
+        window: std::num::NonZeroU32,
+        callback: impl FnOnce() + 'static,
+    ) {
+        if let Some(callback) = self.dumps.on_acked(window, Box::new(callback)) {
+            callback()
+        }
+    }
+
+    /// Report the bodies of `MSG_CLIPBOARD_DATA` messages incrementally, as
+    /// [`Event::ClipboardChunk`] and [`Event::ClipboardEnd`], instead of
+    /// buffering them whole.  This only affects [`Connection::read_event`];
+    /// [`Connection::read_message`] skips streamed clipboard data.  Takes
+    /// effect from the next message header.
+    pub fn set_stream_clipboard(&mut self, stream: bool) {
+        self.raw.stream_clipboard = stream
+    }
+
+    /// Report the headers of messages of unknown type as
+    /// [`Event::UnknownMessage`], so that they can be logged.  Their bodies
+    /// are discarded either way.  This only affects
+    /// [`Connection::read_event`].
+    pub fn set_report_unknown_messages(&mut self, report: bool) {
+        self.raw.report_unknown = report
+    }
+
+    /// Set what to do with deprecated messages (`MSG_RESIZE`, `MSG_EXECUTE`,
+    /// and `MSG_MFNDUMP`) from the peer.  The default is
+    /// [`qubes_gui::DeprecatedPolicy::AcceptLegacy`].  Messages skipped by
+    /// [`qubes_gui::DeprecatedPolicy::Ignore`] are reported to the violation
+    /// sink as [`qubes_gui::ViolationKind::Deprecated`].  `MSG_RESIZE`
+    /// skipped by [`qubes_gui::DeprecatedPolicy::AcceptLegacy`] is treated
+    /// like a message of unknown type: only daemons report it, as
+    /// [`qubes_gui::ViolationKind::UnknownMessage`].  Skipped messages are
+    /// also returned as [`Event::UnknownMessage`] if enabled with
+    /// [`Connection::set_report_unknown_messages`].
+    pub fn set_deprecated_policy(&mut self, policy: qubes_gui::DeprecatedPolicy) {
+        self.raw.deprecated = policy
+    }
+
+    /// Offer [`PROPOSED_VERSION`](qubes_gui::proposed::PROPOSED_VERSION) to
+    /// peers that connect from now on.  Off by default.  Agents send it in
+    /// place of [`qubes_gui::PROTOCOL_VERSION`], and still accept a daemon
+    /// that only speaks the current version.  Daemons accept it from agents
+    /// that send it, and reply with an
+    /// [`XConfScale`](qubes_gui::proposed::XConfScale) (see
+    /// [`Connection::set_scale_offer`]).  Both sides must opt in for the
+    /// proposed messages to be used.
+    #[cfg(feature = "proposed")]
+    pub fn set_propose(&mut self, propose: bool) {
+        self.raw.propose = propose
+    }
+
+    /// Daemon only: the default scale factor and
+    /// [feature bits](qubes_gui::proposed::ALL_FEATURES) to send to agents
+    /// that negotiate the proposed version from now on.  The default is a
+    /// scale factor of 1 and no features.
+    #[cfg(feature = "proposed")]
+    pub fn set_scale_offer(
+        &mut self,
+        default_scale: qubes_gui::proposed::ValidScale,
+        features: u32,
+    ) {
+        self.raw.scale_offer.default_scale = default_scale.get();
+        self.raw.scale_offer.features = features
+    }
+
+    /// The [`XConfScale`](qubes_gui::proposed::XConfScale) sent during
+    /// version negotiation, if the proposed version was negotiated.  This is
+    /// `None` before negotiation completes, and for peers that did not opt
+    /// in.
+    #[cfg(feature = "proposed")]
+    pub fn xconf_scale(&self) -> Option<qubes_gui::proposed::XConfScale> {
+        self.raw.scale
+    }
+
+    /// Send a message from [`qubes_gui::proposed`].  Like
+    /// [`Connection::send_agent`], this never blocks.
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`io::ErrorKind::InvalidInput`] if the proposed version has
+    /// not been negotiated, if `ty` is not a proposed message, or if `body`
+    /// is not a valid length for it.
+    #[cfg(feature = "proposed")]
+    pub fn send_proposed(
+        &mut self,
+        ty: u32,
+        window: qubes_gui::WindowID,
+        body: &[u8],
+    ) -> io::Result<()> {
+        let header = UntrustedHeader {
+            ty,
+            window,
+            untrusted_len: u32::try_from(body.len()).unwrap_or(u32::MAX),
+        };
+        match qubes_gui::proposed::check_header(&header, self.raw.xconf.version) {
+            Ok(true) => {}
+            Ok(false) => {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("Message type {} needs the proposed version", ty),
+                ))
+            }
+            Err(e) => return Err(Error::new(ErrorKind::InvalidInput, e)),
+        }
+        self.raw.write_vectored(&[header.as_bytes(), body])?;
+        self.raw
+            .metrics
+            .record_sent(ty, size_of::<UntrustedHeader>() + body.len());
+        Ok(())
+    }
+
+    /// Report protocol violations by the peer to `sink`.  Only violations
+    /// detected while reading messages are reported: messages with a bad
+    /// length, deprecated messages that were not accepted, and (for daemons)
+    /// messages of unknown type.  Replaces any previous sink.
+    pub fn set_violation_sink(&mut self, sink: impl qubes_gui::ViolationSink + 'static) {
+        self.raw.violations = Violations(Some(Box::new(sink)))
+    }
+
+    /// Reconnect automatically according to `policy`.  This only affects
+    /// [`Connection::read_event`].  Pass `None` to go back to manual
+    /// reconnection.  Like [`Connection::reconnect`], this is only meaningful
+    /// for agents.
+    pub fn set_reconnect_policy(&mut self, policy: Option<ReconnectPolicy>) {
+        self.reconnect = policy.map(reconnect::ReconnectManager::new)
+    }
+
+    /// If [`Connection::read_event`] is waiting to retry a failed
+    /// reconnection, returns the time of the next attempt.  No event will
+    /// arrive on the file descriptor in this case, so callers must arrange to
+    /// call [`Connection::read_event`] again at this time.
+    pub fn reconnect_deadline(&self) -> Option<Instant> {
+        match self.reconnect.as_ref()?.poll(Instant::now()) {
+            reconnect::Action::Wait(deadline) => Some(deadline),
+            reconnect::Action::Attempt | reconnect::Action::GiveUp(_) => None,
+        }
+    }
+
+    /// Report [`Event::PeerUnresponsive`] from [`Connection::read_event`] if
+    /// nothing arrives from the peer for `timeout`.  Reading data and changes
+    /// in the status of the transport (such as the peer connecting or
+    /// disconnecting) reset the timer.  Data that cannot be read yet, such as
+    /// part of a message header, does not.  The protocol has no keepalive
+    /// messages, so an idle but healthy peer is also reported; choose the
+    /// timeout accordingly.  Pass `None` to stop monitoring.
+    pub fn set_liveness_timeout(&mut self, timeout: Option<Duration>) {
+        let (consumed, status) = (self.raw.consumed, self.raw.vchan.status());
+        self.liveness = timeout.map(|timeout| {
+            liveness::LivenessMonitor::new(timeout, Instant::now(), consumed, status)
+        })
+    }
+
+    /// If a liveness timeout is set, returns when
+    /// [`Event::PeerUnresponsive`] will be reported if nothing arrives
+    /// first.  No event will arrive on the file descriptor in this case, so
+    /// callers must arrange to call [`Connection::read_event`] again at this
+    /// time.
+    pub fn liveness_deadline(&self) -> Option<Instant> {
+        self.liveness.as_ref()?.deadline()
+    }
+
     /// Creates a daemon instance
     pub fn daemon(domain: u16, xconf: qubes_gui::XConf) -> io::Result<Self> {
-        Ok(Self {
-            raw: RawMessageStream::daemon(domain, xconf)?,
-        })
+        Ok(Self::daemon_over(VchanTransport::daemon(domain)?, xconf))
     }
 
     /// Creates an agent instance
     pub fn agent(domain: u16) -> io::Result<Self> {
-        Ok(Self {
-            raw: RawMessageStream::agent(domain)?,
-        })
+        Ok(Self::agent_over(VchanTransport::agent(domain)?))
+    }
+
+    /// Creates a daemon instance that uses `transport` instead of a vchan
+    pub fn daemon_over(transport: impl Transport + 'static, xconf: qubes_gui::XConf) -> Self {
+        let xconf = qubes_gui::XConfVersion {
+            version: qubes_gui::PROTOCOL_VERSION,
+            xconf,
+        };
+        Self::over(transport, Kind::Daemon, xconf)
+    }
+
+    /// Creates an agent instance that uses `transport` instead of a vchan
+    pub fn agent_over(transport: impl Transport + 'static) -> Self {
+        Self::over(transport, Kind::Agent, Default::default())
+    }
+
+    fn over(
+        transport: impl Transport + 'static,
+        kind: Kind,
+        xconf: qubes_gui::XConfVersion,
+    ) -> Self {
+        Self {
+            raw: RawMessageStream::new(Box::new(transport), kind, xconf),
+            reconnect: None,
+            extensions: Default::default(),
+            liveness: None,
+            dumps: Default::default(),
+            screen: None,
+            screen_changed: None,
+            reconnect_error: None,
+        }
+    }
+
+    /// Send everything still queued, waiting at most `timeout`, and then close
+    /// the connection.  Use this before exiting, so that final messages (such
+    /// as [`qubes_gui::MSG_DESTROY`]) reach the peer.  Dropping a
+    /// [`Connection`] only sends what can be sent without waiting.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the queue could not be emptied in time or the peer went away
+    /// first.  The connection is closed either way.
+    pub fn shutdown(mut self, timeout: Duration) -> io::Result<()> {
+        self.raw.drain(Instant::now() + timeout)
     }
 
     /// Try to reconnect.  If this fails, the agent is no longer usable; future
@@ -519,12 +1684,49 @@ impl Connection {
         self.raw.needs_reconnect()
     }
 
+    /// Returns true if the transport is waiting for the file descriptor to
+    /// become writable (see [`Transport::wants_writable`]).  Event loops
+    /// should then also wait for that, and call [`Connection::wait`] when it
+    /// happens.
+    pub fn wants_writable(&self) -> bool {
+        self.raw.vchan.wants_writable()
+    }
+
     /// Get version information
     pub fn xconf(&self) -> qubes_gui::XConfVersion {
         self.raw.xconf
     }
+
+    /// Daemon only: the root window has changed to `xconf`.  Agents that
+    /// connect from now on are told so during version negotiation, and the
+    /// current agent is sent `MSG_SCREEN_CONFIGURED` if it has negotiated
+    /// [`Extension::ScreenConfig`], which needs the `proposed` feature.
+    /// Returns whether it was sent; if not, the agent only learns of the
+    /// change when it reconnects.
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`ErrorKind::InvalidInput`] on an agent connection, and
+    /// otherwise only if sending fails.
+    pub fn set_screen(&mut self, xconf: qubes_gui::XConf) -> io::Result<bool> {
+        if let Kind::Agent = self.raw.kind {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "Only daemons configure the screen",
+            ));
+        }
+        self.raw.xconf.xconf = xconf;
+        #[cfg(feature = "proposed")]
+        if self.extensions().contains(Extension::ScreenConfig) {
+            let ty = qubes_gui::proposed::MSG_SCREEN_CONFIGURED;
+            self.send_proposed(ty, Default::default(), xconf.as_bytes())?;
+            return Ok(true);
+        }
+        Ok(false)
+    }
 }
 
+#[cfg(unix)]
 impl std::os::unix::io::AsRawFd for Connection {
     fn as_raw_fd(&self) -> std::os::raw::c_int {
         self.raw.as_raw_fd()
@@ -0,0 +1,136 @@
+/*
+ * The Qubes OS Project, https://www.qubes-os.org
+ *
+ * Copyright (C) 2021  Demi Marie Obenour  <demi@invisiblethingslab.com>
+ *
+ * This program is free software; you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License
+ * as published by the Free Software Foundation; either version 2
+ * of the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301, USA.
+ *
+ */
+
+//! Detection of peers that have stopped talking.
+//!
+//! The GUI protocol has no keepalive messages, so all that can be done is to
+//! notice when nothing has arrived for a while.  With a timeout set by
+//! [`Connection::set_liveness_timeout`](crate::Connection::set_liveness_timeout),
+//! [`Connection::read_event`](crate::Connection::read_event) reports
+//! [`Event::PeerUnresponsive`](crate::Event::PeerUnresponsive) once the peer
+//! has been silent for that long.
+
+use std::time::{Duration, Instant};
+use vchan::Status;
+
+/// Tracks how long it has been since the peer was last heard from
+#[derive(Debug)]
+pub(crate) struct LivenessMonitor {
+    timeout: Duration,
+    /// When data was last read, or the status of the transport last changed
+    last_activity: Instant,
+    /// The number of bytes read from the transport when last observed
+    consumed: u64,
+    /// The status of the transport when last observed
+    status: Status,
+    /// Has the current silence already been reported?
+    reported: bool,
+}
+
+impl LivenessMonitor {
+    pub(crate) fn new(timeout: Duration, now: Instant, consumed: u64, status: Status) -> Self {
+        Self {
+            timeout,
+            last_activity: now,
+            consumed,
+            status,
+            reported: false,
+        }
+    }
+
+    /// Record that `consumed` bytes have been read from the transport so far
+    /// and its status is `status`.  Either reading more data or a change of
+    /// status (such as a reconnection) counts as activity.  Data that is
+    /// waiting but cannot be read yet, such as part of a header, does not,
+    /// so a peer that stops partway through a message is still noticed.
+    pub(crate) fn observe(&mut self, now: Instant, consumed: u64, status: Status) {
+        if consumed != self.consumed || status != self.status {
+            self.last_activity = now;
+            self.consumed = consumed;
+            self.status = status;
+            self.reported = false;
+        }
+    }
+
+    /// If the peer has just become unresponsive, returns how long it has
+    /// been silent.  Each silence is only reported once.
+    pub(crate) fn check(&mut self, now: Instant) -> Option<Duration> {
+        let silent_for = now.saturating_duration_since(self.last_activity);
+        if self.reported || silent_for < self.timeout {
+            return None;
+        }
+        self.reported = true;
+        Some(silent_for)
+    }
+
+    /// When the current silence will be reported, unless it already has been
+    pub(crate) fn deadline(&self) -> Option<Instant> {
+        if self.reported {
+            None
+        } else {
+            Some(self.last_activity + self.timeout)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports_each_silence_once() {
+        let start = Instant::now();
+        let second = Duration::from_secs(1);
+        let mut monitor = LivenessMonitor::new(5 * second, start, 0, Status::Connected);
+        assert_eq!(monitor.deadline(), Some(start + 5 * second));
+        monitor.observe(start + 4 * second, 0, Status::Connected);
+        assert_eq!(monitor.check(start + 4 * second), None);
+        assert_eq!(monitor.check(start + 6 * second), Some(6 * second));
+        assert_eq!(monitor.check(start + 7 * second), None, "already reported");
+        assert_eq!(monitor.deadline(), None);
+
+        monitor.observe(start + 8 * second, 12, Status::Connected);
+        assert_eq!(monitor.deadline(), Some(start + 13 * second));
+        assert_eq!(monitor.check(start + 12 * second), None);
+        assert_eq!(monitor.check(start + 13 * second), Some(5 * second));
+    }
+
+    #[test]
+    fn status_change_is_activity() {
+        let start = Instant::now();
+        let second = Duration::from_secs(1);
+        let mut monitor = LivenessMonitor::new(second, start, 0, Status::Waiting);
+        monitor.observe(start + second / 2, 0, Status::Connected);
+        assert_eq!(monitor.check(start + second), None);
+        assert!(monitor.check(start + 2 * second).is_some());
+    }
+
+    #[test]
+    fn only_reading_is_activity() {
+        let start = Instant::now();
+        let second = Duration::from_secs(1);
+        let mut monitor = LivenessMonitor::new(second, start, 100, Status::Connected);
+        monitor.observe(start + second / 2, 100, Status::Connected);
+        assert_eq!(monitor.deadline(), Some(start + second), "nothing read");
+        monitor.observe(start + second / 2, 116, Status::Connected);
+        assert_eq!(monitor.deadline(), Some(start + second * 3 / 2));
+    }
+}
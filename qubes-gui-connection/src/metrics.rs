@@ -0,0 +1,128 @@
+/*
+ * The Qubes OS Project, https://www.qubes-os.org
+ *
+ * Copyright (C) 2021  Demi Marie Obenour  <demi@invisiblethingslab.com>
+ *
+ * This program is free software; you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License
+ * as published by the Free Software Foundation; either version 2
+ * of the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301, USA.
+ *
+ */
+
+//! Counters describing the traffic on a [`Connection`](crate::Connection),
+//! for monitoring.  Obtained with
+//! [`Connection::metrics`](crate::Connection::metrics).
+
+use std::collections::BTreeMap;
+
+/// Traffic of one message type in one direction
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct Traffic {
+    /// Number of messages
+    pub messages: u64,
+    /// Number of bytes, including headers
+    pub bytes: u64,
+}
+
+impl Traffic {
+    fn add(&mut self, bytes: usize) {
+        self.messages += 1;
+        self.bytes += bytes as u64;
+    }
+}
+
+/// Counters for a connection.  These are never reset, not even when the
+/// connection is re-established.
+#[derive(Debug, Default, Clone)]
+pub struct Metrics {
+    sent: BTreeMap<u32, Traffic>,
+    received: BTreeMap<u32, Traffic>,
+    queue_depth: usize,
+    peak_queue_depth: usize,
+    discarded_unknown: u64,
+    reconnects: u64,
+}
+
+impl Metrics {
+    /// Messages of type `ty` sent with [`Connection::send_agent`],
+    /// [`Connection::send_daemon`] or [`Connection::send_raw`].  Data sent
+    /// with [`Connection::send_raw_bytes`] is not counted, as its type is not
+    /// known.
+    ///
+    /// [`Connection::send_agent`]: crate::Connection::send_agent
+    /// [`Connection::send_daemon`]: crate::Connection::send_daemon
+    /// [`Connection::send_raw`]: crate::Connection::send_raw
+    /// [`Connection::send_raw_bytes`]: crate::Connection::send_raw_bytes
+    pub fn sent(&self, ty: u32) -> Traffic {
+        self.sent.get(&ty).copied().unwrap_or_default()
+    }
+
+    /// Messages of type `ty` received from the peer.  Streamed clipboard
+    /// data is counted once the whole message has arrived.
+    pub fn received(&self, ty: u32) -> Traffic {
+        self.received.get(&ty).copied().unwrap_or_default()
+    }
+
+    /// Iterate over the types of message that have been sent, with their
+    /// traffic, in order of type
+    pub fn sent_by_type(&self) -> impl Iterator<Item = (u32, Traffic)> + '_ {
+        self.sent.iter().map(|(&ty, &traffic)| (ty, traffic))
+    }
+
+    /// Iterate over the types of message that have been received, with
+    /// their traffic, in order of type
+    pub fn received_by_type(&self) -> impl Iterator<Item = (u32, Traffic)> + '_ {
+        self.received.iter().map(|(&ty, &traffic)| (ty, traffic))
+    }
+
+    /// Number of bytes waiting to be written to the vchan
+    pub fn queue_depth(&self) -> usize {
+        self.queue_depth
+    }
+
+    /// The largest [`Metrics::queue_depth`] so far
+    pub fn peak_queue_depth(&self) -> usize {
+        self.peak_queue_depth
+    }
+
+    /// Number of messages of unknown type that were discarded
+    pub fn discarded_unknown(&self) -> u64 {
+        self.discarded_unknown
+    }
+
+    /// Number of times the vchan has been re-established
+    pub fn reconnects(&self) -> u64 {
+        self.reconnects
+    }
+
+    pub(crate) fn record_sent(&mut self, ty: u32, bytes: usize) {
+        self.sent.entry(ty).or_default().add(bytes)
+    }
+
+    pub(crate) fn record_received(&mut self, ty: u32, bytes: usize) {
+        self.received.entry(ty).or_default().add(bytes)
+    }
+
+    pub(crate) fn record_queue_depth(&mut self, depth: usize) {
+        self.queue_depth = depth;
+        self.peak_queue_depth = self.peak_queue_depth.max(depth);
+    }
+
+    pub(crate) fn record_discarded(&mut self) {
+        self.discarded_unknown += 1
+    }
+
+    pub(crate) fn record_reconnect(&mut self) {
+        self.reconnects += 1
+    }
+}
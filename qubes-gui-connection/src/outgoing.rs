@@ -0,0 +1,331 @@
+/*
+ * The Qubes OS Project, https://www.qubes-os.org
+ *
+ * Copyright (C) 2021  Demi Marie Obenour  <demi@invisiblethingslab.com>
+ *
+ * This program is free software; you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License
+ * as published by the Free Software Foundation; either version 2
+ * of the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301, USA.
+ *
+ */
+
+//! Messages of any type, for code that queues them before sending.
+
+use qubes_castable::Castable;
+use qubes_gui::{Header, Msg, ProtocolError, WindowID};
+use std::borrow::Cow;
+use std::fmt;
+
+/// A message of any type that may still be sent.  Unlike
+/// [`qubes_gui::Message`], this covers messages with variable-length bodies,
+/// and messages of different types can be stored together.  The `Debug`
+/// output does not include clipboard data.
+#[derive(Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum OutgoingMessage {
+    /// `MSG_KEYPRESS`
+    Keypress(qubes_gui::Keypress),
+    /// `MSG_BUTTON`
+    Button(qubes_gui::Button),
+    /// `MSG_MOTION`
+    Motion(qubes_gui::Motion),
+    /// `MSG_CROSSING`
+    Crossing(qubes_gui::Crossing),
+    /// `MSG_FOCUS`
+    Focus(qubes_gui::Focus),
+    /// `MSG_CREATE`
+    Create(qubes_gui::Create),
+    /// `MSG_DESTROY`
+    Destroy,
+    /// `MSG_MAP`
+    Map(qubes_gui::MapInfo),
+    /// `MSG_UNMAP`
+    Unmap,
+    /// `MSG_CONFIGURE`
+    Configure(qubes_gui::Configure),
+    /// `MSG_MFNDUMP`.  Deprecated; only for daemons that predate
+    /// `MSG_WINDOW_DUMP`.
+    MfnDump {
+        /// The fixed-size part of the message.  `num_mfn` should be the
+        /// length of `mfns`.
+        cmd: qubes_gui::ShmCmd,
+        /// The machine frame numbers of the pages of the window's buffer
+        mfns: Vec<u32>,
+    },
+    /// `MSG_SHMIMAGE`
+    ShmImage(qubes_gui::ShmImage),
+    /// `MSG_CLOSE`
+    Close,
+    /// `MSG_CLIPBOARD_REQ`
+    ClipboardReq,
+    /// `MSG_CLIPBOARD_DATA`, with the clipboard contents
+    ClipboardData(Vec<u8>),
+    /// `MSG_WMNAME`
+    SetTitle(qubes_gui::WMName),
+    /// `MSG_KEYMAP_NOTIFY`
+    KeymapNotify(qubes_gui::KeymapNotify),
+    /// `MSG_DOCK`
+    Dock,
+    /// `MSG_WINDOW_HINTS`
+    WindowHints(qubes_gui::WindowHints),
+    /// `MSG_WINDOW_FLAGS`
+    WindowFlags(qubes_gui::WindowFlags),
+    /// `MSG_WINDOW_CLASS`
+    WindowClass(qubes_gui::WMClass),
+    /// `MSG_WINDOW_DUMP`
+    WindowDump {
+        /// The fixed-size part of the message
+        header: qubes_gui::WindowDumpHeader,
+        /// Grant references to the window contents
+        grant_refs: Vec<u32>,
+    },
+    /// `MSG_CURSOR`
+    Cursor(qubes_gui::Cursor),
+    /// `MSG_WINDOW_DUMP_ACK`
+    DumpAck,
+}
+
+macro_rules! from_message {
+    ($($variant: ident($t: ty),)+) => {
+        $(impl From<$t> for OutgoingMessage {
+            fn from(message: $t) -> Self {
+                OutgoingMessage::$variant(message)
+            }
+        })+
+
+        impl fmt::Debug for OutgoingMessage {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                match self {
+                    $(OutgoingMessage::$variant(m) => {
+                        f.debug_tuple(stringify!($variant)).field(m).finish()
+                    })+
+                    OutgoingMessage::MfnDump { cmd, mfns } => f
+                        .debug_struct("MfnDump")
+                        .field("cmd", cmd)
+                        .field("mfns", mfns)
+                        .finish(),
+                    OutgoingMessage::ClipboardData(data) => f
+                        .debug_tuple("ClipboardData")
+                        .field(&qubes_gui::Redacted(data))
+                        .finish(),
+                    OutgoingMessage::WindowDump { header, grant_refs } => f
+                        .debug_struct("WindowDump")
+                        .field("header", header)
+                        .field("grant_refs", grant_refs)
+                        .finish(),
+                    OutgoingMessage::Destroy => f.write_str("Destroy"),
+                    OutgoingMessage::Unmap => f.write_str("Unmap"),
+                    OutgoingMessage::Close => f.write_str("Close"),
+                    OutgoingMessage::ClipboardReq => f.write_str("ClipboardReq"),
+                    OutgoingMessage::Dock => f.write_str("Dock"),
+                    OutgoingMessage::DumpAck => f.write_str("DumpAck"),
+                }
+            }
+        }
+    }
+}
+
+from_message! {
+    Keypress(qubes_gui::Keypress),
+    Button(qubes_gui::Button),
+    Motion(qubes_gui::Motion),
+    Crossing(qubes_gui::Crossing),
+    Focus(qubes_gui::Focus),
+    Create(qubes_gui::Create),
+    Map(qubes_gui::MapInfo),
+    Configure(qubes_gui::Configure),
+    ShmImage(qubes_gui::ShmImage),
+    SetTitle(qubes_gui::WMName),
+    KeymapNotify(qubes_gui::KeymapNotify),
+    WindowHints(qubes_gui::WindowHints),
+    WindowFlags(qubes_gui::WindowFlags),
+    WindowClass(qubes_gui::WMClass),
+    Cursor(qubes_gui::Cursor),
+}
+
+/// `prefix` followed by `words`
+fn words(prefix: &[u8], words: &[u32]) -> Vec<u8> {
+    let mut body = prefix.to_vec();
+    for word in words {
+        body.extend_from_slice(&word.to_ne_bytes())
+    }
+    body
+}
+
+impl OutgoingMessage {
+    /// The type of the message
+    pub fn kind(&self) -> Msg {
+        match self {
+            OutgoingMessage::Keypress(_) => Msg::Keypress,
+            OutgoingMessage::Button(_) => Msg::Button,
+            OutgoingMessage::Motion(_) => Msg::Motion,
+            OutgoingMessage::Crossing(_) => Msg::Crossing,
+            OutgoingMessage::Focus(_) => Msg::Focus,
+            OutgoingMessage::Create(_) => Msg::Create,
+            OutgoingMessage::Destroy => Msg::Destroy,
+            OutgoingMessage::Map(_) => Msg::Map,
+            OutgoingMessage::Unmap => Msg::Unmap,
+            OutgoingMessage::Configure(_) => Msg::Configure,
+            OutgoingMessage::MfnDump { .. } => Msg::MfnDump,
+            OutgoingMessage::ShmImage(_) => Msg::ShmImage,
+            OutgoingMessage::Close => Msg::Close,
+            OutgoingMessage::ClipboardReq => Msg::ClipboardReq,
+            OutgoingMessage::ClipboardData(_) => Msg::ClipboardData,
+            OutgoingMessage::SetTitle(_) => Msg::SetTitle,
+            OutgoingMessage::KeymapNotify(_) => Msg::KeymapNotify,
+            OutgoingMessage::Dock => Msg::Dock,
+            OutgoingMessage::WindowHints(_) => Msg::WindowHints,
+            OutgoingMessage::WindowFlags(_) => Msg::WindowFlags,
+            OutgoingMessage::WindowClass(_) => Msg::WindowClass,
+            OutgoingMessage::WindowDump { .. } => Msg::WindowDump,
+            OutgoingMessage::Cursor(_) => Msg::Cursor,
+            OutgoingMessage::DumpAck => Msg::DumpAck,
+        }
+    }
+
+    /// The body of the message, in native byte order
+    pub fn body(&self) -> Cow<'_, [u8]> {
+        Cow::Borrowed(match self {
+            OutgoingMessage::Keypress(m) => m.as_bytes(),
+            OutgoingMessage::Button(m) => m.as_bytes(),
+            OutgoingMessage::Motion(m) => m.as_bytes(),
+            OutgoingMessage::Crossing(m) => m.as_bytes(),
+            OutgoingMessage::Focus(m) => m.as_bytes(),
+            OutgoingMessage::Create(m) => m.as_bytes(),
+            OutgoingMessage::Map(m) => m.as_bytes(),
+            OutgoingMessage::Configure(m) => m.as_bytes(),
+            OutgoingMessage::ShmImage(m) => m.as_bytes(),
+            OutgoingMessage::ClipboardData(data) => data,
+            OutgoingMessage::SetTitle(m) => m.as_bytes(),
+            OutgoingMessage::KeymapNotify(m) => m.as_bytes(),
+            OutgoingMessage::WindowHints(m) => m.as_bytes(),
+            OutgoingMessage::WindowFlags(m) => m.as_bytes(),
+            OutgoingMessage::WindowClass(m) => m.as_bytes(),
+            OutgoingMessage::Cursor(m) => m.as_bytes(),
+            OutgoingMessage::MfnDump { cmd, mfns } => {
+                return Cow::Owned(words(cmd.as_bytes(), mfns))
+            }
+            OutgoingMessage::WindowDump { header, grant_refs } => {
+                return Cow::Owned(words(header.as_bytes(), grant_refs))
+            }
+            OutgoingMessage::Destroy
+            | OutgoingMessage::Unmap
+            | OutgoingMessage::Close
+            | OutgoingMessage::ClipboardReq
+            | OutgoingMessage::Dock
+            | OutgoingMessage::DumpAck => &[],
+        })
+    }
+
+    /// The header for this message, sent to `window`
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`ProtocolError::BadLength`] if the clipboard data or the
+    /// list of grant references is too long.
+    pub fn header(&self, window: WindowID) -> Result<Header, ProtocolError> {
+        let len = match self {
+            OutgoingMessage::ClipboardData(data) => data.len(),
+            OutgoingMessage::MfnDump { mfns, .. } => {
+                std::mem::size_of::<qubes_gui::ShmCmd>() + mfns.len() * std::mem::size_of::<u32>()
+            }
+            OutgoingMessage::WindowDump { grant_refs, .. } => {
+                std::mem::size_of::<qubes_gui::WindowDumpHeader>()
+                    + grant_refs.len() * std::mem::size_of::<u32>()
+            }
+            _ => self.body().len(),
+        };
+        Header::with_len(self.kind(), window, len)
+    }
+
+    /// Encode the message, sent to `window`, header first.  The result is
+    /// exactly what [`Connection::send_message`](crate::Connection::send_message)
+    /// sends.
+    ///
+    /// # Errors
+    ///
+    /// Fails under the same conditions as [`OutgoingMessage::header`].
+    pub fn encode(&self, window: WindowID) -> Result<Vec<u8>, ProtocolError> {
+        let header = self.header(window)?;
+        Ok([header.inner().as_bytes(), &self.body()].concat())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use qubes_gui::UntrustedHeader;
+    use std::mem::size_of;
+
+    #[test]
+    fn debug_redacts_clipboard() {
+        let message = OutgoingMessage::ClipboardData(b"secret".to_vec());
+        assert_eq!(
+            format!("{:?}", message),
+            "ClipboardData(<6 bytes redacted>)"
+        );
+        assert_eq!(format!("{:?}", OutgoingMessage::Dock), "Dock");
+    }
+
+    #[test]
+    fn fixed_size() {
+        let cursor = qubes_gui::Cursor::new(Some(qubes_gui::CursorShape::Watch));
+        let message = OutgoingMessage::from(cursor);
+        assert_eq!(message.kind(), Msg::Cursor);
+        let bytes = message.encode(3.into()).unwrap();
+        let header = Header::for_message::<qubes_gui::Cursor>(3.into());
+        assert_eq!(
+            bytes,
+            [header.inner().as_bytes(), cursor.as_bytes()].concat()
+        );
+        let empty = OutgoingMessage::Close.encode(3.into()).unwrap();
+        assert_eq!(empty.len(), size_of::<UntrustedHeader>());
+    }
+
+    #[test]
+    fn variable_length() {
+        let dump = OutgoingMessage::WindowDump {
+            header: qubes_gui::WindowDumpHeader {
+                ty: qubes_gui::WINDOW_DUMP_TYPE_GRANT_REFS,
+                width: 4,
+                height: 4,
+                bpp: 24,
+            },
+            grant_refs: vec![7, 8],
+        };
+        let bytes = dump.encode(1.into()).unwrap();
+        let header = UntrustedHeader::from_bytes(&bytes[..size_of::<UntrustedHeader>()]);
+        assert_eq!(header.ty, qubes_gui::MSG_WINDOW_DUMP);
+        assert_eq!(header.untrusted_len, 24);
+        assert_eq!(bytes[bytes.len() - 4..], 8u32.to_ne_bytes());
+
+        let mfns = OutgoingMessage::MfnDump {
+            cmd: Default::default(),
+            mfns: vec![1, 2, 3],
+        };
+        let bytes = mfns.encode(1.into()).unwrap();
+        let len = size_of::<qubes_gui::ShmCmd>() + 12;
+        assert_eq!(bytes.len(), size_of::<UntrustedHeader>() + len);
+        assert_eq!(bytes[bytes.len() - 4..], 3u32.to_ne_bytes());
+        assert_eq!(mfns.header(1.into()).unwrap().ty(), qubes_gui::MSG_MFNDUMP);
+
+        let too_long = OutgoingMessage::ClipboardData(vec![0; 65001]);
+        assert_eq!(
+            too_long.encode(0.into()),
+            Err(ProtocolError::BadLength {
+                ty: qubes_gui::MSG_CLIPBOARD_DATA,
+                untrusted_len: 65001
+            })
+        );
+    }
+}
@@ -0,0 +1,96 @@
+/*
+ * The Qubes OS Project, https://www.qubes-os.org
+ *
+ * Copyright (C) 2021  Demi Marie Obenour  <demi@invisiblethingslab.com>
+ *
+ * This program is free software; you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License
+ * as published by the Free Software Foundation; either version 2
+ * of the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301, USA.
+ *
+ */
+
+//! Recycling of message body buffers.
+//!
+//! [`Buffer::take`](crate::Buffer::take) hands the read buffer to the caller,
+//! so the next message would otherwise start from an empty `Vec`.  Callers
+//! that are done with a taken body can give it back with
+//! [`Connection::recycle`](crate::Connection::recycle), and it will be reused
+//! for a later message.
+
+/// Maximum number of idle buffers kept
+const MAX_BUFFERS: usize = 4;
+
+/// Idle buffers larger than this are freed instead of kept
+const MAX_CAPACITY: usize = 1 << 20;
+
+/// A small pool of idle buffers
+#[derive(Debug, Default)]
+pub(crate) struct BufferPool {
+    free: Vec<Vec<u8>>,
+}
+
+impl BufferPool {
+    /// Make `buffer` empty with room for at least `len` bytes, reusing a
+    /// pooled allocation if `buffer` has none of its own.
+    pub(crate) fn prepare(&mut self, buffer: &mut Vec<u8>, len: usize) {
+        if buffer.capacity() == 0 {
+            if let Some(pooled) = self.free.pop() {
+                *buffer = pooled
+            }
+        }
+        buffer.clear();
+        buffer.reserve(len)
+    }
+
+    /// Return `buffer` to the pool, unless the pool is full or `buffer` is
+    /// too large to be worth keeping.
+    pub(crate) fn recycle(&mut self, mut buffer: Vec<u8>) {
+        let capacity = buffer.capacity();
+        if capacity != 0 && capacity <= MAX_CAPACITY && self.free.len() < MAX_BUFFERS {
+            buffer.clear();
+            self.free.push(buffer)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reuses_recycled_buffers() {
+        let mut pool = BufferPool::default();
+        let mut recycled = Vec::with_capacity(100);
+        recycled.extend_from_slice(b"stale");
+        let ptr = recycled.as_ptr();
+        pool.recycle(recycled);
+        let mut buffer = vec![];
+        pool.prepare(&mut buffer, 50);
+        assert_eq!(buffer.as_ptr(), ptr, "allocation reused");
+        assert!(buffer.is_empty(), "old contents cleared");
+        pool.prepare(&mut buffer, 200);
+        assert!(buffer.capacity() >= 200);
+    }
+
+    #[test]
+    fn bounded() {
+        let mut pool = BufferPool::default();
+        for _ in 0..2 * MAX_BUFFERS {
+            pool.recycle(Vec::with_capacity(8))
+        }
+        pool.recycle(Vec::with_capacity(MAX_CAPACITY + 1));
+        pool.recycle(vec![]);
+        assert_eq!(pool.free.len(), MAX_BUFFERS);
+        assert!(pool.free.iter().all(|b| b.capacity() < MAX_CAPACITY));
+    }
+}
@@ -0,0 +1,187 @@
+/*
+ * The Qubes OS Project, https://www.qubes-os.org
+ *
+ * Copyright (C) 2021  Demi Marie Obenour  <demi@invisiblethingslab.com>
+ *
+ * This program is free software; you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License
+ * as published by the Free Software Foundation; either version 2
+ * of the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301, USA.
+ *
+ */
+
+//! A man-in-the-middle proxy for debugging.
+//!
+//! A [`Proxy`] sits between a real agent and a real daemon and forwards
+//! everything unchanged, decoding and checking each message on the way with
+//! a [`Reassembler`].  Protocol violations are reported but never fixed up:
+//! the proxy is meant to show exactly what the peers do.
+
+use crate::decode::{Item, Peer, Reassembler};
+use crate::Transport;
+use std::io;
+use std::os::raw::c_int;
+use vchan::Status;
+
+/// The largest chunk forwarded at once
+const CHUNK_SIZE: usize = 1 << 16;
+
+/// Forwards traffic between an agent and a daemon
+#[derive(Debug)]
+pub struct Proxy<A: Transport, D: Transport> {
+    /// Connected to the agent
+    agent: A,
+    /// Connected to the daemon
+    daemon: D,
+    from_agent: Reassembler,
+    from_daemon: Reassembler,
+    buffer: Vec<u8>,
+}
+
+/// Move as much data from `src` to `dst` as possible without blocking
+fn forward(
+    src: &mut impl Transport,
+    dst: &mut impl Transport,
+    reassembler: &mut Reassembler,
+    buffer: &mut Vec<u8>,
+    log: &mut impl FnMut(Peer, Item<'_>),
+) -> io::Result<()> {
+    loop {
+        let len = src.data_ready().min(dst.buffer_space()).min(CHUNK_SIZE);
+        if len == 0 {
+            return Ok(());
+        }
+        buffer.resize(len, 0);
+        src.recv(buffer)?;
+        dst.send(buffer)?;
+        let peer = reassembler.peer();
+        reassembler.feed(buffer, |item| log(peer, item))
+    }
+}
+
+impl<A: Transport, D: Transport> Proxy<A, D> {
+    /// Forward between `agent`, which is connected to the agent, and
+    /// `daemon`, which is connected to the daemon.  Both must be connected
+    /// from the start, so that the version handshake is seen.
+    pub fn new(agent: A, daemon: D) -> Self {
+        Self {
+            agent,
+            daemon,
+            from_agent: Reassembler::new(Peer::Agent),
+            from_daemon: Reassembler::new(Peer::Daemon),
+            buffer: vec![],
+        }
+    }
+
+    /// Forward everything that can be forwarded without blocking, calling
+    /// `log` on each item seen.
+    pub fn pump(&mut self, mut log: impl FnMut(Peer, Item<'_>)) -> io::Result<()> {
+        forward(
+            &mut self.agent,
+            &mut self.daemon,
+            &mut self.from_agent,
+            &mut self.buffer,
+            &mut log,
+        )?;
+        forward(
+            &mut self.daemon,
+            &mut self.agent,
+            &mut self.from_daemon,
+            &mut self.buffer,
+            &mut log,
+        )
+    }
+
+    /// Acknowledge events on both file descriptors.  Call this when either
+    /// of [`Proxy::fds`] is readable (or writable, if
+    /// [`Proxy::wants_writable`] says so), then call [`Proxy::pump`].
+    pub fn wait(&mut self) {
+        self.agent.wait();
+        self.daemon.wait()
+    }
+
+    /// The file descriptors of the agent and daemon transports
+    pub fn fds(&self) -> [c_int; 2] {
+        [self.agent.fd(), self.daemon.fd()]
+    }
+
+    /// Whether each of [`Proxy::fds`] must also be waited on until it is
+    /// writable (see [`Transport::wants_writable`])
+    pub fn wants_writable(&self) -> [bool; 2] {
+        [self.agent.wants_writable(), self.daemon.wants_writable()]
+    }
+
+    /// Which peer, if any, has disconnected.  Once this returns
+    /// [`Some`], nothing more will be forwarded.
+    pub fn disconnected(&self) -> Option<Peer> {
+        if self.agent.status() == Status::Disconnected {
+            Some(Peer::Agent)
+        } else if self.daemon.status() == Status::Disconnected {
+            Some(Peer::Daemon)
+        } else {
+            None
+        }
+    }
+
+    /// The transports connected to the agent and the daemon
+    pub fn into_inner(self) -> (A, D) {
+        (self.agent, self.daemon)
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::SocketTransport;
+    use qubes_castable::Castable;
+    use std::os::unix::net::UnixStream;
+
+    #[test]
+    fn forwards_and_decodes() {
+        let (agent, agent_side) = UnixStream::pair().unwrap();
+        let (daemon, daemon_side) = UnixStream::pair().unwrap();
+        let mut agent = SocketTransport::new(agent).unwrap();
+        let mut daemon = SocketTransport::new(daemon).unwrap();
+        let mut proxy = Proxy::new(
+            SocketTransport::new(agent_side).unwrap(),
+            SocketTransport::new(daemon_side).unwrap(),
+        );
+
+        agent.send(qubes_gui::PROTOCOL_VERSION.as_bytes()).unwrap();
+        let header = qubes_gui::UntrustedHeader {
+            ty: qubes_gui::MSG_KEYPRESS,
+            window: 1.into(),
+            untrusted_len: std::mem::size_of::<qubes_gui::Keypress>() as u32,
+        };
+        agent.send(header.as_bytes()).unwrap();
+        agent
+            .send(qubes_gui::Keypress::default().as_bytes())
+            .unwrap();
+
+        let mut log = vec![];
+        proxy.wait();
+        proxy
+            .pump(|peer, item| log.push(format!("{} {}", peer.name(), item.describe(false))))
+            .unwrap();
+        assert_eq!(log.len(), 3, "{:?}", log);
+        assert_eq!(log[0], "agent VERSION 1.7");
+        assert!(log[1].contains("WrongDirection"));
+        assert!(log[2].starts_with("agent KEYPRESS window 1"));
+
+        daemon.wait();
+        assert_eq!(daemon.data_ready(), 4 + 12 + header.untrusted_len as usize);
+        assert_eq!(proxy.disconnected(), None);
+        drop(agent);
+        proxy.wait();
+        assert_eq!(proxy.disconnected(), Some(Peer::Agent));
+    }
+}
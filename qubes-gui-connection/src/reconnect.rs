@@ -0,0 +1,229 @@
+/*
+ * The Qubes OS Project, https://www.qubes-os.org
+ *
+ * Copyright (C) 2021  Demi Marie Obenour  <demi@invisiblethingslab.com>
+ *
+ * This program is free software; you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License
+ * as published by the Free Software Foundation; either version 2
+ * of the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301, USA.
+ *
+ */
+
+//! Automatic reconnection with exponential backoff.
+//!
+//! Without a [`ReconnectPolicy`], callers must check
+//! [`Connection::needs_reconnect`](crate::Connection::needs_reconnect), call
+//! [`Connection::reconnect`](crate::Connection::reconnect), and later poll
+//! [`Connection::reconnected`](crate::Connection::reconnected) themselves.
+//! With a policy installed, [`Connection::read_event`](crate::Connection::read_event)
+//! does all of this and reports [`Event::Reconnected`](crate::Event::Reconnected)
+//! once the new connection has been negotiated.
+
+use std::time::{Duration, Instant};
+
+/// Callback invoked after a successful (re)connection
+pub type ReconnectCallback = Box<dyn FnMut(&qubes_gui::XConfVersion)>;
+
+/// How a [`Connection`](crate::Connection) should reconnect after the peer
+/// goes away.
+pub struct ReconnectPolicy {
+    max_retries: Option<u32>,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+    on_reconnect: Option<ReconnectCallback>,
+}
+
+impl std::fmt::Debug for ReconnectPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ReconnectPolicy")
+            .field("max_retries", &self.max_retries)
+            .field("initial_backoff", &self.initial_backoff)
+            .field("max_backoff", &self.max_backoff)
+            .field("on_reconnect", &self.on_reconnect.is_some())
+            .finish()
+    }
+}
+
+impl Default for ReconnectPolicy {
+    /// Retry forever, starting at 100ms between attempts and backing off to at
+    /// most 10s.
+    fn default() -> Self {
+        Self {
+            max_retries: None,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(10),
+            on_reconnect: None,
+        }
+    }
+}
+
+impl ReconnectPolicy {
+    /// Creates the default policy.  See [`ReconnectPolicy::default`].
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Give up once the first attempt and `retries` further attempts have all
+    /// failed.  `None` means retry forever.
+    pub fn max_retries(mut self, retries: Option<u32>) -> Self {
+        self.max_retries = retries;
+        self
+    }
+
+    /// Set the delay before the first retry.  Each further failure doubles the
+    /// delay, up to the maximum set by [`ReconnectPolicy::max_backoff`].
+    pub fn initial_backoff(mut self, backoff: Duration) -> Self {
+        self.initial_backoff = backoff;
+        self
+    }
+
+    /// Set the upper bound on the delay between attempts.
+    pub fn max_backoff(mut self, backoff: Duration) -> Self {
+        self.max_backoff = backoff;
+        self
+    }
+
+    /// Set a callback that is invoked with the new configuration each time
+    /// version negotiation completes.  Like [`Event::Reconnected`](crate::Event::Reconnected),
+    /// this includes the initial connection.
+    pub fn on_reconnect(
+        mut self,
+        callback: impl FnMut(&qubes_gui::XConfVersion) + 'static,
+    ) -> Self {
+        self.on_reconnect = Some(Box::new(callback));
+        self
+    }
+
+    /// The delay before retry number `attempt` (counting from zero).
+    pub(crate) fn backoff(&self, attempt: u32) -> Duration {
+        self.initial_backoff
+            .checked_mul(1u32.checked_shl(attempt).unwrap_or(u32::MAX))
+            .unwrap_or(self.max_backoff)
+            .min(self.max_backoff)
+    }
+}
+
+/// What the connection should do about a disconnected peer right now
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Action {
+    /// Try to reconnect now
+    Attempt,
+    /// Wait until the given time
+    Wait(Instant),
+    /// Too many failures; give up
+    GiveUp(u32),
+}
+
+/// Driver for a [`ReconnectPolicy`]
+#[derive(Debug)]
+pub(crate) struct ReconnectManager {
+    policy: ReconnectPolicy,
+    /// Consecutive failed attempts
+    failures: u32,
+    /// Earliest time of the next attempt
+    next_attempt: Option<Instant>,
+    /// Number of successful reconnections
+    pub(crate) reconnects: u64,
+}
+
+impl ReconnectManager {
+    pub(crate) fn new(policy: ReconnectPolicy) -> Self {
+        Self {
+            policy,
+            failures: 0,
+            next_attempt: None,
+            reconnects: 0,
+        }
+    }
+
+    /// Decide what to do about a disconnected peer at time `now`.
+    pub(crate) fn poll(&self, now: Instant) -> Action {
+        match (self.policy.max_retries, self.next_attempt) {
+            (Some(max), _) if self.failures > max => Action::GiveUp(self.failures),
+            (_, Some(deadline)) if now < deadline => Action::Wait(deadline),
+            _ => Action::Attempt,
+        }
+    }
+
+    /// Record the result of a reconnection attempt made at time `now`.
+    pub(crate) fn attempted(&mut self, now: Instant, success: bool) {
+        if success {
+            self.next_attempt = None;
+            self.reconnects += 1;
+        } else {
+            self.next_attempt = Some(now + self.policy.backoff(self.failures));
+            self.failures += 1;
+        }
+    }
+
+    /// Record that version negotiation succeeded, and run the callback.
+    pub(crate) fn negotiated(&mut self, xconf: &qubes_gui::XConfVersion) {
+        self.failures = 0;
+        self.next_attempt = None;
+        if let Some(cb) = self.policy.on_reconnect.as_mut() {
+            cb(xconf)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_doubles_and_saturates() {
+        let policy = ReconnectPolicy::new()
+            .initial_backoff(Duration::from_millis(100))
+            .max_backoff(Duration::from_secs(1));
+        assert_eq!(policy.backoff(0), Duration::from_millis(100));
+        assert_eq!(policy.backoff(1), Duration::from_millis(200));
+        assert_eq!(policy.backoff(3), Duration::from_millis(800));
+        assert_eq!(policy.backoff(4), Duration::from_secs(1));
+        assert_eq!(policy.backoff(200), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn manager_gives_up() {
+        let mut manager = ReconnectManager::new(
+            ReconnectPolicy::new()
+                .max_retries(Some(1))
+                .initial_backoff(Duration::from_secs(1)),
+        );
+        let start = Instant::now();
+        assert_eq!(manager.poll(start), Action::Attempt);
+        manager.attempted(start, false);
+        let deadline = start + Duration::from_secs(1);
+        assert_eq!(manager.poll(start), Action::Wait(deadline));
+        assert_eq!(manager.poll(deadline), Action::Attempt);
+        manager.attempted(deadline, false);
+        assert_eq!(manager.poll(deadline), Action::GiveUp(2));
+    }
+
+    #[test]
+    fn negotiation_resets_failures() {
+        use std::{cell::Cell, rc::Rc};
+        let called = Rc::new(Cell::new(0));
+        let c = called.clone();
+        let mut manager = ReconnectManager::new(
+            ReconnectPolicy::new()
+                .max_retries(Some(0))
+                .on_reconnect(move |_| c.set(c.get() + 1)),
+        );
+        let now = Instant::now();
+        manager.attempted(now, true);
+        manager.negotiated(&Default::default());
+        assert_eq!(manager.poll(now), Action::Attempt);
+        assert_eq!(manager.reconnects, 1);
+        assert_eq!(called.get(), 1);
+    }
+}
@@ -0,0 +1,212 @@
+/*
+ * The Qubes OS Project, https://www.qubes-os.org
+ *
+ * Copyright (C) 2021  Demi Marie Obenour  <demi@invisiblethingslab.com>
+ *
+ * This program is free software; you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License
+ * as published by the Free Software Foundation; either version 2
+ * of the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301, USA.
+ *
+ */
+
+//! Recording window state so it can be replayed after the daemon restarts.
+//!
+//! When the GUI daemon restarts, it knows nothing about the agent’s windows,
+//! so the agent must recreate all of them.  [`SessionState`] records the
+//! messages needed to do that, and [`SessionState::replay`] sends them again.
+
+use crate::Connection;
+use qubes_castable::Castable;
+use qubes_gui::{Message, Msg, WindowID};
+use std::collections::BTreeMap;
+use std::io;
+use std::num::NonZeroU32;
+
+/// The recorded state of one window
+#[derive(Debug)]
+struct RecordedWindow {
+    create: qubes_gui::Create,
+    configure: Option<qubes_gui::Configure>,
+    map: Option<qubes_gui::MapInfo>,
+    title: Option<qubes_gui::WMName>,
+    class: Option<qubes_gui::WMClass>,
+    cursor: Option<qubes_gui::Cursor>,
+    /// Has the window been docked?
+    docked: bool,
+}
+
+/// Agent-side recorder of the messages needed to recreate all windows.
+///
+/// Only the most recent message of each kind is kept for each window.
+/// Destroying a window forgets it.
+#[derive(Debug, Default)]
+pub struct SessionState {
+    windows: BTreeMap<NonZeroU32, RecordedWindow>,
+}
+
+impl SessionState {
+    /// Create an empty recorder
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record a message that the agent has sent to `window`.  Messages that
+    /// do not affect window state, and messages for windows that were never
+    /// created, are ignored.
+    pub fn record<T: Message>(&mut self, window: WindowID, message: &T) {
+        let window = match window.window {
+            Some(window) => window,
+            None => return,
+        };
+        let bytes = message.as_bytes();
+        if let Msg::Create = T::KIND {
+            self.windows.insert(
+                window,
+                RecordedWindow {
+                    create: Castable::from_bytes(bytes),
+                    configure: None,
+                    map: None,
+                    title: None,
+                    class: None,
+                    cursor: None,
+                    docked: false,
+                },
+            );
+            return;
+        }
+        let state = match self.windows.get_mut(&window) {
+            Some(state) => state,
+            None => return,
+        };
+        match T::KIND {
+            Msg::Configure => state.configure = Some(Castable::from_bytes(bytes)),
+            Msg::Map => state.map = Some(Castable::from_bytes(bytes)),
+            Msg::Unmap => state.map = None,
+            Msg::SetTitle => state.title = Some(Castable::from_bytes(bytes)),
+            Msg::WindowClass => state.class = Some(Castable::from_bytes(bytes)),
+            Msg::Cursor => state.cursor = Some(Castable::from_bytes(bytes)),
+            Msg::Dock => state.docked = true,
+            Msg::Destroy => {
+                self.windows.remove(&window);
+            }
+            _ => {}
+        }
+    }
+
+    /// Forget everything
+    pub fn clear(&mut self) {
+        self.windows.clear()
+    }
+
+    /// Is nothing recorded?
+    pub fn is_empty(&self) -> bool {
+        self.windows.is_empty()
+    }
+
+    /// The order in which windows must be recreated: every parent before its
+    /// children.  Windows whose parent is not recorded are created first.
+    fn creation_order(&self) -> Vec<NonZeroU32> {
+        let mut order = Vec::with_capacity(self.windows.len());
+        let mut remaining: Vec<_> = self.windows.keys().copied().collect();
+        while !remaining.is_empty() {
+            let before = remaining.len();
+            remaining.retain(|id| match self.windows[id].create.parent {
+                Some(parent) if self.windows.contains_key(&parent) && !order.contains(&parent) => {
+                    true
+                }
+                _ => {
+                    order.push(*id);
+                    false
+                }
+            });
+            if remaining.len() == before {
+                // Parent cycle.  The daemon will reject this anyway.
+                order.append(&mut remaining)
+            }
+        }
+        order
+    }
+
+    /// Send all recorded messages on `connection`.  Windows are created
+    /// parents-first, and are only mapped once every window exists, so that
+    /// `transient_for` always refers to an existing window.
+    pub fn replay(&self, connection: &mut Connection) -> io::Result<()> {
+        let order = self.creation_order();
+        for &id in &order {
+            let (state, window) = (&self.windows[&id], WindowID::from(id));
+            connection.send_agent(&state.create, window)?;
+            if let Some(configure) = &state.configure {
+                connection.send_agent(configure, window)?
+            }
+            if let Some(title) = &state.title {
+                connection.send_agent(title, window)?
+            }
+            if let Some(class) = &state.class {
+                connection.send_agent(class, window)?
+            }
+            if let Some(cursor) = &state.cursor {
+                connection.send_agent(cursor, window)?
+            }
+            if state.docked {
+                connection.send_agent(&qubes_gui::Dock {}, window)?
+            }
+        }
+        for &id in &order {
+            if let Some(map) = &self.windows[&id].map {
+                connection.send_agent(map, id.into())?
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn id(n: u32) -> NonZeroU32 {
+        NonZeroU32::new(n).unwrap()
+    }
+
+    #[test]
+    fn records_latest_state() {
+        let mut state = SessionState::new();
+        state.record(id(1).into(), &qubes_gui::Configure::default());
+        assert!(state.is_empty(), "configure before create is ignored");
+        state.record(id(1).into(), &qubes_gui::Create::default());
+        state.record(id(1).into(), &qubes_gui::MapInfo::default());
+        assert!(state.windows[&id(1)].map.is_some());
+        state.record(id(1).into(), &qubes_gui::Dock {});
+        assert!(state.windows[&id(1)].docked);
+        state.record(id(1).into(), &qubes_gui::Unmap {});
+        assert!(state.windows[&id(1)].map.is_none());
+        state.record(id(1).into(), &qubes_gui::Destroy {});
+        assert!(state.is_empty());
+    }
+
+    #[test]
+    fn parents_first() {
+        let mut state = SessionState::new();
+        for (window, parent) in [(1, Some(3)), (2, None), (3, Some(4)), (4, None)] {
+            let create = qubes_gui::Create {
+                parent: parent.map(id),
+                ..Default::default()
+            };
+            state.record(id(window).into(), &create);
+        }
+        let order = state.creation_order();
+        let pos = |n| order.iter().position(|&w| w == id(n)).unwrap();
+        assert_eq!(order.len(), 4);
+        assert!(pos(4) < pos(3) && pos(3) < pos(1));
+    }
+}
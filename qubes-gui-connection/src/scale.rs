@@ -0,0 +1,416 @@
+/*
+ * The Qubes OS Project, https://www.qubes-os.org
+ *
+ * Copyright (C) 2021  Demi Marie Obenour  <demi@invisiblethingslab.com>
+ *
+ * This program is free software; you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License
+ * as published by the Free Software Foundation; either version 2
+ * of the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301, USA.
+ *
+ */
+
+//! Drawing at a scale factor, for HiDPI displays.
+//!
+//! The protocol only knows about physical pixels, and the daemon knows
+//! nothing of scale factors.  An agent that lays out its windows in logical
+//! units picks a [`Scale`], and converts with it in both directions: sizes,
+//! positions, and damage going to the daemon are scaled up, and coordinates
+//! coming from it are scaled down.  [`Scaled`] does the latter for a
+//! [`MessageHandler`].
+//!
+//! With a fractional factor, converting a logical rectangle back to physical
+//! pixels may not give the rectangle the daemon sent.  `MSG_CONFIGURE` must
+//! be acknowledged with the exact rectangle, which [`Scaled::physical`]
+//! keeps.
+
+use crate::{Extension, MessageHandler};
+use qubes_gui::{Coordinates, Header, Rectangle, WindowID, WindowSize, XConf, XConfVersion};
+use std::collections::BTreeMap;
+use std::num::NonZeroU32;
+use std::time::Duration;
+
+/// The number of physical pixels per logical unit, in each direction
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Scale(f64);
+
+impl Default for Scale {
+    fn default() -> Self {
+        Self::IDENTITY
+    }
+}
+
+/// Convert to `i32`, saturating
+fn to_i32(value: f64) -> i32 {
+    value as i32
+}
+
+impl Scale {
+    /// No scaling
+    pub const IDENTITY: Scale = Scale(1.0);
+
+    /// A scale of `factor` physical pixels per logical unit, or `None` if
+    /// `factor` is not finite and positive
+    pub fn new(factor: f64) -> Option<Self> {
+        if factor.is_finite() && factor > 0.0 {
+            Some(Self(factor))
+        } else {
+            None
+        }
+    }
+
+    /// The scale factor
+    pub fn factor(self) -> f64 {
+        self.0
+    }
+
+    fn size(size: WindowSize, f: impl Fn(f64) -> f64) -> WindowSize {
+        // Rounding never makes a nonempty dimension empty
+        let dimension = |d: u32| match d {
+            0 => 0,
+            d => f(f64::from(d)).round().max(1.0) as u32,
+        };
+        WindowSize {
+            width: dimension(size.width),
+            height: dimension(size.height),
+        }
+    }
+
+    /// The physical size of something of logical size `size`, such as the
+    /// buffer of a window
+    pub fn to_physical_size(self, size: WindowSize) -> WindowSize {
+        Self::size(size, |d| d * self.0)
+    }
+
+    /// The logical size of something of physical size `size`
+    pub fn to_logical_size(self, size: WindowSize) -> WindowSize {
+        Self::size(size, |d| d / self.0)
+    }
+
+    /// The physical position of logical position `point`
+    pub fn to_physical_point(self, point: Coordinates) -> Coordinates {
+        Coordinates {
+            x: to_i32((f64::from(point.x) * self.0).round()),
+            y: to_i32((f64::from(point.y) * self.0).round()),
+        }
+    }
+
+    /// The logical unit that contains physical pixel `point`
+    pub fn to_logical_point(self, point: Coordinates) -> Coordinates {
+        Coordinates {
+            x: to_i32((f64::from(point.x) / self.0).floor()),
+            y: to_i32((f64::from(point.y) / self.0).floor()),
+        }
+    }
+
+    /// The exact logical position of physical pixel `point`, for toolkits
+    /// that take fractional positions
+    pub fn to_logical_point_f64(self, point: Coordinates) -> (f64, f64) {
+        (f64::from(point.x) / self.0, f64::from(point.y) / self.0)
+    }
+
+    /// The physical position and size of a window with logical position and
+    /// size `rectangle`
+    pub fn to_physical_rect(self, rectangle: Rectangle) -> Rectangle {
+        Rectangle {
+            top_left: self.to_physical_point(rectangle.top_left),
+            size: self.to_physical_size(rectangle.size),
+        }
+    }
+
+    /// The logical position and size of a window with physical position and
+    /// size `rectangle`
+    pub fn to_logical_rect(self, rectangle: Rectangle) -> Rectangle {
+        let top_left = rectangle.top_left;
+        Rectangle {
+            top_left: Coordinates {
+                x: to_i32((f64::from(top_left.x) / self.0).round()),
+                y: to_i32((f64::from(top_left.y) / self.0).round()),
+            },
+            size: self.to_logical_size(rectangle.size),
+        }
+    }
+
+    /// The physical pixels touched by logical rectangle `damage`.  Unlike
+    /// [`Scale::to_physical_rect`], this rounds outwards, so that partly
+    /// covered pixels are included.
+    pub fn damage(self, damage: Rectangle) -> Rectangle {
+        let left = f64::from(damage.top_left.x) * self.0;
+        let top = f64::from(damage.top_left.y) * self.0;
+        let right = (f64::from(damage.top_left.x) + f64::from(damage.size.width)) * self.0;
+        let bottom = (f64::from(damage.top_left.y) + f64::from(damage.size.height)) * self.0;
+        let (left, top) = (left.floor(), top.floor());
+        Rectangle {
+            top_left: Coordinates {
+                x: to_i32(left),
+                y: to_i32(top),
+            },
+            size: WindowSize {
+                width: (right.ceil() - left) as u32,
+                height: (bottom.ceil() - top) as u32,
+            },
+        }
+    }
+}
+
+/// A [`MessageHandler`] that converts the coordinates in messages from the
+/// daemon to logical units before passing them on to another handler.
+/// Pointer positions are rounded down, to the logical unit that contains
+/// them.
+#[derive(Debug)]
+pub struct Scaled<H> {
+    scale: Scale,
+    inner: H,
+    /// The last rectangle the daemon sent for each window, in physical pixels
+    physical: BTreeMap<NonZeroU32, Rectangle>,
+}
+
+impl<H: MessageHandler> Scaled<H> {
+    /// Pass messages, scaled by `scale`, to `inner`
+    pub fn new(scale: Scale, inner: H) -> Self {
+        Self {
+            scale,
+            inner,
+            physical: BTreeMap::new(),
+        }
+    }
+
+    /// The scale in use
+    pub fn scale(&self) -> Scale {
+        self.scale
+    }
+
+    /// Use `scale` from now on
+    pub fn set_scale(&mut self, scale: Scale) {
+        self.scale = scale
+    }
+
+    /// The last rectangle the daemon sent for `window` with `MSG_CONFIGURE`,
+    /// in physical pixels.  Acknowledge it with this, rather than with the
+    /// logical rectangle scaled back up.
+    pub fn physical(&self, window: NonZeroU32) -> Option<Rectangle> {
+        self.physical.get(&window).copied()
+    }
+
+    /// The handler messages are passed to
+    pub fn inner(&self) -> &H {
+        &self.inner
+    }
+
+    /// The handler messages are passed to
+    pub fn inner_mut(&mut self) -> &mut H {
+        &mut self.inner
+    }
+
+    /// Stop scaling, and return the handler
+    pub fn into_inner(self) -> H {
+        self.inner
+    }
+}
+
+impl<H: MessageHandler> MessageHandler for Scaled<H> {
+    fn on_keypress(&mut self, window: WindowID, keypress: &qubes_gui::Keypress) {
+        let keypress = qubes_gui::Keypress {
+            coordinates: self.scale.to_logical_point(keypress.coordinates),
+            ..*keypress
+        };
+        self.inner.on_keypress(window, &keypress)
+    }
+
+    fn on_button(&mut self, window: WindowID, button: &qubes_gui::Button) {
+        let button = qubes_gui::Button {
+            coordinates: self.scale.to_logical_point(button.coordinates),
+            ..*button
+        };
+        self.inner.on_button(window, &button)
+    }
+
+    fn on_motion(&mut self, window: WindowID, motion: &qubes_gui::Motion) {
+        let motion = qubes_gui::Motion {
+            coordinates: self.scale.to_logical_point(motion.coordinates),
+            ..*motion
+        };
+        self.inner.on_motion(window, &motion)
+    }
+
+    fn on_crossing(&mut self, window: WindowID, crossing: &qubes_gui::Crossing) {
+        let crossing = qubes_gui::Crossing {
+            coordinates: self.scale.to_logical_point(crossing.coordinates),
+            ..*crossing
+        };
+        self.inner.on_crossing(window, &crossing)
+    }
+
+    fn on_focus(&mut self, window: WindowID, focus: &qubes_gui::Focus) {
+        self.inner.on_focus(window, focus)
+    }
+
+    fn on_map(&mut self, window: WindowID, info: &qubes_gui::MapInfo) {
+        self.inner.on_map(window, info)
+    }
+
+    fn on_configure(&mut self, window: WindowID, configure: &qubes_gui::Configure) {
+        if let Some(window) = window.window {
+            self.physical.insert(window, configure.rectangle);
+        }
+        let configure = qubes_gui::Configure {
+            rectangle: self.scale.to_logical_rect(configure.rectangle),
+            ..*configure
+        };
+        self.inner.on_configure(window, &configure)
+    }
+
+    fn on_close(&mut self, window: WindowID) {
+        self.inner.on_close(window)
+    }
+
+    fn on_destroy(&mut self, window: WindowID) {
+        if let Some(window) = window.window {
+            self.physical.remove(&window);
+        }
+        self.inner.on_destroy(window)
+    }
+
+    fn on_clipboard_req(&mut self, window: WindowID) {
+        self.inner.on_clipboard_req(window)
+    }
+
+    fn on_clipboard_data(&mut self, window: WindowID, untrusted_data: &[u8]) {
+        self.inner.on_clipboard_data(window, untrusted_data)
+    }
+
+    fn on_clipboard_chunk(&mut self, window: WindowID, untrusted_data: &[u8]) {
+        self.inner.on_clipboard_chunk(window, untrusted_data)
+    }
+
+    fn on_clipboard_end(&mut self, window: WindowID, len: usize) {
+        self.inner.on_clipboard_end(window, len)
+    }
+
+    fn on_keymap(&mut self, window: WindowID, keymap: &qubes_gui::KeymapNotify) {
+        self.inner.on_keymap(window, keymap)
+    }
+
+    fn on_window_flags(&mut self, window: WindowID, flags: &qubes_gui::WindowFlags) {
+        self.inner.on_window_flags(window, flags)
+    }
+
+    fn on_dump_ack(&mut self, window: WindowID) {
+        self.inner.on_dump_ack(window)
+    }
+
+    fn on_reconnected(&mut self, xconf: &XConfVersion) {
+        self.physical.clear();
+        self.inner.on_reconnected(xconf)
+    }
+
+    fn on_downgraded(&mut self, ext: Extension) {
+        self.inner.on_downgraded(ext)
+    }
+
+    fn on_screen_changed(&mut self, xconf: &XConf) {
+        let xconf = XConf {
+            size: self.scale.to_logical_size(xconf.size),
+            ..*xconf
+        };
+        self.inner.on_screen_changed(&xconf)
+    }
+
+    fn on_peer_unresponsive(&mut self, silent_for: Duration) {
+        self.inner.on_peer_unresponsive(silent_for)
+    }
+
+    fn on_unknown_message(&mut self, ty: u32, window: WindowID, len: u32) {
+        self.inner.on_unknown_message(ty, window, len)
+    }
+
+    #[cfg(feature = "proposed")]
+    fn on_proposed(&mut self, ty: u32, window: WindowID, untrusted_body: &[u8]) {
+        self.inner.on_proposed(ty, window, untrusted_body)
+    }
+
+    fn on_other(&mut self, header: Header, body: &[u8]) {
+        self.inner.on_other(header, body)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rectangle(x: i32, y: i32, width: u32, height: u32) -> Rectangle {
+        Rectangle {
+            top_left: Coordinates { x, y },
+            size: WindowSize { width, height },
+        }
+    }
+
+    #[test]
+    fn conversions() {
+        assert_eq!(Scale::new(0.0), None);
+        assert_eq!(Scale::new(f64::NAN), None);
+        let double = Scale::new(2.0).unwrap();
+        let r = rectangle(-3, 4, 10, 1);
+        assert_eq!(double.to_physical_rect(r), rectangle(-6, 8, 20, 2));
+        assert_eq!(double.to_logical_rect(double.to_physical_rect(r)), r);
+        let point = Coordinates { x: -1, y: 5 };
+        assert_eq!(double.to_logical_point(point), Coordinates { x: -1, y: 2 });
+        assert_eq!(double.to_logical_point_f64(point), (-0.5, 2.5));
+
+        let half = Scale::new(1.5).unwrap();
+        let size = WindowSize {
+            width: 1,
+            height: 0,
+        };
+        assert_eq!(half.to_logical_size(size), size, "stays nonempty");
+        assert_eq!(half.damage(rectangle(1, 1, 1, 1)), rectangle(1, 1, 2, 2));
+        assert_eq!(
+            Scale::default().damage(rectangle(1, 2, 3, 4)),
+            rectangle(1, 2, 3, 4)
+        );
+    }
+
+    #[derive(Default)]
+    struct Recorder(Vec<Coordinates>, Vec<Rectangle>);
+
+    impl MessageHandler for Recorder {
+        fn on_button(&mut self, _: WindowID, button: &qubes_gui::Button) {
+            self.0.push(button.coordinates)
+        }
+
+        fn on_configure(&mut self, _: WindowID, configure: &qubes_gui::Configure) {
+            self.1.push(configure.rectangle)
+        }
+    }
+
+    #[test]
+    fn scales_messages() {
+        let window = NonZeroU32::new(1).unwrap();
+        let mut scaled = Scaled::new(Scale::new(2.0).unwrap(), Recorder::default());
+        let button = qubes_gui::Button {
+            coordinates: Coordinates { x: 9, y: 4 },
+            ..Default::default()
+        };
+        scaled.on_button(window.into(), &button);
+        let configure = qubes_gui::Configure {
+            rectangle: rectangle(100, 50, 801, 600),
+            override_redirect: 0,
+        };
+        scaled.on_configure(window.into(), &configure);
+        let recorder = scaled.inner();
+        assert_eq!(recorder.0, [Coordinates { x: 4, y: 2 }]);
+        assert_eq!(recorder.1, [rectangle(50, 25, 401, 300)]);
+        assert_eq!(scaled.physical(window), Some(configure.rectangle));
+        scaled.on_destroy(window.into());
+        assert_eq!(scaled.physical(window), None);
+    }
+}
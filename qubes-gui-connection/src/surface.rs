@@ -0,0 +1,849 @@
+/*
+ * The Qubes OS Project, https://www.qubes-os.org
+ *
+ * Copyright (C) 2021  Demi Marie Obenour  <demi@invisiblethingslab.com>
+ *
+ * This program is free software; you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License
+ * as published by the Free Software Foundation; either version 2
+ * of the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301, USA.
+ *
+ */
+
+//! CPU rendering into the buffer of a window, modelled on the `softbuffer`
+//! crate.
+//!
+//! The buffer of a [`Surface`] must already be shared with the daemon, for
+//! example with grant references sent in a `MSG_WINDOW_DUMP` message.  A
+//! [`ResizableSurface`] shares its buffers itself, using a
+//! [`GrantAllocator`].
+//!
+//! With the `raw-window-handle` feature, `Surface::handle` returns a
+//! `QubesWindowHandle`, which implements the `raw-window-handle` traits.
+//! Every `RawWindowHandle` variant names a native windowing system, and none
+//! of them can describe a Qubes window, so the handle is opaque: asking it
+//! for a raw handle fails with `HandleError::NotSupported`.  Crates that
+//! only pass handles along accept it, and code that knows about Qubes gets
+//! the window ID from `QubesWindowHandle::window`.  `softbuffer` itself
+//! needs a raw handle, and cannot be used; draw with a [`Surface`] instead.
+//!
+//! With the `proposed` feature, a [`ResizableSurface`] can also share its
+//! buffers as ARGB, if the daemon supports it.
+
+use crate::{Connection, Extension, OutgoingMessage};
+#[cfg(feature = "proposed")]
+use qubes_gui::proposed::PixelFormat;
+use qubes_gui::{Coordinates, Rectangle, ShmImage, WindowSize};
+#[cfg(feature = "raw-window-handle")]
+use raw_window_handle::{
+    DisplayHandle, HandleError, HasDisplayHandle, HasWindowHandle, WindowHandle,
+};
+use std::collections::VecDeque;
+use std::convert::TryFrom;
+use std::io;
+use std::num::NonZeroU32;
+use std::ops::{Deref, DerefMut};
+
+/// The buffer of a window, which holds one `u32` per pixel, in rows from top
+/// to bottom.  Each pixel is `0x00RRGGBB`, as with `softbuffer`, unless the
+/// buffer was shared with another pixel format.
+#[derive(Debug)]
+pub struct Surface<M> {
+    window: NonZeroU32,
+    size: WindowSize,
+    memory: M,
+}
+
+fn pixels(size: WindowSize) -> io::Result<usize> {
+    size.checked_area()
+        .and_then(|area| usize::try_from(area).ok())
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{}x{} window is too large", size.width, size.height),
+            )
+        })
+}
+
+impl<M: AsMut<[u32]>> Surface<M> {
+    /// Draw into `memory`, the shared buffer of `window`, which has size
+    /// `size`
+    ///
+    /// # Errors
+    ///
+    /// Fails if `memory` is too small.
+    pub fn new(window: NonZeroU32, size: WindowSize, mut memory: M) -> io::Result<Self> {
+        let needed = pixels(size)?;
+        let len = memory.as_mut().len();
+        if len < needed {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} pixels needed, but only {} provided", needed, len),
+            ));
+        }
+        Ok(Self {
+            window,
+            size,
+            memory,
+        })
+    }
+
+    /// The window being drawn to
+    pub fn window(&self) -> NonZeroU32 {
+        self.window
+    }
+
+    /// The size of the buffer
+    pub fn size(&self) -> WindowSize {
+        self.size
+    }
+
+    /// A handle to the window being drawn to
+    #[cfg(feature = "raw-window-handle")]
+    pub fn handle(&self) -> QubesWindowHandle {
+        QubesWindowHandle::new(self.window)
+    }
+
+    /// Switch to a new buffer of size `size`, which must already be shared
+    /// with the daemon, and return the old one.  On failure, nothing
+    /// changes, and `memory` is dropped.
+    ///
+    /// # Errors
+    ///
+    /// Fails if `memory` is too small.
+    pub fn resize(&mut self, size: WindowSize, memory: M) -> io::Result<M> {
+        let new = Self::new(self.window, size, memory)?;
+        self.size = new.size;
+        Ok(std::mem::replace(&mut self.memory, new.memory))
+    }
+
+    /// Get the pixels, to draw into them
+    pub fn buffer_mut(&mut self) -> Buffer<'_> {
+        let len = pixels(self.size).expect("checked on creation");
+        Buffer {
+            window: self.window,
+            size: self.size,
+            pixels: &mut self.memory.as_mut()[..len],
+        }
+    }
+
+    /// Stop drawing, and return the buffer
+    pub fn into_inner(self) -> M {
+        self.memory
+    }
+}
+
+/// An opaque handle to a Qubes window, for crates that take a window or
+/// display handle.  There is no raw handle to return, so
+/// [`HasWindowHandle::window_handle`] and
+/// [`HasDisplayHandle::display_handle`] always fail with
+/// [`HandleError::NotSupported`].
+#[cfg(feature = "raw-window-handle")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct QubesWindowHandle {
+    window: NonZeroU32,
+}
+
+#[cfg(feature = "raw-window-handle")]
+impl QubesWindowHandle {
+    /// A handle to `window`
+    pub fn new(window: NonZeroU32) -> Self {
+        Self { window }
+    }
+
+    /// The window this is a handle to
+    pub fn window(&self) -> NonZeroU32 {
+        self.window
+    }
+}
+
+#[cfg(feature = "raw-window-handle")]
+impl HasWindowHandle for QubesWindowHandle {
+    fn window_handle(&self) -> Result<WindowHandle<'_>, HandleError> {
+        Err(HandleError::NotSupported)
+    }
+}
+
+#[cfg(feature = "raw-window-handle")]
+impl HasDisplayHandle for QubesWindowHandle {
+    fn display_handle(&self) -> Result<DisplayHandle<'_>, HandleError> {
+        Err(HandleError::NotSupported)
+    }
+}
+
+/// The pixels of a [`Surface`], as returned by [`Surface::buffer_mut`]
+#[derive(Debug)]
+pub struct Buffer<'a> {
+    window: NonZeroU32,
+    size: WindowSize,
+    pixels: &'a mut [u32],
+}
+
+impl Buffer<'_> {
+    /// Tell the daemon that the whole window has changed
+    ///
+    /// # Errors
+    ///
+    /// Fails if sending fails.
+    pub fn present(self, connection: &mut Connection) -> io::Result<()> {
+        let whole = Rectangle {
+            top_left: Coordinates { x: 0, y: 0 },
+            size: self.size,
+        };
+        self.present_with_damage(connection, &[whole])
+    }
+
+    /// Tell the daemon that the pixels in `damage` have changed.  Parts of
+    /// rectangles outside the window are ignored.
+    ///
+    /// # Errors
+    ///
+    /// Fails if sending fails.
+    pub fn present_with_damage(
+        self,
+        connection: &mut Connection,
+        damage: &[Rectangle],
+    ) -> io::Result<()> {
+        damage
+            .iter()
+            .filter_map(|rectangle| rectangle.clamp_to(self.size))
+            .try_for_each(|rectangle| {
+                connection.send_agent(&ShmImage { rectangle }, self.window.into())
+            })
+    }
+}
+
+impl Deref for Buffer<'_> {
+    type Target = [u32];
+    fn deref(&self) -> &[u32] {
+        self.pixels
+    }
+}
+
+impl DerefMut for Buffer<'_> {
+    fn deref_mut(&mut self) -> &mut [u32] {
+        self.pixels
+    }
+}
+
+/// The default size of the tiles compared by [`DoubleBuffer`]
+const DEFAULT_TILE: WindowSize = WindowSize {
+    width: 64,
+    height: 64,
+};
+
+/// A [`Surface`] with a private back buffer.  Drawing happens in the back
+/// buffer, and [`DoubleBuffer::present`] copies only the tiles that differ
+/// from the shared (front) buffer, reporting them with as few rectangles as
+/// it can.  This saves work when little of the window changes between
+/// frames.
+#[derive(Debug)]
+pub struct DoubleBuffer<M> {
+    surface: Surface<M>,
+    back: Vec<u32>,
+    tile: WindowSize,
+}
+
+impl<M: AsMut<[u32]>> DoubleBuffer<M> {
+    /// Draw into a back buffer for `surface`.  The back buffer starts as a
+    /// copy of the shared buffer.
+    pub fn new(mut surface: Surface<M>) -> Self {
+        let back = surface.buffer_mut().to_vec();
+        Self {
+            surface,
+            back,
+            tile: DEFAULT_TILE,
+        }
+    }
+
+    /// Compare tiles of size `tile` (by default, 64×64).  Smaller tiles
+    /// find smaller damage, but need more rectangles to report it.
+    pub fn tile_size(mut self, tile: WindowSize) -> Self {
+        self.tile = WindowSize {
+            width: tile.width.max(1),
+            height: tile.height.max(1),
+        };
+        self
+    }
+
+    /// The surface being drawn to
+    pub fn surface(&self) -> &Surface<M> {
+        &self.surface
+    }
+
+    /// Get the back buffer, to draw into it
+    pub fn back_mut(&mut self) -> &mut [u32] {
+        &mut self.back
+    }
+
+    /// Switch to a new shared buffer of size `size`, as with
+    /// [`Surface::resize`], and return the old one.  The back buffer starts
+    /// over as a copy of the new shared buffer.
+    ///
+    /// # Errors
+    ///
+    /// Fails if `memory` is too small.
+    pub fn resize(&mut self, size: WindowSize, memory: M) -> io::Result<M> {
+        let old = self.surface.resize(size, memory)?;
+        self.back = self.surface.buffer_mut().to_vec();
+        Ok(old)
+    }
+
+    /// Copy the tiles that have changed to the shared buffer, and tell the
+    /// daemon about them
+    ///
+    /// # Errors
+    ///
+    /// Fails if sending fails.
+    pub fn present(&mut self, connection: &mut Connection) -> io::Result<()> {
+        let damage = self.flip();
+        self.surface
+            .buffer_mut()
+            .present_with_damage(connection, &damage)
+    }
+
+    /// Stop drawing, and return the surface
+    pub fn into_inner(self) -> Surface<M> {
+        self.surface
+    }
+
+    /// Copy the tiles that have changed to the shared buffer, and return
+    /// rectangles covering them.  Dirty tiles next to each other in a row
+    /// are merged, and so are runs of them with the same columns in
+    /// consecutive rows.
+    fn flip(&mut self) -> Vec<Rectangle> {
+        let size = self.surface.size();
+        let (width, height) = (size.width as usize, size.height as usize);
+        let (tile_width, tile_height) = (self.tile.width as usize, self.tile.height as usize);
+        let back = &self.back;
+        let mut front = self.surface.buffer_mut();
+        let mut damage = vec![];
+        // Indices in `damage` of the runs ending at the previous row of tiles
+        let mut previous = vec![];
+        for top in (0..height).step_by(tile_height) {
+            let rows = top..height.min(top + tile_height);
+            let mut current = vec![];
+            let mut run = None;
+            for left in (0..width).step_by(tile_width) {
+                let right = width.min(left + tile_width);
+                let dirty = rows.clone().any(|y| {
+                    let span = y * width + left..y * width + right;
+                    front[span.clone()] != back[span]
+                });
+                if dirty {
+                    for y in rows.clone() {
+                        let span = y * width + left..y * width + right;
+                        front[span.clone()].copy_from_slice(&back[span])
+                    }
+                    run = Some((run.map_or(left, |(start, _)| start), right))
+                } else if let Some(columns) = run.take() {
+                    add_run(&mut damage, &previous, &mut current, columns, rows.clone())
+                }
+            }
+            if let Some(columns) = run {
+                add_run(&mut damage, &previous, &mut current, columns, rows)
+            }
+            previous = current;
+        }
+        damage
+    }
+}
+
+/// Report dirty `columns` of `rows`, by growing a rectangle in `previous`
+/// with the same columns if there is one.  The index of the rectangle is
+/// added to `current`.
+fn add_run(
+    damage: &mut Vec<Rectangle>,
+    previous: &[usize],
+    current: &mut Vec<usize>,
+    (left, right): (usize, usize),
+    rows: std::ops::Range<usize>,
+) {
+    let above = previous.iter().copied().find(|&i| {
+        let rectangle: &Rectangle = &damage[i];
+        rectangle.top_left.x as usize == left && rectangle.size.width as usize == right - left
+    });
+    let index = match above {
+        Some(i) => {
+            damage[i].size.height += rows.len() as u32;
+            i
+        }
+        None => {
+            damage.push(Rectangle {
+                top_left: Coordinates {
+                    x: left as i32,
+                    y: rows.start as i32,
+                },
+                size: WindowSize {
+                    width: (right - left) as u32,
+                    height: rows.len() as u32,
+                },
+            });
+            damage.len() - 1
+        }
+    };
+    current.push(index)
+}
+
+/// Allocates window buffers and shares them with the daemon
+pub trait GrantAllocator {
+    /// A buffer shared with the daemon
+    type Memory: AsMut<[u32]>;
+
+    /// Allocate a buffer for `window` with room for `size`, and grant the
+    /// daemon access to it.  Returns the buffer and the grant references of
+    /// its pages, in order.  The grants should be revoked when the buffer is
+    /// dropped.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the buffer cannot be allocated or shared.
+    fn allocate(
+        &mut self,
+        window: NonZeroU32,
+        size: WindowSize,
+    ) -> io::Result<(Self::Memory, Vec<u32>)>;
+}
+
+/// A [`Surface`] that can change size.  Resizing allocates a new buffer,
+/// tells the daemon about it with `MSG_WINDOW_DUMP`, and reports the whole
+/// window as damaged once the daemon uses it.  With [`Extension::DumpAck`],
+/// each old buffer is kept until the daemon acknowledges the dump that
+/// replaced it, so resizing completes asynchronously; call
+/// [`ResizableSurface::dump_acknowledged`] for each `MSG_WINDOW_DUMP_ACK`
+/// for the window.  Without it, old buffers are dropped at once.
+///
+/// This only changes the buffer.  The window itself is resized with
+/// `MSG_CONFIGURE`, as usual.
+#[derive(Debug)]
+pub struct ResizableSurface<A: GrantAllocator> {
+    allocator: A,
+    surface: Surface<A::Memory>,
+    /// For each unacknowledged dump, oldest first, the buffer it replaced
+    retired: VecDeque<Option<A::Memory>>,
+    /// Bits per pixel of the buffer, as sent in `MSG_WINDOW_DUMP`
+    bpp: u32,
+}
+
+impl<A: GrantAllocator> ResizableSurface<A> {
+    /// Allocate a buffer of size `size` for `window`, and tell the daemon
+    /// about it
+    ///
+    /// # Errors
+    ///
+    /// Fails if allocation or sending fails.
+    pub fn new(
+        connection: &mut Connection,
+        window: NonZeroU32,
+        size: WindowSize,
+        mut allocator: A,
+    ) -> io::Result<Self> {
+        let bpp = 24;
+        let surface = allocate(&mut allocator, connection, window, size, bpp)?;
+        let mut res = Self {
+            allocator,
+            surface,
+            retired: VecDeque::new(),
+            bpp,
+        };
+        res.dumped(connection, None)?;
+        Ok(res)
+    }
+
+    /// The surface to draw to.  Its size is the size most recently asked for.
+    pub fn surface_mut(&mut self) -> &mut Surface<A::Memory> {
+        &mut self.surface
+    }
+
+    /// Is the daemon still using an old buffer?
+    pub fn resizing(&self) -> bool {
+        self.retired.iter().any(Option::is_some)
+    }
+
+    /// Switch to a new buffer of size `size`.  The contents of the new
+    /// buffer are whatever the allocator put there.
+    ///
+    /// # Errors
+    ///
+    /// Fails if allocation or sending fails.  If allocation fails, nothing
+    /// changes.
+    pub fn resize(&mut self, connection: &mut Connection, size: WindowSize) -> io::Result<()> {
+        let window = self.surface.window();
+        let new = allocate(&mut self.allocator, connection, window, size, self.bpp)?;
+        let old = std::mem::replace(&mut self.surface, new);
+        self.dumped(connection, Some(old.into_inner()))
+    }
+
+    /// The pixel format of the buffer
+    #[cfg(feature = "proposed")]
+    pub fn format(&self) -> PixelFormat {
+        PixelFormat::try_from(self.bpp).expect("only valid formats are set")
+    }
+
+    /// Share a new buffer, of the same size, in `format`.  `features` are
+    /// the feature bits the daemon sent in its
+    /// [`XConfScale`](qubes_gui::proposed::XConfScale), as returned by
+    /// [`Connection::xconf_scale`].  The contents of the
+    /// new buffer are whatever the allocator put there.  Nothing is sent if
+    /// the format does not change.
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`io::ErrorKind::Unsupported`] if `features` does not
+    /// allow `format`, and otherwise if allocation or sending fails.  On
+    /// failure, the format does not change.
+    #[cfg(feature = "proposed")]
+    pub fn set_format(
+        &mut self,
+        connection: &mut Connection,
+        format: PixelFormat,
+        features: u32,
+    ) -> io::Result<()> {
+        if matches!(format.feature(), Some(feature) if features & feature == 0) {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("The daemon does not support {:?}", format),
+            ));
+        }
+        if format.bpp() == self.bpp {
+            return Ok(());
+        }
+        let old = std::mem::replace(&mut self.bpp, format.bpp());
+        let size = self.surface.size();
+        self.resize(connection, size)
+            .inspect_err(|_| self.bpp = old)
+    }
+
+    /// The daemon has acknowledged the oldest unacknowledged dump, so the
+    /// buffer it replaced can be dropped.  Once the newest dump is
+    /// acknowledged, the whole window is reported as damaged.  Spurious
+    /// acknowledgements are ignored.
+    ///
+    /// # Errors
+    ///
+    /// Fails if sending fails.
+    pub fn dump_acknowledged(&mut self, connection: &mut Connection) -> io::Result<()> {
+        if self.retired.pop_front().is_some() && self.retired.is_empty() {
+            self.surface.buffer_mut().present(connection)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// `extension` is no longer available.  If it is
+    /// [`Extension::DumpAck`], old buffers are dropped, and the whole
+    /// window is reported as damaged.
+    ///
+    /// # Errors
+    ///
+    /// Fails if sending fails.
+    pub fn downgraded(
+        &mut self,
+        connection: &mut Connection,
+        extension: Extension,
+    ) -> io::Result<()> {
+        if extension != Extension::DumpAck || self.retired.is_empty() {
+            return Ok(());
+        }
+        self.retired.clear();
+        self.surface.buffer_mut().present(connection)
+    }
+
+    /// Share a new buffer, of the same size, with a new daemon.  Its
+    /// contents are whatever the allocator put there.  Buffers that only
+    /// the old daemon was waiting to release are dropped.
+    ///
+    /// # Errors
+    ///
+    /// Fails if allocation or sending fails.
+    pub fn reconnected(&mut self, connection: &mut Connection) -> io::Result<()> {
+        self.retired.clear();
+        let size = self.surface.size();
+        self.resize(connection, size)
+    }
+
+    /// Stop drawing, and return the surface and the allocator.  Old buffers
+    /// still in use by the daemon are dropped.
+    pub fn into_inner(self) -> (Surface<A::Memory>, A) {
+        (self.surface, self.allocator)
+    }
+
+    /// A dump has been sent, replacing `old`
+    fn dumped(&mut self, connection: &mut Connection, old: Option<A::Memory>) -> io::Result<()> {
+        if connection.extensions().contains(Extension::DumpAck) {
+            self.retired.push_back(old);
+            Ok(())
+        } else {
+            drop(old);
+            self.surface.buffer_mut().present(connection)
+        }
+    }
+}
+
+/// Allocate a buffer of size `size` for `window`, and send the dump for it,
+/// with `bpp` bits per pixel
+fn allocate<A: GrantAllocator>(
+    allocator: &mut A,
+    connection: &mut Connection,
+    window: NonZeroU32,
+    size: WindowSize,
+    bpp: u32,
+) -> io::Result<Surface<A::Memory>> {
+    let (memory, grant_refs) = allocator.allocate(window, size)?;
+    let surface = Surface::new(window, size, memory)?;
+    let header = qubes_gui::WindowDumpHeader {
+        ty: qubes_gui::WINDOW_DUMP_TYPE_GRANT_REFS,
+        width: size.width,
+        height: size.height,
+        bpp,
+    };
+    let dump = OutgoingMessage::WindowDump { header, grant_refs };
+    connection.send_message(&dump, window.into())?;
+    Ok(surface)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Event, LoopbackTransport};
+    use qubes_castable::Castable as _;
+    use std::task::Poll;
+    use std::time::Duration;
+
+    fn size(width: u32, height: u32) -> WindowSize {
+        WindowSize { width, height }
+    }
+
+    #[test]
+    fn sizes() {
+        let window = NonZeroU32::new(1).unwrap();
+        assert!(Surface::new(window, size(4, 4), vec![0; 15]).is_err());
+        assert!(Surface::new(window, size(u32::MAX, 2), vec![]).is_err());
+        let mut surface = Surface::new(window, size(4, 4), vec![0; 20]).unwrap();
+        assert_eq!(surface.buffer_mut().len(), 16);
+        assert!(surface.resize(size(8, 8), vec![0; 63]).is_err());
+        assert_eq!(surface.size(), size(4, 4));
+        let old = surface.resize(size(8, 8), vec![0; 64]).unwrap();
+        assert_eq!(old.len(), 20);
+        assert_eq!(surface.buffer_mut().len(), 64);
+    }
+
+    #[cfg(feature = "raw-window-handle")]
+    #[test]
+    fn handle() {
+        let window = NonZeroU32::new(7).unwrap();
+        let surface = Surface::new(window, size(1, 1), vec![0]).unwrap();
+        let handle = surface.handle();
+        assert_eq!(handle.window(), window);
+        assert!(matches!(
+            handle.window_handle(),
+            Err(HandleError::NotSupported)
+        ));
+        assert!(matches!(
+            handle.display_handle(),
+            Err(HandleError::NotSupported)
+        ));
+    }
+
+    #[test]
+    fn present() {
+        let (ours, theirs) = LoopbackTransport::pair();
+        let mut daemon = Connection::daemon_over(theirs, Default::default());
+        let mut agent = Connection::agent_over(ours);
+        loop {
+            let _ = daemon.read_message();
+            match agent.read_event() {
+                Poll::Ready(Ok(Event::Reconnected(_))) => break,
+                Poll::Ready(e) => panic!("unexpected {:?}", e),
+                Poll::Pending => std::thread::sleep(Duration::from_millis(1)),
+            }
+        }
+        let window = NonZeroU32::new(7).unwrap();
+        let mut surface = Surface::new(window, size(10, 5), vec![0; 50]).unwrap();
+        let mut buffer = surface.buffer_mut();
+        buffer[12] = 0x00ff_0000;
+        let damage = [
+            Rectangle {
+                top_left: Coordinates { x: 8, y: -2 },
+                size: size(4, 4),
+            },
+            Rectangle {
+                top_left: Coordinates { x: 20, y: 0 },
+                size: size(1, 1),
+            },
+        ];
+        buffer.present_with_damage(&mut agent, &damage).unwrap();
+        surface.buffer_mut().present(&mut agent).unwrap();
+        let mut images = vec![];
+        while images.len() < 2 {
+            if let Poll::Ready(message) = daemon.read_message() {
+                let message = message.unwrap();
+                assert_eq!(message.hdr().ty(), qubes_gui::MSG_SHMIMAGE);
+                assert_eq!(message.hdr().untrusted_window(), window.into());
+                images.push(ShmImage::from_bytes(message.body()).rectangle);
+            }
+        }
+        assert_eq!(
+            images,
+            [
+                Rectangle {
+                    top_left: Coordinates { x: 8, y: 0 },
+                    size: size(2, 2),
+                },
+                Rectangle {
+                    top_left: Coordinates { x: 0, y: 0 },
+                    size: size(10, 5),
+                },
+            ]
+        );
+        assert_eq!(surface.into_inner()[12], 0x00ff_0000);
+    }
+
+    #[test]
+    fn double_buffer_damage() {
+        let window = NonZeroU32::new(1).unwrap();
+        let surface = Surface::new(window, size(9, 4), vec![0; 36]).unwrap();
+        let mut double = DoubleBuffer::new(surface).tile_size(size(4, 2));
+        let at = |x: usize, y: usize| y * 9 + x;
+        let rectangle = |x, y, width, height| Rectangle {
+            top_left: Coordinates { x, y },
+            size: size(width, height),
+        };
+        assert_eq!(double.flip(), []);
+        // Adjacent tiles in a row, including the narrow one at the edge
+        double.back_mut()[at(0, 0)] = 1;
+        double.back_mut()[at(8, 1)] = 2;
+        double.back_mut()[at(4, 1)] = 3;
+        assert_eq!(double.flip(), [rectangle(0, 0, 9, 2)]);
+        // Tiles that are not adjacent, and the same columns in two rows
+        double.back_mut()[at(0, 0)] = 4;
+        double.back_mut()[at(1, 3)] = 5;
+        double.back_mut()[at(8, 0)] = 6;
+        assert_eq!(
+            double.flip(),
+            [rectangle(0, 0, 4, 4), rectangle(8, 0, 1, 2)]
+        );
+        // Writing the same value again changes nothing
+        double.back_mut()[at(8, 0)] = 6;
+        assert_eq!(double.flip(), []);
+        let front = double.into_inner().into_inner();
+        assert_eq!(
+            (front[at(0, 0)], front[at(1, 3)], front[at(4, 1)]),
+            (4, 5, 3)
+        );
+    }
+
+    /// Buffers whose grant references count up from 1
+    #[derive(Debug, Default)]
+    struct Counting(u32);
+
+    impl GrantAllocator for Counting {
+        type Memory = Vec<u32>;
+
+        fn allocate(
+            &mut self,
+            _: NonZeroU32,
+            size: WindowSize,
+        ) -> io::Result<(Vec<u32>, Vec<u32>)> {
+            self.0 += 1;
+            Ok((vec![0; pixels(size)?], vec![self.0]))
+        }
+    }
+
+    #[test]
+    fn resize_waits_for_ack() {
+        let (ours, theirs) = LoopbackTransport::pair();
+        let mut daemon = Connection::daemon_over(theirs, Default::default());
+        let mut agent = Connection::agent_over(ours);
+        loop {
+            let _ = daemon.read_message();
+            match agent.read_event() {
+                Poll::Ready(Ok(Event::Reconnected(_))) => break,
+                Poll::Ready(e) => panic!("unexpected {:?}", e),
+                Poll::Pending => std::thread::sleep(Duration::from_millis(1)),
+            }
+        }
+        let mut received = || {
+            let mut types = vec![];
+            while let Poll::Ready(message) = daemon.read_message() {
+                types.push(message.unwrap().hdr().ty())
+            }
+            types
+        };
+        let window = NonZeroU32::new(3).unwrap();
+        let mut surface =
+            ResizableSurface::new(&mut agent, window, size(2, 2), Counting::default()).unwrap();
+        assert_eq!(received(), [qubes_gui::MSG_WINDOW_DUMP]);
+        assert!(!surface.resizing());
+        surface.resize(&mut agent, size(4, 3)).unwrap();
+        surface.resize(&mut agent, size(5, 3)).unwrap();
+        assert_eq!(surface.surface_mut().buffer_mut().len(), 15);
+        assert_eq!(
+            received(),
+            [qubes_gui::MSG_WINDOW_DUMP, qubes_gui::MSG_WINDOW_DUMP]
+        );
+        assert!(surface.resizing());
+        // The first acknowledgement is for the first buffer, which replaced
+        // nothing
+        surface.dump_acknowledged(&mut agent).unwrap();
+        surface.dump_acknowledged(&mut agent).unwrap();
+        assert!(surface.resizing());
+        assert_eq!(received(), []);
+        surface.dump_acknowledged(&mut agent).unwrap();
+        assert!(!surface.resizing());
+        assert_eq!(received(), [qubes_gui::MSG_SHMIMAGE]);
+        surface.dump_acknowledged(&mut agent).unwrap();
+        assert_eq!(received(), []);
+        let (surface, allocator) = surface.into_inner();
+        assert_eq!((surface.size(), allocator.0), (size(5, 3), 3));
+    }
+
+    #[cfg(feature = "proposed")]
+    #[test]
+    fn pixel_formats() {
+        use qubes_gui::proposed::FEATURE_ARGB32;
+        let (ours, theirs) = LoopbackTransport::pair();
+        let mut daemon = Connection::daemon_over(theirs, Default::default());
+        let mut agent = Connection::agent_over(ours);
+        loop {
+            let _ = daemon.read_message();
+            match agent.read_event() {
+                Poll::Ready(Ok(Event::Reconnected(_))) => break,
+                Poll::Ready(e) => panic!("unexpected {:?}", e),
+                Poll::Pending => std::thread::sleep(Duration::from_millis(1)),
+            }
+        }
+        let window = NonZeroU32::new(3).unwrap();
+        let mut surface =
+            ResizableSurface::new(&mut agent, window, size(2, 2), Counting::default()).unwrap();
+        assert_eq!(surface.format(), PixelFormat::Xrgb8888);
+        let err = surface
+            .set_format(&mut agent, PixelFormat::Argb8888, 0)
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+        surface
+            .set_format(&mut agent, PixelFormat::Argb8888, FEATURE_ARGB32)
+            .unwrap();
+        assert_eq!(surface.format(), PixelFormat::Argb8888);
+        let mut bpps = vec![];
+        while let Poll::Ready(message) = daemon.read_message() {
+            let message = message.unwrap();
+            if message.hdr().ty() == qubes_gui::MSG_WINDOW_DUMP {
+                let header = qubes_gui::WindowDumpHeader::from_bytes(
+                    &message.body()[..std::mem::size_of::<qubes_gui::WindowDumpHeader>()],
+                );
+                bpps.push(header.bpp)
+            }
+        }
+        assert_eq!(bpps, [24, 32]);
+    }
+}
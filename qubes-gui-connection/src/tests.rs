@@ -19,8 +19,9 @@
  */
 
 use super::*;
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
 use std::rc::Rc;
+#[derive(Debug)]
 struct MockVchan {
     read_buf: Vec<u8>,
     write_buf: Vec<u8>,
@@ -29,8 +30,10 @@ struct MockVchan {
     cursor: usize,
 }
 
-impl VchanMock for Rc<RefCell<MockVchan>> {
-    fn wait(&self) {}
+impl Transport for Rc<RefCell<MockVchan>> {
+    fn fd(&self) -> std::os::raw::c_int {
+        -1
+    }
     fn status(&self) -> vchan::Status {
         vchan::Status::Connected
     }
@@ -40,7 +43,7 @@ impl VchanMock for Rc<RefCell<MockVchan>> {
     fn buffer_space(&self) -> usize {
         self.borrow().buffer_space
     }
-    fn send(&self, buffer: &[u8]) -> Result<(), vchan::Error> {
+    fn send(&mut self, buffer: &[u8]) -> Result<(), vchan::Error> {
         let mut s = self.borrow_mut();
         assert!(
             buffer.len() <= s.buffer_space,
@@ -50,7 +53,7 @@ impl VchanMock for Rc<RefCell<MockVchan>> {
         s.buffer_space -= buffer.len();
         Ok(())
     }
-    fn recv_into(&self, buffer: &mut Vec<u8>, bytes: usize) -> Result<(), vchan::Error> {
+    fn recv_into(&mut self, buffer: &mut Vec<u8>, bytes: usize) -> Result<(), vchan::Error> {
         let mut s = self.borrow_mut();
         assert!(
             s.read_buf.len() >= s.data_ready && s.read_buf.len() - s.data_ready >= s.cursor,
@@ -65,9 +68,8 @@ impl VchanMock for Rc<RefCell<MockVchan>> {
         s.data_ready -= bytes;
         Ok(())
     }
-    fn recv_struct<T: Castable + Default>(&self) -> Result<T, vchan::Error> {
+    fn recv(&mut self, b: &mut [u8]) -> Result<(), vchan::Error> {
         let mut s = self.borrow_mut();
-        let mut v: T = Default::default();
         assert!(
             s.read_buf.len() >= s.data_ready && s.read_buf.len() - s.data_ready >= s.cursor,
             "mock vchan internal bounds error: len is {} and ready is {} but cursor is {}",
@@ -75,7 +77,6 @@ impl VchanMock for Rc<RefCell<MockVchan>> {
             s.data_ready,
             s.cursor,
         );
-        let b = v.as_mut_bytes();
         eprintln!("Reading {} bytes with {} ready", b.len(), s.data_ready);
         assert!(
             b.len() <= s.data_ready,
@@ -84,9 +85,9 @@ impl VchanMock for Rc<RefCell<MockVchan>> {
         b.copy_from_slice(&s.read_buf[s.cursor..s.cursor + b.len()]);
         s.cursor += b.len();
         s.data_ready -= b.len();
-        Ok(v)
+        Ok(())
     }
-    fn discard(&self, bytes: usize) -> Result<(), vchan::Error> {
+    fn discard(&mut self, bytes: usize) -> Result<(), vchan::Error> {
         let mut s = self.borrow_mut();
         assert!(
             s.read_buf.len() >= s.data_ready && s.read_buf.len() - s.data_ready >= s.cursor,
@@ -110,16 +111,11 @@ fn vchan_writes() {
         data_ready: 0,
         cursor: 0,
     };
-    let mut under_test = RawMessageStream::<Rc<RefCell<MockVchan>>> {
-        vchan: Rc::new(RefCell::new(mock_vchan)),
-        queue: Default::default(),
-        state: ReadState::Connecting,
-        buffer: vec![],
-        did_reconnect: false,
-        xconf: Default::default(),
-        kind: Kind::Agent,
-        domid: 0,
-    };
+    let mut under_test = RawMessageStream::new(
+        Rc::new(RefCell::new(mock_vchan)),
+        Kind::Agent,
+        Default::default(),
+    );
     under_test.vchan.borrow_mut().buffer_space = 4;
     assert!(
         under_test.read_message().unwrap().is_none(),
@@ -161,6 +157,8 @@ fn vchan_writes() {
     assert_eq!(under_test.vchan.borrow().write_buf, b"test1\0another al");
     assert_eq!(under_test.queue.len(), 3);
     assert_eq!(under_test.queue, *b"pha");
+    assert_eq!(under_test.metrics.queue_depth(), 3);
+    assert_eq!(under_test.metrics.peak_queue_depth(), 12);
     under_test.vchan.borrow_mut().buffer_space = 8;
     under_test.write(b" gamma delta").expect("write works");
     assert_eq!(
@@ -214,6 +212,35 @@ fn vchan_writes() {
     );
 }
 
+#[test]
+fn wrapped_queue_flushes_in_one_send() {
+    let mock_vchan = MockVchan {
+        read_buf: vec![],
+        write_buf: vec![],
+        buffer_space: 0,
+        data_ready: 0,
+        cursor: 0,
+    };
+    let mut under_test = RawMessageStream::new(
+        Rc::new(RefCell::new(mock_vchan)),
+        Kind::Agent,
+        Default::default(),
+    );
+    under_test.queue = VecDeque::with_capacity(8);
+    under_test.state = ReadState::ReadingHeader;
+    let capacity = under_test.queue.capacity();
+    under_test.queue.resize(capacity - 2, b'x');
+    under_test.queue.drain(..capacity - 4);
+    under_test.queue.extend(*b"abcd");
+    let (front, back) = under_test.queue.as_slices();
+    assert!(!front.is_empty() && !back.is_empty(), "queue wraps around");
+    under_test.vchan.borrow_mut().buffer_space = 16;
+    assert_eq!(under_test.flush_pending_writes().unwrap(), 6);
+    assert!(under_test.queue.is_empty());
+    assert_eq!(under_test.vchan.borrow().write_buf, b"xxabcd");
+    assert_eq!(under_test.metrics.queue_depth(), 0);
+}
+
 macro_rules! s {
     ($v: ty) => {
         ::std::mem::size_of::<$v>() as u32
@@ -230,16 +257,8 @@ fn vchan_reads() {
         cursor: 0,
     };
     let vchan = Rc::new(RefCell::new(mock_vchan));
-    let mut under_test = RawMessageStream::<Rc<RefCell<MockVchan>>> {
-        vchan: vchan.clone(),
-        queue: Default::default(),
-        state: ReadState::ReadingHeader,
-        buffer: vec![],
-        did_reconnect: false,
-        xconf: Default::default(),
-        domid: 0,
-        kind: Kind::Agent,
-    };
+    let mut under_test = RawMessageStream::new(vchan.clone(), Kind::Agent, Default::default());
+    under_test.state = ReadState::ReadingHeader;
     let mut hdr = UntrustedHeader {
         untrusted_len: 1,
         ty: qubes_gui::MSG_MFNDUMP,
@@ -284,11 +303,15 @@ fn vchan_reads() {
         under_test.read_message().unwrap().is_none(),
         "body not fully written yet!"
     );
-    match under_test.state {
+    match &under_test.state {
         ReadState::ReadingBody { header } => assert_eq!(header.inner(), hdr),
         e => panic!("Bad state {:?}!", e),
     }
     assert_eq!(under_test.buffer.len(), 1);
+    assert!(
+        under_test.buffer.capacity() >= s!(qubes_gui::Configure) as usize,
+        "whole body reserved up front"
+    );
     assert_eq!(vchan.borrow_mut().data_ready, 0);
 
     // Test partial reads when there is already some data in the buffer
@@ -297,7 +320,7 @@ fn vchan_reads() {
         under_test.read_message().unwrap().is_none(),
         "body not fully written yet!"
     );
-    match under_test.state {
+    match &under_test.state {
         ReadState::ReadingBody { header } => assert_eq!(header.inner(), hdr),
         e => panic!("Bad state {:?}!", e),
     }
@@ -346,3 +369,1368 @@ fn vchan_reads() {
         "State after complete message not reset to ReadingHeader"
     );
 }
+
+/// Send `version` to a daemon as an agent would, and return the version the
+/// daemon settled on and the length of its reply
+fn daemon_negotiates(version: u32) -> (u32, usize) {
+    let (mut ours, theirs) = LoopbackTransport::pair();
+    let mut daemon = Connection::daemon_over(theirs, Default::default());
+    ours.send(version.as_bytes()).unwrap();
+    match daemon.read_event() {
+        Poll::Ready(Ok(Event::Reconnected(_))) => {}
+        other => panic!("unexpected {:?}", other),
+    }
+    (daemon.xconf().version, ours.data_ready())
+}
+
+#[test]
+fn daemon_version_negotiation() {
+    use qubes_gui::{XConf, XConfVersion, PROTOCOL_VERSION, PROTOCOL_VERSION_MAJOR};
+    // An agent older than 1.4 gets a bare XConf
+    assert_eq!(
+        daemon_negotiates(PROTOCOL_VERSION_MAJOR << 16 | 3),
+        (PROTOCOL_VERSION_MAJOR << 16 | 3, size_of::<XConf>())
+    );
+    // A newer agent is held to our minor version, not the one it sent
+    assert_eq!(
+        daemon_negotiates(PROTOCOL_VERSION + 5),
+        (PROTOCOL_VERSION, size_of::<XConfVersion>())
+    );
+}
+
+#[test]
+fn clipboard_streaming() {
+    let mock_vchan = MockVchan {
+        read_buf: vec![],
+        write_buf: vec![],
+        buffer_space: 0,
+        data_ready: 0,
+        cursor: 0,
+    };
+    let vchan = Rc::new(RefCell::new(mock_vchan));
+    let mut under_test = RawMessageStream::new(vchan.clone(), Kind::Agent, Default::default());
+    under_test.state = ReadState::ReadingHeader;
+    under_test.stream_clipboard = true;
+    let hdr = UntrustedHeader {
+        untrusted_len: 5,
+        ty: qubes_gui::MSG_CLIPBOARD_DATA,
+        window: 0.into(),
+    };
+    vchan
+        .borrow_mut()
+        .read_buf
+        .extend_from_slice(hdr.as_bytes());
+    vchan.borrow_mut().read_buf.extend_from_slice(b"hello");
+    vchan.borrow_mut().data_ready = s!(UntrustedHeader) as usize + 2;
+    assert!(matches!(
+        under_test.read_header(false).unwrap(),
+        Some(Incoming::ClipboardChunk(_))
+    ));
+    assert_eq!(under_test.buffer, b"he");
+    assert!(under_test.read_header(false).unwrap().is_none(), "no data");
+    vchan.borrow_mut().data_ready = 3;
+    assert!(matches!(
+        under_test.read_header(false).unwrap(),
+        Some(Incoming::ClipboardChunk(_))
+    ));
+    assert_eq!(under_test.buffer, b"llo");
+    match under_test.read_header(false).unwrap() {
+        Some(Incoming::ClipboardEnd(header)) => assert_eq!(header.inner(), hdr),
+        e => panic!("Bad result {:?}!", e),
+    }
+    assert!(matches!(under_test.state, ReadState::ReadingHeader));
+
+    // Empty clipboard data is just the end
+    let hdr = UntrustedHeader {
+        untrusted_len: 0,
+        ..hdr
+    };
+    vchan
+        .borrow_mut()
+        .read_buf
+        .extend_from_slice(hdr.as_bytes());
+    vchan.borrow_mut().data_ready = s!(UntrustedHeader) as usize;
+    assert!(matches!(
+        under_test.read_header(false).unwrap(),
+        Some(Incoming::ClipboardEnd(_))
+    ));
+}
+
+#[test]
+fn read_into_caller_buffer() {
+    let mock_vchan = MockVchan {
+        read_buf: vec![],
+        write_buf: vec![],
+        buffer_space: 0,
+        data_ready: 0,
+        cursor: 0,
+    };
+    let vchan = Rc::new(RefCell::new(mock_vchan));
+    let mut under_test = RawMessageStream::new(vchan.clone(), Kind::Agent, Default::default());
+    under_test.state = ReadState::ReadingHeader;
+    let hdr = UntrustedHeader {
+        untrusted_len: 5,
+        ty: qubes_gui::MSG_CLIPBOARD_DATA,
+        window: 0.into(),
+    };
+    for _ in 0..3 {
+        let mut v = vchan.borrow_mut();
+        v.read_buf.extend_from_slice(hdr.as_bytes());
+        v.read_buf.extend_from_slice(b"hello");
+    }
+
+    // Body arrives in two pieces
+    vchan.borrow_mut().data_ready = s!(UntrustedHeader) as usize + 2;
+    let mut buf = [0u8; 8];
+    assert!(under_test.read_message_into(&mut buf).unwrap().is_none());
+    assert_eq!(buf[..2], *b"he");
+    assert!(under_test.buffer.is_empty(), "internal buffer not used");
+    vchan.borrow_mut().data_ready = 3;
+    let header = under_test.read_message_into(&mut buf).unwrap().unwrap();
+    assert_eq!(header.inner(), hdr);
+    assert_eq!(buf[..header.len()], *b"hello");
+    assert_eq!(under_test.metrics.received(hdr.ty).messages, 1);
+
+    // A buffer that is too small is not fatal
+    vchan.borrow_mut().data_ready = s!(UntrustedHeader) as usize + 5;
+    let err = under_test.read_message_into(&mut [0u8; 4]).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    let header = under_test.read_message_into(&mut buf).unwrap().unwrap();
+    assert_eq!(buf[..header.len()], *b"hello");
+
+    // A body partially read with read_message can be finished with
+    // read_message_into
+    vchan.borrow_mut().data_ready = s!(UntrustedHeader) as usize + 1;
+    assert!(under_test.read_message().unwrap().is_none());
+    assert_eq!(under_test.buffer, b"h");
+    vchan.borrow_mut().data_ready = 4;
+    let mut buf = [0u8; 5];
+    let header = under_test.read_message_into(&mut buf).unwrap().unwrap();
+    assert_eq!(header.len(), 5);
+    assert_eq!(buf, *b"hello");
+    assert!(matches!(under_test.state, ReadState::ReadingHeader));
+}
+
+#[test]
+fn peek_header() {
+    let mock_vchan = MockVchan {
+        read_buf: vec![],
+        write_buf: vec![],
+        buffer_space: 0,
+        data_ready: 0,
+        cursor: 0,
+    };
+    let vchan = Rc::new(RefCell::new(mock_vchan));
+    let mut under_test = RawMessageStream::new(vchan.clone(), Kind::Agent, Default::default());
+    under_test.state = ReadState::ReadingHeader;
+    let hdr = UntrustedHeader {
+        untrusted_len: 5,
+        ty: qubes_gui::MSG_CLIPBOARD_DATA,
+        window: 7.into(),
+    };
+    let empty = UntrustedHeader {
+        untrusted_len: 0,
+        ty: qubes_gui::MSG_CLIPBOARD_REQ,
+        window: 8.into(),
+    };
+    {
+        let mut v = vchan.borrow_mut();
+        v.read_buf.extend_from_slice(hdr.as_bytes());
+        v.read_buf.extend_from_slice(b"hello");
+        v.read_buf.extend_from_slice(empty.as_bytes());
+    }
+    vchan.borrow_mut().data_ready = 4;
+    assert!(under_test.peek_header().unwrap().is_none(), "no header yet");
+    vchan.borrow_mut().data_ready = s!(UntrustedHeader) as usize + 5;
+    for _ in 0..2 {
+        let header = under_test.peek_header().unwrap().unwrap();
+        assert_eq!(header.inner(), hdr);
+        assert_eq!(vchan.borrow().data_ready, 5, "body not read");
+    }
+    assert_eq!(under_test.metrics.received(hdr.ty).messages, 0);
+    let buffer = under_test.read_message().unwrap().unwrap();
+    assert_eq!(buffer.hdr().inner(), hdr);
+    assert_eq!(buffer.body(), b"hello");
+
+    // Empty messages are not consumed by peeking either
+    vchan.borrow_mut().data_ready = s!(UntrustedHeader) as usize;
+    let header = under_test.peek_header().unwrap().unwrap();
+    assert_eq!(header.inner(), empty);
+    let header = under_test.read_message_into(&mut []).unwrap().unwrap();
+    assert_eq!(header.inner(), empty);
+    assert!(under_test.peek_header().unwrap().is_none());
+}
+
+/// An agent connected to a daemon that is being run by another thread, with
+/// version negotiation done.  The thread reads `count` messages and then
+/// returns how many bytes of message bodies it received.
+fn agent_with_daemon(count: usize) -> (Connection, std::thread::JoinHandle<usize>) {
+    let (ours, theirs) = LoopbackTransport::pair();
+    let daemon = std::thread::spawn(move || {
+        let mut daemon = Connection::daemon_over(theirs, Default::default());
+        let (mut received, mut bytes) = (0, 0);
+        while received < count {
+            if let Poll::Ready(msg) = daemon.read_message() {
+                bytes += msg.unwrap().body().len();
+                received += 1;
+            } else if daemon.needs_reconnect() {
+                break;
+            } else {
+                std::thread::sleep(Duration::from_millis(1))
+            }
+        }
+        bytes
+    });
+    let mut agent = Connection::agent_over(ours);
+    loop {
+        match agent.read_event() {
+            Poll::Ready(Ok(Event::Reconnected(_))) => break,
+            Poll::Ready(e) => panic!("unexpected {:?}", e),
+            Poll::Pending => std::thread::sleep(Duration::from_millis(1)),
+        }
+    }
+    (agent, daemon)
+}
+
+#[test]
+fn shutdown_flushes_queue() {
+    let (mut agent, daemon) = agent_with_daemon(16);
+    let data = vec![b'x'; 60000];
+    for _ in 0..16 {
+        agent
+            .send_raw(&data, 0.into(), qubes_gui::MSG_CLIPBOARD_DATA)
+            .unwrap();
+    }
+    assert!(agent.metrics().queue_depth() > 0, "not everything fits");
+    agent.shutdown(Duration::from_secs(10)).unwrap();
+    assert_eq!(daemon.join().unwrap(), 16 * data.len());
+}
+
+#[test]
+fn send_queued_messages() {
+    let (mut agent, daemon) = agent_with_daemon(3);
+    let queue: Vec<OutgoingMessage> = vec![
+        qubes_gui::WMName::new("title").unwrap().into(),
+        OutgoingMessage::ClipboardData(b"abc".to_vec()),
+        OutgoingMessage::Unmap,
+    ];
+    for message in &queue {
+        agent.send_message(message, 1.into()).unwrap();
+    }
+    agent.shutdown(Duration::from_secs(10)).unwrap();
+    assert_eq!(daemon.join().unwrap(), 128 + 3);
+}
+
+#[test]
+fn send_raw_rejects_invalid_messages() {
+    let (ours, _theirs) = LoopbackTransport::pair();
+    let mut agent = Connection::agent_over(ours);
+    for (ty, body) in [(0x1234, &[][..]), (qubes_gui::MSG_CLOSE, b"x")] {
+        let err = agent.send_raw(body, 1.into(), ty).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    }
+    assert_eq!(agent.metrics().queue_depth(), 0);
+}
+
+#[test]
+fn shutdown_times_out() {
+    let (ours, _theirs) = LoopbackTransport::pair();
+    let mut agent = RawMessageStream::new(ours, Kind::Agent, Default::default());
+    agent.state = ReadState::ReadingHeader;
+    agent
+        .write(&vec![0; 2 * LoopbackTransport::BUFFER_SIZE])
+        .unwrap();
+    let err = agent
+        .drain(Instant::now() + Duration::from_millis(10))
+        .unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::TimedOut);
+}
+
+#[test]
+fn drop_flushes_what_fits() {
+    let (mut agent, daemon) = agent_with_daemon(1);
+    agent
+        .send_raw(b"bye", 0.into(), qubes_gui::MSG_CLIPBOARD_DATA)
+        .unwrap();
+    drop(agent);
+    assert_eq!(daemon.join().unwrap(), 3);
+}
+
+#[test]
+fn silent_peer_is_reported() {
+    let (mut agent, daemon) = agent_with_daemon(1);
+    let start = Instant::now();
+    agent.set_liveness_timeout(Some(Duration::from_millis(50)));
+    let deadline = agent.liveness_deadline().expect("monitoring");
+    let silent_for = loop {
+        match agent.read_event() {
+            Poll::Ready(Ok(Event::PeerUnresponsive { silent_for })) => break silent_for,
+            Poll::Ready(e) => panic!("unexpected {:?}", e),
+            Poll::Pending => std::thread::sleep(Duration::from_millis(1)),
+        }
+    };
+    assert!(Instant::now() >= deadline);
+    assert!(silent_for >= Duration::from_millis(50));
+    assert!(start.elapsed() >= silent_for);
+    assert_eq!(agent.liveness_deadline(), None, "reported once");
+    assert!(agent.read_event().is_pending());
+    drop(agent);
+    assert_eq!(daemon.join().unwrap(), 0);
+}
+
+#[test]
+fn partial_header_is_silence() {
+    let (ours, theirs) = LoopbackTransport::pair();
+    let mut daemon = Connection::daemon_over(theirs, Default::default());
+    let mut agent = Connection::agent_over(ours);
+    loop {
+        let _ = daemon.read_message();
+        match agent.read_event() {
+            Poll::Ready(Ok(Event::Reconnected(_))) => break,
+            Poll::Ready(e) => panic!("unexpected {:?}", e),
+            Poll::Pending => std::thread::sleep(Duration::from_millis(1)),
+        }
+    }
+    agent.set_liveness_timeout(Some(Duration::from_millis(50)));
+    let header = Header::for_message::<qubes_gui::Focus>(3.into());
+    let focus = qubes_gui::Focus::default();
+    daemon
+        .send_raw_bytes(&header.inner().as_bytes()[..5])
+        .unwrap();
+    let silent_for = loop {
+        match agent.read_event() {
+            Poll::Ready(Ok(Event::PeerUnresponsive { silent_for })) => break silent_for,
+            Poll::Ready(e) => panic!("unexpected {:?}", e),
+            Poll::Pending => std::thread::sleep(Duration::from_millis(1)),
+        }
+    };
+    assert!(silent_for >= Duration::from_millis(50));
+    assert_eq!(agent.liveness_deadline(), None, "reported once");
+
+    // The rest of the message is activity again
+    daemon
+        .send_raw_bytes(&header.inner().as_bytes()[5..])
+        .unwrap();
+    daemon.send_raw_bytes(focus.as_bytes()).unwrap();
+    match agent.read_event() {
+        Poll::Ready(Ok(Event::Message(m))) => assert_eq!(m.hdr(), header),
+        e => panic!("unexpected {:?}", e),
+    }
+    assert!(agent.read_event().is_pending());
+    assert!(agent.liveness_deadline().is_some());
+}
+
+#[test]
+fn unknown_messages_are_reported() {
+    let (ours, theirs) = LoopbackTransport::pair();
+    let mut daemon = Connection::daemon_over(theirs, Default::default());
+    let mut agent = Connection::agent_over(ours);
+    agent.set_report_unknown_messages(true);
+    loop {
+        let _ = daemon.read_message();
+        match agent.read_event() {
+            Poll::Ready(Ok(Event::Reconnected(_))) => break,
+            Poll::Ready(e) => panic!("unexpected {:?}", e),
+            Poll::Pending => std::thread::sleep(Duration::from_millis(1)),
+        }
+    }
+    let unknown = UntrustedHeader {
+        ty: 0x1234,
+        window: 5.into(),
+        untrusted_len: 3,
+    };
+    daemon.send_raw_bytes(unknown.as_bytes()).unwrap();
+    daemon.send_raw_bytes(b"abc").unwrap();
+    daemon
+        .send_raw(&[], 6.into(), qubes_gui::MSG_CLIPBOARD_REQ)
+        .unwrap();
+    let mut events = vec![];
+    while events.len() < 2 {
+        match agent.read_event() {
+            Poll::Ready(Ok(Event::UnknownMessage { ty, window, len })) => {
+                events.push((ty, window, len))
+            }
+            Poll::Ready(Ok(Event::Message(m))) => {
+                events.push((m.hdr().ty(), m.hdr().untrusted_window(), 0))
+            }
+            Poll::Ready(e) => panic!("unexpected {:?}", e),
+            Poll::Pending => std::thread::sleep(Duration::from_millis(1)),
+        }
+    }
+    assert_eq!(
+        events,
+        [
+            (0x1234, 5.into(), 3),
+            (qubes_gui::MSG_CLIPBOARD_REQ, 6.into(), 0)
+        ]
+    );
+    assert_eq!(agent.metrics().discarded_unknown(), 1);
+}
+
+#[test]
+fn deprecated_message_policy() {
+    use qubes_gui::DeprecatedPolicy;
+    let len = s!(qubes_gui::ShmCmd) as usize + 4;
+    let mfn_dump = UntrustedHeader {
+        ty: qubes_gui::MSG_MFNDUMP,
+        window: 5.into(),
+        untrusted_len: len as u32,
+    };
+    for policy in [
+        DeprecatedPolicy::AcceptLegacy,
+        DeprecatedPolicy::Ignore,
+        DeprecatedPolicy::Reject,
+    ] {
+        let (ours, theirs) = LoopbackTransport::pair();
+        let mut daemon = Connection::daemon_over(theirs, Default::default());
+        let mut agent = Connection::agent_over(ours);
+        let violations = Rc::new(RefCell::new(vec![]));
+        let sink = violations.clone();
+        daemon.set_violation_sink(move |v: &qubes_gui::Violation| sink.borrow_mut().push(v.kind));
+        daemon.set_deprecated_policy(policy);
+        loop {
+            let _ = daemon.read_message();
+            match agent.read_event() {
+                Poll::Ready(Ok(Event::Reconnected(_))) => break,
+                Poll::Ready(e) => panic!("unexpected {:?}", e),
+                Poll::Pending => std::thread::sleep(Duration::from_millis(1)),
+            }
+        }
+        agent.send_raw_bytes(mfn_dump.as_bytes()).unwrap();
+        agent.send_raw_bytes(&vec![0; len]).unwrap();
+        agent.send_agent(&qubes_gui::Unmap {}, 5.into()).unwrap();
+        let ty = loop {
+            match daemon.read_message() {
+                Poll::Ready(Ok(m)) => break Ok(m.hdr().ty()),
+                Poll::Ready(Err(e)) => break Err(e.kind()),
+                Poll::Pending => std::thread::sleep(Duration::from_millis(1)),
+            }
+        };
+        let expected = match policy {
+            DeprecatedPolicy::AcceptLegacy => (Ok(qubes_gui::MSG_MFNDUMP), vec![]),
+            DeprecatedPolicy::Ignore => (
+                Ok(qubes_gui::MSG_UNMAP),
+                vec![qubes_gui::ViolationKind::Deprecated],
+            ),
+            _ => (
+                Err(ErrorKind::InvalidData),
+                vec![qubes_gui::ViolationKind::Deprecated],
+            ),
+        };
+        assert_eq!((ty, violations.borrow().clone()), expected, "{:?}", policy);
+    }
+}
+
+#[test]
+fn send_checks_role() {
+    let (ours, theirs) = LoopbackTransport::pair();
+    let mut daemon = Connection::daemon_over(theirs, Default::default());
+    let mut agent = Connection::agent_over(ours);
+    let window = 5.into();
+    let configure = qubes_gui::Configure::default();
+    let err = daemon.send_agent(&configure, window).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    let err = agent.send_daemon(&configure, window).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    negotiate(&mut agent, &mut daemon);
+    agent.send_agent(&configure, window).unwrap();
+    daemon.send_daemon(&configure, window).unwrap();
+    for connection in [&mut daemon, &mut agent] {
+        loop {
+            match connection.read_message() {
+                Poll::Ready(Ok(m)) => break assert_eq!(m.hdr().ty(), qubes_gui::MSG_CONFIGURE),
+                Poll::Ready(Err(e)) => panic!("unexpected {:?}", e),
+                Poll::Pending => std::thread::sleep(Duration::from_millis(1)),
+            }
+        }
+    }
+}
+
+#[test]
+fn skipped_resize_violations() {
+    use qubes_gui::{DeprecatedPolicy, ViolationKind};
+    let resize = UntrustedHeader {
+        ty: qubes_gui::MSG_RESIZE,
+        window: 5.into(),
+        untrusted_len: 0,
+    };
+    for (policy, daemon_kinds, agent_kinds) in [
+        (
+            DeprecatedPolicy::AcceptLegacy,
+            vec![ViolationKind::UnknownMessage],
+            vec![],
+        ),
+        (
+            DeprecatedPolicy::Ignore,
+            vec![ViolationKind::Deprecated],
+            vec![ViolationKind::Deprecated],
+        ),
+    ] {
+        let (ours, theirs) = LoopbackTransport::pair();
+        let mut daemon = Connection::daemon_over(theirs, Default::default());
+        let mut agent = Connection::agent_over(ours);
+        let daemon_violations = Rc::new(RefCell::new(vec![]));
+        let agent_violations = Rc::new(RefCell::new(vec![]));
+        let sink = daemon_violations.clone();
+        daemon.set_violation_sink(move |v: &qubes_gui::Violation| sink.borrow_mut().push(v.kind));
+        let sink = agent_violations.clone();
+        agent.set_violation_sink(move |v: &qubes_gui::Violation| sink.borrow_mut().push(v.kind));
+        daemon.set_deprecated_policy(policy);
+        agent.set_deprecated_policy(policy);
+        loop {
+            let _ = daemon.read_message();
+            match agent.read_event() {
+                Poll::Ready(Ok(Event::Reconnected(_))) => break,
+                Poll::Ready(e) => panic!("unexpected {:?}", e),
+                Poll::Pending => std::thread::sleep(Duration::from_millis(1)),
+            }
+        }
+        agent.send_raw_bytes(resize.as_bytes()).unwrap();
+        agent.send_agent(&qubes_gui::Unmap {}, 5.into()).unwrap();
+        daemon.send_raw_bytes(resize.as_bytes()).unwrap();
+        daemon
+            .send_daemon(&qubes_gui::Focus::default(), 5.into())
+            .unwrap();
+        loop {
+            match daemon.read_message() {
+                Poll::Ready(Ok(m)) => break assert_eq!(m.hdr().ty(), qubes_gui::MSG_UNMAP),
+                Poll::Ready(Err(e)) => panic!("unexpected {:?}", e),
+                Poll::Pending => std::thread::sleep(Duration::from_millis(1)),
+            }
+        }
+        loop {
+            match agent.read_message() {
+                Poll::Ready(Ok(m)) => break assert_eq!(m.hdr().ty(), qubes_gui::MSG_FOCUS),
+                Poll::Ready(Err(e)) => panic!("unexpected {:?}", e),
+                Poll::Pending => std::thread::sleep(Duration::from_millis(1)),
+            }
+        }
+        assert_eq!(*daemon_violations.borrow(), daemon_kinds, "{:?}", policy);
+        assert_eq!(*agent_violations.borrow(), agent_kinds, "{:?}", policy);
+    }
+}
+
+#[test]
+fn legacy_dumps() {
+    let (ours, theirs) = LoopbackTransport::pair();
+    let mut daemon = Connection::daemon_over(theirs, Default::default());
+    daemon.set_deprecated_policy(qubes_gui::DeprecatedPolicy::AcceptLegacy);
+    let mut agent = crate::Agent::new(Connection::agent_over(ours));
+    loop {
+        let _ = daemon.read_message();
+        match agent.read_event() {
+            Poll::Ready(Ok(Event::Reconnected(_))) => break,
+            Poll::Ready(e) => panic!("unexpected {:?}", e),
+            Poll::Pending => std::thread::sleep(Duration::from_millis(1)),
+        }
+    }
+    let window = std::num::NonZeroU32::new(1).unwrap();
+    let size = qubes_gui::WindowSize {
+        width: 32,
+        height: 32,
+    };
+    let create = qubes_gui::Create {
+        rectangle: qubes_gui::Rectangle {
+            top_left: Default::default(),
+            size,
+        },
+        parent: None,
+        override_redirect: 0,
+    };
+    agent.create_window(window, &create).unwrap();
+    let err = agent.legacy_dump(window, size, 16, &[5]).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidInput, "too few MFNs");
+    agent.legacy_dump(window, size, 16, &[5, 6]).unwrap();
+    let body = loop {
+        match daemon.read_message() {
+            Poll::Ready(Ok(m)) if m.hdr().ty() == qubes_gui::MSG_MFNDUMP => {
+                break m.body().to_vec()
+            }
+            Poll::Ready(Ok(_)) => {}
+            Poll::Ready(Err(e)) => panic!("unexpected {:?}", e),
+            Poll::Pending => std::thread::sleep(Duration::from_millis(1)),
+        }
+    };
+    let (cmd, mfns) = body.split_at(s!(qubes_gui::ShmCmd) as usize);
+    let cmd = qubes_gui::ShmCmd::from_bytes(cmd).validate().unwrap();
+    assert_eq!(
+        (cmd.size().get(), cmd.offset(), cmd.num_mfn()),
+        (size, 16, 2)
+    );
+    assert_eq!(mfns, [5u32, 6].as_bytes());
+}
+
+#[test]
+fn dumps_wait_for_acknowledgement() {
+    let (ours, theirs) = LoopbackTransport::pair();
+    let mut daemon = Connection::daemon_over(theirs, Default::default());
+    let mut agent = Connection::agent_over(ours);
+    loop {
+        let _ = daemon.read_message();
+        match agent.read_event() {
+            Poll::Ready(Ok(Event::Reconnected(_))) => break,
+            Poll::Ready(e) => panic!("unexpected {:?}", e),
+            Poll::Pending => std::thread::sleep(Duration::from_millis(1)),
+        }
+    }
+    let window = std::num::NonZeroU32::new(5).unwrap();
+    let dump = OutgoingMessage::WindowDump {
+        header: qubes_gui::WindowDumpHeader {
+            ty: qubes_gui::WINDOW_DUMP_TYPE_GRANT_REFS,
+            width: 1,
+            height: 1,
+            bpp: 24,
+        },
+        grant_refs: vec![7],
+    };
+    let acked = Rc::new(Cell::new(0));
+    agent.set_dump_limit(Some(2));
+    for _ in 0..2 {
+        agent.send_message(&dump, window.into()).unwrap();
+        let acked = acked.clone();
+        agent.on_dumps_acked(window, move || acked.set(acked.get() + 1));
+    }
+    let err = agent.send_message(&dump, window.into()).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::WouldBlock);
+    assert_eq!(agent.outstanding_dumps(Some(window)), 2);
+
+    daemon
+        .send_message(&OutgoingMessage::DumpAck, window.into())
+        .unwrap();
+    loop {
+        match agent.read_event() {
+            Poll::Ready(Ok(Event::Message(m))) => {
+                assert_eq!(m.hdr().ty(), qubes_gui::MSG_WINDOW_DUMP_ACK);
+                break;
+            }
+            Poll::Ready(e) => panic!("unexpected {:?}", e),
+            Poll::Pending => std::thread::sleep(Duration::from_millis(1)),
+        }
+    }
+    assert_eq!(acked.get(), 1);
+    assert_eq!(agent.outstanding_dumps(None), 1);
+    agent.send_message(&dump, window.into()).unwrap();
+
+    assert!(agent.reject_extension(Extension::DumpAck));
+    assert_eq!(acked.get(), 2, "no acknowledgement is coming");
+    assert_eq!(agent.outstanding_dumps(None), 0);
+    let counter = acked.clone();
+    agent.on_dumps_acked(window, move || counter.set(counter.get() + 1));
+    assert_eq!(acked.get(), 3, "nothing to wait for");
+}
+
+#[test]
+fn agent_enumerates_windows() {
+    let (ours, theirs) = LoopbackTransport::pair();
+    let mut daemon = Connection::daemon_over(theirs, Default::default());
+    let mut agent = crate::Agent::new(Connection::agent_over(ours));
+    loop {
+        let _ = daemon.read_message();
+        match agent.read_event() {
+            Poll::Ready(Ok(Event::Reconnected(_))) => break,
+            Poll::Ready(e) => panic!("unexpected {:?}", e),
+            Poll::Pending => std::thread::sleep(Duration::from_millis(1)),
+        }
+    }
+    let id = |n| std::num::NonZeroU32::new(n).unwrap();
+    let (toplevel, child) = (id(5), id(2));
+    let create = qubes_gui::Create {
+        rectangle: qubes_gui::Rectangle {
+            top_left: qubes_gui::Coordinates { x: 10, y: -20 },
+            size: qubes_gui::WindowSize {
+                width: 300,
+                height: 200,
+            },
+        },
+        parent: None,
+        override_redirect: 0,
+    };
+    assert_eq!(agent.windows().count(), 0);
+    agent.create_window(toplevel, &create).unwrap();
+    let create_child = qubes_gui::Create {
+        parent: Some(toplevel),
+        override_redirect: 1,
+        ..create
+    };
+    agent.create_window(child, &create_child).unwrap();
+    let ids: Vec<u32> = agent.windows().map(|w| w.id.get()).collect();
+    assert_eq!(ids, [2, 5], "in order of window ID");
+
+    let map_info = qubes_gui::MapInfo {
+        transient_for: 0,
+        override_redirect: 0,
+    };
+    agent.map(toplevel, &map_info).unwrap();
+    agent.set_title(toplevel, "secret").unwrap();
+    let configure = qubes_gui::Configure {
+        rectangle: qubes_gui::Rectangle {
+            top_left: qubes_gui::Coordinates { x: 0, y: 0 },
+            ..create.rectangle
+        },
+        override_redirect: 0,
+    };
+    agent.configure(toplevel, &configure).unwrap();
+    let info = agent.window(toplevel).unwrap();
+    assert!(info.mapped && !info.override_redirect);
+    assert_eq!(info.rectangle, configure.rectangle);
+    assert_eq!(info.title, "secret");
+    assert!(!format!("{:?}", info).contains("secret"));
+    assert_eq!(
+        info.pending,
+        qubes_gui::PendingOps {
+            configure: true,
+            ..Default::default()
+        }
+    );
+    let info = agent.window(child).unwrap();
+    assert_eq!(info.parent, Some(toplevel));
+    assert!(info.override_redirect && !info.mapped);
+    assert_eq!(
+        (info.popup, info.dialog_owner, info.hints),
+        (false, None, None)
+    );
+
+    let dump = OutgoingMessage::WindowDump {
+        header: qubes_gui::WindowDumpHeader {
+            ty: qubes_gui::WINDOW_DUMP_TYPE_GRANT_REFS,
+            width: 1,
+            height: 1,
+            bpp: 24,
+        },
+        grant_refs: vec![7],
+    };
+    agent
+        .connection()
+        .send_message(&dump, toplevel.into())
+        .unwrap();
+    assert!(agent.window(toplevel).unwrap().pending.dump_ack);
+    assert!(!agent.window(child).unwrap().pending.dump_ack);
+    daemon
+        .send_raw(&[], toplevel.into(), qubes_gui::MSG_CLOSE)
+        .unwrap();
+    loop {
+        let _ = daemon.read_message();
+        match agent.read_event() {
+            Poll::Ready(Ok(Event::Message(m))) => {
+                break assert_eq!(m.hdr().ty(), qubes_gui::MSG_CLOSE)
+            }
+            Poll::Ready(e) => panic!("unexpected {:?}", e),
+            Poll::Pending => std::thread::sleep(Duration::from_millis(1)),
+        }
+    }
+    assert!(agent.window(toplevel).unwrap().pending.close_requested);
+
+    agent.destroy(child).unwrap();
+    assert!(agent.window(child).is_none());
+    let ids: Vec<u32> = agent.windows().map(|w| w.id.get()).collect();
+    assert_eq!(ids, [5]);
+}
+
+#[test]
+fn popups() {
+    let (ours, theirs) = LoopbackTransport::pair();
+    let mut daemon = Connection::daemon_over(theirs, Default::default());
+    let mut agent = crate::Agent::new(Connection::agent_over(ours));
+    loop {
+        let _ = daemon.read_message();
+        match agent.read_event() {
+            Poll::Ready(Ok(Event::Reconnected(_))) => break,
+            Poll::Ready(e) => panic!("unexpected {:?}", e),
+            Poll::Pending => std::thread::sleep(Duration::from_millis(1)),
+        }
+    }
+    let id = |n| std::num::NonZeroU32::new(n).unwrap();
+    let (parent, menu, tooltip) = (id(1), id(2), id(3));
+    let rectangle = |x, y| qubes_gui::Rectangle {
+        top_left: qubes_gui::Coordinates { x, y },
+        size: qubes_gui::WindowSize {
+            width: 10,
+            height: 10,
+        },
+    };
+    let err = agent
+        .create_popup(menu, parent, rectangle(0, 0))
+        .unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::NotFound);
+    let create = qubes_gui::Create {
+        rectangle: rectangle(100, 50),
+        parent: None,
+        override_redirect: 0,
+    };
+    agent.create_window(parent, &create).unwrap();
+    agent.create_popup(menu, parent, rectangle(10, 20)).unwrap();
+    let err = agent.map_popup(menu).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidInput, "parent not mapped");
+    let info = qubes_gui::MapInfo {
+        transient_for: 0,
+        override_redirect: 0,
+    };
+    agent.map(parent, &info).unwrap();
+    agent.map_popup(menu).unwrap();
+    agent
+        .create_popup(tooltip, parent, rectangle(-5, 0))
+        .unwrap();
+    assert_eq!(agent.window(tooltip).unwrap().rectangle, rectangle(95, 50));
+    let err = agent.map_popup(parent).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidInput, "not a popup");
+
+    let received = |daemon: &mut Connection, count: usize| {
+        let mut messages = vec![];
+        while messages.len() < count {
+            match daemon.read_message() {
+                Poll::Ready(Ok(m)) => {
+                    messages.push((m.hdr().ty(), m.hdr().untrusted_window(), m.body().to_vec()))
+                }
+                Poll::Ready(Err(e)) => panic!("unexpected {:?}", e),
+                Poll::Pending => std::thread::sleep(Duration::from_millis(1)),
+            }
+        }
+        messages
+    };
+    let messages = received(&mut daemon, 5);
+    let (ty, window, body) = &messages[1];
+    assert_eq!((*ty, *window), (qubes_gui::MSG_CREATE, menu.into()));
+    let create = qubes_gui::Create::from_bytes(body);
+    assert_eq!(create.rectangle, rectangle(110, 70));
+    assert_eq!((create.parent, create.override_redirect), (Some(parent), 1));
+    let (ty, window, body) = &messages[3];
+    assert_eq!((*ty, *window), (qubes_gui::MSG_MAP, menu.into()));
+    assert_eq!(qubes_gui::MapInfo::from_bytes(body).transient_for, 1);
+
+    daemon
+        .send_message(&OutgoingMessage::Close, menu.into())
+        .unwrap();
+    loop {
+        match agent.read_event() {
+            Poll::Ready(Ok(Event::Message(m))) => {
+                assert_eq!(m.hdr().ty(), qubes_gui::MSG_CLOSE);
+                break;
+            }
+            Poll::Ready(e) => panic!("unexpected {:?}", e),
+            Poll::Pending => std::thread::sleep(Duration::from_millis(1)),
+        }
+    }
+    assert!(agent.read_event().is_pending());
+    assert!(agent.window(menu).is_none(), "destroyed on close");
+    agent.destroy(parent).unwrap();
+    let destroyed: Vec<_> = received(&mut daemon, 3)
+        .into_iter()
+        .map(|(ty, window, _)| (ty, window))
+        .collect();
+    assert_eq!(
+        destroyed,
+        [
+            (qubes_gui::MSG_DESTROY, menu.into()),
+            (qubes_gui::MSG_DESTROY, tooltip.into()),
+            (qubes_gui::MSG_DESTROY, parent.into()),
+        ]
+    );
+}
+
+#[test]
+fn modal_dialogs() {
+    let (ours, theirs) = LoopbackTransport::pair();
+    let mut daemon = Connection::daemon_over(theirs, Default::default());
+    let mut agent = crate::Agent::new(Connection::agent_over(ours));
+    fn next_event(daemon: &mut Connection, agent: &mut crate::Agent) -> u32 {
+        loop {
+            let _ = daemon.read_message();
+            match agent.read_event() {
+                Poll::Ready(Ok(Event::Message(m))) => break m.hdr().ty(),
+                Poll::Ready(Ok(Event::Reconnected(_))) => break 0,
+                Poll::Ready(e) => panic!("unexpected {:?}", e),
+                Poll::Pending => std::thread::sleep(Duration::from_millis(1)),
+            }
+        }
+    }
+    assert_eq!(next_event(&mut daemon, &mut agent), 0);
+    let id = |n| std::num::NonZeroU32::new(n).unwrap();
+    let (owner, dialog) = (id(1), id(2));
+    let create = qubes_gui::Create {
+        rectangle: qubes_gui::Rectangle {
+            top_left: qubes_gui::Coordinates { x: 100, y: 50 },
+            size: qubes_gui::WindowSize {
+                width: 400,
+                height: 300,
+            },
+        },
+        parent: None,
+        override_redirect: 0,
+    };
+    agent.create_window(owner, &create).unwrap();
+    let size = qubes_gui::WindowSize {
+        width: 200,
+        height: 100,
+    };
+    agent.create_dialog(dialog, owner, size, true).unwrap();
+    let info = agent.window(dialog).unwrap();
+    assert_eq!(
+        info.rectangle.top_left,
+        qubes_gui::Coordinates { x: 200, y: 150 }
+    );
+    assert_eq!(info.dialog_owner, Some(owner));
+    let err = agent.map_dialog(dialog).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidInput, "owner not mapped");
+    let info = qubes_gui::MapInfo {
+        transient_for: 0,
+        override_redirect: 0,
+    };
+    agent.map(owner, &info).unwrap();
+    assert_eq!(agent.modal_dialog(owner), None);
+    agent.map_dialog(dialog).unwrap();
+    assert_eq!(agent.modal_dialog(owner), Some(dialog));
+
+    let attention = qubes_gui::WindowFlag::DemandsAttention as u32;
+    let focus = |daemon: &mut Connection, window: std::num::NonZeroU32| {
+        let focus = qubes_gui::Focus {
+            ty: qubes_gui::EV_FOCUS_IN,
+            mode: 0,
+            detail: 0,
+        };
+        daemon.send_daemon(&focus, window.into()).unwrap()
+    };
+    focus(&mut daemon, owner);
+    assert_eq!(next_event(&mut daemon, &mut agent), qubes_gui::MSG_FOCUS);
+    assert!(agent.read_event().is_pending());
+    assert_eq!(agent.window(dialog).unwrap().flags, attention);
+    focus(&mut daemon, dialog);
+    assert_eq!(next_event(&mut daemon, &mut agent), qubes_gui::MSG_FOCUS);
+    assert!(agent.read_event().is_pending());
+    assert_eq!(agent.window(dialog).unwrap().flags, 0);
+
+    daemon
+        .send_message(&OutgoingMessage::Close, dialog.into())
+        .unwrap();
+    assert_eq!(next_event(&mut daemon, &mut agent), qubes_gui::MSG_CLOSE);
+    assert!(agent.read_event().is_pending());
+    assert!(
+        agent.window(dialog).is_some(),
+        "closing is up to the application"
+    );
+    agent.destroy(owner).unwrap();
+    assert!(agent.window(dialog).is_none());
+}
+
+#[test]
+fn screen_changes() {
+    let (ours, theirs) = LoopbackTransport::pair();
+    let mut daemon = Connection::daemon_over(theirs, Default::default());
+    let mut agent = crate::Agent::new(Connection::agent_over(ours));
+    let seen = Rc::new(RefCell::new(vec![]));
+    let hook = seen.clone();
+    agent.on_screen_changed(move |xconf| hook.borrow_mut().push(xconf.size));
+    let mut next_event = |agent: &mut crate::Agent| loop {
+        let _ = daemon.read_message();
+        match agent.read_event() {
+            Poll::Ready(Ok(Event::Reconnected(_))) => break None,
+            Poll::Ready(Ok(Event::ScreenChanged(xconf))) => break Some(xconf),
+            Poll::Ready(e) => panic!("unexpected {:?}", e),
+            Poll::Pending => std::thread::sleep(Duration::from_millis(1)),
+        }
+    };
+    assert_eq!(next_event(&mut agent), None, "first connection");
+    let xconf = qubes_gui::XConf {
+        size: qubes_gui::WindowSize {
+            width: 3840,
+            height: 2160,
+        },
+        depth: 24,
+        mem: 32400,
+    };
+    // As if a new daemon, with a bigger screen, had been negotiated with
+    let raw = &mut agent.connection().raw;
+    raw.xconf.xconf = xconf;
+    raw.did_reconnect = true;
+    assert_eq!(next_event(&mut agent), None);
+    assert_eq!(next_event(&mut agent), Some(xconf));
+    assert_eq!(*seen.borrow(), [xconf.size]);
+
+    let smaller = qubes_gui::XConf {
+        size: qubes_gui::WindowSize {
+            width: 1920,
+            height: 1080,
+        },
+        ..xconf
+    };
+    assert!(!daemon.set_screen(smaller).unwrap(), "protocol 1.7");
+    assert_eq!(daemon.xconf().xconf, smaller);
+    assert!(agent.connection().set_screen(smaller).is_err());
+}
+
+#[test]
+#[cfg(feature = "proposed")]
+fn screen_configured_messages() {
+    use qubes_gui::proposed::MSG_SCREEN_CONFIGURED;
+    let smaller = qubes_gui::XConf {
+        size: qubes_gui::WindowSize {
+            width: 1920,
+            height: 1080,
+        },
+        ..Default::default()
+    };
+    let read_types = |agent: &mut Connection, count| {
+        let mut types = vec![];
+        while types.len() < count {
+            match agent.read_event() {
+                Poll::Ready(Ok(Event::ScreenChanged(new))) => {
+                    assert_eq!(new, smaller);
+                    types.push(MSG_SCREEN_CONFIGURED)
+                }
+                Poll::Ready(Ok(Event::Message(m))) => types.push(m.hdr().ty()),
+                Poll::Ready(Ok(Event::Proposed { ty, .. })) => types.push(ty),
+                Poll::Ready(e) => panic!("unexpected {:?}", e),
+                Poll::Pending => std::thread::sleep(Duration::from_millis(1)),
+            }
+        }
+        types
+    };
+
+    // Without the proposed version, the message is unknown and ignored
+    let (mut agent, mut daemon) = propose(false, true);
+    assert!(!daemon.extensions().contains(Extension::ScreenConfig));
+    assert!(!daemon.set_screen(smaller).unwrap());
+    let header = UntrustedHeader {
+        ty: MSG_SCREEN_CONFIGURED,
+        window: 0.into(),
+        untrusted_len: size_of::<qubes_gui::XConf>() as u32,
+    };
+    daemon.send_raw_bytes(header.as_bytes()).unwrap();
+    daemon.send_raw_bytes(smaller.as_bytes()).unwrap();
+    daemon
+        .send_raw(&[], 6.into(), qubes_gui::MSG_CLIPBOARD_REQ)
+        .unwrap();
+    assert_eq!(read_types(&mut agent, 1), [qubes_gui::MSG_CLIPBOARD_REQ]);
+    assert_ne!(agent.xconf().xconf, smaller);
+
+    let (mut agent, mut daemon) = propose(true, true);
+    assert!(agent.extensions().contains(Extension::ScreenConfig));
+    assert!(daemon.extensions().contains(Extension::ScreenConfig));
+    for _ in 0..2 {
+        assert!(daemon.set_screen(smaller).unwrap());
+    }
+    daemon
+        .send_raw(&[], 6.into(), qubes_gui::MSG_CLIPBOARD_REQ)
+        .unwrap();
+    assert_eq!(
+        read_types(&mut agent, 2),
+        [MSG_SCREEN_CONFIGURED, qubes_gui::MSG_CLIPBOARD_REQ],
+        "unchanged screen not reported twice"
+    );
+    assert_eq!(agent.xconf().xconf, smaller);
+
+    // Once rejected, it is reported like any other proposed message
+    assert!(agent.reject_extension(Extension::ScreenConfig));
+    match agent.read_event() {
+        Poll::Ready(Ok(Event::Downgraded(Extension::ScreenConfig))) => {}
+        e => panic!("unexpected {:?}", e),
+    }
+    assert!(daemon.set_screen(smaller).unwrap());
+    assert_eq!(read_types(&mut agent, 1), [MSG_SCREEN_CONFIGURED]);
+}
+
+#[test]
+fn debug_omits_data() {
+    let secret = b"hunter2 from the clipboard";
+    let (ours, theirs) = LoopbackTransport::pair();
+    let mut daemon = Connection::daemon_over(theirs, Default::default());
+    let mut agent = Connection::agent_over(ours);
+    loop {
+        let _ = daemon.read_message();
+        match agent.read_event() {
+            Poll::Ready(Ok(Event::Reconnected(_))) => break,
+            Poll::Ready(e) => panic!("unexpected {:?}", e),
+            Poll::Pending => std::thread::sleep(Duration::from_millis(1)),
+        }
+    }
+    // In the peer's pipe, in the send queue, and in the read buffer
+    let header = Header::with_len(qubes_gui::Msg::ClipboardData, 0.into(), secret.len()).unwrap();
+    agent.send_with_header(header, secret).unwrap();
+    agent.raw.queue.extend(secret.iter());
+    daemon.raw.buffer.extend_from_slice(secret);
+    let mut replay = ReplayTransport::default();
+    replay.send(secret).unwrap();
+    let bytes = format!("{:?}", &secret[..]);
+    let bytes = &bytes[1..bytes.len() - 1];
+    for debug in [
+        format!("{:?}", agent),
+        format!("{:?}", daemon),
+        format!("{:?}", replay),
+    ] {
+        assert!(!debug.contains("hunter2"), "{}", debug);
+        assert!(!debug.contains(bytes), "{}", debug);
+    }
+}
+
+/// A [`LoopbackTransport`] that reconnects to the next transport sent to
+/// `peers`
+#[derive(Debug)]
+struct Redialing {
+    current: LoopbackTransport,
+    peers: std::sync::mpsc::Receiver<LoopbackTransport>,
+}
+
+impl Transport for Redialing {
+    fn status(&self) -> vchan::Status {
+        self.current.status()
+    }
+    fn data_ready(&self) -> usize {
+        self.current.data_ready()
+    }
+    fn buffer_space(&self) -> usize {
+        self.current.buffer_space()
+    }
+    fn send(&mut self, buffer: &[u8]) -> Result<(), vchan::Error> {
+        self.current.send(buffer)
+    }
+    fn recv(&mut self, buffer: &mut [u8]) -> Result<(), vchan::Error> {
+        self.current.recv(buffer)
+    }
+    fn fd(&self) -> std::os::raw::c_int {
+        -1
+    }
+    fn reconnect(&mut self) -> Result<(), vchan::Error> {
+        self.current = self
+            .peers
+            .try_recv()
+            .map_err(|_| vchan::Error::CannotListen)?;
+        Ok(())
+    }
+}
+
+/// Drive both ends until each has reported [`Event::Reconnected`]
+fn negotiate(a: &mut Connection, b: &mut Connection) {
+    let (mut a_done, mut b_done) = (false, false);
+    while !(a_done && b_done) {
+        for (connection, done) in [(&mut *a, &mut a_done), (&mut *b, &mut b_done)] {
+            match connection.read_event() {
+                Poll::Ready(Ok(Event::Reconnected(_))) => *done = true,
+                Poll::Ready(e) => panic!("unexpected {:?}", e),
+                Poll::Pending => {}
+            }
+        }
+        std::thread::sleep(Duration::from_millis(1))
+    }
+}
+
+/// Connect a fresh peer after the first one goes away, and check that the
+/// end that reconnects keeps its role.
+fn reconnect_over_loopback(daemon_reconnects: bool) {
+    let (sender, peers) = std::sync::mpsc::channel();
+    let connect = |transport| {
+        if daemon_reconnects {
+            Connection::agent_over(transport)
+        } else {
+            Connection::daemon_over(transport, Default::default())
+        }
+    };
+    let (ours, theirs) = LoopbackTransport::pair();
+    let current = Redialing {
+        current: ours,
+        peers,
+    };
+    let mut redialer = if daemon_reconnects {
+        Connection::daemon_over(current, Default::default())
+    } else {
+        Connection::agent_over(current)
+    };
+    redialer.set_reconnect_policy(Some(
+        ReconnectPolicy::new().initial_backoff(Duration::from_millis(1)),
+    ));
+    let mut peer = connect(theirs);
+    negotiate(&mut redialer, &mut peer);
+    drop(peer);
+    let (ours, theirs) = LoopbackTransport::pair();
+    sender.send(ours).unwrap();
+    let mut peer = connect(theirs);
+    negotiate(&mut redialer, &mut peer);
+    let ty = if daemon_reconnects {
+        qubes_gui::MSG_MAP
+    } else {
+        qubes_gui::MSG_CLIPBOARD_REQ
+    };
+    let map_info = qubes_gui::MapInfo::default();
+    let body: &[u8] = if daemon_reconnects {
+        map_info.as_bytes()
+    } else {
+        &[]
+    };
+    peer.send_raw(body, 1.into(), ty).unwrap();
+    loop {
+        match redialer.read_event() {
+            Poll::Ready(Ok(Event::Message(buffer))) => break assert_eq!(buffer.hdr().ty(), ty),
+            Poll::Ready(e) => panic!("unexpected {:?}", e),
+            Poll::Pending => std::thread::sleep(Duration::from_millis(1)),
+        }
+    }
+}
+
+#[test]
+fn agent_reconnects_over_loopback() {
+    reconnect_over_loopback(false)
+}
+
+#[test]
+fn daemon_reconnects_over_loopback() {
+    reconnect_over_loopback(true)
+}
+
+#[test]
+fn failed_replay_still_reports_reconnection() {
+    let (ours, theirs) = LoopbackTransport::pair();
+    let mut daemon = Connection::daemon_over(theirs, Default::default());
+    let mut agent = Connection::agent_over(ours);
+    let fail = |_: &mut Connection| Err(Error::other("replay failed"));
+    loop {
+        let _ = daemon.read_message();
+        match agent.read_event_with(fail, |_| true) {
+            Poll::Ready(Ok(Event::Reconnected(_))) => break,
+            Poll::Ready(e) => panic!("unexpected {:?}", e),
+            Poll::Pending => std::thread::sleep(Duration::from_millis(1)),
+        }
+    }
+    match agent.read_event() {
+        Poll::Ready(Err(e)) => assert_eq!(e.to_string(), "replay failed"),
+        other => panic!("unexpected {:?}", other),
+    }
+    assert!(agent.read_event().is_pending());
+}
+
+#[cfg(feature = "proposed")]
+fn propose(agent_opts_in: bool, daemon_opts_in: bool) -> (Connection, Connection) {
+    let (ours, theirs) = LoopbackTransport::pair();
+    let mut daemon = Connection::daemon_over(theirs, Default::default());
+    daemon.set_propose(daemon_opts_in);
+    let scale = qubes_gui::proposed::ValidScale::new(180).unwrap();
+    daemon.set_scale_offer(scale, qubes_gui::proposed::FEATURE_ARGB32);
+    let mut agent = Connection::agent_over(ours);
+    agent.set_propose(agent_opts_in);
+    loop {
+        let _ = daemon.read_message();
+        match agent.read_event() {
+            Poll::Ready(Ok(Event::Reconnected(_))) => break,
+            Poll::Ready(e) => panic!("unexpected {:?}", e),
+            Poll::Pending => std::thread::sleep(Duration::from_millis(1)),
+        }
+    }
+    match daemon.read_event() {
+        Poll::Ready(Ok(Event::Reconnected(_))) => {}
+        e => panic!("unexpected {:?}", e),
+    }
+    (agent, daemon)
+}
+
+#[test]
+#[cfg(feature = "proposed")]
+fn proposed_version_is_opt_in() {
+    use qubes_gui::proposed::{FEATURE_ARGB32, PROPOSED_VERSION};
+    for &(agent_opts_in, daemon_opts_in) in [(false, true), (true, false)].iter() {
+        let (agent, daemon) = propose(agent_opts_in, daemon_opts_in);
+        assert_eq!(agent.xconf().version, qubes_gui::PROTOCOL_VERSION);
+        assert_eq!(daemon.xconf().version, qubes_gui::PROTOCOL_VERSION);
+        assert_eq!((agent.xconf_scale(), daemon.xconf_scale()), (None, None));
+    }
+    let (mut agent, mut daemon) = propose(true, true);
+    assert_eq!(agent.xconf().version, PROPOSED_VERSION);
+    let scale = agent.xconf_scale().expect("negotiated");
+    assert_eq!((scale.default_scale, scale.features), (180, FEATURE_ARGB32));
+    assert_eq!(daemon.xconf_scale(), Some(scale));
+
+    // Proposed messages are delivered with their bodies, and unknown ones
+    // are still skipped
+    let layout = qubes_gui::proposed::KeyboardLayout::default();
+    let ty = qubes_gui::proposed::MSG_KEYBOARD_LAYOUT;
+    daemon
+        .send_proposed(ty, 0.into(), layout.as_bytes())
+        .unwrap();
+    daemon
+        .send_proposed(qubes_gui::proposed::MSG_CLIPBOARD_END, 7.into(), &[])
+        .unwrap();
+    let unknown = UntrustedHeader {
+        ty: 0x1234,
+        window: 5.into(),
+        untrusted_len: 3,
+    };
+    daemon.send_raw_bytes(unknown.as_bytes()).unwrap();
+    daemon.send_raw_bytes(b"abc").unwrap();
+    assert!(
+        daemon.send_proposed(ty, 0.into(), &[]).is_err(),
+        "bad length"
+    );
+    assert!(
+        daemon.send_proposed(0x1234, 0.into(), &[]).is_err(),
+        "not proposed"
+    );
+    daemon
+        .send_raw(&[], 6.into(), qubes_gui::MSG_CLIPBOARD_REQ)
+        .unwrap();
+    let mut events = vec![];
+    while events.len() < 3 {
+        match agent.read_event() {
+            Poll::Ready(Ok(Event::Proposed {
+                ty,
+                window,
+                untrusted_body,
+            })) => events.push((ty, window, untrusted_body.len())),
+            Poll::Ready(Ok(Event::Message(m))) => {
+                events.push((m.hdr().ty(), m.hdr().untrusted_window(), 0))
+            }
+            Poll::Ready(e) => panic!("unexpected {:?}", e),
+            Poll::Pending => std::thread::sleep(Duration::from_millis(1)),
+        }
+    }
+    assert_eq!(
+        events,
+        [
+            (
+                ty,
+                0.into(),
+                size_of::<qubes_gui::proposed::KeyboardLayout>()
+            ),
+            (qubes_gui::proposed::MSG_CLIPBOARD_END, 7.into(), 0),
+            (qubes_gui::MSG_CLIPBOARD_REQ, 6.into(), 0),
+        ]
+    );
+
+    // A proposed message with the wrong length is fatal
+    let bad = UntrustedHeader {
+        ty,
+        window: 0.into(),
+        untrusted_len: 1,
+    };
+    daemon.send_raw_bytes(bad.as_bytes()).unwrap();
+    daemon.send_raw_bytes(b"x").unwrap();
+    let err = loop {
+        match agent.read_event() {
+            Poll::Ready(Err(e)) => break e,
+            Poll::Ready(Ok(e)) => panic!("unexpected {:?}", e),
+            Poll::Pending => std::thread::sleep(Duration::from_millis(1)),
+        }
+    };
+    assert_eq!(err.kind(), ErrorKind::InvalidData);
+}
+
+#[test]
+#[cfg(feature = "proposed")]
+fn bad_xconf_scale() {
+    let (ours, mut theirs) = LoopbackTransport::pair();
+    let mut agent = Connection::agent_over(ours);
+    agent.set_propose(true);
+    assert!(agent.read_event().is_pending());
+    let scale = qubes_gui::proposed::XConfScale {
+        xconf: qubes_gui::XConfVersion {
+            version: qubes_gui::proposed::PROPOSED_VERSION,
+            xconf: Default::default(),
+        },
+        default_scale: 0,
+        features: 0,
+    };
+    // The version alone is not enough to go on
+    let bytes = scale.as_bytes();
+    theirs.send(&bytes[..bytes.len() - 4]).unwrap();
+    assert!(agent.read_event().is_pending());
+    theirs.send(&bytes[bytes.len() - 4..]).unwrap();
+    match agent.read_event() {
+        Poll::Ready(Err(e)) => assert_eq!(e.kind(), ErrorKind::InvalidData),
+        other => panic!("unexpected {:?}", other),
+    }
+}
@@ -0,0 +1,497 @@
+/*
+ * The Qubes OS Project, https://www.qubes-os.org
+ *
+ * Copyright (C) 2021  Demi Marie Obenour  <demi@invisiblethingslab.com>
+ *
+ * This program is free software; you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License
+ * as published by the Free Software Foundation; either version 2
+ * of the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301, USA.
+ *
+ */
+
+//! The byte streams a [`Connection`](crate::Connection) runs over.
+//!
+//! Normally this is a vchan, but anything implementing [`Transport`] can be
+//! used instead, for instance to record or replay traffic.
+
+use std::collections::VecDeque;
+#[cfg(unix)]
+use std::io::{self, Read, Write};
+use std::os::raw::c_int;
+#[cfg(unix)]
+use std::os::unix::{io::AsRawFd, net::UnixStream};
+use std::sync::{Arc, Mutex};
+use vchan::{Error, Status, Vchan};
+
+/// A reliable, ordered byte stream with vchan-like semantics.
+///
+/// Reads and writes never block as long as the caller stays within
+/// [`Transport::data_ready`] and [`Transport::buffer_space`]; the connection
+/// always does.
+pub trait Transport: std::fmt::Debug {
+    /// The status of the stream
+    fn status(&self) -> Status;
+    /// The number of bytes that can be read without blocking
+    fn data_ready(&self) -> usize;
+    /// The number of bytes that can be written without blocking
+    fn buffer_space(&self) -> usize;
+    /// Write all of `buffer`
+    fn send(&mut self, buffer: &[u8]) -> Result<(), Error>;
+    /// Write all of `buffers`, in order.  Transports that can submit them
+    /// together (such as a message header and its body) should override
+    /// this.
+    fn send_vectored(&mut self, buffers: &[&[u8]]) -> Result<(), Error> {
+        buffers.iter().try_for_each(|buffer| self.send(buffer))
+    }
+    /// Fill all of `buffer`
+    fn recv(&mut self, buffer: &mut [u8]) -> Result<(), Error>;
+    /// Append `bytes` bytes to `buffer`
+    fn recv_into(&mut self, buffer: &mut Vec<u8>, bytes: usize) -> Result<(), Error> {
+        buffer.try_reserve(bytes).map_err(Error::OutOfMemory)?;
+        let len = buffer.len();
+        buffer.resize(len + bytes, 0);
+        self.recv(&mut buffer[len..])
+    }
+    /// Read and throw away `bytes` bytes
+    fn discard(&mut self, mut bytes: usize) -> Result<(), Error> {
+        let mut buf = [0u8; 256];
+        while bytes > 0 {
+            let to_read = buf.len().min(bytes);
+            self.recv(&mut buf[..to_read])?;
+            bytes -= to_read;
+        }
+        Ok(())
+    }
+    /// Acknowledge an event on [`Transport::fd`]
+    fn wait(&mut self) {}
+    /// Try to write out data that the transport itself has buffered, without
+    /// blocking.  Returns `true` once all data sent has been handed to the
+    /// peer.  Transports that do not buffer need not override this.
+    fn flush(&mut self) -> Result<bool, Error> {
+        Ok(true)
+    }
+    /// Whether data the transport has buffered is waiting for
+    /// [`Transport::fd`] to become writable.  If so, callers should also wait
+    /// for that and then call [`Transport::wait`].  Transports whose file
+    /// descriptor becomes readable when there is room to write need not
+    /// override this.
+    fn wants_writable(&self) -> bool {
+        false
+    }
+    /// A file descriptor that becomes readable when there is something to
+    /// do, or -1 if there is none
+    fn fd(&self) -> c_int;
+    /// Re-establish the stream after the peer has gone away, in the same
+    /// role as before.  Only transports used with a
+    /// [`ReconnectPolicy`](crate::ReconnectPolicy) or
+    /// [`Connection::reconnect`](crate::Connection::reconnect) need to
+    /// support this.  The default implementation always fails.
+    fn reconnect(&mut self) -> Result<(), Error> {
+        Err(Error::CannotListen)
+    }
+}
+
+impl<T: Transport + ?Sized> Transport for Box<T> {
+    fn status(&self) -> Status {
+        (**self).status()
+    }
+    fn data_ready(&self) -> usize {
+        (**self).data_ready()
+    }
+    fn buffer_space(&self) -> usize {
+        (**self).buffer_space()
+    }
+    fn send(&mut self, buffer: &[u8]) -> Result<(), Error> {
+        (**self).send(buffer)
+    }
+    fn send_vectored(&mut self, buffers: &[&[u8]]) -> Result<(), Error> {
+        (**self).send_vectored(buffers)
+    }
+    fn recv(&mut self, buffer: &mut [u8]) -> Result<(), Error> {
+        (**self).recv(buffer)
+    }
+    fn recv_into(&mut self, buffer: &mut Vec<u8>, bytes: usize) -> Result<(), Error> {
+        (**self).recv_into(buffer, bytes)
+    }
+    fn discard(&mut self, bytes: usize) -> Result<(), Error> {
+        (**self).discard(bytes)
+    }
+    fn wait(&mut self) {
+        (**self).wait()
+    }
+    fn flush(&mut self) -> Result<bool, Error> {
+        (**self).flush()
+    }
+    fn wants_writable(&self) -> bool {
+        (**self).wants_writable()
+    }
+    fn fd(&self) -> c_int {
+        (**self).fd()
+    }
+    fn reconnect(&mut self) -> Result<(), Error> {
+        (**self).reconnect()
+    }
+}
+
+/// The standard transport: a vchan on [`qubes_gui::LISTENING_PORT`].
+/// Agents are the vchan server, and daemons the client.
+#[derive(Debug)]
+pub struct VchanTransport {
+    vchan: Option<Vchan>,
+    /// Peer domain ID
+    domid: u16,
+    /// We are the vchan server (the agent)
+    server: bool,
+}
+
+impl VchanTransport {
+    /// Listen for a daemon in domain `domain`
+    pub fn agent(domain: u16) -> Result<Self, Error> {
+        Ok(Self {
+            vchan: Some(Self::listen(domain)?),
+            domid: domain,
+            server: true,
+        })
+    }
+
+    /// Connect to an agent in domain `domain`
+    pub fn daemon(domain: u16) -> Result<Self, Error> {
+        Ok(Self {
+            vchan: Some(Self::connect(domain)?),
+            domid: domain,
+            server: false,
+        })
+    }
+
+    fn listen(domain: u16) -> Result<Vchan, Error> {
+        Vchan::server(domain, qubes_gui::LISTENING_PORT.into(), 4096, 4096)
+    }
+
+    fn connect(domain: u16) -> Result<Vchan, Error> {
+        Vchan::client(domain, qubes_gui::LISTENING_PORT.into())
+    }
+
+    fn vchan(&self) -> &Vchan {
+        self.vchan.as_ref().unwrap()
+    }
+}
+
+impl Transport for VchanTransport {
+    fn status(&self) -> Status {
+        self.vchan
+            .as_ref()
+            .map(Vchan::status)
+            .unwrap_or(Status::Disconnected)
+    }
+    fn data_ready(&self) -> usize {
+        self.vchan().data_ready()
+    }
+    fn buffer_space(&self) -> usize {
+        self.vchan().buffer_space()
+    }
+    fn send(&mut self, buffer: &[u8]) -> Result<(), Error> {
+        self.vchan().send(buffer)
+    }
+    fn recv(&mut self, buffer: &mut [u8]) -> Result<(), Error> {
+        self.vchan().recv(buffer)
+    }
+    fn recv_into(&mut self, buffer: &mut Vec<u8>, bytes: usize) -> Result<(), Error> {
+        self.vchan().recv_into(buffer, bytes)
+    }
+    fn discard(&mut self, bytes: usize) -> Result<(), Error> {
+        self.vchan().discard(bytes)
+    }
+    fn wait(&mut self) {
+        self.vchan().wait()
+    }
+    fn fd(&self) -> c_int {
+        self.vchan().fd()
+    }
+    fn reconnect(&mut self) -> Result<(), Error> {
+        self.vchan = None;
+        self.vchan = Some(if self.server {
+            Self::listen(self.domid)?
+        } else {
+            Self::connect(self.domid)?
+        });
+        Ok(())
+    }
+}
+
+/// A transport over a Unix stream socket, for debugging and testing without
+/// Xen.
+///
+/// The socket is non-blocking.  Incoming data only becomes visible after
+/// [`Transport::wait`] is called, which should be done whenever
+/// [`Transport::fd`] is readable, just like with a vchan.  Data that cannot
+/// be written immediately is buffered and written by later calls to
+/// [`Transport::send`] or [`Transport::wait`]; while there is any,
+/// [`Transport::wants_writable`] is true.
+#[cfg(unix)]
+pub struct SocketTransport {
+    stream: UnixStream,
+    incoming: VecDeque<u8>,
+    outgoing: VecDeque<u8>,
+    /// The peer has closed the connection or an I/O error occurred
+    closed: bool,
+}
+
+#[cfg(unix)]
+impl std::fmt::Debug for SocketTransport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SocketTransport")
+            .field("stream", &self.stream)
+            .field("incoming", &self.incoming.len())
+            .field("outgoing", &self.outgoing.len())
+            .field("closed", &self.closed)
+            .finish()
+    }
+}
+
+#[cfg(unix)]
+impl SocketTransport {
+    /// The most data that will be buffered in each direction
+    pub const BUFFER_SIZE: usize = 1 << 16;
+
+    /// Use `stream`, which is put into non-blocking mode
+    pub fn new(stream: UnixStream) -> io::Result<Self> {
+        stream.set_nonblocking(true)?;
+        Ok(Self {
+            stream,
+            incoming: VecDeque::new(),
+            outgoing: VecDeque::new(),
+            closed: false,
+        })
+    }
+
+    fn fill(&mut self) {
+        let mut buf = [0u8; 4096];
+        while !self.closed && self.incoming.len() < Self::BUFFER_SIZE {
+            match self.stream.read(&mut buf) {
+                Ok(0) => self.closed = true,
+                Ok(n) => self.incoming.extend(&buf[..n]),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(_) => self.closed = true,
+            }
+        }
+    }
+
+    fn write_out(&mut self) -> Result<(), Error> {
+        while !self.outgoing.is_empty() {
+            match self.stream.write(self.outgoing.as_slices().0) {
+                Ok(0) => return Err(Error::Write),
+                Ok(n) => drop(self.outgoing.drain(..n)),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(_) => return Err(Error::Write),
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(unix)]
+impl Transport for SocketTransport {
+    fn status(&self) -> Status {
+        if self.closed && self.incoming.is_empty() {
+            Status::Disconnected
+        } else {
+            Status::Connected
+        }
+    }
+    fn data_ready(&self) -> usize {
+        self.incoming.len()
+    }
+    fn buffer_space(&self) -> usize {
+        Self::BUFFER_SIZE.saturating_sub(self.outgoing.len())
+    }
+    fn send(&mut self, buffer: &[u8]) -> Result<(), Error> {
+        if self.closed || buffer.len() > self.buffer_space() {
+            return Err(Error::Write);
+        }
+        self.outgoing.extend(buffer);
+        self.write_out().inspect_err(|_| self.closed = true)
+    }
+    fn recv(&mut self, buffer: &mut [u8]) -> Result<(), Error> {
+        if buffer.len() > self.incoming.len() {
+            return Err(Error::Read);
+        }
+        let len = buffer.len();
+        for (dst, src) in buffer.iter_mut().zip(self.incoming.drain(..len)) {
+            *dst = src
+        }
+        Ok(())
+    }
+    fn wait(&mut self) {
+        if self.write_out().is_err() {
+            self.closed = true
+        }
+        self.fill()
+    }
+    fn flush(&mut self) -> Result<bool, Error> {
+        self.write_out().inspect_err(|_| self.closed = true)?;
+        Ok(self.outgoing.is_empty())
+    }
+    fn wants_writable(&self) -> bool {
+        !self.closed && !self.outgoing.is_empty()
+    }
+    fn fd(&self) -> c_int {
+        self.stream.as_raw_fd()
+    }
+}
+
+/// One direction of a [`LoopbackTransport`]
+#[derive(Default)]
+struct Pipe {
+    data: VecDeque<u8>,
+    closed: bool,
+}
+
+impl std::fmt::Debug for Pipe {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Pipe")
+            .field("data", &self.data.len())
+            .field("closed", &self.closed)
+            .finish()
+    }
+}
+
+/// An in-memory transport connected to another [`LoopbackTransport`], for
+/// running both ends of a connection in one process.  The two ends may be
+/// used from different threads.  Dropping either end disconnects both.
+///
+/// There is no file descriptor to wait on, so callers must poll.
+#[derive(Debug)]
+pub struct LoopbackTransport {
+    incoming: Arc<Mutex<Pipe>>,
+    outgoing: Arc<Mutex<Pipe>>,
+}
+
+impl LoopbackTransport {
+    /// The most data that will be buffered in each direction
+    pub const BUFFER_SIZE: usize = 1 << 16;
+
+    /// Create two connected ends
+    pub fn pair() -> (Self, Self) {
+        let (a, b) = (Arc::<Mutex<Pipe>>::default(), Arc::<Mutex<Pipe>>::default());
+        (
+            Self {
+                incoming: a.clone(),
+                outgoing: b.clone(),
+            },
+            Self {
+                incoming: b,
+                outgoing: a,
+            },
+        )
+    }
+
+    fn incoming(&self) -> std::sync::MutexGuard<'_, Pipe> {
+        self.incoming.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn outgoing(&self) -> std::sync::MutexGuard<'_, Pipe> {
+        self.outgoing.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Drop for LoopbackTransport {
+    fn drop(&mut self) {
+        self.incoming().closed = true;
+        self.outgoing().closed = true;
+    }
+}
+
+impl Transport for LoopbackTransport {
+    fn status(&self) -> Status {
+        let incoming = self.incoming();
+        if incoming.closed && incoming.data.is_empty() {
+            Status::Disconnected
+        } else {
+            Status::Connected
+        }
+    }
+    fn data_ready(&self) -> usize {
+        self.incoming().data.len()
+    }
+    fn buffer_space(&self) -> usize {
+        Self::BUFFER_SIZE.saturating_sub(self.outgoing().data.len())
+    }
+    fn send(&mut self, buffer: &[u8]) -> Result<(), Error> {
+        let mut outgoing = self.outgoing();
+        if outgoing.closed || buffer.len() + outgoing.data.len() > Self::BUFFER_SIZE {
+            return Err(Error::Write);
+        }
+        outgoing.data.extend(buffer);
+        Ok(())
+    }
+    fn recv(&mut self, buffer: &mut [u8]) -> Result<(), Error> {
+        let mut incoming = self.incoming();
+        if buffer.len() > incoming.data.len() {
+            return Err(Error::Read);
+        }
+        let len = buffer.len();
+        for (dst, src) in buffer.iter_mut().zip(incoming.data.drain(..len)) {
+            *dst = src
+        }
+        Ok(())
+    }
+    fn fd(&self) -> c_int {
+        -1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    #[test]
+    fn socket_transport() {
+        let (a, b) = UnixStream::pair().unwrap();
+        let (mut a, mut b) = (
+            SocketTransport::new(a).unwrap(),
+            SocketTransport::new(b).unwrap(),
+        );
+        a.send(b"ping").unwrap();
+        assert_eq!(b.data_ready(), 0, "data only shows up after wait()");
+        b.wait();
+        assert_eq!(b.data_ready(), 4);
+        let mut buf = [0u8; 4];
+        b.recv(&mut buf).unwrap();
+        assert_eq!(&buf, b"ping");
+        assert!(b.recv(&mut buf[..1]).is_err());
+        drop(a);
+        assert_eq!(b.status(), Status::Connected);
+        b.wait();
+        assert_eq!(b.status(), Status::Disconnected);
+    }
+
+    #[test]
+    fn loopback_transport() {
+        let (mut a, mut b) = LoopbackTransport::pair();
+        a.send(b"ping").unwrap();
+        assert_eq!(b.data_ready(), 4);
+        assert_eq!(a.buffer_space(), LoopbackTransport::BUFFER_SIZE - 4);
+        let mut buf = [0u8; 4];
+        b.recv(&mut buf).unwrap();
+        assert_eq!(&buf, b"ping");
+        a.send(b"!").unwrap();
+        drop(a);
+        assert_eq!(b.status(), Status::Connected, "data is still buffered");
+        b.discard(1).unwrap();
+        assert_eq!(b.status(), Status::Disconnected);
+        assert!(b.send(b"x").is_err());
+    }
+}
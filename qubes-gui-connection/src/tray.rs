@@ -0,0 +1,327 @@
+/*
+ * The Qubes OS Project, https://www.qubes-os.org
+ *
+ * Copyright (C) 2021  Demi Marie Obenour  <demi@invisiblethingslab.com>
+ *
+ * This program is free software; you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License
+ * as published by the Free Software Foundation; either version 2
+ * of the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301, USA.
+ *
+ */
+
+//! System tray icons.
+//!
+//! A tray icon is an ordinary window that has been docked with `MSG_DOCK`.
+//! The protocol says nothing more, but daemons expect the following:
+//!
+//! - The window is created without a parent, and is not override-redirect.
+//! - `MSG_DOCK` is sent before `MSG_MAP`.  A window that is already mapped
+//!   has been given to the window manager, and is not embedded in the tray.
+//! - The daemon, not the agent, picks the size of the icon, and says so with
+//!   `MSG_CONFIGURE`.  As for any window, the agent acknowledges it with a
+//!   `MSG_CONFIGURE` of its own, and must then draw the icon at the new
+//!   size.  The size it is created with is only a hint.
+//! - Clicks arrive as `MSG_BUTTON`, relative to the icon.  Menus for the icon
+//!   are popups of it.
+//!
+//! [`TrayIcon`] does all of this.
+
+use crate::surface::{GrantAllocator, ResizableSurface};
+use crate::{Agent, Extension};
+use qubes_gui::x11::MouseButton;
+use qubes_gui::{Coordinates, Rectangle, WindowSize};
+use std::io;
+use std::num::NonZeroU32;
+
+/// The size tray icons are created with, until the daemon picks another
+pub const DEFAULT_ICON_SIZE: WindowSize = WindowSize {
+    width: 24,
+    height: 24,
+};
+
+/// A click on a tray icon
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct TrayClick {
+    /// The button that was pressed
+    pub button: MouseButton,
+    /// Where the icon was clicked, relative to its top-left corner
+    pub position: Coordinates,
+}
+
+/// A docked window that shows an icon.  The icon is drawn by `draw`, which
+/// is given the pixels of the icon and its size, whenever it needs to be
+/// drawn again.  Feed the `on_*` methods the messages the daemon sends to
+/// the window.
+pub struct TrayIcon<A: GrantAllocator, F> {
+    window: NonZeroU32,
+    surface: ResizableSurface<A>,
+    /// The position and size the daemon last gave the icon
+    rectangle: Rectangle,
+    draw: F,
+}
+
+impl<A: GrantAllocator, F> std::fmt::Debug for TrayIcon<A, F> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TrayIcon")
+            .field("window", &self.window)
+            .field("rectangle", &self.rectangle)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<A, F> TrayIcon<A, F>
+where
+    A: GrantAllocator,
+    F: FnMut(&mut [u32], WindowSize),
+{
+    /// Create `window`, dock it, draw the icon, and map it
+    ///
+    /// # Errors
+    ///
+    /// Fails if the window cannot be created (see [`Agent::create_window`]),
+    /// or if allocation or sending fails.
+    pub fn new(agent: &mut Agent, window: NonZeroU32, allocator: A, draw: F) -> io::Result<Self> {
+        let rectangle = Rectangle {
+            top_left: Coordinates::default(),
+            size: DEFAULT_ICON_SIZE,
+        };
+        let create = qubes_gui::Create {
+            rectangle,
+            parent: None,
+            override_redirect: 0,
+        };
+        agent.create_window(window, &create)?;
+        agent.dock(window)?;
+        let surface = ResizableSurface::new(agent.connection(), window, rectangle.size, allocator)?;
+        let mut icon = Self {
+            window,
+            surface,
+            rectangle,
+            draw,
+        };
+        icon.redraw(agent)?;
+        agent.map(window, &qubes_gui::MapInfo::default())?;
+        Ok(icon)
+    }
+
+    /// The window of the icon
+    pub fn window(&self) -> NonZeroU32 {
+        self.window
+    }
+
+    /// The current size of the icon
+    pub fn size(&self) -> WindowSize {
+        self.rectangle.size
+    }
+
+    /// Draw the icon again, because what it shows has changed.  If the
+    /// daemon is still switching to a new buffer, the icon is shown once it
+    /// has.
+    ///
+    /// # Errors
+    ///
+    /// Fails if sending fails.
+    pub fn redraw(&mut self, agent: &mut Agent) -> io::Result<()> {
+        let resizing = self.surface.resizing();
+        let mut buffer = self.surface.surface_mut().buffer_mut();
+        (self.draw)(&mut buffer, self.rectangle.size);
+        if resizing {
+            Ok(())
+        } else {
+            buffer.present(agent.connection())
+        }
+    }
+
+    /// Handle a `MSG_CONFIGURE`: resize and redraw the icon if needed, and
+    /// acknowledge the new position and size.
+    ///
+    /// # Errors
+    ///
+    /// Fails if allocation or sending fails.
+    pub fn on_configure(
+        &mut self,
+        agent: &mut Agent,
+        configure: &qubes_gui::Configure,
+    ) -> io::Result<()> {
+        let rectangle = configure.rectangle;
+        let resized = rectangle.size != self.rectangle.size;
+        if resized {
+            self.surface.resize(agent.connection(), rectangle.size)?
+        }
+        self.rectangle = rectangle;
+        if resized {
+            self.redraw(agent)?
+        }
+        let configure = qubes_gui::Configure {
+            rectangle,
+            override_redirect: 0,
+        };
+        agent.configure(self.window, &configure)
+    }
+
+    /// Handle a `MSG_BUTTON`.  Returns the click, if it was a button press.
+    pub fn on_button(&self, button: &qubes_gui::Button) -> Option<TrayClick> {
+        if button.ty != qubes_gui::EV_BUTTON_PRESS {
+            return None;
+        }
+        Some(TrayClick {
+            button: button.mouse_button(),
+            position: button.coordinates,
+        })
+    }
+
+    /// Handle a `MSG_WINDOW_DUMP_ACK`
+    ///
+    /// # Errors
+    ///
+    /// Fails if sending fails.
+    pub fn on_dump_ack(&mut self, agent: &mut Agent) -> io::Result<()> {
+        self.surface.dump_acknowledged(agent.connection())
+    }
+
+    /// Handle [`crate::Event::Downgraded`]
+    ///
+    /// # Errors
+    ///
+    /// Fails if sending fails.
+    pub fn on_downgraded(&mut self, agent: &mut Agent, extension: Extension) -> io::Result<()> {
+        self.surface.downgraded(agent.connection(), extension)
+    }
+
+    /// Handle [`crate::Event::Reconnected`].  The agent has already docked
+    /// the window again; this shares a new buffer with the new daemon and
+    /// draws the icon into it.
+    ///
+    /// # Errors
+    ///
+    /// Fails if allocation or sending fails.
+    pub fn on_reconnected(&mut self, agent: &mut Agent) -> io::Result<()> {
+        self.surface.reconnected(agent.connection())?;
+        self.redraw(agent)
+    }
+
+    /// Remove the icon from the tray, and destroy its window
+    ///
+    /// # Errors
+    ///
+    /// Fails if sending fails.
+    pub fn destroy(self, agent: &mut Agent) -> io::Result<()> {
+        agent.destroy(self.window)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Connection, Event, LoopbackTransport};
+    use qubes_castable::Castable as _;
+    use std::task::Poll;
+    use std::time::Duration;
+
+    /// Zeroed buffers with no grant references
+    #[derive(Debug)]
+    struct Zeroed;
+
+    impl GrantAllocator for Zeroed {
+        type Memory = Vec<u32>;
+
+        fn allocate(
+            &mut self,
+            _: NonZeroU32,
+            size: WindowSize,
+        ) -> io::Result<(Vec<u32>, Vec<u32>)> {
+            let pixels = size.width as usize * size.height as usize;
+            Ok((vec![0; pixels], vec![]))
+        }
+    }
+
+    #[test]
+    fn docks_before_mapping() {
+        let (ours, theirs) = LoopbackTransport::pair();
+        let mut daemon = Connection::daemon_over(theirs, Default::default());
+        let mut agent = Agent::new(Connection::agent_over(ours));
+        loop {
+            let _ = daemon.read_message();
+            match agent.read_event() {
+                Poll::Ready(Ok(Event::Reconnected(_))) => break,
+                Poll::Ready(e) => panic!("unexpected {:?}", e),
+                Poll::Pending => std::thread::sleep(Duration::from_millis(1)),
+            }
+        }
+        let mut received = || {
+            let mut messages = vec![];
+            while let Poll::Ready(message) = daemon.read_message() {
+                let message = message.unwrap();
+                messages.push((message.hdr().ty(), message.body().to_vec()))
+            }
+            messages
+        };
+        let window = NonZeroU32::new(4).unwrap();
+        let sizes = std::rc::Rc::new(std::cell::RefCell::new(vec![]));
+        let drawn = sizes.clone();
+        let draw = move |pixels: &mut [u32], size: WindowSize| {
+            pixels.iter_mut().for_each(|pixel| *pixel = 0xff_0000);
+            drawn.borrow_mut().push(size)
+        };
+        let mut icon = TrayIcon::new(&mut agent, window, Zeroed, draw).unwrap();
+        let types: Vec<u32> = received().into_iter().map(|(ty, _)| ty).collect();
+        assert_eq!(
+            types,
+            [
+                qubes_gui::MSG_CREATE,
+                qubes_gui::MSG_DOCK,
+                qubes_gui::MSG_WINDOW_DUMP,
+                qubes_gui::MSG_SHMIMAGE,
+                qubes_gui::MSG_MAP,
+            ]
+        );
+        assert_eq!(*sizes.borrow(), [DEFAULT_ICON_SIZE]);
+
+        let size = WindowSize {
+            width: 16,
+            height: 16,
+        };
+        let configure = qubes_gui::Configure {
+            rectangle: Rectangle {
+                top_left: Coordinates { x: 5, y: 0 },
+                size,
+            },
+            override_redirect: 0,
+        };
+        icon.on_configure(&mut agent, &configure).unwrap();
+        assert_eq!((icon.size(), sizes.borrow()[1]), (size, size));
+        let messages = received();
+        let (ty, body) = messages.last().unwrap();
+        assert_eq!(*ty, qubes_gui::MSG_CONFIGURE, "acknowledged");
+        assert_eq!(qubes_gui::Configure::from_bytes(body), configure);
+
+        let button = qubes_gui::Button {
+            ty: qubes_gui::EV_BUTTON_PRESS,
+            coordinates: Coordinates { x: 3, y: 4 },
+            state: 0,
+            button: 3,
+        };
+        let click = TrayClick {
+            button: MouseButton::Right,
+            position: Coordinates { x: 3, y: 4 },
+        };
+        assert_eq!(icon.on_button(&button), Some(click));
+        let release = qubes_gui::Button {
+            ty: qubes_gui::EV_BUTTON_RELEASE,
+            ..button
+        };
+        assert_eq!(icon.on_button(&release), None);
+        icon.destroy(&mut agent).unwrap();
+        assert!(agent.window(window).is_none());
+    }
+}
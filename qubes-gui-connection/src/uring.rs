@@ -0,0 +1,375 @@
+/*
+ * The Qubes OS Project, https://www.qubes-os.org
+ *
+ * Copyright (C) 2021  Demi Marie Obenour  <demi@invisiblethingslab.com>
+ *
+ * This program is free software; you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License
+ * as published by the Free Software Foundation; either version 2
+ * of the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301, USA.
+ *
+ */
+
+//! A [`Transport`] over a Unix stream socket that does its I/O with
+//! io_uring, for daemons serving many qubes.
+//!
+//! Requires the `io-uring` feature.  vchans are shared-memory rings with
+//! their own notification mechanism, so there is nothing for io_uring to do
+//! there; this is only useful for socket-based setups.
+
+use crate::Transport;
+use io_uring::{opcode, types, IoUring};
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, Read};
+use std::net::Shutdown;
+use std::os::raw::c_int;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::os::unix::net::UnixStream;
+use vchan::{Error, Status};
+
+/// `user_data` of read completions
+const READ: u64 = 0;
+/// `user_data` of write completions
+const WRITE: u64 = 1;
+
+/// A transport over a Unix stream socket, using io_uring.
+///
+/// This behaves like [`SocketTransport`](crate::SocketTransport): incoming
+/// data only becomes visible after [`Transport::wait`] is called, which
+/// should be done whenever [`Transport::fd`] is readable.  At most one write
+/// is in flight at a time.  Data sent while it is in flight, such as the
+/// header and body of the next few messages, is submitted together as a
+/// single write once it completes.
+pub struct UringTransport {
+    ring: IoUring,
+    stream: UnixStream,
+    /// Signalled by the ring whenever a completion is posted
+    event: File,
+    incoming: VecDeque<u8>,
+    /// Data not yet submitted
+    outgoing: Vec<u8>,
+    /// Data being written by the kernel.  Must not be touched while
+    /// `writing` is true.
+    in_flight: Vec<u8>,
+    /// How much of `in_flight` has been written
+    written: usize,
+    /// Target of the read in flight.  Must not be touched while `reading` is
+    /// true.
+    read_buf: Box<[u8]>,
+    reading: bool,
+    writing: bool,
+    /// The peer has closed the connection or an I/O error occurred
+    closed: bool,
+}
+
+impl std::fmt::Debug for UringTransport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("UringTransport")
+            .field("stream", &self.stream)
+            .field("incoming", &self.incoming.len())
+            .field("pending", &self.pending())
+            .field("reading", &self.reading)
+            .field("writing", &self.writing)
+            .field("closed", &self.closed)
+            .finish()
+    }
+}
+
+impl UringTransport {
+    /// The most data that will be buffered in each direction
+    pub const BUFFER_SIZE: usize = 1 << 16;
+
+    /// Use `stream`.  Fails if io_uring is not available.
+    pub fn new(stream: UnixStream) -> io::Result<Self> {
+        let ring = IoUring::new(8)?;
+        // SAFETY: eventfd() has no memory-safety preconditions
+        let fd = unsafe { libc::eventfd(0, libc::EFD_CLOEXEC | libc::EFD_NONBLOCK) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: `fd` is a freshly created file descriptor that nothing
+        // else owns
+        let event = unsafe { File::from_raw_fd(fd) };
+        ring.submitter().register_eventfd(event.as_raw_fd())?;
+        let mut this = Self {
+            ring,
+            stream,
+            event,
+            incoming: VecDeque::new(),
+            outgoing: vec![],
+            in_flight: vec![],
+            written: 0,
+            read_buf: vec![0; 4096].into_boxed_slice(),
+            reading: false,
+            writing: false,
+            closed: false,
+        };
+        this.submit_read();
+        this.ring.submit()?;
+        Ok(this)
+    }
+
+    /// Push `entry` to the submission queue.
+    ///
+    /// # Safety
+    ///
+    /// Any buffer `entry` refers to must stay valid until it completes.
+    unsafe fn push(&mut self, entry: &io_uring::squeue::Entry) {
+        if self.ring.submission().push(entry).is_err() {
+            // At most two entries are ever outstanding, and the ring has
+            // room for more than that.
+            unreachable!("io_uring submission queue full")
+        }
+    }
+
+    fn submit_read(&mut self) {
+        if self.reading || self.closed || self.incoming.len() >= Self::BUFFER_SIZE {
+            return;
+        }
+        let len = self
+            .read_buf
+            .len()
+            .min(Self::BUFFER_SIZE - self.incoming.len());
+        let entry = opcode::Recv::new(
+            types::Fd(self.stream.as_raw_fd()),
+            self.read_buf.as_mut_ptr(),
+            len as _,
+        )
+        .build()
+        .user_data(READ);
+        // SAFETY: `read_buf` is not touched or freed until the read completes
+        unsafe { self.push(&entry) }
+        self.reading = true
+    }
+
+    fn submit_write(&mut self) {
+        if self.writing || self.closed {
+            return;
+        }
+        if self.written == self.in_flight.len() {
+            if self.outgoing.is_empty() {
+                return;
+            }
+            self.in_flight.clear();
+            self.written = 0;
+            std::mem::swap(&mut self.in_flight, &mut self.outgoing);
+        }
+        let rest = &self.in_flight[self.written..];
+        let entry = opcode::Send::new(
+            types::Fd(self.stream.as_raw_fd()),
+            rest.as_ptr(),
+            rest.len() as _,
+        )
+        .flags(libc::MSG_NOSIGNAL)
+        .build()
+        .user_data(WRITE);
+        // SAFETY: `in_flight` is not touched or freed until the write
+        // completes
+        unsafe { self.push(&entry) }
+        self.writing = true
+    }
+
+    /// Process completions and submit whatever I/O is now possible.
+    fn complete(&mut self) {
+        let completed: Vec<_> = self
+            .ring
+            .completion()
+            .map(|cqe| (cqe.user_data(), cqe.result()))
+            .collect();
+        for (user_data, result) in completed {
+            match user_data {
+                READ => {
+                    self.reading = false;
+                    match result {
+                        0 => self.closed = true,
+                        n if n > 0 => self.incoming.extend(&self.read_buf[..n as usize]),
+                        n if -n == libc::EINTR || -n == libc::EAGAIN => {}
+                        _ => self.closed = true,
+                    }
+                }
+                WRITE => {
+                    self.writing = false;
+                    match result {
+                        n if n > 0 => self.written += n as usize,
+                        n if -n == libc::EINTR || -n == libc::EAGAIN => {}
+                        _ => self.closed = true,
+                    }
+                }
+                _ => unreachable!("unknown io_uring completion"),
+            }
+        }
+        self.submit_read();
+        self.submit_write();
+        if self.ring.submit().is_err() {
+            self.closed = true
+        }
+    }
+
+    /// Bytes accepted by [`Transport::send`] but not yet written
+    fn pending(&self) -> usize {
+        self.outgoing.len() + self.in_flight.len() - self.written
+    }
+}
+
+impl Transport for UringTransport {
+    fn status(&self) -> Status {
+        if self.closed && self.incoming.is_empty() {
+            Status::Disconnected
+        } else {
+            Status::Connected
+        }
+    }
+    fn data_ready(&self) -> usize {
+        self.incoming.len()
+    }
+    fn buffer_space(&self) -> usize {
+        Self::BUFFER_SIZE.saturating_sub(self.pending())
+    }
+    fn send(&mut self, buffer: &[u8]) -> Result<(), Error> {
+        self.send_vectored(&[buffer])
+    }
+    fn send_vectored(&mut self, buffers: &[&[u8]]) -> Result<(), Error> {
+        let len: usize = buffers.iter().map(|buffer| buffer.len()).sum();
+        if self.closed || len > self.buffer_space() {
+            return Err(Error::Write);
+        }
+        buffers
+            .iter()
+            .for_each(|buffer| self.outgoing.extend_from_slice(buffer));
+        self.complete();
+        Ok(())
+    }
+    fn recv(&mut self, buffer: &mut [u8]) -> Result<(), Error> {
+        if buffer.len() > self.incoming.len() {
+            return Err(Error::Read);
+        }
+        let len = buffer.len();
+        for (dst, src) in buffer.iter_mut().zip(self.incoming.drain(..len)) {
+            *dst = src
+        }
+        if !self.reading {
+            self.complete()
+        }
+        Ok(())
+    }
+    fn wait(&mut self) {
+        let mut count = [0u8; 8];
+        let _ = (&self.event).read(&mut count);
+        self.complete()
+    }
+    fn flush(&mut self) -> Result<bool, Error> {
+        self.complete();
+        if self.closed && self.pending() != 0 {
+            return Err(Error::Write);
+        }
+        Ok(self.pending() == 0)
+    }
+    fn fd(&self) -> c_int {
+        self.event.as_raw_fd()
+    }
+}
+
+impl Drop for UringTransport {
+    fn drop(&mut self) {
+        // The kernel may still be using `read_buf` and `in_flight`.  Shutting
+        // down the socket makes any I/O in flight complete promptly.
+        let _ = self.stream.shutdown(Shutdown::Both);
+        while self.reading || self.writing {
+            if self.ring.submit_and_wait(1).is_err() {
+                // Leak the buffers rather than risk the kernel writing to
+                // freed memory.
+                std::mem::forget(std::mem::take(&mut self.read_buf));
+                std::mem::forget(std::mem::take(&mut self.in_flight));
+                return;
+            }
+            for cqe in self.ring.completion() {
+                match cqe.user_data() {
+                    READ => self.reading = false,
+                    _ => self.writing = false,
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pair() -> Option<(UringTransport, UringTransport)> {
+        let (a, b) = UnixStream::pair().unwrap();
+        match (UringTransport::new(a), UringTransport::new(b)) {
+            (Ok(a), Ok(b)) => Some((a, b)),
+            (Err(e), _) | (_, Err(e)) => {
+                eprintln!("io_uring unavailable, skipping: {}", e);
+                None
+            }
+        }
+    }
+
+    /// Call [`Transport::wait`] on `t` until `done` holds
+    fn wait_until(t: &mut UringTransport, done: impl Fn(&UringTransport) -> bool) {
+        for _ in 0..1000 {
+            t.wait();
+            if done(t) {
+                return;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(1))
+        }
+        panic!("timed out")
+    }
+
+    #[test]
+    fn uring_transport() {
+        let (mut a, mut b) = match pair() {
+            Some(pair) => pair,
+            None => return,
+        };
+        a.send_vectored(&[b"pi", b"ng"]).unwrap();
+        wait_until(&mut b, |b| b.data_ready() == 4);
+        let mut buf = [0u8; 4];
+        b.recv(&mut buf).unwrap();
+        assert_eq!(&buf, b"ping");
+        assert!(b.recv(&mut buf[..1]).is_err());
+        wait_until(&mut a, |a| a.buffer_space() == UringTransport::BUFFER_SIZE);
+        drop(a);
+        wait_until(&mut b, |b| b.status() == Status::Disconnected);
+        assert!(b.send(b"x").is_err());
+    }
+
+    #[test]
+    fn backpressure() {
+        let (mut a, mut b) = match pair() {
+            Some(pair) => pair,
+            None => return,
+        };
+        let chunk = vec![0xA5; 1000];
+        let total = 1000 * chunk.len();
+        let (mut sent, mut received) = (0, 0);
+        let mut buf = vec![0u8; 4096];
+        while received < total {
+            if sent < total && a.buffer_space() >= chunk.len() {
+                a.send(&chunk).unwrap();
+                sent += chunk.len();
+            }
+            a.wait();
+            b.wait();
+            let n = b.data_ready().min(buf.len());
+            b.recv(&mut buf[..n]).unwrap();
+            assert!(buf[..n].iter().all(|&byte| byte == 0xA5));
+            received += n;
+            assert!(b.data_ready() <= UringTransport::BUFFER_SIZE);
+        }
+        assert_eq!(sent, received);
+    }
+}
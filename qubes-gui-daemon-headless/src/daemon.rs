@@ -0,0 +1,586 @@
+/*
+ * The Qubes OS Project, https://www.qubes-os.org
+ *
+ * Copyright (C) 2021  Demi Marie Obenour  <demi@invisiblethingslab.com>
+ *
+ * This program is free software; you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License
+ * as published by the Free Software Foundation; either version 2
+ * of the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301, USA.
+ *
+ */
+
+//! The daemon and its errors
+
+use crate::Image;
+use qubes_gui::{
+    ButtonEvent, Coordinates, CrossingDetail, CrossingMode, FocusDetail, FocusEvent, KeyEvent,
+    KeymapNotify, Rectangle, ValidRectangle, WindowSize,
+};
+use qubes_gui_connection::{Connection, Event, Extension, OutgoingMessage};
+use qubes_gui_daemon_proto::{
+    input, GrantMapper, MessageVisitor, Quota, QuotaTracker, QuotaViolation, RateLimiter,
+    RegistryError, WindowDump, WindowRegistry,
+};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufWriter};
+use std::num::NonZeroU32;
+use std::path::PathBuf;
+use std::task::Poll;
+use std::time::Instant;
+
+/// Errors from [`HeadlessDaemon`]
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Error {
+    /// The agent sent an invalid message
+    Protocol(qubes_gui_daemon_proto::Error),
+    /// The agent broke the window lifecycle rules
+    Registry(RegistryError),
+    /// The agent exceeded its [`Quota`]
+    Quota(QuotaViolation),
+    /// A composition buffer could not be mapped
+    Map(io::Error),
+    /// Talking to the agent, or writing a PNG, failed
+    Io(io::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Protocol(e) => write!(f, "Invalid message from agent: {:?}", e),
+            Error::Registry(e) => write!(f, "{}", e),
+            Error::Quota(e) => write!(f, "Quota exceeded: {}", e),
+            Error::Map(e) => write!(f, "Cannot map composition buffer: {}", e),
+            Error::Io(e) => write!(f, "I/O error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<RegistryError> for Error {
+    fn from(e: RegistryError) -> Self {
+        Error::Registry(e)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+/// A window of the agent
+#[derive(Debug)]
+pub struct Window {
+    rectangle: Rectangle,
+    parent: Option<NonZeroU32>,
+    override_redirect: bool,
+    mapped: bool,
+    untrusted_title: String,
+    image: Image,
+}
+
+impl Window {
+    /// Where the window is, and its size
+    pub fn rectangle(&self) -> Rectangle {
+        self.rectangle
+    }
+
+    /// The parent of the window, if any
+    pub fn parent(&self) -> Option<NonZeroU32> {
+        self.parent
+    }
+
+    /// Is this an override-redirect window?
+    pub fn override_redirect(&self) -> bool {
+        self.override_redirect
+    }
+
+    /// Has the agent mapped the window?
+    pub fn mapped(&self) -> bool {
+        self.mapped
+    }
+
+    /// The title set by the agent.  UNTRUSTED.
+    pub fn untrusted_title(&self) -> &str {
+        &self.untrusted_title
+    }
+
+    /// What the window looks like
+    pub fn image(&self) -> &Image {
+        &self.image
+    }
+}
+
+/// A window and its composition buffer
+#[derive(Debug)]
+struct Shown<B> {
+    window: Window,
+    /// The composition buffer and its size
+    buffer: Option<(WindowSize, B)>,
+}
+
+/// Everything but the connection, which is borrowed by incoming messages
+#[derive(Debug)]
+struct State<M: GrantMapper> {
+    mapper: M,
+    registry: WindowRegistry,
+    windows: BTreeMap<NonZeroU32, Shown<M::Buffer>>,
+    outgoing: Vec<(NonZeroU32, OutgoingMessage)>,
+    dump_dir: Option<PathBuf>,
+    frames: u64,
+    /// The first error from a [`MessageVisitor`] method, which cannot
+    /// return it
+    error: Option<Error>,
+}
+
+/// A GUI daemon without a display, for testing agents.  It validates
+/// everything the agent sends, draws windows into [`Image`]s, and lets tests
+/// send input to the agent.
+///
+/// Like the real daemon, it acknowledges `MSG_DESTROY`, acknowledges each
+/// `MSG_WINDOW_DUMP` once the buffer is mapped (if the agent supports
+/// `MSG_WINDOW_DUMP_ACK`), and confirms each `MSG_CONFIGURE` by sending it
+/// back.
+pub struct HeadlessDaemon<M: GrantMapper> {
+    connection: Connection,
+    state: State<M>,
+    /// The keys pressed with [`HeadlessDaemon::key`]
+    keys: KeymapNotify,
+    quota: QuotaTracker,
+    rate_limiter: RateLimiter,
+    /// The number of messages dropped by `rate_limiter`
+    rate_limited: u64,
+    /// The time from which the quota tracker and rate limiter measure
+    epoch: Instant,
+}
+
+impl<M: GrantMapper> fmt::Debug for HeadlessDaemon<M> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("HeadlessDaemon")
+            .field("connection", &self.connection)
+            .field("windows", &self.state.windows.keys())
+            .finish_non_exhaustive()
+    }
+}
+
+impl<M: GrantMapper> HeadlessDaemon<M> {
+    /// Serve the agent at the other end of `connection`, which must be a
+    /// daemon connection, mapping buffers with `mapper`
+    pub fn new(connection: Connection, mapper: M) -> Self {
+        Self {
+            connection,
+            state: State {
+                mapper,
+                registry: WindowRegistry::new(),
+                windows: BTreeMap::new(),
+                outgoing: vec![],
+                dump_dir: None,
+                frames: 0,
+                error: None,
+            },
+            keys: KeymapNotify::default(),
+            quota: QuotaTracker::new(Quota::default()),
+            rate_limiter: RateLimiter::new(),
+            rate_limited: 0,
+            epoch: Instant::now(),
+        }
+    }
+
+    /// Fail with [`Error::Quota`] if the agent exceeds `quota`.  By default,
+    /// [`Quota::default`] is enforced.
+    pub fn quota(mut self, quota: Quota) -> Self {
+        self.quota = QuotaTracker::new(quota);
+        self
+    }
+
+    /// Drop messages that exceed the limits of `limiter`, as a real daemon
+    /// would.  By default nothing is limited.
+    pub fn rate_limits(mut self, limiter: RateLimiter) -> Self {
+        self.rate_limiter = limiter;
+        self
+    }
+
+    /// The number of messages dropped because of [`HeadlessDaemon::rate_limits`]
+    pub fn rate_limited(&self) -> u64 {
+        self.rate_limited
+    }
+
+    /// Write a PNG of each window to `dir` whenever it is drawn to.  The
+    /// files are named `window-<window>-<frame>.png`, where frames are
+    /// numbered from 0 across all windows.
+    pub fn dump_pngs(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.state.dump_dir = Some(dir.into());
+        self
+    }
+
+    /// The connection to the agent
+    pub fn connection(&mut self) -> &mut Connection {
+        &mut self.connection
+    }
+
+    /// The windows of the agent, in order of ID
+    pub fn windows(&self) -> impl Iterator<Item = (NonZeroU32, &Window)> {
+        (self.state.windows.iter()).map(|(&id, shown)| (id, &shown.window))
+    }
+
+    /// The window with ID `window`, if it exists
+    pub fn window(&self, window: NonZeroU32) -> Option<&Window> {
+        self.state.windows.get(&window).map(|shown| &shown.window)
+    }
+
+    /// Handle everything the agent has sent so far.  This never blocks.  A
+    /// new agent (after a reconnection) starts with no windows.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the agent misbehaved, if mapping a buffer or writing a PNG
+    /// failed, or if the connection failed.
+    pub fn process(&mut self) -> Result<(), Error> {
+        loop {
+            let event = match self.connection.read_event() {
+                Poll::Ready(event) => event?,
+                Poll::Pending => return Ok(()),
+            };
+            match event {
+                Event::Message(buffer) => {
+                    let now = self.epoch.elapsed();
+                    self.quota.check(now, &buffer.hdr()).map_err(Error::Quota)?;
+                    if self.rate_limiter.check(now, &buffer.hdr()).is_err() {
+                        self.rate_limited += 1
+                    } else {
+                        qubes_gui_daemon_proto::visit(&mut self.state, buffer.hdr(), buffer.body())
+                            .map_err(Error::Protocol)?
+                    }
+                }
+                Event::Reconnected(_) => {
+                    self.quota = QuotaTracker::new(*self.quota.quota());
+                    self.state.reset()
+                }
+                _ => {}
+            }
+            let acks = self.connection.extensions().contains(Extension::DumpAck);
+            for (window, message) in std::mem::take(&mut self.state.outgoing) {
+                if acks || !matches!(message, OutgoingMessage::DumpAck) {
+                    self.connection.send_message(&message, window.into())?
+                }
+            }
+            if let Some(e) = self.state.error.take() {
+                return Err(e);
+            }
+        }
+    }
+
+    fn send(&mut self, window: NonZeroU32, message: OutgoingMessage) -> io::Result<()> {
+        self.connection.send_message(&message, window.into())
+    }
+
+    /// Press or release the key with X11 keycode `keycode` in `window`
+    ///
+    /// # Errors
+    ///
+    /// Fails if the connection failed.
+    pub fn key(&mut self, window: NonZeroU32, keycode: u8, event: KeyEvent) -> io::Result<()> {
+        match event {
+            KeyEvent::Press => self.keys.set(keycode),
+            KeyEvent::Release => self.keys.clear(keycode),
+        }
+        let keypress = input::keypress(event, Coordinates::default(), 0, keycode);
+        self.send(window, OutgoingMessage::Keypress(keypress))
+    }
+
+    /// Press or release `button` at `position` in `window`
+    ///
+    /// # Errors
+    ///
+    /// Fails if the connection failed.
+    pub fn button(
+        &mut self,
+        window: NonZeroU32,
+        position: Coordinates,
+        button: u32,
+        event: ButtonEvent,
+    ) -> io::Result<()> {
+        let button = input::button(event, position, 0, button);
+        self.send(window, OutgoingMessage::Button(button))
+    }
+
+    /// Move the pointer to `position` in `window`
+    ///
+    /// # Errors
+    ///
+    /// Fails if the connection failed.
+    pub fn motion(&mut self, window: NonZeroU32, position: Coordinates) -> io::Result<()> {
+        let motion = input::motion(position, 0, false);
+        self.send(window, OutgoingMessage::Motion(motion))
+    }
+
+    /// Move the pointer into `window` at `position` (if `entered` is true),
+    /// or out of it
+    ///
+    /// # Errors
+    ///
+    /// Fails if the connection failed.
+    pub fn crossing(
+        &mut self,
+        window: NonZeroU32,
+        position: Coordinates,
+        entered: bool,
+    ) -> io::Result<()> {
+        let (mode, detail) = (CrossingMode::Normal, CrossingDetail::Nonlinear);
+        let crossing = input::crossing(entered, position, 0, mode, detail, false);
+        self.send(window, OutgoingMessage::Crossing(crossing))
+    }
+
+    /// Give `window` the keyboard focus, or take it away.  Before focus is
+    /// given, the agent is told which keys are pressed.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the connection failed.
+    pub fn focus(&mut self, window: NonZeroU32, event: FocusEvent) -> io::Result<()> {
+        let detail = FocusDetail::Nonlinear;
+        if event == FocusEvent::In {
+            let (keymap, focus) = input::focus_in(detail, self.keys);
+            self.send(window, OutgoingMessage::KeymapNotify(keymap))?;
+            self.send(window, OutgoingMessage::Focus(focus))
+        } else {
+            self.send(window, OutgoingMessage::Focus(input::focus(event, detail)))
+        }
+    }
+
+    /// Press exactly the keys with X11 keycodes in `pressed`, as if the
+    /// user had pressed and released keys elsewhere, and tell the agent
+    ///
+    /// # Errors
+    ///
+    /// Fails if the connection failed.
+    pub fn keymap(
+        &mut self,
+        window: NonZeroU32,
+        pressed: impl IntoIterator<Item = u8>,
+    ) -> io::Result<()> {
+        self.keys = input::keymap(pressed);
+        self.send(window, OutgoingMessage::KeymapNotify(self.keys))
+    }
+
+    /// Ask the agent to move or resize `window`, as a user would
+    ///
+    /// # Errors
+    ///
+    /// Fails if the connection failed.
+    pub fn configure(&mut self, window: NonZeroU32, rectangle: Rectangle) -> io::Result<()> {
+        let configure = qubes_gui::Configure {
+            rectangle,
+            override_redirect: 0,
+        };
+        self.send(window, OutgoingMessage::Configure(configure))
+    }
+
+    /// Ask the agent to close `window`, as a user would
+    ///
+    /// # Errors
+    ///
+    /// Fails if the connection failed.
+    pub fn close(&mut self, window: NonZeroU32) -> io::Result<()> {
+        self.send(window, OutgoingMessage::Close)
+    }
+}
+
+impl<M: GrantMapper> State<M> {
+    /// Forget everything about the previous agent
+    fn reset(&mut self) {
+        self.registry = WindowRegistry::new();
+        self.windows.clear();
+        self.outgoing.clear();
+    }
+
+    /// Remember the first error
+    fn fail(&mut self, result: Result<(), Error>) {
+        if let Err(e) = result {
+            self.error.get_or_insert(e);
+        }
+    }
+
+    fn shown(&mut self, window: NonZeroU32) -> Result<&mut Shown<M::Buffer>, Error> {
+        self.registry.check(window)?;
+        Ok(self
+            .windows
+            .get_mut(&window)
+            .expect("registered windows exist"))
+    }
+
+    /// Copy `rectangle` of the buffer of `window`, if it has one, to its
+    /// image, and write a PNG if asked to
+    fn draw(&mut self, window: NonZeroU32, rectangle: Rectangle) -> Result<(), Error> {
+        let shown = self.shown(window)?;
+        match &shown.buffer {
+            Some((size, buffer)) => shown
+                .window
+                .image
+                .copy_from(buffer.as_ref(), *size, rectangle),
+            None => return Ok(()),
+        }
+        let dir = match &self.dump_dir {
+            Some(dir) => dir,
+            None => return Ok(()),
+        };
+        let path = dir.join(format!("window-{}-{}.png", window, self.frames));
+        self.frames += 1;
+        let file = BufWriter::new(File::create(path)?);
+        self.windows[&window].window.image.write_png(file)?;
+        Ok(())
+    }
+
+    fn create(
+        &mut self,
+        window: NonZeroU32,
+        rectangle: ValidRectangle,
+        parent: Option<NonZeroU32>,
+        override_redirect: bool,
+    ) -> Result<(), Error> {
+        let create = qubes_gui::Create {
+            rectangle: rectangle.get(),
+            parent,
+            override_redirect: override_redirect.into(),
+        };
+        self.registry.create(window, &create)?;
+        let window_info = Window {
+            rectangle: rectangle.get(),
+            parent,
+            override_redirect,
+            mapped: false,
+            untrusted_title: String::new(),
+            image: Image::new(rectangle.size().get()),
+        };
+        let shown = Shown {
+            window: window_info,
+            buffer: None,
+        };
+        self.windows.insert(window, shown);
+        Ok(())
+    }
+
+    fn destroy(&mut self, window: NonZeroU32) -> Result<(), Error> {
+        self.registry.destroy(window)?;
+        self.windows.remove(&window);
+        // Acknowledge the destruction, after which the ID may be reused
+        self.outgoing.push((window, OutgoingMessage::Destroy));
+        self.registry.acknowledge_destroy(window);
+        Ok(())
+    }
+
+    fn configure(
+        &mut self,
+        window: NonZeroU32,
+        rectangle: ValidRectangle,
+        override_redirect: bool,
+    ) -> Result<(), Error> {
+        self.registry.configure(window, rectangle.get())?;
+        let shown = self.shown(window)?;
+        let size = rectangle.size().get();
+        if size != shown.window.image.size() {
+            shown.window.image = Image::new(size)
+        }
+        shown.window.rectangle = rectangle.get();
+        let configure = qubes_gui::Configure {
+            rectangle: rectangle.get(),
+            override_redirect: override_redirect.into(),
+        };
+        self.outgoing
+            .push((window, OutgoingMessage::Configure(configure)));
+        Ok(())
+    }
+
+    fn window_dump(&mut self, window: NonZeroU32, dump: WindowDump<'_>) -> Result<(), Error> {
+        self.registry.check(window)?;
+        let buffer = self.mapper.map(window, &dump).map_err(Error::Map)?;
+        let size = dump.size.get();
+        // The buffer this replaces is unmapped here, so the agent may free it
+        self.shown(window)?.buffer = Some((size, buffer));
+        self.outgoing.push((window, OutgoingMessage::DumpAck));
+        let whole = Rectangle {
+            top_left: Coordinates::default(),
+            size,
+        };
+        self.draw(window, whole)
+    }
+}
+
+impl<M: GrantMapper> MessageVisitor for State<M> {
+    fn on_create(
+        &mut self,
+        window: NonZeroU32,
+        rectangle: ValidRectangle,
+        parent: Option<NonZeroU32>,
+        override_redirect: bool,
+    ) {
+        let res = self.create(window, rectangle, parent, override_redirect);
+        self.fail(res)
+    }
+
+    fn on_destroy(&mut self, window: NonZeroU32) {
+        let res = self.destroy(window);
+        self.fail(res)
+    }
+
+    fn on_map(
+        &mut self,
+        window: NonZeroU32,
+        _transient_for: Option<NonZeroU32>,
+        _override_redirect: bool,
+    ) {
+        let res = self.shown(window).map(|shown| shown.window.mapped = true);
+        self.fail(res)
+    }
+
+    fn on_unmap(&mut self, window: NonZeroU32) {
+        let res = self.shown(window).map(|shown| shown.window.mapped = false);
+        self.fail(res)
+    }
+
+    fn on_configure(
+        &mut self,
+        window: NonZeroU32,
+        rectangle: ValidRectangle,
+        override_redirect: bool,
+    ) {
+        let res = self.configure(window, rectangle, override_redirect);
+        self.fail(res)
+    }
+
+    fn on_shm_image(&mut self, window: NonZeroU32, rectangle: ValidRectangle) {
+        let rectangle = rectangle.get();
+        let res = match self.registry.check_damage(window, rectangle) {
+            Ok(()) => self.draw(window, rectangle),
+            Err(e) => Err(e.into()),
+        };
+        self.fail(res)
+    }
+
+    fn on_set_title(&mut self, window: NonZeroU32, untrusted_title: &str) {
+        let res = self.shown(window).map(|shown| {
+            shown.window.untrusted_title.clear();
+            shown.window.untrusted_title.push_str(untrusted_title)
+        });
+        self.fail(res)
+    }
+
+    fn on_window_dump(&mut self, window: NonZeroU32, dump: WindowDump<'_>) {
+        let res = self.window_dump(window, dump);
+        self.fail(res)
+    }
+}
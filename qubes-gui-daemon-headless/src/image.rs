@@ -0,0 +1,149 @@
+/*
+ * The Qubes OS Project, https://www.qubes-os.org
+ *
+ * Copyright (C) 2021  Demi Marie Obenour  <demi@invisiblethingslab.com>
+ *
+ * This program is free software; you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License
+ * as published by the Free Software Foundation; either version 2
+ * of the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301, USA.
+ *
+ */
+
+//! What windows look like
+
+use qubes_gui::{Coordinates, Rectangle, WindowSize};
+use std::convert::TryFrom;
+use std::io::{self, Write};
+
+/// The contents of a window, as drawn so far.  Each pixel is `0x00RRGGBB`,
+/// and pixels that have not been drawn are black.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Image {
+    size: WindowSize,
+    pixels: Vec<u32>,
+}
+
+impl std::fmt::Debug for Image {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Image").field("size", &self.size).finish()
+    }
+}
+
+impl Image {
+    /// A black image of size `size`
+    pub(crate) fn new(size: WindowSize) -> Self {
+        let area = size.checked_area().expect("validated by the registry");
+        Self {
+            size,
+            pixels: vec![0; area as usize],
+        }
+    }
+
+    /// The size of the image
+    pub fn size(&self) -> WindowSize {
+        self.size
+    }
+
+    /// The pixels, in rows from top to bottom
+    pub fn pixels(&self) -> &[u32] {
+        &self.pixels
+    }
+
+    /// The pixel at `point`, if it is inside the image
+    pub fn pixel(&self, point: Coordinates) -> Option<u32> {
+        let x = u32::try_from(point.x)
+            .ok()
+            .filter(|&x| x < self.size.width)?;
+        let y = u32::try_from(point.y)
+            .ok()
+            .filter(|&y| y < self.size.height)?;
+        Some(self.pixels[(y * self.size.width + x) as usize])
+    }
+
+    /// Copy `rectangle` of `buffer`, which has size `size`, to the same place
+    /// in the image.  Parts of `rectangle` outside either of them are
+    /// ignored, and so is a buffer too small for its size.
+    pub(crate) fn copy_from(&mut self, buffer: &[u32], size: WindowSize, rectangle: Rectangle) {
+        let area = size.checked_area().map_or(usize::MAX, |area| area as usize);
+        if buffer.len() < area {
+            return;
+        }
+        let rectangle = match rectangle
+            .clamp_to(size)
+            .and_then(|rectangle| rectangle.clamp_to(self.size))
+        {
+            Some(rectangle) => rectangle,
+            None => return,
+        };
+        let (x, y) = (rectangle.top_left.x as usize, rectangle.top_left.y as usize);
+        let width = rectangle.size.width as usize;
+        for row in y..y + rectangle.size.height as usize {
+            let from = row * size.width as usize + x;
+            let to = row * self.size.width as usize + x;
+            self.pixels[to..to + width].copy_from_slice(&buffer[from..from + width])
+        }
+    }
+
+    /// Write the image to `writer` as an 8-bit RGB PNG
+    ///
+    /// # Errors
+    ///
+    /// Fails if writing fails.
+    pub fn write_png<W: Write>(&self, writer: W) -> io::Result<()> {
+        let mut encoder = png::Encoder::new(writer, self.size.width, self.size.height);
+        encoder.set_color(png::ColorType::Rgb);
+        encoder.set_depth(png::BitDepth::Eight);
+        let data: Vec<u8> = self
+            .pixels
+            .iter()
+            .flat_map(|pixel| {
+                let [_, r, g, b] = pixel.to_be_bytes();
+                [r, g, b]
+            })
+            .collect();
+        encoder.write_header()?.write_image_data(&data)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn copy_clamps() {
+        let size = WindowSize {
+            width: 3,
+            height: 2,
+        };
+        let mut image = Image::new(size);
+        let buffer: Vec<u32> = (1..=6).collect();
+        let rectangle = Rectangle {
+            top_left: Coordinates { x: 1, y: -1 },
+            size: WindowSize {
+                width: 5,
+                height: 2,
+            },
+        };
+        image.copy_from(&buffer, size, rectangle);
+        assert_eq!(image.pixels(), [0, 2, 3, 0, 0, 0]);
+        let whole = Rectangle {
+            top_left: Coordinates::default(),
+            size,
+        };
+        image.copy_from(&buffer[..5], size, whole);
+        assert_eq!(image.pixels(), [0, 2, 3, 0, 0, 0]);
+        assert_eq!(image.pixel(Coordinates { x: 2, y: 0 }), Some(3));
+        assert_eq!(image.pixel(Coordinates { x: 3, y: 0 }), None);
+    }
+}
@@ -0,0 +1,47 @@
+/*
+ * The Qubes OS Project, https://www.qubes-os.org
+ *
+ * Copyright (C) 2021  Demi Marie Obenour  <demi@invisiblethingslab.com>
+ *
+ * This program is free software; you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License
+ * as published by the Free Software Foundation; either version 2
+ * of the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301, USA.
+ *
+ */
+
+#![forbid(missing_docs)]
+#![forbid(clippy::all)]
+//! A GUI daemon without a display
+//!
+//! [`HeadlessDaemon`] serves one agent, for example over a
+//! `LoopbackTransport` or a Unix socket, so that agents can be tested
+//! without a display or Xen.  Everything the agent sends is validated with
+//! `qubes-gui-daemon-proto`, and an error is returned at the first mistake.
+//! Windows are drawn into in-memory [`Image`]s, which can also be written
+//! out as PNG files, and tests can send keyboard, pointer, focus, resize,
+//! and close events to the agent.  A [`Quota`] is enforced, and messages
+//! over a [`RateLimiter`]'s limits are dropped.
+//!
+//! As in `qubes-gui-daemon-x11`, composition buffers are mapped by a
+//! [`GrantMapper`] supplied by the caller.  Tests that run the agent in the
+//! same process can use one that looks buffers up by their grant
+//! references.
+
+mod daemon;
+mod image;
+#[cfg(test)]
+mod tests;
+
+pub use daemon::{Error, HeadlessDaemon, Window};
+pub use image::Image;
+pub use qubes_gui_daemon_proto::{GrantMapper, Quota, RateLimit, RateLimiter};
@@ -0,0 +1,331 @@
+/*
+ * The Qubes OS Project, https://www.qubes-os.org
+ *
+ * Copyright (C) 2021  Demi Marie Obenour  <demi@invisiblethingslab.com>
+ *
+ * This program is free software; you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License
+ * as published by the Free Software Foundation; either version 2
+ * of the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301, USA.
+ *
+ */
+
+use super::*;
+use qubes_gui::{ButtonEvent, Coordinates, FocusEvent, KeyEvent, Rectangle, WindowSize};
+use qubes_gui_connection::surface::{GrantAllocator, ResizableSurface};
+use qubes_gui_connection::{Connection, Event, LoopbackTransport, OutgoingMessage};
+use qubes_gui_daemon_proto::WindowDump;
+use std::convert::TryInto as _;
+use std::io;
+use std::num::NonZeroU32;
+use std::task::Poll;
+use std::time::Duration;
+
+/// Buffers whose pixels count up from their first grant reference
+#[derive(Debug)]
+struct Counting;
+
+impl GrantMapper for Counting {
+    type Buffer = Vec<u32>;
+
+    fn map(&mut self, _: NonZeroU32, dump: &WindowDump<'_>) -> io::Result<Vec<u32>> {
+        let first =
+            dump.grant_refs.iter().next().ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidInput, "no grant references")
+            })?;
+        let area = dump.size.get().checked_area().unwrap();
+        Ok((first..first + area).collect())
+    }
+}
+
+const WINDOW: NonZeroU32 = match NonZeroU32::new(1) {
+    Some(window) => window,
+    None => unreachable!(),
+};
+
+fn connect() -> (Connection, HeadlessDaemon<Counting>) {
+    let (ours, theirs) = LoopbackTransport::pair();
+    let connection = Connection::daemon_over(theirs, Default::default());
+    let mut daemon = HeadlessDaemon::new(connection, Counting);
+    let mut agent = Connection::agent_over(ours);
+    loop {
+        daemon.process().unwrap();
+        match agent.read_event() {
+            Poll::Ready(Ok(Event::Reconnected(_))) => break,
+            Poll::Ready(e) => panic!("unexpected {:?}", e),
+            Poll::Pending => std::thread::sleep(Duration::from_millis(1)),
+        }
+    }
+    (agent, daemon)
+}
+
+/// The types of the messages the agent has received
+fn received(agent: &mut Connection) -> Vec<u32> {
+    let mut types = vec![];
+    while let Poll::Ready(event) = agent.read_event() {
+        match event.unwrap() {
+            Event::Message(buffer) => types.push(buffer.hdr().ty()),
+            e => panic!("unexpected {:?}", e),
+        }
+    }
+    types
+}
+
+fn send(agent: &mut Connection, message: OutgoingMessage) {
+    agent.send_message(&message, WINDOW.into()).unwrap()
+}
+
+fn create(agent: &mut Connection, size: WindowSize) {
+    let create = qubes_gui::Create {
+        rectangle: Rectangle {
+            top_left: Coordinates { x: 10, y: 20 },
+            size,
+        },
+        parent: None,
+        override_redirect: 0,
+    };
+    send(agent, OutgoingMessage::Create(create))
+}
+
+fn dump(agent: &mut Connection, size: WindowSize, grant_ref: u32) {
+    let header = qubes_gui::WindowDumpHeader {
+        ty: qubes_gui::WINDOW_DUMP_TYPE_GRANT_REFS,
+        width: size.width,
+        height: size.height,
+        bpp: 24,
+    };
+    let grant_refs = vec![grant_ref];
+    send(agent, OutgoingMessage::WindowDump { header, grant_refs })
+}
+
+#[test]
+fn draws_windows() {
+    let dir = std::env::temp_dir().join(format!("qubes-gui-headless-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let (mut agent, daemon) = connect();
+    let mut daemon = daemon.dump_pngs(&dir);
+    let size = WindowSize {
+        width: 2,
+        height: 2,
+    };
+    create(&mut agent, size);
+    let title = qubes_gui::WMName::new("hello").unwrap();
+    send(&mut agent, OutgoingMessage::SetTitle(title));
+    send(&mut agent, OutgoingMessage::Map(Default::default()));
+    dump(&mut agent, size, 0x100);
+    daemon.process().unwrap();
+    let window = daemon.window(WINDOW).unwrap();
+    assert!(window.mapped());
+    assert_eq!(window.untrusted_title(), "hello");
+    assert_eq!(window.image().pixels(), [0x100, 0x101, 0x102, 0x103]);
+    let mut png = vec![];
+    window.image().write_png(&mut png).unwrap();
+    let mut reader = png::Decoder::new(&png[..]).read_info().unwrap();
+    let mut rgb = vec![0; reader.output_buffer_size()];
+    reader.next_frame(&mut rgb).unwrap();
+    assert_eq!(rgb, [0, 1, 0, 0, 1, 1, 0, 1, 2, 0, 1, 3]);
+    let dumped = std::fs::read(dir.join("window-1-0.png")).unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert_eq!(dumped, png);
+}
+
+/// Allocates buffers whose only grant reference counts up from 0x100
+#[derive(Debug)]
+struct Allocator(u32);
+
+impl GrantAllocator for Allocator {
+    type Memory = Vec<u32>;
+
+    fn allocate(&mut self, _: NonZeroU32, size: WindowSize) -> io::Result<(Vec<u32>, Vec<u32>)> {
+        self.0 += 0x100;
+        let area = size.checked_area().unwrap() as usize;
+        Ok((vec![0; area], vec![self.0]))
+    }
+}
+
+#[test]
+fn dumps_are_acknowledged() {
+    let (mut agent, mut daemon) = connect();
+    let size = WindowSize {
+        width: 2,
+        height: 2,
+    };
+    create(&mut agent, size);
+    let mut surface = ResizableSurface::new(&mut agent, WINDOW, size, Allocator(0)).unwrap();
+    daemon.process().unwrap();
+    assert_eq!(received(&mut agent), [qubes_gui::MSG_WINDOW_DUMP_ACK]);
+    surface.dump_acknowledged(&mut agent).unwrap();
+    for width in [2, 1] {
+        surface
+            .resize(&mut agent, WindowSize { width, ..size })
+            .unwrap();
+        assert!(surface.resizing());
+        daemon.process().unwrap();
+        assert_eq!(received(&mut agent), [qubes_gui::MSG_WINDOW_DUMP_ACK]);
+        surface.dump_acknowledged(&mut agent).unwrap();
+        assert!(!surface.resizing());
+    }
+    daemon.process().unwrap();
+    assert_eq!(daemon.window(WINDOW).unwrap().image().pixels()[0], 0x300);
+}
+
+#[test]
+fn configure_is_confirmed() {
+    let (mut agent, mut daemon) = connect();
+    create(
+        &mut agent,
+        WindowSize {
+            width: 2,
+            height: 2,
+        },
+    );
+    let size = WindowSize {
+        width: 3,
+        height: 1,
+    };
+    let configure = qubes_gui::Configure {
+        rectangle: Rectangle {
+            top_left: Coordinates { x: 5, y: 5 },
+            size,
+        },
+        override_redirect: 0,
+    };
+    send(&mut agent, OutgoingMessage::Configure(configure));
+    daemon.process().unwrap();
+    assert_eq!(received(&mut agent), [qubes_gui::MSG_CONFIGURE]);
+    let window = daemon.window(WINDOW).unwrap();
+    assert_eq!(window.rectangle(), configure.rectangle);
+    assert_eq!(window.image().size(), size);
+}
+
+#[test]
+fn rate_limits() {
+    let (mut agent, daemon) = connect();
+    let limit = RateLimit {
+        per_second: NonZeroU32::new(1).unwrap(),
+        burst: 1,
+    };
+    let mut daemon = daemon.rate_limits(RateLimiter::new().limit(qubes_gui::MSG_SET_TITLE, limit));
+    let size = WindowSize {
+        width: 1,
+        height: 1,
+    };
+    create(&mut agent, size);
+    for title in ["first", "second"] {
+        let title = qubes_gui::WMName::new(title).unwrap();
+        send(&mut agent, OutgoingMessage::SetTitle(title));
+    }
+    daemon.process().unwrap();
+    assert_eq!(daemon.window(WINDOW).unwrap().untrusted_title(), "first");
+    assert_eq!(daemon.rate_limited(), 1);
+}
+
+#[test]
+fn quota() {
+    let (mut agent, daemon) = connect();
+    let mut daemon = daemon.quota(Quota {
+        max_windows: 1,
+        ..Default::default()
+    });
+    let size = WindowSize {
+        width: 1,
+        height: 1,
+    };
+    create(&mut agent, size);
+    let create = qubes_gui::Create {
+        rectangle: Rectangle {
+            top_left: Coordinates::default(),
+            size,
+        },
+        parent: None,
+        override_redirect: 0,
+    };
+    let other = NonZeroU32::new(2).unwrap();
+    (agent.send_message(&OutgoingMessage::Create(create), other.into())).unwrap();
+    match daemon.process() {
+        Err(Error::Quota(qubes_gui_daemon_proto::QuotaViolation::Windows { limit: 1 })) => {}
+        e => panic!("unexpected {:?}", e),
+    }
+    assert!(daemon.window(other).is_none());
+}
+
+#[test]
+fn destroy_is_acknowledged() {
+    let (mut agent, mut daemon) = connect();
+    create(
+        &mut agent,
+        WindowSize {
+            width: 1,
+            height: 1,
+        },
+    );
+    send(&mut agent, OutgoingMessage::Destroy);
+    daemon.process().unwrap();
+    assert_eq!(received(&mut agent), [qubes_gui::MSG_DESTROY]);
+    assert!(daemon.window(WINDOW).is_none());
+    send(&mut agent, OutgoingMessage::Unmap);
+    match daemon.process() {
+        Err(Error::Registry(_)) => {}
+        e => panic!("unexpected {:?}", e),
+    }
+}
+
+#[test]
+fn injects_input() {
+    let (mut agent, mut daemon) = connect();
+    let at = Coordinates { x: 1, y: 1 };
+    daemon.key(WINDOW, 38, KeyEvent::Press).unwrap();
+    daemon.button(WINDOW, at, 1, ButtonEvent::Press).unwrap();
+    daemon.motion(WINDOW, at).unwrap();
+    daemon.crossing(WINDOW, at, false).unwrap();
+    daemon.focus(WINDOW, FocusEvent::In).unwrap();
+    daemon.keymap(WINDOW, [38]).unwrap();
+    daemon.close(WINDOW).unwrap();
+    assert_eq!(
+        received(&mut agent),
+        [
+            qubes_gui::MSG_KEYPRESS,
+            qubes_gui::MSG_BUTTON,
+            qubes_gui::MSG_MOTION,
+            qubes_gui::MSG_CROSSING,
+            qubes_gui::MSG_KEYMAP_NOTIFY,
+            qubes_gui::MSG_FOCUS,
+            qubes_gui::MSG_KEYMAP_NOTIFY,
+            qubes_gui::MSG_CLOSE,
+        ]
+    );
+}
+
+#[test]
+fn focus_in_sends_pressed_keys() {
+    let (mut agent, mut daemon) = connect();
+    daemon.key(WINDOW, 50, KeyEvent::Press).unwrap();
+    daemon.key(WINDOW, 38, KeyEvent::Press).unwrap();
+    daemon.key(WINDOW, 38, KeyEvent::Release).unwrap();
+    daemon.focus(WINDOW, FocusEvent::Out).unwrap();
+    received(&mut agent);
+    daemon.focus(WINDOW, FocusEvent::In).unwrap();
+    let mut keymaps = vec![];
+    while let Poll::Ready(event) = agent.read_event() {
+        match event.unwrap() {
+            Event::Message(buffer) if buffer.hdr().ty() == qubes_gui::MSG_KEYMAP_NOTIFY => {
+                let keys = qubes_gui::KeymapNotify {
+                    keys: buffer.body().try_into().unwrap(),
+                };
+                keymaps.push(keys.pressed().collect::<Vec<_>>())
+            }
+            Event::Message(buffer) => assert_eq!(buffer.hdr().ty(), qubes_gui::MSG_FOCUS),
+            e => panic!("unexpected {:?}", e),
+        }
+    }
+    assert_eq!(keymaps, [[50]]);
+}
@@ -0,0 +1,182 @@
+/*
+ * The Qubes OS Project, https://www.qubes-os.org
+ *
+ * Copyright (C) 2021  Demi Marie Obenour  <demi@invisiblethingslab.com>
+ *
+ * This program is free software; you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License
+ * as published by the Free Software Foundation; either version 2
+ * of the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301, USA.
+ *
+ */
+
+//! Inter-qube clipboard policy
+
+use alloc::string::String;
+use alloc::vec::Vec;
+
+/// The outcome of a clipboard policy check
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ClipboardVerdict {
+    /// Go ahead
+    Allow,
+    /// Refuse
+    Deny,
+    /// Hold the operation until the user confirms it, then go ahead; or
+    /// drop it if the user refuses
+    Confirm,
+}
+
+/// Decides what may be copied from and pasted into each qube.
+///
+/// The daemon owns the global clipboard.  When the user copies from a
+/// qube, the daemon calls [`ClipboardPolicy::copy`] and, if allowed, sends
+/// `MSG_CLIPBOARD_REQ`.  The qube’s `MSG_CLIPBOARD_DATA` reply goes through
+/// [`ClipboardPolicy::filter`] before being stored.  When the user pastes,
+/// the daemon calls [`ClipboardPolicy::paste`] and, if allowed, sends the
+/// stored data to the target in a `MSG_CLIPBOARD_DATA`.
+pub trait ClipboardPolicy {
+    /// The user wants to copy from `qube`
+    fn copy(&mut self, qube: &str) -> ClipboardVerdict;
+
+    /// Filter UNTRUSTED clipboard data received from `qube`.  Returns the
+    /// data to store, which may be a prefix of `untrusted`, or `None` to
+    /// drop it.
+    fn filter<'a>(&mut self, qube: &str, untrusted: &'a [u8]) -> Option<&'a [u8]>;
+
+    /// The user wants to paste data copied from `source` into `target`
+    fn paste(&mut self, source: &str, target: &str) -> ClipboardVerdict;
+}
+
+/// `pattern` matches `qube` if it is equal to it or is `*`
+fn matches(pattern: &str, qube: &str) -> bool {
+    pattern == "*" || pattern == qube
+}
+
+/// A [`ClipboardPolicy`] made of rules.
+///
+/// Rules are checked in the order they were added, and the first match
+/// wins.  A qube name of `*` matches any qube.  If no rule matches, the
+/// operation is allowed.  Data longer than the maximum size is truncated,
+/// and (by default) data that is not UTF-8 is dropped.
+#[derive(Debug)]
+pub struct ClipboardRules {
+    copy: Vec<(String, ClipboardVerdict)>,
+    paste: Vec<(String, String, ClipboardVerdict)>,
+    max_size: usize,
+    require_utf8: bool,
+}
+
+impl Default for ClipboardRules {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ClipboardRules {
+    /// No rules, a maximum size of [`qubes_gui::MAX_CLIPBOARD_SIZE`], and
+    /// UTF-8 required
+    pub fn new() -> Self {
+        Self {
+            copy: Vec::new(),
+            paste: Vec::new(),
+            max_size: qubes_gui::MAX_CLIPBOARD_SIZE as usize,
+            require_utf8: true,
+        }
+    }
+
+    /// Add a rule for copying from `qube`
+    pub fn copy_rule(mut self, qube: &str, verdict: ClipboardVerdict) -> Self {
+        self.copy.push((qube.into(), verdict));
+        self
+    }
+
+    /// Add a rule for pasting data from `source` into `target`
+    pub fn paste_rule(mut self, source: &str, target: &str, verdict: ClipboardVerdict) -> Self {
+        self.paste.push((source.into(), target.into(), verdict));
+        self
+    }
+
+    /// Truncate data to `max_size` bytes
+    pub fn max_size(mut self, max_size: usize) -> Self {
+        self.max_size = max_size;
+        self
+    }
+
+    /// Require (`true`) or do not require (`false`) UTF-8.  If UTF-8 is
+    /// required, truncation never splits a character.
+    pub fn require_utf8(mut self, require_utf8: bool) -> Self {
+        self.require_utf8 = require_utf8;
+        self
+    }
+}
+
+impl ClipboardPolicy for ClipboardRules {
+    fn copy(&mut self, qube: &str) -> ClipboardVerdict {
+        self.copy
+            .iter()
+            .find(|(pattern, _)| matches(pattern, qube))
+            .map_or(ClipboardVerdict::Allow, |&(_, verdict)| verdict)
+    }
+
+    fn filter<'a>(&mut self, _qube: &str, untrusted: &'a [u8]) -> Option<&'a [u8]> {
+        let data = &untrusted[..untrusted.len().min(self.max_size)];
+        if !self.require_utf8 {
+            return Some(data);
+        }
+        match core::str::from_utf8(data) {
+            Ok(_) => Some(data),
+            // Only the last character was cut off by truncation
+            Err(e) if e.error_len().is_none() && data.len() < untrusted.len() => {
+                Some(&data[..e.valid_up_to()])
+            }
+            Err(_) => None,
+        }
+    }
+
+    fn paste(&mut self, source: &str, target: &str) -> ClipboardVerdict {
+        self.paste
+            .iter()
+            .find(|(s, t, _)| matches(s, source) && matches(t, target))
+            .map_or(ClipboardVerdict::Allow, |&(_, _, verdict)| verdict)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rules() {
+        let mut rules = ClipboardRules::new()
+            .copy_rule("vault", ClipboardVerdict::Deny)
+            .paste_rule("work", "personal", ClipboardVerdict::Confirm)
+            .paste_rule("*", "vault", ClipboardVerdict::Deny);
+        assert_eq!(rules.copy("vault"), ClipboardVerdict::Deny);
+        assert_eq!(rules.copy("work"), ClipboardVerdict::Allow);
+        assert_eq!(rules.paste("work", "personal"), ClipboardVerdict::Confirm);
+        assert_eq!(rules.paste("personal", "work"), ClipboardVerdict::Allow);
+        assert_eq!(rules.paste("work", "vault"), ClipboardVerdict::Deny);
+    }
+
+    #[test]
+    fn filter() {
+        let mut rules = ClipboardRules::new().max_size(4);
+        assert_eq!(rules.filter("work", b"abc"), Some(&b"abc"[..]));
+        assert_eq!(rules.filter("work", b"abcdef"), Some(&b"abcd"[..]));
+        // “é” is two bytes, and would be split
+        assert_eq!(rules.filter("work", "abcé".as_bytes()), Some(&b"abc"[..]));
+        assert_eq!(rules.filter("work", b"a\xffb"), None);
+        let mut rules = rules.require_utf8(false);
+        assert_eq!(rules.filter("work", b"a\xffbcd"), Some(&b"a\xffbc"[..]));
+    }
+}
@@ -0,0 +1,136 @@
+/*
+ * The Qubes OS Project, https://www.qubes-os.org
+ *
+ * Copyright (C) 2021  Demi Marie Obenour  <demi@invisiblethingslab.com>
+ *
+ * This program is free software; you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License
+ * as published by the Free Software Foundation; either version 2
+ * of the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301, USA.
+ *
+ */
+
+//! Experimental: importing GPU buffers that agents share, as an alternative
+//! to [`crate::GrantMapper`]
+
+use core::num::NonZeroU32;
+use qubes_castable::Castable as _;
+use qubes_gui::proposed::dmabuf::{DmabufDump, ValidDmabufDump};
+use qubes_gui::proposed::FEATURE_DMABUF;
+use std::io;
+
+/// Imports the GPU buffers that agents share with the proposed
+/// `MSG_WINDOW_DUMP_DMABUF`
+pub trait DmabufImporter {
+    /// An imported buffer
+    type Buffer;
+
+    /// The feature bits to send to agents.  [`FEATURE_DMABUF`] is added if
+    /// the importer can import anything at all.
+    fn features(&self, features: u32) -> u32 {
+        features | FEATURE_DMABUF
+    }
+
+    /// Import the buffer described by `dump`, for `window`
+    ///
+    /// # Errors
+    ///
+    /// Fails if the buffer cannot be imported, such as if the resource does
+    /// not exist or is too small.  This is not necessarily the agent’s
+    /// fault.
+    fn import(&mut self, window: NonZeroU32, dump: &ValidDmabufDump) -> io::Result<Self::Buffer>;
+}
+
+/// An importer for daemons without GPU support, which imports nothing and
+/// does not advertise [`FEATURE_DMABUF`]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct NoDmabuf;
+
+impl DmabufImporter for NoDmabuf {
+    type Buffer = core::convert::Infallible;
+
+    fn features(&self, features: u32) -> u32 {
+        features & !FEATURE_DMABUF
+    }
+
+    fn import(&mut self, _: NonZeroU32, _: &ValidDmabufDump) -> io::Result<Self::Buffer> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+}
+
+/// Validate the body of a `MSG_WINDOW_DUMP_DMABUF` for `window`, sent by an
+/// agent that was sent `features`, and import the buffer with `importer`
+///
+/// # Errors
+///
+/// Fails with [`io::ErrorKind::InvalidData`] if the body is not valid, and
+/// otherwise if the importer fails.
+pub fn import_dump<I: DmabufImporter + ?Sized>(
+    importer: &mut I,
+    window: NonZeroU32,
+    body: &[u8],
+    features: u32,
+) -> io::Result<I::Buffer> {
+    if body.len() != core::mem::size_of::<DmabufDump>() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            std::format!("Bad length {} of MSG_WINDOW_DUMP_DMABUF", body.len()),
+        ));
+    }
+    let dump = DmabufDump::from_bytes(body)
+        .validate(features)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    importer.import(window, &dump)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use qubes_gui::proposed::dmabuf::DRM_FORMAT_XRGB8888;
+
+    /// Imports every buffer as its resource ID
+    struct Ids;
+
+    impl DmabufImporter for Ids {
+        type Buffer = u32;
+
+        fn import(&mut self, _: NonZeroU32, dump: &ValidDmabufDump) -> io::Result<u32> {
+            Ok(dump.resource_id().get())
+        }
+    }
+
+    #[test]
+    fn imports() {
+        let window = NonZeroU32::new(2).unwrap();
+        let dump = DmabufDump {
+            resource_id: 9,
+            width: 4,
+            height: 4,
+            fourcc: DRM_FORMAT_XRGB8888,
+            stride: 16,
+            ..Default::default()
+        };
+        let features = Ids.features(0);
+        assert_eq!(features, FEATURE_DMABUF);
+        assert_eq!(
+            import_dump(&mut Ids, window, dump.as_bytes(), features).unwrap(),
+            9
+        );
+        let err = import_dump(&mut Ids, window, dump.as_bytes(), 0).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        let err = import_dump(&mut Ids, window, &[0; 4], features).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(NoDmabuf.features(FEATURE_DMABUF | 1), 1);
+        let err = import_dump(&mut NoDmabuf, window, dump.as_bytes(), features).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+    }
+}
@@ -0,0 +1,224 @@
+/*
+ * The Qubes OS Project, https://www.qubes-os.org
+ *
+ * Copyright (C) 2021  Demi Marie Obenour  <demi@invisiblethingslab.com>
+ *
+ * This program is free software; you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License
+ * as published by the Free Software Foundation; either version 2
+ * of the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301, USA.
+ *
+ */
+
+//! Clamping untrusted window geometry to the screen layout
+
+use crate::session::clamp_size;
+use crate::SizeLimits;
+use alloc::vec::Vec;
+use qubes_gui::{Configure, Coordinates, Rectangle, WindowSize};
+
+/// The edges of a rectangle: left, top, right, and bottom.  The right and
+/// bottom edges are exclusive.
+fn edges(r: Rectangle) -> (i64, i64, i64, i64) {
+    let (left, top) = (i64::from(r.top_left.x), i64::from(r.top_left.y));
+    (
+        left,
+        top,
+        left + i64::from(r.size.width),
+        top + i64::from(r.size.height),
+    )
+}
+
+/// Clamp `value` to `[min, max]`, preferring `min` if `max < min`
+fn clamp(value: i64, min: i64, max: i64) -> i64 {
+    value.min(max).max(min)
+}
+
+/// Decides where the agent’s windows may go on the daemon’s screen.
+///
+/// Positions and sizes from the agent are untrusted.  Rather than rejecting
+/// requests that are out of bounds, this rewrites them:
+///
+/// - Sizes are clamped to the [`SizeLimits`].
+/// - A managed window’s top left corner is moved onto the nearest monitor,
+///   so the window manager can always reach it.
+/// - An override-redirect window is not managed by the window manager, so it
+///   is shrunk to fit the nearest monitor and moved entirely onto it.  This
+///   keeps it from covering more than one monitor or hiding off screen.
+#[derive(Debug, Clone)]
+pub struct GeometryPolicy {
+    monitors: Vec<Rectangle>,
+    limits: SizeLimits,
+}
+
+impl GeometryPolicy {
+    /// Create a policy for a screen made of `monitors`, with the default
+    /// [`SizeLimits`].  Empty monitors are ignored.
+    ///
+    /// # Panics
+    ///
+    /// Panics if there are no monitors that are not empty.
+    pub fn new(monitors: &[Rectangle]) -> Self {
+        let monitors: Vec<Rectangle> = monitors.iter().copied().filter(|m| !m.is_empty()).collect();
+        assert!(!monitors.is_empty(), "No monitors");
+        Self {
+            monitors,
+            limits: SizeLimits::default(),
+        }
+    }
+
+    /// Set the size limits
+    pub fn limits(mut self, limits: SizeLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// The monitors, in the order given to [`GeometryPolicy::new`]
+    pub fn monitors(&self) -> &[Rectangle] {
+        &self.monitors
+    }
+
+    /// The monitor nearest to `point`.  Ties go to the first monitor.
+    pub fn nearest_monitor(&self, point: Coordinates) -> Rectangle {
+        let (x, y) = (i64::from(point.x), i64::from(point.y));
+        let distance = |m: &Rectangle| {
+            let (left, top, right, bottom) = edges(*m);
+            (x - clamp(x, left, right - 1)).abs() + (y - clamp(y, top, bottom - 1)).abs()
+        };
+        let mut best = self.monitors[0];
+        for monitor in &self.monitors[1..] {
+            if distance(monitor) < distance(&best) {
+                best = *monitor
+            }
+        }
+        best
+    }
+
+    /// Clamp an untrusted rectangle.  See [`GeometryPolicy`] for the rules.
+    pub fn clamp(&self, untrusted: Rectangle, override_redirect: bool) -> Rectangle {
+        let mut size = clamp_size(untrusted.size, self.limits.min, self.limits.max);
+        let monitor = self.nearest_monitor(untrusted.top_left);
+        let (left, top, right, bottom) = edges(monitor);
+        let (mut x, mut y) = (
+            i64::from(untrusted.top_left.x),
+            i64::from(untrusted.top_left.y),
+        );
+        if override_redirect {
+            size = WindowSize {
+                width: size.width.min(monitor.size.width),
+                height: size.height.min(monitor.size.height),
+            };
+            x = clamp(x, left, right - i64::from(size.width));
+            y = clamp(y, top, bottom - i64::from(size.height));
+        } else {
+            x = clamp(x, left, right - 1);
+            y = clamp(y, top, bottom - 1);
+        }
+        // Keep the right and bottom edges representable
+        let x = x.min(i64::from(i32::MAX) - i64::from(size.width));
+        let y = y.min(i64::from(i32::MAX) - i64::from(size.height));
+        Rectangle {
+            top_left: Coordinates {
+                x: x as i32,
+                y: y as i32,
+            },
+            size,
+        }
+    }
+
+    /// Rewrite an untrusted `MSG_CONFIGURE` so that it is in bounds.  The
+    /// result equals `untrusted` if it already was.  Any nonzero
+    /// `override_redirect` is treated as 1.
+    pub fn configure(&self, untrusted: &Configure) -> Configure {
+        let override_redirect = untrusted.override_redirect != 0;
+        Configure {
+            rectangle: self.clamp(untrusted.rectangle, override_redirect),
+            override_redirect: override_redirect.into(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rect(x: i32, y: i32, width: u32, height: u32) -> Rectangle {
+        Rectangle {
+            top_left: Coordinates { x, y },
+            size: WindowSize { width, height },
+        }
+    }
+
+    fn policy() -> GeometryPolicy {
+        GeometryPolicy::new(&[rect(0, 0, 1920, 1080), rect(1920, 0, 1280, 1024)])
+    }
+
+    #[test]
+    fn managed_windows() {
+        let policy = policy();
+        let ok = rect(100, 100, 800, 600);
+        assert_eq!(policy.clamp(ok, false), ok);
+        // Spanning monitors is fine for managed windows
+        let wide = rect(1800, 0, 800, 600);
+        assert_eq!(policy.clamp(wide, false), wide);
+        assert_eq!(
+            policy.clamp(rect(-500, 5000, 0, 100), false),
+            rect(0, 1079, 1, 100)
+        );
+        assert_eq!(
+            policy.clamp(rect(i32::MAX, 10, 100, 100), false),
+            rect(3199, 10, 100, 100)
+        );
+    }
+
+    #[test]
+    fn override_redirect_windows() {
+        let policy = policy();
+        assert_eq!(
+            policy.clamp(rect(1800, 1000, 300, 200), true),
+            rect(1620, 880, 300, 200)
+        );
+        assert_eq!(
+            policy.clamp(rect(2000, 0, 5000, 5000), true),
+            rect(1920, 0, 1280, 1024)
+        );
+        let configure = Configure {
+            rectangle: rect(-10, -10, 100, 100),
+            override_redirect: 7,
+        };
+        assert_eq!(
+            policy.configure(&configure),
+            Configure {
+                rectangle: rect(0, 0, 100, 100),
+                override_redirect: 1,
+            }
+        );
+    }
+
+    #[test]
+    fn size_limits() {
+        let policy = policy().limits(SizeLimits {
+            min: WindowSize {
+                width: 10,
+                height: 10,
+            },
+            max: WindowSize {
+                width: 1000,
+                height: 1000,
+            },
+        });
+        assert_eq!(
+            policy.clamp(rect(0, 0, 1, 5000), false),
+            rect(0, 0, 10, 1000)
+        );
+    }
+}
@@ -0,0 +1,41 @@
+/*
+ * The Qubes OS Project, https://www.qubes-os.org
+ *
+ * Copyright (C) 2021  Demi Marie Obenour  <demi@invisiblethingslab.com>
+ *
+ * This program is free software; you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License
+ * as published by the Free Software Foundation; either version 2
+ * of the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301, USA.
+ *
+ */
+
+//! Mapping shared composition buffers
+
+use crate::WindowDump;
+use core::num::NonZeroU32;
+use std::io;
+
+/// Maps the composition buffers that agents share.  Each buffer holds one
+/// `u32` per pixel, in rows from top to bottom.
+pub trait GrantMapper {
+    /// A mapped buffer
+    type Buffer: AsRef<[u32]>;
+
+    /// Map the buffer described by `dump`, for `window`
+    ///
+    /// # Errors
+    ///
+    /// Fails if the buffer cannot be mapped.  This is not necessarily the
+    /// agent’s fault.
+    fn map(&mut self, window: NonZeroU32, dump: &WindowDump<'_>) -> io::Result<Self::Buffer>;
+}
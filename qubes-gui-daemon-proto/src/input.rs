@@ -0,0 +1,202 @@
+/*
+ * The Qubes OS Project, https://www.qubes-os.org
+ *
+ * Copyright (C) 2021  Demi Marie Obenour  <demi@invisiblethingslab.com>
+ *
+ * This program is free software; you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License
+ * as published by the Free Software Foundation; either version 2
+ * of the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301, USA.
+ *
+ */
+
+//! Building input events for agents.
+//!
+//! The arguments of these functions can only express valid events, so the
+//! wire structs they return satisfy the constraints documented in
+//! `qubes-gui`.  Daemons that build every input event here cannot send one
+//! that agents must reject.
+
+use core::convert::TryFrom;
+use qubes_gui::{
+    ButtonEvent, Coordinates, CrossingDetail, CrossingMode, FocusDetail, FocusEvent, KeyEvent,
+};
+
+/// X11 `EnterNotify`
+const ENTER_NOTIFY: u32 = 7;
+/// X11 `LeaveNotify`
+const LEAVE_NOTIFY: u32 = 8;
+
+/// A key event, for the key with X11 keycode `keycode`.  `state` is the X11
+/// modifier state.
+pub fn keypress(
+    event: KeyEvent,
+    coordinates: Coordinates,
+    state: u32,
+    keycode: u8,
+) -> qubes_gui::Keypress {
+    qubes_gui::Keypress {
+        ty: event as u32,
+        coordinates,
+        state,
+        keycode: keycode.into(),
+    }
+}
+
+/// A button event, for X11 button number `button`.  `state` is the X11
+/// modifier state.
+pub fn button(
+    event: ButtonEvent,
+    coordinates: Coordinates,
+    state: u32,
+    button: u32,
+) -> qubes_gui::Button {
+    qubes_gui::Button {
+        ty: event as u32,
+        coordinates,
+        state,
+        button,
+    }
+}
+
+/// A motion event.  `state` is the X11 modifier and button state.
+pub fn motion(coordinates: Coordinates, state: u32, is_hint: bool) -> qubes_gui::Motion {
+    qubes_gui::Motion {
+        coordinates,
+        state,
+        is_hint: is_hint.into(),
+    }
+}
+
+/// A crossing event: the pointer has entered (if `entered` is true) or left
+/// a window.  `focus` is whether the window has the keyboard focus.
+pub fn crossing(
+    entered: bool,
+    coordinates: Coordinates,
+    state: u32,
+    mode: CrossingMode,
+    detail: CrossingDetail,
+    focus: bool,
+) -> qubes_gui::Crossing {
+    qubes_gui::Crossing {
+        ty: if entered { ENTER_NOTIFY } else { LEAVE_NOTIFY },
+        coordinates,
+        state,
+        mode: mode as u32,
+        detail: detail as u32,
+        focus: focus.into(),
+    }
+}
+
+/// A focus event.  The mode, which is not part of the protocol, is always 0.
+pub fn focus(event: FocusEvent, detail: FocusDetail) -> qubes_gui::Focus {
+    qubes_gui::Focus {
+        ty: event as u32,
+        mode: 0,
+        detail: detail as u32,
+    }
+}
+
+/// A keymap with the keys with X11 keycodes in `pressed` pressed.  Keycodes
+/// below 8 do not exist in X11, and are ignored.
+pub fn keymap(pressed: impl IntoIterator<Item = u8>) -> qubes_gui::KeymapNotify {
+    let mut keymap = qubes_gui::KeymapNotify::default();
+    for keycode in pressed.into_iter().filter(|&keycode| keycode >= 8) {
+        keymap.set(keycode)
+    }
+    keymap
+}
+
+/// The keymap returned by X11 `QueryKeymap`, which already has the format of
+/// `MSG_KEYMAP_NOTIFY`.  Bits for keycodes below 8 are cleared.
+pub fn keymap_from_x11(keys: [u8; 32]) -> qubes_gui::KeymapNotify {
+    let mut keymap = qubes_gui::KeymapNotify { keys };
+    keymap.keys[0] = 0;
+    keymap
+}
+
+/// A keymap with the keys with evdev (Linux input) codes in `pressed`
+/// pressed.  X11 and xkb keycodes are evdev codes plus 8, so codes above 247
+/// have no X11 keycode, and are ignored.
+pub fn keymap_from_evdev(pressed: impl IntoIterator<Item = u16>) -> qubes_gui::KeymapNotify {
+    keymap(
+        pressed
+            .into_iter()
+            .filter_map(|code| u8::try_from(u32::from(code) + 8).ok()),
+    )
+}
+
+/// The messages to send, in order, when a window gains focus.  `keymap` must
+/// be the state of the keyboard at that moment.
+///
+/// Keys pressed or released while another window had focus are never
+/// reported to the agent.  Without the keymap, an agent that saw a modifier
+/// pressed but not released would treat every later key as modified.
+pub fn focus_in(
+    detail: FocusDetail,
+    keymap: qubes_gui::KeymapNotify,
+) -> (qubes_gui::KeymapNotify, qubes_gui::Focus) {
+    (keymap, focus(FocusEvent::In, detail))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use qubes_gui_agent_proto::{TrustedButton, TrustedCrossing, TrustedFocus, TrustedKeypress};
+
+    const AT: Coordinates = Coordinates { x: 3, y: -4 };
+
+    #[test]
+    fn agents_accept_events() {
+        for &event in KeyEvent::ALL {
+            let trusted = TrustedKeypress::validate(&keypress(event, AT, 1, 38)).unwrap();
+            assert_eq!((trusted.event(), trusted.keycode()), (event, 38));
+        }
+        for &event in ButtonEvent::ALL {
+            let trusted = TrustedButton::validate(&button(event, AT, 0, 3)).unwrap();
+            assert_eq!((trusted.event(), trusted.button()), (event, 3));
+        }
+        for &event in FocusEvent::ALL {
+            for &detail in FocusDetail::ALL {
+                let trusted = TrustedFocus::validate(&focus(event, detail)).unwrap();
+                assert_eq!((trusted.event(), trusted.detail()), (event, detail));
+            }
+        }
+        for &mode in CrossingMode::ALL {
+            for &entered in &[false, true] {
+                let event = crossing(entered, AT, 0, mode, CrossingDetail::Nonlinear, entered);
+                let trusted = TrustedCrossing::validate(&event).unwrap();
+                assert_eq!(trusted.entered(), entered);
+                assert_eq!(trusted.focus(), entered);
+                assert_eq!(trusted.mode(), mode);
+            }
+        }
+        assert_eq!(motion(AT, 0, true).is_hint, 1);
+    }
+
+    #[test]
+    fn keymap_skips_missing_keycodes() {
+        let keymap = keymap([3, 8, 255]);
+        assert_eq!(keymap.pressed().collect::<alloc::vec::Vec<_>>(), [8, 255]);
+    }
+
+    #[test]
+    fn keymap_sources_agree() {
+        let mut keys = [0xff; 32];
+        keys[1..].copy_from_slice(&[0; 31]);
+        keys[4] = 1;
+        let x11 = keymap_from_x11(keys);
+        assert_eq!(x11.pressed().collect::<alloc::vec::Vec<_>>(), [32]);
+        assert_eq!(x11, keymap_from_evdev([24, 248]));
+        assert_eq!(x11, keymap([32]));
+    }
+}
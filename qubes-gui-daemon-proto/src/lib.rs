@@ -0,0 +1,67 @@
+/*
+ * The Qubes OS Project, https://www.qubes-os.org
+ *
+ * Copyright (C) 2021  Demi Marie Obenour  <demi@invisiblethingslab.com>
+ *
+ * This program is free software; you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License
+ * as published by the Free Software Foundation; either version 2
+ * of the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301, USA.
+ *
+ */
+
+#![no_std]
+#![forbid(missing_docs)]
+#![forbid(clippy::all)]
+//! Daemon-side support for the Qubes OS GUI Protocol
+//!
+//! This crate holds the state a GUI daemon needs to keep about each agent it
+//! serves, and the policy decisions the daemon makes about what that agent
+//! asks for.  Like `qubes-gui-agent-proto`, it performs no I/O.  Unlike that
+//! crate, it needs an allocator.
+//!
+//! The `std` feature adds [`GrantMapper`], for daemons that draw from the
+//! composition buffers agents share.  The experimental `dmabuf` feature adds
+//! the `dmabuf` module, for daemons that import GPU buffers instead.
+
+extern crate alloc;
+#[cfg(feature = "std")]
+extern crate std;
+
+mod clipboard;
+#[cfg(feature = "dmabuf")]
+pub mod dmabuf;
+mod geometry;
+#[cfg(feature = "std")]
+mod grant;
+pub mod input;
+mod override_redirect;
+mod quota;
+mod ratelimit;
+mod registry;
+pub mod sanitize;
+mod session;
+mod title;
+mod visitor;
+
+pub use clipboard::{ClipboardPolicy, ClipboardRules, ClipboardVerdict};
+pub use geometry::GeometryPolicy;
+#[cfg(feature = "std")]
+pub use grant::GrantMapper;
+pub use override_redirect::{OverrideRedirect, OverrideRedirectPolicy};
+pub use qubes_gui::{PendingOps, WindowInfo};
+pub use quota::{Quota, QuotaTracker, QuotaViolation};
+pub use ratelimit::{RateLimit, RateLimited, RateLimiter};
+pub use registry::{RegistryError, WindowRegistry};
+pub use session::{DaemonSession, SizeConstraints, SizeLimits};
+pub use title::{Label, TitlePolicy};
+pub use visitor::{visit, visit_audited, Error, GrantRefs, MessageVisitor, Mfns, WindowDump};
@@ -0,0 +1,230 @@
+/*
+ * The Qubes OS Project, https://www.qubes-os.org
+ *
+ * Copyright (C) 2021  Demi Marie Obenour  <demi@invisiblethingslab.com>
+ *
+ * This program is free software; you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License
+ * as published by the Free Software Foundation; either version 2
+ * of the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301, USA.
+ *
+ */
+
+//! Limits on override-redirect windows
+
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::num::NonZeroU32;
+use core::time::Duration;
+use qubes_gui::WindowSize;
+
+/// What the daemon should do with an override-redirect window
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum OverrideRedirect {
+    /// Show the window without decorations, as the agent asked
+    Allow,
+    /// Treat the window as a normal, managed window, so that the window
+    /// manager decorates it with the qube’s label
+    Decorate,
+}
+
+/// Limits what override-redirect windows may do.
+///
+/// Override-redirect windows (menus, tooltips, and the like) bypass the
+/// window manager, so they have no colored border showing which qube they
+/// came from.  An agent could abuse this to draw a window that pretends to
+/// belong to another qube, or to dom0.  Like the C daemon, this policy
+/// forces decorations on override-redirect windows that are too large, that
+/// cover (nearly) the whole screen, or that stay mapped for too long.
+///
+/// Times are measured from an arbitrary fixed point, and must come from a
+/// monotonic clock.
+#[derive(Debug)]
+pub struct OverrideRedirectPolicy {
+    screen: WindowSize,
+    max_size: Option<WindowSize>,
+    max_coverage_percent: u32,
+    timeout: Option<Duration>,
+    /// When each allowed, mapped override-redirect window was mapped
+    mapped: BTreeMap<NonZeroU32, Duration>,
+}
+
+impl OverrideRedirectPolicy {
+    /// Create a policy for a screen of size `screen`.  By default, windows
+    /// may cover at most 90% of the screen, as in the C daemon, and there is
+    /// no maximum size or timeout.
+    pub fn new(screen: WindowSize) -> Self {
+        Self {
+            screen,
+            max_size: None,
+            max_coverage_percent: 90,
+            timeout: None,
+            mapped: BTreeMap::new(),
+        }
+    }
+
+    /// Set the size of the screen, such as after a monitor was added
+    pub fn set_screen(&mut self, screen: WindowSize) {
+        self.screen = screen
+    }
+
+    /// Decorate windows wider or taller than `max_size`
+    pub fn max_size(mut self, max_size: Option<WindowSize>) -> Self {
+        self.max_size = max_size;
+        self
+    }
+
+    /// Decorate windows covering more than `percent` percent of the screen
+    pub fn max_coverage_percent(mut self, percent: u32) -> Self {
+        self.max_coverage_percent = percent;
+        self
+    }
+
+    /// Decorate windows that stay mapped for longer than `timeout`.  See
+    /// [`OverrideRedirectPolicy::expired`].
+    pub fn timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Check a window of size `size`, ignoring the timeout
+    pub fn check(&self, size: WindowSize) -> OverrideRedirect {
+        if let Some(max) = self.max_size {
+            if size.width > max.width || size.height > max.height {
+                return OverrideRedirect::Decorate;
+            }
+        }
+        let area = u64::from(size.width) * u64::from(size.height);
+        let screen = u64::from(self.screen.width) * u64::from(self.screen.height);
+        if area * 100 > screen * u64::from(self.max_coverage_percent) {
+            OverrideRedirect::Decorate
+        } else {
+            OverrideRedirect::Allow
+        }
+    }
+
+    /// Handle the mapping of an override-redirect window of size `size` at
+    /// time `now`.  If it is allowed, its timeout starts.
+    pub fn map(&mut self, now: Duration, window: NonZeroU32, size: WindowSize) -> OverrideRedirect {
+        let decision = self.check(size);
+        match decision {
+            OverrideRedirect::Allow => {
+                self.mapped.entry(window).or_insert(now);
+            }
+            OverrideRedirect::Decorate => {
+                self.mapped.remove(&window);
+            }
+        }
+        decision
+    }
+
+    /// Handle a change in the size of a mapped override-redirect window.
+    /// Once a window has been decorated, it stays decorated until it is
+    /// unmapped.
+    pub fn configure(&mut self, window: NonZeroU32, size: WindowSize) -> OverrideRedirect {
+        if !self.mapped.contains_key(&window) {
+            return OverrideRedirect::Decorate;
+        }
+        let decision = self.check(size);
+        if decision == OverrideRedirect::Decorate {
+            self.mapped.remove(&window);
+        }
+        decision
+    }
+
+    /// Handle the unmapping or destruction of a window
+    pub fn unmap(&mut self, window: NonZeroU32) {
+        self.mapped.remove(&window);
+    }
+
+    /// Is `window` an override-redirect window that is currently allowed?
+    pub fn is_allowed(&self, window: NonZeroU32) -> bool {
+        self.mapped.contains_key(&window)
+    }
+
+    /// The windows whose timeout has expired at time `now`, in order of
+    /// window ID.  The daemon must decorate them.  They are no longer
+    /// tracked.
+    pub fn expired(&mut self, now: Duration) -> Vec<NonZeroU32> {
+        let timeout = match self.timeout {
+            Some(timeout) => timeout,
+            None => return Vec::new(),
+        };
+        let expired: Vec<NonZeroU32> = self
+            .mapped
+            .iter()
+            .filter(|&(_, &since)| now.saturating_sub(since) >= timeout)
+            .map(|(&window, _)| window)
+            .collect();
+        for window in &expired {
+            self.mapped.remove(window);
+        }
+        expired
+    }
+
+    /// The next time at which [`OverrideRedirectPolicy::expired`] will
+    /// return a window, if any
+    pub fn next_deadline(&self) -> Option<Duration> {
+        let timeout = self.timeout?;
+        self.mapped.values().min().map(|&since| since + timeout)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn size(width: u32, height: u32) -> WindowSize {
+        WindowSize { width, height }
+    }
+
+    fn id(n: u32) -> NonZeroU32 {
+        NonZeroU32::new(n).unwrap()
+    }
+
+    #[test]
+    fn size_limits() {
+        let policy = OverrideRedirectPolicy::new(size(1000, 1000)).max_size(Some(size(800, 2000)));
+        assert_eq!(policy.check(size(300, 200)), OverrideRedirect::Allow);
+        assert_eq!(policy.check(size(801, 1)), OverrideRedirect::Decorate);
+        // Exactly 90% of the screen is allowed
+        assert_eq!(policy.check(size(800, 1125)), OverrideRedirect::Allow);
+        assert_eq!(policy.check(size(800, 1126)), OverrideRedirect::Decorate);
+    }
+
+    #[test]
+    fn timeout() {
+        let secs = Duration::from_secs;
+        let mut policy = OverrideRedirectPolicy::new(size(1000, 1000)).timeout(Some(secs(10)));
+        assert_eq!(
+            policy.map(secs(0), id(1), size(10, 10)),
+            OverrideRedirect::Allow
+        );
+        assert_eq!(
+            policy.map(secs(5), id(2), size(10, 10)),
+            OverrideRedirect::Allow
+        );
+        assert_eq!(policy.next_deadline(), Some(secs(10)));
+        assert_eq!(policy.expired(secs(9)), []);
+        assert_eq!(policy.expired(secs(10)), [id(1)]);
+        assert!(!policy.is_allowed(id(1)) && policy.is_allowed(id(2)));
+        assert_eq!(
+            policy.configure(id(2), size(1000, 1000)),
+            OverrideRedirect::Decorate
+        );
+        assert_eq!(
+            policy.configure(id(2), size(10, 10)),
+            OverrideRedirect::Decorate
+        );
+        assert_eq!(policy.next_deadline(), None);
+    }
+}
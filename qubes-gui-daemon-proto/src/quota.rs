@@ -0,0 +1,349 @@
+/*
+ * The Qubes OS Project, https://www.qubes-os.org
+ *
+ * Copyright (C) 2021  Demi Marie Obenour  <demi@invisiblethingslab.com>
+ *
+ * This program is free software; you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License
+ * as published by the Free Software Foundation; either version 2
+ * of the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301, USA.
+ *
+ */
+
+//! Per-agent resource quotas
+
+use alloc::collections::BTreeMap;
+use core::num::NonZeroU32;
+use core::time::Duration;
+use qubes_gui::Header;
+
+/// Limits on the resources one agent may consume.  The defaults are generous
+/// enough for any well-behaved agent.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Quota {
+    /// Maximum number of live windows
+    pub max_windows: usize,
+    /// Maximum total size, in bytes, of the shared memory referenced by the
+    /// current `MSG_WINDOW_DUMP` of every window
+    pub max_shared_memory: u64,
+    /// Maximum number of clipboard bytes per [`Quota::clipboard_interval`]
+    pub max_clipboard_bytes: usize,
+    /// The interval over which [`Quota::max_clipboard_bytes`] applies
+    pub clipboard_interval: Duration,
+    /// Maximum number of messages per second
+    pub max_messages_per_second: u32,
+}
+
+impl Default for Quota {
+    fn default() -> Self {
+        Self {
+            max_windows: 4096,
+            max_shared_memory: 2 << 30,
+            max_clipboard_bytes: 16 * qubes_gui::MAX_CLIPBOARD_SIZE as usize,
+            clipboard_interval: Duration::from_secs(1),
+            max_messages_per_second: 100_000,
+        }
+    }
+}
+
+/// A quota was exceeded.  The daemon should terminate the connection.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum QuotaViolation {
+    /// Too many live windows
+    Windows {
+        /// The limit
+        limit: usize,
+    },
+    /// Too much shared memory
+    SharedMemory {
+        /// The total that would have been in use
+        requested: u64,
+        /// The limit
+        limit: u64,
+    },
+    /// Too much clipboard data
+    Clipboard {
+        /// The limit
+        limit: usize,
+    },
+    /// Too many messages
+    MessageRate {
+        /// The limit
+        limit: u32,
+    },
+}
+
+impl core::fmt::Display for QuotaViolation {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match *self {
+            QuotaViolation::Windows { limit } => write!(f, "More than {} windows", limit),
+            QuotaViolation::SharedMemory { requested, limit } => write!(
+                f,
+                "{} bytes of shared memory requested, but the limit is {}",
+                requested, limit
+            ),
+            QuotaViolation::Clipboard { limit } => {
+                write!(f, "More than {} bytes of clipboard data", limit)
+            }
+            QuotaViolation::MessageRate { limit } => {
+                write!(f, "More than {} messages per second", limit)
+            }
+        }
+    }
+}
+
+/// Counts events in fixed intervals
+#[derive(Debug, Default)]
+struct Counter {
+    start: Duration,
+    count: u64,
+}
+
+impl Counter {
+    /// Has the interval starting at `self.start` ended by time `now`?
+    fn expired(&self, now: Duration, interval: Duration) -> bool {
+        now < self.start || now - self.start >= interval
+    }
+
+    /// The total for the current interval at time `now`, if `amount` were
+    /// added
+    fn total(&self, now: Duration, interval: Duration, amount: u64) -> u64 {
+        if self.expired(now, interval) {
+            amount
+        } else {
+            self.count.saturating_add(amount)
+        }
+    }
+
+    /// Add `amount` at time `now`
+    fn add(&mut self, now: Duration, interval: Duration, amount: u64) {
+        if self.expired(now, interval) {
+            self.start = now;
+            self.count = 0;
+        }
+        self.count = self.count.saturating_add(amount);
+    }
+}
+
+/// Enforces a [`Quota`] on the messages sent by one agent.
+///
+/// Only headers are needed, so [`QuotaTracker::check`] can (and should) be
+/// called before the body of a message is read.  Times are measured from an
+/// arbitrary fixed point, and must come from a monotonic clock.  Messages
+/// that fail validation should not be passed to the tracker, and once it has
+/// reported a violation the connection should be dropped.
+#[derive(Debug)]
+pub struct QuotaTracker {
+    quota: Quota,
+    /// Shared memory used by each live window
+    windows: BTreeMap<NonZeroU32, u64>,
+    shared_memory: u64,
+    clipboard: Counter,
+    messages: Counter,
+}
+
+impl QuotaTracker {
+    /// Create a tracker enforcing `quota`
+    pub fn new(quota: Quota) -> Self {
+        Self {
+            quota,
+            windows: BTreeMap::new(),
+            shared_memory: 0,
+            clipboard: Counter::default(),
+            messages: Counter::default(),
+        }
+    }
+
+    /// The quota being enforced
+    pub fn quota(&self) -> &Quota {
+        &self.quota
+    }
+
+    /// The total shared memory currently in use, in bytes
+    pub fn shared_memory(&self) -> u64 {
+        self.shared_memory
+    }
+
+    /// Account for a message with header `header`, received at time `now`.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the message would exceed the quota.  In this case, the
+    /// message is not accounted for.
+    pub fn check(&mut self, now: Duration, header: &Header) -> Result<(), QuotaViolation> {
+        let quota = &self.quota;
+        let limit = quota.max_messages_per_second;
+        let second = Duration::from_secs(1);
+        if self.messages.total(now, second, 1) > limit.into() {
+            return Err(QuotaViolation::MessageRate { limit });
+        }
+        let window = header.untrusted_window().window;
+        match (header.ty(), window) {
+            (qubes_gui::MSG_CLIPBOARD_DATA, _) => {
+                let (limit, interval) = (quota.max_clipboard_bytes, quota.clipboard_interval);
+                let len = header.len() as u64;
+                if self.clipboard.total(now, interval, len) > limit as u64 {
+                    return Err(QuotaViolation::Clipboard { limit });
+                }
+                self.clipboard.add(now, interval, len)
+            }
+            (qubes_gui::MSG_CREATE, Some(window)) if !self.windows.contains_key(&window) => {
+                if self.windows.len() >= quota.max_windows {
+                    return Err(QuotaViolation::Windows {
+                        limit: quota.max_windows,
+                    });
+                }
+                self.windows.insert(window, 0);
+            }
+            (qubes_gui::MSG_DESTROY, Some(window)) => {
+                if let Some(used) = self.windows.remove(&window) {
+                    self.shared_memory -= used
+                }
+            }
+            (ty @ (qubes_gui::MSG_WINDOW_DUMP | qubes_gui::MSG_MFNDUMP), Some(window)) => {
+                let list_len = match ty {
+                    qubes_gui::MSG_MFNDUMP => {
+                        header.len() - core::mem::size_of::<qubes_gui::ShmCmd>()
+                    }
+                    _ => header.len() - core::mem::size_of::<qubes_gui::WindowDumpHeader>(),
+                };
+                let pages = list_len / core::mem::size_of::<u32>();
+                let bytes = pages as u64 * u64::from(qubes_gui::XC_PAGE_SIZE);
+                if let Some(used) = self.windows.get_mut(&window) {
+                    let requested = self.shared_memory - *used + bytes;
+                    if requested > quota.max_shared_memory {
+                        return Err(QuotaViolation::SharedMemory {
+                            requested,
+                            limit: quota.max_shared_memory,
+                        });
+                    }
+                    *used = bytes;
+                    self.shared_memory = requested;
+                }
+            }
+            _ => {}
+        }
+        self.messages.add(now, second, 1);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use qubes_gui::UntrustedHeader;
+
+    fn header(ty: u32, window: u32, len: usize) -> Header {
+        UntrustedHeader {
+            ty,
+            window: qubes_gui::WindowID {
+                window: NonZeroU32::new(window),
+            },
+            untrusted_len: len as u32,
+        }
+        .validate_length()
+        .unwrap()
+        .unwrap()
+    }
+
+    fn dump(window: u32, refs: usize) -> Header {
+        let len = core::mem::size_of::<qubes_gui::WindowDumpHeader>() + 4 * refs;
+        header(qubes_gui::MSG_WINDOW_DUMP, window, len)
+    }
+
+    fn create(window: u32) -> Header {
+        header(
+            qubes_gui::MSG_CREATE,
+            window,
+            core::mem::size_of::<qubes_gui::Create>(),
+        )
+    }
+
+    #[test]
+    fn windows_and_shared_memory() {
+        let mut tracker = QuotaTracker::new(Quota {
+            max_windows: 2,
+            max_shared_memory: 3 * 4096,
+            ..Default::default()
+        });
+        let now = Duration::from_secs(0);
+        tracker.check(now, &create(1)).unwrap();
+        tracker.check(now, &create(2)).unwrap();
+        assert_eq!(
+            tracker.check(now, &create(3)),
+            Err(QuotaViolation::Windows { limit: 2 })
+        );
+        tracker.check(now, &dump(1, 2)).unwrap();
+        tracker.check(now, &dump(1, 1)).unwrap();
+        tracker.check(now, &dump(2, 2)).unwrap();
+        assert_eq!(tracker.shared_memory(), 3 * 4096);
+        assert_eq!(
+            tracker.check(now, &dump(2, 3)),
+            Err(QuotaViolation::SharedMemory {
+                requested: 4 * 4096,
+                limit: 3 * 4096
+            })
+        );
+        tracker
+            .check(now, &header(qubes_gui::MSG_DESTROY, 1, 0))
+            .unwrap();
+        assert_eq!(tracker.shared_memory(), 2 * 4096);
+        tracker.check(now, &create(3)).unwrap();
+        let mfn_dump = |pages: usize| {
+            let len = core::mem::size_of::<qubes_gui::ShmCmd>() + 4 * pages;
+            header(qubes_gui::MSG_MFNDUMP, 3, len)
+        };
+        assert_eq!(
+            tracker.check(now, &mfn_dump(2)),
+            Err(QuotaViolation::SharedMemory {
+                requested: 4 * 4096,
+                limit: 3 * 4096
+            })
+        );
+        tracker.check(now, &mfn_dump(1)).unwrap();
+        assert_eq!(tracker.shared_memory(), 3 * 4096);
+    }
+
+    #[test]
+    fn rates() {
+        let mut tracker = QuotaTracker::new(Quota {
+            max_clipboard_bytes: 10,
+            max_messages_per_second: 3,
+            ..Default::default()
+        });
+        let clipboard = header(qubes_gui::MSG_CLIPBOARD_DATA, 0, 6);
+        let start = Duration::from_secs(5);
+        tracker.check(start, &clipboard).unwrap();
+        assert_eq!(
+            tracker.check(start, &clipboard),
+            Err(QuotaViolation::Clipboard { limit: 10 })
+        );
+        assert_eq!(
+            tracker.check(start, &header(qubes_gui::MSG_CLIPBOARD_DATA, 0, 5)),
+            Err(QuotaViolation::Clipboard { limit: 10 }),
+            "rejected clipboard data is not counted"
+        );
+        tracker
+            .check(start, &header(qubes_gui::MSG_CLIPBOARD_DATA, 0, 4))
+            .unwrap();
+        tracker.check(start, &create(1)).unwrap();
+        assert_eq!(
+            tracker.check(start, &create(2)),
+            Err(QuotaViolation::MessageRate { limit: 3 }),
+            "rejected messages are not counted"
+        );
+        let later = start + Duration::from_secs(1);
+        tracker.check(later, &clipboard).unwrap();
+        tracker.check(later, &create(2)).unwrap();
+    }
+}
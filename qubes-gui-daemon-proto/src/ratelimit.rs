@@ -0,0 +1,218 @@
+/*
+ * The Qubes OS Project, https://www.qubes-os.org
+ *
+ * Copyright (C) 2021  Demi Marie Obenour  <demi@invisiblethingslab.com>
+ *
+ * This program is free software; you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License
+ * as published by the Free Software Foundation; either version 2
+ * of the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301, USA.
+ *
+ */
+
+//! Per-window rate limiting
+
+use alloc::collections::BTreeMap;
+use core::num::NonZeroU32;
+use core::time::Duration;
+use qubes_gui::Header;
+
+/// The rate at which one type of message may be sent to one window
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct RateLimit {
+    /// Sustained rate, in messages per second
+    pub per_second: NonZeroU32,
+    /// Number of messages that may be sent in a burst above the sustained
+    /// rate.  Zero is treated as one.
+    pub burst: u32,
+}
+
+impl RateLimit {
+    /// Time between messages at the sustained rate
+    fn interval(&self) -> Duration {
+        Duration::from_secs(1) / self.per_second.get()
+    }
+
+    /// How far ahead of the sustained rate a burst may get
+    fn tolerance(&self) -> Duration {
+        self.interval() * self.burst.saturating_sub(1)
+    }
+}
+
+/// A message was rate-limited
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct RateLimited {
+    /// The window the message was sent to, or [`None`] for the whole screen
+    pub window: Option<NonZeroU32>,
+    /// The message type
+    pub ty: u32,
+}
+
+impl core::fmt::Display for RateLimited {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "Message type {} sent too quickly to window ", self.ty)?;
+        match self.window {
+            Some(window) => write!(f, "{}", window),
+            None => f.write_str("0"),
+        }
+    }
+}
+
+/// The fewest buckets at which full ones are removed
+const MIN_PRUNE_AT: usize = 64;
+
+/// Token-bucket rate limiter keyed by window and message type.  Only message
+/// types given a limit with [`RateLimiter::limit`] are limited.
+///
+/// Times are measured from an arbitrary fixed point, and must come from a
+/// monotonic clock.  Like [`crate::QuotaTracker`], this only needs message
+/// headers, and the daemon must call [`RateLimiter::check`] on each message
+/// it receives: nothing in this crate does so.
+///
+/// Agents choose window IDs, so buckets are kept only while they are not
+/// full.  A full bucket is the same as a missing one, and full buckets are
+/// removed whenever the number of buckets doubles.  The number of buckets is
+/// thus bounded by the number of limited messages received in the longest
+/// time a bucket takes to refill.
+#[derive(Debug, Default)]
+pub struct RateLimiter {
+    limits: BTreeMap<u32, RateLimit>,
+    /// The time at which each bucket will be full again
+    buckets: BTreeMap<(Option<NonZeroU32>, u32), Duration>,
+    /// The number of buckets at which to remove the full ones
+    prune_at: usize,
+}
+
+impl RateLimiter {
+    /// Create a rate limiter that does not limit anything
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Limit messages of type `ty` to `limit`, separately for each window
+    pub fn limit(mut self, ty: u32, limit: RateLimit) -> Self {
+        self.limits.insert(ty, limit);
+        self
+    }
+
+    /// The limit for messages of type `ty`, if any
+    pub fn limit_for(&self, ty: u32) -> Option<&RateLimit> {
+        self.limits.get(&ty)
+    }
+
+    /// Account for a message with header `header`, received at time `now`.
+    /// Destroying a window resets its buckets.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the message exceeds its limit.  The message is not accounted
+    /// for, so the daemon may drop it or try again later.
+    pub fn check(&mut self, now: Duration, header: &Header) -> Result<(), RateLimited> {
+        let (window, ty) = (header.untrusted_window().window, header.ty());
+        if ty == qubes_gui::MSG_DESTROY {
+            self.buckets.retain(|&(w, _), _| w != window);
+        }
+        let limit = match self.limits.get(&ty) {
+            Some(limit) => limit,
+            None => return Ok(()),
+        };
+        if self.buckets.len() >= self.prune_at {
+            self.buckets.retain(|_, &mut full_at| full_at > now);
+            self.prune_at = (2 * self.buckets.len()).max(MIN_PRUNE_AT);
+        }
+        let full_at = self.buckets.entry((window, ty)).or_insert(now);
+        let start = (*full_at).max(now);
+        if start - now > limit.tolerance() {
+            return Err(RateLimited { window, ty });
+        }
+        *full_at = start + limit.interval();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use qubes_gui::UntrustedHeader;
+
+    fn header(ty: u32, window: u32) -> Header {
+        UntrustedHeader {
+            ty,
+            window: qubes_gui::WindowID {
+                window: NonZeroU32::new(window),
+            },
+            untrusted_len: 0,
+        }
+        .validate_length()
+        .unwrap()
+        .unwrap()
+    }
+
+    #[test]
+    fn bursts_then_sustained_rate() {
+        let mut limiter = RateLimiter::new().limit(
+            qubes_gui::MSG_UNMAP,
+            RateLimit {
+                per_second: NonZeroU32::new(10).unwrap(),
+                burst: 3,
+            },
+        );
+        let (unmap, other) = (
+            header(qubes_gui::MSG_UNMAP, 1),
+            header(qubes_gui::MSG_UNMAP, 2),
+        );
+        let start = Duration::from_secs(100);
+        for _ in 0..3 {
+            limiter.check(start, &unmap).unwrap();
+        }
+        assert_eq!(
+            limiter.check(start, &unmap),
+            Err(RateLimited {
+                window: NonZeroU32::new(1),
+                ty: qubes_gui::MSG_UNMAP
+            })
+        );
+        limiter.check(start, &other).unwrap();
+        limiter
+            .check(start, &header(qubes_gui::MSG_DOCK, 1))
+            .unwrap();
+        let later = start + Duration::from_millis(100);
+        limiter.check(later, &unmap).unwrap();
+        assert!(limiter.check(later, &unmap).is_err());
+        limiter
+            .check(later, &header(qubes_gui::MSG_DESTROY, 1))
+            .unwrap();
+        limiter.check(later, &unmap).unwrap();
+    }
+
+    #[test]
+    fn full_buckets_are_removed() {
+        let mut limiter = RateLimiter::new().limit(
+            qubes_gui::MSG_UNMAP,
+            RateLimit {
+                per_second: NonZeroU32::new(1000).unwrap(),
+                burst: 1,
+            },
+        );
+        let start = Duration::from_secs(1);
+        for window in 1..=10_000 {
+            let now = start + Duration::from_millis(window.into());
+            limiter
+                .check(now, &header(qubes_gui::MSG_UNMAP, window))
+                .unwrap();
+            assert!(limiter.buckets.len() <= MIN_PRUNE_AT);
+        }
+        let now = start + Duration::from_secs(10);
+        let last = header(qubes_gui::MSG_UNMAP, 10_000);
+        assert!(limiter.check(now, &last).is_err(), "live buckets are kept");
+    }
+}
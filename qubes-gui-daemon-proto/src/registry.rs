@@ -0,0 +1,382 @@
+/*
+ * The Qubes OS Project, https://www.qubes-os.org
+ *
+ * Copyright (C) 2021  Demi Marie Obenour  <demi@invisiblethingslab.com>
+ *
+ * This program is free software; you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License
+ * as published by the Free Software Foundation; either version 2
+ * of the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301, USA.
+ *
+ */
+
+//! Window lifecycle tracking
+
+use alloc::collections::{BTreeMap, BTreeSet};
+use core::num::NonZeroU32;
+use qubes_gui::{Create, Rectangle, WindowSize};
+
+/// A violation of the window lifecycle rules by the agent.  The daemon should
+/// terminate the connection when it receives one of these.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum RegistryError {
+    /// `MSG_CREATE` used an ID that is already in use
+    AlreadyExists(NonZeroU32),
+    /// `MSG_CREATE` used an ID whose destruction the daemon has not yet
+    /// acknowledged
+    NotAcknowledged(NonZeroU32),
+    /// A message was sent to a window that does not exist
+    NoSuchWindow(NonZeroU32),
+    /// `MSG_CREATE` specified a parent window that does not exist
+    NoSuchParent {
+        /// The window being created
+        window: NonZeroU32,
+        /// The nonexistent parent
+        parent: NonZeroU32,
+    },
+    /// `MSG_CREATE` or `MSG_CONFIGURE` specified a rectangle whose right or
+    /// bottom edge does not fit in an `i32`
+    Overflow {
+        /// The window being created or configured
+        window: NonZeroU32,
+        /// The UNTRUSTED rectangle
+        rectangle: Rectangle,
+    },
+    /// A rectangle to redraw was not entirely inside its window
+    OutOfBounds {
+        /// The window being redrawn
+        window: NonZeroU32,
+        /// The UNTRUSTED rectangle
+        rectangle: Rectangle,
+        /// The current size of the window
+        size: WindowSize,
+    },
+}
+
+impl core::fmt::Display for RegistryError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match *self {
+            RegistryError::AlreadyExists(w) => write!(f, "Window {} already exists", w),
+            RegistryError::NotAcknowledged(w) => {
+                write!(
+                    f,
+                    "Window {} reused before its destruction was acknowledged",
+                    w
+                )
+            }
+            RegistryError::NoSuchWindow(w) => write!(f, "Window {} does not exist", w),
+            RegistryError::NoSuchParent { window, parent } => {
+                write!(f, "Parent {} of window {} does not exist", parent, window)
+            }
+            RegistryError::Overflow { window, rectangle } => write!(
+                f,
+                "Rectangle {}x{}+{}+{} of window {} overflows",
+                rectangle.size.width,
+                rectangle.size.height,
+                rectangle.top_left.x,
+                rectangle.top_left.y,
+                window
+            ),
+            RegistryError::OutOfBounds {
+                window,
+                rectangle,
+                size,
+            } => write!(
+                f,
+                "Rectangle {}x{}+{}+{} is outside window {} of size {}x{}",
+                rectangle.size.width,
+                rectangle.size.height,
+                rectangle.top_left.x,
+                rectangle.top_left.y,
+                window,
+                size.width,
+                size.height
+            ),
+        }
+    }
+}
+
+/// Tracks which of the agent’s window IDs are in use, and enforces the
+/// lifecycle rules of the protocol:
+///
+/// - `MSG_CREATE` must not reuse an ID that is live, or whose destruction the
+///   daemon has not yet acknowledged.
+/// - The parent named in `MSG_CREATE`, if any, must exist.  It cannot be
+///   changed afterwards.
+/// - All other messages must be sent to a live window.
+/// - The rectangle in `MSG_CREATE` and `MSG_CONFIGURE` must not overflow, and
+///   the rectangle in `MSG_SHMIMAGE` must lie inside the window.
+///
+/// This performs no I/O.
+#[derive(Debug, Default)]
+pub struct WindowRegistry {
+    live: BTreeMap<NonZeroU32, Live>,
+    destroyed: BTreeSet<NonZeroU32>,
+}
+
+/// What the registry knows about a live window
+#[derive(Debug, Copy, Clone)]
+struct Live {
+    parent: Option<NonZeroU32>,
+    size: WindowSize,
+}
+
+/// Check that the edges of `rectangle` fit in an `i32`
+fn check_overflow(window: NonZeroU32, rectangle: Rectangle) -> Result<(), RegistryError> {
+    match rectangle.checked_bottom_right() {
+        Some(_) => Ok(()),
+        None => Err(RegistryError::Overflow { window, rectangle }),
+    }
+}
+
+impl WindowRegistry {
+    /// Create an empty registry
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Handle `MSG_CREATE`
+    pub fn create(&mut self, window: NonZeroU32, create: &Create) -> Result<(), RegistryError> {
+        let parent = create.parent;
+        if self.live.contains_key(&window) {
+            return Err(RegistryError::AlreadyExists(window));
+        }
+        if self.destroyed.contains(&window) {
+            return Err(RegistryError::NotAcknowledged(window));
+        }
+        match parent {
+            Some(parent) if !self.live.contains_key(&parent) => {
+                Err(RegistryError::NoSuchParent { window, parent })
+            }
+            _ => {
+                check_overflow(window, create.rectangle)?;
+                let size = create.rectangle.size;
+                self.live.insert(window, Live { parent, size });
+                Ok(())
+            }
+        }
+    }
+
+    /// Handle `MSG_CONFIGURE`, recording the new size of the window
+    pub fn configure(
+        &mut self,
+        window: NonZeroU32,
+        rectangle: Rectangle,
+    ) -> Result<(), RegistryError> {
+        check_overflow(window, rectangle)?;
+        let live = self
+            .live
+            .get_mut(&window)
+            .ok_or(RegistryError::NoSuchWindow(window))?;
+        live.size = rectangle.size;
+        Ok(())
+    }
+
+    /// Check that the rectangle of a `MSG_SHMIMAGE` lies inside the window
+    pub fn check_damage(
+        &self,
+        window: NonZeroU32,
+        rectangle: Rectangle,
+    ) -> Result<(), RegistryError> {
+        let size = self.size(window)?;
+        if rectangle.fits_within(size) {
+            Ok(())
+        } else {
+            Err(RegistryError::OutOfBounds {
+                window,
+                rectangle,
+                size,
+            })
+        }
+    }
+
+    /// The current size of `window`, as of the last `MSG_CREATE` or
+    /// `MSG_CONFIGURE`
+    pub fn size(&self, window: NonZeroU32) -> Result<WindowSize, RegistryError> {
+        self.live
+            .get(&window)
+            .map(|live| live.size)
+            .ok_or(RegistryError::NoSuchWindow(window))
+    }
+
+    /// Check that a message other than `MSG_CREATE` is sent to a live window
+    pub fn check(&self, window: NonZeroU32) -> Result<(), RegistryError> {
+        if self.live.contains_key(&window) {
+            Ok(())
+        } else {
+            Err(RegistryError::NoSuchWindow(window))
+        }
+    }
+
+    /// Handle `MSG_DESTROY`.  The ID cannot be reused until
+    /// [`WindowRegistry::acknowledge_destroy`] is called.
+    pub fn destroy(&mut self, window: NonZeroU32) -> Result<(), RegistryError> {
+        self.live
+            .remove(&window)
+            .ok_or(RegistryError::NoSuchWindow(window))?;
+        self.destroyed.insert(window);
+        Ok(())
+    }
+
+    /// Record that the daemon has acknowledged the destruction of `window`,
+    /// allowing the agent to reuse its ID.  Returns `false` if the window was
+    /// not waiting for an acknowledgement.
+    pub fn acknowledge_destroy(&mut self, window: NonZeroU32) -> bool {
+        self.destroyed.remove(&window)
+    }
+
+    /// Windows that have been destroyed, but whose destruction has not been
+    /// acknowledged, in order of window ID
+    pub fn unacknowledged(&self) -> impl Iterator<Item = NonZeroU32> + '_ {
+        self.destroyed.iter().copied()
+    }
+
+    /// Is `window` live?
+    pub fn contains(&self, window: NonZeroU32) -> bool {
+        self.live.contains_key(&window)
+    }
+
+    /// The parent of `window`.  Returns `Err` if the window does not exist,
+    /// and `Ok(None)` if it has no parent.
+    pub fn parent(&self, window: NonZeroU32) -> Result<Option<NonZeroU32>, RegistryError> {
+        self.live
+            .get(&window)
+            .map(|live| live.parent)
+            .ok_or(RegistryError::NoSuchWindow(window))
+    }
+
+    /// Iterate over all live windows, in order of window ID
+    pub fn windows(&self) -> impl Iterator<Item = NonZeroU32> + '_ {
+        self.live.keys().copied()
+    }
+
+    /// The number of live windows
+    pub fn len(&self) -> usize {
+        self.live.len()
+    }
+
+    /// Are there no live windows?
+    pub fn is_empty(&self) -> bool {
+        self.live.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn id(n: u32) -> NonZeroU32 {
+        NonZeroU32::new(n).unwrap()
+    }
+
+    fn create(parent: Option<NonZeroU32>) -> Create {
+        Create {
+            rectangle: rectangle(0, 0, 100, 50),
+            parent,
+            override_redirect: 0,
+        }
+    }
+
+    fn rectangle(x: i32, y: i32, width: u32, height: u32) -> Rectangle {
+        Rectangle {
+            top_left: qubes_gui::Coordinates { x, y },
+            size: WindowSize { width, height },
+        }
+    }
+
+    #[test]
+    fn lifecycle() {
+        let mut registry = WindowRegistry::new();
+        assert_eq!(
+            registry.check(id(1)),
+            Err(RegistryError::NoSuchWindow(id(1)))
+        );
+        registry.create(id(1), &create(None)).unwrap();
+        assert_eq!(
+            registry.create(id(1), &create(None)),
+            Err(RegistryError::AlreadyExists(id(1)))
+        );
+        assert_eq!(
+            registry.create(id(2), &create(Some(id(3)))),
+            Err(RegistryError::NoSuchParent {
+                window: id(2),
+                parent: id(3)
+            })
+        );
+        assert!(!registry.contains(id(2)));
+        registry.create(id(2), &create(Some(id(1)))).unwrap();
+        assert_eq!(registry.parent(id(2)), Ok(Some(id(1))));
+        registry.check(id(2)).unwrap();
+        registry.destroy(id(2)).unwrap();
+        assert_eq!(
+            registry.check(id(2)),
+            Err(RegistryError::NoSuchWindow(id(2)))
+        );
+        assert_eq!(
+            registry.destroy(id(2)),
+            Err(RegistryError::NoSuchWindow(id(2)))
+        );
+        assert_eq!(
+            registry.create(id(2), &create(None)),
+            Err(RegistryError::NotAcknowledged(id(2)))
+        );
+        assert!(registry.unacknowledged().eq([id(2)].iter().copied()));
+        assert!(registry.acknowledge_destroy(id(2)));
+        assert!(!registry.acknowledge_destroy(id(2)));
+        registry.create(id(2), &create(None)).unwrap();
+        assert_eq!(registry.len(), 2);
+    }
+
+    #[test]
+    fn geometry() {
+        let mut registry = WindowRegistry::new();
+        registry.create(id(1), &create(None)).unwrap();
+        registry
+            .check_damage(id(1), rectangle(0, 0, 100, 50))
+            .unwrap();
+        registry
+            .check_damage(id(1), rectangle(90, 40, 10, 10))
+            .unwrap();
+        for bad in [rectangle(-1, 0, 10, 10), rectangle(91, 0, 10, 10)] {
+            assert_eq!(
+                registry.check_damage(id(1), bad),
+                Err(RegistryError::OutOfBounds {
+                    window: id(1),
+                    rectangle: bad,
+                    size: WindowSize {
+                        width: 100,
+                        height: 50
+                    },
+                })
+            );
+        }
+        let overflow = rectangle(i32::MAX - 5, 0, 10, 10);
+        assert_eq!(
+            registry.configure(id(1), overflow),
+            Err(RegistryError::Overflow {
+                window: id(1),
+                rectangle: overflow
+            })
+        );
+        registry
+            .configure(id(1), rectangle(-5, -5, 200, 100))
+            .unwrap();
+        registry
+            .check_damage(id(1), rectangle(150, 0, 50, 100))
+            .unwrap();
+        assert_eq!(
+            registry.configure(id(2), rectangle(0, 0, 1, 1)),
+            Err(RegistryError::NoSuchWindow(id(2)))
+        );
+    }
+}
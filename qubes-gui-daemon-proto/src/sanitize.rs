@@ -0,0 +1,131 @@
+/*
+ * The Qubes OS Project, https://www.qubes-os.org
+ *
+ * Copyright (C) 2021  Demi Marie Obenour  <demi@invisiblethingslab.com>
+ *
+ * This program is free software; you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License
+ * as published by the Free Software Foundation; either version 2
+ * of the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301, USA.
+ *
+ */
+
+//! Sanitizing untrusted strings from agents before showing them to the user.
+
+use alloc::string::String;
+
+/// What untrusted characters are replaced with
+pub const REPLACEMENT: char = '_';
+
+/// Is `c` safe to show the user?  Control characters and the Unicode
+/// directional overrides and isolates (which can make text appear in a
+/// different order than it is stored) are not.
+fn is_safe(c: char, allow_utf8: bool) -> bool {
+    match c {
+        ' ' => true,
+        _ if c.is_ascii() => c.is_ascii_graphic(),
+        '\u{200E}' | '\u{200F}' | '\u{202A}'..='\u{202E}' | '\u{2066}'..='\u{2069}' => false,
+        _ => allow_utf8 && !c.is_control(),
+    }
+}
+
+/// Sanitize an untrusted string from an agent, as the C GUI daemon does.
+///
+/// The string ends at the first NUL byte, if any.  Invalid UTF-8 and unsafe
+/// characters are replaced with [`REPLACEMENT`].  If `allow_utf8` is false,
+/// so is everything except printable ASCII.  The result is at most `max_len`
+/// bytes long, truncated at a character boundary.
+pub fn sanitize(untrusted: &[u8], allow_utf8: bool, max_len: usize) -> String {
+    let untrusted = &untrusted[..untrusted
+        .iter()
+        .position(|&b| b == 0)
+        .unwrap_or(untrusted.len())];
+    let mut res = String::with_capacity(untrusted.len().min(max_len));
+    let mut push = |c: char| {
+        let c = if is_safe(c, allow_utf8) {
+            c
+        } else {
+            REPLACEMENT
+        };
+        if res.len() + c.len_utf8() > max_len {
+            return false;
+        }
+        res.push(c);
+        true
+    };
+    let mut rest = untrusted;
+    loop {
+        let (valid, invalid) = match core::str::from_utf8(rest) {
+            Ok(valid) => (valid, None),
+            Err(e) => (
+                core::str::from_utf8(&rest[..e.valid_up_to()]).expect("valid prefix"),
+                Some(e.valid_up_to() + e.error_len().unwrap_or(rest.len() - e.valid_up_to())),
+            ),
+        };
+        for c in valid.chars() {
+            if !push(c) {
+                return res;
+            }
+        }
+        match invalid {
+            Some(end) if push(REPLACEMENT) => rest = &rest[end..],
+            _ => return res,
+        }
+    }
+}
+
+/// Sanitize a window title.  See [`sanitize`].
+pub fn sanitize_title(untrusted: &qubes_gui::WMName, allow_utf8: bool) -> String {
+    sanitize(&untrusted.data, allow_utf8, untrusted.data.len() - 1)
+}
+
+/// Sanitize a window class, returning the class and instance names.  See
+/// [`sanitize`].
+pub fn sanitize_class(untrusted: &qubes_gui::WMClass, allow_utf8: bool) -> (String, String) {
+    (
+        sanitize(
+            &untrusted.res_class,
+            allow_utf8,
+            untrusted.res_class.len() - 1,
+        ),
+        sanitize(
+            &untrusted.res_name,
+            allow_utf8,
+            untrusted.res_name.len() - 1,
+        ),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strips_unsafe() {
+        assert_eq!(sanitize(b"a\tb\x7fc\0d", true, 100), "a_b_c");
+        assert_eq!(sanitize("x\u{202E}y".as_bytes(), true, 100), "x_y");
+        assert_eq!(sanitize("ü".as_bytes(), false, 100), "_");
+        assert_eq!(sanitize("ü".as_bytes(), true, 100), "ü");
+    }
+
+    #[test]
+    fn invalid_utf8() {
+        assert_eq!(sanitize(b"a\xffb\xe2\x82", true, 100), "a_b_");
+    }
+
+    #[test]
+    fn clamps() {
+        assert_eq!(sanitize("aüb".as_bytes(), true, 2), "a");
+        let name = qubes_gui::WMName { data: [b'a'; 128] };
+        assert_eq!(sanitize_title(&name, true).len(), 127);
+    }
+}
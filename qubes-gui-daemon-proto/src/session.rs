@@ -0,0 +1,469 @@
+/*
+ * The Qubes OS Project, https://www.qubes-os.org
+ *
+ * Copyright (C) 2021  Demi Marie Obenour  <demi@invisiblethingslab.com>
+ *
+ * This program is free software; you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License
+ * as published by the Free Software Foundation; either version 2
+ * of the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301, USA.
+ *
+ */
+
+//! Per-agent session state
+
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use core::num::NonZeroU32;
+use qubes_gui::{
+    Create, PendingOps, Rectangle, WindowHints, WindowHintsFlags, WindowInfo, WindowSize,
+};
+
+/// Size limits imposed by the daemon, regardless of what the agent asks for.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct SizeLimits {
+    /// No window may be smaller than this
+    pub min: WindowSize,
+    /// No window may be larger than this
+    pub max: WindowSize,
+}
+
+impl Default for SizeLimits {
+    /// The limits imposed by the protocol itself: 1×1 up to
+    /// [`qubes_gui::MAX_WINDOW_WIDTH`]×[`qubes_gui::MAX_WINDOW_HEIGHT`].
+    fn default() -> Self {
+        Self {
+            min: WindowSize {
+                width: 1,
+                height: 1,
+            },
+            max: WindowSize {
+                width: qubes_gui::MAX_WINDOW_WIDTH,
+                height: qubes_gui::MAX_WINDOW_HEIGHT,
+            },
+        }
+    }
+}
+
+/// The size constraints that actually apply to a window, after resolving the
+/// agent’s [`WindowHints`] against the daemon’s [`SizeLimits`].
+///
+/// The resolution order is:
+///
+/// 1. The daemon’s [`SizeLimits`] are absolute.  Nothing the agent sends can
+///    make a window smaller than `SizeLimits::min` or larger than
+///    `SizeLimits::max`.
+/// 2. The agent’s minimum and maximum size hints are clamped to those limits.
+///    If the clamped minimum exceeds the clamped maximum, the minimum wins.
+/// 3. User resizes are clamped to the result of step 2, and then rounded down
+///    to the agent’s resize increment (counting from the base size), but
+///    never below the minimum.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct SizeConstraints {
+    /// Minimum size
+    pub min: WindowSize,
+    /// Maximum size
+    pub max: WindowSize,
+    /// Base size for resize increments.  Defaults to `min`, as in the ICCCM.
+    pub base: WindowSize,
+    /// Resize increment.  Both dimensions are at least 1, and no more than
+    /// [`qubes_gui::MAX_WINDOW_WIDTH`] and [`qubes_gui::MAX_WINDOW_HEIGHT`]
+    /// respectively.
+    pub increment: WindowSize,
+}
+
+pub(crate) fn clamp_size(size: WindowSize, min: WindowSize, max: WindowSize) -> WindowSize {
+    WindowSize {
+        width: size.width.max(min.width).min(max.width),
+        height: size.height.max(min.height).min(max.height),
+    }
+}
+
+/// Round `value` down to `base + k * increment`, staying within `[min, max]`
+fn snap(value: u32, base: u32, increment: u32, min: u32, max: u32) -> u32 {
+    let snapped = if value > base {
+        value - (value - base) % increment
+    } else {
+        value
+    };
+    if snapped >= min {
+        snapped
+    } else {
+        match snapped.checked_add(increment) {
+            Some(next) if next <= max => next,
+            _ => value,
+        }
+    }
+}
+
+impl SizeConstraints {
+    /// Resolve `hints` (if any) against `limits`.
+    pub fn resolve(limits: &SizeLimits, hints: Option<&WindowHints>) -> Self {
+        let mut res = Self {
+            min: limits.min,
+            max: limits.max,
+            base: limits.min,
+            increment: WindowSize {
+                width: 1,
+                height: 1,
+            },
+        };
+        let hints = match hints {
+            Some(hints) => hints,
+            None => return res,
+        };
+        let has = |flag: WindowHintsFlags| hints.flags & flag as u32 != 0;
+        if has(WindowHintsFlags::PMinSize) {
+            res.min = clamp_size(hints.min_size, limits.min, limits.max);
+        }
+        if has(WindowHintsFlags::PMaxSize) {
+            res.max = clamp_size(hints.max_size, res.min, limits.max);
+        }
+        res.base = if has(WindowHintsFlags::PBaseSize) {
+            clamp_size(hints.size_base, limits.min, res.max)
+        } else {
+            res.min
+        };
+        if has(WindowHintsFlags::PResizeInc) {
+            res.increment = WindowSize {
+                width: hints
+                    .size_increment
+                    .width
+                    .clamp(1, qubes_gui::MAX_WINDOW_WIDTH),
+                height: hints
+                    .size_increment
+                    .height
+                    .clamp(1, qubes_gui::MAX_WINDOW_HEIGHT),
+            }
+        }
+        res
+    }
+
+    /// Apply these constraints to a size requested by the user (for
+    /// instance, by dragging a window border).
+    pub fn apply(&self, size: WindowSize) -> WindowSize {
+        let size = clamp_size(size, self.min, self.max);
+        WindowSize {
+            width: snap(
+                size.width,
+                self.base.width,
+                self.increment.width,
+                self.min.width,
+                self.max.width,
+            ),
+            height: snap(
+                size.height,
+                self.base.height,
+                self.increment.height,
+                self.min.height,
+                self.max.height,
+            ),
+        }
+    }
+}
+
+/// What the daemon knows about one of the agent’s windows
+#[derive(Debug)]
+struct WindowState {
+    /// Current position and size
+    rectangle: Rectangle,
+    /// Parent window
+    parent: Option<NonZeroU32>,
+    /// Is this an override-redirect window?
+    override_redirect: bool,
+    /// Is the window mapped?
+    mapped: bool,
+    /// Currently set [`qubes_gui::WindowFlag`]s
+    flags: u32,
+    /// UNTRUSTED title, as last set by the agent
+    title: String,
+    /// The most recent hints sent by the agent, if any
+    hints: Option<WindowHints>,
+    /// Operations that are waiting for the agent
+    pending: PendingOps,
+}
+
+impl WindowState {
+    fn info(&self, id: NonZeroU32) -> WindowInfo<'_> {
+        WindowInfo {
+            id,
+            rectangle: self.rectangle,
+            parent: self.parent,
+            override_redirect: self.override_redirect,
+            mapped: self.mapped,
+            flags: self.flags,
+            title: &self.title,
+            hints: self.hints.as_ref(),
+            popup: false,
+            dialog_owner: None,
+            pending: self.pending,
+        }
+    }
+}
+
+/// The state of a daemon’s session with one agent.
+///
+/// This performs no I/O.  The daemon feeds it validated messages from the
+/// agent and queries it when making policy decisions.
+#[derive(Debug, Default)]
+pub struct DaemonSession {
+    limits: SizeLimits,
+    windows: BTreeMap<NonZeroU32, WindowState>,
+}
+
+impl DaemonSession {
+    /// Create a new session that imposes `limits` on all windows.
+    pub fn new(limits: SizeLimits) -> Self {
+        Self {
+            limits,
+            windows: BTreeMap::new(),
+        }
+    }
+
+    /// The daemon’s size limits
+    pub fn limits(&self) -> &SizeLimits {
+        &self.limits
+    }
+
+    /// Start tracking a newly created window.  Returns `false` if the window
+    /// already exists.
+    pub fn create(&mut self, window: NonZeroU32, create: &Create) -> bool {
+        if self.windows.contains_key(&window) {
+            return false;
+        }
+        self.windows.insert(
+            window,
+            WindowState {
+                rectangle: create.rectangle,
+                parent: create.parent,
+                override_redirect: create.override_redirect != 0,
+                mapped: false,
+                flags: 0,
+                title: String::new(),
+                hints: None,
+                pending: PendingOps::default(),
+            },
+        );
+        true
+    }
+
+    /// Iterate over all live windows, in order of window ID
+    pub fn windows(&self) -> impl Iterator<Item = WindowInfo<'_>> {
+        self.windows.iter().map(|(&id, state)| state.info(id))
+    }
+
+    /// Get a single window, or `None` if it does not exist
+    pub fn window(&self, window: NonZeroU32) -> Option<WindowInfo<'_>> {
+        self.windows.get(&window).map(|state| state.info(window))
+    }
+
+    fn with_window(&mut self, window: NonZeroU32, f: impl FnOnce(&mut WindowState)) -> bool {
+        match self.windows.get_mut(&window) {
+            Some(state) => {
+                f(state);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Record a new position and size.  Returns `false` if the window does not
+    /// exist.
+    pub fn configure(&mut self, window: NonZeroU32, rectangle: Rectangle) -> bool {
+        self.with_window(window, |state| state.rectangle = rectangle)
+    }
+
+    /// Record that the window has been mapped (`true`) or unmapped (`false`).
+    /// Returns `false` if the window does not exist.
+    pub fn set_mapped(&mut self, window: NonZeroU32, mapped: bool) -> bool {
+        self.with_window(window, |state| state.mapped = mapped)
+    }
+
+    /// Apply a [`qubes_gui::WindowFlags`] message.  Returns `false` if the
+    /// window does not exist.
+    pub fn update_flags(&mut self, window: NonZeroU32, flags: &qubes_gui::WindowFlags) -> bool {
+        self.with_window(window, |state| {
+            state.flags = (state.flags | flags.set) & !flags.unset
+        })
+    }
+
+    /// Record a new UNTRUSTED title.  Returns `false` if the window does not
+    /// exist.
+    pub fn set_title(&mut self, window: NonZeroU32, untrusted_title: &str) -> bool {
+        self.with_window(window, |state| {
+            state.title.clear();
+            state.title.push_str(untrusted_title)
+        })
+    }
+
+    /// Record that the daemon has asked the agent to close the window.
+    /// Returns `false` if the window does not exist.
+    pub fn request_close(&mut self, window: NonZeroU32) -> bool {
+        self.with_window(window, |state| state.pending.close_requested = true)
+    }
+
+    /// Record that a window dump is (`true`) or is not (`false`) waiting to be
+    /// acknowledged.  Returns `false` if the window does not exist.
+    pub fn set_dump_pending(&mut self, window: NonZeroU32, pending: bool) -> bool {
+        self.with_window(window, |state| state.pending.dump_ack = pending)
+    }
+
+    /// Stop tracking a destroyed window.  Returns `false` if the window did
+    /// not exist.
+    pub fn destroy(&mut self, window: NonZeroU32) -> bool {
+        self.windows.remove(&window).is_some()
+    }
+
+    /// Record new hints from the agent.  Returns `false` if the window does
+    /// not exist.
+    pub fn set_hints(&mut self, window: NonZeroU32, hints: WindowHints) -> bool {
+        self.with_window(window, |state| state.hints = Some(hints))
+    }
+
+    /// The constraints that currently apply to `window`, or `None` if the
+    /// window does not exist.  See [`SizeConstraints`] for how they are
+    /// computed.
+    pub fn effective_constraints(&self, window: NonZeroU32) -> Option<SizeConstraints> {
+        let state = self.windows.get(&window)?;
+        Some(SizeConstraints::resolve(&self.limits, state.hints.as_ref()))
+    }
+
+    /// Apply [`DaemonSession::effective_constraints`] to a size requested by
+    /// the user.  Returns `None` if the window does not exist.
+    pub fn constrain_resize(&self, window: NonZeroU32, size: WindowSize) -> Option<WindowSize> {
+        Some(self.effective_constraints(window)?.apply(size))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn size(width: u32, height: u32) -> WindowSize {
+        WindowSize { width, height }
+    }
+
+    fn session() -> (DaemonSession, NonZeroU32) {
+        let mut session = DaemonSession::new(SizeLimits {
+            min: size(10, 10),
+            max: size(1000, 800),
+        });
+        let window = NonZeroU32::new(5).unwrap();
+        assert!(session.create(window, &Default::default()));
+        assert!(!session.create(window, &Default::default()));
+        (session, window)
+    }
+
+    #[test]
+    fn enumerate_windows() {
+        let (mut session, window) = session();
+        let other = NonZeroU32::new(2).unwrap();
+        assert!(session.create(
+            other,
+            &Create {
+                parent: Some(window),
+                override_redirect: 1,
+                ..Default::default()
+            }
+        ));
+        assert!(session.set_mapped(other, true));
+        assert!(session.set_title(other, "secret"));
+        assert!(session.update_flags(other, &qubes_gui::WindowFlags { set: 3, unset: 1 }));
+        assert!(session.request_close(window));
+        let ids: alloc::vec::Vec<_> = session.windows().map(|w| w.id.get()).collect();
+        assert_eq!(ids, [2, 5]);
+        let info = session.window(other).unwrap();
+        assert!(info.mapped && info.override_redirect);
+        assert_eq!(info.parent, Some(window));
+        assert_eq!(info.flags, 2);
+        assert_eq!(info.title, "secret");
+        assert!(!alloc::format!("{:?}", info).contains("secret"));
+        assert!(session.window(window).unwrap().pending.close_requested);
+        assert!(session.set_dump_pending(other, true));
+        let info = session.window(other).unwrap();
+        assert!(info.pending.dump_ack && !info.pending.close_requested);
+        assert_eq!((info.popup, info.dialog_owner), (false, None));
+    }
+
+    #[test]
+    fn policy_clamps_hints() {
+        let (mut session, window) = session();
+        let no_hints = session.effective_constraints(window).unwrap();
+        assert_eq!(no_hints.min, size(10, 10));
+        assert_eq!(no_hints.max, size(1000, 800));
+        assert!(session.set_hints(
+            window,
+            WindowHints {
+                flags: WindowHintsFlags::PMinSize as u32 | WindowHintsFlags::PMaxSize as u32,
+                min_size: size(1, 2000),
+                max_size: size(5000, 5),
+                ..Default::default()
+            }
+        ));
+        let c = session.effective_constraints(window).unwrap();
+        assert_eq!(c.min, size(10, 800));
+        assert_eq!(c.max, size(1000, 800), "minimum wins over maximum");
+        assert_eq!(
+            session.effective_constraints(NonZeroU32::new(6).unwrap()),
+            None
+        );
+    }
+
+    #[test]
+    fn hints_clamp_resizes() {
+        let (mut session, window) = session();
+        session.set_hints(
+            window,
+            WindowHints {
+                flags: WindowHintsFlags::PMinSize as u32
+                    | WindowHintsFlags::PMaxSize as u32
+                    | WindowHintsFlags::PResizeInc as u32
+                    | WindowHintsFlags::PBaseSize as u32,
+                min_size: size(100, 100),
+                max_size: size(500, 500),
+                size_increment: size(10, 0),
+                size_base: size(15, 15),
+            },
+        );
+        let c = session.effective_constraints(window).unwrap();
+        assert_eq!(c.increment, size(10, 1));
+        assert_eq!(c.apply(size(1, 1)), size(105, 100));
+        assert_eq!(c.apply(size(203, 203)), size(195, 203));
+        assert_eq!(c.apply(size(9999, 9999)), size(495, 500));
+        assert!(session.destroy(window));
+        assert_eq!(session.effective_constraints(window), None);
+    }
+
+    #[test]
+    fn huge_increment() {
+        let (mut session, window) = session();
+        session.set_hints(
+            window,
+            WindowHints {
+                flags: WindowHintsFlags::PMinSize as u32
+                    | WindowHintsFlags::PResizeInc as u32
+                    | WindowHintsFlags::PBaseSize as u32,
+                min_size: size(100, 100),
+                size_increment: size(u32::MAX, u32::MAX),
+                size_base: size(15, 15),
+                ..Default::default()
+            },
+        );
+        let c = session.effective_constraints(window).unwrap();
+        assert_eq!(
+            c.increment,
+            size(qubes_gui::MAX_WINDOW_WIDTH, qubes_gui::MAX_WINDOW_HEIGHT)
+        );
+        assert_eq!(c.apply(size(200, 200)), size(200, 200));
+        assert_eq!(snap(200, 15, u32::MAX, 100, u32::MAX), 200);
+    }
+}
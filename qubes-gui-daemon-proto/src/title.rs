@@ -0,0 +1,166 @@
+/*
+ * The Qubes OS Project, https://www.qubes-os.org
+ *
+ * Copyright (C) 2021  Demi Marie Obenour  <demi@invisiblethingslab.com>
+ *
+ * This program is free software; you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License
+ * as published by the Free Software Foundation; either version 2
+ * of the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301, USA.
+ *
+ */
+
+use crate::sanitize::sanitize_title;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use core::num::NonZeroU32;
+
+/// The color label of a qube
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Label {
+    /// Red
+    Red,
+    /// Orange
+    Orange,
+    /// Yellow
+    Yellow,
+    /// Green
+    Green,
+    /// Gray
+    Gray,
+    /// Blue
+    Blue,
+    /// Purple
+    Purple,
+    /// Black
+    Black,
+}
+
+impl Label {
+    /// The name of the label, as used in qube properties
+    pub fn name(self) -> &'static str {
+        match self {
+            Label::Red => "red",
+            Label::Orange => "orange",
+            Label::Yellow => "yellow",
+            Label::Green => "green",
+            Label::Gray => "gray",
+            Label::Blue => "blue",
+            Label::Purple => "purple",
+            Label::Black => "black",
+        }
+    }
+
+    /// The color of the label, as `0xRRGGBB`
+    pub fn color(self) -> u32 {
+        match self {
+            Label::Red => 0xcc0000,
+            Label::Orange => 0xf57900,
+            Label::Yellow => 0xedd400,
+            Label::Green => 0x73d216,
+            Label::Gray => 0x555753,
+            Label::Blue => 0x3465a4,
+            Label::Purple => 0x75507b,
+            Label::Black => 0x000000,
+        }
+    }
+}
+
+/// Composes the titles shown to the user from the qube name and the
+/// sanitized titles sent by the agent, as in `[personal] Firefox`.
+///
+/// The qube name and label are trusted; agent titles are not.
+#[derive(Debug)]
+pub struct TitlePolicy {
+    qube_name: String,
+    label: Label,
+    allow_utf8: bool,
+    titles: BTreeMap<NonZeroU32, String>,
+}
+
+impl TitlePolicy {
+    /// Create a policy for the qube `qube_name` with label `label`.  UTF-8
+    /// titles are not allowed by default.
+    pub fn new(qube_name: &str, label: Label) -> Self {
+        Self {
+            qube_name: qube_name.into(),
+            label,
+            allow_utf8: false,
+            titles: BTreeMap::new(),
+        }
+    }
+
+    /// Allow or forbid non-ASCII characters in titles
+    pub fn allow_utf8(mut self, allow_utf8: bool) -> Self {
+        self.allow_utf8 = allow_utf8;
+        self
+    }
+
+    /// The qube name
+    pub fn qube_name(&self) -> &str {
+        &self.qube_name
+    }
+
+    /// The qube label
+    pub fn label(&self) -> Label {
+        self.label
+    }
+
+    /// The title shown for a window whose (already sanitized) title is
+    /// `title`
+    pub fn format(&self, title: &str) -> String {
+        let mut res = String::with_capacity(self.qube_name.len() + title.len() + 3);
+        res.push('[');
+        res.push_str(&self.qube_name);
+        res.push_str("] ");
+        res.push_str(title);
+        res
+    }
+
+    /// Handle a `MSG_SET_TITLE` from the agent.  Returns the new title to
+    /// show, or `None` if it has not changed.
+    pub fn update(&mut self, window: NonZeroU32, untrusted: &qubes_gui::WMName) -> Option<&str> {
+        let title = self.format(&sanitize_title(untrusted, self.allow_utf8));
+        match self.titles.insert(window, title) {
+            Some(old) if old == self.titles[&window] => None,
+            _ => Some(&self.titles[&window]),
+        }
+    }
+
+    /// The title currently shown for `window`, or `None` if the agent has
+    /// never set one
+    pub fn title(&self, window: NonZeroU32) -> Option<&str> {
+        self.titles.get(&window).map(|t| &**t)
+    }
+
+    /// Forget a destroyed window
+    pub fn forget(&mut self, window: NonZeroU32) {
+        self.titles.remove(&window);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn composes_and_tracks() {
+        let mut policy = TitlePolicy::new("personal", Label::Yellow);
+        let window = NonZeroU32::new(1).unwrap();
+        let name = qubes_gui::WMName::new("Fire\u{7f}fox").unwrap();
+        assert_eq!(policy.update(window, &name), Some("[personal] Fire_fox"));
+        assert_eq!(policy.update(window, &name), None, "unchanged");
+        assert_eq!(policy.title(window), Some("[personal] Fire_fox"));
+        policy.forget(window);
+        assert_eq!(policy.title(window), None);
+    }
+}
@@ -0,0 +1,692 @@
+/*
+ * The Qubes OS Project, https://www.qubes-os.org
+ *
+ * Copyright (C) 2021  Demi Marie Obenour  <demi@invisiblethingslab.com>
+ *
+ * This program is free software; you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License
+ * as published by the Free Software Foundation; either version 2
+ * of the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301, USA.
+ *
+ */
+
+use core::convert::TryInto as _;
+use core::num::NonZeroU32;
+use qubes_castable::Castable;
+use qubes_gui::{
+    Header, Msg, Rectangle, ValidRectangle, ValidWindowSize, Violation, ViolationKind,
+    ViolationSink, WindowHints,
+};
+
+/// Errors when validating an agent ⇒ daemon message.  All of these are
+/// protocol violations by the agent.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum Error {
+    /// A message that must be sent to a window was sent to the whole screen
+    MissingWindow {
+        /// The type of the message
+        ty: u32,
+    },
+    /// A message was sent that only the daemon may send, or that is not
+    /// supported
+    UnexpectedMessage {
+        /// The type of the message
+        ty: u32,
+    },
+    /// A rectangle was empty or too large
+    BadRectangle(Rectangle),
+    /// A boolean field was neither 0 nor 1
+    BadBoolean(u32),
+    /// Invalid UTF-8
+    BadUTF8(core::str::Utf8Error),
+    /// A string was not NUL-terminated
+    Unterminated,
+    /// A window dump had an unsupported type or depth
+    BadWindowDump {
+        /// The dump type
+        ty: u32,
+        /// Bits per pixel
+        bpp: u32,
+    },
+    /// A `MSG_MFNDUMP` body was not a [`qubes_gui::ShmCmd`] followed by
+    /// `num_mfn` MFNs
+    BadMfnDump {
+        /// The length of the body
+        len: usize,
+    },
+    /// The [`qubes_gui::ShmCmd`] of a `MSG_MFNDUMP` was not valid
+    BadShmCmd {
+        /// The first field that is not valid
+        field: &'static str,
+    },
+    /// A `MSG_WINDOW_FLAGS` had unknown flags, or set and unset the same flag
+    BadWindowFlags {
+        /// The flags to set
+        set: u32,
+        /// The flags to unset
+        unset: u32,
+    },
+}
+
+impl Error {
+    /// The kind of protocol violation this is
+    pub fn kind(&self) -> ViolationKind {
+        match self {
+            Error::MissingWindow { .. } => ViolationKind::UnknownWindow,
+            Error::BadMfnDump { .. } => ViolationKind::BadLength,
+            Error::UnexpectedMessage { .. } => ViolationKind::WrongDirection,
+            Error::BadRectangle(_)
+            | Error::BadBoolean(_)
+            | Error::BadUTF8(_)
+            | Error::Unterminated
+            | Error::BadWindowDump { .. }
+            | Error::BadShmCmd { .. }
+            | Error::BadWindowFlags { .. } => ViolationKind::BadField,
+        }
+    }
+}
+
+/// The grant references of a window dump
+#[derive(Debug, Copy, Clone)]
+pub struct GrantRefs<'a>(&'a [u8]);
+
+impl<'a> GrantRefs<'a> {
+    /// The number of grant references
+    pub fn len(&self) -> usize {
+        self.0.len() / 4
+    }
+
+    /// Are there no grant references?
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Iterate over the grant references.  These are UNTRUSTED, and may be
+    /// invalid or belong to someone else.
+    pub fn iter(&self) -> impl Iterator<Item = u32> + 'a {
+        self.0
+            .chunks_exact(4)
+            .map(|c| u32::from_ne_bytes(c.try_into().unwrap()))
+    }
+}
+
+/// A validated `MSG_MFNDUMP`: the size and offset of a window's buffer, and
+/// the machine frame numbers (MFNs) of its pages, in order.  Only agents that
+/// predate `MSG_WINDOW_DUMP` send these.
+///
+/// Mapping MFNs needs privcmd, which is out of scope for this crate: a
+/// [`crate::GrantMapper`] only maps grant references.
+#[derive(Debug, Copy, Clone)]
+pub struct Mfns<'a>(qubes_gui::ValidShmCmd, &'a [u8]);
+
+impl<'a> Mfns<'a> {
+    /// Parse the body of a `MSG_MFNDUMP`: a [`qubes_gui::ShmCmd`], followed
+    /// by `num_mfn` MFNs
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`Error::BadMfnDump`] if `body` is too short, or if the
+    /// number of MFNs is not `num_mfn`, and with [`Error::BadShmCmd`] if the
+    /// command is not valid.
+    pub fn parse(body: &'a [u8]) -> Result<Self, Error> {
+        let len = body.len();
+        let cmd_len = core::mem::size_of::<qubes_gui::ShmCmd>();
+        if len < cmd_len {
+            return Err(Error::BadMfnDump { len });
+        }
+        let (cmd, mfns) = body.split_at(cmd_len);
+        let cmd: qubes_gui::ShmCmd = Castable::from_bytes(cmd);
+        let cmd = cmd.validate().map_err(|e| match e {
+            qubes_gui::ProtocolError::BadFieldValue { field, .. } => Error::BadShmCmd { field },
+            _ => Error::BadMfnDump { len },
+        })?;
+        if mfns.len() != cmd.num_mfn() as usize * 4 {
+            return Err(Error::BadMfnDump { len });
+        }
+        Ok(Self(cmd, mfns))
+    }
+
+    /// The size of the image, and its offset into the first page
+    pub fn cmd(&self) -> qubes_gui::ValidShmCmd {
+        self.0
+    }
+
+    /// The number of MFNs
+    pub fn len(&self) -> usize {
+        self.1.len() / 4
+    }
+
+    /// Are there no MFNs?  This is never the case, but is here for
+    /// consistency with [`Mfns::len`].
+    pub fn is_empty(&self) -> bool {
+        self.1.is_empty()
+    }
+
+    /// Iterate over the MFNs.  These are UNTRUSTED: the agent may name pages
+    /// that it does not own.
+    pub fn iter(&self) -> impl Iterator<Item = u32> + 'a {
+        self.1
+            .chunks_exact(4)
+            .map(|c| u32::from_ne_bytes(c.try_into().unwrap()))
+    }
+}
+
+/// A validated `MSG_WINDOW_DUMP`
+#[derive(Debug, Copy, Clone)]
+pub struct WindowDump<'a> {
+    /// Size of the buffer
+    pub size: ValidWindowSize,
+    /// The grant references making up the buffer
+    pub grant_refs: GrantRefs<'a>,
+}
+
+/// Callbacks for messages sent by a GUI agent.  Every argument has already
+/// been validated: window IDs are never the whole-screen window, rectangles
+/// are non-empty and not too large, booleans really are booleans, and strings
+/// are valid UTF-8.  Whether a window actually exists is *not* checked; use
+/// [`crate::DaemonSession`] for that.
+///
+/// Strings and grant references are still UNTRUSTED in the sense that the
+/// agent controls their contents.
+#[allow(unused_variables)]
+pub trait MessageVisitor {
+    /// Create a window
+    fn on_create(
+        &mut self,
+        window: NonZeroU32,
+        rectangle: ValidRectangle,
+        parent: Option<NonZeroU32>,
+        override_redirect: bool,
+    ) {
+    }
+    /// Destroy a window
+    fn on_destroy(&mut self, window: NonZeroU32) {}
+    /// Map a window
+    fn on_map(
+        &mut self,
+        window: NonZeroU32,
+        transient_for: Option<NonZeroU32>,
+        override_redirect: bool,
+    ) {
+    }
+    /// Unmap a window
+    fn on_unmap(&mut self, window: NonZeroU32) {}
+    /// Move and/or resize a window
+    fn on_configure(
+        &mut self,
+        window: NonZeroU32,
+        rectangle: ValidRectangle,
+        override_redirect: bool,
+    ) {
+    }
+    /// Redraw part of a window from shared memory
+    fn on_shm_image(&mut self, window: NonZeroU32, rectangle: ValidRectangle) {}
+    /// Set the title of a window
+    fn on_set_title(&mut self, window: NonZeroU32, untrusted_title: &str) {}
+    /// Set the class of a window
+    fn on_window_class(&mut self, window: NonZeroU32, untrusted_class: &str, untrusted_name: &str) {
+    }
+    /// Set window manager hints
+    fn on_window_hints(&mut self, window: NonZeroU32, hints: &WindowHints) {}
+    /// Set and/or clear window flags.  Only known flags are present, and no
+    /// flag is both set and cleared.
+    fn on_window_flags(&mut self, window: NonZeroU32, set: u32, unset: u32) {}
+    /// Dock a window
+    fn on_dock(&mut self, window: NonZeroU32) {}
+    /// Set the buffer of a window
+    fn on_window_dump(&mut self, window: NonZeroU32, dump: WindowDump<'_>) {}
+    /// Set the buffer of a window the legacy way, from the MFNs of its pages.
+    /// Whether these are accepted at all is up to the
+    /// [`qubes_gui::DeprecatedPolicy`] used when reading headers.
+    fn on_mfn_dump(&mut self, window: NonZeroU32, mfns: Mfns<'_>) {}
+    /// Set the cursor of a window.  The cursor is not checked against the
+    /// known cursor types.
+    fn on_cursor(&mut self, window: NonZeroU32, cursor: u32) {}
+    /// Set the clipboard contents.  The data is not necessarily valid UTF-8.
+    fn on_clipboard_data(&mut self, untrusted_data: &[u8]) {}
+}
+
+fn window_of(header: Header) -> Result<NonZeroU32, Error> {
+    header
+        .untrusted_window()
+        .window
+        .ok_or(Error::MissingWindow { ty: header.ty() })
+}
+
+fn boolean(value: u32) -> Result<bool, Error> {
+    match value {
+        0 => Ok(false),
+        1 => Ok(true),
+        other => Err(Error::BadBoolean(other)),
+    }
+}
+
+fn rectangle(rectangle: Rectangle) -> Result<ValidRectangle, Error> {
+    ValidRectangle::new(rectangle).map_err(|_| Error::BadRectangle(rectangle))
+}
+
+fn c_str(bytes: &[u8]) -> Result<&str, Error> {
+    let len = bytes
+        .iter()
+        .position(|&b| b == 0)
+        .ok_or(Error::Unterminated)?;
+    core::str::from_utf8(&bytes[..len]).map_err(Error::BadUTF8)
+}
+
+/// Validate a message from an agent and call the corresponding method of
+/// `visitor`.  `body` must have the length in `header`.
+///
+/// # Errors
+///
+/// Fails if the message is not valid.  Nothing is called in that case.
+pub fn visit<V: MessageVisitor + ?Sized>(
+    visitor: &mut V,
+    header: Header,
+    body: &[u8],
+) -> Result<(), Error> {
+    assert_eq!(header.len(), body.len(), "Wrong body length provided!");
+    let ty = header.ty();
+    let msg: Msg = ty.try_into().map_err(|_| Error::UnexpectedMessage { ty })?;
+    if let Msg::ClipboardData = msg {
+        visitor.on_clipboard_data(body);
+        return Ok(());
+    }
+    let window = window_of(header)?;
+    match msg {
+        Msg::Create => {
+            let create: qubes_gui::Create = Castable::from_bytes(body);
+            let override_redirect = boolean(create.override_redirect)?;
+            visitor.on_create(
+                window,
+                rectangle(create.rectangle)?,
+                create.parent,
+                override_redirect,
+            )
+        }
+        Msg::Destroy => visitor.on_destroy(window),
+        Msg::Map => {
+            let info: qubes_gui::MapInfo = Castable::from_bytes(body);
+            let override_redirect = boolean(info.override_redirect)?;
+            visitor.on_map(
+                window,
+                NonZeroU32::new(info.transient_for),
+                override_redirect,
+            )
+        }
+        Msg::Unmap => visitor.on_unmap(window),
+        Msg::Configure => {
+            let configure: qubes_gui::Configure = Castable::from_bytes(body);
+            let override_redirect = boolean(configure.override_redirect)?;
+            visitor.on_configure(window, rectangle(configure.rectangle)?, override_redirect)
+        }
+        Msg::ShmImage => {
+            let image: qubes_gui::ShmImage = Castable::from_bytes(body);
+            visitor.on_shm_image(window, rectangle(image.rectangle)?)
+        }
+        Msg::SetTitle => {
+            let name: qubes_gui::WMName = Castable::from_bytes(body);
+            visitor.on_set_title(window, c_str(&name.data)?)
+        }
+        Msg::WindowClass => {
+            let class: qubes_gui::WMClass = Castable::from_bytes(body);
+            let (res_class, res_name) = (c_str(&class.res_class)?, c_str(&class.res_name)?);
+            visitor.on_window_class(window, res_class, res_name)
+        }
+        Msg::WindowHints => visitor.on_window_hints(window, &Castable::from_bytes(body)),
+        Msg::WindowFlags => {
+            let flags: qubes_gui::WindowFlags = Castable::from_bytes(body);
+            flags.validate().map_err(|_| Error::BadWindowFlags {
+                set: flags.set,
+                unset: flags.unset,
+            })?;
+            visitor.on_window_flags(window, flags.set, flags.unset)
+        }
+        Msg::Dock => visitor.on_dock(window),
+        Msg::WindowDump => {
+            let (hdr, refs) = body.split_at(core::mem::size_of::<qubes_gui::WindowDumpHeader>());
+            let hdr: qubes_gui::WindowDumpHeader = Castable::from_bytes(hdr);
+            if hdr.ty != qubes_gui::WINDOW_DUMP_TYPE_GRANT_REFS || hdr.bpp != 24 {
+                return Err(Error::BadWindowDump {
+                    ty: hdr.ty,
+                    bpp: hdr.bpp,
+                });
+            }
+            let size = rectangle(Rectangle {
+                top_left: Default::default(),
+                size: qubes_gui::WindowSize {
+                    width: hdr.width,
+                    height: hdr.height,
+                },
+            })?
+            .size();
+            visitor.on_window_dump(
+                window,
+                WindowDump {
+                    size,
+                    grant_refs: GrantRefs(refs),
+                },
+            )
+        }
+        Msg::MfnDump => visitor.on_mfn_dump(window, Mfns::parse(body)?),
+        Msg::Cursor => {
+            let cursor: qubes_gui::Cursor = Castable::from_bytes(body);
+            visitor.on_cursor(window, cursor.cursor)
+        }
+        // Daemon ⇒ agent messages, and deprecated ones
+        _ => return Err(Error::UnexpectedMessage { ty }),
+    }
+    Ok(())
+}
+
+/// Like [`visit`], but also reports any error to `sink`
+pub fn visit_audited<V: MessageVisitor + ?Sized, S: ViolationSink + ?Sized>(
+    visitor: &mut V,
+    sink: &mut S,
+    header: Header,
+    body: &[u8],
+) -> Result<(), Error> {
+    visit(visitor, header, body)
+        .inspect_err(|e| sink.report(&Violation::from_header(e.kind(), &header)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::{vec, vec::Vec};
+    use qubes_gui::UntrustedHeader;
+
+    #[derive(Default)]
+    struct Count(u32);
+
+    impl MessageVisitor for Count {
+        fn on_create(&mut self, _: NonZeroU32, _: ValidRectangle, _: Option<NonZeroU32>, _: bool) {
+            self.0 += 1
+        }
+        fn on_set_title(&mut self, _: NonZeroU32, untrusted_title: &str) {
+            assert_eq!(untrusted_title, "hi");
+            self.0 += 1
+        }
+    }
+
+    fn header(ty: u32, window: u32, len: usize) -> Header {
+        UntrustedHeader {
+            ty,
+            window: qubes_gui::WindowID {
+                window: NonZeroU32::new(window),
+            },
+            untrusted_len: len as u32,
+        }
+        .validate_length()
+        .unwrap()
+        .unwrap()
+    }
+
+    fn create(width: u32, override_redirect: u32) -> qubes_gui::Create {
+        let mut create = qubes_gui::Create::default();
+        create.rectangle.size.width = width;
+        create.rectangle.size.height = 1;
+        create.override_redirect = override_redirect;
+        create
+    }
+
+    #[test]
+    fn validates_create() {
+        let mut count = Count::default();
+        let good = create(1, 0);
+        let len = good.as_bytes().len();
+        visit(
+            &mut count,
+            header(qubes_gui::MSG_CREATE, 1, len),
+            good.as_bytes(),
+        )
+        .unwrap();
+        assert_eq!(count.0, 1);
+        for (bad, window) in [(create(1, 0), 0), (create(0, 0), 1), (create(1, 2), 1)] {
+            let hdr = header(qubes_gui::MSG_CREATE, window, len);
+            assert!(visit(&mut count, hdr, bad.as_bytes()).is_err());
+        }
+        assert_eq!(count.0, 1, "visitor not called on error");
+    }
+
+    #[test]
+    fn titles() {
+        let mut count = Count::default();
+        let mut name = qubes_gui::WMName::default();
+        name.data[..2].copy_from_slice(b"hi");
+        let hdr = header(qubes_gui::MSG_SET_TITLE, 1, name.as_bytes().len());
+        visit(&mut count, hdr, name.as_bytes()).unwrap();
+        name.data = [b'a'; 128];
+        assert_eq!(
+            visit(&mut count, hdr, name.as_bytes()),
+            Err(Error::Unterminated)
+        );
+        name.data[..2].copy_from_slice(&[0xff, 0]);
+        assert!(matches!(
+            visit(&mut count, hdr, name.as_bytes()),
+            Err(Error::BadUTF8(_))
+        ));
+        assert_eq!(count.0, 1);
+    }
+
+    #[test]
+    fn mfn_dumps() {
+        struct Dump(Vec<u32>);
+        impl MessageVisitor for Dump {
+            fn on_mfn_dump(&mut self, _: NonZeroU32, mfns: Mfns<'_>) {
+                self.0.extend(mfns.iter())
+            }
+        }
+        let cmd = qubes_gui::ShmCmd {
+            width: 32,
+            height: 32,
+            bpp: 24,
+            off: 16,
+            num_mfn: 2,
+            ..Default::default()
+        };
+        let body = |cmd: qubes_gui::ShmCmd, mfns: &[u32]| -> Vec<u8> {
+            let mfns = mfns.iter().flat_map(|m| m.to_ne_bytes());
+            cmd.as_bytes().iter().copied().chain(mfns).collect()
+        };
+        let good = body(cmd, &[5, 6]);
+        let mut dump = Dump(vec![]);
+        let hdr = header(qubes_gui::MSG_MFNDUMP, 1, good.len());
+        visit(&mut dump, hdr, &good).unwrap();
+        assert_eq!(dump.0, [5, 6]);
+        assert_eq!(Mfns::parse(&good).unwrap().cmd().offset(), 16);
+        assert_eq!(
+            Mfns::parse(&good[..7]).unwrap_err(),
+            Error::BadMfnDump { len: 7 }
+        );
+        let short = body(cmd, &[5]);
+        assert_eq!(
+            Mfns::parse(&short).unwrap_err(),
+            Error::BadMfnDump { len: short.len() }
+        );
+        let bad = body(qubes_gui::ShmCmd { bpp: 32, ..cmd }, &[5, 6]);
+        assert_eq!(
+            Mfns::parse(&bad).unwrap_err(),
+            Error::BadShmCmd { field: "bpp" }
+        );
+        let too_few = body(qubes_gui::ShmCmd { num_mfn: 1, ..cmd }, &[5]);
+        assert_eq!(
+            Mfns::parse(&too_few).unwrap_err(),
+            Error::BadShmCmd { field: "num_mfn" }
+        );
+    }
+
+    #[test]
+    fn validates_shm_image() {
+        struct Damage(Vec<Rectangle>);
+        impl MessageVisitor for Damage {
+            fn on_shm_image(&mut self, _: NonZeroU32, rectangle: ValidRectangle) {
+                self.0.push(rectangle.get())
+            }
+        }
+        let image = |x: i32, width: u32, height: u32| qubes_gui::ShmImage {
+            rectangle: Rectangle {
+                top_left: qubes_gui::Coordinates { x, y: 0 },
+                size: qubes_gui::WindowSize { width, height },
+            },
+        };
+        let hdr = header(qubes_gui::MSG_SHMIMAGE, 1, 16);
+        let mut damage = Damage(vec![]);
+        visit(&mut damage, hdr, image(-3, 5, 5).as_bytes()).unwrap();
+        assert_eq!(damage.0, [image(-3, 5, 5).rectangle]);
+        for bad in [
+            image(0, -1i32 as u32, 5),
+            image(0, 5, qubes_gui::MAX_WINDOW_HEIGHT + 1),
+            image(0, 0, 5),
+        ] {
+            assert_eq!(
+                visit(&mut damage, hdr, bad.as_bytes()),
+                Err(Error::BadRectangle(bad.rectangle))
+            );
+        }
+        assert_eq!(damage.0.len(), 1, "visitor not called on error");
+    }
+
+    #[test]
+    fn window_flags() {
+        let hdr = header(qubes_gui::MSG_WINDOW_FLAGS, 1, 8);
+        let flags = |set: u32, unset: u32| qubes_gui::WindowFlags { set, unset };
+        visit(&mut Count::default(), hdr, flags(1, 2).as_bytes()).unwrap();
+        for (set, unset) in [(1, 1), (1 << 3, 0), (0, 1 << 31)] {
+            assert_eq!(
+                visit(&mut Count::default(), hdr, flags(set, unset).as_bytes()),
+                Err(Error::BadWindowFlags { set, unset })
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_daemon_messages() {
+        let hdr = header(qubes_gui::MSG_CLOSE, 1, 0);
+        assert_eq!(
+            visit(&mut Count::default(), hdr, &[]),
+            Err(Error::UnexpectedMessage {
+                ty: qubes_gui::MSG_CLOSE
+            })
+        );
+        let mut reported = None;
+        let mut sink = |v: &Violation| reported = Some(*v);
+        assert!(visit_audited(&mut Count::default(), &mut sink, hdr, &[]).is_err());
+        assert_eq!(
+            reported,
+            Some(Violation {
+                kind: ViolationKind::WrongDirection,
+                ty: qubes_gui::MSG_CLOSE,
+                window: NonZeroU32::new(1),
+                untrusted_len: 0,
+            })
+        );
+    }
+
+    struct Ignore;
+
+    impl MessageVisitor for Ignore {}
+
+    /// Check that the specification names every field of `valid` that
+    /// [`visit`] checks
+    fn check_rules<T: Castable>(msg: Msg, valid: T, trailer: &[u8]) {
+        let accepts = |body: &T| {
+            let bytes = [body.as_bytes(), trailer].concat();
+            let header = header(msg as u32, 1, bytes.len());
+            visit(&mut Ignore, header, &bytes).is_ok()
+        };
+        assert!(accepts(&valid), "{}", msg);
+        qubes_gui::spec::corrupt_each_field(&valid, &mut |path, corrupted| {
+            assert!(
+                accepts(corrupted) || qubes_gui::spec::mentions(msg, path),
+                "{} rules do not mention {:?}",
+                msg,
+                path
+            )
+        });
+    }
+
+    #[test]
+    fn rules_name_checked_fields() {
+        let rectangle = Rectangle {
+            top_left: qubes_gui::Coordinates { x: 1, y: 2 },
+            size: qubes_gui::WindowSize {
+                width: 3,
+                height: 4,
+            },
+        };
+        check_rules(Msg::Create, create(3, 1), &[]);
+        check_rules(
+            Msg::Map,
+            qubes_gui::MapInfo {
+                transient_for: 2,
+                override_redirect: 1,
+            },
+            &[],
+        );
+        check_rules(
+            Msg::Configure,
+            qubes_gui::Configure {
+                rectangle,
+                override_redirect: 1,
+            },
+            &[],
+        );
+        check_rules(Msg::ShmImage, qubes_gui::ShmImage { rectangle }, &[]);
+        let mut name = qubes_gui::WMName::default();
+        name.data[..3].copy_from_slice(b"hi\0");
+        check_rules(Msg::SetTitle, name, &[]);
+        let mut class = qubes_gui::WMClass::default();
+        class.res_class[..3].copy_from_slice(b"hi\0");
+        class.res_name[..3].copy_from_slice(b"hi\0");
+        check_rules(Msg::WindowClass, class, &[]);
+        check_rules(Msg::WindowHints, WindowHints::default(), &[]);
+        check_rules(
+            Msg::WindowFlags,
+            qubes_gui::WindowFlags {
+                set: qubes_gui::WindowFlag::Fullscreen as u32,
+                unset: qubes_gui::WindowFlag::Minimize as u32,
+            },
+            &[],
+        );
+        check_rules(
+            Msg::WindowDump,
+            qubes_gui::WindowDumpHeader {
+                ty: qubes_gui::WINDOW_DUMP_TYPE_GRANT_REFS,
+                width: 3,
+                height: 4,
+                bpp: 24,
+            },
+            &[5, 0, 0, 0],
+        );
+        check_rules(
+            Msg::MfnDump,
+            qubes_gui::ShmCmd {
+                shmid: 0,
+                width: 3,
+                height: 4,
+                bpp: 24,
+                off: 0,
+                num_mfn: 1,
+                domid: 0,
+            },
+            &[5, 0, 0, 0],
+        );
+        check_rules(
+            Msg::Cursor,
+            qubes_gui::Cursor {
+                cursor: qubes_gui::CURSOR_X11,
+            },
+            &[],
+        );
+    }
+}
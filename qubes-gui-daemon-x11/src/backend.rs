@@ -0,0 +1,505 @@
+/*
+ * The Qubes OS Project, https://www.qubes-os.org
+ *
+ * Copyright (C) 2021  Demi Marie Obenour  <demi@invisiblethingslab.com>
+ *
+ * This program is free software; you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License
+ * as published by the Free Software Foundation; either version 2
+ * of the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301, USA.
+ *
+ */
+
+//! The backend and its errors
+
+use crate::blit::{self, Target};
+use crate::input::Input;
+use qubes_gui::{Coordinates, Header, Rectangle, ValidRectangle, WindowSize};
+use qubes_gui_connection::OutgoingMessage;
+use qubes_gui_daemon_proto::{
+    input, GrantMapper, MessageVisitor, RegistryError, TitlePolicy, WindowDump, WindowRegistry,
+};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io;
+use std::num::NonZeroU32;
+use x11rb::connection::Connection;
+use x11rb::errors::{ConnectionError, ReplyError, ReplyOrIdError};
+use x11rb::protocol::xproto::{
+    AtomEnum, ConfigureWindowAux, ConnectionExt as _, CreateGCAux, CreateWindowAux, EventMask,
+    ImageOrder, PropMode, Window, WindowClass,
+};
+use x11rb::protocol::Event;
+use x11rb::wrapper::ConnectionExt as _;
+
+x11rb::atom_manager! {
+    Atoms: AtomsCookie {
+        WM_PROTOCOLS,
+        WM_DELETE_WINDOW,
+        _NET_WM_NAME,
+        UTF8_STRING,
+    }
+}
+
+/// Errors from [`X11Backend`]
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Error {
+    /// The agent sent an invalid message
+    Protocol(qubes_gui_daemon_proto::Error),
+    /// The agent broke the window lifecycle rules
+    Registry(RegistryError),
+    /// The X server failed or reported an error
+    X11(ReplyOrIdError),
+    /// A composition buffer could not be mapped
+    Map(io::Error),
+    /// The X server does not support 24-bit color with 32 bits per pixel
+    UnsupportedDepth(u8),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Protocol(e) => write!(f, "Invalid message from agent: {:?}", e),
+            Error::Registry(e) => write!(f, "{}", e),
+            Error::X11(e) => write!(f, "X11 error: {}", e),
+            Error::Map(e) => write!(f, "Cannot map composition buffer: {}", e),
+            Error::UnsupportedDepth(depth) => write!(f, "Unsupported root depth {}", depth),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<RegistryError> for Error {
+    fn from(e: RegistryError) -> Self {
+        Error::Registry(e)
+    }
+}
+
+impl From<ReplyOrIdError> for Error {
+    fn from(e: ReplyOrIdError) -> Self {
+        Error::X11(e)
+    }
+}
+
+impl From<ReplyError> for Error {
+    fn from(e: ReplyError) -> Self {
+        Error::X11(e.into())
+    }
+}
+
+impl From<ConnectionError> for Error {
+    fn from(e: ConnectionError) -> Self {
+        Error::X11(e.into())
+    }
+}
+
+/// An agent window shown on the X server
+#[derive(Debug)]
+struct Shown<B> {
+    x_window: Window,
+    /// The composition buffer and its size
+    buffer: Option<(WindowSize, B)>,
+}
+
+/// Shows the windows of one agent on an X server.  See the crate
+/// documentation for what it does and does not do.
+#[derive(Debug)]
+pub struct X11Backend<C: Connection, M: GrantMapper> {
+    conn: C,
+    root: Window,
+    target: Target,
+    atoms: Atoms,
+    mapper: M,
+    registry: WindowRegistry,
+    titles: TitlePolicy,
+    windows: BTreeMap<NonZeroU32, Shown<M::Buffer>>,
+    input: Input,
+    outgoing: Vec<(NonZeroU32, OutgoingMessage)>,
+    /// Acknowledge window dumps?
+    dump_acks: bool,
+    /// The first error from a [`MessageVisitor`] method, which cannot
+    /// return it
+    error: Option<Error>,
+}
+
+/// Clamp a coordinate to what X11 can express
+fn x11_coordinate(value: i32) -> i16 {
+    value.clamp(i16::MIN.into(), i16::MAX.into()) as i16
+}
+
+impl<C: Connection, M: GrantMapper> X11Backend<C, M> {
+    /// Show windows on screen `screen` of `conn`, mapping buffers with
+    /// `mapper` and formatting titles with `titles`
+    ///
+    /// # Errors
+    ///
+    /// Fails if `screen` does not exist, if its root window does not have a
+    /// depth of 24 with 32 bits per pixel, or if talking to the X server
+    /// fails.
+    pub fn new(conn: C, screen: usize, mapper: M, titles: TitlePolicy) -> Result<Self, Error> {
+        let setup = conn.setup();
+        let root_screen = setup
+            .roots
+            .get(screen)
+            .ok_or(ConnectionError::UnknownError)?;
+        let (root, depth) = (root_screen.root, root_screen.root_depth);
+        let packed = setup
+            .pixmap_formats
+            .iter()
+            .any(|format| format.depth == depth && format.bits_per_pixel == 32);
+        if depth != 24 || !packed {
+            return Err(Error::UnsupportedDepth(depth));
+        }
+        let big_endian = setup.image_byte_order == ImageOrder::MSB_FIRST;
+        let atoms = Atoms::new(&conn)?.reply()?;
+        let gc = conn.generate_id()?;
+        conn.create_gc(gc, root, &CreateGCAux::new())?;
+        let target = Target {
+            drawable: root,
+            gc,
+            depth,
+            big_endian,
+        };
+        let input = Input::new(root, atoms.WM_PROTOCOLS, atoms.WM_DELETE_WINDOW);
+        Ok(Self {
+            conn,
+            root,
+            target,
+            atoms,
+            mapper,
+            registry: WindowRegistry::new(),
+            titles,
+            windows: BTreeMap::new(),
+            input,
+            outgoing: vec![],
+            dump_acks: true,
+            error: None,
+        })
+    }
+
+    /// Send `MSG_WINDOW_DUMP_ACK` once the buffer of each `MSG_WINDOW_DUMP`
+    /// is mapped, so the agent can free the buffer it replaced.  This is on
+    /// by default, and should be turned off for agents that do not support
+    /// `Extension::DumpAck`.
+    pub fn set_dump_acks(&mut self, enabled: bool) {
+        self.dump_acks = enabled
+    }
+
+    /// The connection to the X server
+    pub fn connection(&self) -> &C {
+        &self.conn
+    }
+
+    /// The X11 window that shows `window`, if it exists
+    pub fn x_window(&self, window: NonZeroU32) -> Option<Window> {
+        self.windows.get(&window).map(|shown| shown.x_window)
+    }
+
+    /// Handle a message from the agent.  `body` must have the length in
+    /// `header`.  Quotas and rate limits are not enforced here: the caller
+    /// should pass `header` to a `QuotaTracker` and a `RateLimiter` first.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the message is invalid, if it breaks the window lifecycle
+    /// rules, or if drawing or mapping a buffer fails.  The connection to
+    /// the agent should be closed on errors other than [`Error::X11`].
+    pub fn handle_message(&mut self, header: Header, body: &[u8]) -> Result<(), Error> {
+        qubes_gui_daemon_proto::visit(self, header, body).map_err(Error::Protocol)?;
+        self.conn.flush()?;
+        self.error.take().map_or(Ok(()), Err)
+    }
+
+    /// Handle an event from the X server.  Messages for the agent are
+    /// queued; see [`X11Backend::take_messages`].
+    ///
+    /// # Errors
+    ///
+    /// Fails if redrawing a window or getting the keymap fails.
+    pub fn handle_event(&mut self, event: &Event) -> Result<(), Error> {
+        if let Event::Expose(expose) = event {
+            if let Some(window) = self.input.window(expose.window) {
+                let rectangle = Rectangle {
+                    top_left: Coordinates {
+                        x: expose.x.into(),
+                        y: expose.y.into(),
+                    },
+                    size: WindowSize {
+                        width: expose.width.into(),
+                        height: expose.height.into(),
+                    },
+                };
+                self.draw(window, rectangle)?;
+                self.conn.flush()?;
+            }
+        } else if let Some((window, message)) = self.input.translate(event) {
+            if let OutgoingMessage::Focus(qubes_gui::Focus {
+                ty: qubes_gui::EV_FOCUS_IN,
+                ..
+            }) = message
+            {
+                // The agent must learn about keys pressed or released while
+                // it did not have focus, and before it gets focus.
+                let keys = self.conn.query_keymap()?.reply()?.keys;
+                let keymap = input::keymap_from_x11(keys);
+                self.outgoing
+                    .push((window, OutgoingMessage::KeymapNotify(keymap)))
+            }
+            self.outgoing.push((window, message))
+        }
+        Ok(())
+    }
+
+    /// Take the messages to send to the agent, in order, with the windows
+    /// to send them to
+    pub fn take_messages(&mut self) -> Vec<(NonZeroU32, OutgoingMessage)> {
+        std::mem::take(&mut self.outgoing)
+    }
+
+    /// Copy `rectangle` of the buffer of `window`, if it has one, to the
+    /// window
+    fn draw(&self, window: NonZeroU32, rectangle: Rectangle) -> Result<(), ConnectionError> {
+        let shown = match self.windows.get(&window) {
+            Some(shown) => shown,
+            None => return Ok(()),
+        };
+        match &shown.buffer {
+            Some((size, buffer)) => {
+                let target = Target {
+                    drawable: shown.x_window,
+                    ..self.target
+                };
+                blit::put(&self.conn, target, buffer.as_ref(), *size, rectangle)
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Remember the first error
+    fn fail(&mut self, result: Result<(), Error>) {
+        if let Err(e) = result {
+            self.error.get_or_insert(e);
+        }
+    }
+
+    fn create(
+        &mut self,
+        window: NonZeroU32,
+        rectangle: ValidRectangle,
+        parent: Option<NonZeroU32>,
+        override_redirect: bool,
+    ) -> Result<(), Error> {
+        let create = qubes_gui::Create {
+            rectangle: rectangle.get(),
+            parent,
+            override_redirect: override_redirect.into(),
+        };
+        self.registry.create(window, &create)?;
+        let x_window = self.conn.generate_id()?;
+        let events = EventMask::KEY_PRESS
+            | EventMask::KEY_RELEASE
+            | EventMask::BUTTON_PRESS
+            | EventMask::BUTTON_RELEASE
+            | EventMask::POINTER_MOTION
+            | EventMask::ENTER_WINDOW
+            | EventMask::LEAVE_WINDOW
+            | EventMask::FOCUS_CHANGE
+            | EventMask::EXPOSURE
+            | EventMask::STRUCTURE_NOTIFY;
+        let aux = CreateWindowAux::new()
+            .background_pixel(0)
+            .override_redirect(u32::from(override_redirect))
+            .event_mask(events);
+        let top_left = rectangle.top_left();
+        let (x, y) = (x11_coordinate(top_left.x), x11_coordinate(top_left.y));
+        // Sizes are at most `MAX_WINDOW_WIDTH` by `MAX_WINDOW_HEIGHT`, which
+        // fit in a `u16`.
+        let size = rectangle.size();
+        self.conn.create_window(
+            x11rb::COPY_DEPTH_FROM_PARENT,
+            x_window,
+            self.root,
+            x,
+            y,
+            size.width() as u16,
+            size.height() as u16,
+            0,
+            WindowClass::INPUT_OUTPUT,
+            x11rb::COPY_FROM_PARENT,
+            &aux,
+        )?;
+        self.conn.change_property32(
+            PropMode::REPLACE,
+            x_window,
+            self.atoms.WM_PROTOCOLS,
+            AtomEnum::ATOM,
+            &[self.atoms.WM_DELETE_WINDOW],
+        )?;
+        let shown = Shown {
+            x_window,
+            buffer: None,
+        };
+        self.windows.insert(window, shown);
+        self.input.insert(x_window, window, top_left);
+        Ok(())
+    }
+
+    fn destroy(&mut self, window: NonZeroU32) -> Result<(), Error> {
+        self.registry.destroy(window)?;
+        self.titles.forget(window);
+        if let Some(shown) = self.windows.remove(&window) {
+            self.input.remove(shown.x_window);
+            self.conn.destroy_window(shown.x_window)?;
+        }
+        // Acknowledge the destruction, after which the ID may be reused
+        self.outgoing.push((window, OutgoingMessage::Destroy));
+        self.registry.acknowledge_destroy(window);
+        Ok(())
+    }
+
+    fn x_window_checked(&self, window: NonZeroU32) -> Result<Window, Error> {
+        self.registry.check(window)?;
+        Ok(self.windows[&window].x_window)
+    }
+
+    fn map(&mut self, window: NonZeroU32, transient_for: Option<NonZeroU32>) -> Result<(), Error> {
+        let x_window = self.x_window_checked(window)?;
+        if let Some(parent) = transient_for.and_then(|parent| self.x_window(parent)) {
+            self.conn.change_property32(
+                PropMode::REPLACE,
+                x_window,
+                AtomEnum::WM_TRANSIENT_FOR,
+                AtomEnum::WINDOW,
+                &[parent],
+            )?;
+        }
+        self.conn.map_window(x_window)?;
+        Ok(())
+    }
+
+    fn configure(&mut self, window: NonZeroU32, rectangle: ValidRectangle) -> Result<(), Error> {
+        self.registry.configure(window, rectangle.get())?;
+        let top_left = rectangle.top_left();
+        let aux = ConfigureWindowAux::new()
+            .x(i32::from(x11_coordinate(top_left.x)))
+            .y(i32::from(x11_coordinate(top_left.y)))
+            .width(rectangle.size().width())
+            .height(rectangle.size().height());
+        self.conn
+            .configure_window(self.windows[&window].x_window, &aux)?;
+        Ok(())
+    }
+
+    fn set_title(&mut self, window: NonZeroU32, untrusted_title: &str) -> Result<(), Error> {
+        let x_window = self.x_window_checked(window)?;
+        // Titles from the visitor end at the first NUL, so this cannot fail.
+        let untrusted = qubes_gui::WMName::new(untrusted_title).unwrap_or_default();
+        let title = match self.titles.update(window, &untrusted) {
+            Some(title) => title.as_bytes(),
+            None => return Ok(()),
+        };
+        let (name, utf8) = (self.atoms._NET_WM_NAME, self.atoms.UTF8_STRING);
+        self.conn
+            .change_property8(PropMode::REPLACE, x_window, name, utf8, title)?;
+        self.conn.change_property8(
+            PropMode::REPLACE,
+            x_window,
+            AtomEnum::WM_NAME,
+            AtomEnum::STRING,
+            title,
+        )?;
+        Ok(())
+    }
+
+    fn window_dump(&mut self, window: NonZeroU32, dump: WindowDump<'_>) -> Result<(), Error> {
+        self.registry.check(window)?;
+        let buffer = self.mapper.map(window, &dump).map_err(Error::Map)?;
+        let size = dump.size.get();
+        if let Some(shown) = self.windows.get_mut(&window) {
+            shown.buffer = Some((size, buffer))
+        }
+        if self.dump_acks {
+            self.outgoing.push((window, OutgoingMessage::DumpAck))
+        }
+        let whole = Rectangle {
+            top_left: Coordinates::default(),
+            size,
+        };
+        Ok(self.draw(window, whole)?)
+    }
+}
+
+impl<C: Connection, M: GrantMapper> MessageVisitor for X11Backend<C, M> {
+    fn on_create(
+        &mut self,
+        window: NonZeroU32,
+        rectangle: ValidRectangle,
+        parent: Option<NonZeroU32>,
+        override_redirect: bool,
+    ) {
+        let res = self.create(window, rectangle, parent, override_redirect);
+        self.fail(res)
+    }
+
+    fn on_destroy(&mut self, window: NonZeroU32) {
+        let res = self.destroy(window);
+        self.fail(res)
+    }
+
+    fn on_map(
+        &mut self,
+        window: NonZeroU32,
+        transient_for: Option<NonZeroU32>,
+        _override_redirect: bool,
+    ) {
+        let res = self.map(window, transient_for);
+        self.fail(res)
+    }
+
+    fn on_unmap(&mut self, window: NonZeroU32) {
+        let res = self
+            .x_window_checked(window)
+            .and_then(|x_window| Ok(self.conn.unmap_window(x_window).map(drop)?));
+        self.fail(res)
+    }
+
+    fn on_configure(
+        &mut self,
+        window: NonZeroU32,
+        rectangle: ValidRectangle,
+        _override_redirect: bool,
+    ) {
+        let res = self.configure(window, rectangle);
+        self.fail(res)
+    }
+
+    fn on_shm_image(&mut self, window: NonZeroU32, rectangle: ValidRectangle) {
+        let rectangle = rectangle.get();
+        let res = match self.registry.check_damage(window, rectangle) {
+            Ok(()) => self.draw(window, rectangle).map_err(Error::from),
+            Err(e) => Err(e.into()),
+        };
+        self.fail(res)
+    }
+
+    fn on_set_title(&mut self, window: NonZeroU32, untrusted_title: &str) {
+        let res = self.set_title(window, untrusted_title);
+        self.fail(res)
+    }
+
+    fn on_window_dump(&mut self, window: NonZeroU32, dump: WindowDump<'_>) {
+        let res = self.window_dump(window, dump);
+        self.fail(res)
+    }
+}
@@ -0,0 +1,143 @@
+/*
+ * The Qubes OS Project, https://www.qubes-os.org
+ *
+ * Copyright (C) 2021  Demi Marie Obenour  <demi@invisiblethingslab.com>
+ *
+ * This program is free software; you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License
+ * as published by the Free Software Foundation; either version 2
+ * of the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301, USA.
+ *
+ */
+
+//! Copying from composition buffers to X11 windows
+
+use qubes_gui::{Rectangle, WindowSize};
+use x11rb::connection::Connection;
+use x11rb::errors::ConnectionError;
+use x11rb::protocol::xproto::{ConnectionExt as _, Drawable, Gcontext, ImageFormat};
+
+/// Size of a `PutImage` request without its data
+const PUT_IMAGE_HEADER: usize = 24;
+
+/// The pixels of `rows` rows of `rectangle`, starting at row `first`, as
+/// `ZPixmap` data with 32 bits per pixel.  `rectangle` must lie inside
+/// `buffer`, which has size `size`.
+pub(crate) fn image_data(
+    buffer: &[u32],
+    size: WindowSize,
+    rectangle: Rectangle,
+    first: u32,
+    rows: u32,
+    big_endian: bool,
+) -> Vec<u8> {
+    let (x, y) = (rectangle.top_left.x as usize, rectangle.top_left.y as usize);
+    let width = rectangle.size.width as usize;
+    let mut data = Vec::with_capacity(4 * width * rows as usize);
+    for row in first..first + rows {
+        let start = (y + row as usize) * size.width as usize + x;
+        for &pixel in &buffer[start..start + width] {
+            let bytes = if big_endian {
+                pixel.to_be_bytes()
+            } else {
+                pixel.to_le_bytes()
+            };
+            data.extend_from_slice(&bytes)
+        }
+    }
+    data
+}
+
+/// Where the pixels of a buffer go
+#[derive(Debug, Copy, Clone)]
+pub(crate) struct Target {
+    pub(crate) drawable: Drawable,
+    pub(crate) gc: Gcontext,
+    pub(crate) depth: u8,
+    pub(crate) big_endian: bool,
+}
+
+/// Copy `rectangle` of `buffer`, which has size `size`, to the same place in
+/// `target`.  Parts of `rectangle` outside the buffer are ignored, and so is
+/// a buffer too small for its size.  Large rectangles are split into several
+/// requests.
+pub(crate) fn put<C: Connection>(
+    conn: &C,
+    target: Target,
+    buffer: &[u32],
+    size: WindowSize,
+    rectangle: Rectangle,
+) -> Result<(), ConnectionError> {
+    let rectangle = match rectangle.clamp_to(size) {
+        Some(rectangle) => rectangle,
+        None => return Ok(()),
+    };
+    let area = size.checked_area().map_or(usize::MAX, |area| area as usize);
+    if buffer.len() < area {
+        return Ok(());
+    }
+    let row_bytes = 4 * rectangle.size.width as usize;
+    let max_rows = (conn.maximum_request_bytes() - PUT_IMAGE_HEADER) / row_bytes;
+    let max_rows = max_rows.clamp(1, u16::MAX.into()) as u32;
+    let mut first = 0;
+    while first < rectangle.size.height {
+        let rows = max_rows.min(rectangle.size.height - first);
+        let data = image_data(buffer, size, rectangle, first, rows, target.big_endian);
+        conn.put_image(
+            ImageFormat::Z_PIXMAP,
+            target.drawable,
+            target.gc,
+            rectangle.size.width as u16,
+            rows as u16,
+            rectangle.top_left.x as i16,
+            (rectangle.top_left.y + first as i32) as i16,
+            0,
+            target.depth,
+            &data,
+        )?;
+        first += rows
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use qubes_gui::Coordinates;
+
+    #[test]
+    fn rows() {
+        let size = WindowSize {
+            width: 4,
+            height: 3,
+        };
+        let buffer: Vec<u32> = (0..12).map(|i| 0x0010_0000 * i + i).collect();
+        let rectangle = Rectangle {
+            top_left: Coordinates { x: 1, y: 1 },
+            size: WindowSize {
+                width: 2,
+                height: 2,
+            },
+        };
+        assert_eq!(
+            image_data(&buffer, size, rectangle, 1, 1, false),
+            [9, 0, 0x90, 0, 10, 0, 0xa0, 0]
+        );
+        assert_eq!(
+            image_data(&buffer, size, rectangle, 0, 2, true),
+            [
+                0, 0x50, 0, 5, 0, 0x60, 0, 6, //
+                0, 0x90, 0, 9, 0, 0xa0, 0, 10,
+            ]
+        );
+    }
+}
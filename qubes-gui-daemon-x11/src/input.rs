@@ -0,0 +1,325 @@
+/*
+ * The Qubes OS Project, https://www.qubes-os.org
+ *
+ * Copyright (C) 2021  Demi Marie Obenour  <demi@invisiblethingslab.com>
+ *
+ * This program is free software; you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License
+ * as published by the Free Software Foundation; either version 2
+ * of the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301, USA.
+ *
+ */
+
+//! Turning X11 events into messages for the agent
+
+use qubes_gui::{
+    ButtonEvent, Coordinates, CrossingDetail, CrossingMode, FocusDetail, FocusEvent, KeyEvent,
+    Rectangle, WindowSize,
+};
+use qubes_gui_connection::OutgoingMessage;
+use qubes_gui_daemon_proto::input;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::num::NonZeroU32;
+use x11rb::protocol::xproto::{Atom, Motion, Window};
+use x11rb::protocol::Event;
+
+/// An agent window, as seen by the X server
+#[derive(Debug, Copy, Clone)]
+struct Tracked {
+    window: NonZeroU32,
+    /// Position in root window coordinates
+    position: Coordinates,
+    /// Has a window manager reparented the window?  If so, real (as opposed
+    /// to synthetic) `ConfigureNotify` events are relative to the frame.
+    reparented: bool,
+}
+
+/// Translates X11 events on agent windows
+#[derive(Debug)]
+pub(crate) struct Input {
+    root: Window,
+    wm_protocols: Atom,
+    wm_delete_window: Atom,
+    windows: HashMap<Window, Tracked>,
+}
+
+fn coordinates(x: i16, y: i16) -> Coordinates {
+    Coordinates {
+        x: x.into(),
+        y: y.into(),
+    }
+}
+
+impl Input {
+    pub(crate) fn new(root: Window, wm_protocols: Atom, wm_delete_window: Atom) -> Self {
+        Self {
+            root,
+            wm_protocols,
+            wm_delete_window,
+            windows: HashMap::new(),
+        }
+    }
+
+    /// Start translating events on `x_window`, which shows `window`
+    pub(crate) fn insert(&mut self, x_window: Window, window: NonZeroU32, position: Coordinates) {
+        let tracked = Tracked {
+            window,
+            position,
+            reparented: false,
+        };
+        self.windows.insert(x_window, tracked);
+    }
+
+    /// Stop translating events on `x_window`
+    pub(crate) fn remove(&mut self, x_window: Window) {
+        self.windows.remove(&x_window);
+    }
+
+    /// The agent window that `x_window` shows
+    pub(crate) fn window(&self, x_window: Window) -> Option<NonZeroU32> {
+        self.windows.get(&x_window).map(|tracked| tracked.window)
+    }
+
+    /// The message for the agent caused by `event`, if any.  Events on
+    /// other windows are ignored.
+    pub(crate) fn translate(&mut self, event: &Event) -> Option<(NonZeroU32, OutgoingMessage)> {
+        let (x_window, message) = match event {
+            Event::KeyPress(ev) | Event::KeyRelease(ev) => {
+                let event = match event {
+                    Event::KeyPress(_) => KeyEvent::Press,
+                    _ => KeyEvent::Release,
+                };
+                let at = coordinates(ev.event_x, ev.event_y);
+                let keypress = input::keypress(event, at, u16::from(ev.state).into(), ev.detail);
+                (ev.event, OutgoingMessage::Keypress(keypress))
+            }
+            Event::ButtonPress(ev) | Event::ButtonRelease(ev) => {
+                let event = match event {
+                    Event::ButtonPress(_) => ButtonEvent::Press,
+                    _ => ButtonEvent::Release,
+                };
+                let at = coordinates(ev.event_x, ev.event_y);
+                let state = u16::from(ev.state).into();
+                let button = input::button(event, at, state, ev.detail.into());
+                (ev.event, OutgoingMessage::Button(button))
+            }
+            Event::MotionNotify(ev) => {
+                let at = coordinates(ev.event_x, ev.event_y);
+                let is_hint = ev.detail == Motion::HINT;
+                let motion = input::motion(at, u16::from(ev.state).into(), is_hint);
+                (ev.event, OutgoingMessage::Motion(motion))
+            }
+            Event::EnterNotify(ev) | Event::LeaveNotify(ev) => {
+                let entered = matches!(event, Event::EnterNotify(_));
+                // Crossing events never have other modes or details
+                let mode = CrossingMode::try_from(u32::from(u8::from(ev.mode))).ok()?;
+                let detail = CrossingDetail::try_from(u32::from(u8::from(ev.detail))).ok()?;
+                let crossing = input::crossing(
+                    entered,
+                    coordinates(ev.event_x, ev.event_y),
+                    u16::from(ev.state).into(),
+                    mode,
+                    detail,
+                    ev.same_screen_focus & 1 != 0,
+                );
+                (ev.event, OutgoingMessage::Crossing(crossing))
+            }
+            Event::FocusIn(ev) | Event::FocusOut(ev) => {
+                let event = match event {
+                    Event::FocusIn(_) => FocusEvent::In,
+                    _ => FocusEvent::Out,
+                };
+                let detail = FocusDetail::try_from(u32::from(u8::from(ev.detail))).ok()?;
+                (
+                    ev.event,
+                    OutgoingMessage::Focus(input::focus(event, detail)),
+                )
+            }
+            Event::ReparentNotify(ev) => {
+                if let Some(tracked) = self.windows.get_mut(&ev.window) {
+                    tracked.reparented = ev.parent != self.root;
+                    if !tracked.reparented {
+                        tracked.position = coordinates(ev.x, ev.y)
+                    }
+                }
+                return None;
+            }
+            Event::ConfigureNotify(ev) => {
+                let tracked = self.windows.get_mut(&ev.window)?;
+                let synthetic = ev.response_type & 0x80 != 0;
+                if synthetic || !tracked.reparented {
+                    tracked.position = coordinates(ev.x, ev.y)
+                }
+                let configure = qubes_gui::Configure {
+                    rectangle: Rectangle {
+                        top_left: tracked.position,
+                        size: WindowSize {
+                            width: ev.width.into(),
+                            height: ev.height.into(),
+                        },
+                    },
+                    override_redirect: ev.override_redirect.into(),
+                };
+                (ev.window, OutgoingMessage::Configure(configure))
+            }
+            Event::ClientMessage(ev)
+                if ev.type_ == self.wm_protocols
+                    && ev.format == 32
+                    && ev.data.as_data32()[0] == self.wm_delete_window =>
+            {
+                (ev.window, OutgoingMessage::Close)
+            }
+            _ => return None,
+        };
+        Some((self.window(x_window)?, message))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use x11rb::protocol::xproto::{
+        ClientMessageEvent, ConfigureNotifyEvent, FocusInEvent, KeyButMask, KeyPressEvent,
+        NotifyDetail, NotifyMode, ReparentNotifyEvent,
+    };
+
+    const ROOT: Window = 0x100;
+    const X_WINDOW: Window = 0x200_0001;
+
+    fn input() -> (Input, NonZeroU32) {
+        let mut input = Input::new(ROOT, 10, 11);
+        let window = NonZeroU32::new(5).unwrap();
+        input.insert(X_WINDOW, window, Coordinates { x: 1, y: 2 });
+        (input, window)
+    }
+
+    fn key(window: Window) -> KeyPressEvent {
+        KeyPressEvent {
+            response_type: 2,
+            detail: 38,
+            sequence: 0,
+            time: 0,
+            root: ROOT,
+            event: window,
+            child: 0,
+            root_x: 0,
+            root_y: 0,
+            event_x: 3,
+            event_y: 4,
+            state: KeyButMask::SHIFT,
+            same_screen: true,
+        }
+    }
+
+    fn configure(response_type: u8, x: i16) -> Event {
+        Event::ConfigureNotify(ConfigureNotifyEvent {
+            response_type,
+            sequence: 0,
+            event: X_WINDOW,
+            window: X_WINDOW,
+            above_sibling: 0,
+            x,
+            y: 20,
+            width: 30,
+            height: 40,
+            border_width: 0,
+            override_redirect: false,
+        })
+    }
+
+    #[test]
+    fn keys() {
+        let (mut input, window) = input();
+        let keypress = qubes_gui::Keypress {
+            ty: qubes_gui::EV_KEY_RELEASE,
+            coordinates: Coordinates { x: 3, y: 4 },
+            state: qubes_gui::x11::SHIFT_MASK,
+            keycode: 38,
+        };
+        assert_eq!(
+            input.translate(&Event::KeyRelease(key(X_WINDOW))),
+            Some((window, OutgoingMessage::Keypress(keypress)))
+        );
+        assert_eq!(input.translate(&Event::KeyPress(key(ROOT))), None);
+    }
+
+    #[test]
+    fn focus() {
+        let (mut input, window) = input();
+        let focus = FocusInEvent {
+            response_type: 9,
+            detail: NotifyDetail::NONLINEAR,
+            sequence: 0,
+            event: X_WINDOW,
+            mode: NotifyMode::WHILE_GRABBED,
+        };
+        let expected = qubes_gui::Focus {
+            ty: qubes_gui::EV_FOCUS_IN,
+            mode: 0,
+            detail: 3,
+        };
+        assert_eq!(
+            input.translate(&Event::FocusIn(focus)),
+            Some((window, OutgoingMessage::Focus(expected)))
+        );
+        input.remove(X_WINDOW);
+        assert_eq!(input.translate(&Event::FocusIn(focus)), None);
+    }
+
+    #[test]
+    fn configure_positions() {
+        let (mut input, window) = input();
+        let position = |message: Option<(NonZeroU32, OutgoingMessage)>| match message {
+            Some((w, OutgoingMessage::Configure(configure))) if w == window => {
+                assert_eq!(configure.rectangle.size.width, 30);
+                configure.rectangle.top_left.x
+            }
+            other => panic!("unexpected {:?}", other),
+        };
+        assert_eq!(position(input.translate(&configure(22, 10))), 10);
+        let reparent = ReparentNotifyEvent {
+            response_type: 21,
+            sequence: 0,
+            event: X_WINDOW,
+            window: X_WINDOW,
+            parent: 0x300,
+            x: 0,
+            y: 0,
+            override_redirect: false,
+        };
+        input.translate(&Event::ReparentNotify(reparent));
+        // Relative to the frame, so the last known position is kept
+        assert_eq!(position(input.translate(&configure(22, 5))), 10);
+        // Sent by the window manager, in root coordinates
+        assert_eq!(position(input.translate(&configure(22 | 0x80, 50))), 50);
+    }
+
+    #[test]
+    fn close() {
+        let (mut input, window) = input();
+        let mut message = ClientMessageEvent {
+            response_type: 33,
+            format: 32,
+            sequence: 0,
+            window: X_WINDOW,
+            type_: 10,
+            data: [11, 0, 0, 0, 0].into(),
+        };
+        assert_eq!(
+            input.translate(&Event::ClientMessage(message)),
+            Some((window, OutgoingMessage::Close))
+        );
+        message.data = [12, 0, 0, 0, 0].into();
+        assert_eq!(input.translate(&Event::ClientMessage(message)), None);
+    }
+}
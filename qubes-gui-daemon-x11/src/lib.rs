@@ -0,0 +1,43 @@
+/*
+ * The Qubes OS Project, https://www.qubes-os.org
+ *
+ * Copyright (C) 2021  Demi Marie Obenour  <demi@invisiblethingslab.com>
+ *
+ * This program is free software; you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License
+ * as published by the Free Software Foundation; either version 2
+ * of the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301, USA.
+ *
+ */
+
+#![forbid(missing_docs)]
+#![forbid(clippy::all)]
+//! An X11 backend for GUI daemons
+//!
+//! [`X11Backend`] shows the windows of one agent as real X11 windows, using
+//! `x11rb`.  Messages from the agent are validated with
+//! `qubes-gui-daemon-proto` before anything is done with them.  X11 input
+//! events on those windows are turned into messages for the agent, and the
+//! windows are drawn from the composition buffers the agent shares.
+//!
+//! This is the core of a daemon, not a whole one.  Mapping the grant
+//! references of a composition buffer needs Xen interfaces this crate does
+//! not use, so it is left to a [`GrantMapper`].  Window classes, hints,
+//! flags, cursors, docking, and the clipboard are not handled yet, and the
+//! title is shown without a colored frame.
+
+mod backend;
+mod blit;
+mod input;
+
+pub use backend::{Error, X11Backend};
+pub use qubes_gui_daemon_proto::GrantMapper;
@@ -0,0 +1,82 @@
+/*
+ * The Qubes OS Project, https://www.qubes-os.org
+ *
+ * Copyright (C) 2021  Demi Marie Obenour  <demi@invisiblethingslab.com>
+ *
+ * This program is free software; you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License
+ * as published by the Free Software Foundation; either version 2
+ * of the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301, USA.
+ */
+
+/* C interface to the Rust validation routines in libqubes_gui_ffi.
+ * Include qubes-gui-protocol.h first. */
+
+#ifndef QUBES_GUI_RUST_H
+#define QUBES_GUI_RUST_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+enum qubes_gui_status {
+    QUBES_GUI_OK = 0,
+    QUBES_GUI_UNKNOWN = 1,
+    QUBES_GUI_NULL_POINTER = -1,
+    QUBES_GUI_BAD_LENGTH = -2,
+    QUBES_GUI_UNEXPECTED_MESSAGE = -3,
+    QUBES_GUI_MISSING_WINDOW = -4,
+    QUBES_GUI_BAD_RECTANGLE = -5,
+    QUBES_GUI_BAD_BOOLEAN = -6,
+    QUBES_GUI_BAD_UTF8 = -7,
+    QUBES_GUI_UNTERMINATED = -8,
+    QUBES_GUI_BAD_WINDOW_DUMP = -9,
+};
+
+/* The rectangle members of struct msg_configure and friends */
+struct qubes_gui_rectangle {
+    int32_t x;
+    int32_t y;
+    uint32_t width;
+    uint32_t height;
+};
+
+/* Check the length in a header: QUBES_GUI_OK, QUBES_GUI_UNKNOWN (skip the
+ * body), or QUBES_GUI_BAD_LENGTH. */
+enum qubes_gui_status qubes_gui_validate_length(const struct msg_hdr *header);
+
+/* 1 if the agent (or daemon) may send messages of this type, else 0.
+ * Unknown types are allowed. */
+int32_t qubes_gui_agent_may_send(uint32_t type);
+int32_t qubes_gui_daemon_may_send(uint32_t type);
+
+/* Fully validate a message from an agent.  body_len must equal the length in
+ * the header.  Does not check that the window exists. */
+enum qubes_gui_status qubes_gui_validate_agent_message(const struct msg_hdr *header,
+                                                       const uint8_t *body,
+                                                       size_t body_len);
+
+/* QUBES_GUI_OK if the rectangle is non-empty and not too large, else
+ * QUBES_GUI_BAD_RECTANGLE. */
+enum qubes_gui_status qubes_gui_validate_rectangle(const struct qubes_gui_rectangle *rectangle);
+
+/* Static description of any status value */
+const char *qubes_gui_status_string(int32_t status);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* QUBES_GUI_RUST_H */
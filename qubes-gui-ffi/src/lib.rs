@@ -0,0 +1,325 @@
+/*
+ * The Qubes OS Project, https://www.qubes-os.org
+ *
+ * Copyright (C) 2021  Demi Marie Obenour  <demi@invisiblethingslab.com>
+ *
+ * This program is free software; you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License
+ * as published by the Free Software Foundation; either version 2
+ * of the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301, USA.
+ *
+ */
+
+//! C ABI for the Qubes OS GUI protocol validation routines
+//!
+//! This lets the C GUI daemon use the Rust validation code at its trust
+//! boundary.  The declarations are in `include/qubes-gui-rust.h`.  Every
+//! function checks its pointer arguments for NULL, and none of them panic on
+//! any input.
+
+#![forbid(missing_docs)]
+#![forbid(clippy::all)]
+
+use qubes_gui::{Rectangle, UntrustedHeader, ValidRectangle};
+use qubes_gui_daemon_proto::{Error, MessageVisitor};
+
+/// Result of a validation function
+#[repr(C)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum QubesGuiStatus {
+    /// The message is valid
+    Ok = 0,
+    /// The message type is not known.  The body may be skipped.
+    Unknown = 1,
+    /// A pointer argument was NULL
+    NullPointer = -1,
+    /// The length of the message is wrong for its type
+    BadLength = -2,
+    /// The message may not be sent in this direction, or is not supported
+    UnexpectedMessage = -3,
+    /// A message that must be sent to a window was sent to the whole screen
+    MissingWindow = -4,
+    /// A rectangle was empty or too large
+    BadRectangle = -5,
+    /// A boolean field was neither 0 nor 1
+    BadBoolean = -6,
+    /// A string was not valid UTF-8
+    BadUtf8 = -7,
+    /// A string was not NUL-terminated
+    Unterminated = -8,
+    /// A window dump had an unsupported type or depth
+    BadWindowDump = -9,
+}
+
+impl From<Error> for QubesGuiStatus {
+    fn from(e: Error) -> Self {
+        match e {
+            Error::MissingWindow { .. } => Self::MissingWindow,
+            Error::UnexpectedMessage { .. } => Self::UnexpectedMessage,
+            Error::BadRectangle(_) => Self::BadRectangle,
+            Error::BadBoolean(_) => Self::BadBoolean,
+            Error::BadUTF8(_) => Self::BadUtf8,
+            Error::Unterminated => Self::Unterminated,
+            Error::BadWindowDump { .. } => Self::BadWindowDump,
+            Error::BadMfnDump { .. } => Self::BadLength,
+            _ => Self::UnexpectedMessage,
+        }
+    }
+}
+
+/// Check the length of the message with header `header`.
+///
+/// Returns [`QubesGuiStatus::Ok`] if the length is valid for the message
+/// type, [`QubesGuiStatus::Unknown`] if the type is unknown, and
+/// [`QubesGuiStatus::BadLength`] otherwise.
+///
+/// # Safety
+///
+/// `header` must be NULL or point to a readable `struct msg_hdr`.
+#[no_mangle]
+pub unsafe extern "C" fn qubes_gui_validate_length(
+    header: *const UntrustedHeader,
+) -> QubesGuiStatus {
+    match header.as_ref() {
+        None => QubesGuiStatus::NullPointer,
+        Some(header) => match header.validate_length() {
+            Ok(Some(_)) => QubesGuiStatus::Ok,
+            Ok(None) => QubesGuiStatus::Unknown,
+            Err(_) => QubesGuiStatus::BadLength,
+        },
+    }
+}
+
+/// Returns 1 if an agent may send messages of type `ty`, and 0 otherwise.
+/// Unknown types are allowed.
+#[no_mangle]
+pub extern "C" fn qubes_gui_agent_may_send(ty: u32) -> i32 {
+    qubes_gui::agent_may_send(ty).into()
+}
+
+/// Returns 1 if a daemon may send messages of type `ty`, and 0 otherwise.
+/// Unknown types are allowed.
+#[no_mangle]
+pub extern "C" fn qubes_gui_daemon_may_send(ty: u32) -> i32 {
+    qubes_gui::daemon_may_send(ty).into()
+}
+
+/// Visitor that accepts everything that passes validation
+struct Accept;
+
+impl MessageVisitor for Accept {}
+
+/// Validate a message from an agent: its length, its direction, and every
+/// field of its body.  Whether the window exists is not checked.
+///
+/// Returns [`QubesGuiStatus::Ok`] if the message is valid,
+/// [`QubesGuiStatus::Unknown`] if its type is unknown, and an error
+/// otherwise.  `body_len` must be the length in `header`, or
+/// [`QubesGuiStatus::BadLength`] is returned.
+///
+/// # Safety
+///
+/// `header` must be NULL or point to a readable `struct msg_hdr`.  `body`
+/// must be NULL or point to `body_len` readable bytes.  `body` may be NULL if
+/// `body_len` is 0.
+#[no_mangle]
+pub unsafe extern "C" fn qubes_gui_validate_agent_message(
+    header: *const UntrustedHeader,
+    body: *const u8,
+    body_len: usize,
+) -> QubesGuiStatus {
+    let header = match header.as_ref() {
+        None => return QubesGuiStatus::NullPointer,
+        Some(header) => header,
+    };
+    let body = match (body.is_null(), body_len) {
+        (_, 0) => &[][..],
+        (true, _) => return QubesGuiStatus::NullPointer,
+        (false, len) => core::slice::from_raw_parts(body, len),
+    };
+    let header = match header.validate_length() {
+        Err(_) => return QubesGuiStatus::BadLength,
+        Ok(None) => return QubesGuiStatus::Unknown,
+        Ok(Some(header)) => header,
+    };
+    if header.len() != body.len() {
+        QubesGuiStatus::BadLength
+    } else if !qubes_gui::agent_may_send(header.ty()) {
+        QubesGuiStatus::UnexpectedMessage
+    } else {
+        match qubes_gui_daemon_proto::visit(&mut Accept, header, body) {
+            Ok(()) => QubesGuiStatus::Ok,
+            Err(e) => e.into(),
+        }
+    }
+}
+
+/// Validate a rectangle: it must be non-empty and no larger than the maximum
+/// window size.  Returns [`QubesGuiStatus::Ok`] or
+/// [`QubesGuiStatus::BadRectangle`].
+///
+/// # Safety
+///
+/// `rectangle` must be NULL or point to a readable rectangle, laid out as
+/// the `x`, `y`, `width` and `height` members of `struct msg_configure`.
+#[no_mangle]
+pub unsafe extern "C" fn qubes_gui_validate_rectangle(
+    rectangle: *const Rectangle,
+) -> QubesGuiStatus {
+    match rectangle.as_ref() {
+        None => QubesGuiStatus::NullPointer,
+        Some(&rectangle) => match ValidRectangle::new(rectangle) {
+            Ok(_) => QubesGuiStatus::Ok,
+            Err(_) => QubesGuiStatus::BadRectangle,
+        },
+    }
+}
+
+impl QubesGuiStatus {
+    const ALL: [Self; 11] = [
+        Self::Ok,
+        Self::Unknown,
+        Self::NullPointer,
+        Self::BadLength,
+        Self::UnexpectedMessage,
+        Self::MissingWindow,
+        Self::BadRectangle,
+        Self::BadBoolean,
+        Self::BadUtf8,
+        Self::Unterminated,
+        Self::BadWindowDump,
+    ];
+
+    /// A NUL-terminated description of the status
+    fn description(self) -> &'static [u8] {
+        match self {
+            Self::Ok => b"valid\0",
+            Self::Unknown => b"unknown message type\0",
+            Self::NullPointer => b"NULL pointer\0",
+            Self::BadLength => b"bad message length\0",
+            Self::UnexpectedMessage => b"message not allowed in this direction\0",
+            Self::MissingWindow => b"message needs a window\0",
+            Self::BadRectangle => b"rectangle empty or too large\0",
+            Self::BadBoolean => b"boolean neither 0 nor 1\0",
+            Self::BadUtf8 => b"string not valid UTF-8\0",
+            Self::Unterminated => b"string not NUL-terminated\0",
+            Self::BadWindowDump => b"unsupported window dump\0",
+        }
+    }
+}
+
+/// A static, NUL-terminated description of `status`, which may be any
+/// integer
+#[no_mangle]
+pub extern "C" fn qubes_gui_status_string(status: i32) -> *const u8 {
+    let description = QubesGuiStatus::ALL
+        .iter()
+        .find(|&&s| s as i32 == status)
+        .map_or(&b"invalid status\0"[..], |s| s.description());
+    description.as_ptr()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use qubes_castable::Castable;
+    use std::ffi::CStr;
+
+    fn header(ty: u32, window: u32, len: usize) -> UntrustedHeader {
+        UntrustedHeader {
+            ty,
+            window: window.into(),
+            untrusted_len: len as u32,
+        }
+    }
+
+    fn validate(header: &UntrustedHeader, body: &[u8]) -> QubesGuiStatus {
+        unsafe { qubes_gui_validate_agent_message(header, body.as_ptr(), body.len()) }
+    }
+
+    #[test]
+    fn lengths() {
+        let good = header(qubes_gui::MSG_DESTROY, 1, 0);
+        let bad = header(qubes_gui::MSG_DESTROY, 1, 4);
+        let unknown = header(0x7fff_0000, 1, 100);
+        unsafe {
+            assert_eq!(qubes_gui_validate_length(&good), QubesGuiStatus::Ok);
+            assert_eq!(qubes_gui_validate_length(&bad), QubesGuiStatus::BadLength);
+            assert_eq!(qubes_gui_validate_length(&unknown), QubesGuiStatus::Unknown);
+            assert_eq!(
+                qubes_gui_validate_length(core::ptr::null()),
+                QubesGuiStatus::NullPointer
+            );
+        }
+    }
+
+    #[test]
+    fn directions() {
+        assert_eq!(qubes_gui_agent_may_send(qubes_gui::MSG_CREATE), 1);
+        assert_eq!(qubes_gui_agent_may_send(qubes_gui::MSG_KEYPRESS), 0);
+        assert_eq!(qubes_gui_daemon_may_send(qubes_gui::MSG_KEYPRESS), 1);
+        assert_eq!(qubes_gui_daemon_may_send(qubes_gui::MSG_CREATE), 0);
+    }
+
+    #[test]
+    fn agent_messages() {
+        let mut create = qubes_gui::Create::default();
+        create.rectangle.size = qubes_gui::WindowSize {
+            width: 10,
+            height: 10,
+        };
+        let len = create.as_bytes().len();
+        let hdr = header(qubes_gui::MSG_CREATE, 1, len);
+        assert_eq!(validate(&hdr, create.as_bytes()), QubesGuiStatus::Ok);
+        assert_eq!(
+            validate(&hdr, &create.as_bytes()[1..]),
+            QubesGuiStatus::BadLength
+        );
+        let screen = header(qubes_gui::MSG_CREATE, 0, len);
+        assert_eq!(
+            validate(&screen, create.as_bytes()),
+            QubesGuiStatus::MissingWindow
+        );
+        create.override_redirect = 2;
+        assert_eq!(
+            validate(&hdr, create.as_bytes()),
+            QubesGuiStatus::BadBoolean
+        );
+        let mut image = qubes_gui::ShmImage::default();
+        image.rectangle.size.width = -1i32 as u32;
+        image.rectangle.size.height = 10;
+        let hdr = header(qubes_gui::MSG_SHMIMAGE, 1, image.as_bytes().len());
+        assert_eq!(
+            validate(&hdr, image.as_bytes()),
+            QubesGuiStatus::BadRectangle
+        );
+        let keypress = qubes_gui::Keypress::default();
+        let hdr = header(qubes_gui::MSG_KEYPRESS, 1, keypress.as_bytes().len());
+        assert_eq!(
+            validate(&hdr, keypress.as_bytes()),
+            QubesGuiStatus::UnexpectedMessage
+        );
+        let hdr = header(qubes_gui::MSG_DESTROY, 1, 0);
+        let status = unsafe { qubes_gui_validate_agent_message(&hdr, core::ptr::null(), 0) };
+        assert_eq!(status, QubesGuiStatus::Ok);
+    }
+
+    #[test]
+    fn status_strings() {
+        for &status in &QubesGuiStatus::ALL {
+            let s = unsafe { CStr::from_ptr(qubes_gui_status_string(status as i32).cast()) };
+            assert!(!s.to_bytes().is_empty());
+        }
+        let s = unsafe { CStr::from_ptr(qubes_gui_status_string(1000).cast()) };
+        assert_eq!(s.to_bytes(), b"invalid status");
+    }
+}
@@ -0,0 +1,460 @@
+/*
+ * The Qubes OS Project, https://www.qubes-os.org
+ *
+ * Copyright (C) 2021  Demi Marie Obenour  <demi@invisiblethingslab.com>
+ *
+ * This program is free software; you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License
+ * as published by the Free Software Foundation; either version 2
+ * of the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301, USA.
+ *
+ */
+
+//! Python bindings for scripting protocol tests
+//!
+//! This builds a Python extension module named `qubes_gui`; use `maturin`
+//! to build and install it.  It can build
+//! and take apart any message struct, split a byte stream into messages,
+//! read captures, and connect scripts through in-memory transports:
+//!
+//! ```python
+//! import qubes_gui as q
+//! body = q.pack("Create", {"rectangle": {"size": {"width": 640, "height": 480}}})
+//! data = q.encode(q.MSG_CREATE, 5, body)
+//! [item] = q.parse(data, "agent")
+//! assert q.unpack("Create", item["body"])["rectangle"]["size"]["width"] == 640
+//! ```
+//!
+//! Structs are represented as dictionaries keyed by the Rust field names.
+//! Missing fields are zero, optional window IDs are `None` or an integer, and
+//! byte arrays are `bytes`.
+//!
+//! The tests in `tests/` use the installed module: run `maturin develop`,
+//! then `python -m unittest discover tests`.
+
+#![forbid(missing_docs)]
+// The code generated by pyo3 converts results that are already `PyErr`.
+#![allow(clippy::useless_conversion)]
+
+use pyo3::exceptions::{PyKeyError, PyOSError, PyValueError};
+use pyo3::prelude::*;
+use pyo3::types::{PyBytes, PyDict};
+use qubes_castable::{Castable, Field, Scalar};
+use qubes_gui_connection::decode::{self, Item, Peer, Reassembler};
+use qubes_gui_connection::{CaptureReader, LoopbackTransport, Transport};
+use std::convert::TryFrom;
+use std::mem::size_of;
+use vchan::Status;
+
+/// The layout of a struct that Python code can pack and unpack
+struct Layout {
+    name: &'static str,
+    size: usize,
+    fields: &'static [Field],
+}
+
+macro_rules! layouts {
+    ($($t: ident),+$(,)?) => {
+        &[$(Layout {
+            name: stringify!($t),
+            size: size_of::<qubes_gui::$t>(),
+            fields: <qubes_gui::$t as Castable>::FIELDS,
+        }),+]
+    };
+}
+
+const LAYOUTS: &[Layout] = layouts![
+    UntrustedHeader,
+    Coordinates,
+    WindowSize,
+    Rectangle,
+    XConf,
+    XConfVersion,
+    MapInfo,
+    Create,
+    Keypress,
+    Button,
+    Motion,
+    Crossing,
+    Configure,
+    ShmImage,
+    Focus,
+    WMName,
+    Unmap,
+    Dock,
+    Destroy,
+    KeymapNotify,
+    WindowHints,
+    WindowFlags,
+    ShmCmd,
+    WMClass,
+    WindowDumpHeader,
+    Cursor,
+    DumpAck,
+];
+
+fn layout(name: &str) -> PyResult<&'static Layout> {
+    LAYOUTS
+        .iter()
+        .find(|l| l.name == name)
+        .ok_or_else(|| PyKeyError::new_err(format!("no struct named {}", name)))
+}
+
+fn peer(name: &str) -> PyResult<Peer> {
+    match name {
+        "agent" => Ok(Peer::Agent),
+        "daemon" => Ok(Peer::Daemon),
+        _ => Err(PyValueError::new_err(
+            "peer must be \"agent\" or \"daemon\"",
+        )),
+    }
+}
+
+/// Write `value`, a dictionary or `None`, into `out` according to `fields`
+fn pack_fields(
+    fields: &[Field],
+    value: Option<&Bound<'_, PyDict>>,
+    out: &mut [u8],
+) -> PyResult<()> {
+    if let Some(value) = value {
+        for key in value.keys() {
+            let key: String = key.extract()?;
+            if !fields.iter().any(|f| f.name == key) {
+                return Err(PyKeyError::new_err(format!("no field named {}", key)));
+            }
+        }
+    }
+    let mut offset = 0;
+    for field in fields {
+        let out = &mut out[offset..offset + field.size];
+        offset += field.size;
+        let item = match value {
+            Some(value) => value.get_item(field.name)?,
+            None => None,
+        };
+        if !field.fields.is_empty() {
+            let nested = item.map(|i| i.downcast_into::<PyDict>()).transpose()?;
+            pack_fields(field.fields, nested.as_ref(), out)?;
+            continue;
+        }
+        let item = match item {
+            Some(item) if !item.is_none() => item,
+            _ => continue,
+        };
+        if let Scalar::Bytes = field.scalar {
+            let bytes: Vec<u8> = match item.extract::<String>() {
+                Ok(s) => s.into_bytes(),
+                Err(_) => item.extract()?,
+            };
+            if bytes.len() > out.len() {
+                return Err(PyValueError::new_err(format!(
+                    "{} is at most {} bytes",
+                    field.name,
+                    out.len()
+                )));
+            }
+            out[..bytes.len()].copy_from_slice(&bytes)
+        } else {
+            pack_int(field, &item, out)?
+        }
+    }
+    Ok(())
+}
+
+fn write<'py, T: Castable + FromPyObject<'py>>(
+    item: &Bound<'py, PyAny>,
+    out: &mut [u8],
+) -> PyResult<()> {
+    out.copy_from_slice(item.extract::<T>()?.as_bytes());
+    Ok(())
+}
+
+fn read<T: Castable + IntoPy<PyObject>>(py: Python<'_>, data: &[u8]) -> PyObject {
+    T::from_bytes(data).into_py(py)
+}
+
+fn unsupported(field: &Field) -> PyErr {
+    PyValueError::new_err(format!(
+        "field {} of type {} is not supported",
+        field.name, field.ty
+    ))
+}
+
+/// Write the integer `item` into the leaf field `field`.  `None` has already
+/// been written as 0, so optional integers are written like the others.
+fn pack_int(field: &Field, item: &Bound<'_, PyAny>, out: &mut [u8]) -> PyResult<()> {
+    use Scalar::*;
+    match (field.scalar, field.size) {
+        (Unsigned | OptionalUnsigned, 1) => write::<u8>(item, out),
+        (Unsigned | OptionalUnsigned, 2) => write::<u16>(item, out),
+        (Unsigned | OptionalUnsigned, 4) => write::<u32>(item, out),
+        (Unsigned | OptionalUnsigned, 8) => write::<u64>(item, out),
+        (Signed | OptionalSigned, 1) => write::<i8>(item, out),
+        (Signed | OptionalSigned, 2) => write::<i16>(item, out),
+        (Signed | OptionalSigned, 4) => write::<i32>(item, out),
+        (Signed | OptionalSigned, 8) => write::<i64>(item, out),
+        _ => Err(unsupported(field)),
+    }
+}
+
+/// Read the leaf field `field` from `data`.  Optional integers that are 0
+/// are `None`.
+fn unpack_scalar(py: Python<'_>, field: &Field, data: &[u8]) -> PyResult<PyObject> {
+    use Scalar::*;
+    if let OptionalUnsigned | OptionalSigned = field.scalar {
+        if data.iter().all(|&b| b == 0) {
+            return Ok(py.None());
+        }
+    }
+    Ok(match (field.scalar, field.size) {
+        (Bytes, _) => PyBytes::new_bound(py, data).into_any().unbind(),
+        (Unsigned | OptionalUnsigned, 1) => read::<u8>(py, data),
+        (Unsigned | OptionalUnsigned, 2) => read::<u16>(py, data),
+        (Unsigned | OptionalUnsigned, 4) => read::<u32>(py, data),
+        (Unsigned | OptionalUnsigned, 8) => read::<u64>(py, data),
+        (Signed | OptionalSigned, 1) => read::<i8>(py, data),
+        (Signed | OptionalSigned, 2) => read::<i16>(py, data),
+        (Signed | OptionalSigned, 4) => read::<i32>(py, data),
+        (Signed | OptionalSigned, 8) => read::<i64>(py, data),
+        _ => return Err(unsupported(field)),
+    })
+}
+
+/// Read `data` into a dictionary according to `fields`
+fn unpack_fields<'py>(
+    py: Python<'py>,
+    fields: &[Field],
+    data: &[u8],
+) -> PyResult<Bound<'py, PyDict>> {
+    let dict = PyDict::new_bound(py);
+    let mut offset = 0;
+    for field in fields {
+        let data = &data[offset..offset + field.size];
+        offset += field.size;
+        if !field.fields.is_empty() {
+            dict.set_item(field.name, unpack_fields(py, field.fields, data)?)?;
+            continue;
+        }
+        dict.set_item(field.name, unpack_scalar(py, field, data)?)?
+    }
+    Ok(dict)
+}
+
+/// pack(name, fields=None)
+/// --
+///
+/// Build the struct `name` (such as "Create") from a dictionary of fields
+#[pyfunction]
+#[pyo3(signature = (name, fields=None))]
+fn pack<'py>(
+    py: Python<'py>,
+    name: &str,
+    fields: Option<&Bound<'py, PyDict>>,
+) -> PyResult<Bound<'py, PyBytes>> {
+    let layout = layout(name)?;
+    let mut out = vec![0; layout.size];
+    pack_fields(layout.fields, fields, &mut out)?;
+    Ok(PyBytes::new_bound(py, &out))
+}
+
+/// Take apart the struct `name` (such as "Create") into a dictionary
+#[pyfunction]
+fn unpack<'py>(py: Python<'py>, name: &str, data: &[u8]) -> PyResult<Bound<'py, PyDict>> {
+    let layout = layout(name)?;
+    if data.len() != layout.size {
+        return Err(PyValueError::new_err(format!(
+            "{} is {} bytes, not {}",
+            name,
+            layout.size,
+            data.len()
+        )));
+    }
+    unpack_fields(py, layout.fields, data)
+}
+
+/// Prefix `body` with a header for a message of type `ty` sent to `window`.
+/// The length is not checked, so this can build invalid messages.
+#[pyfunction]
+fn encode<'py>(
+    py: Python<'py>,
+    ty: u32,
+    window: u32,
+    body: &[u8],
+) -> PyResult<Bound<'py, PyBytes>> {
+    let len = u32::try_from(body.len()).map_err(|_| PyValueError::new_err("body too long"))?;
+    let header = qubes_gui::UntrustedHeader {
+        ty,
+        window: window.into(),
+        untrusted_len: len,
+    };
+    Ok(PyBytes::new_bound(py, &[header.as_bytes(), body].concat()))
+}
+
+fn item_dict<'py>(py: Python<'py>, item: &Item<'_>) -> PyResult<Bound<'py, PyDict>> {
+    let dict = PyDict::new_bound(py);
+    match *item {
+        Item::Handshake { version, xconf } => {
+            dict.set_item("kind", "handshake")?;
+            dict.set_item("version", version)?;
+            if let Some(xconf) = xconf {
+                dict.set_item(
+                    "xconf",
+                    unpack_fields(py, qubes_gui::XConf::FIELDS, xconf.as_bytes())?,
+                )?;
+            }
+        }
+        Item::Message { header, body } => {
+            dict.set_item("kind", "message")?;
+            dict.set_item("type", header.ty())?;
+            dict.set_item("name", decode::message_name(header.ty()))?;
+            dict.set_item(
+                "window",
+                header.untrusted_window().window.map_or(0, |w| w.get()),
+            )?;
+            dict.set_item("body", PyBytes::new_bound(py, body))?;
+        }
+        Item::Unknown(header) => {
+            dict.set_item("kind", "unknown")?;
+            dict.set_item("type", header.ty)?;
+            dict.set_item("window", header.window.window.map_or(0, |w| w.get()))?;
+            dict.set_item("length", header.untrusted_len)?;
+        }
+        Item::Violation(_) => dict.set_item("kind", "violation")?,
+    }
+    dict.set_item("description", item.describe(true))?;
+    Ok(dict)
+}
+
+/// parse(data, peer)
+/// --
+///
+/// Split `data`, everything sent by `peer` ("agent" or "daemon") from the
+/// start of the connection, into a list of dictionaries: the handshake,
+/// messages, skipped unknown messages, and protocol violations
+#[pyfunction]
+fn parse<'py>(py: Python<'py>, data: &[u8], peer: &str) -> PyResult<Vec<Bound<'py, PyDict>>> {
+    let mut reassembler = Reassembler::new(self::peer(peer)?);
+    let mut items = vec![];
+    let mut res = Ok(());
+    reassembler.feed(data, |item| {
+        if res.is_ok() {
+            res = item_dict(py, &item).map(|d| items.push(d))
+        }
+    });
+    res.map(|()| items)
+}
+
+/// Read a capture file into a list of `(direction, seconds, data)` tuples,
+/// where `direction` is "sent" or "received"
+#[pyfunction]
+fn read_capture(py: Python<'_>, path: &str) -> PyResult<Vec<(&'static str, f64, Py<PyBytes>)>> {
+    let file = std::io::BufReader::new(std::fs::File::open(path)?);
+    let reader = CaptureReader::new(file)?;
+    reader
+        .map(|record| {
+            let record = record.map_err(PyOSError::new_err)?;
+            let direction = match record.direction {
+                qubes_gui_connection::capture_file::Direction::Sent => "sent",
+                qubes_gui_connection::capture_file::Direction::Received => "received",
+            };
+            Ok((
+                direction,
+                record.timestamp.as_secs_f64(),
+                PyBytes::new_bound(py, &record.data).unbind(),
+            ))
+        })
+        .collect()
+}
+
+/// One end of an in-memory connection.  Create two with `pair()`.
+#[pyclass(name = "LoopbackTransport")]
+struct PyLoopback(Option<LoopbackTransport>);
+
+impl PyLoopback {
+    fn get(&mut self) -> PyResult<&mut LoopbackTransport> {
+        self.0
+            .as_mut()
+            .ok_or_else(|| PyValueError::new_err("transport is closed"))
+    }
+}
+
+#[pymethods]
+impl PyLoopback {
+    /// Create two connected ends
+    #[staticmethod]
+    fn pair() -> (Self, Self) {
+        let (a, b) = LoopbackTransport::pair();
+        (Self(Some(a)), Self(Some(b)))
+    }
+
+    /// Send all of `data`, which must fit in `buffer_space()`
+    fn send(&mut self, data: &[u8]) -> PyResult<()> {
+        let res = self.get()?.send(data);
+        Ok(res.map_err(std::io::Error::from)?)
+    }
+
+    /// Receive exactly `len` bytes, which must not exceed `data_ready()`
+    fn recv<'py>(&mut self, py: Python<'py>, len: usize) -> PyResult<Bound<'py, PyBytes>> {
+        let transport = self.get()?;
+        let ready = transport.data_ready();
+        if len > ready {
+            return Err(PyValueError::new_err(format!(
+                "cannot receive {} bytes: only {} are ready",
+                len, ready
+            )));
+        }
+        let mut buffer = vec![0; len];
+        let res = transport.recv(&mut buffer);
+        res.map_err(std::io::Error::from)?;
+        Ok(PyBytes::new_bound(py, &buffer))
+    }
+
+    /// The number of bytes that can be received
+    fn data_ready(&mut self) -> PyResult<usize> {
+        Ok(self.get()?.data_ready())
+    }
+
+    /// The number of bytes that can be sent
+    fn buffer_space(&mut self) -> PyResult<usize> {
+        Ok(self.get()?.buffer_space())
+    }
+
+    /// Whether the other end is still open, or there is data left to read
+    fn connected(&mut self) -> PyResult<bool> {
+        Ok(self.get()?.status() == Status::Connected)
+    }
+
+    /// Close this end
+    fn close(&mut self) {
+        self.0 = None
+    }
+}
+
+/// Build and parse Qubes OS GUI protocol messages
+#[pymodule]
+#[pyo3(name = "qubes_gui")]
+fn qubes_gui_module(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add("PROTOCOL_VERSION", qubes_gui::PROTOCOL_VERSION)?;
+    for &msg in qubes_gui::Msg::ALL {
+        m.add(format!("MSG_{}", msg).as_str(), msg as u32)?
+    }
+    m.add("MAX_CLIPBOARD_SIZE", qubes_gui::MAX_CLIPBOARD_SIZE)?;
+    m.add("MAX_WINDOW_WIDTH", qubes_gui::MAX_WINDOW_WIDTH)?;
+    m.add("MAX_WINDOW_HEIGHT", qubes_gui::MAX_WINDOW_HEIGHT)?;
+    m.add_function(wrap_pyfunction!(pack, m)?)?;
+    m.add_function(wrap_pyfunction!(unpack, m)?)?;
+    m.add_function(wrap_pyfunction!(encode, m)?)?;
+    m.add_function(wrap_pyfunction!(parse, m)?)?;
+    m.add_function(wrap_pyfunction!(read_capture, m)?)?;
+    m.add_class::<PyLoopback>()?;
+    Ok(())
+}
@@ -0,0 +1,153 @@
+# The Qubes OS Project, https://www.qubes-os.org
+#
+# Copyright (C) 2021  Demi Marie Obenour  <demi@invisiblethingslab.com>
+#
+# This program is free software; you can redistribute it and/or
+# modify it under the terms of the GNU General Public License
+# as published by the Free Software Foundation; either version 2
+# of the License, or (at your option) any later version.
+#
+# This program is distributed in the hope that it will be useful,
+# but WITHOUT ANY WARRANTY; without even the implied warranty of
+# MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+# GNU General Public License for more details.
+#
+# You should have received a copy of the GNU General Public License
+# along with this program; if not, write to the Free Software
+# Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301, USA.
+
+"""Tests of the qubes_gui extension module.  Build and install it with
+`maturin develop`, then run `python -m unittest discover tests`."""
+
+import os
+import struct
+import tempfile
+import unittest
+
+import qubes_gui as q
+
+
+class PackTest(unittest.TestCase):
+    def test_round_trip(self):
+        fields = {
+            "rectangle": {
+                "top_left": {"x": -5, "y": 7},
+                "size": {"width": 640, "height": 480},
+            },
+            "parent": 3,
+            "override_redirect": 1,
+        }
+        body = q.pack("Create", fields)
+        self.assertEqual(body, struct.pack("=iiIIII", -5, 7, 640, 480, 3, 1))
+        self.assertEqual(q.unpack("Create", body), fields)
+
+    def test_missing_fields_are_zero(self):
+        body = q.pack("Create")
+        self.assertEqual(body, bytes(24))
+        unpacked = q.unpack("Create", body)
+        self.assertIsNone(unpacked["parent"])
+        self.assertEqual(unpacked["rectangle"]["top_left"], {"x": 0, "y": 0})
+
+    def test_bytes(self):
+        body = q.pack("WMName", {"data": "title"})
+        self.assertEqual(body, b"title" + bytes(123))
+        self.assertEqual(q.unpack("WMName", body)["data"], body)
+        class_ = q.pack("WMClass", {"res_class": b"a", "res_name": b"b"})
+        self.assertEqual(class_[:1] + class_[64:65], b"ab")
+        with self.assertRaises(ValueError):
+            q.pack("WMName", {"data": bytes(129)})
+
+    def test_bad_values(self):
+        with self.assertRaises(OverflowError):
+            q.pack("Cursor", {"cursor": 1 << 32})
+        with self.assertRaises(OverflowError):
+            q.pack("Coordinates", {"x": -(1 << 31) - 1})
+        with self.assertRaises(OverflowError):
+            q.pack("Cursor", {"cursor": -1})
+        with self.assertRaises(TypeError):
+            q.pack("Cursor", {"cursor": "default"})
+
+    def test_bad_names(self):
+        with self.assertRaises(KeyError):
+            q.pack("NoSuchStruct")
+        with self.assertRaises(KeyError):
+            q.pack("Cursor", {"no_such_field": 1})
+        with self.assertRaises(ValueError):
+            q.unpack("Cursor", bytes(3))
+
+
+class ParseTest(unittest.TestCase):
+    def handshake(self):
+        return struct.pack("<I", q.PROTOCOL_VERSION)
+
+    def test_messages(self):
+        body = q.pack("Create", {"rectangle": {"size": {"width": 1, "height": 2}}})
+        data = self.handshake() + q.encode(q.MSG_CREATE, 5, body)
+        data += q.encode(q.MSG_MAP, 5, q.pack("MapInfo"))
+        handshake, create, map_ = q.parse(data, "agent")
+        self.assertEqual(handshake["kind"], "handshake")
+        self.assertEqual(handshake["version"], q.PROTOCOL_VERSION)
+        self.assertEqual(create["kind"], "message")
+        self.assertEqual(create["type"], q.MSG_CREATE)
+        self.assertEqual(create["window"], 5)
+        self.assertEqual(create["body"], body)
+        self.assertEqual(map_["type"], q.MSG_MAP)
+
+    def test_unknown_and_violation(self):
+        data = self.handshake() + q.encode(0x7FFF0000, 0, b"xx")
+        data += q.encode(q.MSG_CREATE, 5, b"short")
+        kinds = [item["kind"] for item in q.parse(data, "agent")]
+        self.assertEqual(kinds, ["handshake", "unknown", "violation"])
+
+    def test_bad_peer(self):
+        with self.assertRaises(ValueError):
+            q.parse(b"", "neither")
+
+
+class CaptureTest(unittest.TestCase):
+    def test_read_capture(self):
+        with tempfile.TemporaryDirectory() as dir:
+            path = os.path.join(dir, "capture")
+            with open(path, "wb") as f:
+                f.write(b"QGUICAP\0" + struct.pack("<I", 1))
+                f.write(struct.pack("<BQI", 0, 1500000, 2) + b"hi")
+                f.write(struct.pack("<BQI", 1, 2000000, 0))
+            self.assertEqual(
+                q.read_capture(path),
+                [("sent", 1.5, b"hi"), ("received", 2.0, b"")],
+            )
+            with open(path, "wb") as f:
+                f.write(b"not a capture")
+            with self.assertRaises(OSError):
+                q.read_capture(path)
+
+
+class LoopbackTest(unittest.TestCase):
+    def test_send_and_recv(self):
+        a, b = q.LoopbackTransport.pair()
+        a.send(b"hello")
+        self.assertEqual(b.data_ready(), 5)
+        self.assertEqual(b.recv(2), b"he")
+        self.assertEqual(b.recv(3), b"llo")
+        self.assertEqual(b.data_ready(), 0)
+        self.assertTrue(a.connected())
+
+    def test_recv_is_bounded(self):
+        a, b = q.LoopbackTransport.pair()
+        a.send(b"hi")
+        with self.assertRaises(ValueError):
+            b.recv(3)
+        with self.assertRaises(ValueError):
+            b.recv(1 << 62)
+        self.assertEqual(b.recv(2), b"hi")
+
+    def test_close(self):
+        a, b = q.LoopbackTransport.pair()
+        a.close()
+        with self.assertRaises(ValueError):
+            a.send(b"x")
+        self.assertFalse(b.connected())
+
+
+if __name__ == "__main__":
+    unittest.main()
@@ -0,0 +1,567 @@
+/*
+ * The Qubes OS Project, https://www.qubes-os.org
+ *
+ * Copyright (C) 2021  Demi Marie Obenour  <demi@invisiblethingslab.com>
+ *
+ * This program is free software; you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License
+ * as published by the Free Software Foundation; either version 2
+ * of the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301, USA.
+ *
+ */
+
+//! The compositor and its core protocol objects
+
+use crate::input::Inbox;
+use crate::shm::BufferData;
+use qubes_gui::{Coordinates, Rectangle, WindowSize};
+use qubes_gui_agent_proto::Modifiers;
+use qubes_gui_connection::{surface::Surface, Agent};
+use rustix::event::{PollFd, PollFlags};
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryFrom;
+use std::ffi::OsStr;
+use std::io;
+use std::num::NonZeroU32;
+use std::os::unix::io::{AsFd as _, AsRawFd as _, BorrowedFd};
+use std::os::unix::net::UnixStream;
+use std::sync::Arc;
+use std::time::Instant;
+use wayland_protocols::xdg::shell::server::xdg_wm_base::XdgWmBase;
+use wayland_protocols::xdg::shell::server::{xdg_surface::XdgSurface, xdg_toplevel::XdgToplevel};
+use wayland_server::backend::{ClientData, ObjectId};
+use wayland_server::protocol::wl_buffer::WlBuffer;
+use wayland_server::protocol::wl_callback::{self, WlCallback};
+use wayland_server::protocol::wl_compositor::{self, WlCompositor};
+use wayland_server::protocol::wl_keyboard::WlKeyboard;
+use wayland_server::protocol::wl_pointer::WlPointer;
+use wayland_server::protocol::wl_region::{self, WlRegion};
+use wayland_server::protocol::wl_seat::WlSeat;
+use wayland_server::protocol::wl_shm::WlShm;
+use wayland_server::protocol::wl_surface::{self, WlSurface};
+use wayland_server::{
+    Client, DataInit, Dispatch, Display, DisplayHandle, GlobalDispatch, ListeningSocket, New,
+    Resource,
+};
+
+/// Provides the buffers that windows are drawn into.  Each buffer holds one
+/// `u32` per pixel, as [`Surface`] expects, and must be shared with the GUI
+/// daemon.
+pub trait SharedMemory {
+    /// A buffer shared with the daemon
+    type Buffer: AsMut<[u32]>;
+
+    /// Allocate a buffer for `window`, which has size `size`, share it with
+    /// the daemon, and send the message that tells the daemon about it,
+    /// usually `MSG_WINDOW_DUMP`.  Any previous buffer of the window will be
+    /// dropped once this returns.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the buffer cannot be allocated or shared, or if sending
+    /// fails.  The error is returned by [`Compositor::dispatch`].
+    fn allocate(
+        &mut self,
+        agent: &mut Agent,
+        window: NonZeroU32,
+        size: WindowSize,
+    ) -> io::Result<Self::Buffer>;
+}
+
+/// Per-client data, of which there is none
+struct ClientState;
+
+impl ClientData for ClientState {}
+
+/// Double-buffered state of a `wl_surface`
+#[derive(Debug, Default)]
+pub(crate) struct SurfaceState {
+    /// The buffer attached since the last commit.  `Some(None)` means that
+    /// the buffer was detached.
+    buffer: Option<Option<WlBuffer>>,
+    /// Damage since the last commit
+    damage: Vec<Rectangle>,
+    /// Frame callbacks requested since the last commit
+    frames: Vec<WlCallback>,
+    /// The window showing the surface, once it is a toplevel
+    pub(crate) window: Option<NonZeroU32>,
+}
+
+/// A toplevel, shown as a Qubes window
+pub(crate) struct Window<B> {
+    pub(crate) surface: WlSurface,
+    pub(crate) xdg_surface: XdgSurface,
+    pub(crate) toplevel: XdgToplevel,
+    /// The buffer shared with the daemon.  The window is created when the
+    /// first buffer is committed.
+    pub(crate) pixels: Option<Surface<B>>,
+    pub(crate) title: String,
+}
+
+/// Everything the Wayland request handlers can access
+pub(crate) struct State<S: SharedMemory> {
+    pub(crate) agent: Agent,
+    pub(crate) memory: S,
+    pub(crate) surfaces: HashMap<ObjectId, SurfaceState>,
+    pub(crate) windows: BTreeMap<NonZeroU32, Window<S::Buffer>>,
+    next_window: u32,
+    pub(crate) pointers: Vec<WlPointer>,
+    pub(crate) keyboards: Vec<WlKeyboard>,
+    /// The xkb keymap sent to clients, if any
+    pub(crate) keymap: Option<Arc<str>>,
+    pub(crate) pointer_focus: Option<NonZeroU32>,
+    pub(crate) keyboard_focus: Option<NonZeroU32>,
+    pub(crate) modifiers: Modifiers,
+    serial: u32,
+    start: Instant,
+    /// The first error from a request handler, which cannot return it
+    error: Option<io::Error>,
+}
+
+impl<S: SharedMemory> State<S> {
+    /// A new event serial
+    pub(crate) fn serial(&mut self) -> u32 {
+        self.serial = self.serial.wrapping_add(1);
+        self.serial
+    }
+
+    /// The timestamp of events, in milliseconds
+    pub(crate) fn time(&self) -> u32 {
+        self.start.elapsed().as_millis() as u32
+    }
+
+    /// Remember the first error, to be returned by [`Compositor::dispatch`]
+    pub(crate) fn fail(&mut self, result: io::Result<()>) {
+        if let Err(e) = result {
+            self.error.get_or_insert(e);
+        }
+    }
+
+    /// An unused window ID
+    pub(crate) fn new_window(&mut self) -> NonZeroU32 {
+        loop {
+            self.next_window = self.next_window.wrapping_add(1);
+            if let Some(window) = NonZeroU32::new(self.next_window) {
+                if !self.windows.contains_key(&window)
+                    && self.agent.window(window).is_none()
+                    && !self.agent.destroy_pending(window)
+                {
+                    break window;
+                }
+            }
+        }
+    }
+
+    /// Destroy `window`, if it still exists
+    pub(crate) fn destroy_window(&mut self, window: NonZeroU32) {
+        let window_state = match self.windows.remove(&window) {
+            Some(window_state) => window_state,
+            None => return,
+        };
+        if let Some(surface) = self.surfaces.get_mut(&window_state.surface.id()) {
+            surface.window = None
+        }
+        if self.pointer_focus == Some(window) {
+            self.pointer_focus = None
+        }
+        if self.keyboard_focus == Some(window) {
+            self.keyboard_focus = None
+        }
+        if window_state.pixels.is_some() {
+            let res = self.agent.destroy(window);
+            self.fail(res)
+        }
+    }
+
+    /// Share a new buffer for `window` with the daemon
+    pub(crate) fn allocate(&mut self, window: NonZeroU32, size: WindowSize) -> io::Result<()> {
+        let window_state = match self.windows.get_mut(&window) {
+            Some(window_state) => window_state,
+            None => return Ok(()),
+        };
+        let memory = self.memory.allocate(&mut self.agent, window, size)?;
+        match &mut window_state.pixels {
+            Some(pixels) => drop(pixels.resize(size, memory)?),
+            None => window_state.pixels = Some(Surface::new(window, size, memory)?),
+        }
+        Ok(())
+    }
+
+    /// Show `buffer` in `window`, creating and mapping the window if needed
+    fn show(
+        &mut self,
+        window: NonZeroU32,
+        buffer: &BufferData,
+        damage: &[Rectangle],
+    ) -> io::Result<()> {
+        let size = buffer.size();
+        let window_state = match self.windows.get(&window) {
+            Some(window_state) => window_state,
+            None => return Ok(()),
+        };
+        let old_size = window_state.pixels.as_ref().map(Surface::size);
+        if old_size.is_none() {
+            let create = qubes_gui::Create {
+                rectangle: Rectangle {
+                    top_left: Coordinates::default(),
+                    size,
+                },
+                parent: None,
+                override_redirect: 0,
+            };
+            self.agent.create_window(window, &create)?;
+            if !window_state.title.is_empty() {
+                self.agent.set_title(window, &window_state.title)?;
+            }
+        }
+        if old_size != Some(size) {
+            self.allocate(window, size)?;
+            if let Some(info) = self.agent.window(window).filter(|_| old_size.is_some()) {
+                let configure = qubes_gui::Configure {
+                    rectangle: Rectangle {
+                        top_left: info.rectangle.top_left,
+                        size,
+                    },
+                    override_redirect: 0,
+                };
+                self.agent.configure(window, &configure)?;
+            }
+        }
+        let pixels = match self
+            .windows
+            .get_mut(&window)
+            .and_then(|w| w.pixels.as_mut())
+        {
+            Some(pixels) => pixels,
+            None => return Ok(()),
+        };
+        let mut target = pixels.buffer_mut();
+        buffer.copy_to(&mut target);
+        if old_size == Some(size) {
+            target.present_with_damage(self.agent.connection(), damage)?;
+        } else {
+            target.present(self.agent.connection())?;
+        }
+        if !self.agent.window(window).is_some_and(|info| info.mapped) {
+            let info = qubes_gui::MapInfo {
+                transient_for: 0,
+                override_redirect: 0,
+            };
+            self.agent.map(window, &info)?;
+        }
+        Ok(())
+    }
+
+    /// Apply the pending state of `surface`
+    fn commit(&mut self, surface: &WlSurface) {
+        let time = self.time();
+        let pending = match self.surfaces.get_mut(&surface.id()) {
+            Some(pending) => pending,
+            None => return,
+        };
+        let damage = std::mem::take(&mut pending.damage);
+        let frames = std::mem::take(&mut pending.frames);
+        let (window, buffer) = (pending.window, pending.buffer.take());
+        match (window, buffer) {
+            (Some(window), Some(Some(buffer))) => {
+                if let Some(data) = buffer.data::<BufferData>() {
+                    let res = self.show(window, data, &damage);
+                    self.fail(res)
+                }
+                buffer.release()
+            }
+            (Some(window), Some(None)) if self.agent.window(window).is_some() => {
+                let res = self.agent.unmap(window);
+                self.fail(res)
+            }
+            (None, Some(Some(buffer))) => buffer.release(),
+            _ => {}
+        }
+        for frame in frames {
+            frame.done(time)
+        }
+    }
+}
+
+/// A Wayland compositor that shows the windows of its clients through a
+/// GUI agent.  See the crate documentation for what it supports.
+pub struct Compositor<S: SharedMemory + 'static> {
+    display: Display<State<S>>,
+    socket: Option<ListeningSocket>,
+    pub(crate) state: State<S>,
+}
+
+impl<S: SharedMemory + 'static> std::fmt::Debug for Compositor<S> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Compositor")
+            .field("socket", &self.socket_name())
+            .field("agent", &self.state.agent)
+            .field("windows", &self.state.windows.len())
+            .finish()
+    }
+}
+
+impl<S: SharedMemory + 'static> Compositor<S> {
+    /// Show the windows of Wayland clients using `agent`, which must not
+    /// have any windows yet, with buffers allocated by `memory`.  No clients
+    /// can connect until [`Compositor::listen`] or
+    /// [`Compositor::insert_client`] is used.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the Wayland display cannot be created.
+    pub fn new(agent: Agent, memory: S) -> io::Result<Self> {
+        let display = Display::new().map_err(|e| io::Error::other(e.to_string()))?;
+        let handle = display.handle();
+        handle.create_global::<State<S>, WlCompositor, ()>(4, ());
+        handle.create_global::<State<S>, WlShm, ()>(1, ());
+        handle.create_global::<State<S>, WlSeat, ()>(5, ());
+        handle.create_global::<State<S>, XdgWmBase, ()>(1, ());
+        Ok(Self {
+            display,
+            socket: None,
+            state: State {
+                agent,
+                memory,
+                surfaces: HashMap::new(),
+                windows: BTreeMap::new(),
+                next_window: 0,
+                pointers: vec![],
+                keyboards: vec![],
+                keymap: None,
+                pointer_focus: None,
+                keyboard_focus: None,
+                modifiers: Modifiers::new(),
+                serial: 0,
+                start: Instant::now(),
+                error: None,
+            },
+        })
+    }
+
+    /// Accept clients on `socket`, such as one created with
+    /// [`ListeningSocket::bind_auto`]
+    pub fn listen(mut self, socket: ListeningSocket) -> Self {
+        self.socket = Some(socket);
+        self
+    }
+
+    /// Send `keymap`, an xkb keymap in text form, to clients.  Otherwise,
+    /// clients are told that there is no keymap, and get raw evdev
+    /// keycodes.
+    pub fn keymap(mut self, keymap: &str) -> Self {
+        self.state.keymap = Some(keymap.into());
+        self
+    }
+
+    /// The name of the listening socket, to be used as `WAYLAND_DISPLAY`
+    pub fn socket_name(&self) -> Option<&OsStr> {
+        self.socket.as_ref()?.socket_name()
+    }
+
+    /// The agent.  Windows created directly with the agent must not use IDs
+    /// that the compositor might use, so this is mostly useful for
+    /// configuring the underlying connection.
+    pub fn agent(&mut self) -> &mut Agent {
+        &mut self.state.agent
+    }
+
+    /// Serve a client connected to `stream`
+    pub fn insert_client(&mut self, stream: UnixStream) -> io::Result<()> {
+        self.display
+            .handle()
+            .insert_client(stream, Arc::new(ClientState))
+            .map(drop)
+    }
+
+    /// Do everything that can be done without blocking: accept new clients,
+    /// handle their requests, handle messages from the GUI daemon, and
+    /// flush everything out.
+    ///
+    /// # Errors
+    ///
+    /// Fails on I/O errors, other than those of an individual Wayland
+    /// client, which only disconnect that client.
+    pub fn dispatch(&mut self) -> io::Result<()> {
+        while let Some(stream) = match &self.socket {
+            Some(socket) => socket.accept()?,
+            None => None,
+        } {
+            self.insert_client(stream)?
+        }
+        self.display.dispatch_clients(&mut self.state)?;
+        let mut inbox = Inbox::default();
+        self.state.agent.dispatch(&mut inbox)?;
+        inbox.deliver(&mut self.state);
+        self.display.flush_clients()?;
+        self.state.error.take().map_or(Ok(()), Err)
+    }
+
+    /// Run forever, or until an error occurs
+    ///
+    /// # Errors
+    ///
+    /// Fails if waiting for events fails, or if [`Compositor::dispatch`]
+    /// does.
+    pub fn run(&mut self) -> io::Result<()> {
+        loop {
+            self.dispatch()?;
+            let agent_fd = self.state.agent.connection().as_raw_fd();
+            // SAFETY: the connection owns its file descriptor, and is not
+            // touched until polling is done.
+            let agent_fd = (agent_fd >= 0).then(|| unsafe { BorrowedFd::borrow_raw(agent_fd) });
+            let mut fds: Vec<PollFd<'_>> = self
+                .socket
+                .iter()
+                .map(|socket| socket.as_fd())
+                .chain(Some(self.display.backend().poll_fd()))
+                .chain(agent_fd)
+                .map(|fd| PollFd::from_borrowed_fd(fd, PollFlags::IN))
+                .collect();
+            match rustix::event::poll(&mut fds, None) {
+                Ok(_) | Err(rustix::io::Errno::INTR) => {}
+                Err(e) => return Err(e.into()),
+            }
+            let agent_ready = agent_fd.is_some() && !fds.last().unwrap().revents().is_empty();
+            drop(fds);
+            if agent_ready {
+                self.state.agent.connection().wait()
+            }
+        }
+    }
+}
+
+impl<S: SharedMemory + 'static> GlobalDispatch<WlCompositor, ()> for State<S> {
+    fn bind(
+        _state: &mut Self,
+        _handle: &DisplayHandle,
+        _client: &Client,
+        resource: New<WlCompositor>,
+        _global_data: &(),
+        data_init: &mut DataInit<'_, Self>,
+    ) {
+        data_init.init(resource, ());
+    }
+}
+
+impl<S: SharedMemory + 'static> Dispatch<WlCompositor, ()> for State<S> {
+    fn request(
+        state: &mut Self,
+        _client: &Client,
+        _resource: &WlCompositor,
+        request: wl_compositor::Request,
+        _data: &(),
+        _dhandle: &DisplayHandle,
+        data_init: &mut DataInit<'_, Self>,
+    ) {
+        match request {
+            wl_compositor::Request::CreateSurface { id } => {
+                let surface = data_init.init(id, ());
+                state.surfaces.insert(surface.id(), SurfaceState::default());
+            }
+            wl_compositor::Request::CreateRegion { id } => {
+                data_init.init(id, ());
+            }
+            _ => {}
+        }
+    }
+}
+
+/// A rectangle from the arguments of `wl_surface.damage`, or `None` if it is
+/// empty
+fn damage(x: i32, y: i32, width: i32, height: i32) -> Option<Rectangle> {
+    let size = WindowSize {
+        width: u32::try_from(width).ok()?,
+        height: u32::try_from(height).ok()?,
+    };
+    Some(Rectangle {
+        top_left: Coordinates { x, y },
+        size,
+    })
+    .filter(|rectangle| !rectangle.is_empty())
+}
+
+impl<S: SharedMemory + 'static> Dispatch<WlSurface, ()> for State<S> {
+    fn request(
+        state: &mut Self,
+        _client: &Client,
+        resource: &WlSurface,
+        request: wl_surface::Request,
+        _data: &(),
+        _dhandle: &DisplayHandle,
+        data_init: &mut DataInit<'_, Self>,
+    ) {
+        if let wl_surface::Request::Commit = request {
+            return state.commit(resource);
+        }
+        let pending = match state.surfaces.get_mut(&resource.id()) {
+            Some(pending) => pending,
+            None => return,
+        };
+        match request {
+            wl_surface::Request::Attach { buffer, .. } => pending.buffer = Some(buffer),
+            wl_surface::Request::Damage {
+                x,
+                y,
+                width,
+                height,
+            }
+            | wl_surface::Request::DamageBuffer {
+                x,
+                y,
+                width,
+                height,
+            } => pending.damage.extend(damage(x, y, width, height)),
+            wl_surface::Request::Frame { callback } => {
+                pending.frames.push(data_init.init(callback, ()))
+            }
+            _ => {}
+        }
+    }
+
+    fn destroyed(
+        state: &mut Self,
+        _client: wayland_server::backend::ClientId,
+        resource: &WlSurface,
+        _data: &(),
+    ) {
+        if let Some(window) = state.surfaces.remove(&resource.id()).and_then(|s| s.window) {
+            state.destroy_window(window)
+        }
+    }
+}
+
+impl<S: SharedMemory + 'static> Dispatch<WlRegion, ()> for State<S> {
+    fn request(
+        _state: &mut Self,
+        _client: &Client,
+        _resource: &WlRegion,
+        _request: wl_region::Request,
+        _data: &(),
+        _dhandle: &DisplayHandle,
+        _data_init: &mut DataInit<'_, Self>,
+    ) {
+    }
+}
+
+impl<S: SharedMemory + 'static> Dispatch<WlCallback, ()> for State<S> {
+    fn request(
+        _state: &mut Self,
+        _client: &Client,
+        _resource: &WlCallback,
+        _request: wl_callback::Request,
+        _data: &(),
+        _dhandle: &DisplayHandle,
+        _data_init: &mut DataInit<'_, Self>,
+    ) {
+    }
+}
@@ -0,0 +1,491 @@
+/*
+ * The Qubes OS Project, https://www.qubes-os.org
+ *
+ * Copyright (C) 2021  Demi Marie Obenour  <demi@invisiblethingslab.com>
+ *
+ * This program is free software; you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License
+ * as published by the Free Software Foundation; either version 2
+ * of the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301, USA.
+ *
+ */
+
+//! The seat, and input from the GUI daemon
+
+use crate::compositor::{SharedMemory, State};
+use qubes_gui::x11::{MouseButton, LOCK_MASK, MOD2_MASK, MODIFIER_MASK};
+use qubes_gui::{ButtonEvent, Coordinates, FocusEvent, KeyEvent, Rectangle, WindowID};
+use qubes_gui_agent_proto::{TrustedButton, TrustedCrossing, TrustedFocus, TrustedKeypress};
+use qubes_gui_connection::MessageHandler;
+use std::convert::TryFrom;
+use std::io::{self, Write as _};
+use std::num::NonZeroU32;
+use std::os::unix::io::AsFd as _;
+use wayland_server::protocol::wl_keyboard::{self, KeymapFormat, WlKeyboard};
+use wayland_server::protocol::wl_pointer::{self, Axis, ButtonState, WlPointer};
+use wayland_server::protocol::wl_seat::{self, Capability, WlSeat};
+use wayland_server::protocol::wl_surface::WlSurface;
+use wayland_server::{Client, DataInit, Dispatch, DisplayHandle, GlobalDispatch, New, Resource};
+
+/// Scroll distance of one wheel click, in surface coordinates
+const SCROLL_STEP: f64 = 10.0;
+
+/// An event from the daemon, after validation
+#[derive(Debug)]
+enum Input {
+    Keypress(NonZeroU32, TrustedKeypress),
+    Button(NonZeroU32, TrustedButton),
+    Motion(NonZeroU32, qubes_gui::Motion),
+    Crossing(NonZeroU32, TrustedCrossing),
+    Focus(NonZeroU32, TrustedFocus),
+    Configure(NonZeroU32, Rectangle),
+    Close(NonZeroU32),
+    Reconnected,
+}
+
+/// Collects events from the daemon, as they cannot be handled while the
+/// agent is borrowed.  Events that fail validation, or that are for the
+/// whole screen, are dropped.
+#[derive(Debug, Default)]
+pub(crate) struct Inbox(Vec<Input>);
+
+impl Inbox {
+    fn push<T>(&mut self, window: WindowID, event: Option<T>, f: fn(NonZeroU32, T) -> Input) {
+        if let (Some(window), Some(event)) = (window.window, event) {
+            self.0.push(f(window, event))
+        }
+    }
+
+    /// Forward the collected events to the clients
+    pub(crate) fn deliver<S: SharedMemory>(self, state: &mut State<S>) {
+        for input in self.0 {
+            match input {
+                Input::Keypress(window, keypress) => {
+                    state.modifiers.keypress(&keypress);
+                    keyboard_focus(state, Some(window));
+                    key(state, window, &keypress);
+                }
+                Input::Button(window, button) => {
+                    state.modifiers.button(&button);
+                    pointer_focus(state, window, button.coordinates());
+                    pointer_button(state, window, &button);
+                }
+                Input::Motion(window, motion) => {
+                    state.modifiers.motion(&motion);
+                    if state.pointer_focus == Some(window) {
+                        pointer_motion(state, window, motion.coordinates)
+                    } else {
+                        pointer_focus(state, window, motion.coordinates)
+                    }
+                }
+                Input::Crossing(window, crossing) => {
+                    state.modifiers.crossing(&crossing);
+                    if crossing.entered() {
+                        pointer_focus(state, window, crossing.coordinates())
+                    } else if state.pointer_focus == Some(window) {
+                        pointer_leave(state)
+                    }
+                }
+                Input::Focus(window, focus) => match focus.event() {
+                    FocusEvent::In => keyboard_focus(state, Some(window)),
+                    FocusEvent::Out if state.keyboard_focus == Some(window) => {
+                        keyboard_focus(state, None)
+                    }
+                    FocusEvent::Out => {}
+                },
+                Input::Configure(window, rectangle) => configure(state, window, rectangle),
+                Input::Close(window) => {
+                    if let Some(window_state) = state.windows.get(&window) {
+                        window_state.toplevel.close()
+                    }
+                }
+                Input::Reconnected => {
+                    let res = reconnected(state);
+                    state.fail(res)
+                }
+            }
+        }
+    }
+}
+
+impl MessageHandler for Inbox {
+    fn on_keypress(&mut self, window: WindowID, keypress: &qubes_gui::Keypress) {
+        let keypress = TrustedKeypress::validate(keypress).ok();
+        self.push(window, keypress, Input::Keypress)
+    }
+
+    fn on_button(&mut self, window: WindowID, button: &qubes_gui::Button) {
+        let button = TrustedButton::validate(button).ok();
+        self.push(window, button, Input::Button)
+    }
+
+    fn on_motion(&mut self, window: WindowID, motion: &qubes_gui::Motion) {
+        self.push(window, Some(*motion), Input::Motion)
+    }
+
+    fn on_crossing(&mut self, window: WindowID, crossing: &qubes_gui::Crossing) {
+        let crossing = TrustedCrossing::validate(crossing).ok();
+        self.push(window, crossing, Input::Crossing)
+    }
+
+    fn on_focus(&mut self, window: WindowID, focus: &qubes_gui::Focus) {
+        let focus = TrustedFocus::validate(focus).ok();
+        self.push(window, focus, Input::Focus)
+    }
+
+    fn on_configure(&mut self, window: WindowID, configure: &qubes_gui::Configure) {
+        self.push(window, Some(configure.rectangle), Input::Configure)
+    }
+
+    fn on_close(&mut self, window: WindowID) {
+        self.push(window, Some(()), |window, ()| Input::Close(window))
+    }
+
+    fn on_reconnected(&mut self, _xconf: &qubes_gui::XConfVersion) {
+        self.0.push(Input::Reconnected)
+    }
+}
+
+/// The surface shown in `window`
+fn surface<S: SharedMemory>(state: &State<S>, window: NonZeroU32) -> Option<WlSurface> {
+    Some(state.windows.get(&window)?.surface.clone())
+}
+
+fn pointers<'a>(
+    pointers: &'a [WlPointer],
+    surface: &'a WlSurface,
+) -> impl Iterator<Item = &'a WlPointer> {
+    pointers
+        .iter()
+        .filter(move |pointer| pointer.id().same_client_as(&surface.id()))
+}
+
+fn keyboards<'a>(
+    keyboards: &'a [WlKeyboard],
+    surface: &'a WlSurface,
+) -> impl Iterator<Item = &'a WlKeyboard> {
+    keyboards
+        .iter()
+        .filter(move |keyboard| keyboard.id().same_client_as(&surface.id()))
+}
+
+fn frame(pointer: &WlPointer) {
+    if pointer.version() >= 5 {
+        pointer.frame()
+    }
+}
+
+/// Move the pointer into `window`, unless it is already there
+fn pointer_focus<S: SharedMemory>(state: &mut State<S>, window: NonZeroU32, at: Coordinates) {
+    if state.pointer_focus == Some(window) {
+        return;
+    }
+    pointer_leave(state);
+    let surface = match surface(state, window) {
+        Some(surface) => surface,
+        None => return,
+    };
+    let serial = state.serial();
+    for pointer in pointers(&state.pointers, &surface) {
+        pointer.enter(serial, &surface, at.x.into(), at.y.into());
+        frame(pointer)
+    }
+    state.pointer_focus = Some(window)
+}
+
+fn pointer_leave<S: SharedMemory>(state: &mut State<S>) {
+    let surface = match state.pointer_focus.take().and_then(|w| surface(state, w)) {
+        Some(surface) => surface,
+        None => return,
+    };
+    let serial = state.serial();
+    for pointer in pointers(&state.pointers, &surface) {
+        pointer.leave(serial, &surface);
+        frame(pointer)
+    }
+}
+
+fn pointer_motion<S: SharedMemory>(state: &mut State<S>, window: NonZeroU32, at: Coordinates) {
+    let (surface, time) = match surface(state, window) {
+        Some(surface) => (surface, state.time()),
+        None => return,
+    };
+    for pointer in pointers(&state.pointers, &surface) {
+        pointer.motion(time, at.x.into(), at.y.into());
+        frame(pointer)
+    }
+}
+
+fn pointer_button<S: SharedMemory>(
+    state: &mut State<S>,
+    window: NonZeroU32,
+    button: &TrustedButton,
+) {
+    let surface = match surface(state, window) {
+        Some(surface) => surface,
+        None => return,
+    };
+    let (serial, time) = (state.serial(), state.time());
+    let pressed = button.event() == ButtonEvent::Press;
+    let mouse_button = button.mouse_button();
+    if let Some((dx, dy)) = mouse_button.scroll_delta() {
+        // Scrolling is reported as a press and a release; only one counts.
+        let (axis, delta) = match (pressed, dx) {
+            (false, _) => return,
+            (true, 0) => (Axis::VerticalScroll, dy),
+            (true, _) => (Axis::HorizontalScroll, dx),
+        };
+        for pointer in pointers(&state.pointers, &surface) {
+            pointer.axis(time, axis, f64::from(delta) * SCROLL_STEP);
+            frame(pointer)
+        }
+        return;
+    }
+    // Linux input event codes
+    let code = match mouse_button {
+        MouseButton::Left => 0x110,
+        MouseButton::Right => 0x111,
+        MouseButton::Middle => 0x112,
+        MouseButton::Other(8) => 0x113,
+        MouseButton::Other(9) => 0x114,
+        _ => return,
+    };
+    let button_state = if pressed {
+        ButtonState::Pressed
+    } else {
+        ButtonState::Released
+    };
+    for pointer in pointers(&state.pointers, &surface) {
+        pointer.button(serial, time, code, button_state);
+        frame(pointer)
+    }
+}
+
+/// Send the modifier state to `keyboard`.  The lock modifiers are Caps Lock
+/// and Num Lock; the rest are held down.
+fn modifiers(keyboard: &WlKeyboard, serial: u32, mask: u32) {
+    let locks = LOCK_MASK | MOD2_MASK;
+    let mask = mask & MODIFIER_MASK;
+    keyboard.modifiers(serial, mask & !locks, 0, mask & locks, 0)
+}
+
+/// Move the keyboard focus to `window`, unless it is already there
+fn keyboard_focus<S: SharedMemory>(state: &mut State<S>, window: Option<NonZeroU32>) {
+    if state.keyboard_focus == window {
+        return;
+    }
+    if let Some(surface) = state.keyboard_focus.take().and_then(|w| surface(state, w)) {
+        let serial = state.serial();
+        for keyboard in keyboards(&state.keyboards, &surface) {
+            keyboard.leave(serial, &surface)
+        }
+    }
+    let surface = match window.and_then(|w| surface(state, w)) {
+        Some(surface) => surface,
+        None => return,
+    };
+    let serial = state.serial();
+    for keyboard in keyboards(&state.keyboards, &surface) {
+        keyboard.enter(serial, &surface, vec![]);
+        modifiers(keyboard, serial, state.modifiers.mask())
+    }
+    state.keyboard_focus = window
+}
+
+fn key<S: SharedMemory>(state: &mut State<S>, window: NonZeroU32, keypress: &TrustedKeypress) {
+    let surface = match surface(state, window) {
+        Some(surface) => surface,
+        None => return,
+    };
+    // X11 keycodes are evdev keycodes plus 8
+    let code = match keypress.keycode().checked_sub(8) {
+        Some(code) => code,
+        None => return,
+    };
+    let key_state = match keypress.event() {
+        KeyEvent::Press => wl_keyboard::KeyState::Pressed,
+        KeyEvent::Release => wl_keyboard::KeyState::Released,
+    };
+    let (serial, time) = (state.serial(), state.time());
+    for keyboard in keyboards(&state.keyboards, &surface) {
+        keyboard.key(serial, time, code, key_state);
+        modifiers(keyboard, serial, state.modifiers.mask())
+    }
+}
+
+/// Handle a `MSG_CONFIGURE`.  A move is acknowledged at once.  A resize is
+/// passed on to the client, and acknowledged when a buffer of the new size
+/// is committed.
+fn configure<S: SharedMemory>(state: &mut State<S>, window: NonZeroU32, rectangle: Rectangle) {
+    let serial = state.serial();
+    let window_state = match state.windows.get(&window) {
+        Some(window_state) => window_state,
+        None => return,
+    };
+    let size = match &window_state.pixels {
+        Some(pixels) if state.agent.window(window).is_some() => pixels.size(),
+        _ => return,
+    };
+    if size != rectangle.size {
+        let (width, height) = (rectangle.size.width, rectangle.size.height);
+        window_state.toplevel.configure(
+            width.min(i32::MAX as u32) as i32,
+            height.min(i32::MAX as u32) as i32,
+            vec![],
+        );
+        window_state.xdg_surface.configure(serial);
+    }
+    let configure = qubes_gui::Configure {
+        rectangle: Rectangle {
+            top_left: rectangle.top_left,
+            size,
+        },
+        override_redirect: 0,
+    };
+    let res = state.agent.configure(window, &configure);
+    state.fail(res)
+}
+
+/// Share new buffers for every window with the new daemon, which the agent
+/// has already told about the windows themselves
+fn reconnected<S: SharedMemory>(state: &mut State<S>) -> io::Result<()> {
+    let windows: Vec<NonZeroU32> = state.windows.keys().copied().collect();
+    for window in windows {
+        let size = match state.windows.get(&window).and_then(|w| w.pixels.as_ref()) {
+            Some(pixels) => pixels.size(),
+            None => continue,
+        };
+        let memory = state.memory.allocate(&mut state.agent, window, size)?;
+        let pixels = match state
+            .windows
+            .get_mut(&window)
+            .and_then(|w| w.pixels.as_mut())
+        {
+            Some(pixels) => pixels,
+            None => continue,
+        };
+        let mut old = pixels.resize(size, memory)?;
+        let mut buffer = pixels.buffer_mut();
+        let len = buffer.len();
+        buffer.copy_from_slice(&old.as_mut()[..len]);
+        buffer.present(state.agent.connection())?;
+    }
+    Ok(())
+}
+
+/// Send the keymap to a new keyboard
+fn keymap(keyboard: &WlKeyboard, keymap: Option<&str>) -> io::Result<()> {
+    use rustix::fs::{memfd_create, MemfdFlags};
+    match keymap {
+        Some(keymap) => {
+            let fd = memfd_create("qubes-gui-keymap", MemfdFlags::CLOEXEC)?;
+            let mut file = std::fs::File::from(fd);
+            file.write_all(keymap.as_bytes())?;
+            file.write_all(b"\0")?;
+            let size = u32::try_from(keymap.len() + 1).map_err(io::Error::other)?;
+            keyboard.keymap(KeymapFormat::XkbV1, file.as_fd(), size)
+        }
+        None => {
+            let file = std::fs::File::open("/dev/null")?;
+            keyboard.keymap(KeymapFormat::NoKeymap, file.as_fd(), 0)
+        }
+    }
+    Ok(())
+}
+
+impl<S: SharedMemory + 'static> GlobalDispatch<WlSeat, ()> for State<S> {
+    fn bind(
+        _state: &mut Self,
+        _handle: &DisplayHandle,
+        _client: &Client,
+        resource: New<WlSeat>,
+        _global_data: &(),
+        data_init: &mut DataInit<'_, Self>,
+    ) {
+        let seat = data_init.init(resource, ());
+        seat.capabilities(Capability::Pointer | Capability::Keyboard);
+        if seat.version() >= 2 {
+            seat.name("seat0".to_owned())
+        }
+    }
+}
+
+impl<S: SharedMemory + 'static> Dispatch<WlSeat, ()> for State<S> {
+    fn request(
+        state: &mut Self,
+        _client: &Client,
+        _resource: &WlSeat,
+        request: wl_seat::Request,
+        _data: &(),
+        _dhandle: &DisplayHandle,
+        data_init: &mut DataInit<'_, Self>,
+    ) {
+        match request {
+            wl_seat::Request::GetPointer { id } => state.pointers.push(data_init.init(id, ())),
+            wl_seat::Request::GetKeyboard { id } => {
+                let keyboard = data_init.init(id, ());
+                let res = keymap(&keyboard, state.keymap.as_deref());
+                state.fail(res);
+                if keyboard.version() >= 4 {
+                    // The daemon repeats keys itself.
+                    keyboard.repeat_info(0, 0)
+                }
+                state.keyboards.push(keyboard)
+            }
+            _ => {}
+        }
+    }
+}
+
+impl<S: SharedMemory + 'static> Dispatch<WlPointer, ()> for State<S> {
+    fn request(
+        _state: &mut Self,
+        _client: &Client,
+        _resource: &WlPointer,
+        _request: wl_pointer::Request,
+        _data: &(),
+        _dhandle: &DisplayHandle,
+        _data_init: &mut DataInit<'_, Self>,
+    ) {
+    }
+
+    fn destroyed(
+        state: &mut Self,
+        _client: wayland_server::backend::ClientId,
+        resource: &WlPointer,
+        _data: &(),
+    ) {
+        state.pointers.retain(|pointer| pointer != resource)
+    }
+}
+
+impl<S: SharedMemory + 'static> Dispatch<WlKeyboard, ()> for State<S> {
+    fn request(
+        _state: &mut Self,
+        _client: &Client,
+        _resource: &WlKeyboard,
+        _request: wl_keyboard::Request,
+        _data: &(),
+        _dhandle: &DisplayHandle,
+        _data_init: &mut DataInit<'_, Self>,
+    ) {
+    }
+
+    fn destroyed(
+        state: &mut Self,
+        _client: wayland_server::backend::ClientId,
+        resource: &WlKeyboard,
+        _data: &(),
+    ) {
+        state.keyboards.retain(|keyboard| keyboard != resource)
+    }
+}
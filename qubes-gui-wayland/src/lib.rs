@@ -0,0 +1,46 @@
+/*
+ * The Qubes OS Project, https://www.qubes-os.org
+ *
+ * Copyright (C) 2021  Demi Marie Obenour  <demi@invisiblethingslab.com>
+ *
+ * This program is free software; you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License
+ * as published by the Free Software Foundation; either version 2
+ * of the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301, USA.
+ *
+ */
+
+#![forbid(missing_docs)]
+#![forbid(clippy::all)]
+//! A minimal Wayland compositor for use inside a qube
+//!
+//! [`Compositor`] accepts Wayland clients and shows each of their
+//! `xdg_toplevel` surfaces as a Qubes window, using an
+//! [`Agent`](qubes_gui_connection::Agent).  Input from the GUI daemon is
+//! forwarded to the client that owns the window.
+//!
+//! Only what simple clients need is supported: `wl_compositor`, `wl_shm`
+//! buffers in the `argb8888` and `xrgb8888` formats, a `wl_seat` with a
+//! pointer and a keyboard, and `xdg_wm_base` toplevels.  Popups are
+//! dismissed as soon as they are created, and subsurfaces are not offered.
+//! Pixels are copied into a buffer shared with the daemon, which must be
+//! provided by a [`SharedMemory`] implementation, as sharing memory with
+//! another qube needs kernel interfaces this crate does not use.
+
+mod compositor;
+mod input;
+mod shell;
+mod shm;
+#[cfg(test)]
+mod tests;
+
+pub use compositor::{Compositor, SharedMemory};
@@ -0,0 +1,173 @@
+/*
+ * The Qubes OS Project, https://www.qubes-os.org
+ *
+ * Copyright (C) 2021  Demi Marie Obenour  <demi@invisiblethingslab.com>
+ *
+ * This program is free software; you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License
+ * as published by the Free Software Foundation; either version 2
+ * of the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301, USA.
+ *
+ */
+
+//! `xdg_wm_base` and its toplevels
+
+use crate::compositor::{SharedMemory, State, Window};
+use std::num::NonZeroU32;
+use wayland_protocols::xdg::shell::server::xdg_popup::{self, XdgPopup};
+use wayland_protocols::xdg::shell::server::xdg_positioner::{self, XdgPositioner};
+use wayland_protocols::xdg::shell::server::xdg_surface::{self, XdgSurface};
+use wayland_protocols::xdg::shell::server::xdg_toplevel::{self, XdgToplevel};
+use wayland_protocols::xdg::shell::server::xdg_wm_base::{self, XdgWmBase};
+use wayland_server::backend::ClientId;
+use wayland_server::protocol::wl_surface::WlSurface;
+use wayland_server::{Client, DataInit, Dispatch, DisplayHandle, GlobalDispatch, New, Resource};
+
+impl<S: SharedMemory + 'static> GlobalDispatch<XdgWmBase, ()> for State<S> {
+    fn bind(
+        _state: &mut Self,
+        _handle: &DisplayHandle,
+        _client: &Client,
+        resource: New<XdgWmBase>,
+        _global_data: &(),
+        data_init: &mut DataInit<'_, Self>,
+    ) {
+        data_init.init(resource, ());
+    }
+}
+
+impl<S: SharedMemory + 'static> Dispatch<XdgWmBase, ()> for State<S> {
+    fn request(
+        _state: &mut Self,
+        _client: &Client,
+        _resource: &XdgWmBase,
+        request: xdg_wm_base::Request,
+        _data: &(),
+        _dhandle: &DisplayHandle,
+        data_init: &mut DataInit<'_, Self>,
+    ) {
+        match request {
+            xdg_wm_base::Request::CreatePositioner { id } => {
+                data_init.init(id, ());
+            }
+            xdg_wm_base::Request::GetXdgSurface { id, surface } => {
+                data_init.init(id, surface);
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Positioners only matter for popups, which are not shown
+impl<S: SharedMemory + 'static> Dispatch<XdgPositioner, ()> for State<S> {
+    fn request(
+        _state: &mut Self,
+        _client: &Client,
+        _resource: &XdgPositioner,
+        _request: xdg_positioner::Request,
+        _data: &(),
+        _dhandle: &DisplayHandle,
+        _data_init: &mut DataInit<'_, Self>,
+    ) {
+    }
+}
+
+impl<S: SharedMemory + 'static> Dispatch<XdgSurface, WlSurface> for State<S> {
+    fn request(
+        state: &mut Self,
+        _client: &Client,
+        resource: &XdgSurface,
+        request: xdg_surface::Request,
+        surface: &WlSurface,
+        _dhandle: &DisplayHandle,
+        data_init: &mut DataInit<'_, Self>,
+    ) {
+        match request {
+            xdg_surface::Request::GetToplevel { id } => {
+                let pending = state.surfaces.get(&surface.id());
+                if pending.is_none_or(|pending| pending.window.is_some()) {
+                    return resource.post_error(
+                        xdg_surface::Error::AlreadyConstructed,
+                        "Surface already has a toplevel",
+                    );
+                }
+                let window = state.new_window();
+                let toplevel = data_init.init(id, window);
+                toplevel.configure(0, 0, vec![]);
+                resource.configure(state.serial());
+                if let Some(pending) = state.surfaces.get_mut(&surface.id()) {
+                    pending.window = Some(window)
+                }
+                state.windows.insert(
+                    window,
+                    Window {
+                        surface: surface.clone(),
+                        xdg_surface: resource.clone(),
+                        toplevel,
+                        pixels: None,
+                        title: String::new(),
+                    },
+                );
+            }
+            xdg_surface::Request::GetPopup { id, .. } => {
+                // Popups would need override-redirect windows placed
+                // relative to their parent, so they are dismissed instead.
+                data_init.init(id, ()).popup_done()
+            }
+            _ => {}
+        }
+    }
+}
+
+impl<S: SharedMemory + 'static> Dispatch<XdgToplevel, NonZeroU32> for State<S> {
+    fn request(
+        state: &mut Self,
+        _client: &Client,
+        _resource: &XdgToplevel,
+        request: xdg_toplevel::Request,
+        &window: &NonZeroU32,
+        _dhandle: &DisplayHandle,
+        _data_init: &mut DataInit<'_, Self>,
+    ) {
+        if let xdg_toplevel::Request::SetTitle { title } = request {
+            if state.agent.window(window).is_some() {
+                let res = state.agent.set_title(window, &title);
+                state.fail(res)
+            }
+            if let Some(window_state) = state.windows.get_mut(&window) {
+                window_state.title = title
+            }
+        }
+    }
+
+    fn destroyed(
+        state: &mut Self,
+        _client: ClientId,
+        _resource: &XdgToplevel,
+        &window: &NonZeroU32,
+    ) {
+        state.destroy_window(window)
+    }
+}
+
+impl<S: SharedMemory + 'static> Dispatch<XdgPopup, ()> for State<S> {
+    fn request(
+        _state: &mut Self,
+        _client: &Client,
+        _resource: &XdgPopup,
+        _request: xdg_popup::Request,
+        _data: &(),
+        _dhandle: &DisplayHandle,
+        _data_init: &mut DataInit<'_, Self>,
+    ) {
+    }
+}
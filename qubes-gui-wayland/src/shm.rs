@@ -0,0 +1,228 @@
+/*
+ * The Qubes OS Project, https://www.qubes-os.org
+ *
+ * Copyright (C) 2021  Demi Marie Obenour  <demi@invisiblethingslab.com>
+ *
+ * This program is free software; you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License
+ * as published by the Free Software Foundation; either version 2
+ * of the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301, USA.
+ *
+ */
+
+//! `wl_shm` buffers
+
+use crate::compositor::{SharedMemory, State};
+use memmap2::{Mmap, MmapOptions};
+use qubes_gui::WindowSize;
+use std::convert::TryFrom;
+use std::fs::File;
+use std::sync::{Arc, RwLock};
+use wayland_server::protocol::wl_buffer::{self, WlBuffer};
+use wayland_server::protocol::wl_shm::{self, Format, WlShm};
+use wayland_server::protocol::wl_shm_pool::{self, WlShmPool};
+use wayland_server::{
+    Client, DataInit, Dispatch, DisplayHandle, GlobalDispatch, New, Resource, WEnum,
+};
+
+/// A `wl_shm_pool`, mapped read-only.  A client that truncates the file
+/// behind a pool can crash the compositor with `SIGBUS`; as the client runs
+/// in the same qube, that is no worse than what it could do otherwise.
+#[derive(Debug)]
+pub(crate) struct Pool {
+    file: File,
+    map: RwLock<Mmap>,
+}
+
+impl Pool {
+    fn map(file: &File, size: i32) -> Result<Mmap, String> {
+        let len = usize::try_from(size)
+            .ok()
+            .filter(|&len| len > 0)
+            .ok_or_else(|| format!("Invalid pool size {}", size))?;
+        // SAFETY: the mapping is only ever read, and only through
+        // `BufferData::copy_to`.  See above for what happens if it is
+        // truncated.
+        unsafe { MmapOptions::new().len(len).map(file) }.map_err(|e| e.to_string())
+    }
+
+    fn len(&self) -> usize {
+        self.map.read().unwrap_or_else(|e| e.into_inner()).len()
+    }
+}
+
+/// A `wl_buffer` in a [`Pool`]
+#[derive(Debug)]
+pub(crate) struct BufferData {
+    pool: Arc<Pool>,
+    offset: usize,
+    size: WindowSize,
+    stride: usize,
+}
+
+impl BufferData {
+    /// The size of the buffer
+    pub(crate) fn size(&self) -> WindowSize {
+        self.size
+    }
+
+    /// Copy the pixels into `dst`, which holds exactly enough pixels.  The
+    /// alpha channel is discarded.
+    pub(crate) fn copy_to(&self, dst: &mut [u32]) {
+        let map = self.pool.map.read().unwrap_or_else(|e| e.into_inner());
+        let width = self.size.width as usize;
+        for (row, dst) in dst.chunks_exact_mut(width).enumerate() {
+            let start = self.offset + row * self.stride;
+            let src = &map[start..start + 4 * width];
+            for (pixel, src) in dst.iter_mut().zip(src.chunks_exact(4)) {
+                *pixel = u32::from_le_bytes([src[0], src[1], src[2], 0])
+            }
+        }
+    }
+}
+
+/// Check the arguments of `wl_shm_pool.create_buffer`
+fn buffer(
+    pool: &Arc<Pool>,
+    offset: i32,
+    width: i32,
+    height: i32,
+    stride: i32,
+) -> Result<BufferData, String> {
+    let bad = || {
+        format!(
+            "Bad buffer: offset {}, width {}, height {}, stride {}",
+            offset, width, height, stride
+        )
+    };
+    let (offset, width, height, stride) = match (
+        usize::try_from(offset),
+        u32::try_from(width),
+        u32::try_from(height),
+        usize::try_from(stride),
+    ) {
+        (Ok(offset), Ok(width), Ok(height), Ok(stride)) if width > 0 && height > 0 => {
+            (offset, width, height, stride)
+        }
+        _ => return Err(bad()),
+    };
+    let end = (height as usize - 1)
+        .checked_mul(stride)
+        .and_then(|rows| rows.checked_add(4 * width as usize))
+        .and_then(|len| len.checked_add(offset));
+    if stride < 4 * width as usize || end.is_none_or(|end| end > pool.len()) {
+        return Err(bad());
+    }
+    Ok(BufferData {
+        pool: pool.clone(),
+        offset,
+        size: WindowSize { width, height },
+        stride,
+    })
+}
+
+impl<S: SharedMemory + 'static> GlobalDispatch<WlShm, ()> for State<S> {
+    fn bind(
+        _state: &mut Self,
+        _handle: &DisplayHandle,
+        _client: &Client,
+        resource: New<WlShm>,
+        _global_data: &(),
+        data_init: &mut DataInit<'_, Self>,
+    ) {
+        let shm = data_init.init(resource, ());
+        shm.format(Format::Argb8888);
+        shm.format(Format::Xrgb8888);
+    }
+}
+
+impl<S: SharedMemory + 'static> Dispatch<WlShm, ()> for State<S> {
+    fn request(
+        _state: &mut Self,
+        _client: &Client,
+        _resource: &WlShm,
+        request: wl_shm::Request,
+        _data: &(),
+        _dhandle: &DisplayHandle,
+        data_init: &mut DataInit<'_, Self>,
+    ) {
+        if let wl_shm::Request::CreatePool { id, fd, size } = request {
+            let file = File::from(fd);
+            match Pool::map(&file, size) {
+                Ok(map) => {
+                    let map = RwLock::new(map);
+                    data_init.init(id, Arc::new(Pool { file, map }));
+                }
+                Err(e) => data_init.post_error(id, wl_shm::Error::InvalidFd, e),
+            }
+        }
+    }
+}
+
+impl<S: SharedMemory + 'static> Dispatch<WlShmPool, Arc<Pool>> for State<S> {
+    fn request(
+        _state: &mut Self,
+        _client: &Client,
+        resource: &WlShmPool,
+        request: wl_shm_pool::Request,
+        pool: &Arc<Pool>,
+        _dhandle: &DisplayHandle,
+        data_init: &mut DataInit<'_, Self>,
+    ) {
+        match request {
+            wl_shm_pool::Request::CreateBuffer {
+                id,
+                offset,
+                width,
+                height,
+                stride,
+                format,
+            } => match format {
+                WEnum::Value(Format::Argb8888) | WEnum::Value(Format::Xrgb8888) => {
+                    match buffer(pool, offset, width, height, stride) {
+                        Ok(data) => {
+                            data_init.init(id, data);
+                        }
+                        Err(e) => data_init.post_error(id, wl_shm::Error::InvalidStride, e),
+                    }
+                }
+                _ => data_init.post_error(
+                    id,
+                    wl_shm::Error::InvalidFormat,
+                    format!("Unsupported format {:?}", format),
+                ),
+            },
+            wl_shm_pool::Request::Resize { size } => {
+                let mut map = pool.map.write().unwrap_or_else(|e| e.into_inner());
+                match Pool::map(&pool.file, size) {
+                    Ok(new) if new.len() >= map.len() => *map = new,
+                    Ok(_) => resource.post_error(wl_shm::Error::InvalidFd, "Pools cannot shrink"),
+                    Err(e) => resource.post_error(wl_shm::Error::InvalidFd, e),
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+impl<S: SharedMemory + 'static> Dispatch<WlBuffer, BufferData> for State<S> {
+    fn request(
+        _state: &mut Self,
+        _client: &Client,
+        _resource: &WlBuffer,
+        _request: wl_buffer::Request,
+        _data: &BufferData,
+        _dhandle: &DisplayHandle,
+        _data_init: &mut DataInit<'_, Self>,
+    ) {
+    }
+}
@@ -0,0 +1,464 @@
+/*
+ * The Qubes OS Project, https://www.qubes-os.org
+ *
+ * Copyright (C) 2021  Demi Marie Obenour  <demi@invisiblethingslab.com>
+ *
+ * This program is free software; you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License
+ * as published by the Free Software Foundation; either version 2
+ * of the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301, USA.
+ *
+ */
+
+use super::*;
+use qubes_castable::Castable as _;
+use qubes_gui::{Coordinates, Rectangle, WindowSize};
+use qubes_gui_connection::{Agent, Connection, LoopbackTransport};
+use std::io::{self, Write as _};
+use std::num::NonZeroU32;
+use std::os::unix::io::AsFd as _;
+use std::os::unix::net::UnixStream;
+use std::task::Poll;
+use wayland_client::protocol::wl_buffer::{self, WlBuffer};
+use wayland_client::protocol::wl_callback::{self, WlCallback};
+use wayland_client::protocol::wl_compositor::WlCompositor;
+use wayland_client::protocol::wl_keyboard::{self, WlKeyboard};
+use wayland_client::protocol::wl_pointer::{self, WlPointer};
+use wayland_client::protocol::wl_registry::{self, WlRegistry};
+use wayland_client::protocol::wl_seat::{self, WlSeat};
+use wayland_client::protocol::wl_shm::{Format, WlShm};
+use wayland_client::protocol::wl_shm_pool::WlShmPool;
+use wayland_client::protocol::wl_surface::WlSurface;
+use wayland_client::{delegate_noop, Dispatch, QueueHandle, WEnum};
+use wayland_protocols::xdg::shell::client::xdg_surface::{self, XdgSurface};
+use wayland_protocols::xdg::shell::client::xdg_toplevel::{self, XdgToplevel};
+use wayland_protocols::xdg::shell::client::xdg_wm_base::{self, XdgWmBase};
+
+/// Buffers that are not shared with anyone
+#[derive(Debug, Default)]
+struct Memory {
+    allocations: Vec<(NonZeroU32, WindowSize)>,
+}
+
+impl SharedMemory for Memory {
+    type Buffer = Vec<u32>;
+
+    fn allocate(
+        &mut self,
+        _agent: &mut Agent,
+        window: NonZeroU32,
+        size: WindowSize,
+    ) -> io::Result<Vec<u32>> {
+        self.allocations.push((window, size));
+        Ok(vec![0; size.checked_area().unwrap() as usize])
+    }
+}
+
+/// What the client has seen
+#[derive(Debug, Default)]
+struct Client {
+    compositor: Option<WlCompositor>,
+    shm: Option<WlShm>,
+    wm_base: Option<XdgWmBase>,
+    seat: Option<WlSeat>,
+    configured: bool,
+    toplevel_size: Option<(i32, i32)>,
+    closed: bool,
+    released: bool,
+    frames: usize,
+    pointer: Vec<(f64, f64)>,
+    keys: Vec<(u32, wl_keyboard::KeyState)>,
+    keyboard_entered: bool,
+    keymap_format: Option<wl_keyboard::KeymapFormat>,
+}
+
+impl Dispatch<WlRegistry, ()> for Client {
+    fn event(
+        state: &mut Self,
+        registry: &WlRegistry,
+        event: wl_registry::Event,
+        _: &(),
+        _: &wayland_client::Connection,
+        qh: &QueueHandle<Self>,
+    ) {
+        if let wl_registry::Event::Global {
+            name, interface, ..
+        } = event
+        {
+            match &*interface {
+                "wl_compositor" => state.compositor = Some(registry.bind(name, 4, qh, ())),
+                "wl_shm" => state.shm = Some(registry.bind(name, 1, qh, ())),
+                "xdg_wm_base" => state.wm_base = Some(registry.bind(name, 1, qh, ())),
+                "wl_seat" => state.seat = Some(registry.bind(name, 5, qh, ())),
+                _ => {}
+            }
+        }
+    }
+}
+
+impl Dispatch<XdgWmBase, ()> for Client {
+    fn event(
+        _: &mut Self,
+        wm_base: &XdgWmBase,
+        event: xdg_wm_base::Event,
+        _: &(),
+        _: &wayland_client::Connection,
+        _: &QueueHandle<Self>,
+    ) {
+        if let xdg_wm_base::Event::Ping { serial } = event {
+            wm_base.pong(serial)
+        }
+    }
+}
+
+impl Dispatch<XdgSurface, ()> for Client {
+    fn event(
+        state: &mut Self,
+        xdg_surface: &XdgSurface,
+        event: xdg_surface::Event,
+        _: &(),
+        _: &wayland_client::Connection,
+        _: &QueueHandle<Self>,
+    ) {
+        if let xdg_surface::Event::Configure { serial } = event {
+            xdg_surface.ack_configure(serial);
+            state.configured = true
+        }
+    }
+}
+
+impl Dispatch<XdgToplevel, ()> for Client {
+    fn event(
+        state: &mut Self,
+        _: &XdgToplevel,
+        event: xdg_toplevel::Event,
+        _: &(),
+        _: &wayland_client::Connection,
+        _: &QueueHandle<Self>,
+    ) {
+        match event {
+            xdg_toplevel::Event::Configure { width, height, .. } => {
+                state.toplevel_size = Some((width, height))
+            }
+            xdg_toplevel::Event::Close => state.closed = true,
+            _ => {}
+        }
+    }
+}
+
+impl Dispatch<WlBuffer, ()> for Client {
+    fn event(
+        state: &mut Self,
+        _: &WlBuffer,
+        event: wl_buffer::Event,
+        _: &(),
+        _: &wayland_client::Connection,
+        _: &QueueHandle<Self>,
+    ) {
+        if let wl_buffer::Event::Release = event {
+            state.released = true
+        }
+    }
+}
+
+impl Dispatch<WlCallback, ()> for Client {
+    fn event(
+        state: &mut Self,
+        _: &WlCallback,
+        event: wl_callback::Event,
+        _: &(),
+        _: &wayland_client::Connection,
+        _: &QueueHandle<Self>,
+    ) {
+        if let wl_callback::Event::Done { .. } = event {
+            state.frames += 1
+        }
+    }
+}
+
+impl Dispatch<WlSeat, ()> for Client {
+    fn event(
+        _: &mut Self,
+        seat: &WlSeat,
+        event: wl_seat::Event,
+        _: &(),
+        _: &wayland_client::Connection,
+        qh: &QueueHandle<Self>,
+    ) {
+        if let wl_seat::Event::Capabilities {
+            capabilities: WEnum::Value(capabilities),
+        } = event
+        {
+            if capabilities.contains(wl_seat::Capability::Pointer) {
+                seat.get_pointer(qh, ());
+            }
+            if capabilities.contains(wl_seat::Capability::Keyboard) {
+                seat.get_keyboard(qh, ());
+            }
+        }
+    }
+}
+
+impl Dispatch<WlPointer, ()> for Client {
+    fn event(
+        state: &mut Self,
+        _: &WlPointer,
+        event: wl_pointer::Event,
+        _: &(),
+        _: &wayland_client::Connection,
+        _: &QueueHandle<Self>,
+    ) {
+        match event {
+            wl_pointer::Event::Enter {
+                surface_x,
+                surface_y,
+                ..
+            }
+            | wl_pointer::Event::Motion {
+                surface_x,
+                surface_y,
+                ..
+            } => state.pointer.push((surface_x, surface_y)),
+            _ => {}
+        }
+    }
+}
+
+impl Dispatch<WlKeyboard, ()> for Client {
+    fn event(
+        state: &mut Self,
+        _: &WlKeyboard,
+        event: wl_keyboard::Event,
+        _: &(),
+        _: &wayland_client::Connection,
+        _: &QueueHandle<Self>,
+    ) {
+        match event {
+            wl_keyboard::Event::Keymap {
+                format: WEnum::Value(format),
+                ..
+            } => state.keymap_format = Some(format),
+            wl_keyboard::Event::Enter { .. } => state.keyboard_entered = true,
+            wl_keyboard::Event::Key {
+                key,
+                state: WEnum::Value(key_state),
+                ..
+            } => state.keys.push((key, key_state)),
+            _ => {}
+        }
+    }
+}
+
+delegate_noop!(Client: ignore WlCompositor);
+delegate_noop!(Client: ignore WlShm);
+delegate_noop!(Client: ignore WlShmPool);
+delegate_noop!(Client: ignore WlSurface);
+
+/// A compositor, a daemon, and a client, all on one thread
+struct Harness {
+    compositor: Compositor<Memory>,
+    daemon: Connection,
+    /// Type and window of every message the daemon received
+    received: Vec<(u32, u32, Vec<u8>)>,
+    connection: wayland_client::Connection,
+    queue: wayland_client::EventQueue<Client>,
+    client: Client,
+}
+
+impl Harness {
+    fn new() -> Self {
+        let (ours, theirs) = LoopbackTransport::pair();
+        let daemon = Connection::daemon_over(theirs, Default::default());
+        let agent = Agent::new(Connection::agent_over(ours));
+        let mut compositor = Compositor::new(agent, Memory::default()).unwrap();
+        let (client_stream, server_stream) = UnixStream::pair().unwrap();
+        compositor.insert_client(server_stream).unwrap();
+        let connection = wayland_client::Connection::from_socket(client_stream).unwrap();
+        let queue = connection.new_event_queue();
+        connection.display().get_registry(&queue.handle(), ());
+        let mut harness = Self {
+            compositor,
+            daemon,
+            received: vec![],
+            connection,
+            queue,
+            client: Client::default(),
+        };
+        harness.pump();
+        harness
+    }
+
+    /// Run everything until nothing happens
+    fn pump(&mut self) {
+        for _ in 0..10 {
+            self.connection.flush().unwrap();
+            self.compositor.dispatch().unwrap();
+            while let Poll::Ready(message) = self.daemon.read_message() {
+                let message = message.unwrap();
+                let (header, body) = (message.hdr(), message.body().to_vec());
+                let window = header.untrusted_window().window.map_or(0, u32::from);
+                self.received.push((header.ty(), window, body))
+            }
+            if let Some(guard) = self.queue.prepare_read() {
+                match guard.read() {
+                    Ok(_) => {}
+                    Err(wayland_client::backend::WaylandError::Io(e))
+                        if e.kind() == io::ErrorKind::WouldBlock => {}
+                    Err(e) => panic!("{}", e),
+                }
+            }
+            self.queue.dispatch_pending(&mut self.client).unwrap();
+        }
+    }
+
+    /// Types of the messages the daemon received, which are then forgotten
+    fn types(&mut self) -> Vec<u32> {
+        self.received.drain(..).map(|(ty, _, _)| ty).collect()
+    }
+
+    /// Pretend to be the daemon and send `message` to `window`
+    fn send<T: qubes_gui::DaemonMessage>(&mut self, window: NonZeroU32, message: &T) {
+        self.daemon.send_daemon(message, window.into()).unwrap()
+    }
+}
+
+/// A 4x3 `xrgb8888` buffer whose pixels are their own index, with alpha set
+fn buffer(harness: &Harness) -> WlBuffer {
+    let fd = rustix::fs::memfd_create("test", rustix::fs::MemfdFlags::CLOEXEC).unwrap();
+    let mut file = std::fs::File::from(fd);
+    for i in 0..12u32 {
+        file.write_all(&(0xff00_0000 | i).to_le_bytes()).unwrap()
+    }
+    let qh = harness.queue.handle();
+    let shm = harness.client.shm.as_ref().unwrap();
+    let pool = shm.create_pool(file.as_fd(), 48, &qh, ());
+    let buffer = pool.create_buffer(0, 4, 3, 16, Format::Xrgb8888, &qh, ());
+    pool.destroy();
+    buffer
+}
+
+#[test]
+fn toplevel() {
+    let mut harness = Harness::new();
+    let qh = harness.queue.handle();
+    assert_eq!(
+        harness.client.keymap_format,
+        Some(wl_keyboard::KeymapFormat::NoKeymap)
+    );
+    let surface = harness
+        .client
+        .compositor
+        .as_ref()
+        .unwrap()
+        .create_surface(&qh, ());
+    let wm_base = harness.client.wm_base.as_ref().unwrap();
+    let xdg_surface = wm_base.get_xdg_surface(&surface, &qh, ());
+    let toplevel = xdg_surface.get_toplevel(&qh, ());
+    toplevel.set_title("Hello".to_owned());
+    surface.commit();
+    harness.pump();
+    assert!(harness.client.configured);
+    assert!(harness.types().is_empty(), "nothing shown before a buffer");
+
+    let buffer = buffer(&harness);
+    surface.attach(Some(&buffer), 0, 0);
+    surface.damage_buffer(0, 0, 4, 3);
+    surface.frame(&qh, ());
+    surface.commit();
+    harness.pump();
+    assert!(harness.client.released);
+    assert_eq!(harness.client.frames, 1);
+    let window = NonZeroU32::new(1).unwrap();
+    let size = WindowSize {
+        width: 4,
+        height: 3,
+    };
+    assert_eq!(
+        harness.compositor.state.memory.allocations,
+        [(window, size)]
+    );
+    assert_eq!(
+        harness.received.iter().map(|m| m.1).collect::<Vec<_>>(),
+        [1; 4]
+    );
+    assert_eq!(
+        harness.types(),
+        [
+            qubes_gui::MSG_CREATE,
+            qubes_gui::MSG_SET_TITLE,
+            qubes_gui::MSG_SHMIMAGE,
+            qubes_gui::MSG_MAP,
+        ]
+    );
+    let pixels = harness.compositor.state.windows.get_mut(&window).unwrap();
+    let pixels = pixels.pixels.as_mut().unwrap().buffer_mut().to_vec();
+    assert_eq!(pixels, (0..12).collect::<Vec<u32>>());
+
+    // Input goes to the client
+    let motion = qubes_gui::Motion {
+        coordinates: Coordinates { x: 1, y: 2 },
+        state: 0,
+        is_hint: 0,
+    };
+    harness.send(window, &motion);
+    let keypress = qubes_gui::Keypress {
+        ty: qubes_gui::EV_KEY_PRESS,
+        coordinates: Coordinates { x: 1, y: 2 },
+        state: 0,
+        keycode: 38,
+    };
+    harness.send(window, &keypress);
+    harness.pump();
+    assert_eq!(harness.client.pointer, [(1.0, 2.0)]);
+    assert!(harness.client.keyboard_entered);
+    assert_eq!(harness.client.keys, [(30, wl_keyboard::KeyState::Pressed)]);
+
+    // A move is acknowledged at once, but a resize goes to the client
+    let configure = qubes_gui::Configure {
+        rectangle: Rectangle {
+            top_left: Coordinates { x: 10, y: 20 },
+            size: WindowSize {
+                width: 8,
+                height: 6,
+            },
+        },
+        override_redirect: 0,
+    };
+    harness.send(window, &configure);
+    let close = qubes_gui::MSG_CLOSE;
+    harness.daemon.send_raw(&[], window.into(), close).unwrap();
+    harness.pump();
+    assert_eq!(harness.client.toplevel_size, Some((8, 6)));
+    assert!(harness.client.closed);
+    let (ty, _, body) = harness.received.pop().unwrap();
+    assert_eq!(ty, qubes_gui::MSG_CONFIGURE);
+    let acked = qubes_gui::Configure::from_bytes(&body);
+    assert_eq!(acked.rectangle.top_left, Coordinates { x: 10, y: 20 });
+    assert_eq!(acked.rectangle.size, size);
+
+    toplevel.destroy();
+    xdg_surface.destroy();
+    surface.destroy();
+    harness.pump();
+    assert_eq!(harness.types(), [qubes_gui::MSG_DESTROY]);
+    assert!(harness.compositor.state.windows.is_empty());
+}
+
+#[test]
+fn keymap() {
+    let (ours, _theirs) = LoopbackTransport::pair();
+    let agent = Agent::new(Connection::agent_over(ours));
+    let compositor = Compositor::new(agent, Memory::default()).unwrap();
+    let compositor = compositor.keymap("xkb_keymap {};");
+    assert_eq!(compositor.state.keymap.as_deref(), Some("xkb_keymap {};"));
+    assert!(compositor.socket_name().is_none());
+}
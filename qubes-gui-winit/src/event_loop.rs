@@ -0,0 +1,481 @@
+/*
+ * The Qubes OS Project, https://www.qubes-os.org
+ *
+ * Copyright (C) 2021  Demi Marie Obenour  <demi@invisiblethingslab.com>
+ *
+ * This program is free software; you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License
+ * as published by the Free Software Foundation; either version 2
+ * of the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301, USA.
+ *
+ */
+
+//! The event loop, and the windows it manages
+
+use crate::input::Inbox;
+use crate::{qubes_window, window_id, KeyboardInput};
+use qubes_gui::{Coordinates, Rectangle, WindowSize};
+use qubes_gui_agent_proto::Modifiers;
+use qubes_gui_connection::{Agent, GrantAllocator, ResizableSurface};
+use std::collections::{BTreeMap, BTreeSet};
+use std::io;
+use std::num::NonZeroU32;
+use winit::dpi::{PhysicalPosition, PhysicalSize, Position, Size};
+use winit::event::WindowEvent;
+use winit::keyboard::ModifiersState;
+use winit::window::{WindowAttributes, WindowId};
+
+/// Size of a window created without an inner size, as in winit
+const DEFAULT_SIZE: WindowSize = WindowSize {
+    width: 800,
+    height: 600,
+};
+
+/// Handles the events of an [`EventLoop`].  This follows winit's
+/// `ApplicationHandler`.
+#[allow(unused_variables)]
+pub trait ApplicationHandler<A: GrantAllocator> {
+    /// The connection to the daemon is ready.  This is called once, and is
+    /// where the first windows should be created.
+    fn resumed(&mut self, event_loop: &mut ActiveEventLoop<A>);
+
+    /// `event` happened to `window`
+    fn window_event(
+        &mut self,
+        event_loop: &mut ActiveEventLoop<A>,
+        window: WindowId,
+        event: WindowEvent,
+    );
+
+    /// A key was pressed or released in `window`.  This takes the place of
+    /// [`WindowEvent::KeyboardInput`].
+    fn keyboard_input(
+        &mut self,
+        event_loop: &mut ActiveEventLoop<A>,
+        window: WindowId,
+        input: KeyboardInput,
+    ) {
+    }
+
+    /// Every pending event has been handled, and the event loop is about to
+    /// wait for more
+    fn about_to_wait(&mut self, event_loop: &mut ActiveEventLoop<A>) {}
+}
+
+/// A window created by [`ActiveEventLoop::create_window`]
+pub(crate) struct Window<A: GrantAllocator> {
+    pub(crate) surface: ResizableSurface<A>,
+    /// Where the window is, as the application was last told
+    pub(crate) rectangle: Rectangle,
+}
+
+/// The state of an [`EventLoop`], which is passed to each callback of an
+/// [`ApplicationHandler`]
+pub struct ActiveEventLoop<A: GrantAllocator> {
+    pub(crate) agent: Agent,
+    pub(crate) windows: BTreeMap<NonZeroU32, Window<A>>,
+    next_window: u32,
+    /// Windows that are to get [`WindowEvent::RedrawRequested`]
+    pub(crate) redraws: BTreeSet<NonZeroU32>,
+    /// Windows that are to get [`WindowEvent::Destroyed`]
+    destroyed: Vec<NonZeroU32>,
+    pub(crate) modifiers: Modifiers,
+    /// The modifiers the application was last told about
+    pub(crate) reported_modifiers: ModifiersState,
+    /// The window the pointer is in
+    pub(crate) pointer: Option<NonZeroU32>,
+    pub(crate) resumed: bool,
+    exiting: bool,
+}
+
+fn no_such_window(window: WindowId) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("Window {} does not exist", u64::from(window)),
+    )
+}
+
+fn window_size(size: Size) -> WindowSize {
+    let size: PhysicalSize<u32> = size.to_physical(1.0);
+    WindowSize {
+        width: size.width.max(1),
+        height: size.height.max(1),
+    }
+}
+
+fn coordinates(position: Position) -> Coordinates {
+    let position: PhysicalPosition<i32> = position.to_physical(1.0);
+    Coordinates {
+        x: position.x,
+        y: position.y,
+    }
+}
+
+impl<A: GrantAllocator> ActiveEventLoop<A> {
+    /// The agent.  Windows created directly with the agent must not use IDs
+    /// that [`ActiveEventLoop::create_window`] might pick, and get no
+    /// events.
+    pub fn agent(&mut self) -> &mut Agent {
+        &mut self.agent
+    }
+
+    /// An unused window ID
+    fn new_window(&mut self) -> NonZeroU32 {
+        loop {
+            self.next_window = self.next_window.wrapping_add(1);
+            if let Some(window) = NonZeroU32::new(self.next_window) {
+                if self.agent.window(window).is_none() && !self.agent.destroy_pending(window) {
+                    break window;
+                }
+            }
+        }
+    }
+
+    /// The Qubes window of one of the windows of the event loop
+    fn id(&self, window: WindowId) -> io::Result<NonZeroU32> {
+        qubes_window(window)
+            .filter(|id| self.windows.contains_key(id))
+            .ok_or_else(|| no_such_window(window))
+    }
+
+    /// Create a window, with a buffer from `allocator`, and ask for it to be
+    /// redrawn.  This sends `MSG_CREATE`, the title, the buffer,
+    /// `MSG_CONFIGURE`, and, if the window is visible, `MSG_MAP`.  Of the
+    /// attributes, only the inner size, position, title, and visibility are
+    /// used.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the title contains a NUL byte, or if allocation or sending
+    /// fails.
+    pub fn create_window(
+        &mut self,
+        attributes: WindowAttributes,
+        allocator: A,
+    ) -> io::Result<WindowId> {
+        let rectangle = Rectangle {
+            top_left: attributes
+                .position
+                .map_or_else(Default::default, coordinates),
+            size: attributes.inner_size.map_or(DEFAULT_SIZE, window_size),
+        };
+        let window = self.new_window();
+        let create = qubes_gui::Create {
+            rectangle,
+            parent: None,
+            override_redirect: 0,
+        };
+        self.agent.create_window(window, &create)?;
+        let res = self.show(window, rectangle, &attributes, allocator);
+        if res.is_err() {
+            // The error being returned says more than this one would
+            let _ = self.agent.destroy(window);
+        }
+        res
+    }
+
+    fn show(
+        &mut self,
+        window: NonZeroU32,
+        rectangle: Rectangle,
+        attributes: &WindowAttributes,
+        allocator: A,
+    ) -> io::Result<WindowId> {
+        if !attributes.title.is_empty() {
+            self.agent.set_title(window, &attributes.title)?;
+        }
+        let connection = self.agent.connection();
+        let surface = ResizableSurface::new(connection, window, rectangle.size, allocator)?;
+        let configure = qubes_gui::Configure {
+            rectangle,
+            override_redirect: 0,
+        };
+        self.agent.configure(window, &configure)?;
+        if attributes.visible {
+            let info = qubes_gui::MapInfo {
+                transient_for: 0,
+                override_redirect: 0,
+            };
+            self.agent.map(window, &info)?;
+        }
+        self.windows.insert(window, Window { surface, rectangle });
+        self.redraws.insert(window);
+        Ok(window_id(window))
+    }
+
+    /// Destroy `window`.  It gets [`WindowEvent::Destroyed`], and then no
+    /// more events.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the window does not exist, or if sending fails.
+    pub fn destroy_window(&mut self, window: WindowId) -> io::Result<()> {
+        let id = self.id(window)?;
+        self.windows.remove(&id);
+        self.redraws.remove(&id);
+        if self.pointer == Some(id) {
+            self.pointer = None
+        }
+        self.destroyed.push(id);
+        self.agent.destroy(id)
+    }
+
+    /// The size of the buffer of `window`, or `None` if it does not exist
+    pub fn inner_size(&self, window: WindowId) -> Option<PhysicalSize<u32>> {
+        let state = self.windows.get(&qubes_window(window)?)?;
+        let size = state.rectangle.size;
+        Some(PhysicalSize::new(size.width, size.height))
+    }
+
+    /// The position of `window`, or `None` if it does not exist
+    pub fn outer_position(&self, window: WindowId) -> Option<PhysicalPosition<i32>> {
+        let state = self.windows.get(&qubes_window(window)?)?;
+        let top_left = state.rectangle.top_left;
+        Some(PhysicalPosition::new(top_left.x, top_left.y))
+    }
+
+    /// Move and resize `window` to `rectangle`, and return where it was.
+    /// A new size needs a new buffer, and a redraw.
+    pub(crate) fn configure(
+        &mut self,
+        window: NonZeroU32,
+        rectangle: Rectangle,
+    ) -> io::Result<Rectangle> {
+        let state = match self.windows.get_mut(&window) {
+            Some(state) => state,
+            None => return Err(no_such_window(window_id(window))),
+        };
+        let old = state.rectangle;
+        if old.size != rectangle.size {
+            state
+                .surface
+                .resize(self.agent.connection(), rectangle.size)?;
+            self.redraws.insert(window);
+        }
+        state.rectangle = rectangle;
+        let configure = qubes_gui::Configure {
+            rectangle,
+            override_redirect: 0,
+        };
+        self.agent.configure(window, &configure)?;
+        Ok(old)
+    }
+
+    /// Resize `window` to `size`, and ask for it to be redrawn if its size
+    /// changes.  The new size takes effect at once, so there is no
+    /// [`WindowEvent::Resized`].
+    ///
+    /// # Errors
+    ///
+    /// Fails if the window does not exist, or if allocation or sending
+    /// fails.
+    pub fn request_inner_size(
+        &mut self,
+        window: WindowId,
+        size: impl Into<Size>,
+    ) -> io::Result<()> {
+        let id = self.id(window)?;
+        let rectangle = Rectangle {
+            top_left: self.windows[&id].rectangle.top_left,
+            size: window_size(size.into()),
+        };
+        self.configure(id, rectangle).map(drop)
+    }
+
+    /// Move `window` to `position`.  There is no [`WindowEvent::Moved`].
+    ///
+    /// # Errors
+    ///
+    /// Fails if the window does not exist, or if sending fails.
+    pub fn set_outer_position(
+        &mut self,
+        window: WindowId,
+        position: impl Into<Position>,
+    ) -> io::Result<()> {
+        let id = self.id(window)?;
+        let rectangle = Rectangle {
+            top_left: coordinates(position.into()),
+            size: self.windows[&id].rectangle.size,
+        };
+        self.configure(id, rectangle).map(drop)
+    }
+
+    /// Set the title of `window`.  Titles longer than 127 bytes are
+    /// truncated, at a character boundary.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the window does not exist, if the title contains a NUL byte,
+    /// or if sending fails.
+    pub fn set_title(&mut self, window: WindowId, title: &str) -> io::Result<()> {
+        let id = self.id(window)?;
+        self.agent.set_title(id, title)
+    }
+
+    /// Show or hide `window`
+    ///
+    /// # Errors
+    ///
+    /// Fails if the window does not exist, or if sending fails.
+    pub fn set_visible(&mut self, window: WindowId, visible: bool) -> io::Result<()> {
+        let id = self.id(window)?;
+        let mapped = self.agent.window(id).is_some_and(|info| info.mapped);
+        match (visible, mapped) {
+            (true, false) => {
+                let info = qubes_gui::MapInfo {
+                    transient_for: 0,
+                    override_redirect: 0,
+                };
+                self.agent.map(id, &info)
+            }
+            (false, true) => self.agent.unmap(id),
+            _ => Ok(()),
+        }
+    }
+
+    /// Ask for [`WindowEvent::RedrawRequested`] to be sent to `window`.
+    /// Windows that do not exist are ignored.
+    pub fn request_redraw(&mut self, window: WindowId) {
+        if let Some(id) = qubes_window(window).filter(|id| self.windows.contains_key(id)) {
+            self.redraws.insert(id);
+        }
+    }
+
+    /// Draw into `window` with `draw`, which gets the pixels of its buffer
+    /// and the size of the buffer, and tell the daemon that the whole
+    /// window changed.  See [`Surface`](qubes_gui_connection::Surface) for
+    /// the pixel format.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the window does not exist, or if sending fails.
+    pub fn draw(
+        &mut self,
+        window: WindowId,
+        draw: impl FnOnce(&mut [u32], WindowSize),
+    ) -> io::Result<()> {
+        self.present(window, None, draw)
+    }
+
+    /// Like [`ActiveEventLoop::draw`], but only tell the daemon that
+    /// `damage` changed
+    ///
+    /// # Errors
+    ///
+    /// Fails if the window does not exist, or if sending fails.
+    pub fn draw_with_damage(
+        &mut self,
+        window: WindowId,
+        damage: &[Rectangle],
+        draw: impl FnOnce(&mut [u32], WindowSize),
+    ) -> io::Result<()> {
+        self.present(window, Some(damage), draw)
+    }
+
+    fn present(
+        &mut self,
+        window: WindowId,
+        damage: Option<&[Rectangle]>,
+        draw: impl FnOnce(&mut [u32], WindowSize),
+    ) -> io::Result<()> {
+        let id = self.id(window)?;
+        let surface = match self.windows.get_mut(&id) {
+            Some(state) => state.surface.surface_mut(),
+            None => return Err(no_such_window(window)),
+        };
+        let size = surface.size();
+        let mut buffer = surface.buffer_mut();
+        draw(&mut buffer, size);
+        match damage {
+            Some(damage) => buffer.present_with_damage(self.agent.connection(), damage),
+            None => buffer.present(self.agent.connection()),
+        }
+    }
+
+    /// Stop the event loop once the current events have been handled
+    pub fn exit(&mut self) {
+        self.exiting = true
+    }
+
+    /// Has [`ActiveEventLoop::exit`] been called?
+    pub fn exiting(&self) -> bool {
+        self.exiting
+    }
+}
+
+/// An event loop that delivers the events of Qubes windows in the way winit
+/// does.  See the crate documentation.
+pub struct EventLoop<A: GrantAllocator> {
+    active: ActiveEventLoop<A>,
+}
+
+impl<A: GrantAllocator> EventLoop<A> {
+    /// Deliver the events that `agent` receives
+    pub fn new(agent: Agent) -> Self {
+        Self {
+            active: ActiveEventLoop {
+                agent,
+                windows: BTreeMap::new(),
+                next_window: 0,
+                redraws: BTreeSet::new(),
+                destroyed: vec![],
+                modifiers: Modifiers::new(),
+                reported_modifiers: ModifiersState::empty(),
+                pointer: None,
+                resumed: false,
+                exiting: false,
+            },
+        }
+    }
+
+    /// Deliver everything that can be delivered without blocking: the
+    /// events from the daemon, then [`WindowEvent::Destroyed`] and
+    /// [`WindowEvent::RedrawRequested`], and finally
+    /// [`ApplicationHandler::about_to_wait`].
+    ///
+    /// # Errors
+    ///
+    /// Fails on I/O errors.  Events not yet delivered are dropped.
+    pub fn pump_app_events<H: ApplicationHandler<A>>(&mut self, app: &mut H) -> io::Result<()> {
+        let active = &mut self.active;
+        let mut inbox = Inbox::default();
+        active.agent.dispatch(&mut inbox)?;
+        inbox.deliver(active, app)?;
+        for window in std::mem::take(&mut active.destroyed) {
+            app.window_event(active, window_id(window), WindowEvent::Destroyed)
+        }
+        for window in std::mem::take(&mut active.redraws) {
+            if active.windows.contains_key(&window) {
+                app.window_event(active, window_id(window), WindowEvent::RedrawRequested)
+            }
+        }
+        app.about_to_wait(active);
+        Ok(())
+    }
+
+    /// Deliver events until [`ActiveEventLoop::exit`] is called
+    ///
+    /// # Errors
+    ///
+    /// Fails if [`EventLoop::pump_app_events`] does.
+    pub fn run_app<H: ApplicationHandler<A>>(&mut self, app: &mut H) -> io::Result<()> {
+        loop {
+            self.pump_app_events(app)?;
+            if self.active.exiting {
+                break Ok(());
+            }
+            if self.active.redraws.is_empty() && self.active.destroyed.is_empty() {
+                self.active.agent.connection().wait()
+            }
+        }
+    }
+}
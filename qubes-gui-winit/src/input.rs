@@ -0,0 +1,394 @@
+/*
+ * The Qubes OS Project, https://www.qubes-os.org
+ *
+ * Copyright (C) 2021  Demi Marie Obenour  <demi@invisiblethingslab.com>
+ *
+ * This program is free software; you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License
+ * as published by the Free Software Foundation; either version 2
+ * of the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301, USA.
+ *
+ */
+
+//! Input from the GUI daemon, as winit events
+
+use crate::event_loop::{ActiveEventLoop, ApplicationHandler};
+use crate::window_id;
+use qubes_gui::x11::{keycode_to_evdev, MouseButton as X11Button};
+use qubes_gui::{
+    ButtonEvent, Coordinates, FocusEvent, KeyEvent, KeymapNotify, Rectangle, WindowID,
+};
+use qubes_gui_agent_proto::{TrustedButton, TrustedCrossing, TrustedFocus, TrustedKeypress};
+use qubes_gui_connection::{Extension, GrantAllocator, MessageHandler};
+use std::convert::TryFrom;
+use std::io;
+use std::num::NonZeroU32;
+use winit::dpi::{PhysicalPosition, PhysicalSize};
+use winit::event::{
+    DeviceId, ElementState, MouseButton, MouseScrollDelta, TouchPhase, WindowEvent,
+};
+use winit::keyboard::{ModifiersState, PhysicalKey};
+use winit::platform::scancode::PhysicalKeyExtScancode as _;
+
+/// The only input device there is
+const DEVICE: DeviceId = DeviceId::dummy();
+
+/// A key was pressed or released.  This takes the place of winit's
+/// `KeyEvent`, which cannot be built outside winit.  Only the physical key
+/// is known: turning it into text needs the keymap of the user's keyboard.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct KeyboardInput {
+    /// The key, by its position on the keyboard
+    pub physical_key: PhysicalKey,
+    /// Whether the key was pressed or released
+    pub state: ElementState,
+}
+
+/// An event from the daemon, after validation
+#[derive(Debug)]
+enum Input {
+    Keypress(NonZeroU32, TrustedKeypress),
+    Button(NonZeroU32, TrustedButton),
+    Motion(NonZeroU32, qubes_gui::Motion),
+    Crossing(NonZeroU32, TrustedCrossing),
+    Focus(NonZeroU32, TrustedFocus),
+    Keymap(NonZeroU32, KeymapNotify),
+    Configure(NonZeroU32, Rectangle),
+    Close(NonZeroU32),
+    DumpAck(NonZeroU32),
+    Downgraded(Extension),
+    Reconnected,
+}
+
+/// Collects events from the daemon, as they cannot be handled while the
+/// agent is borrowed.  Events that fail validation, or that are for the
+/// whole screen, are dropped.
+#[derive(Debug, Default)]
+pub(crate) struct Inbox(Vec<Input>);
+
+impl Inbox {
+    fn push<T>(&mut self, window: WindowID, event: Option<T>, f: fn(NonZeroU32, T) -> Input) {
+        if let (Some(window), Some(event)) = (window.window, event) {
+            self.0.push(f(window, event))
+        }
+    }
+
+    /// Deliver the collected events to `app`.  Events for windows that the
+    /// event loop did not create are dropped.
+    pub(crate) fn deliver<A: GrantAllocator, H: ApplicationHandler<A>>(
+        self,
+        event_loop: &mut ActiveEventLoop<A>,
+        app: &mut H,
+    ) -> io::Result<()> {
+        for input in self.0 {
+            match input {
+                Input::Keypress(window, keypress) => {
+                    event_loop.modifiers.keypress(&keypress);
+                    modifiers(event_loop, app, window);
+                    key(event_loop, app, window, &keypress)
+                }
+                Input::Button(window, button) => {
+                    event_loop.modifiers.button(&button);
+                    modifiers(event_loop, app, window);
+                    pointer_enter(event_loop, app, window, button.coordinates());
+                    pointer_button(event_loop, app, window, &button)
+                }
+                Input::Motion(window, motion) => {
+                    event_loop.modifiers.motion(&motion);
+                    modifiers(event_loop, app, window);
+                    if event_loop.pointer == Some(window) {
+                        let position = position(motion.coordinates);
+                        let event = WindowEvent::CursorMoved {
+                            device_id: DEVICE,
+                            position,
+                        };
+                        send(event_loop, app, window, event)
+                    } else {
+                        pointer_enter(event_loop, app, window, motion.coordinates)
+                    }
+                }
+                Input::Crossing(window, crossing) => {
+                    event_loop.modifiers.crossing(&crossing);
+                    modifiers(event_loop, app, window);
+                    if crossing.entered() {
+                        pointer_enter(event_loop, app, window, crossing.coordinates())
+                    } else if event_loop.pointer == Some(window) {
+                        pointer_leave(event_loop, app)
+                    }
+                }
+                Input::Focus(window, focus) => {
+                    let focused = focus.event() == FocusEvent::In;
+                    send(event_loop, app, window, WindowEvent::Focused(focused))
+                }
+                Input::Keymap(window, keymap) => {
+                    event_loop.modifiers.keymap(&keymap);
+                    modifiers(event_loop, app, window)
+                }
+                Input::Configure(window, rectangle) => {
+                    configure(event_loop, app, window, rectangle)?
+                }
+                Input::Close(window) => send(event_loop, app, window, WindowEvent::CloseRequested),
+                Input::DumpAck(window) => {
+                    if let Some(state) = event_loop.windows.get_mut(&window) {
+                        state
+                            .surface
+                            .dump_acknowledged(event_loop.agent.connection())?
+                    }
+                }
+                Input::Downgraded(extension) => {
+                    for state in event_loop.windows.values_mut() {
+                        state
+                            .surface
+                            .downgraded(event_loop.agent.connection(), extension)?
+                    }
+                }
+                Input::Reconnected => reconnected(event_loop, app)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl MessageHandler for Inbox {
+    fn on_keypress(&mut self, window: WindowID, keypress: &qubes_gui::Keypress) {
+        let keypress = TrustedKeypress::validate(keypress).ok();
+        self.push(window, keypress, Input::Keypress)
+    }
+
+    fn on_button(&mut self, window: WindowID, button: &qubes_gui::Button) {
+        let button = TrustedButton::validate(button).ok();
+        self.push(window, button, Input::Button)
+    }
+
+    fn on_motion(&mut self, window: WindowID, motion: &qubes_gui::Motion) {
+        self.push(window, Some(*motion), Input::Motion)
+    }
+
+    fn on_crossing(&mut self, window: WindowID, crossing: &qubes_gui::Crossing) {
+        let crossing = TrustedCrossing::validate(crossing).ok();
+        self.push(window, crossing, Input::Crossing)
+    }
+
+    fn on_focus(&mut self, window: WindowID, focus: &qubes_gui::Focus) {
+        let focus = TrustedFocus::validate(focus).ok();
+        self.push(window, focus, Input::Focus)
+    }
+
+    fn on_keymap(&mut self, window: WindowID, keymap: &KeymapNotify) {
+        self.push(window, Some(*keymap), Input::Keymap)
+    }
+
+    fn on_configure(&mut self, window: WindowID, configure: &qubes_gui::Configure) {
+        self.push(window, Some(configure.rectangle), Input::Configure)
+    }
+
+    fn on_close(&mut self, window: WindowID) {
+        self.push(window, Some(()), |window, ()| Input::Close(window))
+    }
+
+    fn on_dump_ack(&mut self, window: WindowID) {
+        self.push(window, Some(()), |window, ()| Input::DumpAck(window))
+    }
+
+    fn on_downgraded(&mut self, extension: Extension) {
+        self.0.push(Input::Downgraded(extension))
+    }
+
+    fn on_reconnected(&mut self, _xconf: &qubes_gui::XConfVersion) {
+        self.0.push(Input::Reconnected)
+    }
+}
+
+/// Send `event` to `window`, if the event loop created it and it has not
+/// been destroyed since
+fn send<A: GrantAllocator, H: ApplicationHandler<A>>(
+    event_loop: &mut ActiveEventLoop<A>,
+    app: &mut H,
+    window: NonZeroU32,
+    event: WindowEvent,
+) {
+    if event_loop.windows.contains_key(&window) {
+        app.window_event(event_loop, window_id(window), event)
+    }
+}
+
+fn position(at: Coordinates) -> PhysicalPosition<f64> {
+    PhysicalPosition::new(at.x.into(), at.y.into())
+}
+
+/// Tell `window` about the modifiers, if they have changed
+fn modifiers<A: GrantAllocator, H: ApplicationHandler<A>>(
+    event_loop: &mut ActiveEventLoop<A>,
+    app: &mut H,
+    window: NonZeroU32,
+) {
+    let tracked = &event_loop.modifiers;
+    let mut state = ModifiersState::empty();
+    state.set(ModifiersState::SHIFT, tracked.shift());
+    state.set(ModifiersState::CONTROL, tracked.control());
+    state.set(ModifiersState::ALT, tracked.alt());
+    state.set(ModifiersState::SUPER, tracked.super_key());
+    if state != event_loop.reported_modifiers {
+        event_loop.reported_modifiers = state;
+        send(
+            event_loop,
+            app,
+            window,
+            WindowEvent::ModifiersChanged(state.into()),
+        )
+    }
+}
+
+/// Move the pointer into `window`, unless it is already there
+fn pointer_enter<A: GrantAllocator, H: ApplicationHandler<A>>(
+    event_loop: &mut ActiveEventLoop<A>,
+    app: &mut H,
+    window: NonZeroU32,
+    at: Coordinates,
+) {
+    if event_loop.pointer == Some(window) || !event_loop.windows.contains_key(&window) {
+        return;
+    }
+    pointer_leave(event_loop, app);
+    event_loop.pointer = Some(window);
+    let event = WindowEvent::CursorEntered { device_id: DEVICE };
+    send(event_loop, app, window, event);
+    let event = WindowEvent::CursorMoved {
+        device_id: DEVICE,
+        position: position(at),
+    };
+    send(event_loop, app, window, event)
+}
+
+fn pointer_leave<A: GrantAllocator, H: ApplicationHandler<A>>(
+    event_loop: &mut ActiveEventLoop<A>,
+    app: &mut H,
+) {
+    if let Some(window) = event_loop.pointer.take() {
+        let event = WindowEvent::CursorLeft { device_id: DEVICE };
+        send(event_loop, app, window, event)
+    }
+}
+
+fn pointer_button<A: GrantAllocator, H: ApplicationHandler<A>>(
+    event_loop: &mut ActiveEventLoop<A>,
+    app: &mut H,
+    window: NonZeroU32,
+    button: &TrustedButton,
+) {
+    let pressed = button.event() == ButtonEvent::Press;
+    let mouse_button = button.mouse_button();
+    if let Some((dx, dy)) = mouse_button.scroll_delta() {
+        // Scrolling is reported as a press and a release; only one counts.
+        // winit's deltas are the other way round from X11's: scrolling up
+        // moves the content down.
+        if pressed {
+            let event = WindowEvent::MouseWheel {
+                device_id: DEVICE,
+                delta: MouseScrollDelta::LineDelta(-dx as f32, -dy as f32),
+                phase: TouchPhase::Moved,
+            };
+            send(event_loop, app, window, event)
+        }
+        return;
+    }
+    let button = match mouse_button {
+        X11Button::Left => MouseButton::Left,
+        X11Button::Right => MouseButton::Right,
+        X11Button::Middle => MouseButton::Middle,
+        X11Button::Other(8) => MouseButton::Back,
+        X11Button::Other(9) => MouseButton::Forward,
+        X11Button::Other(other) => match u16::try_from(other) {
+            Ok(other) => MouseButton::Other(other),
+            Err(_) => return,
+        },
+        _ => return,
+    };
+    let state = if pressed {
+        ElementState::Pressed
+    } else {
+        ElementState::Released
+    };
+    let event = WindowEvent::MouseInput {
+        device_id: DEVICE,
+        state,
+        button,
+    };
+    send(event_loop, app, window, event)
+}
+
+fn key<A: GrantAllocator, H: ApplicationHandler<A>>(
+    event_loop: &mut ActiveEventLoop<A>,
+    app: &mut H,
+    window: NonZeroU32,
+    keypress: &TrustedKeypress,
+) {
+    let code = match keycode_to_evdev(keypress.keycode()) {
+        Some(code) if event_loop.windows.contains_key(&window) => code,
+        _ => return,
+    };
+    let state = match keypress.event() {
+        KeyEvent::Press => ElementState::Pressed,
+        KeyEvent::Release => ElementState::Released,
+    };
+    let input = KeyboardInput {
+        physical_key: PhysicalKey::from_scancode(code),
+        state,
+    };
+    app.keyboard_input(event_loop, window_id(window), input)
+}
+
+/// Handle a `MSG_CONFIGURE`, which is acknowledged with the same geometry.
+/// The application is told about the change.
+fn configure<A: GrantAllocator, H: ApplicationHandler<A>>(
+    event_loop: &mut ActiveEventLoop<A>,
+    app: &mut H,
+    window: NonZeroU32,
+    mut rectangle: Rectangle,
+) -> io::Result<()> {
+    if !event_loop.windows.contains_key(&window) {
+        return Ok(());
+    }
+    rectangle.size.width = rectangle.size.width.max(1);
+    rectangle.size.height = rectangle.size.height.max(1);
+    let old = event_loop.configure(window, rectangle)?;
+    if old.top_left != rectangle.top_left {
+        let top_left = rectangle.top_left;
+        let position = PhysicalPosition::new(top_left.x, top_left.y);
+        send(event_loop, app, window, WindowEvent::Moved(position))
+    }
+    if old.size != rectangle.size {
+        let size = PhysicalSize::new(rectangle.size.width, rectangle.size.height);
+        send(event_loop, app, window, WindowEvent::Resized(size))
+    }
+    Ok(())
+}
+
+/// The first connection resumes the application.  After a reconnection,
+/// the agent has already told the new daemon about the windows, so only
+/// their buffers need to be shared again, and redrawn.
+fn reconnected<A: GrantAllocator, H: ApplicationHandler<A>>(
+    event_loop: &mut ActiveEventLoop<A>,
+    app: &mut H,
+) -> io::Result<()> {
+    event_loop.pointer = None;
+    if !event_loop.resumed {
+        event_loop.resumed = true;
+        app.resumed(event_loop);
+        return Ok(());
+    }
+    for (&window, state) in event_loop.windows.iter_mut() {
+        state.surface.reconnected(event_loop.agent.connection())?;
+        event_loop.redraws.insert(window);
+    }
+    Ok(())
+}
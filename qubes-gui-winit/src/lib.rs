@@ -0,0 +1,72 @@
+/*
+ * The Qubes OS Project, https://www.qubes-os.org
+ *
+ * Copyright (C) 2021  Demi Marie Obenour  <demi@invisiblethingslab.com>
+ *
+ * This program is free software; you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License
+ * as published by the Free Software Foundation; either version 2
+ * of the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301, USA.
+ *
+ */
+
+#![forbid(missing_docs)]
+#![forbid(clippy::all)]
+//! A [winit]-style event loop for Qubes windows
+//!
+//! winit chooses its platform backends when it is compiled, and has no
+//! interface for backends provided by other crates, so this crate cannot
+//! make `winit::event_loop::EventLoop` talk to the GUI daemon.  Instead,
+//! [`EventLoop`] and [`ApplicationHandler`] follow the shape of their winit
+//! counterparts, and deliver winit's own [`WindowEvent`]s, so that code
+//! written against winit needs few changes.
+//!
+//! Windows are created with [`ActiveEventLoop::create_window`], from winit
+//! [`WindowAttributes`](winit::window::WindowAttributes), which sends
+//! `MSG_CREATE`, `MSG_CONFIGURE`, and `MSG_MAP` through an
+//! [`Agent`](qubes_gui_connection::Agent).  Input from the daemon becomes
+//! [`WindowEvent`]s, except for key presses: winit's `KeyEvent` cannot be
+//! built outside winit, so they are reported as [`KeyboardInput`] instead.
+//! Each window is drawn into with [`ActiveEventLoop::draw`] after a
+//! [`WindowEvent::RedrawRequested`], and its buffer is shared with the
+//! daemon by a [`GrantAllocator`](qubes_gui_connection::GrantAllocator).
+//!
+//! All sizes and positions are in the pixels of the protocol, so the scale
+//! factor is always 1.
+//!
+//! [`WindowEvent`]: winit::event::WindowEvent
+//! [`WindowEvent::RedrawRequested`]: winit::event::WindowEvent::RedrawRequested
+//! [winit]: https://github.com/rust-windowing/winit
+
+mod event_loop;
+mod input;
+#[cfg(test)]
+mod tests;
+
+pub use event_loop::{ActiveEventLoop, ApplicationHandler, EventLoop};
+pub use input::KeyboardInput;
+pub use winit;
+
+use std::convert::TryFrom;
+use std::num::NonZeroU32;
+use winit::window::WindowId;
+
+/// The winit ID of a Qubes window
+pub fn window_id(window: NonZeroU32) -> WindowId {
+    WindowId::from(u64::from(window.get()))
+}
+
+/// The Qubes window with winit ID `id`, or `None` if `id` cannot be the ID of
+/// a Qubes window
+pub fn qubes_window(id: WindowId) -> Option<NonZeroU32> {
+    u32::try_from(u64::from(id)).ok().and_then(NonZeroU32::new)
+}
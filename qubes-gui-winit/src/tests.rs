@@ -0,0 +1,335 @@
+/*
+ * The Qubes OS Project, https://www.qubes-os.org
+ *
+ * Copyright (C) 2021  Demi Marie Obenour  <demi@invisiblethingslab.com>
+ *
+ * This program is free software; you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License
+ * as published by the Free Software Foundation; either version 2
+ * of the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301, USA.
+ *
+ */
+
+use super::*;
+use qubes_castable::Castable as _;
+use qubes_gui::{Coordinates, Rectangle, WindowSize};
+use qubes_gui_connection::{Agent, Connection, GrantAllocator, LoopbackTransport};
+use std::io;
+use std::task::Poll;
+use winit::dpi::{PhysicalPosition, PhysicalSize};
+use winit::event::WindowEvent;
+use winit::event::{DeviceId, ElementState, MouseButton, MouseScrollDelta, TouchPhase};
+use winit::keyboard::{KeyCode, ModifiersState, PhysicalKey};
+use winit::window::{WindowAttributes, WindowId};
+
+/// Buffers that are not shared with anyone
+struct Memory;
+
+impl GrantAllocator for Memory {
+    type Memory = Vec<u32>;
+
+    fn allocate(
+        &mut self,
+        _window: NonZeroU32,
+        size: WindowSize,
+    ) -> io::Result<(Vec<u32>, Vec<u32>)> {
+        let pixels = size.checked_area().unwrap() as usize;
+        Ok((vec![0; pixels], vec![0; (pixels * 4).div_ceil(4096)]))
+    }
+}
+
+/// Creates one window, draws into it when asked, and closes it when asked
+#[derive(Debug, Default)]
+struct App {
+    window: Option<WindowId>,
+    events: Vec<WindowEvent>,
+    keys: Vec<KeyboardInput>,
+    redraws: usize,
+    size: Option<PhysicalSize<u32>>,
+    exiting: bool,
+}
+
+impl ApplicationHandler<Memory> for App {
+    fn resumed(&mut self, event_loop: &mut ActiveEventLoop<Memory>) {
+        let attributes = WindowAttributes::default()
+            .with_title("Hello")
+            .with_inner_size(PhysicalSize::new(4, 3));
+        self.window = Some(event_loop.create_window(attributes, Memory).unwrap())
+    }
+
+    fn window_event(
+        &mut self,
+        event_loop: &mut ActiveEventLoop<Memory>,
+        window: WindowId,
+        event: WindowEvent,
+    ) {
+        assert_eq!(Some(window), self.window);
+        match event {
+            WindowEvent::RedrawRequested => {
+                self.redraws += 1;
+                self.size = event_loop.inner_size(window);
+                let draw = |pixels: &mut [u32], _| {
+                    for (i, pixel) in pixels.iter_mut().enumerate() {
+                        *pixel = i as u32
+                    }
+                };
+                event_loop.draw(window, draw).unwrap()
+            }
+            WindowEvent::CloseRequested => {
+                event_loop.destroy_window(window).unwrap();
+                event_loop.exit();
+                self.events.push(event)
+            }
+            event => self.events.push(event),
+        }
+    }
+
+    fn keyboard_input(
+        &mut self,
+        _event_loop: &mut ActiveEventLoop<Memory>,
+        window: WindowId,
+        input: KeyboardInput,
+    ) {
+        assert_eq!(Some(window), self.window);
+        self.keys.push(input)
+    }
+
+    fn about_to_wait(&mut self, event_loop: &mut ActiveEventLoop<Memory>) {
+        self.exiting = event_loop.exiting()
+    }
+}
+
+/// An event loop and a daemon, on one thread
+struct Harness {
+    event_loop: EventLoop<Memory>,
+    app: App,
+    daemon: Connection,
+    /// Type and body of every message the daemon received
+    received: Vec<(u32, Vec<u8>)>,
+}
+
+impl Harness {
+    fn new() -> Self {
+        let (ours, theirs) = LoopbackTransport::pair();
+        let daemon = Connection::daemon_over(theirs, Default::default());
+        let agent = Agent::new(Connection::agent_over(ours));
+        let mut harness = Self {
+            event_loop: EventLoop::new(agent),
+            app: App::default(),
+            daemon,
+            received: vec![],
+        };
+        harness.pump();
+        harness
+    }
+
+    /// Run everything until nothing happens
+    fn pump(&mut self) {
+        for _ in 0..10 {
+            self.event_loop.pump_app_events(&mut self.app).unwrap();
+            while let Poll::Ready(message) = self.daemon.read_message() {
+                let message = message.unwrap();
+                let (header, body) = (message.hdr(), message.body().to_vec());
+                self.received.push((header.ty(), body))
+            }
+        }
+    }
+
+    /// Types of the messages the daemon received, which are then forgotten
+    fn types(&mut self) -> Vec<u32> {
+        self.received.drain(..).map(|(ty, _)| ty).collect()
+    }
+
+    /// Pretend to be the daemon and send `message` to the window
+    fn send<T: qubes_gui::DaemonMessage>(&mut self, message: &T) {
+        let window = NonZeroU32::new(1).unwrap().into();
+        self.daemon.send_daemon(message, window).unwrap()
+    }
+}
+
+const DEVICE: DeviceId = DeviceId::dummy();
+
+#[test]
+fn window_ids() {
+    let window = NonZeroU32::new(7).unwrap();
+    assert_eq!(qubes_window(window_id(window)), Some(window));
+    assert_eq!(qubes_window(WindowId::from(0)), None);
+    assert_eq!(qubes_window(WindowId::from(1 << 32)), None);
+}
+
+#[test]
+fn create_and_draw() {
+    let mut harness = Harness::new();
+    let window = window_id(NonZeroU32::new(1).unwrap());
+    assert_eq!(harness.app.window, Some(window));
+    assert_eq!(harness.app.redraws, 1);
+    assert_eq!(harness.app.size, Some(PhysicalSize::new(4, 3)));
+    assert!(harness.app.events.is_empty());
+    let (ty, body) = harness.received[0].clone();
+    assert_eq!(ty, qubes_gui::MSG_CREATE);
+    let create = qubes_gui::Create::from_bytes(&body);
+    assert_eq!(
+        create.rectangle.size,
+        WindowSize {
+            width: 4,
+            height: 3
+        }
+    );
+    assert_eq!(
+        harness.types(),
+        [
+            qubes_gui::MSG_CREATE,
+            qubes_gui::MSG_SET_TITLE,
+            qubes_gui::MSG_WINDOW_DUMP,
+            qubes_gui::MSG_CONFIGURE,
+            qubes_gui::MSG_MAP,
+            qubes_gui::MSG_SHMIMAGE,
+        ]
+    );
+}
+
+#[test]
+fn input() {
+    let mut harness = Harness::new();
+    harness.types();
+    let at = Coordinates { x: 1, y: 2 };
+    harness.send(&qubes_gui::Motion {
+        coordinates: at,
+        state: 0,
+        is_hint: 0,
+    });
+    harness.send(&qubes_gui::Button {
+        ty: qubes_gui::EV_BUTTON_PRESS,
+        coordinates: at,
+        state: 0,
+        button: 1,
+    });
+    // Scroll up, as a press and a release
+    for ty in [qubes_gui::EV_BUTTON_PRESS, qubes_gui::EV_BUTTON_RELEASE] {
+        harness.send(&qubes_gui::Button {
+            ty,
+            coordinates: at,
+            state: 0,
+            button: 4,
+        });
+    }
+    // The A key, with Shift down
+    harness.send(&qubes_gui::Keypress {
+        ty: qubes_gui::EV_KEY_PRESS,
+        coordinates: at,
+        state: qubes_gui::x11::SHIFT_MASK,
+        keycode: 38,
+    });
+    harness.send(&qubes_gui::Focus {
+        ty: qubes_gui::EV_FOCUS_IN,
+        mode: 0,
+        detail: 0,
+    });
+    harness.pump();
+    assert_eq!(
+        harness.app.events,
+        [
+            WindowEvent::CursorEntered { device_id: DEVICE },
+            WindowEvent::CursorMoved {
+                device_id: DEVICE,
+                position: PhysicalPosition::new(1.0, 2.0),
+            },
+            WindowEvent::MouseInput {
+                device_id: DEVICE,
+                state: ElementState::Pressed,
+                button: MouseButton::Left,
+            },
+            WindowEvent::MouseWheel {
+                device_id: DEVICE,
+                delta: MouseScrollDelta::LineDelta(0.0, 1.0),
+                phase: TouchPhase::Moved,
+            },
+            WindowEvent::ModifiersChanged(ModifiersState::SHIFT.into()),
+            WindowEvent::Focused(true),
+        ]
+    );
+    assert_eq!(
+        harness.app.keys,
+        [KeyboardInput {
+            physical_key: PhysicalKey::Code(KeyCode::KeyA),
+            state: ElementState::Pressed,
+        }]
+    );
+    assert!(harness.types().is_empty());
+
+    // Closing destroys the window, after which nothing is delivered
+    harness.app.events.clear();
+    let window = NonZeroU32::new(1).unwrap().into();
+    let close = qubes_gui::MSG_CLOSE;
+    harness.daemon.send_raw(&[], window, close).unwrap();
+    harness.pump();
+    harness.send(&qubes_gui::Focus {
+        ty: qubes_gui::EV_FOCUS_OUT,
+        mode: 0,
+        detail: 0,
+    });
+    harness.pump();
+    assert_eq!(
+        harness.app.events,
+        [WindowEvent::CloseRequested, WindowEvent::Destroyed]
+    );
+    assert!(harness.app.exiting);
+    assert_eq!(harness.types(), [qubes_gui::MSG_DESTROY]);
+}
+
+#[test]
+fn configure() {
+    let mut harness = Harness::new();
+    harness.types();
+    let rectangle = Rectangle {
+        top_left: Coordinates { x: 10, y: 20 },
+        size: WindowSize {
+            width: 8,
+            height: 6,
+        },
+    };
+    harness.send(&qubes_gui::Configure {
+        rectangle,
+        override_redirect: 0,
+    });
+    harness.pump();
+    assert_eq!(
+        harness.app.events,
+        [
+            WindowEvent::Moved(PhysicalPosition::new(10, 20)),
+            WindowEvent::Resized(PhysicalSize::new(8, 6)),
+        ]
+    );
+    assert_eq!(harness.app.redraws, 2);
+    assert_eq!(harness.app.size, Some(PhysicalSize::new(8, 6)));
+    let (ty, body) = harness.received[1].clone();
+    assert_eq!(ty, qubes_gui::MSG_CONFIGURE);
+    assert_eq!(qubes_gui::Configure::from_bytes(&body).rectangle, rectangle);
+    assert_eq!(
+        harness.types(),
+        [
+            qubes_gui::MSG_WINDOW_DUMP,
+            qubes_gui::MSG_CONFIGURE,
+            qubes_gui::MSG_SHMIMAGE,
+        ]
+    );
+
+    // Once the daemon acknowledges both dumps, the whole window is damaged
+    let window = NonZeroU32::new(1).unwrap().into();
+    let ack = qubes_gui::MSG_WINDOW_DUMP_ACK;
+    harness.daemon.send_raw(&[], window, ack).unwrap();
+    harness.pump();
+    assert!(harness.types().is_empty());
+    harness.daemon.send_raw(&[], window, ack).unwrap();
+    harness.pump();
+    assert_eq!(harness.types(), [qubes_gui::MSG_SHMIMAGE]);
+}
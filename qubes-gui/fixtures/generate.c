@@ -0,0 +1,201 @@
+/*
+ * The Qubes OS Project, https://www.qubes-os.org
+ *
+ * Copyright (C) 2021  Demi Marie Obenour  <demi@invisiblethingslab.com>
+ *
+ * This program is free software; you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License
+ * as published by the Free Software Foundation; either version 2
+ * of the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301, USA.
+ *
+ */
+
+/* Generate the golden wire-format fixtures from the C definitions.
+ *
+ * Build against the canonical header and run it on a little-endian machine,
+ * with the directory to write the fixtures to as the only argument:
+ *
+ *     cc -std=c11 -I /path/to/qubes-gui-common/include -o generate generate.c
+ *     ./generate qubes-gui/fixtures
+ *
+ * Each fixture is a struct msg_hdr followed by the body.  The values must be
+ * the same as in src/golden.rs, which checks that the Rust structs encode
+ * them identically. */
+
+#include <stdint.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+#include <qubes-gui-protocol.h>
+
+/* From X11, which the protocol header does not include */
+#define X_KEY_PRESS 2
+#define X_BUTTON_PRESS 4
+#define X_ENTER_NOTIFY 7
+#define X_FOCUS_IN 9
+#define X_P_MIN_SIZE (1L << 4)
+#define X_P_MAX_SIZE (1L << 5)
+
+/* Window ID used in every fixture */
+#define WINDOW 42
+
+static const char *dir;
+
+static void put(FILE *f, const void *data, size_t len)
+{
+	if (len && fwrite(data, len, 1, f) != 1) {
+		perror("fwrite");
+		exit(1);
+	}
+}
+
+static void fixture(const char *name, uint32_t type, const void *body,
+                    size_t body_len, const void *trailer, size_t trailer_len)
+{
+	struct msg_hdr hdr = {
+		.type = type,
+		.window = WINDOW,
+		.untrusted_len = (uint32_t)(body_len + trailer_len),
+	};
+	char path[4096];
+	FILE *f;
+
+	if ((size_t)snprintf(path, sizeof path, "%s/%s.bin", dir, name) >= sizeof path) {
+		fputs("path too long\n", stderr);
+		exit(1);
+	}
+	if (!(f = fopen(path, "wb"))) {
+		perror(path);
+		exit(1);
+	}
+	put(f, &hdr, sizeof hdr);
+	put(f, body, body_len);
+	put(f, trailer, trailer_len);
+	if (fclose(f)) {
+		perror(path);
+		exit(1);
+	}
+}
+
+#define FIXTURE(name, type, body) fixture(name, type, &(body), sizeof(body), NULL, 0)
+#define EMPTY(name, type) fixture(name, type, NULL, 0, NULL, 0)
+
+int main(int argc, char **argv)
+{
+	const uint16_t one = 1;
+
+	if (argc != 2) {
+		fprintf(stderr, "usage: %s DIRECTORY\n", argv[0]);
+		return 2;
+	}
+	if (*(const uint8_t *)&one != 1) {
+		fputs("the fixtures are little-endian; run this on a little-endian machine\n", stderr);
+		return 1;
+	}
+	dir = argv[1];
+
+	struct msg_keypress keypress = {
+		.type = X_KEY_PRESS, .x = 10, .y = -20, .state = 0x11, .keycode = 38,
+	};
+	FIXTURE("keypress", MSG_KEYPRESS, keypress);
+
+	struct msg_button button = {
+		.type = X_BUTTON_PRESS, .x = 30, .y = 40, .state = 0x100, .button = 1,
+	};
+	FIXTURE("button", MSG_BUTTON, button);
+
+	struct msg_motion motion = { .x = -5, .y = 7, .state = 0x200, .is_hint = 1 };
+	FIXTURE("motion", MSG_MOTION, motion);
+
+	struct msg_crossing crossing = {
+		.type = X_ENTER_NOTIFY, .x = 1, .y = 2, .state = 0, .mode = 0, .detail = 3, .focus = 1,
+	};
+	FIXTURE("crossing", MSG_CROSSING, crossing);
+
+	struct msg_focus focus = { .type = X_FOCUS_IN, .mode = 0, .detail = 3 };
+	FIXTURE("focus", MSG_FOCUS, focus);
+
+	struct msg_create create = {
+		.x = 10, .y = 20, .width = 640, .height = 480, .parent = 5, .override_redirect = 0,
+	};
+	FIXTURE("create", MSG_CREATE, create);
+
+	EMPTY("destroy", MSG_DESTROY);
+
+	struct msg_map_info map = { .transient_for = 5, .override_redirect = 1 };
+	FIXTURE("map", MSG_MAP, map);
+
+	EMPTY("unmap", MSG_UNMAP);
+
+	struct msg_configure configure = {
+		.x = -100, .y = 50, .width = 800, .height = 600, .override_redirect = 0,
+	};
+	FIXTURE("configure", MSG_CONFIGURE, configure);
+
+	struct shm_cmd mfn_dump = {
+		.shmid = 0, .width = 64, .height = 32, .bpp = 24, .off = 0, .num_mfn = 2, .domid = 0,
+	};
+	uint32_t mfns[] = { 0x1234, 0x5678 };
+	fixture("mfndump", MSG_MFNDUMP, &mfn_dump, sizeof mfn_dump, mfns, sizeof mfns);
+
+	struct msg_shmimage shmimage = { .x = 0, .y = 0, .width = 64, .height = 32 };
+	FIXTURE("shmimage", MSG_SHMIMAGE, shmimage);
+
+	EMPTY("close", MSG_CLOSE);
+	EMPTY("clipboard_req", MSG_CLIPBOARD_REQ);
+
+	static const char clipboard[] = "Qubes clipboard";
+	fixture("clipboard_data", MSG_CLIPBOARD_DATA, clipboard, sizeof clipboard - 1, NULL, 0);
+
+	struct msg_wmname wmname = { 0 };
+	strcpy(wmname.data, "Fixture window");
+	FIXTURE("wmname", MSG_WMNAME, wmname);
+
+	struct msg_keymap_notify keymap = { 0 };
+	static const unsigned keycodes[] = { 9, 38, 255 };
+	for (size_t i = 0; i < sizeof keycodes / sizeof keycodes[0]; i++)
+		keymap.keys[keycodes[i] >> 3] |= 1 << (keycodes[i] & 7);
+	FIXTURE("keymap_notify", MSG_KEYMAP_NOTIFY, keymap);
+
+	EMPTY("dock", MSG_DOCK);
+
+	struct msg_window_hints hints = {
+		.flags = X_P_MIN_SIZE | X_P_MAX_SIZE,
+		.min_width = 100, .min_height = 50,
+		.max_width = 1920, .max_height = 1080,
+	};
+	FIXTURE("window_hints", MSG_WINDOW_HINTS, hints);
+
+	struct msg_window_flags flags = {
+		.flags_set = WINDOW_FLAG_FULLSCREEN,
+		.flags_unset = WINDOW_FLAG_DEMANDS_ATTENTION,
+	};
+	FIXTURE("window_flags", MSG_WINDOW_FLAGS, flags);
+
+	struct msg_wmclass wmclass = { 0 };
+	strcpy(wmclass.res_class, "Firefox");
+	strcpy(wmclass.res_name, "Navigator");
+	FIXTURE("window_class", MSG_WINDOW_CLASS, wmclass);
+
+	struct msg_window_dump_hdr window_dump = {
+		.type = WINDOW_DUMP_TYPE_GRANT_REFS, .width = 64, .height = 32, .bpp = 24,
+	};
+	uint32_t grant_refs[] = { 7, 8 };
+	fixture("window_dump", MSG_WINDOW_DUMP, &window_dump, sizeof window_dump,
+	        grant_refs, sizeof grant_refs);
+
+	struct msg_cursor cursor = { .cursor = CURSOR_X11 | 68 };
+	FIXTURE("cursor", MSG_CURSOR, cursor);
+
+	EMPTY("window_dump_ack", MSG_WINDOW_DUMP_ACK);
+	return 0;
+}
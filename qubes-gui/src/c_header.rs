@@ -0,0 +1,261 @@
+/*
+ * The Qubes OS Project, https://www.qubes-os.org
+ *
+ * Copyright (C) 2021  Demi Marie Obenour  <demi@invisiblethingslab.com>
+ *
+ * This program is free software; you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License
+ * as published by the Free Software Foundation; either version 2
+ * of the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301, USA.
+ *
+ */
+
+//! C header parity checks.
+//!
+//! [`write()`] generates a C header from the layout of the Rust message structs.
+//! The header contains only `_Static_assert`s on the size of each struct and
+//! the offset of each member.  A C file that includes `qubes-gui-protocol.h`
+//! and then this header compiles only if the C and Rust definitions agree on
+//! the wire layout.
+//!
+//! This does not use cbindgen.  cbindgen reads Rust source, and cannot see
+//! the structs that [`castable!`](qubes_castable::castable) defines without
+//! expanding macros, which needs a nightly compiler.  Nor would the result
+//! match the C header: nested Rust structs such as [`Rectangle`] are flat
+//! lists of members in C.  Instead, the layout comes from
+//! [`Castable::FIELDS`], and the name of each C member from a table here.
+//! Adding a field to a Rust struct without naming its C member is a compile
+//! error.
+
+use super::*;
+use core::fmt::{self, Write};
+use qubes_castable::{Castable, Field};
+
+/// A Rust struct and the C struct it corresponds to
+struct CStruct {
+    rust: &'static str,
+    c: &'static str,
+    size: usize,
+    fields: &'static [Field],
+    /// Names of the C members, one for each leaf field of the Rust struct
+    members: &'static [&'static str],
+}
+
+/// The number of fields in `fields`, counting the fields of those that have
+/// their own instead
+const fn count_leaves(fields: &[Field]) -> usize {
+    let (mut i, mut count) = (0, 0);
+    while i < fields.len() {
+        count += if fields[i].fields.is_empty() {
+            1
+        } else {
+            count_leaves(fields[i].fields)
+        };
+        i += 1;
+    }
+    count
+}
+
+macro_rules! c_structs {
+    ($(($rust: ident, $c: expr, [$($member: expr),*$(,)?]$(,)?),)+) => {
+        &[$({
+            qubes_castable::static_assert!(
+                count_leaves(<$rust as Castable>::FIELDS) == [$($member),*].len(),
+                concat!("one C member name is needed for each field of ", stringify!($rust))
+            );
+            CStruct {
+                rust: stringify!($rust),
+                c: $c,
+                size: core::mem::size_of::<$rust>(),
+                fields: <$rust as Castable>::FIELDS,
+                members: &[$($member),*],
+            }
+        }),+]
+    };
+}
+
+const C_STRUCTS: &[CStruct] = c_structs![
+    (
+        UntrustedHeader,
+        "msg_hdr",
+        ["type", "window", "untrusted_len"]
+    ),
+    (
+        Keypress,
+        "msg_keypress",
+        ["type", "x", "y", "state", "keycode"]
+    ),
+    (Button, "msg_button", ["type", "x", "y", "state", "button"]),
+    (Motion, "msg_motion", ["x", "y", "state", "is_hint"]),
+    (
+        Crossing,
+        "msg_crossing",
+        ["type", "x", "y", "state", "mode", "detail", "focus"],
+    ),
+    (
+        Create,
+        "msg_create",
+        ["x", "y", "width", "height", "parent", "override_redirect"],
+    ),
+    (
+        MapInfo,
+        "msg_map_info",
+        ["transient_for", "override_redirect"]
+    ),
+    (
+        Configure,
+        "msg_configure",
+        ["x", "y", "width", "height", "override_redirect"],
+    ),
+    (ShmImage, "msg_shmimage", ["x", "y", "width", "height"]),
+    (Focus, "msg_focus", ["type", "mode", "detail"]),
+    (WMName, "msg_wmname", ["data"]),
+    (KeymapNotify, "msg_keymap_notify", ["keys"]),
+    (
+        WindowHints,
+        "msg_window_hints",
+        [
+            "flags",
+            "min_width",
+            "min_height",
+            "max_width",
+            "max_height",
+            "width_inc",
+            "height_inc",
+            "base_width",
+            "base_height",
+        ],
+    ),
+    (
+        WindowFlags,
+        "msg_window_flags",
+        ["flags_set", "flags_unset"]
+    ),
+    (
+        ShmCmd,
+        "shm_cmd",
+        ["shmid", "width", "height", "bpp", "off", "num_mfn", "domid"],
+    ),
+    (WMClass, "msg_wmclass", ["res_class", "res_name"]),
+    (
+        WindowDumpHeader,
+        "msg_window_dump_hdr",
+        ["type", "width", "height", "bpp"],
+    ),
+    (Cursor, "msg_cursor", ["cursor"]),
+    (XConf, "msg_xconf", ["w", "h", "depth", "mem"]),
+];
+
+/// Call `f` with the offset of each field of `fields` that has no fields of
+/// its own, in order
+fn leaves(fields: &[Field], mut offset: usize, f: &mut dyn FnMut(usize, &Field)) {
+    for field in fields {
+        if field.fields.is_empty() {
+            f(offset, field)
+        } else {
+            leaves(field.fields, offset, f)
+        }
+        offset += field.size
+    }
+}
+
+/// Write the parity header to `out`
+pub fn write(out: &mut dyn Write) -> fmt::Result {
+    out.write_str(concat!(
+        "/* Generated from the Rust definitions in the qubes-gui crate.  Do not edit.\n",
+        " *\n",
+        " * Include this after qubes-gui-protocol.h: it compiles only if both agree\n",
+        " * on the size of every message struct and the offset of every member. */\n",
+        "\n",
+        "#include <stddef.h>\n",
+    ))?;
+    for s in C_STRUCTS {
+        write!(
+            out,
+            "\n/* {} */\n_Static_assert(sizeof(struct {c}) == {}, \"size of struct {c}\");\n",
+            s.rust,
+            s.size,
+            c = s.c
+        )?;
+        let mut members = s.members.iter();
+        let mut res = Ok(());
+        leaves(s.fields, 0, &mut |offset, field| {
+            let member = members.next().expect("too few C member names");
+            res = res.and_then(|()| {
+                writeln!(
+                    out,
+                    "_Static_assert(offsetof(struct {c}, {m}) == {}, \"offset of {c}.{m} ({})\");",
+                    offset,
+                    field.ty,
+                    c = s.c,
+                    m = member
+                )
+            })
+        });
+        res?;
+        assert!(members.next().is_none(), "too many C member names");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use std::{string::String, vec::Vec};
+
+    #[test]
+    fn one_member_per_leaf() {
+        for s in C_STRUCTS {
+            let mut sizes = Vec::new();
+            leaves(s.fields, 0, &mut |offset, field| {
+                sizes.push((offset, field.size))
+            });
+            assert_eq!(sizes.len(), s.members.len(), "{}", s.rust);
+            let (offset, size) = sizes.last().unwrap();
+            assert_eq!(offset + size, s.size, "{}", s.rust);
+        }
+    }
+
+    #[test]
+    fn generates_checks() {
+        let mut header = String::new();
+        write(&mut header).unwrap();
+        assert!(header.contains("_Static_assert(sizeof(struct msg_create) == 24,"));
+        assert!(header.contains("_Static_assert(offsetof(struct msg_create, parent) == 16,"));
+        assert!(header.contains("offsetof(struct msg_wmclass, res_name) == 64,"));
+    }
+
+    /// Compile the generated header against the canonical C header, which
+    /// `QUBES_GUI_PROTOCOL_H` names
+    #[test]
+    #[ignore = "needs qubes-gui-protocol.h; see the README"]
+    fn matches_protocol_header() {
+        use std::{io::Write as _, process};
+        let protocol_h = std::env::var("QUBES_GUI_PROTOCOL_H")
+            .expect("QUBES_GUI_PROTOCOL_H must name qubes-gui-protocol.h");
+        let mut source = std::format!("#include <stdint.h>\n#include \"{}\"\n", protocol_h);
+        write(&mut source).unwrap();
+        let mut cc = process::Command::new(std::env::var("CC").unwrap_or_else(|_| "cc".into()))
+            .args(["-fsyntax-only", "-std=c11", "-x", "c", "-"])
+            .stdin(process::Stdio::piped())
+            .spawn()
+            .expect("cannot run the C compiler");
+        cc.stdin
+            .take()
+            .unwrap()
+            .write_all(source.as_bytes())
+            .unwrap();
+        assert!(cc.wait().unwrap().success(), "C and Rust layouts differ");
+    }
+}
@@ -0,0 +1,238 @@
+/*
+ * The Qubes OS Project, https://www.qubes-os.org
+ *
+ * Copyright (C) 2021  Demi Marie Obenour  <demi@invisiblethingslab.com>
+ *
+ * This program is free software; you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License
+ * as published by the Free Software Foundation; either version 2
+ * of the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301, USA.
+ *
+ */
+
+//! Named X11 cursors for [`crate::Cursor`]
+
+use crate::{Cursor, CURSOR_DEFAULT, CURSOR_X11, CURSOR_X11_MAX};
+
+macro_rules! cursor_shapes {
+    ($(($variant: ident, $name: expr) = $id: expr,)+) => {
+        /// A glyph of the standard X11 cursor font.  The discriminant is the
+        /// glyph ID, as in `<X11/cursorfont.h>`.
+        #[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+        #[repr(u32)]
+        pub enum CursorShape {
+            $(
+                #[doc = $name]
+                $variant = $id,
+            )+
+        }
+
+        impl CursorShape {
+            /// All cursor shapes, in order of glyph ID
+            pub const ALL: &'static [CursorShape] = &[$(CursorShape::$variant),+];
+
+            /// The X11 name of the cursor, without the `XC_` prefix
+            pub fn name(self) -> &'static str {
+                match self {
+                    $(CursorShape::$variant => $name,)+
+                }
+            }
+
+            /// The cursor shape with X11 glyph ID `id`, or `None` if there
+            /// is no such glyph
+            pub fn from_id(id: u32) -> Option<Self> {
+                match id {
+                    $($id => Some(CursorShape::$variant),)+
+                    _ => None,
+                }
+            }
+        }
+    }
+}
+
+cursor_shapes! {
+    (XCursor, "X_cursor") = 0,
+    (Arrow, "arrow") = 2,
+    (BasedArrowDown, "based_arrow_down") = 4,
+    (BasedArrowUp, "based_arrow_up") = 6,
+    (Boat, "boat") = 8,
+    (Bogosity, "bogosity") = 10,
+    (BottomLeftCorner, "bottom_left_corner") = 12,
+    (BottomRightCorner, "bottom_right_corner") = 14,
+    (BottomSide, "bottom_side") = 16,
+    (BottomTee, "bottom_tee") = 18,
+    (BoxSpiral, "box_spiral") = 20,
+    (CenterPtr, "center_ptr") = 22,
+    (Circle, "circle") = 24,
+    (Clock, "clock") = 26,
+    (CoffeeMug, "coffee_mug") = 28,
+    (Cross, "cross") = 30,
+    (CrossReverse, "cross_reverse") = 32,
+    (Crosshair, "crosshair") = 34,
+    (DiamondCross, "diamond_cross") = 36,
+    (Dot, "dot") = 38,
+    (Dotbox, "dotbox") = 40,
+    (DoubleArrow, "double_arrow") = 42,
+    (DraftLarge, "draft_large") = 44,
+    (DraftSmall, "draft_small") = 46,
+    (DrapedBox, "draped_box") = 48,
+    (Exchange, "exchange") = 50,
+    (Fleur, "fleur") = 52,
+    (Gobbler, "gobbler") = 54,
+    (Gumby, "gumby") = 56,
+    (Hand1, "hand1") = 58,
+    (Hand2, "hand2") = 60,
+    (Heart, "heart") = 62,
+    (Icon, "icon") = 64,
+    (IronCross, "iron_cross") = 66,
+    (LeftPtr, "left_ptr") = 68,
+    (LeftSide, "left_side") = 70,
+    (LeftTee, "left_tee") = 72,
+    (Leftbutton, "leftbutton") = 74,
+    (LlAngle, "ll_angle") = 76,
+    (LrAngle, "lr_angle") = 78,
+    (Man, "man") = 80,
+    (Middlebutton, "middlebutton") = 82,
+    (Mouse, "mouse") = 84,
+    (Pencil, "pencil") = 86,
+    (Pirate, "pirate") = 88,
+    (Plus, "plus") = 90,
+    (QuestionArrow, "question_arrow") = 92,
+    (RightPtr, "right_ptr") = 94,
+    (RightSide, "right_side") = 96,
+    (RightTee, "right_tee") = 98,
+    (Rightbutton, "rightbutton") = 100,
+    (RtlLogo, "rtl_logo") = 102,
+    (Sailboat, "sailboat") = 104,
+    (SbDownArrow, "sb_down_arrow") = 106,
+    (SbHDoubleArrow, "sb_h_double_arrow") = 108,
+    (SbLeftArrow, "sb_left_arrow") = 110,
+    (SbRightArrow, "sb_right_arrow") = 112,
+    (SbUpArrow, "sb_up_arrow") = 114,
+    (SbVDoubleArrow, "sb_v_double_arrow") = 116,
+    (Shuttle, "shuttle") = 118,
+    (Sizing, "sizing") = 120,
+    (Spider, "spider") = 122,
+    (Spraycan, "spraycan") = 124,
+    (Star, "star") = 126,
+    (Target, "target") = 128,
+    (Tcross, "tcross") = 130,
+    (TopLeftArrow, "top_left_arrow") = 132,
+    (TopLeftCorner, "top_left_corner") = 134,
+    (TopRightCorner, "top_right_corner") = 136,
+    (TopSide, "top_side") = 138,
+    (TopTee, "top_tee") = 140,
+    (Trek, "trek") = 142,
+    (UlAngle, "ul_angle") = 144,
+    (Umbrella, "umbrella") = 146,
+    (UrAngle, "ur_angle") = 148,
+    (Watch, "watch") = 150,
+    (Xterm, "xterm") = 152,
+}
+
+/// Error indicating that a `MSG_CURSOR` value is not a known cursor
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct BadCursorError(pub u32);
+
+impl core::fmt::Display for BadCursorError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "Bad cursor {:#x}", self.0)
+    }
+}
+
+impl CursorShape {
+    /// The X11 glyph ID
+    pub fn id(self) -> u32 {
+        self as u32
+    }
+
+    /// The value used on the wire, with [`CURSOR_X11`] set
+    pub fn to_wire(self) -> u32 {
+        CURSOR_X11 | self.id()
+    }
+
+    /// Parse a value from the wire.  Returns `Ok(None)` for
+    /// [`CURSOR_DEFAULT`].
+    ///
+    /// # Errors
+    ///
+    /// Fails if [`CURSOR_X11`] is not set, if the value is greater than
+    /// [`CURSOR_X11_MAX`], or if there is no such glyph.
+    pub fn from_wire(value: u32) -> Result<Option<Self>, BadCursorError> {
+        match value {
+            CURSOR_DEFAULT => Ok(None),
+            CURSOR_X11..=CURSOR_X11_MAX => Self::from_id(value & !CURSOR_X11)
+                .map(Some)
+                .ok_or(BadCursorError(value)),
+            _ => Err(BadCursorError(value)),
+        }
+    }
+}
+
+impl Cursor {
+    /// A `MSG_CURSOR` body for `shape`, or for the default cursor if `shape`
+    /// is `None`
+    pub fn new(shape: Option<CursorShape>) -> Self {
+        Self {
+            cursor: shape.map_or(CURSOR_DEFAULT, CursorShape::to_wire),
+        }
+    }
+
+    /// The requested cursor shape, or `None` for the default cursor.  See
+    /// [`CursorShape::from_wire`].
+    ///
+    /// # Errors
+    ///
+    /// Fails if the cursor is not valid.
+    pub fn shape(&self) -> Result<Option<CursorShape>, BadCursorError> {
+        CursorShape::from_wire(self.cursor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        for &shape in CursorShape::ALL {
+            assert_eq!(CursorShape::from_id(shape.id()), Some(shape));
+            assert_eq!(CursorShape::from_wire(shape.to_wire()), Ok(Some(shape)));
+            assert_eq!(Cursor::new(Some(shape)).shape(), Ok(Some(shape)));
+        }
+        assert_eq!(CursorShape::Watch.to_wire(), CURSOR_X11 | 150);
+        assert_eq!(CursorShape::Xterm.name(), "xterm");
+        assert_eq!(CursorShape::from_wire(CURSOR_DEFAULT), Ok(None));
+        assert_eq!(Cursor::new(None).cursor, CURSOR_DEFAULT);
+        assert_eq!(Cursor::new(None).shape(), Ok(None));
+    }
+
+    #[test]
+    fn unknown_values() {
+        for &value in &[
+            1,
+            CURSOR_X11 - 1,
+            CURSOR_X11 | 1,
+            CURSOR_X11 | (CursorShape::Xterm.id() + 2),
+            CURSOR_X11_MAX,
+            CURSOR_X11_MAX + 1,
+            CURSOR_X11 << 1,
+            u32::MAX,
+        ] {
+            assert_eq!(CursorShape::from_wire(value), Err(BadCursorError(value)));
+            assert_eq!(Cursor { cursor: value }.shape(), Err(BadCursorError(value)));
+        }
+        assert_eq!(CursorShape::from_id(1), None);
+        assert_eq!(CursorShape::from_id(CURSOR_X11), None);
+    }
+}
@@ -0,0 +1,243 @@
+/*
+ * The Qubes OS Project, https://www.qubes-os.org
+ *
+ * Copyright (C) 2021  Demi Marie Obenour  <demi@invisiblethingslab.com>
+ *
+ * This program is free software; you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License
+ * as published by the Free Software Foundation; either version 2
+ * of the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301, USA.
+ *
+ */
+
+//! Incremental decoding into fixed-capacity buffers, without allocating
+
+use crate::{Header, ProtocolError, UntrustedHeader};
+use core::mem::size_of;
+use qubes_castable::Castable as _;
+
+const HEADER_SIZE: usize = size_of::<UntrustedHeader>();
+
+/// Error decoding a message
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[non_exhaustive]
+pub enum DecodeError {
+    /// The peer violated the protocol.  The stream cannot be resynchronized,
+    /// so the decoder returns this error from then on.
+    Protocol(ProtocolError),
+    /// The body of a valid message is larger than the capacity of the
+    /// decoder.  The body is skipped, and decoding continues with the next
+    /// message.
+    BodyTooLarge(Header),
+}
+
+impl core::fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            DecodeError::Protocol(e) => e.fmt(f),
+            DecodeError::BodyTooLarge(header) => write!(
+                f,
+                "Message of type {} has a {} byte body, which is too large",
+                header.ty(),
+                header.len()
+            ),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for DecodeError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            DecodeError::Protocol(e) => Some(e),
+            DecodeError::BodyTooLarge(_) => None,
+        }
+    }
+}
+
+/// What the decoder is waiting for
+#[derive(Debug, Copy, Clone)]
+enum State {
+    /// The rest of a header
+    Header,
+    /// The rest of the body of a message
+    Body(Header),
+    /// The rest of a body that is being discarded
+    Skip(usize),
+    /// Nothing: the stream is broken
+    Failed(DecodeError),
+}
+
+/// An incremental decoder that holds message bodies of up to `N` bytes in a
+/// fixed-size buffer, for agents that cannot allocate.  Messages of unknown
+/// type are skipped, as agents are required to do.  Bodies larger than `N`
+/// are rejected with [`DecodeError::BodyTooLarge`].
+#[derive(Debug, Clone)]
+pub struct BoundedDecoder<const N: usize> {
+    header: [u8; HEADER_SIZE],
+    body: [u8; N],
+    /// How much of the header or body has been filled
+    filled: usize,
+    state: State,
+}
+
+impl<const N: usize> Default for BoundedDecoder<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> BoundedDecoder<N> {
+    /// Creates a decoder that expects a message header next
+    pub const fn new() -> Self {
+        Self {
+            header: [0; HEADER_SIZE],
+            body: [0; N],
+            filled: 0,
+            state: State::Header,
+        }
+    }
+
+    /// Consumes bytes from the front of `input` until a complete message has
+    /// been decoded or `input` is empty.  Returns the message, or `None` if
+    /// more data is needed.  The body is only valid until the next call, and
+    /// is in little-endian order: parse it with `Castable::from_le_bytes`.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the peer violated the protocol, or if a body does not fit in
+    /// `N` bytes.  See [`DecodeError`] for whether decoding can continue.
+    pub fn decode(&mut self, input: &mut &[u8]) -> Result<Option<(Header, &[u8])>, DecodeError> {
+        loop {
+            match self.state {
+                State::Failed(e) => return Err(e),
+                State::Header => {
+                    if !fill(&mut self.header, &mut self.filled, input) {
+                        return Ok(None);
+                    }
+                    let untrusted = UntrustedHeader::from_le_bytes(&self.header);
+                    self.state = match untrusted.validate_length() {
+                        Err(e) => State::Failed(DecodeError::Protocol(e)),
+                        Ok(None) => State::Skip(untrusted.untrusted_len as usize),
+                        Ok(Some(header)) if header.len() > N => {
+                            self.state = State::Skip(header.len());
+                            return Err(DecodeError::BodyTooLarge(header));
+                        }
+                        Ok(Some(header)) => State::Body(header),
+                    }
+                }
+                State::Skip(remaining) => {
+                    let skipped = remaining.min(input.len());
+                    *input = &input[skipped..];
+                    if skipped < remaining {
+                        self.state = State::Skip(remaining - skipped);
+                        return Ok(None);
+                    }
+                    self.state = State::Header
+                }
+                State::Body(header) => {
+                    if !fill(&mut self.body[..header.len()], &mut self.filled, input) {
+                        return Ok(None);
+                    }
+                    self.state = State::Header;
+                    return Ok(Some((header, &self.body[..header.len()])));
+                }
+            }
+        }
+    }
+}
+
+/// Copies bytes from `input` into `buf[*filled..]`.  Returns `true` (and
+/// resets `filled`) once `buf` is full.
+fn fill(buf: &mut [u8], filled: &mut usize, input: &mut &[u8]) -> bool {
+    let to_copy = (buf.len() - *filled).min(input.len());
+    buf[*filled..*filled + to_copy].copy_from_slice(&input[..to_copy]);
+    *input = &input[to_copy..];
+    *filled += to_copy;
+    if *filled == buf.len() {
+        *filled = 0;
+        true
+    } else {
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use crate::{MSG_CLIPBOARD_DATA, MSG_CLOSE, MSG_CURSOR};
+    use std::vec::Vec;
+
+    fn message(ty: u32, body: &[u8]) -> Vec<u8> {
+        let header = UntrustedHeader {
+            ty,
+            window: 3.into(),
+            untrusted_len: body.len() as u32,
+        };
+        let mut out = header.as_bytes().to_vec();
+        out.extend_from_slice(body);
+        out
+    }
+
+    #[test]
+    fn byte_at_a_time() {
+        let mut stream = message(MSG_CLIPBOARD_DATA, b"hello");
+        stream.extend(message(0x1234, b"unknown"));
+        stream.extend(message(MSG_CLOSE, b""));
+        let mut decoder = BoundedDecoder::<8>::new();
+        let mut decoded = Vec::new();
+        for byte in &stream {
+            let mut input = core::slice::from_ref(byte);
+            if let Some((header, body)) = decoder.decode(&mut input).unwrap() {
+                decoded.push((header.ty(), body.to_vec()));
+            }
+            assert!(input.is_empty());
+        }
+        assert_eq!(
+            decoded,
+            [
+                (MSG_CLIPBOARD_DATA, b"hello".to_vec()),
+                (MSG_CLOSE, Vec::new())
+            ]
+        );
+    }
+
+    #[test]
+    fn too_large() {
+        let mut stream = message(MSG_CLIPBOARD_DATA, b"too large");
+        stream.extend(message(MSG_CLIPBOARD_DATA, b"fits"));
+        let mut decoder = BoundedDecoder::<4>::new();
+        let mut input = &stream[..];
+        match decoder.decode(&mut input) {
+            Err(DecodeError::BodyTooLarge(header)) => assert_eq!(header.len(), 9),
+            other => panic!("unexpected {:?}", other),
+        }
+        let (_, body) = decoder.decode(&mut input).unwrap().unwrap();
+        assert_eq!(body, b"fits");
+        assert!(input.is_empty());
+    }
+
+    #[test]
+    fn protocol_error_is_sticky() {
+        let stream = message(MSG_CURSOR, b"");
+        let mut decoder = BoundedDecoder::<4>::new();
+        let expected = Err(DecodeError::Protocol(ProtocolError::BadLength {
+            ty: MSG_CURSOR,
+            untrusted_len: 0,
+        }));
+        assert_eq!(decoder.decode(&mut &stream[..]), expected);
+        assert_eq!(decoder.decode(&mut &stream[..]), expected);
+    }
+}
@@ -0,0 +1,152 @@
+/*
+ * The Qubes OS Project, https://www.qubes-os.org
+ *
+ * Copyright (C) 2021  Demi Marie Obenour  <demi@invisiblethingslab.com>
+ *
+ * This program is free software; you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License
+ * as published by the Free Software Foundation; either version 2
+ * of the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301, USA.
+ *
+ */
+
+//! Encoding messages into caller-provided buffers, without allocating
+
+use crate::{ProtocolError, UntrustedHeader, WindowID};
+use core::convert::TryFrom as _;
+use core::mem::size_of;
+use qubes_castable::Castable as _;
+
+/// Error encoding a message
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[non_exhaustive]
+pub enum EncodeError {
+    /// The buffer is too small for the message
+    BufferTooSmall {
+        /// The size of the header and body together
+        needed: usize,
+    },
+    /// The message is not valid: its type is unknown, or its body has the
+    /// wrong length for its type
+    Invalid(ProtocolError),
+}
+
+impl core::fmt::Display for EncodeError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            EncodeError::BufferTooSmall { needed } => {
+                write!(f, "Buffer too small: message needs {} bytes", needed)
+            }
+            EncodeError::Invalid(e) => write!(f, "Invalid message: {}", e),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for EncodeError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            EncodeError::BufferTooSmall { .. } => None,
+            EncodeError::Invalid(e) => Some(e),
+        }
+    }
+}
+
+/// Write a message of type `ty`, sent to `window`, with body `body`, to the
+/// start of `buf`.  Returns the number of bytes written.  The header is
+/// written in little-endian order, but `body` is copied as-is.  Use
+/// [`crate::Message::encode_into`] for messages with a fixed-size body.
+///
+/// # Errors
+///
+/// Fails if the message is not valid, or if `buf` is too small.  Nothing is
+/// written in either case.
+pub fn encode_raw_into(
+    ty: u32,
+    window: WindowID,
+    body: &[u8],
+    buf: &mut [u8],
+) -> Result<usize, EncodeError> {
+    let header = UntrustedHeader {
+        ty,
+        window,
+        untrusted_len: u32::try_from(body.len()).unwrap_or(u32::MAX),
+    };
+    match header.validate_length() {
+        Ok(Some(_)) => {}
+        Ok(None) => return Err(EncodeError::Invalid(ProtocolError::UnknownType { ty })),
+        Err(e) => return Err(EncodeError::Invalid(e)),
+    }
+    let needed = size_of::<UntrustedHeader>() + body.len();
+    let buf = buf
+        .get_mut(..needed)
+        .ok_or(EncodeError::BufferTooSmall { needed })?;
+    let (dst_header, dst_body) = buf.split_at_mut(size_of::<UntrustedHeader>());
+    dst_header.copy_from_slice(header.to_le_order().as_bytes());
+    dst_body.copy_from_slice(body);
+    Ok(needed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Cursor, CursorShape, Message as _, MSG_CLIPBOARD_DATA, MSG_CURSOR};
+
+    #[test]
+    fn fixed_size() {
+        let cursor = Cursor::new(Some(CursorShape::Watch));
+        let mut buf = [0xFF; 64];
+        let len = cursor.encode_into(7.into(), &mut buf).unwrap();
+        assert_eq!(len, size_of::<UntrustedHeader>() + size_of::<Cursor>());
+        let header = UntrustedHeader::from_bytes(&buf[..size_of::<UntrustedHeader>()]);
+        assert_eq!(header.ty, MSG_CURSOR);
+        assert_eq!(header.window, 7.into());
+        assert_eq!(header.untrusted_len as usize, size_of::<Cursor>());
+        assert_eq!(&buf[size_of::<UntrustedHeader>()..len], cursor.as_bytes());
+        assert_eq!(buf[len], 0xFF, "nothing written past the message");
+    }
+
+    #[test]
+    fn too_small() {
+        let mut buf = [0; 16];
+        assert_eq!(
+            encode_raw_into(MSG_CLIPBOARD_DATA, 0.into(), b"hello", &mut buf),
+            Err(EncodeError::BufferTooSmall { needed: 17 })
+        );
+        assert_eq!(buf, [0; 16]);
+        let mut buf = [0; 17];
+        assert_eq!(
+            encode_raw_into(MSG_CLIPBOARD_DATA, 0.into(), b"hello", &mut buf),
+            Ok(17)
+        );
+        assert_eq!(&buf[12..], b"hello");
+    }
+
+    #[test]
+    fn invalid() {
+        let mut buf = [0; 64];
+        assert_eq!(
+            encode_raw_into(0x1234, 0.into(), b"", &mut buf),
+            Err(EncodeError::Invalid(ProtocolError::UnknownType {
+                ty: 0x1234
+            }))
+        );
+        assert_eq!(
+            encode_raw_into(MSG_CURSOR, 0.into(), b"", &mut buf),
+            Err(EncodeError::Invalid(ProtocolError::BadLength {
+                ty: MSG_CURSOR,
+                untrusted_len: 0
+            }))
+        );
+    }
+}
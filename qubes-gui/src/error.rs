@@ -0,0 +1,258 @@
+/*
+ * The Qubes OS Project, https://www.qubes-os.org
+ *
+ * Copyright (C) 2021  Demi Marie Obenour  <demi@invisiblethingslab.com>
+ *
+ * This program is free software; you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License
+ * as published by the Free Software Foundation; either version 2
+ * of the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301, USA.
+ *
+ */
+
+//! Errors found when validating messages from the other side
+
+use crate::{
+    BadCursorError, Header, UntrustedHeader, ViolationKind, MSG_CURSOR, PROTOCOL_VERSION_MAJOR,
+    PROTOCOL_VERSION_MINOR,
+};
+
+/// A violation of the GUI protocol by the other side
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[non_exhaustive]
+pub enum ProtocolError {
+    /// The length of a message is wrong for its type
+    BadLength {
+        /// The type of the message
+        ty: u32,
+        /// The UNTRUSTED length of the message
+        untrusted_len: u32,
+    },
+    /// The type of a message is not known
+    UnknownType {
+        /// The type of the message
+        ty: u32,
+    },
+    /// A field of a message has a bad value
+    BadFieldValue {
+        /// The type of the message
+        msg: u32,
+        /// The name of the field
+        field: &'static str,
+    },
+    /// A message was sent in the wrong direction, such as an agent sending a
+    /// message that only the daemon may send
+    WrongDirection {
+        /// The type of the message
+        ty: u32,
+    },
+    /// A deprecated message was rejected because of
+    /// [`crate::DeprecatedPolicy::Reject`]
+    Deprecated {
+        /// The type of the message
+        ty: u32,
+    },
+    /// The other side speaks an incompatible protocol version
+    VersionMismatch {
+        /// Their major version
+        major: u32,
+        /// Their minor version
+        minor: u32,
+    },
+}
+
+impl ProtocolError {
+    /// The kind of [`crate::Violation`] to report for this error, if it
+    /// concerns a particular message
+    pub fn violation_kind(&self) -> Option<ViolationKind> {
+        match self {
+            ProtocolError::BadLength { .. } => Some(ViolationKind::BadLength),
+            ProtocolError::UnknownType { .. } => Some(ViolationKind::UnknownMessage),
+            ProtocolError::BadFieldValue { .. } => Some(ViolationKind::BadField),
+            ProtocolError::WrongDirection { .. } => Some(ViolationKind::WrongDirection),
+            ProtocolError::Deprecated { .. } => Some(ViolationKind::Deprecated),
+            ProtocolError::VersionMismatch { .. } => None,
+        }
+    }
+}
+
+impl core::fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match *self {
+            ProtocolError::BadLength { ty, untrusted_len } => {
+                write!(f, "Bad length {} for message of type {}", untrusted_len, ty)
+            }
+            ProtocolError::UnknownType { ty } => write!(f, "Unknown message type {}", ty),
+            ProtocolError::BadFieldValue { msg, field } => {
+                write!(f, "Bad value for {} in message of type {}", field, msg)
+            }
+            ProtocolError::WrongDirection { ty } => {
+                write!(f, "Message of type {} sent in the wrong direction", ty)
+            }
+            ProtocolError::Deprecated { ty } => {
+                write!(f, "Deprecated message of type {} rejected", ty)
+            }
+            ProtocolError::VersionMismatch { major, minor } => write!(
+                f,
+                "Unsupported protocol version {}.{}: this implementation supports {}.{}",
+                major, minor, PROTOCOL_VERSION_MAJOR, PROTOCOL_VERSION_MINOR
+            ),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for ProtocolError {}
+
+impl From<BadCursorError> for ProtocolError {
+    fn from(_: BadCursorError) -> Self {
+        ProtocolError::BadFieldValue {
+            msg: MSG_CURSOR,
+            field: "cursor",
+        }
+    }
+}
+
+impl UntrustedHeader {
+    /// Validate a header received by a daemon: the type must be known, the
+    /// length must be correct, and agents must be allowed to send it.
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`ProtocolError::UnknownType`],
+    /// [`ProtocolError::BadLength`], or [`ProtocolError::WrongDirection`].
+    pub fn validate_from_agent(&self) -> Result<Header, ProtocolError> {
+        self.validate_from(crate::agent_may_send)
+    }
+
+    /// Validate a header received by an agent.  Like
+    /// [`UntrustedHeader::validate_from_agent`], but agents MUST ignore
+    /// messages of unknown type, so callers should skip the body on
+    /// [`ProtocolError::UnknownType`] instead of treating it as fatal.
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`ProtocolError::UnknownType`],
+    /// [`ProtocolError::BadLength`], or [`ProtocolError::WrongDirection`].
+    pub fn validate_from_daemon(&self) -> Result<Header, ProtocolError> {
+        self.validate_from(crate::daemon_may_send)
+    }
+
+    fn validate_from(&self, may_send: fn(u32) -> bool) -> Result<Header, ProtocolError> {
+        match self.validate_length()? {
+            None => Err(ProtocolError::UnknownType { ty: self.ty }),
+            Some(header) if !may_send(header.ty()) => {
+                Err(ProtocolError::WrongDirection { ty: header.ty() })
+            }
+            Some(header) => Ok(header),
+        }
+    }
+}
+
+/// Split a protocol version into its major and minor parts
+fn split_version(version: u32) -> (u32, u32) {
+    (version >> 16, version & 0xFFFF)
+}
+
+/// Check the version an agent sent at the start of a connection.  Returns
+/// the minor version the daemon should use, which is the lower of the
+/// agent's and ours.
+///
+/// # Errors
+///
+/// Fails with [`ProtocolError::VersionMismatch`] if the major version is not
+/// ours.
+pub fn negotiate_agent_version(version: u32) -> Result<u32, ProtocolError> {
+    match split_version(version) {
+        (PROTOCOL_VERSION_MAJOR, minor) => Ok(minor.min(PROTOCOL_VERSION_MINOR)),
+        (major, minor) => Err(ProtocolError::VersionMismatch { major, minor }),
+    }
+}
+
+/// Check the version a daemon sent in its [`crate::XConfVersion`].
+///
+/// # Errors
+///
+/// Fails with [`ProtocolError::VersionMismatch`] unless the major version is
+/// ours and the minor version is between 4 (the first to send a version to
+/// agents) and ours, inclusive.
+pub fn check_daemon_version(version: u32) -> Result<(), ProtocolError> {
+    match split_version(version) {
+        (PROTOCOL_VERSION_MAJOR, minor) if (4..=PROTOCOL_VERSION_MINOR).contains(&minor) => Ok(()),
+        (major, minor) => Err(ProtocolError::VersionMismatch { major, minor }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{MSG_CLOSE, MSG_CREATE, MSG_DESTROY, PROTOCOL_VERSION};
+
+    fn header(ty: u32, untrusted_len: u32) -> UntrustedHeader {
+        UntrustedHeader {
+            ty,
+            window: Default::default(),
+            untrusted_len,
+        }
+    }
+
+    #[test]
+    fn direction() {
+        let create = header(MSG_CREATE, core::mem::size_of::<crate::Create>() as u32);
+        assert!(create.validate_from_agent().is_ok());
+        assert_eq!(
+            create.validate_from_daemon(),
+            Err(ProtocolError::WrongDirection { ty: MSG_CREATE })
+        );
+        assert!(header(MSG_CLOSE, 0).validate_from_daemon().is_ok());
+        assert!(header(MSG_DESTROY, 0).validate_from_daemon().is_ok());
+        assert_eq!(
+            header(MSG_CLOSE, 1).validate_from_daemon(),
+            Err(ProtocolError::BadLength {
+                ty: MSG_CLOSE,
+                untrusted_len: 1
+            })
+        );
+        let unknown = header(0xdead, 0);
+        assert_eq!(
+            unknown.validate_from_agent(),
+            Err(ProtocolError::UnknownType { ty: 0xdead })
+        );
+        assert_eq!(
+            unknown.validate_from_agent().unwrap_err().violation_kind(),
+            Some(ViolationKind::UnknownMessage)
+        );
+    }
+
+    #[test]
+    fn versions() {
+        assert_eq!(
+            negotiate_agent_version(PROTOCOL_VERSION),
+            Ok(PROTOCOL_VERSION_MINOR)
+        );
+        assert_eq!(negotiate_agent_version(0x1_0002), Ok(2));
+        assert_eq!(
+            negotiate_agent_version(0x1_FFFF),
+            Ok(PROTOCOL_VERSION_MINOR)
+        );
+        assert_eq!(
+            negotiate_agent_version(0x2_0000),
+            Err(ProtocolError::VersionMismatch { major: 2, minor: 0 })
+        );
+        assert_eq!(check_daemon_version(PROTOCOL_VERSION), Ok(()));
+        assert_eq!(check_daemon_version(0x1_0004), Ok(()));
+        assert!(check_daemon_version(0x1_0003).is_err());
+        assert!(check_daemon_version(PROTOCOL_VERSION + 1).is_err());
+        assert!(check_daemon_version(0x2_0004).is_err());
+    }
+}
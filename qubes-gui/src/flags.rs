@@ -0,0 +1,77 @@
+/*
+ * The Qubes OS Project, https://www.qubes-os.org
+ *
+ * Copyright (C) 2021  Demi Marie Obenour  <demi@invisiblethingslab.com>
+ *
+ * This program is free software; you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License
+ * as published by the Free Software Foundation; either version 2
+ * of the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301, USA.
+ *
+ */
+
+//! Typed access to [`crate::WindowFlags`]
+
+use crate::{ProtocolError, WindowFlag, WindowFlags, MSG_WINDOW_FLAGS};
+
+impl WindowFlags {
+    /// All [`WindowFlag`]s known to this implementation
+    pub const KNOWN: u32 = WindowFlag::Fullscreen as u32
+        | WindowFlag::DemandsAttention as u32
+        | WindowFlag::Minimize as u32;
+
+    /// Check that only [`WindowFlags::KNOWN`] flags are present, and that no
+    /// flag is both set and unset
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`ProtocolError::BadFieldValue`] naming the offending
+    /// field.
+    pub fn validate(&self) -> Result<(), ProtocolError> {
+        let field = if self.set & !Self::KNOWN != 0 {
+            "set"
+        } else if self.unset & !Self::KNOWN != 0 || self.set & self.unset != 0 {
+            "unset"
+        } else {
+            return Ok(());
+        };
+        Err(ProtocolError::BadFieldValue {
+            msg: MSG_WINDOW_FLAGS,
+            field,
+        })
+    }
+
+    /// Does this message set `flag`?
+    pub fn sets(&self, flag: WindowFlag) -> bool {
+        self.set & flag as u32 != 0
+    }
+
+    /// Does this message unset `flag`?
+    pub fn unsets(&self, flag: WindowFlag) -> bool {
+        self.unset & flag as u32 != 0
+    }
+
+    /// Is fullscreen requested?
+    pub fn is_fullscreen_requested(&self) -> bool {
+        self.sets(WindowFlag::Fullscreen)
+    }
+
+    /// Is the window asking for attention?
+    pub fn is_attention_demanded(&self) -> bool {
+        self.sets(WindowFlag::DemandsAttention)
+    }
+
+    /// Is minimizing requested?
+    pub fn is_minimize_requested(&self) -> bool {
+        self.sets(WindowFlag::Minimize)
+    }
+}
@@ -0,0 +1,416 @@
+/*
+ * The Qubes OS Project, https://www.qubes-os.org
+ *
+ * Copyright (C) 2021  Demi Marie Obenour  <demi@invisiblethingslab.com>
+ *
+ * This program is free software; you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License
+ * as published by the Free Software Foundation; either version 2
+ * of the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301, USA.
+ *
+ */
+
+//! Geometry arithmetic on [`Coordinates`], [`WindowSize`], and [`Rectangle`].
+//!
+//! Coordinates are signed and sizes are unsigned, so all computations are
+//! done in `i64`, which cannot overflow.  Results that cannot be represented
+//! on the wire are reported as `None`.
+
+use crate::{Coordinates, Rectangle, WindowSize};
+use core::convert::TryFrom;
+
+impl Coordinates {
+    /// Move by `dx` pixels right and `dy` pixels down, or `None` on overflow
+    pub fn translate(self, dx: i32, dy: i32) -> Option<Self> {
+        Some(Self {
+            x: self.x.checked_add(dx)?,
+            y: self.y.checked_add(dy)?,
+        })
+    }
+}
+
+impl WindowSize {
+    /// Is either dimension zero?
+    pub fn is_empty(self) -> bool {
+        self.width == 0 || self.height == 0
+    }
+
+    /// The number of pixels, or `None` if it does not fit in a `u32`
+    pub fn checked_area(self) -> Option<u32> {
+        self.width.checked_mul(self.height)
+    }
+}
+
+impl Rectangle {
+    /// The left, top, right, and bottom edges.  The right and bottom edges
+    /// are exclusive.
+    fn edges(self) -> (i64, i64, i64, i64) {
+        let left = i64::from(self.top_left.x);
+        let top = i64::from(self.top_left.y);
+        (
+            left,
+            top,
+            left + i64::from(self.size.width),
+            top + i64::from(self.size.height),
+        )
+    }
+
+    /// The rectangle with the given edges, or `None` if it cannot be
+    /// represented
+    fn from_edges(left: i64, top: i64, right: i64, bottom: i64) -> Option<Self> {
+        Some(Self {
+            top_left: Coordinates {
+                x: i32::try_from(left).ok()?,
+                y: i32::try_from(top).ok()?,
+            },
+            size: WindowSize {
+                width: u32::try_from(right - left).ok()?,
+                height: u32::try_from(bottom - top).ok()?,
+            },
+        })
+    }
+
+    /// Does the rectangle have no pixels?
+    pub fn is_empty(self) -> bool {
+        self.size.is_empty()
+    }
+
+    /// The number of pixels, or `None` if it does not fit in a `u32`
+    pub fn checked_area(self) -> Option<u32> {
+        self.size.checked_area()
+    }
+
+    /// Is the pixel at `point` inside the rectangle?
+    pub fn contains(self, point: Coordinates) -> bool {
+        let (left, top, right, bottom) = self.edges();
+        let (x, y) = (i64::from(point.x), i64::from(point.y));
+        (left..right).contains(&x) && (top..bottom).contains(&y)
+    }
+
+    /// The pixels in both `self` and `other`, or `None` if there are none
+    pub fn intersect(self, other: Self) -> Option<Self> {
+        let (l1, t1, r1, b1) = self.edges();
+        let (l2, t2, r2, b2) = other.edges();
+        let (left, top, right, bottom) = (l1.max(l2), t1.max(t2), r1.min(r2), b1.min(b2));
+        if left >= right || top >= bottom {
+            return None;
+        }
+        Self::from_edges(left, top, right, bottom)
+    }
+
+    /// The smallest rectangle containing both `self` and `other`, or `None`
+    /// if it is too large to represent.  Empty rectangles are ignored.
+    pub fn union(self, other: Self) -> Option<Self> {
+        if other.is_empty() {
+            return Some(self);
+        }
+        if self.is_empty() {
+            return Some(other);
+        }
+        let (l1, t1, r1, b1) = self.edges();
+        let (l2, t2, r2, b2) = other.edges();
+        Self::from_edges(l1.min(l2), t1.min(t2), r1.max(r2), b1.max(b2))
+    }
+
+    /// The exclusive bottom right corner, or `None` if `x + width` or
+    /// `y + height` does not fit in an `i32`
+    pub fn checked_bottom_right(self) -> Option<Coordinates> {
+        let (_, _, right, bottom) = self.edges();
+        Some(Coordinates {
+            x: i32::try_from(right).ok()?,
+            y: i32::try_from(bottom).ok()?,
+        })
+    }
+
+    /// Does the rectangle lie entirely inside a window of size `size`?
+    pub fn fits_within(self, size: WindowSize) -> bool {
+        let (left, top, right, bottom) = self.edges();
+        left >= 0 && top >= 0 && right <= i64::from(size.width) && bottom <= i64::from(size.height)
+    }
+
+    /// Move by `dx` pixels right and `dy` pixels down, or `None` on overflow
+    pub fn translate(self, dx: i32, dy: i32) -> Option<Self> {
+        Some(Self {
+            top_left: self.top_left.translate(dx, dy)?,
+            size: self.size,
+        })
+    }
+
+    /// The part of the rectangle inside a window of size `size`, or `None`
+    /// if the rectangle lies entirely outside it
+    pub fn clamp_to(self, size: WindowSize) -> Option<Self> {
+        let (left, top, right, bottom) = self.edges();
+        let (left, top) = (left.max(0), top.max(0));
+        let right = right.min(i64::from(size.width));
+        let bottom = bottom.min(i64::from(size.height));
+        if left >= right || top >= bottom {
+            return None;
+        }
+        Self::from_edges(left, top, right, bottom)
+    }
+}
+
+/// Error indicating that a size is empty, or larger than
+/// [`crate::MAX_WINDOW_WIDTH`]×[`crate::MAX_WINDOW_HEIGHT`]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct BadSizeError(pub WindowSize);
+
+impl core::fmt::Display for BadSizeError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "Bad window size {}x{}", self.0.width, self.0.height)
+    }
+}
+
+#[cfg(feature = "defmt")]
+impl defmt::Format for BadSizeError {
+    fn format(&self, f: defmt::Formatter<'_>) {
+        defmt::write!(
+            f,
+            "Bad window size {=u32}x{=u32}",
+            self.0.width,
+            self.0.height
+        )
+    }
+}
+
+/// A [`WindowSize`] that is not empty and not larger than
+/// [`crate::MAX_WINDOW_WIDTH`]×[`crate::MAX_WINDOW_HEIGHT`]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct ValidWindowSize(WindowSize);
+
+impl ValidWindowSize {
+    /// Validate `size`
+    ///
+    /// # Errors
+    ///
+    /// Fails if either dimension is zero or too large.
+    pub fn new(size: WindowSize) -> Result<Self, BadSizeError> {
+        if size.is_empty()
+            || size.width > crate::MAX_WINDOW_WIDTH
+            || size.height > crate::MAX_WINDOW_HEIGHT
+        {
+            Err(BadSizeError(size))
+        } else {
+            Ok(Self(size))
+        }
+    }
+
+    /// Width in pixels.  Between 1 and [`crate::MAX_WINDOW_WIDTH`] inclusive.
+    pub fn width(self) -> u32 {
+        self.0.width
+    }
+
+    /// Height in pixels.  Between 1 and [`crate::MAX_WINDOW_HEIGHT`]
+    /// inclusive.
+    pub fn height(self) -> u32 {
+        self.0.height
+    }
+
+    /// The number of pixels.  This cannot overflow.
+    pub fn area(self) -> u32 {
+        self.0.width * self.0.height
+    }
+
+    /// The underlying size
+    pub fn get(self) -> WindowSize {
+        self.0
+    }
+}
+
+impl From<ValidWindowSize> for WindowSize {
+    fn from(size: ValidWindowSize) -> Self {
+        size.0
+    }
+}
+
+/// A [`Rectangle`] whose size is a [`ValidWindowSize`]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct ValidRectangle {
+    top_left: Coordinates,
+    size: ValidWindowSize,
+}
+
+impl ValidRectangle {
+    /// Validate `rectangle`
+    ///
+    /// # Errors
+    ///
+    /// Fails if the size is not valid.  See [`ValidWindowSize::new`].
+    pub fn new(rectangle: Rectangle) -> Result<Self, BadSizeError> {
+        Ok(Self {
+            top_left: rectangle.top_left,
+            size: ValidWindowSize::new(rectangle.size)?,
+        })
+    }
+
+    /// Coordinates of the top left corner
+    pub fn top_left(self) -> Coordinates {
+        self.top_left
+    }
+
+    /// The size
+    pub fn size(self) -> ValidWindowSize {
+        self.size
+    }
+
+    /// The underlying rectangle
+    pub fn get(self) -> Rectangle {
+        Rectangle {
+            top_left: self.top_left,
+            size: self.size.0,
+        }
+    }
+}
+
+impl From<ValidRectangle> for Rectangle {
+    fn from(rectangle: ValidRectangle) -> Self {
+        rectangle.get()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rect(x: i32, y: i32, width: u32, height: u32) -> Rectangle {
+        Rectangle {
+            top_left: Coordinates { x, y },
+            size: WindowSize { width, height },
+        }
+    }
+
+    #[test]
+    fn translate() {
+        let point = Coordinates { x: -5, y: 10 };
+        assert_eq!(
+            point.translate(-3, -20),
+            Some(Coordinates { x: -8, y: -10 })
+        );
+        assert_eq!(point.translate(i32::MIN, 0), None);
+        assert_eq!(Coordinates { x: i32::MAX, y: 0 }.translate(1, 0), None);
+        assert_eq!(
+            rect(-5, -5, 10, 10).translate(5, 5),
+            Some(rect(0, 0, 10, 10))
+        );
+        assert_eq!(rect(0, i32::MAX, 1, 1).translate(0, 1), None);
+    }
+
+    #[test]
+    fn area() {
+        assert_eq!(rect(-10, -10, 640, 480).checked_area(), Some(307_200));
+        assert_eq!(rect(0, 0, 65536, 65535).checked_area(), Some(4_294_901_760));
+        assert_eq!(rect(0, 0, 65536, 65536).checked_area(), None);
+        assert_eq!(rect(0, 0, 0, 100).checked_area(), Some(0));
+        assert!(rect(0, 0, 0, 100).is_empty());
+        assert!(!rect(0, 0, 1, 1).is_empty());
+    }
+
+    #[test]
+    fn contains() {
+        let r = rect(-10, -20, 20, 40);
+        assert!(r.contains(Coordinates { x: -10, y: -20 }));
+        assert!(r.contains(Coordinates { x: 9, y: 19 }));
+        assert!(!r.contains(Coordinates { x: 10, y: 0 }));
+        assert!(!r.contains(Coordinates { x: 0, y: 20 }));
+        assert!(!r.contains(Coordinates { x: -11, y: 0 }));
+        assert!(!rect(0, 0, 0, 10).contains(Coordinates { x: 0, y: 0 }));
+        // The exclusive edges may lie beyond `i32::MAX`
+        let huge = rect(i32::MAX, i32::MAX, u32::MAX, u32::MAX);
+        assert!(huge.contains(Coordinates {
+            x: i32::MAX,
+            y: i32::MAX
+        }));
+    }
+
+    #[test]
+    fn intersect() {
+        let r = rect(-10, -10, 20, 20);
+        assert_eq!(r.intersect(rect(0, 0, 100, 100)), Some(rect(0, 0, 10, 10)));
+        assert_eq!(
+            r.intersect(rect(-100, -5, 95, 5)),
+            Some(rect(-10, -5, 5, 5))
+        );
+        assert_eq!(r.intersect(r), Some(r));
+        // Touching edges share no pixels
+        assert_eq!(r.intersect(rect(10, -10, 5, 5)), None);
+        assert_eq!(r.intersect(rect(0, 0, 0, 5)), None);
+        let low = rect(i32::MIN, i32::MIN, u32::MAX, u32::MAX);
+        let high = rect(i32::MAX - 1, i32::MAX - 1, u32::MAX, u32::MAX);
+        assert_eq!(
+            low.intersect(high),
+            Some(rect(i32::MAX - 1, i32::MAX - 1, 1, 1))
+        );
+    }
+
+    #[test]
+    fn union() {
+        let r = rect(-10, -10, 20, 20);
+        assert_eq!(rect(-10, -10, 5, 5).union(rect(0, 0, 10, 10)), Some(r));
+        // Empty rectangles are ignored, wherever they are
+        assert_eq!(r.union(rect(100, 100, 0, 0)), Some(r));
+        assert_eq!(rect(-500, 5, 0, 7).union(r), Some(r));
+        // A width of `u32::MAX` still fits, but one more pixel does not
+        assert_eq!(
+            rect(i32::MIN, 0, 1, 1).union(rect(i32::MAX - 1, 0, 1, 1)),
+            Some(rect(i32::MIN, 0, u32::MAX, 1))
+        );
+        assert_eq!(rect(i32::MIN, 0, 1, 1).union(rect(i32::MAX, 0, 1, 1)), None);
+    }
+
+    #[test]
+    fn clamp_to() {
+        let size = WindowSize {
+            width: 100,
+            height: 50,
+        };
+        assert_eq!(
+            rect(-10, -10, 30, 30).clamp_to(size),
+            Some(rect(0, 0, 20, 20))
+        );
+        assert_eq!(
+            rect(90, 40, 30, 30).clamp_to(size),
+            Some(rect(90, 40, 10, 10))
+        );
+        assert_eq!(rect(10, 10, 5, 5).clamp_to(size), Some(rect(10, 10, 5, 5)));
+        assert_eq!(rect(100, 0, 5, 5).clamp_to(size), None);
+        assert_eq!(rect(-5, 0, 5, 5).clamp_to(size), None);
+        assert_eq!(
+            rect(i32::MIN, i32::MIN, u32::MAX, u32::MAX).clamp_to(size),
+            Some(rect(0, 0, 100, 50))
+        );
+        let no_width = WindowSize {
+            width: 0,
+            height: 10,
+        };
+        assert_eq!(rect(0, 0, 10, 10).clamp_to(no_width), None);
+        assert!(rect(0, 0, 100, 50).fits_within(size));
+        assert!(!rect(-1, 0, 10, 10).fits_within(size));
+        assert!(!rect(95, 0, 10, 10).fits_within(size));
+    }
+
+    #[test]
+    fn bottom_right() {
+        assert_eq!(
+            rect(-10, -10, 5, 5).checked_bottom_right(),
+            Some(Coordinates { x: -5, y: -5 })
+        );
+        assert_eq!(
+            rect(i32::MAX - 1, 0, 1, 1).checked_bottom_right(),
+            Some(Coordinates { x: i32::MAX, y: 1 })
+        );
+        assert_eq!(rect(i32::MAX, 0, 1, 1).checked_bottom_right(), None);
+        assert_eq!(
+            rect(0, i32::MIN, 0, u32::MAX).checked_bottom_right(),
+            Some(Coordinates { x: 0, y: i32::MAX })
+        );
+    }
+}
@@ -0,0 +1,128 @@
+/*
+ * The Qubes OS Project, https://www.qubes-os.org
+ *
+ * Copyright (C) 2021  Demi Marie Obenour  <demi@invisiblethingslab.com>
+ *
+ * This program is free software; you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License
+ * as published by the Free Software Foundation; either version 2
+ * of the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301, USA.
+ *
+ */
+
+//! Typed access to [`crate::WindowHints`]
+
+use crate::{ProtocolError, WindowHints, WindowHintsFlags, WindowSize, MSG_WINDOW_HINTS};
+
+fn bad_field(field: &'static str) -> ProtocolError {
+    ProtocolError::BadFieldValue {
+        msg: MSG_WINDOW_HINTS,
+        field,
+    }
+}
+
+impl WindowHints {
+    /// A builder with no hints set
+    pub fn builder() -> WindowHintsBuilder {
+        WindowHintsBuilder(Self::default())
+    }
+
+    fn get(&self, flag: WindowHintsFlags, size: WindowSize) -> Option<WindowSize> {
+        if self.flags & flag as u32 != 0 {
+            Some(size)
+        } else {
+            None
+        }
+    }
+
+    /// The minimum size, if [`WindowHintsFlags::PMinSize`] is set
+    pub fn min_size(&self) -> Option<WindowSize> {
+        self.get(WindowHintsFlags::PMinSize, self.min_size)
+    }
+
+    /// The maximum size, if [`WindowHintsFlags::PMaxSize`] is set
+    pub fn max_size(&self) -> Option<WindowSize> {
+        self.get(WindowHintsFlags::PMaxSize, self.max_size)
+    }
+
+    /// The size increment, if [`WindowHintsFlags::PResizeInc`] is set
+    pub fn size_increment(&self) -> Option<WindowSize> {
+        self.get(WindowHintsFlags::PResizeInc, self.size_increment)
+    }
+
+    /// The base size, if [`WindowHintsFlags::PBaseSize`] is set
+    pub fn size_base(&self) -> Option<WindowSize> {
+        self.get(WindowHintsFlags::PBaseSize, self.size_base)
+    }
+
+    /// Check that the minimum size is no larger than the maximum size in
+    /// either dimension, and that the size increment is not zero in either
+    /// dimension.  Hints that are not set are not checked.
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`ProtocolError::BadFieldValue`] naming the offending
+    /// field.
+    pub fn validate(&self) -> Result<(), ProtocolError> {
+        if let (Some(min), Some(max)) = (self.min_size(), self.max_size()) {
+            if min.width > max.width || min.height > max.height {
+                return Err(bad_field("max_size"));
+            }
+        }
+        match self.size_increment() {
+            Some(inc) if inc.width == 0 || inc.height == 0 => Err(bad_field("size_increment")),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Builder for [`WindowHints`] that sets the flag for each hint provided
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct WindowHintsBuilder(WindowHints);
+
+impl WindowHintsBuilder {
+    fn set(mut self, flag: WindowHintsFlags, f: impl FnOnce(&mut WindowHints)) -> Self {
+        self.0.flags |= flag as u32;
+        f(&mut self.0);
+        self
+    }
+
+    /// Set the minimum size
+    pub fn min_size(self, size: WindowSize) -> Self {
+        self.set(WindowHintsFlags::PMinSize, |h| h.min_size = size)
+    }
+
+    /// Set the maximum size
+    pub fn max_size(self, size: WindowSize) -> Self {
+        self.set(WindowHintsFlags::PMaxSize, |h| h.max_size = size)
+    }
+
+    /// Set the size increment
+    pub fn size_increment(self, size: WindowSize) -> Self {
+        self.set(WindowHintsFlags::PResizeInc, |h| h.size_increment = size)
+    }
+
+    /// Set the base size
+    pub fn size_base(self, size: WindowSize) -> Self {
+        self.set(WindowHintsFlags::PBaseSize, |h| h.size_base = size)
+    }
+
+    /// Build the hints
+    ///
+    /// # Errors
+    ///
+    /// Fails if the hints are not valid.  See [`WindowHints::validate`].
+    pub fn build(self) -> Result<WindowHints, ProtocolError> {
+        self.0.validate()?;
+        Ok(self.0)
+    }
+}
@@ -39,6 +39,11 @@
 //! directly into the struct.  This is safe because all possible bit patterns
 //! are valid for every GUI message.  All messages are in native byte order,
 //! which is little-endian for the only platform (amd64) supported by Qubes OS.
+//! Tools that handle messages from another machine, such as capture files,
+//! must treat the wire format as little-endian: `Castable::from_le_bytes` and
+//! `Castable::to_le_order` convert at the cast boundary, and compile to
+//! nothing on little-endian hosts.  [`encode_raw_into`],
+//! [`Message::encode_into`], and [`BoundedDecoder`] do this for headers.
 //!
 //! This is very natural to implement in C, but is much less natural to
 //! implement in Rust, as casting a struct reference to a byte slice is
@@ -112,15 +117,75 @@
 //! and explicitly lists each reference to the X11 protocol specification.  A
 //! future release will not depend on the X11 protocol specification at all,
 //! even for documentation.
+//!
+//! ## Features
+//!
+//! With the `arbitrary` feature, every message struct and [`UntrustedHeader`]
+//! implements `arbitrary::Arbitrary`, generating each field separately.  This
+//! lets fuzzers and property tests produce structurally interesting messages
+//! instead of raw bytes.
+//!
+//! With the `std` feature, [`ProtocolError`], [`BadMsgNameError`],
+//! [`EncodeError`], and [`DecodeError`] implement `std::error::Error`.
+//!
+//! With the `serde` feature, every message struct, [`UntrustedHeader`],
+//! [`Header`], and [`Msg`] implement `serde::Serialize` and
+//! `serde::Deserialize`.  Structs are serialized field by field, with byte
+//! arrays as bytes, and [`Msg`] as its [name](Msg::name).  Deserializing a
+//! [`Header`] validates it.
+//!
+//! With the `defmt` feature, [`UntrustedHeader`], [`Header`], [`Msg`], and the
+//! error types implement `defmt::Format`, for logging from embedded agents
+//! without pulling in `core::fmt`.
+//!
+//! With the `proposed` feature, the `proposed` module defines messages
+//! proposed for a future protocol version.  They are not part of the
+//! protocol yet.  The `dmabuf` feature adds experimental messages for
+//! sharing GPU buffers to it.
 
 #![forbid(missing_docs)]
 #![no_std]
 #![forbid(clippy::all)]
 
+#[cfg(feature = "std")]
+extern crate std;
+
 use core::convert::TryFrom;
 use core::num::NonZeroU32;
 use core::result::Result;
 
+pub mod c_header;
+mod cursor;
+mod decoder;
+mod encode;
+mod error;
+mod flags;
+mod geometry;
+#[cfg(all(test, target_endian = "little"))]
+mod golden;
+mod hints;
+#[cfg(feature = "proposed")]
+pub mod proposed;
+mod redacted;
+mod shm;
+pub mod spec;
+mod violation;
+mod window;
+mod window_info;
+pub mod x11;
+
+pub use cursor::{BadCursorError, CursorShape};
+pub use decoder::{BoundedDecoder, DecodeError};
+pub use encode::{encode_raw_into, EncodeError};
+pub use error::{check_daemon_version, negotiate_agent_version, ProtocolError};
+pub use geometry::{BadSizeError, ValidRectangle, ValidWindowSize};
+pub use hints::WindowHintsBuilder;
+pub use redacted::Redacted;
+pub use shm::ValidShmCmd;
+pub use violation::{Violation, ViolationKind, ViolationSink};
+pub use window::{ValidCreate, ValidMapInfo};
+pub use window_info::{PendingOps, WindowInfo};
+
 /// Arbitrary maximum size of a clipboard message
 pub const MAX_CLIPBOARD_SIZE: u32 = 65000;
 
@@ -211,6 +276,11 @@ macro_rules! enum_const {
                 }
             }
         }
+
+        impl $n {
+            /// All values, in order of declaration
+            $p const ALL: &'static [$n] = &[$($n::$variant_name),*];
+        }
     }
 }
 
@@ -218,6 +288,7 @@ enum_const! {
     #[repr(u32)]
     #[non_exhaustive]
     /// Message types
+    #[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
     pub enum Msg {
         /// Daemon ⇒ agent: A key has been pressed or released.
         (MSG_KEYPRESS, Keypress) = 124,
@@ -275,9 +346,148 @@ enum_const! {
     }
 }
 
+impl Msg {
+    /// The name of the message type, as used in the C headers, without the
+    /// `MSG_` prefix.  [`Msg::SetTitle`] is called `WMNAME` there.
+    pub fn name(self) -> &'static str {
+        match self {
+            Msg::Keypress => "KEYPRESS",
+            Msg::Button => "BUTTON",
+            Msg::Motion => "MOTION",
+            Msg::Crossing => "CROSSING",
+            Msg::Focus => "FOCUS",
+            Msg::Resize => "RESIZE",
+            Msg::Create => "CREATE",
+            Msg::Destroy => "DESTROY",
+            Msg::Map => "MAP",
+            Msg::Unmap => "UNMAP",
+            Msg::Configure => "CONFIGURE",
+            Msg::MfnDump => "MFNDUMP",
+            Msg::ShmImage => "SHMIMAGE",
+            Msg::Close => "CLOSE",
+            Msg::Execute => "EXECUTE",
+            Msg::ClipboardReq => "CLIPBOARD_REQ",
+            Msg::ClipboardData => "CLIPBOARD_DATA",
+            Msg::SetTitle => "WMNAME",
+            Msg::KeymapNotify => "KEYMAP_NOTIFY",
+            Msg::Dock => "DOCK",
+            Msg::WindowHints => "WINDOW_HINTS",
+            Msg::WindowFlags => "WINDOW_FLAGS",
+            Msg::WindowClass => "WINDOW_CLASS",
+            Msg::WindowDump => "WINDOW_DUMP",
+            Msg::Cursor => "CURSOR",
+            Msg::DumpAck => "WINDOW_DUMP_ACK",
+        }
+    }
+}
+
+impl Msg {
+    /// Whether the message type is deprecated.  What to do with these is up
+    /// to the receiver; see [`DeprecatedPolicy`].
+    pub fn is_deprecated(self) -> bool {
+        matches!(self, Msg::Resize | Msg::Execute | Msg::MfnDump)
+    }
+
+    /// The minor protocol version that introduced the message type, or
+    /// `None` if it is in every version.  It must not be sent to peers that
+    /// negotiated an older version.
+    pub fn since(self) -> Option<u32> {
+        match self {
+            Msg::DumpAck => Some(7),
+            _ => None,
+        }
+    }
+}
+
+/// What a receiver does with deprecated messages ([`Msg::is_deprecated`])
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Default)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[non_exhaustive]
+pub enum DeprecatedPolicy {
+    /// Reject them with [`ProtocolError::Deprecated`]
+    Reject,
+    /// Skip them, just like messages of unknown type
+    Ignore,
+    /// Accept the ones with a known layout, which is only `MSG_MFNDUMP`, and
+    /// skip `MSG_RESIZE`.  `MSG_EXECUTE` asked the agent to run a command, so
+    /// it is still rejected, with [`ProtocolError::BadLength`].  This is
+    /// what [`UntrustedHeader::validate_length`] does.
+    #[default]
+    AcceptLegacy,
+}
+
+impl core::fmt::Display for Msg {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// Error indicating that a string is not the name of a message type
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct BadMsgNameError;
+
+impl core::fmt::Display for BadMsgNameError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str("Unknown message name")
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for BadMsgNameError {}
+
+impl core::str::FromStr for Msg {
+    type Err = BadMsgNameError;
+
+    /// Parses a name as returned by [`Msg::name`], with or without the `MSG_`
+    /// prefix.
+    fn from_str(s: &str) -> Result<Self, BadMsgNameError> {
+        let name = s.strip_prefix("MSG_").unwrap_or(s);
+        Self::ALL
+            .iter()
+            .copied()
+            .find(|msg| msg.name() == name)
+            .ok_or(BadMsgNameError)
+    }
+}
+
+#[cfg(feature = "defmt")]
+impl defmt::Format for Msg {
+    fn format(&self, f: defmt::Formatter<'_>) {
+        defmt::write!(f, "{=str}", self.name())
+    }
+}
+
+#[cfg(feature = "serde")]
+impl serde::Serialize for Msg {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.name())
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for Msg {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct Visitor;
+        impl serde::de::Visitor<'_> for Visitor {
+            type Value = Msg;
+
+            fn expecting(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+                f.write_str("a message name")
+            }
+
+            fn visit_str<E: serde::de::Error>(self, v: &str) -> Result<Msg, E> {
+                v.parse().map_err(E::custom)
+            }
+        }
+        deserializer.deserialize_str(Visitor)
+    }
+}
+
 enum_const! {
     #[repr(u32)]
     /// State of a button
+    #[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
     pub enum ButtonEvent {
         /// A button has been pressed
         (EV_BUTTON_PRESS, Press) = 4,
@@ -289,6 +499,7 @@ enum_const! {
 enum_const! {
     #[repr(u32)]
     /// Key change event
+    #[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
     pub enum KeyEvent {
         /// The key was pressed
         (EV_KEY_PRESS, Press) = 2,
@@ -300,6 +511,7 @@ enum_const! {
 enum_const! {
     #[repr(u32)]
     /// Focus change event
+    #[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
     pub enum FocusEvent {
         /// The window now has focus
         (EV_FOCUS_IN, In) = 9,
@@ -308,6 +520,87 @@ enum_const! {
     }
 }
 
+enum_const! {
+    #[repr(u32)]
+    /// X11 mode of a [`Crossing`]
+    #[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+    pub enum CrossingMode {
+        /// `NotifyNormal`
+        (NOTIFY_NORMAL, Normal) = 0,
+        /// `NotifyGrab`
+        (NOTIFY_GRAB, Grab) = 1,
+        /// `NotifyUngrab`
+        (NOTIFY_UNGRAB, Ungrab) = 2,
+    }
+}
+
+enum_const! {
+    #[repr(u32)]
+    /// X11 detail of a [`Focus`]
+    #[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+    pub enum FocusDetail {
+        /// `NotifyAncestor`
+        (NOTIFY_ANCESTOR, Ancestor) = 0,
+        /// `NotifyVirtual`
+        (NOTIFY_VIRTUAL, Virtual) = 1,
+        /// `NotifyInferior`
+        (NOTIFY_INFERIOR, Inferior) = 2,
+        /// `NotifyNonlinear`
+        (NOTIFY_NONLINEAR, Nonlinear) = 3,
+        /// `NotifyNonlinearVirtual`
+        (NOTIFY_NONLINEAR_VIRTUAL, NonlinearVirtual) = 4,
+        /// `NotifyPointer`
+        (NOTIFY_POINTER, Pointer) = 5,
+        /// `NotifyPointerRoot`
+        (NOTIFY_POINTER_ROOT, PointerRoot) = 6,
+        /// `NotifyDetailNone`
+        (NOTIFY_DETAIL_NONE, DetailNone) = 7,
+    }
+}
+
+/// X11 detail of a [`Crossing`].  These are the first five
+/// [`FocusDetail`]s; the constants are shared.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+#[repr(u32)]
+pub enum CrossingDetail {
+    /// `NotifyAncestor`
+    Ancestor = NOTIFY_ANCESTOR,
+    /// `NotifyVirtual`
+    Virtual = NOTIFY_VIRTUAL,
+    /// `NotifyInferior`
+    Inferior = NOTIFY_INFERIOR,
+    /// `NotifyNonlinear`
+    Nonlinear = NOTIFY_NONLINEAR,
+    /// `NotifyNonlinearVirtual`
+    NonlinearVirtual = NOTIFY_NONLINEAR_VIRTUAL,
+}
+
+impl TryFrom<u32> for CrossingDetail {
+    type Error = u32;
+    fn try_from(value: u32) -> Result<Self, u32> {
+        match value {
+            NOTIFY_ANCESTOR => Ok(CrossingDetail::Ancestor),
+            NOTIFY_VIRTUAL => Ok(CrossingDetail::Virtual),
+            NOTIFY_INFERIOR => Ok(CrossingDetail::Inferior),
+            NOTIFY_NONLINEAR => Ok(CrossingDetail::Nonlinear),
+            NOTIFY_NONLINEAR_VIRTUAL => Ok(CrossingDetail::NonlinearVirtual),
+            other => Err(other),
+        }
+    }
+}
+
+impl From<CrossingDetail> for FocusDetail {
+    fn from(detail: CrossingDetail) -> Self {
+        match detail {
+            CrossingDetail::Ancestor => FocusDetail::Ancestor,
+            CrossingDetail::Virtual => FocusDetail::Virtual,
+            CrossingDetail::Inferior => FocusDetail::Inferior,
+            CrossingDetail::Nonlinear => FocusDetail::Nonlinear,
+            CrossingDetail::NonlinearVirtual => FocusDetail::NonlinearVirtual,
+        }
+    }
+}
+
 /// Flags for [`WindowHints`].  These are a bitmask.
 pub enum WindowHintsFlags {
     /// User-specified position
@@ -325,6 +618,7 @@ pub enum WindowHintsFlags {
 }
 
 /// Flags for [`WindowFlags`].  These are a bitmask.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
 pub enum WindowFlag {
     /// Fullscreen request.  This may or may not be honored.
     Fullscreen = 1 << 0,
@@ -334,12 +628,81 @@ pub enum WindowFlag {
     Minimize = 1 << 2,
 }
 
+/// Whether an agent may send messages of type `ty`.  Unknown types are
+/// allowed, so that they can be skipped.
+pub const fn agent_may_send(ty: u32) -> bool {
+    !matches!(
+        ty,
+        MSG_KEYPRESS
+            | MSG_BUTTON
+            | MSG_MOTION
+            | MSG_CROSSING
+            | MSG_FOCUS
+            | MSG_RESIZE
+            | MSG_CLOSE
+            | MSG_EXECUTE
+            | MSG_CLIPBOARD_REQ
+            | MSG_KEYMAP_NOTIFY
+            | MSG_WINDOW_DUMP_ACK
+    )
+}
+
+/// Whether a daemon may send messages of type `ty`.  Unknown types are
+/// allowed, so that they can be skipped.  `MSG_DESTROY` is allowed as an
+/// acknowledgement of the agent destroying a window.
+pub const fn daemon_may_send(ty: u32) -> bool {
+    !matches!(
+        ty,
+        MSG_CREATE
+            | MSG_UNMAP
+            | MSG_MFNDUMP
+            | MSG_SHMIMAGE
+            | MSG_SET_TITLE
+            | MSG_DOCK
+            | MSG_WINDOW_HINTS
+            | MSG_WINDOW_CLASS
+            | MSG_WINDOW_DUMP
+            | MSG_CURSOR
+    )
+}
+
 /// Trait for Qubes GUI structs, specifying the message number.
 pub trait Message: qubes_castable::Castable + core::default::Default {
     /// The kind of the message
     const KIND: Msg;
+
+    /// Write this message, sent to `window`, to the start of `buf`, header
+    /// first.  Returns the number of bytes written.  This does not allocate.
+    ///
+    /// # Errors
+    ///
+    /// Fails if `buf` is too small, or if the message cannot be sent without
+    /// a trailing variable-length part.  Nothing is written in either case.
+    fn encode_into(&self, window: WindowID, buf: &mut [u8]) -> Result<usize, EncodeError> {
+        encode_raw_into(
+            Self::KIND as u32,
+            window,
+            self.to_le_order().as_bytes(),
+            buf,
+        )
+    }
 }
 
+/// A [`Message`] that agents may send.  Sending anything else to a daemon is
+/// a protocol error, so functions that send on behalf of an agent should
+/// require this:
+///
+/// ```compile_fail
+/// fn send<T: qubes_gui::AgentMessage>(_: &T) {}
+/// send(&qubes_gui::Keypress::default());
+/// ```
+pub trait AgentMessage: Message {}
+
+/// A [`Message`] that daemons may send.  Some messages, such as
+/// [`Configure`], may be sent by either side.  [`Destroy`] is only sent by
+/// daemons to acknowledge the agent destroying a window.
+pub trait DaemonMessage: Message {}
+
 impl From<NonZeroU32> for WindowID {
     fn from(other: NonZeroU32) -> Self {
         Self {
@@ -499,9 +862,9 @@ qubes_castable::castable! {
         pub coordinates: Coordinates,
         /// X11 state of the crossing
         pub state: u32,
-        /// X11 mode of the crossing
+        /// X11 mode of the crossing.  See [`CrossingMode`].
         pub mode: u32,
-        /// X11 detail of the crossing
+        /// X11 detail of the crossing.  See [`CrossingDetail`].
         pub detail: u32,
         /// X11 focus of the crossing
         pub focus: u32,
@@ -532,11 +895,14 @@ qubes_castable::castable! {
         /// Daemons MUST set this to 0 to avoid information leaks.  Agents MAY
         /// consider nonzero values to be a protocol error.
         pub mode: u32,
-        /// The X11 event detail.  MUST be between 0 and 7 inclusive.
+        /// The X11 event detail.  MUST be between 0 and 7 inclusive.  See
+        /// [`FocusDetail`].
         pub detail: u32,
     }
 
-    /// Agent ⇒ daemon: Set the window name
+    #[castable(no_debug)]
+    /// Agent ⇒ daemon: Set the window name.  The `Debug` output does not
+    /// include the name.
     pub struct WMName {
         /// NUL-terminated name
         pub data: [u8; 128],
@@ -631,52 +997,176 @@ qubes_castable::castable! {
 }
 
 macro_rules! impl_message {
-    ($(($t: ty, $kind: expr),)+) => {
-        $(impl Message for $t {
-            const KIND: Msg = $kind;
-        })+
-    }
+    ($(($t: ty, $kind: expr, [$($direction: ident),+]),)+) => {
+        $(
+            impl Message for $t {
+                const KIND: Msg = $kind;
+            }
+            $(impl_message!(@$direction $t);)+
+        )+
+    };
+    (@Agent $t: ty) => {
+        const _: () = assert!(agent_may_send(<$t as Message>::KIND as u32));
+        impl AgentMessage for $t {}
+    };
+    (@Daemon $t: ty) => {
+        const _: () = assert!(daemon_may_send(<$t as Message>::KIND as u32));
+        impl DaemonMessage for $t {}
+    };
 }
 
 impl_message! {
-    (MapInfo, Msg::Map),
-    (Create, Msg::Create),
-    (Keypress, Msg::Keypress),
-    (Button, Msg::Button),
-    (Motion, Msg::Motion),
-    (Crossing, Msg::Crossing),
-    (Configure, Msg::Configure),
-    (ShmImage, Msg::ShmImage),
-    (Focus, Msg::Focus),
-    (WMName, Msg::SetTitle),
-    (KeymapNotify, Msg::KeymapNotify),
-    (WindowHints, Msg::WindowHints),
-    (WindowFlags, Msg::WindowFlags),
-    (ShmCmd, Msg::ShmImage),
-    (WMClass, Msg::WindowClass),
-    (WindowDumpHeader, Msg::WindowDump),
-    (Cursor, Msg::Cursor),
-    (Destroy, Msg::Destroy),
-    (Dock, Msg::Dock),
-    (Unmap, Msg::Unmap),
-}
-
-/// Error indicating that the length of a message is bad
-#[derive(Debug)]
-pub struct BadLengthError {
-    /// The type of the bad message
-    pub ty: u32,
-    /// The length of the bad message
-    pub untrusted_len: u32,
-}
-
-impl core::fmt::Display for BadLengthError {
+    (MapInfo, Msg::Map, [Agent, Daemon]),
+    (Create, Msg::Create, [Agent]),
+    (Keypress, Msg::Keypress, [Daemon]),
+    (Button, Msg::Button, [Daemon]),
+    (Motion, Msg::Motion, [Daemon]),
+    (Crossing, Msg::Crossing, [Daemon]),
+    (Configure, Msg::Configure, [Agent, Daemon]),
+    (ShmImage, Msg::ShmImage, [Agent]),
+    (Focus, Msg::Focus, [Daemon]),
+    (WMName, Msg::SetTitle, [Agent]),
+    (KeymapNotify, Msg::KeymapNotify, [Daemon]),
+    (WindowHints, Msg::WindowHints, [Agent]),
+    (WindowFlags, Msg::WindowFlags, [Agent, Daemon]),
+    (ShmCmd, Msg::ShmImage, [Agent]),
+    (WMClass, Msg::WindowClass, [Agent]),
+    (WindowDumpHeader, Msg::WindowDump, [Agent]),
+    (Cursor, Msg::Cursor, [Agent]),
+    (Destroy, Msg::Destroy, [Agent, Daemon]),
+    (Dock, Msg::Dock, [Agent]),
+    (Unmap, Msg::Unmap, [Agent]),
+}
+
+/// Error indicating that a string passed to [`WMName::new`] or
+/// [`WMClass::new`] contains a NUL byte
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct NulError {
+    /// The position of the first NUL byte
+    pub position: usize,
+}
+
+impl core::fmt::Display for NulError {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
-        write!(
-            f,
-            "Bad length {} for message of type {}",
-            self.untrusted_len, self.ty
-        )
+        write!(f, "NUL byte at position {}", self.position)
+    }
+}
+
+/// Copy as much of `s` as fits into `buf`, truncating at a character boundary
+/// and leaving room for the NUL terminator.  `buf` must be zeroed.
+fn copy_c_str(buf: &mut [u8], s: &str) -> Result<(), NulError> {
+    if let Some(position) = s.bytes().position(|b| b == 0) {
+        return Err(NulError { position });
+    }
+    let mut len = s.len().min(buf.len() - 1);
+    while !s.is_char_boundary(len) {
+        len -= 1
+    }
+    buf[..len].copy_from_slice(&s.as_bytes()[..len]);
+    Ok(())
+}
+
+/// The bytes of the NUL-terminated string in `buf`, up to the first NUL (or
+/// the end of `buf`)
+fn c_str_bytes(buf: &[u8]) -> &[u8] {
+    &buf[..buf.iter().position(|&b| b == 0).unwrap_or(buf.len())]
+}
+
+/// Read a NUL-terminated string from `buf`, stopping at the first NUL (or the
+/// end of `buf`) and replacing invalid UTF-8 with U+FFFD.
+#[cfg(feature = "std")]
+fn read_c_str_lossy(buf: &[u8]) -> std::borrow::Cow<'_, str> {
+    std::string::String::from_utf8_lossy(c_str_bytes(buf))
+}
+
+impl WMName {
+    /// Create a [`WMName`] from `name`.  Names longer than 127 bytes are
+    /// truncated at a character boundary, so the result is always
+    /// NUL-terminated.
+    ///
+    /// # Errors
+    ///
+    /// Fails if `name` contains a NUL byte.
+    pub fn new(name: &str) -> Result<Self, NulError> {
+        let mut res = Self::default();
+        copy_c_str(&mut res.data, name)?;
+        Ok(res)
+    }
+
+    /// The name, up to the first NUL byte, with any invalid UTF-8 replaced
+    /// by U+FFFD.  The contents are UNTRUSTED if they came from the other
+    /// side.
+    #[cfg(feature = "std")]
+    pub fn as_str_lossy(&self) -> std::borrow::Cow<'_, str> {
+        read_c_str_lossy(&self.data)
+    }
+}
+
+impl WMClass {
+    /// Create a [`WMClass`] from a class and instance name.  Each is
+    /// truncated to 63 bytes at a character boundary, so the result is
+    /// always NUL-terminated.
+    ///
+    /// # Errors
+    ///
+    /// Fails if either string contains a NUL byte.
+    pub fn new(class: &str, instance: &str) -> Result<Self, NulError> {
+        let mut res = Self::default();
+        copy_c_str(&mut res.res_class, class)?;
+        copy_c_str(&mut res.res_name, instance)?;
+        Ok(res)
+    }
+
+    /// The class, up to the first NUL byte.  See [`WMName::as_str_lossy`].
+    #[cfg(feature = "std")]
+    pub fn class_as_str_lossy(&self) -> std::borrow::Cow<'_, str> {
+        read_c_str_lossy(&self.res_class)
+    }
+
+    /// The instance name, up to the first NUL byte.  See
+    /// [`WMName::as_str_lossy`].
+    #[cfg(feature = "std")]
+    pub fn instance_as_str_lossy(&self) -> std::borrow::Cow<'_, str> {
+        read_c_str_lossy(&self.res_name)
+    }
+}
+
+impl KeymapNotify {
+    /// Is the key with X11 keycode `keycode` pressed?
+    pub fn is_pressed(&self, keycode: u8) -> bool {
+        self.keys[usize::from(keycode >> 3)] & (1 << (keycode & 7)) != 0
+    }
+
+    /// Mark `keycode` as pressed
+    pub fn set(&mut self, keycode: u8) {
+        self.keys[usize::from(keycode >> 3)] |= 1 << (keycode & 7)
+    }
+
+    /// Mark `keycode` as released
+    pub fn clear(&mut self, keycode: u8) {
+        self.keys[usize::from(keycode >> 3)] &= !(1 << (keycode & 7))
+    }
+
+    /// Iterate over the keycodes of all pressed keys, in increasing order
+    pub fn pressed(&self) -> impl Iterator<Item = u8> + '_ {
+        (0..=u8::MAX).filter(move |&keycode| self.is_pressed(keycode))
+    }
+
+    /// The key events needed to go from `self` to `new`, in increasing order
+    /// of keycode.  Only [`Keypress::ty`] and [`Keypress::keycode`] are set.
+    pub fn diff<'a>(&'a self, new: &'a KeymapNotify) -> impl Iterator<Item = Keypress> + 'a {
+        (0..=u8::MAX).filter_map(move |keycode| {
+            let ty = match (self.is_pressed(keycode), new.is_pressed(keycode)) {
+                (false, true) => EV_KEY_PRESS,
+                (true, false) => EV_KEY_RELEASE,
+                _ => return None,
+            };
+            Some(Keypress {
+                ty,
+                keycode: keycode.into(),
+                ..Default::default()
+            })
+        })
     }
 }
 
@@ -688,6 +1178,40 @@ impl core::fmt::Display for BadLengthError {
 pub struct Header(UntrustedHeader);
 
 impl Header {
+    /// The header of a `T` sent to `window`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a `T` cannot be sent without a trailing variable-length
+    /// part, as is the case for [`ShmCmd`].  Use [`Header::with_len`] for
+    /// those.
+    pub fn for_message<T: Message>(window: WindowID) -> Self {
+        match Self::with_len(T::KIND, window, core::mem::size_of::<T>()) {
+            Ok(header) => header,
+            Err(e) => panic!("{} is not a complete message: {}", T::KIND, e),
+        }
+    }
+
+    /// The header of a message of type `kind` with a body of `len` bytes,
+    /// sent to `window`.
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`ProtocolError::BadLength`] if `len` is not a valid length
+    /// for `kind`, or with [`ProtocolError::UnknownType`] if `kind` is
+    /// obsolete and may not be sent at all.
+    pub fn with_len(kind: Msg, window: WindowID, len: usize) -> Result<Self, ProtocolError> {
+        let ty = kind as u32;
+        let header = UntrustedHeader {
+            ty,
+            window,
+            untrusted_len: u32::try_from(len).unwrap_or(u32::MAX),
+        };
+        header
+            .validate_length()?
+            .ok_or(ProtocolError::UnknownType { ty })
+    }
+
     /// Get the type of the header as a u32.
     ///
     /// The type is guaranteed to be a valid message type.
@@ -717,6 +1241,56 @@ impl Header {
     }
 }
 
+#[cfg(feature = "defmt")]
+impl defmt::Format for UntrustedHeader {
+    fn format(&self, f: defmt::Formatter<'_>) {
+        let window = self.window.window.map_or(0, NonZeroU32::get);
+        match Msg::try_from(self.ty) {
+            Ok(msg) => defmt::write!(
+                f,
+                "{} window {=u32:#x} len {=u32}",
+                msg,
+                window,
+                self.untrusted_len
+            ),
+            Err(ty) => defmt::write!(
+                f,
+                "unknown type {=u32} window {=u32:#x} len {=u32}",
+                ty,
+                window,
+                self.untrusted_len
+            ),
+        }
+    }
+}
+
+#[cfg(feature = "defmt")]
+impl defmt::Format for Header {
+    fn format(&self, f: defmt::Formatter<'_>) {
+        defmt::Format::format(&self.0, f)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl serde::Serialize for Header {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serde::Serialize::serialize(&self.0, serializer)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for Header {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let untrusted: UntrustedHeader = serde::Deserialize::deserialize(deserializer)?;
+        match untrusted.validate_length() {
+            Ok(Some(header)) => Ok(header),
+            Ok(None) => Err(ProtocolError::UnknownType { ty: untrusted.ty }),
+            Err(e) => Err(e),
+        }
+        .map_err(serde::de::Error::custom)
+    }
+}
+
 impl UntrustedHeader {
     /// Validate that the length of this header is correct
     ///
@@ -727,9 +1301,9 @@ impl UntrustedHeader {
     ///
     /// # Errors
     ///
-    /// Returns an error if the length is bad, or if the type of the message is
-    /// not valid in any supported protocol version.
-    pub fn validate_length(&self) -> Result<Option<Header>, BadLengthError> {
+    /// Returns [`ProtocolError::BadLength`] if the length is bad, or if the
+    /// type of the message is not valid in any supported protocol version.
+    pub fn validate_length(&self) -> Result<Option<Header>, ProtocolError> {
         const U32_SIZE: u32 = size_of::<u32>() as u32;
         use core::mem::size_of;
         let untrusted_len = self.untrusted_len;
@@ -745,8 +1319,11 @@ impl UntrustedHeader {
             MSG_MAP => untrusted_len == size_of::<MapInfo>() as u32,
             MSG_UNMAP => untrusted_len == 0,
             MSG_CONFIGURE => untrusted_len == size_of::<Configure>() as u32,
-            MSG_MFNDUMP if untrusted_len % U32_SIZE != 0 => false,
-            MSG_MFNDUMP => untrusted_len / U32_SIZE <= MAX_MFN_COUNT,
+            // A ShmCmd, followed by the MFNs
+            MSG_MFNDUMP => match untrusted_len.checked_sub(size_of::<ShmCmd>() as u32) {
+                Some(len) => len % U32_SIZE == 0 && len / U32_SIZE <= MAX_MFN_COUNT,
+                None => false,
+            },
             MSG_SHMIMAGE => untrusted_len == size_of::<ShmImage>() as u32,
             MSG_CLOSE | MSG_CLIPBOARD_REQ => untrusted_len == 0,
             MSG_SET_TITLE => untrusted_len == size_of::<WMName>() as u32,
@@ -762,15 +1339,522 @@ impl UntrustedHeader {
             }
             MSG_CURSOR => untrusted_len == size_of::<Cursor>() as u32,
             MSG_WINDOW_DUMP_ACK => untrusted_len == 0,
+            // Deprecated messages get the treatment described in
+            // `DeprecatedPolicy::AcceptLegacy`
             MSG_EXECUTE => false,
             _ => return Ok(None),
         } {
             Ok(Some(Header(*self)))
         } else {
-            Err(BadLengthError {
+            Err(ProtocolError::BadLength {
                 ty: self.ty,
                 untrusted_len: self.untrusted_len,
             })
         }
     }
+
+    /// Like [`UntrustedHeader::validate_length`], but with `policy` for
+    /// deprecated messages.  Messages that `policy` skips are reported as
+    /// unknown, with `Ok(None)`.
+    ///
+    /// # Errors
+    ///
+    /// Fails like [`UntrustedHeader::validate_length`], and with
+    /// [`ProtocolError::Deprecated`] for deprecated messages if `policy` is
+    /// [`DeprecatedPolicy::Reject`].
+    pub fn validate_length_with(
+        &self,
+        policy: DeprecatedPolicy,
+    ) -> Result<Option<Header>, ProtocolError> {
+        match Msg::try_from(self.ty) {
+            Ok(msg) if msg.is_deprecated() => match policy {
+                DeprecatedPolicy::Reject => Err(ProtocolError::Deprecated { ty: self.ty }),
+                DeprecatedPolicy::Ignore => Ok(None),
+                DeprecatedPolicy::AcceptLegacy => self.validate_length(),
+            },
+            _ => self.validate_length(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use core::mem::size_of;
+    use proptest::prelude::*;
+    use qubes_castable::Castable;
+    use std::vec::Vec;
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde() {
+        let header = UntrustedHeader {
+            ty: MSG_CLIPBOARD_REQ,
+            window: 3.into(),
+            untrusted_len: 0,
+        };
+        let json = serde_json::to_string(&header.validate_length().unwrap().unwrap()).unwrap();
+        assert_eq!(
+            json,
+            r#"{"ty":139,"window":{"window":3},"untrusted_len":0}"#
+        );
+        let parsed: Header = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed.inner(), header);
+        let bad = r#"{"ty":139,"window":{"window":3},"untrusted_len":1}"#;
+        assert!(serde_json::from_str::<Header>(bad).is_err());
+        assert!(serde_json::from_str::<UntrustedHeader>(bad).is_ok());
+
+        assert_eq!(
+            serde_json::to_string(&Msg::SetTitle).unwrap(),
+            r#""WMNAME""#
+        );
+        assert_eq!(
+            serde_json::from_str::<Msg>(r#""MSG_MAP""#).unwrap(),
+            Msg::Map
+        );
+
+        let title = WMName::from_bytes(&[b'x'; 128]);
+        let json = serde_json::to_string(&title).unwrap();
+        assert_eq!(
+            serde_json::from_str::<WMName>(&json).unwrap().data,
+            title.data
+        );
+    }
+
+    #[test]
+    fn swap_endian() {
+        let mut header = UntrustedHeader {
+            ty: 0x0102_0304,
+            window: 0x0506_0708.into(),
+            untrusted_len: 0x090a_0b0c,
+        };
+        header.swap_endian();
+        assert_eq!(header.ty, 0x0403_0201);
+        assert_eq!(header.window, 0x0807_0605.into());
+        assert_eq!(header.untrusted_len, 0x0c0b_0a09);
+        let bytes = [0x89, 0, 0, 0, 3, 0, 0, 0, 0, 0, 0, 0];
+        let header = UntrustedHeader::from_le_bytes(&bytes);
+        assert_eq!((header.ty, header.window), (MSG_CLOSE, 3.into()));
+    }
+
+    #[test]
+    fn msg_names_round_trip() {
+        let all: Vec<u32> = Msg::ALL.iter().map(|&msg| msg as u32).collect();
+        let expected: Vec<u32> = (MSG_KEYPRESS..=MSG_WINDOW_DUMP_ACK)
+            .filter(|&ty| Msg::try_from(ty).is_ok())
+            .collect();
+        assert_eq!(all, expected);
+        for &msg in Msg::ALL {
+            assert_eq!(msg.name().parse(), Ok(msg));
+            assert_eq!(std::format!("MSG_{}", msg).parse(), Ok(msg));
+        }
+        assert_eq!("WMNAME".parse(), Ok(Msg::SetTitle));
+        assert_eq!("SET_TITLE".parse::<Msg>(), Err(BadMsgNameError));
+        assert_eq!("keypress".parse::<Msg>(), Err(BadMsgNameError));
+    }
+
+    #[test]
+    fn header_for_message() {
+        let header = Header::for_message::<Create>(5.into());
+        assert_eq!(
+            header.inner(),
+            UntrustedHeader {
+                ty: MSG_CREATE,
+                window: 5.into(),
+                untrusted_len: size_of::<Create>() as u32,
+            }
+        );
+        assert_eq!(Header::for_message::<Unmap>(5.into()).len(), 0);
+        let data = Header::with_len(Msg::ClipboardData, 0.into(), 3).unwrap();
+        assert_eq!((data.ty(), data.len()), (MSG_CLIPBOARD_DATA, 3));
+        assert_eq!(
+            Header::with_len(Msg::Close, 1.into(), 1),
+            Err(ProtocolError::BadLength {
+                ty: MSG_CLOSE,
+                untrusted_len: 1
+            })
+        );
+        assert_eq!(
+            Header::with_len(Msg::Resize, 1.into(), 0),
+            Err(ProtocolError::UnknownType { ty: MSG_RESIZE })
+        );
+    }
+
+    #[test]
+    fn deprecated_policy() {
+        let header = |ty, untrusted_len| UntrustedHeader {
+            ty,
+            window: 1.into(),
+            untrusted_len,
+        };
+        for ty in [MSG_RESIZE, MSG_EXECUTE, MSG_MFNDUMP] {
+            assert_eq!(
+                header(ty, 4).validate_length_with(DeprecatedPolicy::Reject),
+                Err(ProtocolError::Deprecated { ty })
+            );
+            assert_eq!(
+                header(ty, 4).validate_length_with(DeprecatedPolicy::Ignore),
+                Ok(None)
+            );
+            assert_eq!(
+                header(ty, 4).validate_length_with(DeprecatedPolicy::AcceptLegacy),
+                header(ty, 4).validate_length()
+            );
+        }
+        let mfn_dump = size_of::<ShmCmd>() as u32 + 4;
+        assert!(matches!(
+            header(MSG_MFNDUMP, mfn_dump).validate_length_with(DeprecatedPolicy::AcceptLegacy),
+            Ok(Some(_))
+        ));
+        for len in [4, mfn_dump - 1, mfn_dump + 4 * MAX_MFN_COUNT] {
+            assert!(header(MSG_MFNDUMP, len)
+                .validate_length_with(DeprecatedPolicy::AcceptLegacy)
+                .is_err());
+        }
+        assert_eq!(
+            header(MSG_CREATE, 0).validate_length_with(DeprecatedPolicy::Ignore),
+            header(MSG_CREATE, 0).validate_length()
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "SHMIMAGE is not a complete message")]
+    fn header_for_incomplete_message() {
+        Header::for_message::<ShmCmd>(1.into());
+    }
+
+    #[test]
+    fn window_hints_builder() {
+        let size = |width, height| WindowSize { width, height };
+        let hints = WindowHints::builder()
+            .min_size(size(10, 20))
+            .max_size(size(100, 200))
+            .size_increment(size(1, 2))
+            .build()
+            .unwrap();
+        assert_eq!(
+            hints.flags,
+            WindowHintsFlags::PMinSize as u32
+                | WindowHintsFlags::PMaxSize as u32
+                | WindowHintsFlags::PResizeInc as u32
+        );
+        assert_eq!(hints.min_size(), Some(size(10, 20)));
+        assert_eq!(hints.max_size(), Some(size(100, 200)));
+        assert_eq!(hints.size_increment(), Some(size(1, 2)));
+        assert_eq!(hints.size_base(), None);
+        let bad = |field| ProtocolError::BadFieldValue {
+            msg: MSG_WINDOW_HINTS,
+            field,
+        };
+        assert_eq!(
+            WindowHints::builder()
+                .min_size(size(10, 300))
+                .max_size(size(100, 200))
+                .build(),
+            Err(bad("max_size"))
+        );
+        assert_eq!(
+            WindowHints::builder().size_increment(size(0, 1)).build(),
+            Err(bad("size_increment"))
+        );
+        // Sizes whose flags are not set are ignored
+        let unset = WindowHints {
+            min_size: size(10, 10),
+            size_increment: size(0, 0),
+            ..WindowHints::default()
+        };
+        assert_eq!(unset.validate(), Ok(()));
+        assert_eq!(unset.min_size(), None);
+    }
+
+    #[test]
+    fn window_flags_validation() {
+        let flags = WindowFlags {
+            set: WindowFlag::Fullscreen as u32,
+            unset: WindowFlag::Minimize as u32,
+        };
+        assert_eq!(flags.validate(), Ok(()));
+        assert!(flags.is_fullscreen_requested());
+        assert!(!flags.is_attention_demanded() && !flags.is_minimize_requested());
+        assert!(flags.unsets(WindowFlag::Minimize));
+        let bad = |field| {
+            Err(ProtocolError::BadFieldValue {
+                msg: MSG_WINDOW_FLAGS,
+                field,
+            })
+        };
+        assert_eq!(WindowFlags { set: 8, unset: 0 }.validate(), bad("set"));
+        assert_eq!(WindowFlags { set: 0, unset: 8 }.validate(), bad("unset"));
+        assert_eq!(WindowFlags { set: 1, unset: 3 }.validate(), bad("unset"));
+    }
+
+    #[test]
+    fn mouse_buttons() {
+        use x11::MouseButton;
+        for button in 0..10 {
+            assert_eq!(u32::from(MouseButton::from(button)), button);
+        }
+        let scroll = Button {
+            button: 5,
+            ..Default::default()
+        };
+        assert_eq!(scroll.mouse_button(), MouseButton::ScrollDown);
+        assert!(scroll.is_scroll());
+        assert_eq!(MouseButton::ScrollLeft.scroll_delta(), Some((-1, 0)));
+        assert!(!MouseButton::Right.is_scroll() && !MouseButton::Other(8).is_scroll());
+    }
+
+    #[test]
+    fn create_and_map_info_validation() {
+        let mut create = Create {
+            rectangle: Rectangle {
+                top_left: Coordinates { x: -5, y: 7 },
+                size: WindowSize {
+                    width: 10,
+                    height: 20,
+                },
+            },
+            parent: NonZeroU32::new(3),
+            override_redirect: 1,
+        };
+        let valid = create.validate().unwrap();
+        assert_eq!(valid.rectangle().get(), create.rectangle);
+        assert_eq!(valid.parent(), Some(WindowID::from(3)));
+        assert!(valid.override_redirect());
+        assert_eq!(Create::from(valid), create);
+        create.override_redirect = 2;
+        assert_eq!(
+            create.validate(),
+            Err(ProtocolError::BadFieldValue {
+                msg: MSG_CREATE,
+                field: "override_redirect"
+            })
+        );
+        create.rectangle.size.width = 0;
+        assert_eq!(
+            create.validate(),
+            Err(ProtocolError::BadFieldValue {
+                msg: MSG_CREATE,
+                field: "rectangle"
+            })
+        );
+        let info = MapInfo {
+            transient_for: 0,
+            override_redirect: 0,
+        };
+        let valid = info.validate().unwrap();
+        assert_eq!(
+            (valid.transient_for(), valid.override_redirect()),
+            (None, false)
+        );
+        assert_eq!(MapInfo::from(valid), info);
+        assert!(MapInfo {
+            transient_for: 1,
+            override_redirect: 3
+        }
+        .validate()
+        .is_err());
+    }
+
+    #[test]
+    fn redacted_debug() {
+        use std::format;
+        let name = WMName::new("secret").unwrap();
+        assert_eq!(format!("{:?}", name), "WMName { data: <6 bytes redacted> }");
+        assert_eq!(
+            format!("{:?}", WindowFlags { set: 1, unset: 2 }),
+            "WindowFlags { set: 1, unset: 2 }"
+        );
+    }
+
+    #[test]
+    fn shm_cmd_validation() {
+        let cmd = ShmCmd {
+            shmid: 0,
+            width: 32,
+            height: 32,
+            bpp: 24,
+            off: 16,
+            num_mfn: 2,
+            domid: 0,
+        };
+        let valid = cmd.validate().unwrap();
+        assert_eq!(valid.size().area(), 1024);
+        assert_eq!((valid.offset(), valid.num_mfn()), (16, 2));
+        assert_eq!(ShmCmd::from(valid), cmd);
+        let bad = |field, cmd: ShmCmd| {
+            assert_eq!(
+                cmd.validate(),
+                Err(ProtocolError::BadFieldValue {
+                    msg: MSG_MFNDUMP,
+                    field
+                })
+            )
+        };
+        bad("width", ShmCmd { width: 0, ..cmd });
+        bad(
+            "height",
+            ShmCmd {
+                height: MAX_WINDOW_HEIGHT + 1,
+                ..cmd
+            },
+        );
+        bad("shmid", ShmCmd { shmid: 1, ..cmd });
+        bad("bpp", ShmCmd { bpp: 32, ..cmd });
+        bad(
+            "off",
+            ShmCmd {
+                off: XC_PAGE_SIZE,
+                ..cmd
+            },
+        );
+        bad("domid", ShmCmd { domid: 1, ..cmd });
+        bad("num_mfn", ShmCmd { num_mfn: 1, ..cmd });
+        bad(
+            "num_mfn",
+            ShmCmd {
+                num_mfn: MAX_MFN_COUNT + 1,
+                ..cmd
+            },
+        );
+    }
+
+    #[test]
+    fn wm_name_and_class() {
+        let name = WMName::new("Mail \u{2014} Inbox").unwrap();
+        assert_eq!(c_str_bytes(&name.data), "Mail \u{2014} Inbox".as_bytes());
+        assert_eq!(WMName::new("a\0b").err(), Some(NulError { position: 1 }));
+        // Truncated at a character boundary, leaving room for the NUL
+        let long = "\u{e9}".repeat(100);
+        let name = WMName::new(&long).unwrap();
+        assert_eq!(c_str_bytes(&name.data), long[..126].as_bytes());
+        assert_eq!(name.data[126..], [0, 0]);
+
+        let class = WMClass::new("Navigator", "firefox").unwrap();
+        assert_eq!(c_str_bytes(&class.res_class), b"Navigator");
+        assert_eq!(c_str_bytes(&class.res_name), b"firefox");
+        assert_eq!(
+            WMClass::new("ok", "b\0").err(),
+            Some(NulError { position: 1 })
+        );
+        let class = WMClass::new(&"x".repeat(100), "").unwrap();
+        assert_eq!(c_str_bytes(&class.res_class), "x".repeat(63).as_bytes());
+        assert_eq!(c_str_bytes(&class.res_name), b"");
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn untrusted_wm_name_and_class() {
+        let mut name = WMName::default();
+        name.data[..9].copy_from_slice(b"ab\xffcd\0zz\xfe");
+        // Without a NUL, the whole buffer is used
+        let unterminated = WMName { data: [b'x'; 128] };
+
+        let mut class = WMClass::default();
+        class.res_class[..3].copy_from_slice(b"\xc3(x");
+        class.res_name[..2].copy_from_slice(b"i\xe2");
+        assert_eq!(name.as_str_lossy(), "ab\u{fffd}cd");
+        assert_eq!(unterminated.as_str_lossy(), "x".repeat(128));
+        assert_eq!(class.class_as_str_lossy(), "\u{fffd}(x");
+        assert_eq!(class.instance_as_str_lossy(), "i\u{fffd}");
+    }
+
+    /// Any byte string of exactly the size of `T`
+    fn wire_bytes<T: Castable>() -> impl Strategy<Value = Vec<u8>> {
+        proptest::collection::vec(any::<u8>(), size_of::<T>())
+    }
+
+    /// Lengths around the correct length of `T`, plus any length at all
+    fn lengths<T: Castable>() -> impl Strategy<Value = u32> {
+        let size = size_of::<T>() as u32;
+        prop_oneof![Just(size), 0..=2 * size + 8, any::<u32>()]
+    }
+
+    /// Check that `bytes` are unchanged by a trip through `T`.  This fails if
+    /// `T` has padding or bit patterns that are not valid.
+    fn check_round_trip<T: Castable>(bytes: &[u8]) -> Result<(), TestCaseError> {
+        let value = T::from_bytes(bytes);
+        prop_assert_eq!(value.as_bytes(), bytes);
+        let mut copy = T::zeroed();
+        copy.as_mut_bytes().copy_from_slice(bytes);
+        prop_assert_eq!(copy, value);
+        Ok(())
+    }
+
+    /// Check that a `T` is accepted with a length of exactly `size_of::<T>()`
+    /// and rejected with any other length
+    fn check_length<T: Message>(len: u32) -> Result<(), TestCaseError> {
+        let header = UntrustedHeader {
+            ty: T::KIND as u32,
+            window: 1.into(),
+            untrusted_len: len,
+        };
+        match header.validate_length() {
+            Ok(Some(header)) => prop_assert_eq!(header.len(), size_of::<T>()),
+            Ok(None) => prop_assert!(false, "message type {} is unknown", T::KIND as u32),
+            Err(_) => prop_assert_ne!(len as usize, size_of::<T>()),
+        }
+        Ok(())
+    }
+
+    macro_rules! message_layout_tests {
+        ($($name: ident: $t: ty,)+) => {
+            proptest! {
+                $(
+                    #[test]
+                    fn $name(bytes in wire_bytes::<$t>(), len in lengths::<$t>()) {
+                        check_round_trip::<$t>(&bytes)?;
+                        check_length::<$t>(len)?;
+                    }
+                )+
+            }
+        };
+    }
+
+    macro_rules! layout_tests {
+        ($($name: ident: $t: ty,)+) => {
+            proptest! {
+                $(
+                    #[test]
+                    fn $name(bytes in wire_bytes::<$t>()) {
+                        check_round_trip::<$t>(&bytes)?;
+                    }
+                )+
+            }
+        };
+    }
+
+    message_layout_tests! {
+        map_info: MapInfo,
+        create: Create,
+        keypress: Keypress,
+        button: Button,
+        motion: Motion,
+        crossing: Crossing,
+        configure: Configure,
+        shm_image: ShmImage,
+        focus: Focus,
+        wm_name: WMName,
+        keymap_notify: KeymapNotify,
+        window_hints: WindowHints,
+        window_flags: WindowFlags,
+        wm_class: WMClass,
+        cursor: Cursor,
+        destroy: Destroy,
+        dock: Dock,
+        unmap: Unmap,
+    }
+
+    // These have variable lengths, or are not sent after a header.
+    layout_tests! {
+        window_id: WindowID,
+        untrusted_header: UntrustedHeader,
+        xconf: XConf,
+        xconf_version: XConfVersion,
+        shm_cmd: ShmCmd,
+        window_dump_header: WindowDumpHeader,
+        dump_ack: DumpAck,
+    }
 }
@@ -0,0 +1,1464 @@
+/*
+ * The Qubes OS Project, https://www.qubes-os.org
+ *
+ * Copyright (C) 2021  Demi Marie Obenour  <demi@invisiblethingslab.com>
+ *
+ * This program is free software; you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License
+ * as published by the Free Software Foundation; either version 2
+ * of the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301, USA.
+ *
+ */
+
+//! Messages proposed for protocol version 1.8.
+//!
+//! Nothing here is part of the protocol yet, and numbers and layouts may
+//! change before they are adopted.  The messages may only be sent once
+//! [`PROPOSED_VERSION`] or better has been negotiated.  Before that they are
+//! unknown messages, which daemons reject and agents ignore.
+//!
+//! ## Screen changes
+//!
+//! Without help, agents only learn the size of the root window during
+//! version negotiation.  The daemon sends [`MSG_SCREEN_CONFIGURED`] whenever
+//! it changes, such as when a monitor is plugged in, so that agents can lay
+//! their windows out again without reconnecting.
+//!
+//! ## Scale factors
+//!
+//! As in Wayland's `wp_fractional_scale_v1`, scale factors are fixed-point,
+//! in units of 1/[`SCALE_DENOMINATOR`].  A daemon that negotiates
+//! [`PROPOSED_VERSION`] sends [`XConfScale`] in place of
+//! [`XConfVersion`](crate::XConfVersion), giving the scale factor windows
+//! should be drawn at by default.  It then sends [`MSG_WINDOW_SCALE`]
+//! whenever it would like a window drawn at another scale, such as when the
+//! window moves to another monitor.  Either way, the agent is free to ignore
+//! the request: buffers are still in physical pixels.
+//!
+//! ## Feature bits and pixel formats
+//!
+//! [`XConfScale::features`] says which optional features the daemon
+//! supports.  Agents must ignore bits they do not know.  With
+//! [`FEATURE_ARGB32`], the agent may set [`WindowDumpHeader::bpp`] to 32
+//! ([`PixelFormat::Argb8888`]), so that the top byte of each pixel is alpha
+//! instead of being ignored.  Either way, pixels are 4 bytes each, so a
+//! buffer needs the same number of grant references.
+//!
+//! ## Row stride
+//!
+//! In a `MSG_WINDOW_DUMP` buffer, each row starts right after the one
+//! before.  Buffers from GPUs and toolkits often pad rows for alignment, so
+//! [`MSG_WINDOW_DUMP_STRIDED`] gives the stride, the number of bytes from the
+//! start of one row to the start of the next, explicitly.  It is otherwise
+//! the same as `MSG_WINDOW_DUMP`, and is acknowledged the same way.
+//!
+//! ## Incremental dumps
+//!
+//! Resizing a window by a little should not mean granting its whole buffer
+//! again.  [`MSG_WINDOW_DUMP_UPDATE`] changes the size of a window that
+//! already has a buffer, and replaces [`WindowDumpUpdate::removed`] of its
+//! grant references, starting at page [`WindowDumpUpdate::first_page`], with
+//! the ones that follow the message.  Those that are not replaced keep their
+//! pages, but not necessarily their contents, as rows may move.  The result
+//! must be a valid buffer for the new size.  The daemon acknowledges it as
+//! it would a `MSG_WINDOW_DUMP`.
+//!
+//! ## Touch input
+//!
+//! The daemon reports touchscreen input with [`MSG_TOUCH`], instead of
+//! synthesizing pointer events.  Each finger on the screen has a slot, from
+//! 0 to [`MAX_TOUCH_SLOTS`] - 1, which it keeps from [`TouchEvent::Down`] to
+//! [`TouchEvent::Up`].  A touch point belongs to the window it went down in,
+//! and its coordinates stay relative to that window even if the finger
+//! leaves it.  A slot is only reused once its touch point is up.
+//!
+//! ## Large clipboard transfers
+//!
+//! `MSG_CLIPBOARD_DATA` is limited to [`MAX_CLIPBOARD_SIZE`] bytes.  Larger
+//! clipboard contents are sent in pieces: [`MSG_CLIPBOARD_BEGIN`] with the
+//! total length, then [`MSG_CLIPBOARD_CHUNK`]s, then [`MSG_CLIPBOARD_END`].
+//! Both directions work the same way.
+//!
+//! Each side says how much it is willing to receive with
+//! [`MSG_CLIPBOARD_LIMIT`], once, after version negotiation.  Until it has
+//! done so, the other side must not send more than [`MAX_CLIPBOARD_SIZE`]
+//! bytes.  No limit may exceed [`MAX_CHUNKED_CLIPBOARD_SIZE`].
+//! [`ClipboardSender`] splits clipboard contents into messages, and
+//! [`ClipboardReceiver`] checks the messages as they arrive, so that the
+//! receiver can reassemble the contents without trusting the sender.
+//!
+//! ## Keyboard layouts
+//!
+//! `MSG_KEYMAP_NOTIFY` says which keys are pressed, but not what they mean.
+//! The daemon sends [`MSG_KEYBOARD_LAYOUT`] after version negotiation, and
+//! again whenever the layout changes, naming the layout the way xkb does:
+//! rules, model, layout, variant, and options (RMLVO).  The agent compiles
+//! the names into a keymap itself, so the daemon never sends keymap source
+//! for the agent's xkb compiler to parse.
+//!
+//! ## GPU buffers
+//!
+//! The experimental `dmabuf` module, enabled by the `dmabuf` feature,
+//! shares window buffers as virtio-gpu resources instead of grant references.
+//! Agents may only use it if the daemon sets [`FEATURE_DMABUF`].
+
+#[cfg(feature = "dmabuf")]
+pub mod dmabuf;
+
+use crate::{
+    Coordinates, ProtocolError, UntrustedHeader, ValidWindowSize, WindowDumpHeader, WindowSize,
+    XConf, XConfVersion, MAX_CLIPBOARD_SIZE, MAX_GRANT_REFS_COUNT, MSG_WINDOW_DUMP,
+    PROTOCOL_VERSION_MAJOR, WINDOW_DUMP_TYPE_GRANT_REFS, XC_PAGE_SIZE,
+};
+use core::convert::TryFrom;
+use core::mem::size_of;
+use core::num::NonZeroU32;
+
+/// The protocol version that the proposed messages need
+pub const PROPOSED_VERSION: u32 = PROTOCOL_VERSION_MAJOR << 16 | 8;
+
+/// Daemon ⇒ agent: The root window has changed.  The body is an [`XConf`],
+/// and the window is 0.
+pub const MSG_SCREEN_CONFIGURED: u32 = 150;
+
+/// Daemon ⇒ agent: The scale factor the daemon would like a window drawn at.
+/// The body is a [`WindowScale`].
+pub const MSG_WINDOW_SCALE: u32 = 151;
+
+/// Daemon ⇒ agent: A touch point has gone down, moved, or gone up.  The body
+/// is a [`Touch`].
+pub const MSG_TOUCH: u32 = 152;
+
+/// Bidirectional: How many bytes of clipboard data the sender will accept.
+/// The body is a [`ClipboardLimit`].
+pub const MSG_CLIPBOARD_LIMIT: u32 = 153;
+
+/// Bidirectional: Clipboard data follows in [`MSG_CLIPBOARD_CHUNK`]s.  The
+/// body is a [`ClipboardBegin`].
+pub const MSG_CLIPBOARD_BEGIN: u32 = 154;
+
+/// Bidirectional: Part of the clipboard data.  The body is between 1 and
+/// [`MAX_CLIPBOARD_CHUNK`] bytes of it.
+pub const MSG_CLIPBOARD_CHUNK: u32 = 155;
+
+/// Bidirectional: All of the clipboard data has been sent.  The body is
+/// empty.
+pub const MSG_CLIPBOARD_END: u32 = 156;
+
+/// The largest body of a [`MSG_CLIPBOARD_CHUNK`]
+pub const MAX_CLIPBOARD_CHUNK: u32 = MAX_CLIPBOARD_SIZE;
+
+/// The largest limit either side may ask for with [`MSG_CLIPBOARD_LIMIT`]
+pub const MAX_CHUNKED_CLIPBOARD_SIZE: u32 = 64 << 20;
+
+/// Feature bit: the daemon accepts window dumps in
+/// [`PixelFormat::Argb8888`]
+pub const FEATURE_ARGB32: u32 = 1 << 0;
+
+/// Feature bit: the daemon can import GPU buffers.  See `dmabuf`; this
+/// bit is defined even without that module, so that it is never reused.
+pub const FEATURE_DMABUF: u32 = 1 << 1;
+
+/// Every feature bit defined so far
+pub const ALL_FEATURES: u32 = FEATURE_ARGB32 | FEATURE_DMABUF;
+
+/// Daemon ⇒ agent: The keyboard layout has changed.  The body is a
+/// [`KeyboardLayout`].
+pub const MSG_KEYBOARD_LAYOUT: u32 = 157;
+
+/// Agent ⇒ daemon: Like `MSG_WINDOW_DUMP`, but with an explicit row stride.
+/// The body is a [`WindowDumpStrided`] followed by the grant references.
+pub const MSG_WINDOW_DUMP_STRIDED: u32 = 158;
+
+/// Agent ⇒ daemon: Change the size of the buffer of a window by replacing
+/// some of its grant references.  The body is a [`WindowDumpUpdate`]
+/// followed by the new grant references.
+pub const MSG_WINDOW_DUMP_UPDATE: u32 = 159;
+
+/// The number of touch points that can be down at once
+pub const MAX_TOUCH_SLOTS: u32 = 16;
+
+/// Scale factors are in units of one over this
+pub const SCALE_DENOMINATOR: u32 = 120;
+
+/// The smallest valid scale factor, ×0.5
+pub const MIN_SCALE: u32 = SCALE_DENOMINATOR / 2;
+
+/// The largest valid scale factor, ×8
+pub const MAX_SCALE: u32 = SCALE_DENOMINATOR * 8;
+
+qubes_castable::castable! {
+    /// Daemon ⇒ agent: The scale factor the daemon would like a window drawn
+    /// at.
+    pub struct WindowScale {
+        /// Scale factor, in units of 1/[`SCALE_DENOMINATOR`].  Must be
+        /// between [`MIN_SCALE`] and [`MAX_SCALE`] inclusive.
+        pub scale: u32,
+    }
+
+    /// Daemon ⇒ agent: Version, root window configuration, default scale
+    /// factor, and feature bits; sent only at startup, without a header, in
+    /// place of
+    /// [`XConfVersion`] if [`PROPOSED_VERSION`] or better was negotiated.  It
+    /// starts with an [`XConfVersion`], so the agent reads that first and
+    /// then the rest if the version calls for it.
+    pub struct XConfScale {
+        /// Version and root window configuration
+        pub xconf: XConfVersion,
+        /// Default scale factor, in units of 1/[`SCALE_DENOMINATOR`].  Must be
+        /// between [`MIN_SCALE`] and [`MAX_SCALE`] inclusive.
+        pub default_scale: u32,
+        /// Optional features the daemon supports, such as
+        /// [`FEATURE_ARGB32`]
+        pub features: u32,
+    }
+
+    /// Daemon ⇒ agent: A touch point has gone down, moved, or gone up
+    pub struct Touch {
+        /// A [`TouchEvent`]
+        pub ty: u32,
+        /// The slot of the touch point.  Must be less than
+        /// [`MAX_TOUCH_SLOTS`].
+        pub slot: u32,
+        /// Where the touch point is, relative to the window it went down in
+        pub coordinates: Coordinates,
+    }
+
+    /// Bidirectional: How many bytes of clipboard data the sender will accept
+    pub struct ClipboardLimit {
+        /// The limit, no more than [`MAX_CHUNKED_CLIPBOARD_SIZE`]
+        pub limit: u32,
+    }
+
+    /// Bidirectional: The start of a chunked clipboard transfer
+    pub struct ClipboardBegin {
+        /// UNTRUSTED total length of the data, which must not exceed the
+        /// receiver's limit
+        pub untrusted_len: u32,
+    }
+
+    /// Daemon ⇒ agent: The xkb RMLVO names of the keyboard layout.  Each
+    /// name is printable ASCII without spaces, padded with NUL bytes, and
+    /// NUL-terminated.  Empty names mean the xkb default.
+    pub struct KeyboardLayout {
+        /// Rules file, such as `evdev`
+        pub rules: [u8; 32],
+        /// Keyboard model, such as `pc105`
+        pub model: [u8; 32],
+        /// Comma-separated layouts, such as `us,de`
+        pub layout: [u8; 64],
+        /// Comma-separated variants, one per layout
+        pub variant: [u8; 64],
+        /// Comma-separated options, such as `grp:alt_shift_toggle`
+        pub options: [u8; 128],
+    }
+
+    /// Agent ⇒ daemon: Header of a [`MSG_WINDOW_DUMP_STRIDED`] message
+    pub struct WindowDumpStrided {
+        /// The same as in `MSG_WINDOW_DUMP`
+        pub header: WindowDumpHeader,
+        /// Bytes from the start of one row to the start of the next.  Must
+        /// be a multiple of 4, and no less than the width times the bytes
+        /// per pixel.
+        pub stride: u32,
+    }
+
+    /// Agent ⇒ daemon: Header of a [`MSG_WINDOW_DUMP_UPDATE`] message
+    pub struct WindowDumpUpdate {
+        /// The new size and format of the buffer, as in `MSG_WINDOW_DUMP`
+        pub header: WindowDumpHeader,
+        /// Index of the first grant reference to replace.  Must be no more
+        /// than the number the buffer has.
+        pub first_page: u32,
+        /// How many grant references to remove, starting at
+        /// [`WindowDumpUpdate::first_page`]
+        pub removed: u32,
+    }
+}
+
+impl ClipboardLimit {
+    /// Validate this message.  Returns the limit.
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`ProtocolError::BadFieldValue`] if the limit is larger
+    /// than [`MAX_CHUNKED_CLIPBOARD_SIZE`].
+    pub fn validate(&self) -> Result<u32, ProtocolError> {
+        if self.limit > MAX_CHUNKED_CLIPBOARD_SIZE {
+            Err(ProtocolError::BadFieldValue {
+                msg: MSG_CLIPBOARD_LIMIT,
+                field: "limit",
+            })
+        } else {
+            Ok(self.limit)
+        }
+    }
+}
+
+/// A chunked clipboard transfer went wrong
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[non_exhaustive]
+pub enum ClipboardError {
+    /// The data is larger than the limit
+    TooLarge {
+        /// UNTRUSTED length of the data
+        len: u32,
+        /// The limit
+        limit: u32,
+    },
+    /// A transfer began while another was in progress
+    AlreadyStarted,
+    /// A chunk or the end arrived with no transfer in progress
+    NotStarted,
+    /// A chunk was empty, or took the data past the length it began with
+    BadChunk {
+        /// UNTRUSTED length of the chunk
+        len: u32,
+    },
+    /// The transfer ended before all of the data arrived
+    Truncated {
+        /// The length the transfer began with
+        expected: u32,
+        /// How much arrived
+        received: u32,
+    },
+}
+
+impl core::fmt::Display for ClipboardError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match *self {
+            ClipboardError::TooLarge { len, limit } => write!(
+                f,
+                "Clipboard data of {} bytes exceeds the limit of {} bytes",
+                len, limit
+            ),
+            ClipboardError::AlreadyStarted => f.write_str("Clipboard transfer already started"),
+            ClipboardError::NotStarted => f.write_str("No clipboard transfer in progress"),
+            ClipboardError::BadChunk { len } => write!(f, "Bad clipboard chunk of {} bytes", len),
+            ClipboardError::Truncated { expected, received } => write!(
+                f,
+                "Clipboard transfer ended after {} of {} bytes",
+                received, expected
+            ),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for ClipboardError {}
+
+/// A message of a chunked clipboard transfer, as produced by
+/// [`ClipboardSender`]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ClipboardMessage<'a> {
+    /// Send [`MSG_CLIPBOARD_BEGIN`] with this body
+    Begin(ClipboardBegin),
+    /// Send [`MSG_CLIPBOARD_CHUNK`] with this body
+    Chunk(&'a [u8]),
+    /// Send [`MSG_CLIPBOARD_END`]
+    End,
+}
+
+/// Splits clipboard data into the messages of a chunked transfer.  This is
+/// an iterator over the messages to send, in order.
+#[derive(Debug, Clone)]
+pub struct ClipboardSender<'a> {
+    /// Data not yet sent, or `None` once the end has been sent
+    data: Option<&'a [u8]>,
+    /// Has the beginning been sent?
+    begun: bool,
+}
+
+impl<'a> ClipboardSender<'a> {
+    /// Send `data` to a peer that accepts up to `limit` bytes, as it said
+    /// with [`MSG_CLIPBOARD_LIMIT`]
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`ClipboardError::TooLarge`] if `data` is larger than
+    /// `limit` or [`MAX_CHUNKED_CLIPBOARD_SIZE`].
+    pub fn new(data: &'a [u8], limit: u32) -> Result<Self, ClipboardError> {
+        let limit = limit.min(MAX_CHUNKED_CLIPBOARD_SIZE);
+        let len = u32::try_from(data.len()).unwrap_or(u32::MAX);
+        if len > limit {
+            return Err(ClipboardError::TooLarge { len, limit });
+        }
+        Ok(Self {
+            data: Some(data),
+            begun: false,
+        })
+    }
+}
+
+impl<'a> Iterator for ClipboardSender<'a> {
+    type Item = ClipboardMessage<'a>;
+
+    fn next(&mut self) -> Option<ClipboardMessage<'a>> {
+        let data = self.data?;
+        if !self.begun {
+            self.begun = true;
+            return Some(ClipboardMessage::Begin(ClipboardBegin {
+                untrusted_len: data.len() as u32,
+            }));
+        }
+        if data.is_empty() {
+            self.data = None;
+            return Some(ClipboardMessage::End);
+        }
+        let (chunk, rest) = data.split_at(data.len().min(MAX_CLIPBOARD_CHUNK as usize));
+        self.data = Some(rest);
+        Some(ClipboardMessage::Chunk(chunk))
+    }
+}
+
+/// Checks the messages of chunked clipboard transfers as they arrive.  The
+/// caller keeps the data itself, appending each chunk that is accepted, and
+/// so never holds more than the limit.
+#[derive(Debug, Clone)]
+pub struct ClipboardReceiver {
+    limit: u32,
+    /// The length the current transfer began with, and how much has arrived
+    transfer: Option<(u32, u32)>,
+}
+
+impl ClipboardReceiver {
+    /// Accept transfers of up to `limit` bytes, which should also be sent to
+    /// the peer with [`MSG_CLIPBOARD_LIMIT`].  Larger limits are reduced to
+    /// [`MAX_CHUNKED_CLIPBOARD_SIZE`].
+    pub fn new(limit: u32) -> Self {
+        Self {
+            limit: limit.min(MAX_CHUNKED_CLIPBOARD_SIZE),
+            transfer: None,
+        }
+    }
+
+    /// The limit to send with [`MSG_CLIPBOARD_LIMIT`]
+    pub fn limit(&self) -> ClipboardLimit {
+        ClipboardLimit { limit: self.limit }
+    }
+
+    /// Handle a [`MSG_CLIPBOARD_BEGIN`].  Returns the total length of the
+    /// data, for which space can now be reserved.
+    ///
+    /// # Errors
+    ///
+    /// Fails if a transfer is already in progress, or if the data is larger
+    /// than the limit.  Neither starts a transfer.
+    pub fn begin(&mut self, begin: &ClipboardBegin) -> Result<u32, ClipboardError> {
+        if self.transfer.is_some() {
+            return Err(ClipboardError::AlreadyStarted);
+        }
+        let len = begin.untrusted_len;
+        if len > self.limit {
+            return Err(ClipboardError::TooLarge {
+                len,
+                limit: self.limit,
+            });
+        }
+        self.transfer = Some((len, 0));
+        Ok(len)
+    }
+
+    /// Handle a [`MSG_CLIPBOARD_CHUNK`] with a body of `len` bytes.  If this
+    /// succeeds, the body is the next part of the data.
+    ///
+    /// # Errors
+    ///
+    /// Fails if no transfer is in progress, or if the chunk is empty or
+    /// takes the data past its length.  The transfer is abandoned.
+    pub fn chunk(&mut self, len: usize) -> Result<(), ClipboardError> {
+        let (expected, received) = self.transfer.take().ok_or(ClipboardError::NotStarted)?;
+        let len = u32::try_from(len).unwrap_or(u32::MAX);
+        match received.checked_add(len) {
+            Some(total) if len != 0 && total <= expected => {
+                self.transfer = Some((expected, total));
+                Ok(())
+            }
+            _ => Err(ClipboardError::BadChunk { len }),
+        }
+    }
+
+    /// Handle a [`MSG_CLIPBOARD_END`].  Returns the total length of the
+    /// data, which is now complete.
+    ///
+    /// # Errors
+    ///
+    /// Fails if no transfer is in progress, or if some of the data is
+    /// missing.  Either way, no transfer is in progress afterwards.
+    pub fn end(&mut self) -> Result<u32, ClipboardError> {
+        match self.transfer.take() {
+            None => Err(ClipboardError::NotStarted),
+            Some((expected, received)) if expected == received => Ok(received),
+            Some((expected, received)) => Err(ClipboardError::Truncated { expected, received }),
+        }
+    }
+
+    /// Is a transfer in progress?
+    pub fn in_progress(&self) -> bool {
+        self.transfer.is_some()
+    }
+
+    /// Abandon any transfer in progress, as after a reconnection
+    pub fn reset(&mut self) {
+        self.transfer = None
+    }
+}
+
+/// A [`KeyboardLayout`] whose names are all valid
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct ValidKeyboardLayout(KeyboardLayout);
+
+/// Check that `field` is a valid name in a [`KeyboardLayout`], returning the
+/// name without its padding
+fn layout_name(field: &[u8]) -> Option<&str> {
+    let len = field.iter().position(|&c| c == 0)?;
+    let (name, padding) = field.split_at(len);
+    if name.iter().all(|c| c.is_ascii_graphic()) && padding.iter().all(|&c| c == 0) {
+        core::str::from_utf8(name).ok()
+    } else {
+        None
+    }
+}
+
+/// Get a name that has already been validated
+fn valid_layout_name(field: &[u8]) -> &str {
+    layout_name(field).expect("validated by KeyboardLayout::validate()")
+}
+
+impl KeyboardLayout {
+    /// Validate this message.
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`ProtocolError::BadFieldValue`] naming the first field
+    /// that is not NUL-terminated, has garbage after the terminator, or
+    /// contains anything but printable ASCII.
+    pub fn validate(&self) -> Result<ValidKeyboardLayout, ProtocolError> {
+        let fields: [(&[u8], &'static str); 5] = [
+            (&self.rules, "rules"),
+            (&self.model, "model"),
+            (&self.layout, "layout"),
+            (&self.variant, "variant"),
+            (&self.options, "options"),
+        ];
+        for &(value, field) in fields.iter() {
+            if layout_name(value).is_none() {
+                return Err(ProtocolError::BadFieldValue {
+                    msg: MSG_KEYBOARD_LAYOUT,
+                    field,
+                });
+            }
+        }
+        Ok(ValidKeyboardLayout(*self))
+    }
+}
+
+impl ValidKeyboardLayout {
+    /// The rules file
+    pub fn rules(&self) -> &str {
+        valid_layout_name(&self.0.rules)
+    }
+
+    /// The keyboard model
+    pub fn model(&self) -> &str {
+        valid_layout_name(&self.0.model)
+    }
+
+    /// The comma-separated layouts
+    pub fn layout(&self) -> &str {
+        valid_layout_name(&self.0.layout)
+    }
+
+    /// The comma-separated variants
+    pub fn variant(&self) -> &str {
+        valid_layout_name(&self.0.variant)
+    }
+
+    /// The comma-separated options
+    pub fn options(&self) -> &str {
+        valid_layout_name(&self.0.options)
+    }
+
+    /// The underlying message
+    pub fn get(&self) -> KeyboardLayout {
+        self.0
+    }
+}
+
+impl From<ValidKeyboardLayout> for KeyboardLayout {
+    fn from(layout: ValidKeyboardLayout) -> Self {
+        layout.0
+    }
+}
+
+/// The type of a [`Touch`]
+#[repr(u32)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum TouchEvent {
+    /// A touch point has gone down in a free slot
+    Down = 0,
+    /// A touch point that is down has moved
+    Motion = 1,
+    /// A touch point has gone up, freeing its slot
+    Up = 2,
+}
+
+impl TryFrom<u32> for TouchEvent {
+    type Error = u32;
+
+    fn try_from(value: u32) -> Result<Self, u32> {
+        match value {
+            0 => Ok(TouchEvent::Down),
+            1 => Ok(TouchEvent::Motion),
+            2 => Ok(TouchEvent::Up),
+            other => Err(other),
+        }
+    }
+}
+
+/// A [`Touch`] that satisfies every documented invariant
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct ValidTouch {
+    event: TouchEvent,
+    slot: u32,
+    coordinates: Coordinates,
+}
+
+impl Touch {
+    /// Validate this message.  Whether the slot is free or in use is not
+    /// checked.
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`ProtocolError::BadFieldValue`] naming the offending
+    /// field.
+    pub fn validate(&self) -> Result<ValidTouch, ProtocolError> {
+        let bad = |field| ProtocolError::BadFieldValue {
+            msg: MSG_TOUCH,
+            field,
+        };
+        let event = TouchEvent::try_from(self.ty).map_err(|_| bad("ty"))?;
+        if self.slot >= MAX_TOUCH_SLOTS {
+            return Err(bad("slot"));
+        }
+        Ok(ValidTouch {
+            event,
+            slot: self.slot,
+            coordinates: self.coordinates,
+        })
+    }
+}
+
+impl ValidTouch {
+    /// What happened to the touch point
+    pub fn event(self) -> TouchEvent {
+        self.event
+    }
+
+    /// The slot of the touch point, less than [`MAX_TOUCH_SLOTS`]
+    pub fn slot(self) -> u32 {
+        self.slot
+    }
+
+    /// Where the touch point is
+    pub fn coordinates(self) -> Coordinates {
+        self.coordinates
+    }
+
+    /// The underlying message
+    pub fn get(self) -> Touch {
+        Touch {
+            ty: self.event as u32,
+            slot: self.slot,
+            coordinates: self.coordinates,
+        }
+    }
+}
+
+impl From<ValidTouch> for Touch {
+    fn from(touch: ValidTouch) -> Self {
+        touch.get()
+    }
+}
+
+/// A scale factor between [`MIN_SCALE`] and [`MAX_SCALE`]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ValidScale(NonZeroU32);
+
+impl ValidScale {
+    /// No scaling
+    pub const IDENTITY: ValidScale = match NonZeroU32::new(SCALE_DENOMINATOR) {
+        Some(scale) => ValidScale(scale),
+        None => unreachable!(),
+    };
+
+    /// The scale factor `scale`/[`SCALE_DENOMINATOR`], or `None` if it is not
+    /// between [`MIN_SCALE`] and [`MAX_SCALE`]
+    pub fn new(scale: u32) -> Option<Self> {
+        if (MIN_SCALE..=MAX_SCALE).contains(&scale) {
+            NonZeroU32::new(scale).map(Self)
+        } else {
+            None
+        }
+    }
+
+    /// The scale factor, in units of 1/[`SCALE_DENOMINATOR`]
+    pub fn get(self) -> u32 {
+        self.0.get()
+    }
+
+    /// The scale factor as a floating-point number
+    pub fn to_f64(self) -> f64 {
+        f64::from(self.get()) / f64::from(SCALE_DENOMINATOR)
+    }
+}
+
+impl Default for ValidScale {
+    fn default() -> Self {
+        Self::IDENTITY
+    }
+}
+
+impl WindowScale {
+    /// Validate this message.
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`ProtocolError::BadFieldValue`] if the scale factor is out
+    /// of range.
+    pub fn validate(&self) -> Result<ValidScale, ProtocolError> {
+        ValidScale::new(self.scale).ok_or(ProtocolError::BadFieldValue {
+            msg: MSG_WINDOW_SCALE,
+            field: "scale",
+        })
+    }
+}
+
+impl XConfScale {
+    /// The default scale factor, or `None` if it is out of range.  Agents
+    /// must treat that as a protocol error.
+    pub fn default_scale(&self) -> Option<ValidScale> {
+        ValidScale::new(self.default_scale)
+    }
+
+    /// Does the daemon support `feature`?  Unknown bits are ignored.
+    pub fn supports(&self, feature: u32) -> bool {
+        self.features & feature & ALL_FEATURES != 0
+    }
+}
+
+/// The layout of the pixels of a window dump, given by
+/// [`WindowDumpHeader::bpp`]
+#[repr(u32)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Default)]
+pub enum PixelFormat {
+    /// `0x00RRGGBB`; the top byte is ignored.  The only format without
+    /// [`FEATURE_ARGB32`].
+    #[default]
+    Xrgb8888 = 24,
+    /// `0xAARRGGBB`, with premultiplied alpha.  Needs [`FEATURE_ARGB32`].
+    Argb8888 = 32,
+}
+
+impl TryFrom<u32> for PixelFormat {
+    type Error = u32;
+
+    fn try_from(value: u32) -> Result<Self, u32> {
+        match value {
+            24 => Ok(PixelFormat::Xrgb8888),
+            32 => Ok(PixelFormat::Argb8888),
+            other => Err(other),
+        }
+    }
+}
+
+impl PixelFormat {
+    /// The value of [`WindowDumpHeader::bpp`] for this format
+    pub fn bpp(self) -> u32 {
+        self as u32
+    }
+
+    /// Does the top byte of each pixel hold alpha?
+    pub fn has_alpha(self) -> bool {
+        self == PixelFormat::Argb8888
+    }
+
+    /// The feature bit needed to use this format, if any
+    pub fn feature(self) -> Option<u32> {
+        match self {
+            PixelFormat::Xrgb8888 => None,
+            PixelFormat::Argb8888 => Some(FEATURE_ARGB32),
+        }
+    }
+
+    /// The size of a pixel in memory.  This is 4 for every format, as
+    /// [`PixelFormat::Xrgb8888`] is padded.
+    pub fn bytes_per_pixel(self) -> u32 {
+        4
+    }
+
+    /// The number of pages, and so of grant references, needed for a
+    /// buffer of size `size`.  This is at most [`MAX_GRANT_REFS_COUNT`].
+    pub fn pages(self, size: ValidWindowSize) -> u32 {
+        // Cannot overflow: the area is at most MAX_WINDOW_MEM / 4
+        let bytes = u64::from(size.area()) * u64::from(self.bytes_per_pixel());
+        bytes.div_ceil(u64::from(XC_PAGE_SIZE)) as u32
+    }
+}
+
+/// A [`WindowDumpHeader`] that satisfies every documented invariant, along
+/// with the row stride and the number of grant references after it
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct ValidWindowDump {
+    size: ValidWindowSize,
+    format: PixelFormat,
+    stride: u32,
+    strided: bool,
+    grant_refs: u32,
+}
+
+/// Validate a `MSG_WINDOW_DUMP` with header `header` and `grant_refs` grant
+/// references, received by a daemon that sent `features` in its
+/// [`XConfScale`].  The type must be [`WINDOW_DUMP_TYPE_GRANT_REFS`], the
+/// size must be valid, the format must be one the features allow, and there
+/// must be enough grant references for the buffer but no more than
+/// [`MAX_GRANT_REFS_COUNT`].
+///
+/// # Errors
+///
+/// Fails with [`ProtocolError::BadFieldValue`] naming the first field that
+/// is not valid, or `grant_refs` if the number of grant references is
+/// wrong.
+pub fn validate_window_dump(
+    header: &WindowDumpHeader,
+    grant_refs: u32,
+    features: u32,
+) -> Result<ValidWindowDump, ProtocolError> {
+    validate_dump(MSG_WINDOW_DUMP, header, None, grant_refs, features)
+}
+
+impl WindowDumpStrided {
+    /// Validate this header, followed by `grant_refs` grant references, as
+    /// received by a daemon that sent `features` in its [`XConfScale`].
+    /// The header is checked as by [`validate_window_dump`], the stride must
+    /// be a multiple of 4 and large enough for a row, and there must be
+    /// enough grant references for the height times the stride.
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`ProtocolError::BadFieldValue`] naming the first field
+    /// that is not valid, or `grant_refs` if the number of grant references
+    /// is wrong.
+    pub fn validate(
+        &self,
+        grant_refs: u32,
+        features: u32,
+    ) -> Result<ValidWindowDump, ProtocolError> {
+        validate_dump(
+            MSG_WINDOW_DUMP_STRIDED,
+            &self.header,
+            Some(self.stride),
+            grant_refs,
+            features,
+        )
+    }
+}
+
+/// Validate the header of `msg`, which has an explicit `stride` if it is a
+/// [`MSG_WINDOW_DUMP_STRIDED`]
+fn validate_dump(
+    msg: u32,
+    header: &WindowDumpHeader,
+    stride: Option<u32>,
+    grant_refs: u32,
+    features: u32,
+) -> Result<ValidWindowDump, ProtocolError> {
+    let bad = |field| ProtocolError::BadFieldValue { msg, field };
+    if header.ty != WINDOW_DUMP_TYPE_GRANT_REFS {
+        return Err(bad("ty"));
+    }
+    if header.width == 0 || header.width > crate::MAX_WINDOW_WIDTH {
+        return Err(bad("width"));
+    }
+    let size = ValidWindowSize::new(WindowSize {
+        width: header.width,
+        height: header.height,
+    })
+    .map_err(|_| bad("height"))?;
+    let format = match PixelFormat::try_from(header.bpp) {
+        Ok(format) if format.feature().is_none_or(|f| features & f != 0) => format,
+        _ => return Err(bad("bpp")),
+    };
+    // Cannot overflow: the width is at most MAX_WINDOW_WIDTH
+    let row = size.width() * format.bytes_per_pixel();
+    let pages = match stride {
+        None => format.pages(size),
+        Some(stride) if stride % 4 != 0 || stride < row => return Err(bad("stride")),
+        Some(stride) => {
+            let bytes = u64::from(stride) * u64::from(size.height());
+            // Too large if it does not fit, which the check below catches
+            u32::try_from(bytes.div_ceil(u64::from(XC_PAGE_SIZE))).unwrap_or(u32::MAX)
+        }
+    };
+    if grant_refs < pages || grant_refs > MAX_GRANT_REFS_COUNT {
+        return Err(bad("grant_refs"));
+    }
+    Ok(ValidWindowDump {
+        size,
+        format,
+        stride: stride.unwrap_or(row),
+        strided: stride.is_some(),
+        grant_refs,
+    })
+}
+
+impl ValidWindowDump {
+    /// The size of the buffer
+    pub fn size(self) -> ValidWindowSize {
+        self.size
+    }
+
+    /// The format of the pixels
+    pub fn format(self) -> PixelFormat {
+        self.format
+    }
+
+    /// Bytes from the start of one row to the start of the next.  For a
+    /// `MSG_WINDOW_DUMP`, this is the width times the bytes per pixel.
+    pub fn stride(self) -> u32 {
+        self.stride
+    }
+
+    /// The number of grant references that follow the header.  Enough to
+    /// hold the buffer, and at most [`MAX_GRANT_REFS_COUNT`].
+    pub fn grant_refs(self) -> u32 {
+        self.grant_refs
+    }
+
+    /// The header as a [`WindowDumpStrided`], or `None` if this was a
+    /// `MSG_WINDOW_DUMP`
+    pub fn strided(self) -> Option<WindowDumpStrided> {
+        if self.strided {
+            Some(WindowDumpStrided {
+                header: self.get(),
+                stride: self.stride,
+            })
+        } else {
+            None
+        }
+    }
+
+    /// The underlying header
+    pub fn get(self) -> WindowDumpHeader {
+        WindowDumpHeader {
+            ty: WINDOW_DUMP_TYPE_GRANT_REFS,
+            width: self.size.width(),
+            height: self.size.height(),
+            bpp: self.format.bpp(),
+        }
+    }
+}
+
+impl From<ValidWindowDump> for WindowDumpHeader {
+    fn from(dump: ValidWindowDump) -> Self {
+        dump.get()
+    }
+}
+
+/// A [`WindowDumpUpdate`] that satisfies every documented invariant, for a
+/// buffer with a known number of grant references
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct ValidWindowDumpUpdate {
+    dump: ValidWindowDump,
+    first_page: u32,
+    removed: u32,
+    added: u32,
+}
+
+impl WindowDumpUpdate {
+    /// An update that resizes a buffer of `current` grant references for
+    /// `header`, keeping as many of them as it can.  Returns the update and
+    /// how many grant references must follow it.
+    ///
+    /// # Errors
+    ///
+    /// Fails if `header` is not valid, as with [`validate_window_dump`].
+    pub fn resize(
+        header: &WindowDumpHeader,
+        current: u32,
+        features: u32,
+    ) -> Result<(Self, u32), ProtocolError> {
+        // Any valid header fits in the most grant references allowed
+        let dump = validate_window_dump(header, MAX_GRANT_REFS_COUNT, features)?;
+        let pages = dump.format().pages(dump.size());
+        let kept = pages.min(current);
+        let update = WindowDumpUpdate {
+            header: *header,
+            first_page: kept,
+            removed: current - kept,
+        };
+        Ok((update, pages - kept))
+    }
+
+    /// Validate this update, followed by `added` grant references, to a
+    /// buffer that has `current` grant references, as received by a daemon
+    /// that sent `features` in its [`XConfScale`].  The pages to replace
+    /// must be within the buffer, and the result must be valid as by
+    /// [`validate_window_dump`].
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`ProtocolError::BadFieldValue`] naming the first field
+    /// that is not valid, or `grant_refs` if the number of grant references
+    /// that results is wrong.
+    pub fn validate(
+        &self,
+        added: u32,
+        current: u32,
+        features: u32,
+    ) -> Result<ValidWindowDumpUpdate, ProtocolError> {
+        let bad = |field| ProtocolError::BadFieldValue {
+            msg: MSG_WINDOW_DUMP_UPDATE,
+            field,
+        };
+        if self.first_page > current {
+            return Err(bad("first_page"));
+        }
+        if self.removed > current - self.first_page {
+            return Err(bad("removed"));
+        }
+        let total = (current - self.removed)
+            .checked_add(added)
+            .ok_or_else(|| bad("grant_refs"))?;
+        let dump = validate_dump(MSG_WINDOW_DUMP_UPDATE, &self.header, None, total, features)?;
+        Ok(ValidWindowDumpUpdate {
+            dump,
+            first_page: self.first_page,
+            removed: self.removed,
+            added,
+        })
+    }
+}
+
+impl ValidWindowDumpUpdate {
+    /// The buffer after the update
+    pub fn dump(self) -> ValidWindowDump {
+        self.dump
+    }
+
+    /// Index of the first grant reference replaced
+    pub fn first_page(self) -> u32 {
+        self.first_page
+    }
+
+    /// How many grant references are removed
+    pub fn removed(self) -> u32 {
+        self.removed
+    }
+
+    /// How many grant references follow the message, to be inserted at
+    /// [`ValidWindowDumpUpdate::first_page`]
+    pub fn added(self) -> u32 {
+        self.added
+    }
+
+    /// The indices of the grant references that are removed, as for
+    /// `Vec::splice` with the ones that follow the message
+    pub fn replaced(self) -> core::ops::Range<usize> {
+        let start = self.first_page as usize;
+        start..start + self.removed as usize
+    }
+
+    /// The underlying header
+    pub fn get(self) -> WindowDumpUpdate {
+        WindowDumpUpdate {
+            header: self.dump.get(),
+            first_page: self.first_page,
+            removed: self.removed,
+        }
+    }
+}
+
+impl From<ValidWindowDumpUpdate> for WindowDumpUpdate {
+    fn from(update: ValidWindowDumpUpdate) -> Self {
+        update.get()
+    }
+}
+
+/// The length of a message with a `T` followed by as many grant references
+/// as fit in `header`, up to the limit.  This is only the length in
+/// `header` if nothing is left over.
+fn with_grant_refs<T>(header: &UntrustedHeader) -> usize {
+    let header_len = size_of::<T>() as u32;
+    let refs = header.untrusted_len.saturating_sub(header_len) / 4;
+    (header_len + 4 * refs.min(MAX_GRANT_REFS_COUNT)) as usize
+}
+
+/// Check the header of a proposed message received after negotiating
+/// `version`.  Returns `Ok(false)` if `header` is not a proposed message
+/// available in `version`, in which case it is an unknown message as
+/// usual, and `Ok(true)` if it is one and its length is right.
+///
+/// # Errors
+///
+/// Fails with [`ProtocolError::BadLength`] if the length is wrong.
+pub fn check_header(header: &UntrustedHeader, version: u32) -> Result<bool, ProtocolError> {
+    if version >> 16 != PROPOSED_VERSION >> 16 || version < PROPOSED_VERSION {
+        return Ok(false);
+    }
+    let len = match header.ty {
+        MSG_SCREEN_CONFIGURED => size_of::<XConf>(),
+        MSG_WINDOW_SCALE => size_of::<WindowScale>(),
+        MSG_TOUCH => size_of::<Touch>(),
+        MSG_CLIPBOARD_LIMIT => size_of::<ClipboardLimit>(),
+        MSG_CLIPBOARD_BEGIN => size_of::<ClipboardBegin>(),
+        MSG_CLIPBOARD_CHUNK => header.untrusted_len.clamp(1, MAX_CLIPBOARD_CHUNK) as usize,
+        MSG_CLIPBOARD_END => 0,
+        MSG_KEYBOARD_LAYOUT => size_of::<KeyboardLayout>(),
+        MSG_WINDOW_DUMP_STRIDED => with_grant_refs::<WindowDumpStrided>(header),
+        MSG_WINDOW_DUMP_UPDATE => with_grant_refs::<WindowDumpUpdate>(header),
+        #[cfg(feature = "dmabuf")]
+        dmabuf::MSG_WINDOW_DUMP_DMABUF => size_of::<dmabuf::DmabufDump>(),
+        _ => return Ok(false),
+    };
+    if header.untrusted_len as usize == len {
+        Ok(true)
+    } else {
+        Err(ProtocolError::BadLength {
+            ty: header.ty,
+            untrusted_len: header.untrusted_len,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::PROTOCOL_VERSION;
+
+    #[test]
+    fn scale_range() {
+        assert_eq!(ValidScale::new(MIN_SCALE - 1), None);
+        assert_eq!(ValidScale::new(MAX_SCALE + 1), None);
+        assert_eq!(ValidScale::new(180).unwrap().to_f64(), 1.5);
+        assert_eq!(ValidScale::default().get(), SCALE_DENOMINATOR);
+        let bad = WindowScale { scale: 0 }.validate();
+        assert_eq!(
+            bad,
+            Err(ProtocolError::BadFieldValue {
+                msg: MSG_WINDOW_SCALE,
+                field: "scale"
+            })
+        );
+        let xconf = XConfScale {
+            default_scale: 240,
+            ..Default::default()
+        };
+        assert_eq!(xconf.default_scale().map(ValidScale::get), Some(240));
+        assert_eq!(
+            size_of::<XConfScale>(),
+            size_of::<XConfVersion>() + 8,
+            "extends XConfVersion"
+        );
+    }
+
+    #[test]
+    fn strided_window_dumps() {
+        let strided = WindowDumpStrided {
+            header: WindowDumpHeader {
+                ty: WINDOW_DUMP_TYPE_GRANT_REFS,
+                width: 1000,
+                height: 3,
+                bpp: 24,
+            },
+            stride: 4096,
+        };
+        let bad = |field| {
+            Err(ProtocolError::BadFieldValue {
+                msg: MSG_WINDOW_DUMP_STRIDED,
+                field,
+            })
+        };
+        let dump = strided.validate(3, 0).unwrap();
+        assert_eq!((dump.stride(), dump.strided()), (4096, Some(strided)));
+        assert_eq!(strided.validate(2, 0), bad("grant_refs"));
+        let packed = validate_window_dump(&strided.header, 3, 0).unwrap();
+        assert_eq!((packed.stride(), packed.strided()), (4000, None));
+        for &stride in [3996, 4001, 0].iter() {
+            let dump = WindowDumpStrided { stride, ..strided };
+            assert_eq!(dump.validate(3, 0), bad("stride"));
+        }
+        let huge = WindowDumpStrided {
+            stride: u32::MAX - 3,
+            ..strided
+        };
+        assert_eq!(huge.validate(MAX_GRANT_REFS_COUNT, 0), bad("grant_refs"));
+        let mut header = UntrustedHeader {
+            ty: MSG_WINDOW_DUMP_STRIDED,
+            window: 1.into(),
+            untrusted_len: 20 + 4 * MAX_GRANT_REFS_COUNT,
+        };
+        assert_eq!(check_header(&header, PROPOSED_VERSION), Ok(true));
+        for &len in [19, 22, 24 + 4 * MAX_GRANT_REFS_COUNT].iter() {
+            header.untrusted_len = len;
+            assert!(check_header(&header, PROPOSED_VERSION).is_err());
+        }
+    }
+
+    #[test]
+    fn window_dump_updates() {
+        let header = WindowDumpHeader {
+            ty: WINDOW_DUMP_TYPE_GRANT_REFS,
+            width: 1024,
+            height: 5,
+            bpp: 24,
+        };
+        // Growing from 3 pages to 5 keeps all 3
+        let (update, added) = WindowDumpUpdate::resize(&header, 3, 0).unwrap();
+        assert_eq!((update.first_page, update.removed, added), (3, 0, 2));
+        let valid = update.validate(added, 3, 0).unwrap();
+        assert_eq!((valid.replaced(), valid.dump().grant_refs()), (3..3, 5));
+        assert_eq!(WindowDumpUpdate::from(valid), update);
+        // Shrinking from 8 pages removes the last 3
+        let (update, added) = WindowDumpUpdate::resize(&header, 8, 0).unwrap();
+        assert_eq!((update.first_page, update.removed, added), (5, 3, 0));
+        assert_eq!(update.validate(0, 8, 0).unwrap().replaced(), 5..8);
+        let bad = |field| {
+            Err(ProtocolError::BadFieldValue {
+                msg: MSG_WINDOW_DUMP_UPDATE,
+                field,
+            })
+        };
+        assert_eq!(update.validate(0, 4, 0), bad("first_page"));
+        let too_many = WindowDumpUpdate {
+            removed: 4,
+            ..update
+        };
+        assert_eq!(too_many.validate(0, 8, 0), bad("removed"));
+        let too_few = WindowDumpUpdate {
+            first_page: 4,
+            ..too_many
+        };
+        assert_eq!(too_few.validate(0, 8, 0), bad("grant_refs"), "4 left");
+        assert_eq!(
+            update.validate(u32::MAX, 8, 0),
+            bad("grant_refs"),
+            "overflow"
+        );
+        let argb = WindowDumpHeader { bpp: 32, ..header };
+        assert_eq!(update.validate(0, 8, 0).unwrap().dump().format().bpp(), 24);
+        assert!(WindowDumpUpdate::resize(&argb, 3, 0).is_err());
+        let mut untrusted = UntrustedHeader {
+            ty: MSG_WINDOW_DUMP_UPDATE,
+            window: 1.into(),
+            untrusted_len: 24 + 8,
+        };
+        assert_eq!(check_header(&untrusted, PROPOSED_VERSION), Ok(true));
+        untrusted.untrusted_len = 23;
+        assert!(check_header(&untrusted, PROPOSED_VERSION).is_err());
+    }
+
+    #[test]
+    fn window_dumps() {
+        let header = WindowDumpHeader {
+            ty: WINDOW_DUMP_TYPE_GRANT_REFS,
+            width: 1025,
+            height: 2,
+            bpp: 32,
+        };
+        let bad = |field| {
+            Err(ProtocolError::BadFieldValue {
+                msg: MSG_WINDOW_DUMP,
+                field,
+            })
+        };
+        assert_eq!(validate_window_dump(&header, 3, 0), bad("bpp"));
+        let dump = validate_window_dump(&header, 3, FEATURE_ARGB32 | 1 << 31).unwrap();
+        assert_eq!(dump.format(), PixelFormat::Argb8888);
+        assert!(dump.format().has_alpha());
+        assert_eq!(WindowDumpHeader::from(dump), header);
+        assert_eq!(dump.format().pages(dump.size()), 3);
+        assert_eq!(
+            validate_window_dump(&header, 2, ALL_FEATURES),
+            bad("grant_refs")
+        );
+        let xrgb = WindowDumpHeader { bpp: 24, ..header };
+        assert!(validate_window_dump(&xrgb, 3, 0).is_ok());
+        let too_many = MAX_GRANT_REFS_COUNT + 1;
+        assert_eq!(validate_window_dump(&xrgb, too_many, 0), bad("grant_refs"));
+        let deep = WindowDumpHeader { bpp: 16, ..header };
+        assert_eq!(validate_window_dump(&deep, 3, ALL_FEATURES), bad("bpp"));
+        let empty = WindowDumpHeader {
+            height: 0,
+            ..header
+        };
+        assert_eq!(validate_window_dump(&empty, 3, ALL_FEATURES), bad("height"));
+        let xconf = XConfScale {
+            features: FEATURE_ARGB32 | 1 << 31,
+            ..Default::default()
+        };
+        assert!(xconf.supports(FEATURE_ARGB32));
+        assert!(!xconf.supports(1 << 31), "unknown bit");
+    }
+
+    #[test]
+    fn touch_validation() {
+        let touch = Touch {
+            ty: TouchEvent::Up as u32,
+            slot: MAX_TOUCH_SLOTS - 1,
+            coordinates: Coordinates { x: -5, y: 7 },
+        };
+        let valid = touch.validate().unwrap();
+        assert_eq!((valid.event(), valid.slot()), (TouchEvent::Up, 15));
+        assert_eq!(Touch::from(valid), touch);
+        let bad = |field| {
+            Err(ProtocolError::BadFieldValue {
+                msg: MSG_TOUCH,
+                field,
+            })
+        };
+        let slot = Touch {
+            slot: MAX_TOUCH_SLOTS,
+            ..touch
+        };
+        assert_eq!(slot.validate(), bad("slot"));
+        assert_eq!(Touch { ty: 3, ..touch }.validate(), bad("ty"));
+    }
+
+    #[test]
+    fn chunked_clipboard() {
+        let data = [7u8; MAX_CLIPBOARD_CHUNK as usize + 1];
+        assert_eq!(
+            ClipboardSender::new(&data, MAX_CLIPBOARD_SIZE).unwrap_err(),
+            ClipboardError::TooLarge {
+                len: MAX_CLIPBOARD_CHUNK + 1,
+                limit: MAX_CLIPBOARD_SIZE
+            }
+        );
+        let mut receiver = ClipboardReceiver::new(u32::MAX);
+        assert_eq!(receiver.limit().validate(), Ok(MAX_CHUNKED_CLIPBOARD_SIZE));
+        let sender = ClipboardSender::new(&data, receiver.limit().limit).unwrap();
+        let mut chunks = 0;
+        for message in sender {
+            match message {
+                ClipboardMessage::Begin(begin) => {
+                    assert_eq!(receiver.begin(&begin), Ok(data.len() as u32))
+                }
+                ClipboardMessage::Chunk(chunk) => {
+                    chunks += 1;
+                    receiver.chunk(chunk.len()).unwrap()
+                }
+                ClipboardMessage::End => assert_eq!(receiver.end(), Ok(data.len() as u32)),
+            }
+        }
+        assert_eq!(chunks, 2);
+        assert!(!receiver.in_progress());
+        let empty = [
+            ClipboardMessage::Begin(ClipboardBegin { untrusted_len: 0 }),
+            ClipboardMessage::End,
+        ];
+        assert!(ClipboardSender::new(&[], 0)
+            .unwrap()
+            .eq(empty.iter().copied()));
+    }
+
+    #[test]
+    fn clipboard_violations() {
+        let mut receiver = ClipboardReceiver::new(10);
+        assert_eq!(receiver.chunk(1), Err(ClipboardError::NotStarted));
+        assert_eq!(receiver.end(), Err(ClipboardError::NotStarted));
+        let begin = |untrusted_len| ClipboardBegin { untrusted_len };
+        assert_eq!(
+            receiver.begin(&begin(11)),
+            Err(ClipboardError::TooLarge { len: 11, limit: 10 })
+        );
+        receiver.begin(&begin(10)).unwrap();
+        assert_eq!(
+            receiver.begin(&begin(1)),
+            Err(ClipboardError::AlreadyStarted)
+        );
+        receiver.chunk(6).unwrap();
+        assert_eq!(receiver.chunk(5), Err(ClipboardError::BadChunk { len: 5 }));
+        assert!(!receiver.in_progress(), "abandoned");
+        receiver.begin(&begin(10)).unwrap();
+        receiver.chunk(6).unwrap();
+        assert_eq!(
+            receiver.end(),
+            Err(ClipboardError::Truncated {
+                expected: 10,
+                received: 6
+            })
+        );
+        let limit = ClipboardLimit {
+            limit: MAX_CHUNKED_CLIPBOARD_SIZE + 1,
+        };
+        assert!(limit.validate().is_err());
+    }
+
+    #[test]
+    fn keyboard_layout_validation() {
+        let mut layout = KeyboardLayout::default();
+        layout.rules[..5].copy_from_slice(b"evdev");
+        layout.layout[..5].copy_from_slice(b"us,de");
+        let valid = layout.validate().unwrap();
+        assert_eq!((valid.rules(), valid.layout()), ("evdev", "us,de"));
+        assert_eq!(valid.options(), "");
+        assert_eq!(KeyboardLayout::from(valid), layout);
+        let bad = |layout: KeyboardLayout, field| {
+            assert_eq!(
+                layout.validate(),
+                Err(ProtocolError::BadFieldValue {
+                    msg: MSG_KEYBOARD_LAYOUT,
+                    field
+                })
+            )
+        };
+        let mut spaces = layout;
+        spaces.model[..3].copy_from_slice(b"p c");
+        bad(spaces, "model");
+        let mut garbage = layout;
+        garbage.variant[7] = b'x';
+        bad(garbage, "variant");
+        let mut unterminated = layout;
+        unterminated.options = [b'a'; 128];
+        bad(unterminated, "options");
+    }
+
+    #[test]
+    fn version_gating() {
+        let mut header = UntrustedHeader {
+            ty: MSG_WINDOW_SCALE,
+            window: 1.into(),
+            untrusted_len: 4,
+        };
+        assert_eq!(check_header(&header, PROTOCOL_VERSION), Ok(false));
+        assert_eq!(check_header(&header, PROPOSED_VERSION), Ok(true));
+        assert_eq!(check_header(&header, 2 << 16 | 8), Ok(false));
+        header.untrusted_len = 8;
+        assert!(check_header(&header, PROPOSED_VERSION).is_err());
+        header.ty = MSG_TOUCH;
+        header.untrusted_len = size_of::<Touch>() as u32;
+        assert_eq!(check_header(&header, PROPOSED_VERSION), Ok(true));
+        header.ty = MSG_CLIPBOARD_CHUNK;
+        assert_eq!(check_header(&header, PROPOSED_VERSION), Ok(true));
+        header.untrusted_len = 0;
+        assert!(
+            check_header(&header, PROPOSED_VERSION).is_err(),
+            "empty chunk"
+        );
+        header.ty = MSG_CLIPBOARD_END;
+        assert_eq!(check_header(&header, PROPOSED_VERSION), Ok(true));
+        header.ty = crate::MSG_CLOSE;
+        assert_eq!(check_header(&header, PROPOSED_VERSION), Ok(false));
+    }
+}
@@ -0,0 +1,282 @@
+/*
+ * The Qubes OS Project, https://www.qubes-os.org
+ *
+ * Copyright (C) 2021  Demi Marie Obenour  <demi@invisiblethingslab.com>
+ *
+ * This program is free software; you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License
+ * as published by the Free Software Foundation; either version 2
+ * of the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301, USA.
+ *
+ */
+
+//! Experimental: sharing GPU buffers instead of granting pages.
+//!
+//! A dmabuf is a file descriptor, which cannot cross from one qube to
+//! another.  What can is the ID of the virtio-gpu resource the buffer was
+//! exported from, which the daemon's side of virtio-gpu can import.  An agent
+//! whose daemon sets [`FEATURE_DMABUF`] may send [`MSG_WINDOW_DUMP_DMABUF`]
+//! in place of `MSG_WINDOW_DUMP`.  It is acknowledged the same way.
+//!
+//! Formats and modifiers are those of DRM.  Only the two formats that match
+//! [`PixelFormat`] are allowed.
+
+use super::{PixelFormat, FEATURE_ARGB32, FEATURE_DMABUF};
+use crate::{ProtocolError, ValidWindowSize, WindowSize};
+use core::num::NonZeroU32;
+
+/// Agent ⇒ daemon: Use a GPU buffer as the buffer of a window.  The body is
+/// a [`DmabufDump`].
+pub const MSG_WINDOW_DUMP_DMABUF: u32 = 160;
+
+/// DRM format of [`PixelFormat::Xrgb8888`], `XR24`
+pub const DRM_FORMAT_XRGB8888: u32 = u32::from_le_bytes(*b"XR24");
+
+/// DRM format of [`PixelFormat::Argb8888`], `AR24`
+pub const DRM_FORMAT_ARGB8888: u32 = u32::from_le_bytes(*b"AR24");
+
+/// DRM modifier of a buffer without tiling or compression
+pub const DRM_FORMAT_MOD_LINEAR: u64 = 0;
+
+/// DRM modifier that means no modifier, which is not allowed
+pub const DRM_FORMAT_MOD_INVALID: u64 = 0x00ff_ffff_ffff_ffff;
+
+qubes_castable::castable! {
+    /// Agent ⇒ daemon: A GPU buffer to use as the buffer of a window
+    pub struct DmabufDump {
+        /// The virtio-gpu resource holding the buffer.  Must not be 0.
+        pub resource_id: u32,
+        /// Width in pixels
+        pub width: u32,
+        /// Height in pixels
+        pub height: u32,
+        /// DRM format, [`DRM_FORMAT_XRGB8888`] or [`DRM_FORMAT_ARGB8888`]
+        pub fourcc: u32,
+        /// Bytes from the start of one row to the start of the next.  Must
+        /// be a multiple of 4, and no less than 4 times the width.
+        pub stride: u32,
+        /// Offset of the first pixel in the resource.  Must be a multiple of
+        /// 4.
+        pub offset: u32,
+        /// Low 32 bits of the DRM modifier
+        pub modifier_lo: u32,
+        /// High 32 bits of the DRM modifier, which must not be
+        /// [`DRM_FORMAT_MOD_INVALID`]
+        pub modifier_hi: u32,
+    }
+}
+
+/// The [`PixelFormat`] of DRM format `fourcc`, if it is one of them
+pub fn pixel_format(fourcc: u32) -> Option<PixelFormat> {
+    match fourcc {
+        DRM_FORMAT_XRGB8888 => Some(PixelFormat::Xrgb8888),
+        DRM_FORMAT_ARGB8888 => Some(PixelFormat::Argb8888),
+        _ => None,
+    }
+}
+
+/// A [`DmabufDump`] that satisfies every documented invariant
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct ValidDmabufDump {
+    resource_id: NonZeroU32,
+    size: ValidWindowSize,
+    format: PixelFormat,
+    stride: u32,
+    offset: u32,
+    modifier: u64,
+}
+
+impl DmabufDump {
+    /// The DRM modifier
+    pub fn modifier(&self) -> u64 {
+        u64::from(self.modifier_hi) << 32 | u64::from(self.modifier_lo)
+    }
+
+    /// Validate this message, as received by a daemon that sent `features`.
+    /// Whether the resource exists, and is large enough, is for the importer
+    /// to check.
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`ProtocolError::UnknownType`] if `features` lacks
+    /// [`FEATURE_DMABUF`], and otherwise with
+    /// [`ProtocolError::BadFieldValue`] naming the first field that is not
+    /// valid.
+    pub fn validate(&self, features: u32) -> Result<ValidDmabufDump, ProtocolError> {
+        if features & FEATURE_DMABUF == 0 {
+            return Err(ProtocolError::UnknownType {
+                ty: MSG_WINDOW_DUMP_DMABUF,
+            });
+        }
+        let bad = |field| ProtocolError::BadFieldValue {
+            msg: MSG_WINDOW_DUMP_DMABUF,
+            field,
+        };
+        let resource_id = NonZeroU32::new(self.resource_id).ok_or_else(|| bad("resource_id"))?;
+        if self.width == 0 || self.width > crate::MAX_WINDOW_WIDTH {
+            return Err(bad("width"));
+        }
+        let size = ValidWindowSize::new(WindowSize {
+            width: self.width,
+            height: self.height,
+        })
+        .map_err(|_| bad("height"))?;
+        let format = match pixel_format(self.fourcc) {
+            Some(PixelFormat::Argb8888) if features & FEATURE_ARGB32 == 0 => {
+                return Err(bad("fourcc"))
+            }
+            Some(format) => format,
+            None => return Err(bad("fourcc")),
+        };
+        // Cannot overflow: the width is at most MAX_WINDOW_WIDTH
+        if !self.stride.is_multiple_of(4) || self.stride < size.width() * format.bytes_per_pixel() {
+            return Err(bad("stride"));
+        }
+        if !self.offset.is_multiple_of(4) {
+            return Err(bad("offset"));
+        }
+        if self.modifier() == DRM_FORMAT_MOD_INVALID {
+            return Err(bad("modifier_hi"));
+        }
+        Ok(ValidDmabufDump {
+            resource_id,
+            size,
+            format,
+            stride: self.stride,
+            offset: self.offset,
+            modifier: self.modifier(),
+        })
+    }
+}
+
+impl ValidDmabufDump {
+    /// The virtio-gpu resource holding the buffer
+    pub fn resource_id(self) -> NonZeroU32 {
+        self.resource_id
+    }
+
+    /// The size of the buffer
+    pub fn size(self) -> ValidWindowSize {
+        self.size
+    }
+
+    /// The format of the pixels
+    pub fn format(self) -> PixelFormat {
+        self.format
+    }
+
+    /// Bytes from the start of one row to the start of the next
+    pub fn stride(self) -> u32 {
+        self.stride
+    }
+
+    /// Offset of the first pixel in the resource
+    pub fn offset(self) -> u32 {
+        self.offset
+    }
+
+    /// The DRM modifier, which is not [`DRM_FORMAT_MOD_INVALID`]
+    pub fn modifier(self) -> u64 {
+        self.modifier
+    }
+
+    /// The underlying message
+    pub fn get(self) -> DmabufDump {
+        DmabufDump {
+            resource_id: self.resource_id.get(),
+            width: self.size.width(),
+            height: self.size.height(),
+            fourcc: match self.format {
+                PixelFormat::Xrgb8888 => DRM_FORMAT_XRGB8888,
+                PixelFormat::Argb8888 => DRM_FORMAT_ARGB8888,
+            },
+            stride: self.stride,
+            offset: self.offset,
+            modifier_lo: self.modifier as u32,
+            modifier_hi: (self.modifier >> 32) as u32,
+        }
+    }
+}
+
+impl From<ValidDmabufDump> for DmabufDump {
+    fn from(dump: ValidDmabufDump) -> Self {
+        dump.get()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{check_header, PROPOSED_VERSION};
+    use super::*;
+    use crate::UntrustedHeader;
+
+    #[test]
+    fn validation() {
+        let dump = DmabufDump {
+            resource_id: 5,
+            width: 10,
+            height: 10,
+            fourcc: DRM_FORMAT_ARGB8888,
+            stride: 64,
+            offset: 0,
+            modifier_lo: 1,
+            modifier_hi: 1 << 24,
+        };
+        assert_eq!(
+            dump.validate(FEATURE_ARGB32),
+            Err(ProtocolError::UnknownType {
+                ty: MSG_WINDOW_DUMP_DMABUF
+            })
+        );
+        let bad = |field| {
+            Err(ProtocolError::BadFieldValue {
+                msg: MSG_WINDOW_DUMP_DMABUF,
+                field,
+            })
+        };
+        assert_eq!(dump.validate(FEATURE_DMABUF), bad("fourcc"));
+        let valid = dump.validate(FEATURE_DMABUF | FEATURE_ARGB32).unwrap();
+        assert_eq!(valid.modifier(), 1 << 56 | 1);
+        assert_eq!(DmabufDump::from(valid), dump);
+        let cases = [
+            (
+                DmabufDump {
+                    resource_id: 0,
+                    ..dump
+                },
+                "resource_id",
+            ),
+            (DmabufDump { fourcc: 0, ..dump }, "fourcc"),
+            (DmabufDump { stride: 36, ..dump }, "stride"),
+            (DmabufDump { stride: 42, ..dump }, "stride"),
+            (DmabufDump { offset: 2, ..dump }, "offset"),
+            (DmabufDump { height: 0, ..dump }, "height"),
+            (
+                DmabufDump {
+                    modifier_lo: u32::MAX,
+                    modifier_hi: 0x00ff_ffff,
+                    ..dump
+                },
+                "modifier_hi",
+            ),
+        ];
+        for &(case, field) in cases.iter() {
+            assert_eq!(case.validate(FEATURE_DMABUF | FEATURE_ARGB32), bad(field));
+        }
+        let header = UntrustedHeader {
+            ty: MSG_WINDOW_DUMP_DMABUF,
+            window: 1.into(),
+            untrusted_len: 32,
+        };
+        assert_eq!(check_header(&header, PROPOSED_VERSION), Ok(true));
+    }
+}
@@ -0,0 +1,123 @@
+/*
+ * The Qubes OS Project, https://www.qubes-os.org
+ *
+ * Copyright (C) 2021  Demi Marie Obenour  <demi@invisiblethingslab.com>
+ *
+ * This program is free software; you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License
+ * as published by the Free Software Foundation; either version 2
+ * of the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301, USA.
+ *
+ */
+
+//! Validation of the deprecated [`crate::ShmCmd`]
+
+use crate::{ProtocolError, ShmCmd, ValidWindowSize, WindowSize};
+use crate::{MAX_MFN_COUNT, MAX_WINDOW_WIDTH, MSG_MFNDUMP, XC_PAGE_SIZE};
+
+/// A [`ShmCmd`] that satisfies every documented invariant
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct ValidShmCmd {
+    size: ValidWindowSize,
+    offset: u32,
+    num_mfn: u32,
+}
+
+fn bad_field(field: &'static str) -> ProtocolError {
+    ProtocolError::BadFieldValue {
+        msg: MSG_MFNDUMP,
+        field,
+    }
+}
+
+impl ShmCmd {
+    /// Validate this command.  `bpp` must be 24, `off` must be less than
+    /// [`XC_PAGE_SIZE`], `shmid` and `domid` must be zero, and `num_mfn`
+    /// must be enough pages to hold the image (at 4 bytes per pixel) but
+    /// not more than [`MAX_MFN_COUNT`].
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`ProtocolError::BadFieldValue`] naming the first field
+    /// that is not valid.
+    pub fn validate(&self) -> Result<ValidShmCmd, ProtocolError> {
+        if self.width == 0 || self.width > MAX_WINDOW_WIDTH {
+            return Err(bad_field("width"));
+        }
+        let size = ValidWindowSize::new(WindowSize {
+            width: self.width,
+            height: self.height,
+        })
+        .map_err(|_| bad_field("height"))?;
+        if self.shmid != 0 {
+            return Err(bad_field("shmid"));
+        }
+        if self.bpp != 24 {
+            return Err(bad_field("bpp"));
+        }
+        if self.off >= XC_PAGE_SIZE {
+            return Err(bad_field("off"));
+        }
+        if self.domid != 0 {
+            return Err(bad_field("domid"));
+        }
+        // Cannot overflow: the area is at most MAX_WINDOW_MEM / 4
+        let needed =
+            (u64::from(self.off) + 4 * u64::from(size.area())).div_ceil(u64::from(XC_PAGE_SIZE));
+        if self.num_mfn > MAX_MFN_COUNT || u64::from(self.num_mfn) < needed {
+            return Err(bad_field("num_mfn"));
+        }
+        Ok(ValidShmCmd {
+            size,
+            offset: self.off,
+            num_mfn: self.num_mfn,
+        })
+    }
+}
+
+impl ValidShmCmd {
+    /// The size of the image
+    pub fn size(self) -> ValidWindowSize {
+        self.size
+    }
+
+    /// Offset of the image from the start of the first page.  Less than
+    /// [`XC_PAGE_SIZE`].
+    pub fn offset(self) -> u32 {
+        self.offset
+    }
+
+    /// Number of MFNs that follow.  Between 1 and [`MAX_MFN_COUNT`]
+    /// inclusive, and enough to hold the image.
+    pub fn num_mfn(self) -> u32 {
+        self.num_mfn
+    }
+
+    /// The underlying command
+    pub fn get(self) -> ShmCmd {
+        ShmCmd {
+            shmid: 0,
+            width: self.size.width(),
+            height: self.size.height(),
+            bpp: 24,
+            off: self.offset,
+            num_mfn: self.num_mfn,
+            domid: 0,
+        }
+    }
+}
+
+impl From<ValidShmCmd> for ShmCmd {
+    fn from(cmd: ValidShmCmd) -> Self {
+        cmd.get()
+    }
+}
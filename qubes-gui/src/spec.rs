@@ -0,0 +1,689 @@
+/*
+ * The Qubes OS Project, https://www.qubes-os.org
+ *
+ * Copyright (C) 2021  Demi Marie Obenour  <demi@invisiblethingslab.com>
+ *
+ * This program is free software; you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License
+ * as published by the Free Software Foundation; either version 2
+ * of the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301, USA.
+ *
+ */
+
+//! Protocol specification generator.
+//!
+//! [`write`] generates a Markdown specification of the protocol from the Rust
+//! definitions: message numbers, direction, body layout, length rules, and the
+//! protocol version each message needs.  Direction, length rules, versions,
+//! and deprecation are not written down separately; they are taken from
+//! [`agent_may_send`], [`daemon_may_send`], [`UntrustedHeader::validate_length`],
+//! [`Msg::since`], and [`Msg::is_deprecated`], so the specification cannot
+//! disagree with them.  The other rules are written by hand, and the tests of
+//! each validator check that they name every field it checks.
+
+use super::*;
+use core::fmt::{self, Write};
+use qubes_castable::{Castable, Field};
+
+/// Longest body length probed when working out length rules.  Longer than
+/// any valid message.
+const PROBE_LIMIT: u32 = 1 << 20;
+
+/// The body of a message, as far as the specification is concerned
+struct Body {
+    msg: Msg,
+    /// Rust struct at the start of the body, if any
+    rust: Option<&'static str>,
+    size: usize,
+    fields: &'static [Field],
+    /// What follows the struct, if anything
+    trailer: Option<&'static str>,
+    /// Rules that the length check does not cover.  Every field that a
+    /// validator checks must be named here, in backquotes; the validators'
+    /// tests check this with [`corrupt_each_field`] and [`mentions`].
+    rules: &'static [&'static str],
+}
+
+macro_rules! bodies {
+    ($(($msg: ident, $rust: tt, $trailer: expr, [$($rule: expr),*$(,)?]$(,)?),)+) => {
+        &[$(bodies!(@body $msg, $rust, $trailer, [$($rule),*])),+]
+    };
+    (@body $msg: ident, (), $trailer: expr, [$($rule: expr),*]) => {
+        Body {
+            msg: Msg::$msg,
+            rust: None,
+            size: 0,
+            fields: &[],
+            trailer: $trailer,
+            rules: &[$($rule),*],
+        }
+    };
+    (@body $msg: ident, $rust: ident, $trailer: expr, [$($rule: expr),*]) => {
+        Body {
+            msg: Msg::$msg,
+            rust: Some(stringify!($rust)),
+            size: core::mem::size_of::<$rust>(),
+            fields: <$rust as Castable>::FIELDS,
+            trailer: $trailer,
+            rules: &[$($rule),*],
+        }
+    };
+}
+
+/// One entry for every [`Msg`], in the same order
+const BODIES: &[Body] = bodies![
+    (
+        Keypress,
+        Keypress,
+        None,
+        ["`ty` MUST be 2 (key press) or 3 (key release)."],
+    ),
+    (
+        Button,
+        Button,
+        None,
+        ["`ty` MUST be 4 (button press) or 5 (button release)."],
+    ),
+    (Motion, Motion, None, []),
+    (
+        Crossing,
+        Crossing,
+        None,
+        [
+            "`ty` MUST be 7 (enter) or 8 (leave).",
+            "`mode` MUST be between 0 and 2 inclusive.",
+            "`detail` MUST be between 0 and 4 inclusive.",
+            "`focus` MUST be 0 or 1.",
+        ],
+    ),
+    (
+        Focus,
+        Focus,
+        None,
+        [
+            "`ty` MUST be 9 (focus in) or 10 (focus out).",
+            "`mode` MUST be 0.",
+            "`detail` MUST be between 0 and 7 inclusive.",
+        ],
+    ),
+    (Resize, (), None, ["MUST NOT be sent."]),
+    (
+        Create,
+        Create,
+        None,
+        [
+            "The window MUST NOT exist, and MUST NOT be 0.",
+            "The width and height of `rectangle` MUST be nonzero, and MUST NOT exceed `MAX_WINDOW_WIDTH` and `MAX_WINDOW_HEIGHT` respectively.",
+            "`parent` MUST be 0 or an existing window.",
+            "`override_redirect` MUST be 0 or 1.",
+        ],
+    ),
+    (Destroy, (), None, []),
+    (
+        Map,
+        MapInfo,
+        None,
+        ["`override_redirect` MUST be 0 or 1."]
+    ),
+    (Unmap, (), None, []),
+    (
+        Configure,
+        Configure,
+        None,
+        [
+            "The width and height of `rectangle` MUST be nonzero, and MUST NOT exceed `MAX_WINDOW_WIDTH` and `MAX_WINDOW_HEIGHT` respectively.",
+            "`override_redirect` MUST be 0 or 1.",
+        ],
+    ),
+    (
+        MfnDump,
+        ShmCmd,
+        Some("machine frame numbers, 4 bytes each"),
+        [
+            "`width` and `height` MUST be nonzero, and MUST NOT exceed `MAX_WINDOW_WIDTH` and `MAX_WINDOW_HEIGHT` respectively.",
+            "`bpp` MUST be 24, `off` MUST be less than `XC_PAGE_SIZE`, and `shmid` and `domid` MUST be 0.",
+            "`num_mfn` MUST be the number of MFNs that follow, and enough to hold the image.",
+        ],
+    ),
+    (
+        ShmImage,
+        ShmImage,
+        None,
+        ["The width and height of `rectangle` MUST be nonzero, and MUST NOT exceed `MAX_WINDOW_WIDTH` and `MAX_WINDOW_HEIGHT` respectively."],
+    ),
+    (Close, (), None, []),
+    (Execute, (), None, ["MUST NOT be sent."]),
+    (ClipboardReq, (), None, []),
+    (ClipboardData, (), Some("clipboard contents"), []),
+    (
+        SetTitle,
+        WMName,
+        None,
+        ["`data` MUST contain a NUL byte, and be valid UTF-8 up to it."]
+    ),
+    (KeymapNotify, KeymapNotify, None, []),
+    (Dock, (), None, []),
+    (
+        WindowHints,
+        WindowHints,
+        None,
+        [
+            "If both are set, `min_size` MUST NOT be larger than `max_size` in either dimension.",
+            "If set, `size_increment` MUST NOT be 0 in either dimension.",
+        ],
+    ),
+    (
+        WindowFlags,
+        WindowFlags,
+        None,
+        ["`set` and `unset` MUST contain only known flags, and MUST NOT both contain the same flag."],
+    ),
+    (
+        WindowClass,
+        WMClass,
+        None,
+        ["`res_class` and `res_name` MUST each contain a NUL byte, and be valid UTF-8 up to it."],
+    ),
+    (
+        WindowDump,
+        WindowDumpHeader,
+        Some("grant references, 4 bytes each"),
+        [
+            "`ty` MUST be 0 (grant references).",
+            "`width` and `height` MUST be nonzero, and MUST NOT exceed `MAX_WINDOW_WIDTH` and `MAX_WINDOW_HEIGHT` respectively.",
+            "`bpp` MUST be 24.",
+            "There MUST be exactly enough grant references to hold the image.",
+        ],
+    ),
+    (
+        Cursor,
+        Cursor,
+        None,
+        ["`cursor` MUST be `CURSOR_DEFAULT`, or between `CURSOR_X11` and `CURSOR_X11_MAX` inclusive."],
+    ),
+    (DumpAck, (), None, []),
+];
+
+/// The rules for `msg` that the length check does not cover, as Markdown.
+/// Fields are named in backquotes.  Deprecation and the protocol version
+/// needed are not included; see [`Msg::is_deprecated`] and [`Msg::since`].
+pub fn rules(msg: Msg) -> &'static [&'static str] {
+    BODIES
+        .iter()
+        .find(|body| body.msg == msg)
+        .map_or(&[], |body| body.rules)
+}
+
+/// Whether [`rules`] for `msg` name the field at `path` (as passed by
+/// [`corrupt_each_field`]), or a field containing it
+pub fn mentions(msg: Msg, path: &[&str]) -> bool {
+    rules(msg).iter().any(|rule| {
+        rule.split('`').skip(1).step_by(2).any(|quoted| {
+            let mut names = quoted.split('.');
+            path.iter().zip(&mut names).all(|(a, b)| a == &b) && names.next().is_none()
+        })
+    })
+}
+
+/// Call `f` with copies of `valid` in which one field (with no fields of its
+/// own) is set to 0, to 2, and to all ones, and with the path to that field.
+/// Any field that a validator rejects like this must be [`mentions`]ed by
+/// [`rules`].
+pub fn corrupt_each_field<T: Castable>(valid: &T, f: &mut dyn FnMut(&[&str], &T)) {
+    corrupt_fields(valid, T::FIELDS, &mut [""; 8], 0, 0, f)
+}
+
+fn corrupt_fields<T: Castable>(
+    valid: &T,
+    fields: &[Field],
+    path: &mut [&'static str; 8],
+    depth: usize,
+    mut offset: usize,
+    f: &mut dyn FnMut(&[&str], &T),
+) {
+    for field in fields {
+        path[depth] = field.name;
+        if field.fields.is_empty() {
+            for value in [0, 2, 0xFF] {
+                let mut copy = *valid;
+                let bytes = &mut copy.as_mut_bytes()[offset..offset + field.size];
+                bytes.fill(if value == 2 { 0 } else { value });
+                if value == 2 {
+                    bytes[0] = 2
+                }
+                f(&path[..=depth], &copy)
+            }
+        } else {
+            corrupt_fields(valid, field.fields, path, depth + 1, offset, f)
+        }
+        offset += field.size
+    }
+}
+
+/// Which body lengths [`UntrustedHeader::validate_length`] accepts for a
+/// message type
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum LengthRule {
+    /// The type is not checked at all, so receivers treat it as unknown
+    Unchecked,
+    /// No length is valid
+    Never,
+    /// `min`, `min + step`, … up to and including `max`
+    Range { min: u32, step: u32, max: u32 },
+}
+
+impl LengthRule {
+    fn of(ty: u32) -> Self {
+        let valid = |untrusted_len| {
+            UntrustedHeader {
+                ty,
+                window: WindowID::default(),
+                untrusted_len,
+            }
+            .validate_length()
+        };
+        if let Ok(None) = valid(0) {
+            return LengthRule::Unchecked;
+        }
+        let mut lengths = (0..=PROBE_LIMIT).filter(|&len| valid(len).is_ok());
+        let min = match lengths.next() {
+            Some(min) => min,
+            None => return LengthRule::Never,
+        };
+        let (step, max) = match lengths.next() {
+            Some(next) => (next - min, lengths.next_back().unwrap_or(next)),
+            None => (0, min),
+        };
+        LengthRule::Range { min, step, max }
+    }
+}
+
+impl fmt::Display for LengthRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            LengthRule::Unchecked => f.write_str("not checked"),
+            LengthRule::Never => f.write_str("never valid"),
+            LengthRule::Range { min, step: 0, .. } => write!(f, "exactly {}", min),
+            LengthRule::Range { min, step: 1, max } => write!(f, "{} to {}", min, max),
+            LengthRule::Range { min, step, max } => {
+                write!(f, "{} to {}, in steps of {}", min, max, step)
+            }
+        }
+    }
+}
+
+/// Which side may send a message type
+fn direction(msg: Msg, length: LengthRule) -> &'static str {
+    let ty = msg as u32;
+    match (length, agent_may_send(ty), daemon_may_send(ty)) {
+        (LengthRule::Unchecked, _, _) | (LengthRule::Never, _, _) => "neither (obsolete)",
+        (_, true, true) => "both",
+        (_, true, false) => "agent ⇒ daemon",
+        (_, false, true) => "daemon ⇒ agent",
+        (_, false, false) => "neither",
+    }
+}
+
+/// The version that introduced a message
+struct Since(Option<u32>);
+
+impl fmt::Display for Since {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            Some(minor) => write!(f, "{}.{}", PROTOCOL_VERSION_MAJOR, minor),
+            None => f.write_str("all"),
+        }
+    }
+}
+
+/// Write a row for every field of `fields` that has no fields of its own,
+/// named by its path from the top-level struct
+fn write_fields(
+    out: &mut dyn Write,
+    fields: &[Field],
+    prefix: &mut dyn FnMut(&mut dyn Write) -> fmt::Result,
+    mut offset: usize,
+) -> fmt::Result {
+    for field in fields {
+        if field.fields.is_empty() {
+            write!(out, "| {} | {} | `", offset, field.size)?;
+            prefix(out)?;
+            writeln!(out, "{}` | `{}` |", field.name, field.ty)?;
+        } else {
+            write_fields(
+                out,
+                field.fields,
+                &mut |out| {
+                    prefix(out)?;
+                    write!(out, "{}.", field.name)
+                },
+                offset,
+            )?;
+        }
+        offset += field.size
+    }
+    Ok(())
+}
+
+/// Write a layout table for a struct
+fn write_layout(out: &mut dyn Write, fields: &[Field]) -> fmt::Result {
+    out.write_str("| Offset | Size | Field | Type |\n|---:|---:|---|---|\n")?;
+    write_fields(out, fields, &mut |_| Ok(()), 0)
+}
+
+/// Write the specification, in Markdown, to `out`
+pub fn write(out: &mut dyn Write) -> fmt::Result {
+    write!(
+        out,
+        concat!(
+            "<!-- Generated from the Rust definitions in the qubes-gui crate.  Do not edit. -->\n",
+            "\n",
+            "# Qubes OS GUI protocol, version {}.{}\n",
+            "\n",
+            "The key words MUST, MUST NOT, SHOULD, SHOULD NOT, and MAY are to be\n",
+            "interpreted as described in RFC 2119.  All integers are unsigned and\n",
+            "little-endian unless stated otherwise, and all offsets and sizes are in bytes.\n",
+            "\n",
+            "## Connection setup\n",
+            "\n",
+            "The agent first sends its protocol version as a 4-byte integer, with the\n",
+            "major version in the upper 16 bits and the minor version in the lower 16 bits.\n",
+            "The major version MUST be {}.  The daemon replies, without a header, with the\n",
+            "negotiated version (the lower of the two minor versions) followed by the root\n",
+            "window configuration:\n",
+            "\n",
+        ),
+        PROTOCOL_VERSION_MAJOR, PROTOCOL_VERSION_MINOR, PROTOCOL_VERSION_MAJOR,
+    )?;
+    write_layout(out, XConfVersion::FIELDS)?;
+    write!(
+        out,
+        concat!(
+            "\n",
+            "Agents that sent a minor version below 4 receive only `xconf`.\n",
+            "\n",
+            "## Constants\n",
+            "\n",
+            "| Name | Value |\n",
+            "|---|---:|\n",
+            "| `MAX_WINDOW_WIDTH` | {} |\n",
+            "| `MAX_WINDOW_HEIGHT` | {} |\n",
+            "| `MAX_CLIPBOARD_SIZE` | {} |\n",
+            "| `CURSOR_DEFAULT` | {:#x} |\n",
+            "| `CURSOR_X11` | {:#x} |\n",
+            "| `CURSOR_X11_MAX` | {:#x} |\n",
+            "\n",
+        ),
+        MAX_WINDOW_WIDTH,
+        MAX_WINDOW_HEIGHT,
+        MAX_CLIPBOARD_SIZE,
+        CURSOR_DEFAULT,
+        CURSOR_X11,
+        CURSOR_X11_MAX,
+    )?;
+    out.write_str(concat!(
+        "## Messages\n",
+        "\n",
+        "Every message starts with this header:\n",
+        "\n",
+    ))?;
+    write_layout(out, UntrustedHeader::FIELDS)?;
+    out.write_str(concat!(
+        "\n",
+        "`window` is 0 for the whole screen.  For every message except `CREATE`, the\n",
+        "window MUST exist.  The body follows the header and is exactly `untrusted_len`\n",
+        "bytes long.  Receivers MUST check `untrusted_len` against the rules below\n",
+        "before using it.  Agents MUST skip messages of unknown type; daemons MAY treat\n",
+        "them as an error.\n",
+        "\n",
+        "| Number | Name | Sent by | Since | Body length |\n",
+        "|---:|---|---|---|---|\n",
+    ))?;
+    for body in BODIES {
+        let length = LengthRule::of(body.msg as u32);
+        writeln!(
+            out,
+            "| {} | `MSG_{}` | {} | {} | {} |",
+            body.msg as u32,
+            body.msg,
+            direction(body.msg, length),
+            Since(body.msg.since()),
+            length
+        )?;
+    }
+    for body in BODIES {
+        let length = LengthRule::of(body.msg as u32);
+        write!(
+            out,
+            concat!(
+                "\n### `MSG_{}` ({})\n",
+                "\n",
+                "- Sent by: {}\n",
+                "- Since: {}\n",
+                "- Body length: {}\n",
+            ),
+            body.msg,
+            body.msg as u32,
+            direction(body.msg, length),
+            Since(body.msg.since()),
+            length
+        )?;
+        if body.msg.is_deprecated() {
+            out.write_str("- Deprecated.\n")?
+        }
+        if let Some(minor) = body.msg.since() {
+            writeln!(
+                out,
+                "- MUST NOT be sent unless the negotiated version is {}.{} or later.",
+                PROTOCOL_VERSION_MAJOR, minor
+            )?
+        }
+        for rule in body.rules {
+            writeln!(out, "- {}", rule)?;
+        }
+        match (body.rust, body.trailer) {
+            (Some(rust), _) => {
+                writeln!(out, "\nBody (`{}`, {} bytes):\n", rust, body.size)?;
+                write_layout(out, body.fields)?;
+            }
+            (None, None) if matches!(length, LengthRule::Range { max: 0, .. }) => {
+                out.write_str("\nThe body is empty.\n")?
+            }
+            (None, _) => {}
+        }
+        if let Some(trailer) = body.trailer {
+            let start = if body.size == 0 {
+                "The body is"
+            } else {
+                "Then"
+            };
+            writeln!(out, "\n{} {}.", start, trailer)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use std::string::String;
+
+    /// Whether `rule` allows a body of `len` bytes
+    fn allows(rule: LengthRule, len: u32) -> bool {
+        match rule {
+            LengthRule::Unchecked | LengthRule::Never => false,
+            LengthRule::Range { min, step: 0, .. } => len == min,
+            LengthRule::Range { min, step, max } => {
+                (min..=max).contains(&len) && (len - min).is_multiple_of(step)
+            }
+        }
+    }
+
+    #[test]
+    fn one_body_per_message() {
+        assert_eq!(BODIES.len(), Msg::ALL.len());
+        for (body, &msg) in BODIES.iter().zip(Msg::ALL) {
+            assert_eq!(body.msg, msg);
+        }
+    }
+
+    /// The struct sizes must agree with the length rules, and the length
+    /// rules must agree with the validator
+    #[test]
+    fn length_rules() {
+        for body in BODIES {
+            let ty = body.msg as u32;
+            let length = LengthRule::of(ty);
+            if let LengthRule::Range { min, step, .. } = length {
+                assert_eq!(min as usize, body.size, "{}", body.msg);
+                assert_eq!(step == 0, body.trailer.is_none(), "{}", body.msg);
+            }
+            for len in 0..=PROBE_LIMIT {
+                let header = UntrustedHeader {
+                    ty,
+                    window: WindowID::default(),
+                    untrusted_len: len,
+                };
+                assert_eq!(
+                    allows(length, len),
+                    matches!(header.validate_length(), Ok(Some(_))),
+                    "{} with length {}",
+                    body.msg,
+                    len
+                );
+            }
+        }
+    }
+
+    /// Check that [`rules`] for `msg` name every field of `valid` that
+    /// `validate` checks
+    fn check_rules<T: Castable>(msg: Msg, valid: T, validate: impl Fn(&T) -> bool) {
+        assert!(validate(&valid), "{}", msg);
+        corrupt_each_field(&valid, &mut |path, corrupted| {
+            assert!(
+                validate(corrupted) || mentions(msg, path),
+                "{} rules do not mention {:?}",
+                msg,
+                path
+            )
+        });
+    }
+
+    #[test]
+    fn mentions_fields() {
+        assert!(mentions(Msg::Create, &["override_redirect"]));
+        assert!(mentions(Msg::Create, &["rectangle", "size", "width"]));
+        assert!(!mentions(Msg::Create, &["override"]));
+        assert!(!mentions(Msg::Motion, &["ty"]));
+        let mut paths = std::vec::Vec::new();
+        corrupt_each_field(&WindowSize::default(), &mut |path, size| {
+            paths.push((path.join("."), size.width, size.height))
+        });
+        assert_eq!(
+            paths,
+            [
+                ("width".into(), 0, 0),
+                ("width".into(), 2, 0),
+                ("width".into(), u32::MAX, 0),
+                ("height".into(), 0, 0),
+                ("height".into(), 0, 2),
+                ("height".into(), 0, u32::MAX),
+            ]
+        );
+    }
+
+    #[test]
+    fn rules_name_checked_fields() {
+        let rectangle = Rectangle {
+            top_left: Coordinates { x: 1, y: 2 },
+            size: WindowSize {
+                width: 3,
+                height: 4,
+            },
+        };
+        check_rules(
+            Msg::Create,
+            Create {
+                rectangle,
+                parent: None,
+                override_redirect: 1,
+            },
+            |create| create.validate().is_ok(),
+        );
+        check_rules(
+            Msg::Map,
+            MapInfo {
+                transient_for: 0,
+                override_redirect: 1,
+            },
+            |info| info.validate().is_ok(),
+        );
+        check_rules(
+            Msg::MfnDump,
+            ShmCmd {
+                shmid: 0,
+                width: 3,
+                height: 4,
+                bpp: 24,
+                off: 0,
+                num_mfn: 1,
+                domid: 0,
+            },
+            |cmd| cmd.validate().is_ok(),
+        );
+        let size = |width, height| WindowSize { width, height };
+        check_rules(
+            Msg::WindowHints,
+            WindowHints::builder()
+                .min_size(size(1, 1))
+                .max_size(size(100, 100))
+                .size_increment(size(1, 1))
+                .build()
+                .unwrap(),
+            |hints| hints.validate().is_ok(),
+        );
+        check_rules(
+            Msg::WindowFlags,
+            WindowFlags {
+                set: WindowFlag::Fullscreen as u32,
+                unset: WindowFlag::Minimize as u32,
+            },
+            |flags| flags.validate().is_ok(),
+        );
+        check_rules(Msg::Cursor, Cursor { cursor: CURSOR_X11 }, |cursor| {
+            CursorShape::from_wire(cursor.cursor).is_ok()
+        });
+    }
+
+    #[test]
+    fn generates_spec() {
+        let mut spec = String::new();
+        write(&mut spec).unwrap();
+        assert!(spec.contains("# Qubes OS GUI protocol, version 1.7\n"));
+        assert!(spec.contains("| 124 | `MSG_KEYPRESS` | daemon ⇒ agent | all | exactly 20 |\n"));
+        assert!(spec.contains("| 130 | `MSG_CREATE` | agent ⇒ daemon | all | exactly 24 |\n"));
+        assert!(spec.contains("| 16 | 4 | `parent` | `Option<NonZeroU32>` |\n"));
+        assert!(spec.contains("| 8 | 4 | `rectangle.size.width` | `u32` |\n"));
+        assert!(spec.contains("| 138 | `MSG_EXECUTE` | neither (obsolete) | all | never valid |\n"));
+        assert!(spec.contains("`MSG_WINDOW_DUMP_ACK` | daemon ⇒ agent | 1.7 | exactly 0 |\n"));
+        assert!(spec.contains(
+            "`MSG_WINDOW_DUMP` | agent ⇒ daemon | all | 16 to 393232, in steps of 4 |\n"
+        ));
+        assert!(spec.contains("\nThen grant references, 4 bytes each.\n"));
+        assert!(spec.contains(
+            "- Since: 1.7\n- Body length: exactly 0\n- MUST NOT be sent unless the negotiated version is 1.7 or later.\n"
+        ));
+        assert!(spec.contains("- Body length: 28 to 393244, in steps of 4\n- Deprecated.\n"));
+    }
+}
@@ -0,0 +1,106 @@
+/*
+ * The Qubes OS Project, https://www.qubes-os.org
+ *
+ * Copyright (C) 2021  Demi Marie Obenour  <demi@invisiblethingslab.com>
+ *
+ * This program is free software; you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License
+ * as published by the Free Software Foundation; either version 2
+ * of the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301, USA.
+ *
+ */
+
+//! Reporting of protocol violations
+
+use crate::{Header, UntrustedHeader};
+use core::num::NonZeroU32;
+
+/// The kind of a protocol violation
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum ViolationKind {
+    /// The length of a message was wrong for its type
+    BadLength,
+    /// The type of a message was not recognized
+    UnknownMessage,
+    /// A message was sent in the wrong direction, such as an agent sending a
+    /// message that only the daemon may send
+    WrongDirection,
+    /// A message was sent to a window that does not exist, or `MSG_CREATE`
+    /// reused a window ID
+    UnknownWindow,
+    /// A field of a message was out of range
+    BadField,
+    /// A deprecated message was received, and [`crate::DeprecatedPolicy`]
+    /// did not allow it
+    Deprecated,
+}
+
+/// Metadata about a protocol violation.  This never includes the body of the
+/// offending message, which may contain sensitive data, so it is always safe
+/// to log.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Violation {
+    /// What went wrong
+    pub kind: ViolationKind,
+    /// The type of the offending message
+    pub ty: u32,
+    /// The window the message was sent to, or [`None`] for the whole screen
+    pub window: Option<NonZeroU32>,
+    /// The length of the message.  If `kind` is
+    /// [`ViolationKind::BadLength`], this is UNTRUSTED.
+    pub untrusted_len: u32,
+}
+
+impl Violation {
+    /// A violation of kind `kind` by the message with header `header`
+    pub fn new(kind: ViolationKind, header: &UntrustedHeader) -> Self {
+        Self {
+            kind,
+            ty: header.ty,
+            window: header.window.window,
+            untrusted_len: header.untrusted_len,
+        }
+    }
+
+    /// Like [`Violation::new`], but for a message that has passed length
+    /// validation
+    pub fn from_header(kind: ViolationKind, header: &Header) -> Self {
+        Self::new(kind, &header.inner())
+    }
+}
+
+impl core::fmt::Display for Violation {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "{:?} in message of type {} and length {} to window {}",
+            self.kind,
+            self.ty,
+            self.untrusted_len,
+            self.window.map_or(0, NonZeroU32::get)
+        )
+    }
+}
+
+/// Receives protocol violations, for logging or alerting.  Implemented for
+/// all `FnMut(&Violation)` closures.
+pub trait ViolationSink {
+    /// Called whenever validation of a message fails
+    fn report(&mut self, violation: &Violation);
+}
+
+impl<F: FnMut(&Violation) + ?Sized> ViolationSink for F {
+    fn report(&mut self, violation: &Violation) {
+        self(violation)
+    }
+}
@@ -0,0 +1,149 @@
+/*
+ * The Qubes OS Project, https://www.qubes-os.org
+ *
+ * Copyright (C) 2021  Demi Marie Obenour  <demi@invisiblethingslab.com>
+ *
+ * This program is free software; you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License
+ * as published by the Free Software Foundation; either version 2
+ * of the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301, USA.
+ *
+ */
+
+//! Validation of [`crate::Create`] and [`crate::MapInfo`]
+
+use crate::{Create, MapInfo, ProtocolError, ValidRectangle, WindowID, MSG_CREATE, MSG_MAP};
+use core::num::NonZeroU32;
+
+/// Parse an `override_redirect` field, which must be 0 or 1
+fn override_redirect(msg: u32, value: u32) -> Result<bool, ProtocolError> {
+    match value {
+        0 => Ok(false),
+        1 => Ok(true),
+        _ => Err(ProtocolError::BadFieldValue {
+            msg,
+            field: "override_redirect",
+        }),
+    }
+}
+
+/// A [`Create`] that satisfies every documented invariant
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct ValidCreate {
+    rectangle: ValidRectangle,
+    parent: Option<NonZeroU32>,
+    override_redirect: bool,
+}
+
+impl Create {
+    /// Validate this message.  The rectangle must have a valid size, and
+    /// `override_redirect` must be 0 or 1.  Whether the parent exists is
+    /// not checked.
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`ProtocolError::BadFieldValue`] naming the offending
+    /// field.
+    pub fn validate(&self) -> Result<ValidCreate, ProtocolError> {
+        let rectangle =
+            ValidRectangle::new(self.rectangle).map_err(|_| ProtocolError::BadFieldValue {
+                msg: MSG_CREATE,
+                field: "rectangle",
+            })?;
+        Ok(ValidCreate {
+            rectangle,
+            parent: self.parent,
+            override_redirect: override_redirect(MSG_CREATE, self.override_redirect)?,
+        })
+    }
+}
+
+impl ValidCreate {
+    /// The rectangle the window is to occupy
+    pub fn rectangle(self) -> ValidRectangle {
+        self.rectangle
+    }
+
+    /// The parent window, if any
+    pub fn parent(self) -> Option<WindowID> {
+        self.parent.map(WindowID::from)
+    }
+
+    /// Should the window manager leave this window alone?
+    pub fn override_redirect(self) -> bool {
+        self.override_redirect
+    }
+
+    /// The underlying message
+    pub fn get(self) -> Create {
+        Create {
+            rectangle: self.rectangle.get(),
+            parent: self.parent,
+            override_redirect: self.override_redirect.into(),
+        }
+    }
+}
+
+impl From<ValidCreate> for Create {
+    fn from(create: ValidCreate) -> Self {
+        create.get()
+    }
+}
+
+/// A [`MapInfo`] that satisfies every documented invariant
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct ValidMapInfo {
+    transient_for: Option<NonZeroU32>,
+    override_redirect: bool,
+}
+
+impl MapInfo {
+    /// Validate this message.  `override_redirect` must be 0 or 1.  Whether
+    /// the `transient_for` window exists is not checked.
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`ProtocolError::BadFieldValue`] if `override_redirect` is
+    /// not valid.
+    pub fn validate(&self) -> Result<ValidMapInfo, ProtocolError> {
+        Ok(ValidMapInfo {
+            transient_for: NonZeroU32::new(self.transient_for),
+            override_redirect: override_redirect(MSG_MAP, self.override_redirect)?,
+        })
+    }
+}
+
+impl ValidMapInfo {
+    /// The window this one is transient for, if any
+    pub fn transient_for(self) -> Option<NonZeroU32> {
+        self.transient_for
+    }
+
+    /// Should the window manager leave this window alone?
+    pub fn override_redirect(self) -> bool {
+        self.override_redirect
+    }
+
+    /// The underlying message
+    pub fn get(self) -> MapInfo {
+        MapInfo {
+            transient_for: self.transient_for.map_or(0, NonZeroU32::get),
+            override_redirect: self.override_redirect.into(),
+        }
+    }
+}
+
+impl From<ValidMapInfo> for MapInfo {
+    fn from(info: ValidMapInfo) -> Self {
+        info.get()
+    }
+}
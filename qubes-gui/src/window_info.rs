@@ -0,0 +1,92 @@
+/*
+ * The Qubes OS Project, https://www.qubes-os.org
+ *
+ * Copyright (C) 2021  Demi Marie Obenour  <demi@invisiblethingslab.com>
+ *
+ * This program is free software; you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License
+ * as published by the Free Software Foundation; either version 2
+ * of the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301, USA.
+ *
+ */
+
+//! What agents and daemons know about a window
+
+use crate::{Rectangle, WindowHints};
+use core::num::NonZeroU32;
+
+/// Operations on a window that are not yet complete
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct PendingOps {
+    /// The daemon has sent `MSG_CLOSE`, and the agent has not yet destroyed
+    /// the window.
+    pub close_requested: bool,
+    /// The agent has sent `MSG_CONFIGURE`, and the daemon has not yet sent a
+    /// `MSG_CONFIGURE` of its own.  Only agents track this.
+    pub configure: bool,
+    /// The agent has sent `MSG_WINDOW_DUMP`, and the daemon has not yet sent
+    /// `MSG_WINDOW_DUMP_ACK`.
+    pub dump_ack: bool,
+}
+
+/// A snapshot of one window, as returned by the window enumeration of both
+/// agents and daemons.  Fields that only one side knows about are left at
+/// their defaults by the other.
+///
+/// The [`core::fmt::Debug`] implementation does not include the title, which
+/// may be sensitive, so this can safely be logged.
+#[derive(Copy, Clone)]
+pub struct WindowInfo<'a> {
+    /// The window ID
+    pub id: NonZeroU32,
+    /// The current position and size of the window
+    pub rectangle: Rectangle,
+    /// The window’s parent, if any
+    pub parent: Option<NonZeroU32>,
+    /// Is this an override-redirect window?
+    pub override_redirect: bool,
+    /// Is the window currently mapped?
+    pub mapped: bool,
+    /// The [`crate::WindowFlag`]s currently set on the window
+    pub flags: u32,
+    /// The window title.  On the daemon side this is UNTRUSTED: it was set
+    /// by the agent, and has not been sanitized in any way beyond being
+    /// valid UTF-8.
+    pub title: &'a str,
+    /// The size hints sent by the agent, if any.  Only daemons track this.
+    pub hints: Option<&'a WindowHints>,
+    /// Is this a popup, such as a menu or tooltip?  Only agents track this.
+    pub popup: bool,
+    /// If this is a dialog, the window it belongs to.  Only agents track
+    /// this.
+    pub dialog_owner: Option<NonZeroU32>,
+    /// Operations that are not yet complete
+    pub pending: PendingOps,
+}
+
+impl core::fmt::Debug for WindowInfo<'_> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("WindowInfo")
+            .field("id", &self.id)
+            .field("rectangle", &self.rectangle)
+            .field("parent", &self.parent)
+            .field("override_redirect", &self.override_redirect)
+            .field("mapped", &self.mapped)
+            .field("flags", &self.flags)
+            .field("title_len", &self.title.len())
+            .field("hints", &self.hints)
+            .field("popup", &self.popup)
+            .field("dialog_owner", &self.dialog_owner)
+            .field("pending", &self.pending)
+            .finish()
+    }
+}
@@ -0,0 +1,185 @@
+/*
+ * The Qubes OS Project, https://www.qubes-os.org
+ *
+ * Copyright (C) 2021  Demi Marie Obenour  <demi@invisiblethingslab.com>
+ *
+ * This program is free software; you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License
+ * as published by the Free Software Foundation; either version 2
+ * of the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301, USA.
+ *
+ */
+
+//! X11 constants used by the protocol.
+//!
+//! The `state` field of [`crate::Keypress`], [`crate::Button`],
+//! [`crate::Motion`], and [`crate::Crossing`] is an X11 state mask, and
+//! keycodes are X11 keycodes.  These are the values from `<X11/X.h>`.
+
+/// `ShiftMask`
+pub const SHIFT_MASK: u32 = 1 << 0;
+/// `LockMask` (Caps Lock)
+pub const LOCK_MASK: u32 = 1 << 1;
+/// `ControlMask`
+pub const CONTROL_MASK: u32 = 1 << 2;
+/// `Mod1Mask` (usually Alt)
+pub const MOD1_MASK: u32 = 1 << 3;
+/// `Mod2Mask` (usually Num Lock)
+pub const MOD2_MASK: u32 = 1 << 4;
+/// `Mod3Mask`
+pub const MOD3_MASK: u32 = 1 << 5;
+/// `Mod4Mask` (usually Super)
+pub const MOD4_MASK: u32 = 1 << 6;
+/// `Mod5Mask`
+pub const MOD5_MASK: u32 = 1 << 7;
+/// `Button1Mask` (usually the left button)
+pub const BUTTON1_MASK: u32 = 1 << 8;
+/// `Button2Mask` (usually the middle button)
+pub const BUTTON2_MASK: u32 = 1 << 9;
+/// `Button3Mask` (usually the right button)
+pub const BUTTON3_MASK: u32 = 1 << 10;
+/// `Button4Mask` (usually scroll up)
+pub const BUTTON4_MASK: u32 = 1 << 11;
+/// `Button5Mask` (usually scroll down)
+pub const BUTTON5_MASK: u32 = 1 << 12;
+
+/// All modifier bits
+pub const MODIFIER_MASK: u32 = SHIFT_MASK
+    | LOCK_MASK
+    | CONTROL_MASK
+    | MOD1_MASK
+    | MOD2_MASK
+    | MOD3_MASK
+    | MOD4_MASK
+    | MOD5_MASK;
+/// All button bits
+pub const BUTTON_MASK: u32 =
+    BUTTON1_MASK | BUTTON2_MASK | BUTTON3_MASK | BUTTON4_MASK | BUTTON5_MASK;
+
+/// The mask bit for X11 button `button`, or `None` if `button` is not
+/// between 1 and 5 inclusive
+pub fn button_mask(button: u32) -> Option<u32> {
+    match button {
+        1..=5 => Some(BUTTON1_MASK << (button - 1)),
+        _ => None,
+    }
+}
+
+/// An X11 pointer button, as in [`crate::Button::button`]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum MouseButton {
+    /// Button 1
+    Left,
+    /// Button 2
+    Middle,
+    /// Button 3
+    Right,
+    /// Button 4
+    ScrollUp,
+    /// Button 5
+    ScrollDown,
+    /// Button 6
+    ScrollLeft,
+    /// Button 7
+    ScrollRight,
+    /// Any other button.  [`MouseButton::from`] never returns this for
+    /// buttons 1 through 7.
+    Other(u32),
+}
+
+impl From<u32> for MouseButton {
+    fn from(button: u32) -> Self {
+        match button {
+            1 => MouseButton::Left,
+            2 => MouseButton::Middle,
+            3 => MouseButton::Right,
+            4 => MouseButton::ScrollUp,
+            5 => MouseButton::ScrollDown,
+            6 => MouseButton::ScrollLeft,
+            7 => MouseButton::ScrollRight,
+            other => MouseButton::Other(other),
+        }
+    }
+}
+
+impl From<MouseButton> for u32 {
+    fn from(button: MouseButton) -> Self {
+        match button {
+            MouseButton::Left => 1,
+            MouseButton::Middle => 2,
+            MouseButton::Right => 3,
+            MouseButton::ScrollUp => 4,
+            MouseButton::ScrollDown => 5,
+            MouseButton::ScrollLeft => 6,
+            MouseButton::ScrollRight => 7,
+            MouseButton::Other(other) => other,
+        }
+    }
+}
+
+impl MouseButton {
+    /// Is this a scroll wheel “button” rather than a real one?
+    pub fn is_scroll(self) -> bool {
+        self.scroll_delta().is_some()
+    }
+
+    /// The direction of a scroll as `(dx, dy)` steps, with positive values
+    /// to the right and down, or `None` if this is not a scroll button
+    pub fn scroll_delta(self) -> Option<(i32, i32)> {
+        match self {
+            MouseButton::ScrollUp => Some((0, -1)),
+            MouseButton::ScrollDown => Some((0, 1)),
+            MouseButton::ScrollLeft => Some((-1, 0)),
+            MouseButton::ScrollRight => Some((1, 0)),
+            _ => None,
+        }
+    }
+}
+
+impl crate::Button {
+    /// The button that was pressed or released
+    pub fn mouse_button(&self) -> MouseButton {
+        self.button.into()
+    }
+
+    /// Is this a scroll event?  X11 reports each scroll step as a press and
+    /// a release of a scroll button.
+    pub fn is_scroll(&self) -> bool {
+        self.mouse_button().is_scroll()
+    }
+}
+
+/// Difference between an X11 keycode and the corresponding evdev keycode
+pub const EVDEV_OFFSET: u32 = 8;
+/// Smallest valid X11 keycode
+pub const MIN_KEYCODE: u32 = 8;
+/// Largest valid X11 keycode
+pub const MAX_KEYCODE: u32 = 255;
+
+/// Convert an X11 keycode to a Linux evdev keycode (`KEY_*` in
+/// `<linux/input-event-codes.h>`).  Returns `None` if `keycode` is not a
+/// valid X11 keycode.
+pub fn keycode_to_evdev(keycode: u32) -> Option<u32> {
+    match keycode {
+        MIN_KEYCODE..=MAX_KEYCODE => Some(keycode - EVDEV_OFFSET),
+        _ => None,
+    }
+}
+
+/// Convert a Linux evdev keycode to an X11 keycode.  Returns `None` if the
+/// result would not be a valid X11 keycode.
+pub fn evdev_to_keycode(evdev: u32) -> Option<u32> {
+    match evdev.checked_add(EVDEV_OFFSET)? {
+        keycode @ MIN_KEYCODE..=MAX_KEYCODE => Some(keycode),
+        _ => None,
+    }
+}
@@ -36,7 +36,9 @@ pub const VCHAN_CONNECTED: c_int = 1;
 /* vchan server initialized, waiting for client to connect */
 pub const VCHAN_WAITING: c_int = 2;
 
-#[link(name = "vchan-xen")]
+// There is no libvchan outside of Xen guests, but the rest of the workspace
+// can still be built for targets such as WebAssembly.
+#[cfg_attr(unix, link(name = "vchan-xen"))]
 extern "C" {
     pub fn libvchan_server_init(
         domain: c_int,
@@ -22,7 +22,7 @@
 #![forbid(clippy::all, improper_ctypes, improper_ctypes_definitions)]
 
 use std::io::{ErrorKind, Read, Write};
-use std::os::{raw::c_int, raw::c_void, unix::prelude::RawFd};
+use std::os::raw::{c_int, c_void};
 
 macro_rules! static_assert {
     ($s: expr) => {
@@ -138,7 +138,7 @@ impl Vchan {
 
     /// Returns the underlying file descriptor.  The only valid use of this descriptor
     /// is to call `poll` or similar.
-    pub fn fd(&self) -> RawFd {
+    pub fn fd(&self) -> c_int {
         unsafe { vchan_sys::libvchan_fd_for_select(self.inner) }
     }
 